@@ -6,7 +6,9 @@ use std::process::Command;
 fn main() -> Result<()> {
     build_phinit()?;
     build_kernel()?;
-    build_sommelier()?;
+    if env::var_os("CARGO_FEATURE_WAYLAND").is_some() {
+        build_sommelier()?;
+    }
     // Rerun build.rs upon making or pulling in new commits
     println!("cargo:rerun-if-changed=.git/refs/heads/master");
     println!("cargo:rerun-if-changed=ph-init/src");
@@ -0,0 +1,88 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cmdline::CmdLine;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Controllers made available to the service cgroups
+const CONTROLLERS: &[&str] = &["cpu", "memory", "pids"];
+
+// Period of cpu.max in microseconds
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+/// Enable the controllers used to limit services in the cgroups below the root
+pub fn enable_controllers() {
+    let path = Path::new(CGROUP_ROOT).join("cgroup.subtree_control");
+    for controller in CONTROLLERS {
+        if let Err(err) = fs::write(&path, format!("+{}", controller)) {
+            warn!("Failed to enable cgroup controller {}: {}", controller, err);
+        }
+    }
+}
+
+///
+/// Limits for a service read from the kernel command line variables
+/// `phinit.cgroup.NAME.memory` (bytes, with an optional K, M or G suffix)
+/// and `phinit.cgroup.NAME.cpu` (percentage of one cpu).
+///
+pub struct ServiceLimits {
+    memory: Option<String>,
+    cpu_percent: Option<u64>,
+}
+
+impl ServiceLimits {
+    pub fn load(cmdline: &CmdLine, name: &str) -> Self {
+        let lookup = |key: &str| cmdline.lookup(&format!("phinit.cgroup.{}.{}", name, key));
+        let memory = lookup("memory");
+        let cpu_percent = lookup("cpu").and_then(|cpu| match cpu.parse::<u64>() {
+            Ok(percent) if percent > 0 => Some(percent),
+            _ => {
+                warn!("Ignoring invalid cpu limit '{}' for {}", cpu, name);
+                None
+            }
+        });
+        ServiceLimits { memory, cpu_percent }
+    }
+}
+
+///
+/// A cgroup below the root for a single service. The service and every
+/// process it starts are accounted and limited together.
+///
+pub struct ServiceCGroup {
+    path: PathBuf,
+}
+
+impl ServiceCGroup {
+    pub fn create(name: &str, limits: &ServiceLimits) -> io::Result<Self> {
+        let path = Path::new(CGROUP_ROOT).join(name);
+        if let Err(err) = fs::create_dir(&path) {
+            if err.kind() != io::ErrorKind::AlreadyExists {
+                return Err(err);
+            }
+        }
+        let cgroup = ServiceCGroup { path };
+        if let Some(memory) = &limits.memory {
+            cgroup.write("memory.max", memory)?;
+        }
+        if let Some(percent) = limits.cpu_percent {
+            let quota = CPU_PERIOD_USEC * percent / 100;
+            cgroup.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD_USEC))?;
+        }
+        Ok(cgroup)
+    }
+
+    fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        fs::write(self.path.join(file), value)
+    }
+
+    /// Open `cgroup.procs` so that a forked child can move itself into the
+    /// cgroup by writing "0" without allocating.
+    pub fn open_procs(&self) -> io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .open(self.path.join("cgroup.procs"))
+    }
+}
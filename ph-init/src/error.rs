@@ -20,7 +20,7 @@ pub enum Error {
     MountTmpFS(String, io::Error),
     #[error("failed to mount sysfs at /sys: {0}")]
     MountSysFS(io::Error),
-    #[error("failed to mount cgroup at /sys/fs/cgroup: {0}")]
+    #[error("failed to mount cgroup2 at /sys/fs/cgroup: {0}")]
     MountCGroup(io::Error),
     #[error("failed to mount devtmpfs at /dev: {0}")]
     MountDevTmpFS(io::Error),
@@ -50,6 +50,10 @@ pub enum Error {
     WaitPid(io::Error),
     #[error("failed to write /etc/hosts: {0}")]
     WriteEtcHosts(io::Error),
+    #[error("failed to write /etc/machine-id: {0}")]
+    WriteMachineId(io::Error),
+    #[error("failed to write /run/resolv.conf: {0}")]
+    WriteResolvConf(io::Error),
     #[error("error launching shell: {0}")]
     RunShell(io::Error),
     #[error("failed to create CString")]
@@ -64,6 +68,10 @@ pub enum Error {
     RebootFailed(io::Error),
     #[error("failed to open log file: {0}")]
     OpenLogFailed(io::Error),
+    #[error("failed to read service file {0}: {1}")]
+    ReadServiceUnit(String, io::Error),
+    #[error("error in service file {0} at line {1}: {2}")]
+    ServiceUnit(String, usize, String),
     #[error("error creating .Xauthority file: {0}")]
     XAuthFail(io::Error),
     #[error("error writing bashrc file: {0}")]
@@ -1,10 +1,14 @@
 
 use crate::{Error, Result, Logger, LogLevel, netlink, sys};
 use crate::cmdline::CmdLine;
-use crate::sys::{sethostname, setsid, set_controlling_tty, mount_devtmpfs, mount_tmpfs, mkdir, umount, mount_sysfs, mount_procfs, mount_devpts, chown, chmod, create_directories, mount_overlay, move_mount, pivot_root, mount_9p, mount, waitpid, reboot, getpid, mount_tmpdir, mount_cgroup, umask, _chown};
+use crate::sys::{sethostname, setsid, set_controlling_tty, mount_devtmpfs, mount_tmpfs, mkdir, umount, mount_sysfs, mount_procfs, mount_devpts, chown, chmod, create_directories, mount_overlay, move_mount, pivot_root, mount_9p, mount, waitpid, reboot, power_off, getpid, mount_tmpdir, mount_cgroup, umask, _chown, statfs, umount_lazy, cpu_hotplug_target, cpu_online, set_cpu_online, notify_boot_complete, setup_zram_swap, report_memory_stats};
 use std::path::Path;
-use std::{fs, process, io, env};
+use std::{fs, process, io, env, thread};
+use std::time::Duration;
 use crate::service::{Service, ServiceLaunch};
+use crate::cgroup::{self, ServiceLimits};
+use crate::layout;
+use crate::unit::{self, ServiceUnit};
 use std::collections::BTreeMap;
 use std::io::Read;
 use std::net::Ipv4Addr;
@@ -12,8 +16,33 @@ use std::str::FromStr;
 use crate::audio::AudioSupport;
 use crate::netlink::NetlinkSocket;
 
+// How often the home share is checked for having been replaced by the host
+const HOME_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+// How often pH is asked how many cpus to keep online
+const CPU_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+// How often the memory usage of the guest is sent to pH
+const MEMORY_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+// How often the resolv.conf of the host is checked for changes
+const DNS_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+// The read-only assets volume shared by pH with phinit.assets. pH builds its
+// font caches for the fonts at this path.
+const ASSETS_PATH: &str = "/run/ph/assets";
+// Adds the fonts and font caches of the assets volume to the fontconfig paths
+const ASSETS_FONTS_CONF: &str = "/etc/fonts/conf.d/10-ph-assets.conf";
+// Icon themes of the assets volume are mounted here, in the default XDG_DATA_DIRS
+const ASSETS_ICONS_PATH: &str = "/usr/local/share/icons";
+
+// resolv.conf of the host, exported by pH on the boot filesystem
+const HOST_RESOLV_CONF: &str = "/opt/ph/etc/resolv.conf";
+// Written by init and bind mounted over /etc/resolv.conf
+const RESOLV_CONF: &str = "/run/resolv.conf";
+
+// Hostname used when pH does not pass one
+const DEFAULT_HOSTNAME: &str = "airwolf";
+
 const BASHRC: &str = r#"
-export PS1="airwolf > "
+export PS1="\h > "
 umask 022
 shopt -s checkwinsize
 alias ls='ls --color=auto'
@@ -30,17 +59,20 @@ pub struct InitServer {
     cmdline: CmdLine,
     rootfs: RootFS,
     services: BTreeMap<u32, Service>,
+    units: BTreeMap<String, ServiceUnit>,
 }
 
 impl InitServer {
-    fn new(hostname: &str) -> Result<InitServer> {
+    fn new() -> Result<InitServer> {
         Self::check_pid1()?;
-        let hostname = hostname.to_string();
         let cmdline = CmdLine::load()?;
+        let hostname = cmdline.lookup("phinit.hostname")
+            .unwrap_or_else(|| DEFAULT_HOSTNAME.to_string());
         let homedir = cmdline.lookup("phinit.home")
             .unwrap_or("/home/user".to_string());
         let rootfs = RootFS::load(&cmdline)?;
         let services = BTreeMap::new();
+        let units = BTreeMap::new();
 
         Ok(InitServer {
             hostname,
@@ -48,11 +80,12 @@ impl InitServer {
             cmdline,
             rootfs,
             services,
+            units,
         })
     }
 
-    pub fn create(hostname: &str) -> Result<InitServer> {
-        let init = Self::new(hostname)?;
+    pub fn create() -> Result<InitServer> {
+        let init = Self::new()?;
         init.initialize()?;
         Ok(init)
     }
@@ -92,7 +125,7 @@ impl InitServer {
     pub fn setup_filesystem(&self) -> Result<()> {
         sys::set_umask(0o022);
         //mount_devtmpfs()?;
-        mount_tmpfs("/tmp")?;
+        mount_tmpfs("/tmp", None)?;
         mkdir("/tmp/sysroot")?;
         if self.rootfs.read_only() {
             self.setup_readonly_root()?;
@@ -101,6 +134,8 @@ impl InitServer {
         }
         fs::write("/etc/hosts", format!("127.0.0.1       {} localhost\n", self.hostname))
             .map_err(Error::WriteEtcHosts)?;
+        self.write_machine_id()?;
+        self.set_timezone();
 
         umount("/opt/ph/tmp")?;
         umount("/opt/ph/proc")?;
@@ -108,13 +143,17 @@ impl InitServer {
 
         mount_sysfs()?;
         mount_cgroup()?;
+        cgroup::enable_controllers();
         mount_procfs()?;
+        layout::check_layout();
         mount_devtmpfs()?;
         mount_devpts()?;
-        mount_tmpfs("/run")?;
-        mount_tmpdir("/tmp")?;
+        Self::create_disk_links();
+        self.setup_zram_swap();
+        mount_tmpfs("/run", self.tmpfs_size("run").as_deref())?;
+        mount_tmpdir("/tmp", self.tmpfs_size("tmp").as_deref())?;
         mkdir("/dev/shm")?;
-        mount_tmpdir("/dev/shm")?;
+        mount_tmpdir("/dev/shm", self.tmpfs_size("shm").as_deref())?;
         mkdir("/run/user")?;
         mkdir("/run/user/1000")?;
         chown("/run/user/1000", 1000,1000)?;
@@ -122,11 +161,74 @@ impl InitServer {
         AudioSupport::setup()?;
 
         self.mount_home_if_exists()?;
+        self.mount_assets();
         Logger::set_file_output("/run/phinit.log")
             .map_err(Error::OpenLogFailed)?;
         Ok(())
     }
 
+    // Create the /dev/disk/by-id and /dev/disk/by-label links for virtio block
+    // devices which udev would create, so disks can be mounted by name whatever
+    // order they appear in. The serial number of the device is the label pH
+    // was given for the disk.
+    fn create_disk_links() {
+        let entries = match fs::read_dir("/sys/block") {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("failed to read /sys/block: {}", e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with("vd") {
+                continue;
+            }
+            let target = format!("../../{}", name);
+            if let Ok(serial) = fs::read_to_string(format!("/sys/block/{}/serial", name)) {
+                let serial = serial.trim();
+                if !serial.is_empty() {
+                    Self::create_disk_link("by-id", &format!("virtio-{}", serial), &target);
+                }
+            }
+            if let Some(label) = read_ext4_label(&format!("/dev/{}", name)) {
+                Self::create_disk_link("by-label", &label, &target);
+            }
+        }
+    }
+
+    fn create_disk_link(kind: &str, name: &str, target: &str) {
+        let dir = Path::new("/dev/disk").join(kind);
+        if let Err(e) = fs::create_dir_all(&dir) {
+            warn!("failed to create {}: {}", dir.display(), e);
+            return;
+        }
+        // Escaped as udev does
+        let name = name.replace('/', "\\x2f").replace(' ', "_");
+        if let Err(e) = std::os::unix::fs::symlink(target, dir.join(&name)) {
+            warn!("failed to create link {}/{}: {}", dir.display(), name, e);
+        }
+    }
+
+    // Size limit for a tmpfs set with phinit.tmpfs.NAME=SIZE
+    fn tmpfs_size(&self, name: &str) -> Option<String> {
+        self.cmdline.lookup(&format!("phinit.tmpfs.{}", name))
+    }
+
+    // phinit.zram=BYTES adds compressed swap in RAM
+    fn setup_zram_swap(&self) {
+        let size = match self.cmdline.lookup("phinit.zram") {
+            Some(size) => size,
+            None => return,
+        };
+        match size.parse::<u64>() {
+            Ok(size) => if let Err(err) = setup_zram_swap(size) {
+                warn!("Failed to set up zram swap: {}", err);
+            },
+            Err(_) => warn!("Invalid phinit.zram size: {}", size),
+        }
+    }
+
     fn setup_readonly_root(&self) -> Result<()> {
         create_directories(&[
             "/tmp/ro",
@@ -134,7 +236,7 @@ impl InitServer {
             "/tmp/rw/upper",
             "/tmp/rw/work",
         ])?;
-        mount_tmpfs("/tmp/rw")?;
+        mount_tmpfs("/tmp/rw", self.tmpfs_size("overlay").as_deref())?;
         create_directories(&["/tmp/rw/upper", "/tmp/rw/work"])?;
         self.rootfs.mount("/tmp/ro")?;
         mount_overlay("/tmp/sysroot",
@@ -185,91 +287,299 @@ impl InitServer {
     }
 
 
-    pub fn run_daemons(&mut self) -> Result<()> {
-        if !Path::new("/dev/wl0").exists() {
-            return Ok(());
+    // The assets volume is optional, failing to set it up only leaves GUI
+    // applications to build their own caches.
+    fn mount_assets(&self) {
+        if !self.cmdline.has_var("phinit.assets") {
+            return;
         }
+        let mkdirs = |path: &str| fs::create_dir_all(path)
+            .map_err(|e| Error::MkDir(path.to_string(), e));
+        if let Err(err) = mkdirs(ASSETS_PATH).and_then(|()| mount_9p("assets", ASSETS_PATH)) {
+            warn!("Failed to mount assets volume: {}", err);
+            return;
+        }
+        let assets = Path::new(ASSETS_PATH);
+        if assets.join("fonts").is_dir() && Path::new("/etc/fonts/conf.d").is_dir() {
+            let conf = format!("<?xml version=\"1.0\"?>\n\
+                                <!DOCTYPE fontconfig SYSTEM \"urn:fontconfig:fonts.dtd\">\n\
+                                <fontconfig>\n  \
+                                  <dir>{0}/fonts</dir>\n  \
+                                  <cachedir>{0}/fontconfig</cachedir>\n\
+                                </fontconfig>\n", ASSETS_PATH);
+            if let Err(err) = fs::write(ASSETS_FONTS_CONF, conf) {
+                warn!("Failed to write {}: {}", ASSETS_FONTS_CONF, err);
+            }
+        }
+        if assets.join("icons").is_dir() {
+            let icons = format!("{}/icons", ASSETS_PATH);
+            if let Err(err) = mkdirs(ASSETS_ICONS_PATH).and_then(|()| sys::bind_mount(&icons, ASSETS_ICONS_PATH)) {
+                warn!("Failed to mount assets icons on {}: {}", ASSETS_ICONS_PATH, err);
+            }
+        }
+    }
 
-        chmod("/dev/wl0", 0o666)?;
-
-        let dbus = ServiceLaunch::new("dbus-daemon", "/usr/bin/dbus-daemon")
-            .base_environment()
-            .uidgid(1000,1000)
-            .env("HOME", self.homedir())
-            .env("NO_AT_BRIDGE", "1")
-            .env("QT_ACCESSIBILITY", "1")
-            .env("SHELL", "/bin/bash")
-            .env("USER", "user")
-            .env("WAYLAND_DISPLAY", "wayland-0")
-            .arg("--session")
-            .arg("--nosyslog")
-            .arg("--address=unix:path=/run/user/1000/bus")
-            .arg("--print-address")
-            .pipe_output()
-            .launch()?;
-
-        self.services.insert(dbus.pid(), dbus);
+    // When the host replaces the home directory (`home quiesce` and `home
+    // resume` on the pH control socket) every request on the old mount fails
+    // with ESTALE. Watch for this and mount the share again. Processes with
+    // files open or a working directory on the old mount keep the stale
+    // mount until they let go of it.
+    pub fn watch_home(&self) {
+        if !self.has_9p_home() {
+            return;
+        }
+        let homedir = self.homedir().to_string();
+        thread::spawn(move || loop {
+            thread::sleep(HOME_WATCH_INTERVAL);
+            match statfs(&homedir) {
+                Err(ref e) if e.raw_os_error() == Some(libc::ESTALE) => {
+                    info!("Home directory share was replaced, mounting it again");
+                    if let Err(err) = umount_lazy(&homedir).and_then(|()| mount_9p("home", &homedir)) {
+                        warn!("Failed to remount home directory: {}", err);
+                    }
+                }
+                _ => {}
+            }
+        });
+    }
 
-        let sommelier = ServiceLaunch::new("sommelier", "/opt/ph/usr/bin/sommelier")
-            .base_environment()
-            .uidgid(1000,1000)
-            .arg("--parent")
-            .pipe_output()
-            .launch()?;
+    // With phinit.cpu_hotplug pH creates more vcpus than the kernel brings
+    // up at boot (maxcpus=) and sets how many should be online with the `cpus`
+    // command on its control socket. Poll for the target and bring cpus online
+    // or take them offline to match it.
+    pub fn watch_cpus(&self) {
+        if !self.cmdline.has_var("phinit.cpu_hotplug") {
+            return;
+        }
+        thread::spawn(|| loop {
+            thread::sleep(CPU_WATCH_INTERVAL);
+            match cpu_hotplug_target() {
+                Ok(target) if target > 0 => Self::set_online_cpus(target),
+                Ok(_) => {},
+                Err(err) => {
+                    warn!("Failed to read cpu hotplug target: {}", err);
+                    return;
+                }
+            }
+        });
+    }
 
-        self.services.insert(sommelier.pid(), sommelier);
+    // With phinit.memory_stats pH collects the memory usage of the guest for
+    // its stats, so that the host can tell which VMs are short of memory
+    // without a balloon device.
+    pub fn report_memory(&self) {
+        if !self.cmdline.has_var("phinit.memory_stats") {
+            return;
+        }
+        thread::spawn(|| loop {
+            if let Err(err) = report_memory_stats() {
+                warn!("Failed to report memory usage: {}", err);
+                return;
+            }
+            thread::sleep(MEMORY_REPORT_INTERVAL);
+        });
+    }
 
+    fn set_online_cpus(target: usize) {
+        let mut cpus = Vec::new();
+        while let Some(online) = cpu_online(cpus.len()) {
+            cpus.push(online);
+        }
+        let online = cpus.iter().filter(|&&online| online).count();
+        if online < target {
+            // Bring up the lowest numbered offline cpus
+            for cpu in (0..cpus.len()).filter(|&cpu| !cpus[cpu]).take(target - online) {
+                info!("Bringing cpu{} online", cpu);
+                if let Err(err) = set_cpu_online(cpu, true) {
+                    warn!("Failed to bring cpu{} online: {}", cpu, err);
+                }
+            }
+        } else if online > target {
+            // Take down the highest numbered online cpus, never cpu0
+            for cpu in (1..cpus.len()).rev().filter(|&cpu| cpus[cpu]).take(online - target) {
+                info!("Taking cpu{} offline", cpu);
+                if let Err(err) = set_cpu_online(cpu, false) {
+                    warn!("Failed to take cpu{} offline: {}", cpu, err);
+                }
+            }
+        }
+    }
 
-        if self.cmdline.has_var("phinit.no_x11") {
-            return Ok(());
+    pub fn run_daemons(&mut self) -> Result<()> {
+        if Path::new("/dev/wl0").exists() {
+            chmod("/dev/wl0", 0o666)?;
+            if !self.cmdline.has_var("phinit.no_x11") {
+                mkdir("/tmp/.X11-unix")?;
+                chmod("/tmp/.X11-unix", 0o1777)?;
+                self.write_xauth().map_err(Error::XAuthFail)?;
+            }
         }
 
-        mkdir("/tmp/.X11-unix")?;
-        chmod("/tmp/.X11-unix", 0o1777)?;
-        self.write_xauth().map_err(Error::XAuthFail)?;
+        self.units = unit::load_units();
+        for name in unit::start_order(&self.units) {
+            if !self.units[&name].should_start(&self.cmdline) {
+                continue;
+            }
+            if let Err(err) = self.start_unit(&name) {
+                warn!("Failed to start {}: {}", name, err);
+            }
+        }
+        Ok(())
+    }
 
-        let sommelierx = ServiceLaunch::new("sommelier-x", "/opt/ph/usr/bin/sommelier")
-            .base_environment()
-            .uidgid(1000,1000)
-            .arg("-X")
-            .arg("--x-display=0")
-            .arg("--no-exit-with-child")
-            .arg(format!("--x-auth={}/.Xauthority", self.homedir()))
-            .arg("/bin/true")
-            .pipe_output()
+    fn start_unit(&mut self, name: &str) -> Result<()> {
+        let service = self.units[name].launcher(self.homedir(), &self.cmdline)
             .launch()?;
-
-
-        self.services.insert(sommelierx.pid(), sommelierx);
-
+        self.services.insert(service.pid(), service);
         Ok(())
     }
 
+    fn restart_unit(&mut self, name: &str, status: i32) {
+        let restart = match self.units.get_mut(name) {
+            Some(unit) => unit.should_restart(status),
+            None => false,
+        };
+        if restart {
+            info!("Restarting {}", name);
+            if let Err(err) = self.start_unit(name) {
+                warn!("Failed to restart {}: {}", name, err);
+            }
+        }
+    }
+
     pub fn setup_network(&self) -> Result<()> {
         if let Some(val) = self.cmdline.lookup("phinit.ip") {
             if let Ok(ip) = Ipv4Addr::from_str(&val) {
                 self.configure_network(ip)
                     .map_err(Error::NetworkConfigure)?;
             }
-            sys::bind_mount("/opt/ph/etc/resolv.conf", "/etc/resolv.conf")?;
+            self.setup_dns()?;
         }
         Ok(())
     }
 
+    // Nameservers (phinit.dns) and search domains (phinit.dns_search) set in
+    // the pH configuration replace the matching lines of the host
+    // resolv.conf, everything else in it is kept. The result is written to
+    // /run/resolv.conf and rewritten whenever the host file changes, so that
+    // the guest follows the host moving between networks.
+    fn setup_dns(&self) -> Result<()> {
+        let dns = DnsConfig {
+            servers: self.cmdline.lookup("phinit.dns"),
+            search: self.cmdline.lookup("phinit.dns_search"),
+        };
+        let host = fs::read_to_string(HOST_RESOLV_CONF).unwrap_or_default();
+        let conf = dns.resolv_conf(&host);
+        fs::write(RESOLV_CONF, &conf)
+            .map_err(Error::WriteResolvConf)?;
+        sys::bind_mount(RESOLV_CONF, "/etc/resolv.conf")?;
+
+        if dns.servers.is_some() && dns.search.is_some() {
+            return Ok(());
+        }
+        thread::spawn(move || {
+            let mut last = host;
+            loop {
+                thread::sleep(DNS_WATCH_INTERVAL);
+                let host = match fs::read_to_string(HOST_RESOLV_CONF) {
+                    Ok(host) => host,
+                    Err(_) => continue,
+                };
+                if host == last {
+                    continue;
+                }
+                // Written in place since a rename would leave the bind mount
+                // on the old file
+                info!("Host DNS configuration changed, updating {}", RESOLV_CONF);
+                if let Err(err) = fs::write(RESOLV_CONF, dns.resolv_conf(&host)) {
+                    warn!("Failed to update {}: {}", RESOLV_CONF, err);
+                }
+                last = host;
+            }
+        });
+        Ok(())
+    }
+
+    // phinit.mtu=MTU sets the MTU of eth0 and phinit.vlan=ID puts the
+    // address on the VLAN sub-interface eth0.ID instead of on eth0
     fn configure_network(&self, ip: Ipv4Addr) -> netlink::Result<()> {
         let mut octets = ip.octets();
         octets[3] = 1;
         let gw = Ipv4Addr::from(octets);
         let nl = NetlinkSocket::open()?;
         if !nl.interface_exists("eth0") {
-
+            warn!("No eth0 interface, network is not configured");
+            return Ok(());
+        }
+        if let Some(mtu) = self.cmdline_number::<u32>("phinit.mtu") {
+            nl.set_mtu("eth0", mtu)?;
         }
-        nl.add_ip_address("eth0", ip, 24)?;
-        nl.set_interface_up("eth0")?;
+        let iface = match self.cmdline_number::<u16>("phinit.vlan") {
+            Some(id) => {
+                let name = format!("eth0.{}", id);
+                nl.create_vlan("eth0", &name, id)?;
+                nl.set_interface_up("eth0")?;
+                name
+            }
+            None => "eth0".to_string(),
+        };
+        nl.add_ip_address(&iface, ip, 24)?;
+        nl.set_interface_up(&iface)?;
         nl.add_default_route(gw)?;
         Ok(())
     }
 
+    fn cmdline_number<T: FromStr>(&self, name: &str) -> Option<T> {
+        let val = self.cmdline.lookup(name)?;
+        match val.parse() {
+            Ok(n) => Some(n),
+            Err(_) => {
+                warn!("Invalid {}: {}", name, val);
+                None
+            }
+        }
+    }
+
+    // phinit.machine_id is 32 hex digits chosen by pH for this guest
+    fn write_machine_id(&self) -> Result<()> {
+        let id = match self.cmdline.lookup("phinit.machine_id") {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        if id.len() != 32 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            warn!("Ignoring invalid machine id '{}'", id);
+            return Ok(());
+        }
+        fs::write("/etc/machine-id", format!("{}\n", id.to_ascii_lowercase()))
+            .map_err(Error::WriteMachineId)
+    }
+
+    // phinit.timezone is the name of the timezone of the host, such as
+    // Europe/Berlin. Only zones present in the guest tz database are used.
+    fn set_timezone(&self) {
+        let tz = match self.cmdline.lookup("phinit.timezone") {
+            Some(tz) => tz,
+            None => return,
+        };
+        if tz.starts_with('/') || tz.split('/').any(|part| part.is_empty() || part == "..") {
+            warn!("Ignoring invalid timezone '{}'", tz);
+            return;
+        }
+        let zone = format!("/usr/share/zoneinfo/{}", tz);
+        if !Path::new(&zone).is_file() {
+            warn!("Timezone {} not found in /usr/share/zoneinfo", tz);
+            return;
+        }
+        let _ = fs::remove_file("/etc/localtime");
+        if let Err(err) = std::os::unix::fs::symlink(&zone, "/etc/localtime") {
+            warn!("Failed to set timezone to {}: {}", tz, err);
+        }
+        if let Err(err) = fs::write("/etc/timezone", format!("{}
+", tz)) {
+            warn!("Failed to write /etc/timezone: {}", err);
+        }
+    }
+
     fn write_xauth(&self) -> io::Result<()> {
         let xauth_path = format!("{}/.Xauthority", self.homedir());
 
@@ -281,9 +591,9 @@ impl InitServer {
 
         // ???
         v.extend_from_slice(&[0x01, 0x00]);
-        // "airwolf".len()
-        v.extend_from_slice(&[0x00, 0x07]);
-        v.extend_from_slice(b"airwolf");
+        // hostname length and hostname
+        v.extend_from_slice(&(self.hostname.len() as u16).to_be_bytes());
+        v.extend_from_slice(self.hostname.as_bytes());
         // "0".len() (DISPLAY=:0)
         v.extend_from_slice(&[0x00, 0x01]);
         v.extend_from_slice(b"0");
@@ -305,25 +615,45 @@ impl InitServer {
         let realm = self.cmdline.lookup("phinit.realm");
         let home = if root { "/".to_string() } else { self.homedir().to_string() };
 
-        let shell = ServiceLaunch::new_shell(root, &home, realm)
-            .arg("--rcfile").arg("/run/bashrc")
+        // phinit.run=SCRIPT runs a script instead of an interactive shell
+        let command = self.cmdline.lookup("phinit.run");
+        let shell = ServiceLaunch::new_shell(root, &home, realm);
+        let shell = match &command {
+            Some(script) => shell.arg(script),
+            None => shell.arg("--rcfile").arg("/run/bashrc"),
+        };
+        let interactive = command.is_none();
+        let shell = shell
+            .cgroup(ServiceLimits::load(&self.cmdline, "shell"))
             .launch_with_preexec(move || {
 //                set_controlling_tty(0, true)?;
                 env::set_current_dir(&home)?;
-                println!("{}", splash);
+                if interactive {
+                    println!("{}", splash);
+                }
                 Ok(())
             })?;
         self.services.insert(shell.pid(), shell);
         Ok(())
     }
 
+    pub fn notify_ready(&self) {
+        if let Err(err) = notify_boot_complete() {
+            warn!("Failed to notify pH that boot is complete: {}", err);
+        }
+    }
+
     fn wait_for_next_child(&mut self) -> Result<()> {
-        if let Some(child) = self.wait_for_child() {
+        if let Some((child, status)) = self.wait_for_child() {
             info!("Service exited: {}", child.name());
             if child.name() == "shell" {
+                if let Err(err) = power_off() {
+                    warn!("failed to request shutdown: {}", err);
+                }
                 reboot(libc::RB_AUTOBOOT)
                     .map_err(Error::RebootFailed)?;
             }
+            self.restart_unit(child.name(), status);
         }
         Ok(())
     }
@@ -347,13 +677,38 @@ impl InitServer {
         process::exit(-1);
     }
 
-    fn wait_for_child(&mut self) -> Option<Service> {
+    fn wait_for_child(&mut self) -> Option<(Service, i32)> {
         match waitpid(-1, 0) {
-            Ok((pid,_status)) => self.services.remove(&(pid as u32)),
+            Ok((pid,status)) => self.services.remove(&(pid as u32))
+                .map(|service| (service, status)),
             Err(err) => Self::handle_waitpid_err(err)
         }
     }
 }
+
+// Volume label from the superblock of an ext2/3/4 filesystem on `device`
+fn read_ext4_label(device: &str) -> Option<String> {
+    use std::os::unix::fs::FileExt;
+    const SUPERBLOCK_OFFSET: u64 = 1024;
+    const MAGIC_OFFSET: usize = 0x38;
+    const LABEL_OFFSET: usize = 0x78;
+    const LABEL_LEN: usize = 16;
+    const EXT4_MAGIC: u16 = 0xEF53;
+
+    let file = fs::File::open(device).ok()?;
+    let mut sb = [0u8; LABEL_OFFSET + LABEL_LEN];
+    file.read_exact_at(&mut sb, SUPERBLOCK_OFFSET).ok()?;
+    if u16::from_le_bytes([sb[MAGIC_OFFSET], sb[MAGIC_OFFSET + 1]]) != EXT4_MAGIC {
+        return None;
+    }
+    let label = &sb[LABEL_OFFSET..];
+    let len = label.iter().position(|&b| b == 0).unwrap_or(LABEL_LEN);
+    if len == 0 {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&label[..len]).into_owned())
+}
+
 struct RootFS {
     root: String,
     fstype: String,
@@ -390,3 +745,69 @@ impl RootFS {
             .map_err(|e| Error::RootFsMount(self.root.clone(), e))
     }
 }
+
+// DNS settings passed by pH on the kernel command line as comma separated
+// lists
+struct DnsConfig {
+    servers: Option<String>,
+    search: Option<String>,
+}
+
+impl DnsConfig {
+    fn resolv_conf(&self, host: &str) -> String {
+        let mut conf = String::new();
+        for line in host.lines() {
+            let replaced = match line.split_whitespace().next() {
+                Some("nameserver") => self.servers.is_some(),
+                Some("search") | Some("domain") => self.search.is_some(),
+                _ => false,
+            };
+            if !replaced {
+                conf.push_str(line);
+                conf.push('\n');
+            }
+        }
+        if let Some(ref servers) = self.servers {
+            for server in servers.split(',').filter(|s| !s.is_empty()) {
+                conf.push_str(&format!("nameserver {}\n", server));
+            }
+        }
+        if let Some(ref search) = self.search {
+            let domains = search.split(',').filter(|s| !s.is_empty()).collect::<Vec<_>>();
+            conf.push_str(&format!("search {}\n", domains.join(" ")));
+        }
+        conf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_ext4_label;
+    use std::{env, fs, process};
+
+    // Write an image holding a superblock with `magic` and `label` and read
+    // the label back
+    fn label_of(name: &str, magic: u16, label: &[u8]) -> Option<String> {
+        let path = env::temp_dir().join(format!("ph-init-test-label-{}-{}", name, process::id()));
+        let mut image = vec![0u8; 4096];
+        image[1024 + 0x38..1024 + 0x3a].copy_from_slice(&magic.to_le_bytes());
+        image[1024 + 0x78..1024 + 0x78 + label.len()].copy_from_slice(label);
+        fs::write(&path, &image).unwrap();
+        let result = read_ext4_label(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn ext4_label() {
+        assert_eq!(label_of("label", 0xEF53, b"home"), Some("home".to_string()));
+        assert_eq!(label_of("full", 0xEF53, b"0123456789abcdef"), Some("0123456789abcdef".to_string()));
+    }
+
+    #[test]
+    fn no_ext4_label() {
+        assert_eq!(label_of("empty", 0xEF53, b""), None);
+        assert_eq!(label_of("magic", 0x1234, b"home"), None);
+        assert_eq!(read_ext4_label("/nonexistent/ph-init-test"), None);
+    }
+}
@@ -0,0 +1,98 @@
+use std::fs;
+
+// Guest physical address layout written to the boot filesystem by pH
+pub const LAYOUT_FILE: &str = "/opt/ph/etc/ph-init/layout";
+
+const PROC_IOMEM: &str = "/proc/iomem";
+
+///
+/// A named range of guest physical addresses from the layout file, such as
+/// guest RAM, the PCI window or the window where the wayland device maps
+/// shared memory. Each line of the file describes one region:
+///
+///     device_shm device_shm 0x100000000 0x80000000
+///
+struct Region {
+    name: String,
+    kind: String,
+    base: u64,
+    size: u64,
+}
+
+impl Region {
+    fn parse(line: &str) -> Option<Region> {
+        let mut fields = line.split_whitespace();
+        let name = fields.next()?.to_string();
+        let kind = fields.next()?.to_string();
+        let base = parse_hex(fields.next()?)?;
+        let size = parse_hex(fields.next()?)?;
+        Some(Region { name, kind, base, size })
+    }
+
+    fn end(&self) -> u64 {
+        self.base.saturating_add(self.size)
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.base < end && start < self.end()
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+fn load_layout() -> Option<Vec<Region>> {
+    let s = fs::read_to_string(LAYOUT_FILE).ok()?;
+    Some(s.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(Region::parse)
+        .collect())
+}
+
+// Top level 'System RAM' ranges of /proc/iomem as (start, end) with an
+// exclusive end. Lines look like '00100000-0fffffff : System RAM' and
+// nested resources are indented.
+fn system_ram() -> Vec<(u64, u64)> {
+    let s = match fs::read_to_string(PROC_IOMEM) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("failed to read {}: {}", PROC_IOMEM, e);
+            return Vec::new();
+        }
+    };
+    s.lines()
+        .filter(|line| !line.starts_with(' '))
+        .filter_map(|line| {
+            let (range, name) = line.split_once(" : ")?;
+            if name != "System RAM" {
+                return None;
+            }
+            let (start, end) = range.split_once('-')?;
+            Some((parse_hex(start)?, parse_hex(end)?.saturating_add(1)))
+        })
+        .collect()
+}
+
+///
+/// Compare the address layout pH describes with the RAM the guest kernel
+/// is using and warn about any region besides RAM which the kernel treats
+/// as RAM. Such a conflict means the memory map given to the kernel does
+/// not match the layout and devices mapping memory into the region would
+/// overwrite guest pages.
+///
+pub fn check_layout() {
+    let regions = match load_layout() {
+        Some(regions) => regions,
+        None => return,
+    };
+    let ram = system_ram();
+    for r in regions.iter().filter(|r| r.kind != "ram") {
+        verbose!("address layout: {} ({}) 0x{:x}-0x{:x}", r.name, r.kind, r.base, r.end());
+        if let Some(&(start, end)) = ram.iter().find(|&&(start, end)| r.overlaps(start, end)) {
+            warn!("{} region {} at 0x{:x}-0x{:x} overlaps System RAM at 0x{:x}-0x{:x}",
+                  r.kind, r.name, r.base, r.end(), start, end);
+        }
+    }
+}
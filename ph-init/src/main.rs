@@ -6,8 +6,11 @@ mod audio;
 mod log;
 mod error;
 mod cmdline;
+mod cgroup;
 mod service;
+mod unit;
 mod init;
+mod layout;
 mod sys;
 mod netlink;
 
@@ -16,11 +19,15 @@ pub use log::{Logger,LogLevel};
 use crate::init::InitServer;
 
 fn run_init() -> Result<()> {
-    let mut server = InitServer::create("airwolf")?;
+    let mut server = InitServer::create()?;
     server.setup_filesystem()?;
+    server.watch_home();
+    server.watch_cpus();
+    server.report_memory();
     server.run_daemons()?;
     server.setup_network()?;
     server.launch_console_shell(SPLASH)?;
+    server.notify_ready();
     server.run()?;
     Ok(())
 }
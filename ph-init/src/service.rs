@@ -3,9 +3,10 @@ use std::os::unix::process::CommandExt;
 use std::path::{PathBuf, Path};
 
 use crate::{Result, Error};
-use std::{io, thread, env};
+use std::{fs, io, thread, env};
 use crate::sys::_setsid;
-use std::io::{Read, BufReader, BufRead};
+use crate::cgroup::{ServiceCGroup, ServiceLimits};
+use std::io::{Read, BufReader, BufRead, Write};
 use std::thread::JoinHandle;
 
 #[derive(PartialEq)]
@@ -114,6 +115,7 @@ pub struct ServiceLaunch {
     uid: u32,
     gid: u32,
     stdio: StdioMode,
+    cgroup: Option<ServiceLimits>,
 }
 
 impl ServiceLaunch {
@@ -129,6 +131,7 @@ impl ServiceLaunch {
             uid: 0,
             gid: 0,
             stdio: StdioMode::InheritAll,
+            cgroup: None,
         }
     }
 
@@ -208,6 +211,25 @@ impl ServiceLaunch {
         self
     }
 
+    /// Run the service in its own cgroup named after the service, with
+    /// `limits` applied to it.
+    pub fn cgroup(mut self, limits: ServiceLimits) -> Self {
+        self.cgroup = Some(limits);
+        self
+    }
+
+    // Failing to set up the cgroup is not fatal, the service runs without it
+    fn open_cgroup_procs(&self) -> Option<fs::File> {
+        let limits = self.cgroup.as_ref()?;
+        match ServiceCGroup::create(&self.name, limits).and_then(|cg| cg.open_procs()) {
+            Ok(procs) => Some(procs),
+            Err(err) => {
+                warn!("Failed to create cgroup for {}: {}", self.name, err);
+                None
+            }
+        }
+    }
+
     fn output_stdio(&self) -> Stdio {
         match self.stdio {
             StdioMode::InheritAll => Stdio::inherit(),
@@ -224,10 +246,17 @@ impl ServiceLaunch {
         })
     }
 
-    pub fn launch_with_preexec<F>(self, f: F) -> Result<Service>
+    pub fn launch_with_preexec<F>(self, mut f: F) -> Result<Service>
         where F: FnMut() -> io::Result<()> + Sync + Send + 'static
     {
         info!("Starting: {}", self.name);
+        let procs = self.open_cgroup_procs();
+        let f = move || {
+            if let Some(procs) = &procs {
+                (&*procs).write_all(b"0")?;
+            }
+            f()
+        };
         unsafe {
             let child = Command::new(&self.exec)
                 .stdout(self.output_stdio())
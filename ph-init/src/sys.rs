@@ -1,4 +1,5 @@
-use std::io;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::fs::{self, File, OpenOptions};
 use std::ptr;
 use std::ffi::{CString, OsStr};
 use std::os::unix::ffi::OsStrExt;
@@ -13,15 +14,24 @@ pub fn set_umask(mode: u32) {
     }
 }
 
-pub fn mount_tmpfs(target: &str) -> Result<()> {
-    mount("tmpfs", target, "tmpfs", 0, Some("mode=755"))
+// Mount options for a tmpfs, limited to `size` (bytes with an optional k, m
+// or g suffix, or a percentage of RAM) if given
+fn tmpfs_options(mode: &str, size: Option<&str>) -> String {
+    match size {
+        Some(size) => format!("mode={},size={}", mode, size),
+        None => format!("mode={}", mode),
+    }
+}
+
+pub fn mount_tmpfs(target: &str, size: Option<&str>) -> Result<()> {
+    mount("tmpfs", target, "tmpfs", 0, Some(&tmpfs_options("755", size)))
         .map_err(|e| Error::MountTmpFS(target.to_string(), e))
 }
 
-pub fn mount_tmpdir(target: &str) -> Result<()> {
+pub fn mount_tmpdir(target: &str, size: Option<&str>) -> Result<()> {
     mount("tmpfs", target, "tmpfs",
           libc::MS_NOSUID|libc::MS_NODEV|libc::MS_NOEXEC,
-          Some("mode=1777"))
+          Some(&tmpfs_options("1777", size)))
         .map_err(|e| Error::MountTmpFS(target.to_string(), e))
 }
 
@@ -40,7 +50,7 @@ pub fn mount_sysfs() -> Result<()> {
 }
 
 pub fn mount_cgroup() -> Result<()> {
-    mount("cgroup", "/sys/fs/cgroup", "cgroup",
+    mount("cgroup2", "/sys/fs/cgroup", "cgroup2",
           libc::MS_NOSUID|libc::MS_NODEV|libc::MS_NOEXEC,
           None)
         .map_err(Error::MountCGroup)
@@ -194,6 +204,30 @@ pub fn umount(path: &str) -> Result<()> {
     Ok(())
 }
 
+// Detach the mount at `path` even if files on it are still in use
+pub fn umount_lazy(path: &str) -> Result<()> {
+    let _path = cstr(path);
+    unsafe {
+        if libc::umount2(_path.as_ptr(), libc::MNT_DETACH) == -1 {
+            let last = io::Error::last_os_error();
+            return Err(Error::Umount(path.to_string(), last))
+        }
+    }
+    Ok(())
+}
+
+// Unlike stat() this always asks a 9p server, even with cache=loose
+pub fn statfs(path: &str) -> io::Result<()> {
+    let _path = cstr(path);
+    unsafe {
+        let mut buf: libc::statfs = std::mem::zeroed();
+        if libc::statfs(_path.as_ptr(), &mut buf) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
 pub fn set_controlling_tty(fd: libc::c_int, force: bool) -> Result<()> {
     let flag: libc::c_int = if force { 1 } else { 0 };
     unsafe {
@@ -252,4 +286,114 @@ pub fn reboot(cmd: libc::c_int) -> io::Result<()> {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// pH stops the VM as a clean shutdown when this port is written. Without it a
+// reboot() would look to pH the same as a guest kernel reset or panic.
+const PH_SHUTDOWN_PORT: u64 = 0x501;
+
+pub fn power_off() -> io::Result<()> {
+    let mut port = OpenOptions::new().write(true).open("/dev/port")?;
+    port.seek(SeekFrom::Start(PH_SHUTDOWN_PORT))?;
+    port.write_all(&[0])
+}
+
+const ZRAM_DEVICE: &str = "/dev/zram0";
+const SWAP_SIGNATURE: &[u8] = b"SWAPSPACE2";
+
+///
+/// Use the first zram device as compressed swap of `size` bytes. Pages the
+/// guest swaps out stay in guest RAM, but compressed.
+///
+pub fn setup_zram_swap(size: u64) -> io::Result<()> {
+    fs::write("/sys/block/zram0/disksize", size.to_string())?;
+
+    // Version 1 swap header, see union swap_header in linux/swap.h
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+    let last_page = (size / page_size as u64).saturating_sub(1) as u32;
+    let mut header = vec![0u8; page_size];
+    header[1024..1028].copy_from_slice(&1u32.to_ne_bytes());
+    header[1028..1032].copy_from_slice(&last_page.to_ne_bytes());
+    header[page_size - SWAP_SIGNATURE.len()..].copy_from_slice(SWAP_SIGNATURE);
+    let mut device = OpenOptions::new().write(true).open(ZRAM_DEVICE)?;
+    device.write_all(&header)?;
+    device.sync_all()?;
+
+    let path = cstr(ZRAM_DEVICE);
+    unsafe {
+        if libc::swapon(path.as_ptr(), 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Written once the services and the shell have been started, so that pH can
+// report the guest as ready to use
+const PH_BOOT_COMPLETE_PORT: u64 = 0x503;
+
+pub fn notify_boot_complete() -> io::Result<()> {
+    let mut port = OpenOptions::new().write(true).open("/dev/port")?;
+    port.seek(SeekFrom::Start(PH_BOOT_COMPLETE_PORT))?;
+    port.write_all(&[1])
+}
+
+// The memory usage of the guest is written to the 40 ports starting here, as
+// five little endian u64 values
+const PH_MEMORY_STATS_PORT: u64 = 0x504;
+
+// Fields of /proc/meminfo reported to pH, in the order pH expects them
+const MEMINFO_FIELDS: [&str; 5] = ["MemTotal", "MemFree", "MemAvailable", "SwapTotal", "SwapFree"];
+
+///
+/// Send the total, free and available memory and the total and free swap
+/// from /proc/meminfo to pH, in bytes.
+///
+pub fn report_memory_stats() -> io::Result<()> {
+    let meminfo = fs::read_to_string("/proc/meminfo")?;
+    let mut report = Vec::with_capacity(MEMINFO_FIELDS.len() * 8);
+    for field in MEMINFO_FIELDS.iter() {
+        let kb = meminfo_value(&meminfo, field).unwrap_or(0);
+        report.extend_from_slice(&(kb * 1024).to_le_bytes());
+    }
+    let mut port = OpenOptions::new().write(true).open("/dev/port")?;
+    port.seek(SeekFrom::Start(PH_MEMORY_STATS_PORT))?;
+    port.write_all(&report)
+}
+
+// Value in kB of a line such as "MemFree:         1234 kB"
+fn meminfo_value(meminfo: &str, field: &str) -> Option<u64> {
+    meminfo.lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|kb| kb.parse().ok())
+}
+
+// Reading this port returns the number of cpus pH asks the guest to keep online
+const PH_CPU_HOTPLUG_PORT: u64 = 0x502;
+
+pub fn cpu_hotplug_target() -> io::Result<usize> {
+    let mut port = File::open("/dev/port")?;
+    port.seek(SeekFrom::Start(PH_CPU_HOTPLUG_PORT))?;
+    let mut target = [0u8];
+    port.read_exact(&mut target)?;
+    Ok(target[0] as usize)
+}
+
+pub fn set_cpu_online(cpu: usize, online: bool) -> io::Result<()> {
+    let path = format!("/sys/devices/system/cpu/cpu{}/online", cpu);
+    fs::write(path, if online { "1" } else { "0" })
+}
+
+/// Whether `cpu` is online, or `None` if there is no such cpu. cpu0 cannot be
+/// taken offline and has no online attribute.
+pub fn cpu_online(cpu: usize) -> Option<bool> {
+    let dir = format!("/sys/devices/system/cpu/cpu{}", cpu);
+    if !Path::new(&dir).exists() {
+        return None;
+    }
+    match fs::read_to_string(format!("{}/online", dir)) {
+        Ok(s) => Some(s.trim() != "0"),
+        Err(_) => Some(true),
+    }
+}
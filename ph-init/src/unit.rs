@@ -0,0 +1,484 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+use std::{fs, result};
+
+use crate::{Error, Result};
+use crate::cgroup::ServiceLimits;
+use crate::cmdline::CmdLine;
+use crate::service::ServiceLaunch;
+
+// Service definitions written to the boot filesystem by pH
+pub const SERVICES_DIR: &str = "/opt/ph/etc/ph-init/services.d";
+
+// Give up on restarting a service after it has exited this many times
+const MAX_RESTARTS: usize = 5;
+
+// A parse error with the line number where it occurred
+type ParseResult<T> = result::Result<T, (usize, String)>;
+
+#[derive(Copy,Clone,PartialEq)]
+pub enum RestartPolicy {
+    No,
+    OnFailure,
+    Always,
+}
+
+///
+/// A service launched by ph-init, described by a file `NAME.toml` in
+/// `SERVICES_DIR`. The file uses a small subset of TOML:
+///
+///     exec = "/opt/ph/usr/bin/sommelier"          # required
+///     args = ["--parent"]
+///     environment = ["HOME=${HOME}"]              # ${HOME} is the home directory
+///     user = "user"                               # or "root"
+///     after = ["dbus-daemon"]                     # services started first
+///     restart = "on-failure"                      # "no" (default) or "always"
+///     condition_path_exists = "/dev/wl0"          # only start if the path exists
+///     condition_cmdline_unset = "phinit.no_x11"   # only start without this variable
+///
+pub struct ServiceUnit {
+    name: String,
+    exec: String,
+    args: Vec<String>,
+    env: Vec<String>,
+    root: bool,
+    after: Vec<String>,
+    restart: RestartPolicy,
+    condition_path: Option<String>,
+    condition_cmdline_unset: Option<String>,
+    restarts: usize,
+}
+
+impl ServiceUnit {
+    fn new(name: &str) -> Self {
+        ServiceUnit {
+            name: name.to_string(),
+            exec: String::new(),
+            args: Vec::new(),
+            env: Vec::new(),
+            root: false,
+            after: Vec::new(),
+            restart: RestartPolicy::No,
+            condition_path: None,
+            condition_cmdline_unset: None,
+            restarts: 0,
+        }
+    }
+
+    fn load(path: &Path, name: &str) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::ReadServiceUnit(path.display().to_string(), e))?;
+        Self::parse(name, &text)
+            .map_err(|(line, msg)| Error::ServiceUnit(path.display().to_string(), line, msg))
+    }
+
+    fn parse(name: &str, text: &str) -> ParseResult<Self> {
+        let mut unit = ServiceUnit::new(name);
+        for (line, key, value) in parse_toml(text)? {
+            let err = |msg: &str| (line, format!("{}: {}", key, msg));
+            match key.as_str() {
+                "exec" => unit.exec = value.string().ok_or_else(|| err("expected a string"))?,
+                "args" => unit.args = value.array().ok_or_else(|| err("expected an array of strings"))?,
+                "environment" => unit.env = value.array().ok_or_else(|| err("expected an array of strings"))?,
+                "after" => unit.after = value.array().ok_or_else(|| err("expected an array of strings"))?,
+                "user" => unit.root = match value.string().as_deref() {
+                    Some("user") => false,
+                    Some("root") => true,
+                    _ => return Err(err("expected \"user\" or \"root\"")),
+                },
+                "restart" => unit.restart = match value.string().as_deref() {
+                    Some("no") => RestartPolicy::No,
+                    Some("on-failure") => RestartPolicy::OnFailure,
+                    Some("always") => RestartPolicy::Always,
+                    _ => return Err(err("expected \"no\", \"on-failure\" or \"always\"")),
+                },
+                "condition_path_exists" => unit.condition_path = Some(value.string().ok_or_else(|| err("expected a string"))?),
+                "condition_cmdline_unset" => unit.condition_cmdline_unset = Some(value.string().ok_or_else(|| err("expected a string"))?),
+                _ => return Err(err("unknown key")),
+            }
+        }
+        if unit.exec.is_empty() {
+            return Err((0, "no exec path".to_string()));
+        }
+        Ok(unit)
+    }
+
+    /// Whether the conditions for starting the service hold
+    pub fn should_start(&self, cmdline: &CmdLine) -> bool {
+        if let Some(path) = &self.condition_path {
+            if !Path::new(path).exists() {
+                return false;
+            }
+        }
+        match &self.condition_cmdline_unset {
+            Some(var) => !cmdline.has_var(var),
+            None => true,
+        }
+    }
+
+    /// Whether the service should be started again after exiting with
+    /// `status` as returned by waitpid(). Counts the restart if so.
+    pub fn should_restart(&mut self, status: i32) -> bool {
+        // Zero only for a normal exit with exit code 0
+        let failed = status != 0;
+        let restart = match self.restart {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Always => true,
+        };
+        if !restart {
+            return false;
+        }
+        if self.restarts == MAX_RESTARTS {
+            warn!("{} exited {} times, not restarting it again", self.name, MAX_RESTARTS);
+            return false;
+        }
+        self.restarts += 1;
+        true
+    }
+
+    pub fn launcher(&self, home: &str, cmdline: &CmdLine) -> ServiceLaunch {
+        let expand = |s: &String| s.replace("${HOME}", home);
+        let args: Vec<String> = self.args.iter().map(expand).collect();
+        let env: Vec<String> = self.env.iter().map(expand).collect();
+        let mut launch = ServiceLaunch::new(&self.name, &self.exec)
+            .base_environment()
+            .root(self.root)
+            .env_list(&env)
+            .cgroup(ServiceLimits::load(cmdline, &self.name))
+            .pipe_output();
+        for arg in args {
+            launch = launch.arg(arg);
+        }
+        launch
+    }
+}
+
+/// Load every service definition in `SERVICES_DIR`. Definitions which
+/// cannot be read are logged and skipped.
+pub fn load_units() -> BTreeMap<String, ServiceUnit> {
+    let mut units = BTreeMap::new();
+    let entries = match fs::read_dir(SERVICES_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return units,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+            continue;
+        }
+        let name = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        match ServiceUnit::load(&path, &name) {
+            Ok(unit) => { units.insert(name, unit); }
+            Err(err) => warn!("{}", err),
+        }
+    }
+    units
+}
+
+/// Names of `units` ordered so that each service comes after the services
+/// listed in its `after` key. Unknown services and dependency cycles are
+/// logged and otherwise ignored.
+pub fn start_order(units: &BTreeMap<String, ServiceUnit>) -> Vec<String> {
+    let mut order = Vec::new();
+    let mut done = BTreeSet::new();
+    let mut visiting = BTreeSet::new();
+
+    fn visit<'a>(name: &'a str, units: &'a BTreeMap<String, ServiceUnit>, order: &mut Vec<String>,
+                 done: &mut BTreeSet<&'a str>, visiting: &mut BTreeSet<&'a str>) {
+        if done.contains(name) {
+            return;
+        }
+        if !visiting.insert(name) {
+            warn!("Service dependency cycle involving {}", name);
+            return;
+        }
+        for dep in &units[name].after {
+            if units.contains_key(dep) {
+                visit(dep, units, order, done, visiting);
+            } else {
+                warn!("Service {} is ordered after unknown service {}", name, dep);
+            }
+        }
+        visiting.remove(name);
+        done.insert(name);
+        order.push(name.to_string());
+    }
+
+    for name in units.keys() {
+        visit(name, units, &mut order, &mut done, &mut visiting);
+    }
+    order
+}
+
+enum Value {
+    String(String),
+    Array(Vec<String>),
+}
+
+impl Value {
+    fn string(self) -> Option<String> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn array(self) -> Option<Vec<String>> {
+        match self {
+            Value::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+// Parse `key = value` lines where a value is a string or an array of
+// strings which may span lines. Returns the line number of each key.
+fn parse_toml(text: &str) -> ParseResult<Vec<(usize, String, Value)>> {
+    let mut entries = Vec::new();
+    let mut lines = text.lines().enumerate().map(|(n, line)| (n + 1, strip_comment(line)));
+    while let Some((n, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err((n, format!("table header {} is not supported, keys belong at the top level", line)));
+        }
+        let (key, value) = line.split_once('=')
+            .ok_or((n, "expected key = value".to_string()))?;
+        let key = key.trim().to_string();
+        let mut value = value.trim().to_string();
+        if value.starts_with('[') {
+            while !value.ends_with(']') {
+                match lines.next() {
+                    Some((_, next)) => { value.push(' '); value.push_str(next.trim()); }
+                    None => return Err((n, format!("{}: unterminated array", key))),
+                }
+            }
+        }
+        let value = parse_value(&value).map_err(|msg| (n, format!("{}: {}", key, msg)))?;
+        entries.push((n, key, value));
+    }
+    Ok(entries)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {},
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..i],
+            None => {},
+        }
+    }
+    line
+}
+
+fn parse_value(s: &str) -> result::Result<Value, String> {
+    let mut chars = s.chars().peekable();
+    let value = if s.starts_with('[') {
+        chars.next();
+        let mut items = Vec::new();
+        loop {
+            skip_space(&mut chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                break;
+            }
+            items.push(parse_string(&mut chars)?);
+            skip_space(&mut chars);
+            match chars.next() {
+                Some(',') => {},
+                Some(']') => break,
+                _ => return Err("expected ',' or ']' in array".to_string()),
+            }
+        }
+        Value::Array(items)
+    } else {
+        Value::String(parse_string(&mut chars)?)
+    };
+    skip_space(&mut chars);
+    if chars.next().is_some() {
+        return Err("unexpected characters after value".to_string());
+    }
+    Ok(value)
+}
+
+fn skip_space(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> result::Result<String, String> {
+    let quote = match chars.next() {
+        Some(q) if q == '"' || q == '\'' => q,
+        _ => return Err("expected a quoted string".to_string()),
+    };
+    let mut s = String::new();
+    while let Some(c) = chars.next() {
+        if c == quote {
+            return Ok(s);
+        }
+        if c == '\\' && quote == '"' {
+            match chars.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some('"') => s.push('"'),
+                Some('\\') => s.push('\\'),
+                _ => return Err("invalid escape in string".to_string()),
+            }
+        } else {
+            s.push(c);
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> ParseResult<ServiceUnit> {
+        ServiceUnit::parse("test", text)
+    }
+
+    fn parse_err(text: &str) -> (usize, String) {
+        match parse(text) {
+            Ok(_) => panic!("parsed invalid unit: {:?}", text),
+            Err(err) => err,
+        }
+    }
+
+    fn units(list: &[(&str, &str)]) -> BTreeMap<String, ServiceUnit> {
+        list.iter()
+            .map(|&(name, text)| (name.to_string(), ServiceUnit::parse(name, text).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn multi_line_array() {
+        let unit = parse(concat!(
+            "exec = \"/bin/true\"\n",
+            "args = [\n",
+            "    \"--one\",   # first\n",
+            "\n",
+            "    \"--two\",\n",
+            "]\n",
+            "after = [ \"a\", \"b\" ]\n",
+        )).unwrap();
+        assert_eq!(unit.args, ["--one", "--two"]);
+        assert_eq!(unit.after, ["a", "b"]);
+    }
+
+    #[test]
+    fn comment_character_in_strings() {
+        let unit = parse(concat!(
+            "exec = \"/bin/a#b\" # comment\n",
+            "args = [\"#x\", '#y', \"\\\"#z\"]\n",
+        )).unwrap();
+        assert_eq!(unit.exec, "/bin/a#b");
+        assert_eq!(unit.args, ["#x", "#y", "\"#z"]);
+    }
+
+    #[test]
+    fn escapes() {
+        let unit = parse(r#"exec = "a\"b\\c\td\ne"
+args = ['a\b']"#).unwrap();
+        assert_eq!(unit.exec, "a\"b\\c\td\ne");
+        assert_eq!(unit.args, ["a\\b"]);
+
+        let (line, msg) = parse_err("exec = \"/bin/true\"\nargs = [\"\\x\"]");
+        assert_eq!((line, msg.as_str()), (2, "args: invalid escape in string"));
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let (line, msg) = parse_err("exec = \"/bin/true");
+        assert_eq!((line, msg.as_str()), (1, "exec: unterminated string"));
+
+        let (line, msg) = parse_err("exec = \"/bin/true\"\nargs = [\"a]");
+        assert_eq!((line, msg.as_str()), (2, "args: unterminated string"));
+    }
+
+    #[test]
+    fn unterminated_array() {
+        let (line, msg) = parse_err("args = [\"a\",\n\"b\",\nexec = \"/bin/true\"\n");
+        assert_eq!((line, msg.as_str()), (1, "args: unterminated array"));
+
+        let (line, msg) = parse_err("exec = \"/bin/true\"\nargs = [\"a\" \"b\"]");
+        assert_eq!((line, msg.as_str()), (2, "args: expected ',' or ']' in array"));
+    }
+
+    #[test]
+    fn unknown_key() {
+        let (line, msg) = parse_err("exec = \"/bin/true\"\nuser = \"root\"\nwhat = \"x\"");
+        assert_eq!((line, msg.as_str()), (3, "what: unknown key"));
+    }
+
+    #[test]
+    fn missing_exec() {
+        let (line, msg) = parse_err("args = [\"a\"]\n");
+        assert_eq!((line, msg.as_str()), (0, "no exec path"));
+    }
+
+    #[test]
+    fn table_header() {
+        let (line, msg) = parse_err("[service]\nexec = \"/bin/true\"");
+        assert_eq!(line, 1);
+        assert!(msg.starts_with("table header [service] is not supported"), "{}", msg);
+    }
+
+    #[test]
+    fn wrong_value_types() {
+        let (_, msg) = parse_err("exec = [\"/bin/true\"]");
+        assert_eq!(msg, "exec: expected a string");
+        let (_, msg) = parse_err("exec = \"/bin/true\"\nargs = \"a\"");
+        assert_eq!(msg, "args: expected an array of strings");
+        let (_, msg) = parse_err("exec = \"/bin/true\"\nrestart = \"sometimes\"");
+        assert_eq!(msg, "restart: expected \"no\", \"on-failure\" or \"always\"");
+    }
+
+    #[test]
+    fn start_order_follows_after() {
+        let units = units(&[
+            ("a", "exec = \"/a\"\nafter = [\"c\"]"),
+            ("b", "exec = \"/b\""),
+            ("c", "exec = \"/c\"\nafter = [\"b\"]"),
+        ]);
+        assert_eq!(start_order(&units), ["b", "c", "a"]);
+    }
+
+    #[test]
+    fn start_order_unknown_after() {
+        let units = units(&[
+            ("a", "exec = \"/a\"\nafter = [\"missing\", \"b\"]"),
+            ("b", "exec = \"/b\""),
+        ]);
+        assert_eq!(start_order(&units), ["b", "a"]);
+    }
+
+    #[test]
+    fn start_order_cycle() {
+        let units = units(&[
+            ("a", "exec = \"/a\"\nafter = [\"b\"]"),
+            ("b", "exec = \"/b\"\nafter = [\"c\"]"),
+            ("c", "exec = \"/c\"\nafter = [\"a\"]"),
+            ("d", "exec = \"/d\"\nafter = [\"a\"]"),
+        ]);
+        // Every service is started exactly once and the cycle is broken
+        // where it was found
+        assert_eq!(start_order(&units), ["c", "b", "a", "d"]);
+    }
+}
@@ -1,6 +1,7 @@
 use std::{error, fmt};
 use std::fmt::Display;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use thiserror::Error;
 
 pub mod shm_streams;
@@ -64,6 +65,37 @@ pub enum StreamDirection {
     Capture,
 }
 
+/// Buffering requested from the audio server for playback streams, in
+/// milliseconds of audio. `None` leaves the choice to the server. Smaller values
+/// reduce latency at the cost of more frequent underruns.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AudioLatency {
+    /// Target amount of audio buffered by the server (pulseaudio `tlength`).
+    pub target_ms: Option<u32>,
+    /// Minimum amount of audio requested from the guest at once (pulseaudio `minreq`).
+    pub min_request_ms: Option<u32>,
+}
+
+/// Re-establishes the connection to the audio server while the VM runs, for
+/// example after the server was restarted with the desktop session. Streams
+/// already playing keep their old connection until the guest closes them.
+#[derive(Debug, Default)]
+pub struct AudioControl {
+    reconnect: AtomicBool,
+}
+
+impl AudioControl {
+    /// Drop the connection to the server, the next stream the guest opens
+    /// connects again.
+    pub fn request_reconnect(&self) {
+        self.reconnect.store(true, Ordering::SeqCst);
+    }
+
+    fn take_reconnect(&self) -> bool {
+        self.reconnect.swap(false, Ordering::SeqCst)
+    }
+}
+
 /// Valid effects for an audio stream.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum StreamEffect {
@@ -1,44 +1,112 @@
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::thread;
 use pulse::sample::{Format, Spec};
 use vm_memory::GuestMemoryMmap;
 use crate::audio::pulse::context::PulseContext;
 use crate::audio::pulse::message::PulseMessageChannel;
-use crate::audio::pulse::Result;
-use crate::audio::{SampleFormat, StreamDirection};
+use crate::audio::pulse::{PulseError, Result};
+use crate::audio::{AudioControl, AudioLatency, SampleFormat, StreamDirection, StreamEffect};
 use crate::audio::shm_streams::{GenericResult, NullShmStream, ShmStream, ShmStreamSource};
+use crate::io::stats::{Counter, DeviceStats};
+use crate::system::sched::ThreadPriority;
 
+///
+/// Creates guest audio streams on a pulseaudio server.
+///
+/// The connection to the server is made when the guest first starts a stream
+/// rather than when the VM is created, and is attempted again for later
+/// streams if it fails. A VM started outside of a desktop session therefore
+/// gains audio once a server is available. Until then streams are created
+/// which discard playback and record silence.
+///
 pub struct PulseClient {
-    channel: PulseMessageChannel,
+    guest_memory: GuestMemoryMmap,
+    latency: AudioLatency,
+    underruns: Arc<Counter>,
+    overruns: Arc<Counter>,
+    channel: Option<PulseMessageChannel>,
+    thread_priority: Option<ThreadPriority>,
+    control: Arc<AudioControl>,
 }
 
 impl PulseClient {
-    pub fn connect(guest_memory: &GuestMemoryMmap) -> Result<Self> {
+    /// Playback underruns and capture overruns are counted in `stats`.
+    pub fn new(guest_memory: &GuestMemoryMmap, latency: AudioLatency, stats: &DeviceStats) -> Self {
+        PulseClient {
+            guest_memory: guest_memory.clone(),
+            latency,
+            underruns: stats.counter("underruns"),
+            overruns: stats.counter("overruns"),
+            channel: None,
+            thread_priority: None,
+            control: Arc::new(AudioControl::default()),
+        }
+    }
+
+    pub fn audio_control(&self) -> Arc<AudioControl> {
+        self.control.clone()
+    }
+
+    /// Schedule the thread which runs the connection to the server with
+    /// `priority`. Takes effect when the server is next connected.
+    pub fn set_thread_priority(&mut self, priority: Option<ThreadPriority>) {
+        self.thread_priority = priority;
+    }
+
+    fn connect(&self) -> Result<PulseMessageChannel> {
         let (tx,rx) = mpsc::channel();
+        let (result_tx, result_rx) = mpsc::channel();
 
         let _ = thread::spawn({
-            let guest_memory = guest_memory.clone();
+            let guest_memory = self.guest_memory.clone();
+            let latency = self.latency;
+            let underruns = self.underruns.clone();
+            let overruns = self.overruns.clone();
+            let priority = self.thread_priority;
             move || {
-                let mut ctx = PulseContext::new(guest_memory);
-                if let Err(err) = ctx.connect() {
-                    warn!("PulseAudio Error: {}", err);
-                } else {
-                    ctx.run(rx);
+                if let Some(priority) = priority {
+                    if let Err(e) = priority.apply() {
+                        warn!("Failed to set audio server thread to {}: {}", priority, e);
+                    }
+                }
+                let mut ctx = PulseContext::new(guest_memory, latency, underruns, overruns);
+                match ctx.connect() {
+                    Ok(()) => {
+                        let _ = result_tx.send(Ok(()));
+                        ctx.run(rx);
+                    }
+                    Err(err) => {
+                        let _ = result_tx.send(Err(err));
+                    }
                 }
             }
         });
-        Ok(PulseClient {
-            channel: PulseMessageChannel::new(tx),
-        })
+        result_rx.recv().map_err(|_| PulseError::RecvMessageFailed)??;
+        Ok(PulseMessageChannel::new(tx))
     }
 
-
+    fn channel(&mut self) -> Option<PulseMessageChannel> {
+        if self.control.take_reconnect() && self.channel.take().is_some() {
+            notify!("PulseAudio: reconnecting as requested");
+        }
+        if self.channel.is_none() {
+            match self.connect() {
+                Ok(channel) => {
+                    notify!("Connected to PulseAudio");
+                    self.channel = Some(channel);
+                }
+                Err(err) => warn!("PulseAudio: failed to connect, audio is disabled until the next stream: {}", err),
+            }
+        }
+        self.channel.clone()
+    }
 
     fn create_spec(num_channels: usize, format: SampleFormat, frame_rate: u32) -> Spec {
         let format = match format {
             SampleFormat::U8 => Format::U8,
             SampleFormat::S16LE => Format::S16le,
-            SampleFormat::S24LE => Format::S24le,
+            // Stored in 4 bytes like ALSA S24_LE, not packed
+            SampleFormat::S24LE => Format::S24_32le,
             SampleFormat::S32LE => Format::S32le,
         };
 
@@ -56,14 +124,33 @@ impl ShmStreamSource for PulseClient {
                   num_channels: usize,
                   format: SampleFormat,
                   frame_rate: u32,
-                  buffer_size: usize)-> GenericResult<Box<dyn ShmStream>> {
+                  buffer_size: usize,
+                  effects: &[StreamEffect])-> GenericResult<Box<dyn ShmStream>> {
 
-        if direction != StreamDirection::Playback {
-            let stream = NullShmStream::new(buffer_size, num_channels, format, frame_rate);
-            return Ok(Box::new(stream))
-        }
         let spec = PulseClient::create_spec(num_channels, format, frame_rate);
-        let stream = self.channel.send_new_playback_stream(spec,  buffer_size, self.channel.clone())?;
-        Ok(Box::new(stream))
+        let channel = match self.channel() {
+            Some(channel) => channel,
+            None => return Ok(Box::new(NullShmStream::new(buffer_size, num_channels, format, frame_rate))),
+        };
+        let effects = effects.to_vec();
+        let result = match direction {
+            StreamDirection::Playback => channel.send_new_playback_stream(spec,  buffer_size, effects, channel.clone()),
+            StreamDirection::Capture => channel.send_new_capture_stream(spec, buffer_size, effects, channel.clone()),
+        };
+        match result {
+            Ok(stream) => Ok(Box::new(stream)),
+            Err(err) => {
+                if matches!(err, PulseError::SendMessageFailed | PulseError::RecvMessageFailed) {
+                    // The context thread is gone, connect again for the next stream
+                    self.channel = None;
+                }
+                if direction == StreamDirection::Playback {
+                    return Err(Box::new(err));
+                }
+                // Keep the guest recording even if there is no source to capture from
+                warn!("PulseAudio: failed to create capture stream, nothing will be recorded: {}", err);
+                Ok(Box::new(NullShmStream::new(buffer_size, num_channels, format, frame_rate)))
+            }
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,20 +1,27 @@
 use std::cell::RefCell;
 use std::ops::DerefMut;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::sync::mpsc::Receiver;
 use pulse::context::{Context, FlagSet, State};
+use pulse::def::BufferAttr;
 use pulse::mainloop::threaded::Mainloop;
 use pulse::proplist::{properties, Proplist};
 use pulse::sample::Spec;
 use pulse::stream::Stream;
 use vm_memory::GuestMemoryMmap;
+use crate::audio::{AudioLatency, StreamDirection, StreamEffect};
 use crate::audio::pulse::{Result, PulseError, PulseStream};
 use crate::audio::pulse::message::{PulseContextMessage, PulseContextRequest, PulseMessageChannel};
+use crate::io::stats::Counter;
 
 pub struct PulseContext {
     guest_memory: GuestMemoryMmap,
     mainloop: Rc<RefCell<Mainloop>>,
     context: Rc<RefCell<Context>>,
+    latency: AudioLatency,
+    underruns: Arc<Counter>,
+    overruns: Arc<Counter>,
 }
 
 impl PulseContext {
@@ -34,7 +41,7 @@ impl PulseContext {
         self.mainloop.clone()
     }
 
-    pub fn new(guest_memory: GuestMemoryMmap) -> Self {
+    pub fn new(guest_memory: GuestMemoryMmap, latency: AudioLatency, underruns: Arc<Counter>, overruns: Arc<Counter>) -> Self {
         let mainloop = Mainloop::new()
             .expect("Failed to create a pulseaudio mainloop");
 
@@ -54,6 +61,9 @@ impl PulseContext {
             guest_memory,
             mainloop: Rc::new(RefCell::new(mainloop)),
             context: Rc::new(RefCell::new(context)),
+            latency,
+            underruns,
+            overruns,
         }
     }
 
@@ -102,20 +112,87 @@ impl PulseContext {
         result
     }
 
-    fn new_playback_stream(&self, spec: Spec, buffer_size: usize, channel: PulseMessageChannel) -> PulseStream {
+    // Stream properties requesting `effects`. With echo cancellation the
+    // module-filter-apply module of the server routes the stream through
+    // module-echo-cancel, and the phone role lets servers which route by role
+    // do the same.
+    fn stream_proplist(effects: &[StreamEffect]) -> Proplist {
+        let mut proplist = Proplist::new()
+            .expect("Failed to create pulseaudio proplist");
+        if effects.contains(&StreamEffect::EchoCancellation) {
+            proplist.set_str(properties::FILTER_WANT, "echo-cancel")
+                .expect("Failed to set pulseaudio property");
+            proplist.set_str(properties::MEDIA_ROLE, "phone")
+                .expect("Failed to set pulseaudio property");
+        }
+        proplist
+    }
+
+    fn new_stream(&self, direction: StreamDirection, spec: Spec, buffer_size: usize, effects: &[StreamEffect], channel: PulseMessageChannel) -> PulseStream {
         self.mainloop_lock();
 
-        let stream = Stream::new(self.context.borrow_mut().deref_mut(),
-                                                   "ph-pa-playback",
+        let name = match direction {
+            StreamDirection::Playback => "ph-pa-playback",
+            StreamDirection::Capture => "ph-pa-capture",
+        };
+
+        let mut proplist = Self::stream_proplist(effects);
+        let stream = Stream::new_with_proplist(self.context.borrow_mut().deref_mut(),
+                                                   name,
                                                    &spec,
-                                                   None)
+                                                   None,
+                                                   &mut proplist)
                 .expect("Failed to create pulseaudio stream");
 
-        let ps = PulseStream::new_playback(stream, self.guest_memory.clone(), spec, buffer_size, channel);
+        let guest_memory = self.guest_memory.clone();
+        let attr = self.buffer_attr(direction, &spec, buffer_size);
+        let ps = match direction {
+            StreamDirection::Playback => PulseStream::new_playback(stream, guest_memory, spec, buffer_size, attr, self.underruns.clone(), channel),
+            StreamDirection::Capture => PulseStream::new_capture(stream, guest_memory, spec, buffer_size, attr, self.overruns.clone(), channel),
+        };
         self.mainloop_unlock();
         ps
     }
 
+    fn buffer_attr(&self, direction: StreamDirection, spec: &Spec, buffer_size: usize) -> BufferAttr {
+        let ms_to_bytes = |ms: u32| {
+            let frames = u64::from(ms) * u64::from(spec.rate) / 1000;
+            (frames as usize * spec.frame_size()) as u32
+        };
+        // u32::MAX lets the server choose a value
+        let mut attr = BufferAttr {
+            maxlength: u32::MAX,
+            tlength: u32::MAX,
+            prebuf: u32::MAX,
+            minreq: u32::MAX,
+            fragsize: u32::MAX,
+        };
+        match direction {
+            StreamDirection::Playback => {
+                if let Some(ms) = self.latency.target_ms {
+                    attr.tlength = ms_to_bytes(ms);
+                }
+                if let Some(ms) = self.latency.min_request_ms {
+                    attr.minreq = ms_to_bytes(ms);
+                }
+            }
+            StreamDirection::Capture => {
+                // Ask for captured data to be delivered in fragments of one
+                // guest buffer to keep recording latency low.
+                attr.fragsize = (buffer_size * spec.frame_size()) as u32;
+            }
+        }
+        attr
+    }
+
+    fn connect_new_stream(&self, msg: &PulseContextMessage, direction: StreamDirection, spec: Spec, buffer_size: usize, effects: &[StreamEffect], channel: PulseMessageChannel) {
+        let mut ps = self.new_stream(direction, spec, buffer_size, effects, channel);
+        match ps.connect(self) {
+            Ok(()) => msg.respond_stream(ps),
+            Err(err) => msg.respond_err(err),
+        }
+    }
+
     pub fn run(&mut self, receiver: Receiver<PulseContextMessage>) {
         loop {
             match receiver.recv() {
@@ -135,12 +212,11 @@ impl PulseContext {
                 self.mainloop_unlock();
                 msg.respond_ok();
             }
-            PulseContextRequest::NewPlaybackStream {spec, buffer_size, channel} => {
-                let mut ps = self.new_playback_stream(*spec, *buffer_size, channel.clone());
-                match ps.connect(self) {
-                    Ok(()) => msg.respond_stream(ps),
-                    Err(err) => msg.respond_err(err),
-                }
+            PulseContextRequest::NewPlaybackStream {spec, buffer_size, effects, channel} => {
+                self.connect_new_stream(&msg, StreamDirection::Playback, *spec, *buffer_size, effects, channel.clone());
+            }
+            PulseContextRequest::NewCaptureStream {spec, buffer_size, effects, channel} => {
+                self.connect_new_stream(&msg, StreamDirection::Capture, *spec, *buffer_size, effects, channel.clone());
             }
         }
     }
@@ -3,6 +3,7 @@ use std::sync::mpsc::{Receiver, Sender};
 use pulse::sample::Spec;
 use crate::audio::pulse::{PulseError, PulseStream, Result};
 use crate::audio::pulse::PulseError::UnexpectedResponse;
+use crate::audio::StreamEffect;
 
 pub enum PulseContextRequest {
     MainloopLock,
@@ -10,6 +11,13 @@ pub enum PulseContextRequest {
     NewPlaybackStream {
         spec: Spec,
         buffer_size: usize,
+        effects: Vec<StreamEffect>,
+        channel: PulseMessageChannel,
+    },
+    NewCaptureStream {
+        spec: Spec,
+        buffer_size: usize,
+        effects: Vec<StreamEffect>,
         channel: PulseMessageChannel,
     },
 }
@@ -94,8 +102,16 @@ impl PulseMessageChannel {
         self.send_expect_ok(PulseContextRequest::MainloopUnlock)
     }
 
-    pub fn send_new_playback_stream(&self, spec: Spec, buffer_size: usize, channel: PulseMessageChannel) -> Result<PulseStream> {
-        match self.exchange_message(PulseContextRequest::NewPlaybackStream { spec, buffer_size, channel})? {
+    pub fn send_new_playback_stream(&self, spec: Spec, buffer_size: usize, effects: Vec<StreamEffect>, channel: PulseMessageChannel) -> Result<PulseStream> {
+        self.expect_stream(PulseContextRequest::NewPlaybackStream { spec, buffer_size, effects, channel})
+    }
+
+    pub fn send_new_capture_stream(&self, spec: Spec, buffer_size: usize, effects: Vec<StreamEffect>, channel: PulseMessageChannel) -> Result<PulseStream> {
+        self.expect_stream(PulseContextRequest::NewCaptureStream { spec, buffer_size, effects, channel})
+    }
+
+    fn expect_stream(&self, req: PulseContextRequest) -> Result<PulseStream> {
+        match self.exchange_message(req)? {
             PulseContextResponse::ResponseOk => Err(UnexpectedResponse),
             PulseContextResponse::ResponseError(err) => Err(err),
             PulseContextResponse::ResponseStream(stream) => Ok(stream),
@@ -1,12 +1,17 @@
+use std::result;
 use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::time::Duration;
+use pulse::def::BufferAttr;
+use pulse::error::PAErr;
 use pulse::sample::Spec;
-use pulse::stream::{FlagSet, SeekMode, State, Stream};
+use pulse::stream::{FlagSet, PeekResult, SeekMode, State, Stream};
 use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use crate::audio::StreamDirection;
 use crate::audio::pulse::{PulseError,Result};
 use crate::audio::pulse::context::PulseContext;
 use crate::audio::pulse::message::PulseMessageChannel;
 use crate::audio::shm_streams::{BufferSet, GenericResult, ServerRequest, ShmStream};
+use crate::io::stats::Counter;
 struct Available {
     byte_count: Mutex<usize>,
     cond: Condvar,
@@ -35,9 +40,11 @@ impl Available {
         *byte_count -= amount;
     }
 
-    fn wait_space(&self, timeout: Duration) -> Option<usize> {
+    /// Wait until at least `min` bytes are available to write for playback or
+    /// to read for capture.
+    fn wait_available(&self, timeout: Duration, min: usize) -> Option<usize> {
         let mut byte_count = self.byte_count_lock();
-        while *byte_count == 0 {
+        while *byte_count < min {
             let (new_lock, wt_result) = self.cond.wait_timeout(byte_count, timeout).unwrap();
             if wt_result.timed_out() {
                 return None;
@@ -49,11 +56,15 @@ impl Available {
 }
 
 pub struct PulseStream {
+    direction: StreamDirection,
     spec: Spec,
     buffer_size: usize,
+    attr: BufferAttr,
     guest_memory: GuestMemoryMmap,
     stream: Arc<Mutex<Stream>>,
     avail: Arc<Available>,
+    // Data read from the capture stream which has not been copied to the guest yet.
+    captured: Mutex<Vec<u8>>,
     channel: PulseMessageChannel,
 }
 
@@ -88,12 +99,25 @@ impl PulseStream {
         })));
 
 
-        if let Err(err) = self.stream().connect_playback(
-            None,
-            None,
-            FlagSet::NOFLAGS,
-            None,
-            None) {
+        let connected = match self.direction {
+            StreamDirection::Playback => {
+                // A configured tlength is the total latency including the sink
+                let flags = if self.attr.tlength != u32::MAX {
+                    FlagSet::ADJUST_LATENCY
+                } else {
+                    FlagSet::NOFLAGS
+                };
+                self.stream().connect_playback(
+                    None,
+                    Some(&self.attr),
+                    flags,
+                    None,
+                    None)
+            }
+            StreamDirection::Capture =>
+                self.stream().connect_record(None, Some(&self.attr), FlagSet::ADJUST_LATENCY),
+        };
+        if let Err(err) = connected {
             self.stream().set_state_callback(None);
             ctx.mainloop_unlock();
             return Err(PulseError::StreamConnect(err))
@@ -104,7 +128,7 @@ impl PulseStream {
         result
     }
 
-    pub fn new_playback(mut stream: Stream, guest_memory: GuestMemoryMmap, spec: Spec, buffer_size: usize, channel: PulseMessageChannel) -> Self {
+    pub fn new_playback(mut stream: Stream, guest_memory: GuestMemoryMmap, spec: Spec, buffer_size: usize, attr: BufferAttr, underruns: Arc<Counter>, channel: PulseMessageChannel) -> Self {
         let avail = Arc::new(Available::new());
 
         stream.set_write_callback(Some(Box::new({
@@ -114,13 +138,42 @@ impl PulseStream {
             }
         })));
 
+        stream.set_underflow_callback(Some(Box::new(move || {
+            underruns.inc();
+        })));
+
+        Self::new(StreamDirection::Playback, stream, guest_memory, spec, buffer_size, attr, avail, channel)
+    }
+
+    pub fn new_capture(mut stream: Stream, guest_memory: GuestMemoryMmap, spec: Spec, buffer_size: usize, attr: BufferAttr, overruns: Arc<Counter>, channel: PulseMessageChannel) -> Self {
+        let avail = Arc::new(Available::new());
+
+        stream.set_read_callback(Some(Box::new({
+            let avail = avail.clone();
+            move |readable_bytes| {
+                avail.update(readable_bytes);
+            }
+        })));
+
+        stream.set_overflow_callback(Some(Box::new(move || {
+            overruns.inc();
+        })));
+
+        Self::new(StreamDirection::Capture, stream, guest_memory, spec, buffer_size, attr, avail, channel)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn new(direction: StreamDirection, stream: Stream, guest_memory: GuestMemoryMmap, spec: Spec, buffer_size: usize, attr: BufferAttr, avail: Arc<Available>, channel: PulseMessageChannel) -> Self {
         let stream = Arc::new(Mutex::new(stream));
         PulseStream {
+            direction,
             spec,
             buffer_size,
+            attr,
             guest_memory,
             avail,
             stream,
+            captured: Mutex::new(Vec::new()),
             channel,
         }
     }
@@ -137,6 +190,52 @@ impl PulseStream {
         self.channel.send_mainloop_unlock()?;
         Ok(())
     }
+
+    fn playback_callback(&self, address: u64, frames: usize) -> GenericResult<()> {
+        self.uncork()?;
+        let mut buffer = vec![0u8; frames * self.frame_size()];
+        self.guest_memory.read_slice(&mut buffer, GuestAddress(address))?;
+
+        self.channel.send_mainloop_lock()?;
+        self.stream().write_copy(&buffer, 0, SeekMode::Relative)?;
+        self.channel.send_mainloop_unlock()?;
+        self.avail.decrement(buffer.len());
+        Ok(())
+    }
+
+    fn capture_callback(&self, address: u64, frames: usize) -> GenericResult<()> {
+        let len = frames * self.frame_size();
+        let mut captured = self.captured.lock().unwrap();
+        self.channel.send_mainloop_lock()?;
+        let result = self.read_captured(&mut captured, len);
+        self.channel.send_mainloop_unlock()?;
+        let readable = result?;
+
+        // Fill with silence if the server delivered less than requested
+        if captured.len() < len {
+            captured.resize(len, 0);
+        }
+        self.guest_memory.write_slice(&captured[..len], GuestAddress(address))?;
+        captured.drain(..len);
+        self.avail.update(readable + captured.len());
+        Ok(())
+    }
+
+    // Read fragments from the capture stream until `captured` holds at least
+    // `len` bytes or no more data is available. Returns the number of bytes
+    // still readable from the stream. Must be called with the mainloop locked.
+    fn read_captured(&self, captured: &mut Vec<u8>, len: usize) -> result::Result<usize, PAErr> {
+        let mut stream = self.stream();
+        while captured.len() < len {
+            match stream.peek()? {
+                PeekResult::Empty => break,
+                PeekResult::Hole(n) => captured.resize(captured.len() + n, 0),
+                PeekResult::Data(data) => captured.extend_from_slice(data),
+            }
+            stream.discard()?;
+        }
+        Ok(stream.readable_size().unwrap_or(0))
+    }
 }
 
 impl ShmStream for PulseStream {
@@ -153,7 +252,7 @@ impl ShmStream for PulseStream {
     }
 
     fn wait_for_next_action_with_timeout(&self, timeout: Duration) -> GenericResult<Option<ServerRequest>> {
-        if let Some(bytes) = self.avail.wait_space(timeout) {
+        if let Some(bytes) = self.avail.wait_available(timeout, self.frame_size()) {
             let frames = bytes / self.frame_size();
             let req = frames.min(self.buffer_size);
             return Ok(Some(ServerRequest::new(req, self)))
@@ -164,15 +263,10 @@ impl ShmStream for PulseStream {
 
 impl BufferSet for PulseStream {
     fn callback(&self, address: u64, frames: usize) -> GenericResult<()> {
-        self.uncork()?;
-        let mut buffer = vec![0u8; frames * self.frame_size()];
-        self.guest_memory.read_slice(&mut buffer, GuestAddress(address))?;
-
-        self.channel.send_mainloop_lock()?;
-        self.stream().write_copy(&buffer, 0, SeekMode::Relative)?;
-        self.channel.send_mainloop_unlock()?;
-        self.avail.decrement(buffer.len());
-        Ok(())
+        match self.direction {
+            StreamDirection::Playback => self.playback_callback(address, frames),
+            StreamDirection::Capture => self.capture_callback(address, frames),
+        }
     }
 
     fn ignore(&self) -> GenericResult<()> {
@@ -8,7 +8,7 @@ use std::time::Duration;
 use std::time::Instant;
 
 use thiserror::Error;
-use crate::audio::{BoxError, SampleFormat, StreamDirection};
+use crate::audio::{BoxError, SampleFormat, StreamDirection, StreamEffect};
 
 pub(crate) type GenericResult<T> = Result<T, BoxError>;
 
@@ -188,6 +188,8 @@ pub trait ShmStreamSource: Send {
     /// * `buffer_size` - The maximum size of an audio buffer. This will be the
     ///                   size used for transfers of audio data between client
     ///                   and server.
+    /// * `effects` - Processing the server should apply to the stream, such as
+    ///               echo cancellation.
     ///
     /// # Errors
     ///
@@ -199,6 +201,7 @@ pub trait ShmStreamSource: Send {
         format: SampleFormat,
         frame_rate: u32,
         buffer_size: usize,
+        effects: &[StreamEffect],
     ) -> GenericResult<Box<dyn ShmStream>>;
 }
 
@@ -1,9 +1,11 @@
 #![allow(non_snake_case)]
 
+use std::process;
 use ph::VmConfig;
 
 fn main() {
-    VmConfig::new()
+    let reason = VmConfig::new()
         .ram_size_megs(2048)
         .boot();
+    process::exit(reason.exit_code());
 }
@@ -0,0 +1,6 @@
+use std::process;
+
+// Installed setuid root or run through pkexec, see ph::run_net_helper()
+fn main() {
+    process::exit(ph::run_net_helper());
+}
@@ -3,16 +3,19 @@
 // found in the LICENSE file.
 
 use std::io;
+use std::sync::Arc;
 
 use thiserror::Error;
 use vm_memory::GuestMemoryMmap;
-use crate::audio::pulse::{PulseClient, PulseError};
+use crate::audio::{AudioControl, AudioLatency, StreamEffect};
+use crate::audio::pulse::PulseClient;
 use crate::devices::ac97::ac97_bus_master::{Ac97BusMaster, AudioStreamSource};
 use crate::devices::ac97::ac97_mixer::Ac97Mixer;
 use crate::devices::ac97::ac97_regs::{MASTER_REGS_SIZE, MIXER_REGS_SIZE};
-use crate::devices::irq_event::IrqLevelEvent;
+use crate::io::irq::IrqManager;
 use crate::io::pci::{PciBar, PciBarAllocation, PciConfiguration, PciDevice};
-use crate::vm::KvmVm;
+use crate::io::stats::DeviceStats;
+use crate::system::sched::ThreadPriority;
 
 
 // Use 82801AA because it's what qemu does.
@@ -29,8 +32,6 @@ const PCI_DEVICE_ID_INTEL_82801AA_5: u16 = 0x2415;
 pub enum Ac97Error {
     #[error("Error creating IRQ level event: {0}")]
     IrqLevelEventError(io::Error),
-    #[error("PulseAudio: {0}")]
-    PulseError(PulseError),
 }
 
 pub struct Ac97Dev {
@@ -38,13 +39,14 @@ pub struct Ac97Dev {
     pci_config: PciConfiguration,
     bus_master: Ac97BusMaster,
     mixer: Ac97Mixer,
+    audio_control: Arc<AudioControl>,
 }
 
 const PCI_CLASS_MULTIMEDIA_AUDIO:u16 = 0x0401;
 const PCI_VENDOR_ID_INTEL: u16 = 0x8086;
 
 impl Ac97Dev {
-    /// Creates an 'Ac97Dev' that uses the given `GuestRam` and starts with all registers at
+    /// Creates an 'Ac97Dev' that uses the given `GuestMemoryMmap` and starts with all registers at
     /// default values.
     pub fn new(
         irq: u8,
@@ -61,31 +63,47 @@ impl Ac97Dev {
                 audio_server,
             ),
             mixer: Ac97Mixer::new(),
+            audio_control: Arc::new(AudioControl::default()),
         }
     }
 
+    /// Control for reconnecting to the audio server while the VM runs.
+    pub fn audio_control(&self) -> Arc<AudioControl> {
+        self.audio_control.clone()
+    }
+
     /// Creates an `Ac97Dev` with suitable audio server inside based on Ac97Parameters. If it fails
     /// to create `Ac97Dev` with the given back-end, it'll fallback to the null audio device.
     pub fn try_new(
-        kvm_vm: &KvmVm,
+        irqs: &IrqManager,
         irq: u8,
         mem: &GuestMemoryMmap,
+        latency: AudioLatency,
+        effects: &[StreamEffect],
+        priority: Option<ThreadPriority>,
+        stats: &DeviceStats,
     ) -> Result<Self, Ac97Error> {
-        let mut ac97 = Self::initialize_pulseaudio(irq, mem)?;
-        let irq_event = IrqLevelEvent::register(kvm_vm, irq)
+        let mut ac97 = Self::initialize_pulseaudio(irq, mem, latency, priority, stats);
+        ac97.bus_master.set_stream_effects(effects);
+        ac97.bus_master.set_thread_priority(priority);
+        let irq_event = irqs.level_irq(irq)
             .map_err(Ac97Error::IrqLevelEventError)?;
         ac97.bus_master.set_irq_event(irq_event);
         Ok(ac97)
     }
 
-    fn initialize_pulseaudio(irq: u8, mem: &GuestMemoryMmap) -> Result<Self, Ac97Error> {
-        let server = PulseClient::connect(mem)
-            .map_err(Ac97Error::PulseError)?;
-        Ok(Self::new(
+    // The server is connected when the guest first starts a stream
+    fn initialize_pulseaudio(irq: u8, mem: &GuestMemoryMmap, latency: AudioLatency, priority: Option<ThreadPriority>, stats: &DeviceStats) -> Self {
+        let mut server = PulseClient::new(mem, latency, stats);
+        server.set_thread_priority(priority);
+        let audio_control = server.audio_control();
+        let mut ac97 = Self::new(
             irq,
             mem,
             Box::new(server),
-        ))
+        );
+        ac97.audio_control = audio_control;
+        ac97
     }
 
     fn read_mixer(&mut self, offset: u64, data: &mut [u8]) {
@@ -116,7 +134,7 @@ impl Ac97Dev {
         match data.len() {
             1 => data[0] = self.bus_master.readb(offset),
             2 => {
-                let val: u16 = self.bus_master.readw(offset, &self.mixer);
+                let val: u16 = self.bus_master.readw(offset);
                 data[0] = val as u8;
                 data[1] = (val >> 8) as u8;
             }
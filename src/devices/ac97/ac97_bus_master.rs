@@ -15,13 +15,15 @@ use std::time::{Duration, Instant};
 use thiserror::Error;
 use vm_memory::{Bytes, guest_memory, GuestAddress, GuestMemoryMmap};
 use crate::audio::shm_streams::{ShmStream, ShmStreamSource};
-use crate::audio::{BoxError,  SampleFormat, StreamControl, StreamDirection};
+use crate::audio::{BoxError,  SampleFormat, StreamControl, StreamDirection, StreamEffect};
 use crate::devices::ac97::ac97_mixer::Ac97Mixer;
 use crate::devices::ac97::ac97_regs::*;
-use crate::devices::irq_event::IrqLevelEvent;
+use crate::io::irq::LevelIrq;
+use crate::system::sched::ThreadPriority;
 
-const INPUT_SAMPLE_RATE: u32 = 48000;
 const DEVICE_INPUT_CHANNEL_COUNT: usize = 2;
+// The microphone ADC has a single slot so mic capture is always mono.
+const DEVICE_MIC_CHANNEL_COUNT: usize = 1;
 
 pub(crate) type AudioStreamSource = Box<dyn ShmStreamSource>;
 
@@ -30,13 +32,14 @@ pub(crate) type AudioStreamSource = Box<dyn ShmStreamSource>;
 struct Ac97BusMasterRegs {
     pi_regs: Ac97FunctionRegs,       // Input
     po_regs: Ac97FunctionRegs,       // Output
-    po_pointer_update_time: Instant, // Time the picb and civ regs were last updated.
+    // Samples of the current output buffer the audio backend has played.
+    po_played_samples: u16,
     mc_regs: Ac97FunctionRegs,       // Microphone
     glob_cnt: u32,
     glob_sta: u32,
 
     // IRQ event - driven by the glob_sta register.
-    irq_evt: Option<IrqLevelEvent>,
+    irq_evt: Option<LevelIrq>,
 }
 
 impl Ac97BusMasterRegs {
@@ -44,7 +47,7 @@ impl Ac97BusMasterRegs {
         Ac97BusMasterRegs {
             pi_regs: Ac97FunctionRegs::new("Input"),
             po_regs: Ac97FunctionRegs::new("Output"),
-            po_pointer_update_time: Instant::now(),
+            po_played_samples: 0,
             mc_regs: Ac97FunctionRegs::new("Microphone"),
             glob_cnt: 0,
             glob_sta: GLOB_STA_RESET_VAL,
@@ -84,7 +87,19 @@ impl Ac97BusMasterRegs {
 
         match func {
             Ac97Function::Output => output_tube_count(self.glob_cnt),
-            _ => DEVICE_INPUT_CHANNEL_COUNT,
+            Ac97Function::Input => DEVICE_INPUT_CHANNEL_COUNT,
+            Ac97Function::Microphone => DEVICE_MIC_CHANNEL_COUNT,
+        }
+    }
+
+    // PCM out carries 20 bit samples in 32 bit slots when selected in
+    // GLOB_CNT. The samples are aligned to the top of the slot, so they can
+    // be played as 32 bit samples.
+    fn sample_format(&self, func: Ac97Function) -> SampleFormat {
+        if func == Ac97Function::Output && self.glob_cnt & GLOB_CNT_PCM_MODE_MASK == GLOB_CNT_PCM_20_BIT {
+            SampleFormat::S32LE
+        } else {
+            SampleFormat::S16LE
         }
     }
 
@@ -134,6 +149,8 @@ struct AudioThreadInfo {
     thread_run: Arc<AtomicBool>,
     thread_semaphore: Arc<Condvar>,
     stream_control: Option<Box<dyn StreamControl>>,
+    // Sample rate of the stream the running thread was started with.
+    sample_rate: u32,
 }
 
 impl AudioThreadInfo {
@@ -143,6 +160,7 @@ impl AudioThreadInfo {
             thread_run: Arc::new(AtomicBool::new(false)),
             thread_semaphore: Arc::new(Condvar::new()),
             stream_control: None,
+            sample_rate: 0,
         }
     }
 
@@ -150,9 +168,14 @@ impl AudioThreadInfo {
         self.thread_run.load(Ordering::Relaxed)
     }
 
-    fn start(&mut self, mut worker: AudioWorker) {
+    fn start(&mut self, mut worker: AudioWorker, priority: Option<ThreadPriority>) {
         self.thread_run.store(true, Ordering::Relaxed);
         self.thread = Some(thread::spawn(move || {
+            if let Some(priority) = priority {
+                if let Err(e) = priority.apply() {
+                    warn!("Failed to set {:?} thread to {}: {}", worker.func, priority, e);
+                }
+            }
 
             if let Err(e) = worker.run() {
                 warn!("{:?} error: {}", worker.func, e);
@@ -188,9 +211,10 @@ pub struct Ac97BusMaster {
 
     // Audio server used to create playback or capture streams.
     audio_server: AudioStreamSource,
-
-    // Thread for hadlind IRQ resample events from the guest.
-    irq_resample_thread: Option<thread::JoinHandle<()>>,
+    // Effects requested for every stream
+    stream_effects: Vec<StreamEffect>,
+    // Scheduling of the threads moving samples to and from the streams
+    thread_priority: Option<ThreadPriority>,
 }
 
 impl Ac97BusMaster {
@@ -207,41 +231,29 @@ impl Ac97BusMaster {
             pi_info: AudioThreadInfo::new(),
             pmic_info: AudioThreadInfo::new(),
             audio_server,
-
-            irq_resample_thread: None,
+            stream_effects: Vec::new(),
+            thread_priority: None,
         }
     }
 
+    /// Ask the audio server to apply `effects` to the streams created from
+    /// now on.
+    pub fn set_stream_effects(&mut self, effects: &[StreamEffect]) {
+        self.stream_effects = effects.to_vec();
+    }
+
+    /// Schedule the threads of streams started from now on with `priority`.
+    pub fn set_thread_priority(&mut self, priority: Option<ThreadPriority>) {
+        self.thread_priority = priority;
+    }
+
     fn regs(&self) -> MutexGuard<Ac97BusMasterRegs> {
         self.regs.lock().unwrap()
     }
 
-    /// Provides the events needed to raise interrupts in the guest.
-    pub fn set_irq_event(&mut self, irq_evt: IrqLevelEvent) {
-        let thread_regs = self.regs.clone();
-        self.regs().irq_evt = Some(irq_evt.try_clone().expect("cloning irq_evt failed"));
-
-        self.irq_resample_thread = Some(thread::spawn(move || {
-            loop {
-                if let Err(e) = irq_evt.wait_resample() {
-                    warn!(
-                        "Failed to read the irq event from the resample thread: {}.",
-                        e,
-                    );
-                    break;
-                }
-                {
-                    // Scope for the lock on thread_regs.
-                    let regs = thread_regs.lock().unwrap();
-                    if regs.has_irq() {
-                        if let Err(e) = irq_evt.trigger() {
-                            warn!("Failed to set the irq from the resample thread: {}.", e);
-                            break;
-                        }
-                    }
-                }
-            }
-        }));
+    /// Provides the interrupt line used to raise interrupts in the guest.
+    pub fn set_irq_event(&mut self, irq_evt: LevelIrq) {
+        self.regs().irq_evt = Some(irq_evt);
     }
 
     /// Called when `mixer` has been changed and the new values should be applied to currently
@@ -253,6 +265,21 @@ impl Ac97BusMaster {
             control.set_volume(left_volume);
             control.set_mute(muted);
         }
+        self.update_sample_rates(mixer);
+    }
+
+    // The stream for a running function is created with the sample rate from the mixer when the
+    // function is started. If the guest changes the rate while running, recreate the stream.
+    fn update_sample_rates(&mut self, mixer: &Ac97Mixer) {
+        for func in [Ac97Function::Output, Ac97Function::Input, Ac97Function::Microphone] {
+            let info = self.thread_info(func);
+            if info.is_running() && info.sample_rate != self.current_sample_rate(func, mixer) {
+                self.thread_info_mut(func).stop();
+                if let Err(e) = self.start_audio(func, mixer) {
+                    warn!("Failed to restart audio with new sample rate: {}", e);
+                }
+            }
+        }
     }
 
     /// Checks if the bus master is in the cold reset state.
@@ -285,30 +312,20 @@ impl Ac97BusMaster {
     }
 
     /// Reads a word from the given `offset`.
-    pub fn readw(&mut self, offset: u64, mixer: &Ac97Mixer) -> u16 {
+    pub fn readw(&mut self, offset: u64) -> u16 {
         let regs = self.regs();
         match offset {
             PI_SR_06 => regs.pi_regs.sr,
             PI_PICB_08 => regs.pi_regs.picb,
             PO_SR_16 => regs.po_regs.sr,
             PO_PICB_18 => {
-                // PO PICB
+                // The position only moves when the backend takes more frames,
+                // so two rapid reads return the same value as the linux
+                // driver expects.
                 if !self.thread_info(Ac97Function::Output).is_running() {
-                    // Not running, no need to estimate what has been consumed.
                     regs.po_regs.picb
                 } else {
-                    // Estimate how many samples have been played since the last audio callback.
-                    let num_channels = regs.tube_count(Ac97Function::Output) as u64;
-                    let micros = regs.po_pointer_update_time.elapsed().subsec_micros();
-                    // Round down to the next 10 millisecond boundary. The linux driver often
-                    // assumes that two rapid reads from picb will return the same value.
-                    let millis = micros / 1000 / 10 * 10;
-                    let sample_rate = self.current_sample_rate(Ac97Function::Output, mixer);
-                    let frames_consumed = sample_rate as u64 * u64::from(millis) / 1000;
-
-                    regs.po_regs
-                        .picb
-                        .saturating_sub((num_channels * frames_consumed) as u16)
+                    regs.po_regs.picb.saturating_sub(regs.po_played_samples)
                 }
             }
             MC_SR_26 => regs.mc_regs.sr,
@@ -465,7 +482,7 @@ impl Ac97BusMaster {
     }
 
     fn set_glob_cnt(&mut self, new_glob_cnt: u32, mixer: &mut Ac97Mixer) {
-        // Only the reset bits are emulated, the GPI and PCM formatting are not supported.
+        // Only the reset bits and PCM formatting are emulated, the GPI is not supported.
         if new_glob_cnt & GLOB_CNT_COLD_RESET == 0 {
             self.reset_audio_regs();
             mixer.reset();
@@ -489,7 +506,8 @@ impl Ac97BusMaster {
     fn current_sample_rate(&self, func: Ac97Function, mixer: &Ac97Mixer) -> u32 {
         match func {
             Ac97Function::Output => mixer.get_sample_rate().into(),
-            _ => INPUT_SAMPLE_RATE,
+            Ac97Function::Input => mixer.get_input_sample_rate().into(),
+            Ac97Function::Microphone => mixer.get_mic_sample_rate().into(),
         }
     }
 
@@ -564,6 +582,7 @@ impl Ac97BusMaster {
         let buffer_samples = current_buffer_size(locked_regs.func_regs(func), &self.mem)?;
         let num_channels = locked_regs.tube_count(func);
         let buffer_frames = buffer_samples / num_channels;
+        let format = locked_regs.sample_format(func);
 
         let pending_buffers = VecDeque::with_capacity(2);
 
@@ -572,9 +591,10 @@ impl Ac97BusMaster {
             .new_stream(
                 direction,
                 num_channels,
-                SampleFormat::S16LE,
+                format,
                 sample_rate,
-                buffer_frames)
+                buffer_frames,
+                &self.stream_effects)
             .map_err(AudioError::CreateStream)?;
 
         let params = AudioWorkerParams {
@@ -588,7 +608,11 @@ impl Ac97BusMaster {
 
     fn start_audio(&mut self, func: Ac97Function, mixer: &Ac97Mixer) -> AudioResult<()> {
         let audio_worker = self.create_audio_worker(mixer, func)?;
-        self.thread_info_mut(func).start(audio_worker);
+        let sample_rate = self.current_sample_rate(func, mixer);
+        let priority = self.thread_priority;
+        let info = self.thread_info_mut(func);
+        info.sample_rate = sample_rate;
+        info.start(audio_worker, priority);
         self.update_mixer_settings(mixer);
         Ok(())
     }
@@ -639,7 +663,7 @@ fn buffer_completed(
 
     regs.func_regs_mut(func).picb = current_buffer_size(regs.func_regs(func), mem)? as u16;
     if func == Ac97Function::Output {
-        regs.po_pointer_update_time = Instant::now();
+        regs.po_played_samples = 0;
     }
 
     Ok(())
@@ -673,15 +697,17 @@ fn update_sr(regs: &mut Ac97BusMasterRegs, func: Ac97Function, val: u16) {
 
     if interrupt_high {
         regs.glob_sta |= int_mask;
-        if let Some(ref irq_evt) = regs.irq_evt {
-            // Ignore write failure, nothing can be done about it from here.
-            let _ = irq_evt.trigger();
-        } else {
-            info!("AC97: No interrupt! uh oh");
-        }
     } else {
         regs.glob_sta &= !int_mask;
     }
+
+    // The line is raised again after the guest acknowledges it for as long as
+    // any function still has an interrupt pending.
+    if let Some(ref irq_evt) = regs.irq_evt {
+        irq_evt.set_level(interrupt_high || regs.has_irq());
+    } else if interrupt_high {
+        info!("AC97: No interrupt! uh oh");
+    }
 }
 
 // Returns the size in samples of the buffer pointed to by the CIV register.
@@ -891,6 +917,20 @@ impl AudioWorker {
         Ok(buffer)
     }
 
+    // The backend asks for more frames as it plays the frames it already
+    // holds. Once the buffer at CIV has been handed over completely the
+    // backend holds the rest of it, so every frame taken from a later buffer
+    // stands for a frame of the CIV buffer which has been played.
+    fn update_played(&self, buffer: &GuestBuffer, nframes: usize) {
+        let mut regs = self.regs.lock().unwrap();
+        if buffer.index == regs.po_regs.civ {
+            return;
+        }
+        let samples = nframes * buffer.channels;
+        let played = usize::from(regs.po_played_samples) + samples;
+        regs.po_played_samples = cmp::min(played, usize::from(regs.po_regs.picb)) as u16;
+    }
+
     // Runs and updates the offset within the stream shm where samples can be
     // found/placed for shm playback/capture streams, respectively
     fn run(&mut self) -> AudioResult<()> {
@@ -900,6 +940,7 @@ impl AudioWorker {
             let mut locked_regs = self.regs.lock().unwrap();
             locked_regs.func_regs_mut(func).picb =
                 current_buffer_size(locked_regs.func_regs(func), &self.mem)? as u16;
+            locked_regs.po_played_samples = 0;
         }
 
         'audio_loop: while self.thread_run.load(Ordering::Relaxed) {
@@ -938,6 +979,9 @@ impl AudioWorker {
                     buffer.add_consumed(nframes);
                     request.set_buffer_address_and_frames(addr, nframes)
                         .map_err(AudioError::RespondRequest)?;
+                    if func == Ac97Function::Output {
+                        self.update_played(&buffer, nframes);
+                    }
                 }
             }
         }
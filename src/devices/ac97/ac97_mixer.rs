@@ -6,7 +6,7 @@
 use crate::devices::ac97::ac97_regs::*;
 
 // Extented Audio ID
-const AC97_EXTENDED_ID: u16 = MIXER_EI_VRA | MIXER_EI_CDAC | MIXER_EI_SDAC | MIXER_EI_LDAC;
+const AC97_EXTENDED_ID: u16 = MIXER_EI_VRA | MIXER_EI_VRM | MIXER_EI_CDAC | MIXER_EI_SDAC | MIXER_EI_LDAC;
 const PCI_VENDOR_ID_INTEL: u16 = 0x8086;
 
 // Master volume register is specified in 1.5dB steps.
@@ -34,6 +34,8 @@ pub struct Ac97Mixer {
     pcm_front_dac_rate: u16,
     pcm_surr_dac_rate: u16,
     pcm_lfe_dac_rate: u16,
+    pcm_lr_adc_rate: u16,
+    pcm_mic_adc_rate: u16,
 }
 
 impl Ac97Mixer {
@@ -55,24 +57,32 @@ impl Ac97Mixer {
             power_down_control: PD_REG_STATUS_MASK, // Report everything is ready.
             ext_audio_status_ctl: 0,
             // Default to 48 kHz.
-            pcm_front_dac_rate: 0xBB80,
-            pcm_surr_dac_rate: 0xBB80,
-            pcm_lfe_dac_rate: 0xBB80,
+            pcm_front_dac_rate: MIXER_RATE_DEFAULT,
+            pcm_surr_dac_rate: MIXER_RATE_DEFAULT,
+            pcm_lfe_dac_rate: MIXER_RATE_DEFAULT,
+            pcm_lr_adc_rate: MIXER_RATE_DEFAULT,
+            pcm_mic_adc_rate: MIXER_RATE_DEFAULT,
         }
     }
 
     pub fn reset(&mut self) {
         // Upon reset, the audio sample rate registers default to 48 kHz, and VRA=0.
-        self.ext_audio_status_ctl &= !MIXER_EI_VRA;
-        self.pcm_front_dac_rate = 0xBB80;
-        self.pcm_surr_dac_rate = 0xBB80;
-        self.pcm_lfe_dac_rate = 0xBB80;
+        self.ext_audio_status_ctl &= !(MIXER_EI_VRA | MIXER_EI_VRM);
+        self.reset_pcm_rates();
+        self.pcm_mic_adc_rate = MIXER_RATE_DEFAULT;
+    }
+
+    fn reset_pcm_rates(&mut self) {
+        self.pcm_front_dac_rate = MIXER_RATE_DEFAULT;
+        self.pcm_surr_dac_rate = MIXER_RATE_DEFAULT;
+        self.pcm_lfe_dac_rate = MIXER_RATE_DEFAULT;
+        self.pcm_lr_adc_rate = MIXER_RATE_DEFAULT;
     }
 
     /// Reads a word from the register at `offset`.
     pub fn readw(&self, offset: u64) -> u16 {
         match offset {
-            MIXER_RESET_00 => BC_DEDICATED_MIC,
+            MIXER_RESET_00 => BC_DEDICATED_MIC | BC_20_BIT_DAC,
             MIXER_MASTER_VOL_MUTE_02 => self.get_master_reg(),
             MIXER_MIC_VOL_MUTE_0E => self.get_mic_volume(),
             MIXER_PCM_OUT_VOL_MUTE_18 => self.get_pcm_out_volume(),
@@ -85,6 +95,8 @@ impl Ac97Mixer {
             MIXER_PCM_FRONT_DAC_RATE_2C => self.pcm_front_dac_rate,
             MIXER_PCM_SURR_DAC_RATE_2E => self.pcm_surr_dac_rate,
             MIXER_PCM_LFE_DAC_RATE_30 => self.pcm_lfe_dac_rate,
+            MIXER_PCM_LR_ADC_RATE_32 => self.pcm_lr_adc_rate,
+            MIXER_PCM_MIC_ADC_RATE_34 => self.pcm_mic_adc_rate,
             _ => 0,
         }
     }
@@ -98,10 +110,12 @@ impl Ac97Mixer {
             MIXER_PCM_OUT_VOL_MUTE_18 => self.set_pcm_out_volume(val),
             MIXER_REC_VOL_MUTE_1C => self.set_record_gain_reg(val),
             MIXER_POWER_DOWN_CONTROL_26 => self.set_power_down_reg(val),
-            MIXER_EXTENDED_AUDIO_STATUS_CONTROL_28 => self.ext_audio_status_ctl = val,
-            MIXER_PCM_FRONT_DAC_RATE_2C => self.pcm_front_dac_rate = val,
-            MIXER_PCM_SURR_DAC_RATE_2E => self.pcm_surr_dac_rate = val,
-            MIXER_PCM_LFE_DAC_RATE_30 => self.pcm_lfe_dac_rate = val,
+            MIXER_EXTENDED_AUDIO_STATUS_CONTROL_28 => self.set_ext_audio_status_ctl(val),
+            MIXER_PCM_FRONT_DAC_RATE_2C => self.pcm_front_dac_rate = self.vra_rate(val),
+            MIXER_PCM_SURR_DAC_RATE_2E => self.pcm_surr_dac_rate = self.vra_rate(val),
+            MIXER_PCM_LFE_DAC_RATE_30 => self.pcm_lfe_dac_rate = self.vra_rate(val),
+            MIXER_PCM_LR_ADC_RATE_32 => self.pcm_lr_adc_rate = self.vra_rate(val),
+            MIXER_PCM_MIC_ADC_RATE_34 => self.pcm_mic_adc_rate = self.vrm_rate(val),
             _ => (),
         }
     }
@@ -122,6 +136,49 @@ impl Ac97Mixer {
         self.pcm_front_dac_rate
     }
 
+    /// Returns the PCM input sample rate (reg 0x32).
+    pub fn get_input_sample_rate(&self) -> u16 {
+        self.pcm_lr_adc_rate
+    }
+
+    /// Returns the microphone input sample rate (reg 0x34).
+    pub fn get_mic_sample_rate(&self) -> u16 {
+        self.pcm_mic_adc_rate
+    }
+
+    // Handles writes to the extended audio status and control register (0x2a). Only the
+    // variable rate enable bits are writable. Clearing them returns the rates to 48 kHz.
+    fn set_ext_audio_status_ctl(&mut self, val: u16) {
+        self.ext_audio_status_ctl = val & (MIXER_EI_VRA | MIXER_EI_VRM);
+        if self.ext_audio_status_ctl & MIXER_EI_VRA == 0 {
+            self.reset_pcm_rates();
+        }
+        if self.ext_audio_status_ctl & MIXER_EI_VRM == 0 {
+            self.pcm_mic_adc_rate = MIXER_RATE_DEFAULT;
+        }
+    }
+
+    // Returns the rate to store for a write of `val` to one of the PCM DAC or ADC rate
+    // registers. The registers are fixed at 48 kHz unless VRA is enabled. Drivers probe for
+    // supported rates by reading back the value they wrote, so out of range values are
+    // replaced with the nearest supported rate.
+    fn vra_rate(&self, val: u16) -> u16 {
+        Self::variable_rate(self.ext_audio_status_ctl & MIXER_EI_VRA != 0, val)
+    }
+
+    // As `vra_rate()` for the microphone rate register which is controlled by VRM.
+    fn vrm_rate(&self, val: u16) -> u16 {
+        Self::variable_rate(self.ext_audio_status_ctl & MIXER_EI_VRM != 0, val)
+    }
+
+    fn variable_rate(enabled: bool, val: u16) -> u16 {
+        if enabled {
+            val.clamp(MIXER_RATE_MIN, MIXER_RATE_MAX)
+        } else {
+            MIXER_RATE_DEFAULT
+        }
+    }
+
     // Returns the master mute and l/r volumes (reg 0x02).
     fn get_master_reg(&self) -> u16 {
         let reg = (u16::from(self.master_volume_l)) << 8 | u16::from(self.master_volume_r);
@@ -48,17 +48,26 @@ pub const MIXER_EXTENDED_AUDIO_STATUS_CONTROL_28: u64 = 0x2a;
 pub const MIXER_PCM_FRONT_DAC_RATE_2C: u64 = 0x2c;
 pub const MIXER_PCM_SURR_DAC_RATE_2E: u64 = 0x2e;
 pub const MIXER_PCM_LFE_DAC_RATE_30: u64 = 0x30;
+pub const MIXER_PCM_LR_ADC_RATE_32: u64 = 0x32;
+pub const MIXER_PCM_MIC_ADC_RATE_34: u64 = 0x34;
 pub const MIXER_VENDOR_ID1_7C: u64 = 0x7c;
 pub const MIXER_VENDOR_ID2_7E: u64 = 0x7e;
 
 // Extended Audio ID Bits.
 pub const MIXER_EI_VRA: u16 = 0x0001; // Variable Rate Audio mode is available.
+pub const MIXER_EI_VRM: u16 = 0x0008; // Variable Rate Mic Input is available.
 pub const MIXER_EI_CDAC: u16 = 0x0040; // PCM Center DAC is available.
 pub const MIXER_EI_SDAC: u16 = 0x0080; // PCM Surround DAC is available.
 pub const MIXER_EI_LDAC: u16 = 0x0100; // PCM LFE DAC is available.
 
+// Range of sample rates accepted in the PCM rate registers when variable rate audio is enabled.
+pub const MIXER_RATE_DEFAULT: u16 = 48000;
+pub const MIXER_RATE_MIN: u16 = 8000;
+pub const MIXER_RATE_MAX: u16 = 48000;
+
 // Basic capabilities for MIXER_RESET_00
 pub const BC_DEDICATED_MIC: u16 = 0x0001; /* Dedicated Mic PCM In Tube */
+pub const BC_20_BIT_DAC: u16 = 0x0080; /* 20 bit DAC resolution */
 
 // Bus Master regs from ICH spec:
 // 00h PI_BDBAR PCM In Buffer Descriptor list Base Address Register
@@ -98,10 +107,15 @@ pub const GLOB_CNT_PCM_4: u32 = 0x0010_0000; // 4 tubes
 pub const GLOB_CNT_PCM_6: u32 = 0x0020_0000; // 6 tubes
 pub const GLOB_CNT_PCM_246_MASK: u32 = GLOB_CNT_PCM_4 | GLOB_CNT_PCM_6; // tube mask
 
+// PCM Out sample size bits
+pub const GLOB_CNT_PCM_20_BIT: u32 = 0x0040_0000; // 20 bit samples in 32 bit slots
+pub const GLOB_CNT_PCM_MODE_MASK: u32 = 0x00c0_0000;
+
 // Global status
 pub const GLOB_STA_30: u64 = 0x30;
-// Primary codec ready set and turn on D20:21 to support 4 and 6 tubes on PCM out.
-pub const GLOB_STA_RESET_VAL: u32 = 0x0030_0100;
+// Primary codec ready set, turn on D20:21 to support 4 and 6 tubes on PCM out and D22 to
+// support 20 bit samples on PCM out.
+pub const GLOB_STA_RESET_VAL: u32 = 0x0070_0100;
 
 // glob_sta bits
 pub const GS_MD3: u32 = 1 << 17;
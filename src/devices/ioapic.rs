@@ -0,0 +1,217 @@
+use crate::io::bus::BusDevice;
+use crate::io::ReadableInt;
+use crate::vm::irq_routing::{MsiMessage, IOAPIC_NUM_PINS};
+use crate::vm::KvmVm;
+
+pub const IOAPIC_BASE: u64 = 0xfec00000;
+pub const IOAPIC_SIZE: usize = 0x1000;
+
+const NUM_PINS: usize = IOAPIC_NUM_PINS as usize;
+
+const IOAPIC_REG_SELECT: u64 = 0x00;
+const IOAPIC_REG_WINDOW: u64 = 0x10;
+
+const IOAPIC_ID: u8 = 0x00;
+const IOAPIC_VERSION: u8 = 0x01;
+const IOAPIC_ARBITRATION: u8 = 0x02;
+const IOAPIC_REDIRECTION_TABLE: u8 = 0x10;
+
+const IOAPIC_VERSION_ID: u32 = 0x11;
+
+const REDIR_VECTOR_MASK: u64 = 0xff;
+const REDIR_DELIVERY_MODE_SHIFT: u64 = 8;
+const REDIR_DELIVERY_MODE_MASK: u64 = 0x7;
+const REDIR_DEST_MODE_SHIFT: u64 = 11;
+const REDIR_REMOTE_IRR: u64 = 1 << 14;
+const REDIR_TRIGGER_LEVEL: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+const REDIR_DEST_SHIFT: u64 = 56;
+
+// Delivery status and remote IRR are read only
+const REDIR_RO_BITS: u64 = (1 << 12) | REDIR_REMOTE_IRR;
+
+const MSI_ADDRESS_BASE: u64 = 0xfee00000;
+const MSI_DEST_ID_SHIFT: u64 = 12;
+const MSI_DEST_MODE_SHIFT: u64 = 2;
+const MSI_DELIVERY_MODE_SHIFT: u32 = 8;
+const MSI_TRIGGER_LEVEL: u32 = 1 << 15;
+
+/// IOAPIC emulated in userspace for use with a split irqchip.
+///
+/// Interrupts are delivered to the in-kernel local APICs as MSIs. Each
+/// unmasked redirection entry is also installed as an MSI route for the GSI
+/// with the same number as the pin, which is how irqfds on those GSIs reach
+/// the guest and how KVM knows to exit with an EOI for level triggered
+/// vectors.
+pub struct Ioapic {
+    kvm_vm: KvmVm,
+    id: u32,
+    select: u8,
+    redirect: [u64; NUM_PINS],
+    levels: [bool; NUM_PINS],
+}
+
+impl BusDevice for Ioapic {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        self.peek(offset, data);
+    }
+
+    fn peek(&self, offset: u64, data: &mut [u8]) -> bool {
+        match offset {
+            IOAPIC_REG_SELECT if data.len() == 4 => {
+                ReadableInt::new_dword(self.select as u32).read(data);
+            }
+            IOAPIC_REG_WINDOW if data.len() == 4 => {
+                ReadableInt::new_dword(self.read_register()).read(data);
+            }
+            _ => data.fill(0),
+        }
+        true
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.len() != 4 {
+            return;
+        }
+        let val = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        match offset {
+            IOAPIC_REG_SELECT => self.select = val as u8,
+            IOAPIC_REG_WINDOW => self.write_register(val),
+            _ => {},
+        }
+    }
+}
+
+impl Ioapic {
+    pub fn new(kvm_vm: KvmVm) -> Self {
+        Ioapic {
+            kvm_vm,
+            id: 0,
+            select: 0,
+            redirect: [REDIR_MASKED; NUM_PINS],
+            levels: [false; NUM_PINS],
+        }
+    }
+
+    fn redirect_index(&self) -> Option<(usize, bool)> {
+        if self.select < IOAPIC_REDIRECTION_TABLE {
+            return None;
+        }
+        let n = (self.select - IOAPIC_REDIRECTION_TABLE) as usize;
+        if n / 2 < NUM_PINS {
+            Some((n / 2, n & 1 == 1))
+        } else {
+            None
+        }
+    }
+
+    fn read_register(&self) -> u32 {
+        match self.select {
+            IOAPIC_ID | IOAPIC_ARBITRATION => self.id << 24,
+            IOAPIC_VERSION => ((NUM_PINS as u32 - 1) << 16) | IOAPIC_VERSION_ID,
+            _ => match self.redirect_index() {
+                Some((pin, true)) => (self.redirect[pin] >> 32) as u32,
+                Some((pin, false)) => self.redirect[pin] as u32,
+                None => 0,
+            }
+        }
+    }
+
+    fn write_register(&mut self, val: u32) {
+        if self.select == IOAPIC_ID {
+            self.id = (val >> 24) & 0xf;
+            return;
+        }
+        if let Some((pin, high)) = self.redirect_index() {
+            let old = self.redirect[pin];
+            let new = if high {
+                (old & 0xffffffff) | ((val as u64) << 32)
+            } else {
+                (old & !0xffffffff) | (val as u64 & !REDIR_RO_BITS) | (old & REDIR_RO_BITS)
+            };
+            self.redirect[pin] = new;
+            self.update_route(pin);
+            if old & REDIR_MASKED != 0 && !self.is_masked(pin) && self.is_level_triggered(pin) && self.levels[pin] {
+                self.service(pin);
+            }
+        }
+    }
+
+    fn is_masked(&self, pin: usize) -> bool {
+        self.redirect[pin] & REDIR_MASKED != 0
+    }
+
+    fn is_level_triggered(&self, pin: usize) -> bool {
+        self.redirect[pin] & REDIR_TRIGGER_LEVEL != 0
+    }
+
+    fn msi_message(&self, pin: usize) -> MsiMessage {
+        let entry = self.redirect[pin];
+        let dest = entry >> REDIR_DEST_SHIFT;
+        let dest_mode = (entry >> REDIR_DEST_MODE_SHIFT) & 1;
+        let address = MSI_ADDRESS_BASE | (dest << MSI_DEST_ID_SHIFT) | (dest_mode << MSI_DEST_MODE_SHIFT);
+
+        let vector = (entry & REDIR_VECTOR_MASK) as u32;
+        let delivery_mode = ((entry >> REDIR_DELIVERY_MODE_SHIFT) & REDIR_DELIVERY_MODE_MASK) as u32;
+        let mut data = vector | (delivery_mode << MSI_DELIVERY_MODE_SHIFT);
+        if self.is_level_triggered(pin) {
+            data |= MSI_TRIGGER_LEVEL;
+        }
+        MsiMessage::new(address, data)
+    }
+
+    fn update_route(&self, pin: usize) {
+        let msg = if self.is_masked(pin) {
+            None
+        } else {
+            Some(self.msi_message(pin))
+        };
+        if let Err(e) = self.kvm_vm.set_ioapic_route(pin as u32, msg) {
+            warn!("ioapic: failed to update route for pin {}: {}", pin, e);
+        }
+    }
+
+    /// Deliver the interrupt for `pin` unless it is masked or a level
+    /// triggered interrupt is still waiting for an EOI.
+    fn service(&mut self, pin: usize) {
+        if self.is_masked(pin) || self.redirect[pin] & REDIR_REMOTE_IRR != 0 {
+            return;
+        }
+        if self.is_level_triggered(pin) {
+            self.redirect[pin] |= REDIR_REMOTE_IRR;
+        }
+        if let Err(e) = self.kvm_vm.signal_msi(self.msi_message(pin)) {
+            warn!("ioapic: failed to deliver interrupt for pin {}: {}", pin, e);
+        }
+    }
+
+    /// Set the input level of `pin`. Edge triggered pins deliver an
+    /// interrupt on a rising edge, level triggered pins whenever the line is
+    /// high and no earlier interrupt is waiting for an EOI.
+    pub fn set_level(&mut self, pin: u8, level: bool) {
+        let pin = pin as usize;
+        if pin >= NUM_PINS {
+            return;
+        }
+        let was_high = self.levels[pin];
+        self.levels[pin] = level;
+        if level && (!was_high || self.is_level_triggered(pin)) {
+            self.service(pin);
+        }
+    }
+
+    /// Called when a vCPU exits after the guest acknowledged a level
+    /// triggered interrupt with `vector`. Pins which are still asserted are
+    /// delivered again.
+    pub fn end_of_interrupt(&mut self, vector: u8) {
+        for pin in 0..NUM_PINS {
+            let entry = self.redirect[pin];
+            if entry & REDIR_REMOTE_IRR != 0 && (entry & REDIR_VECTOR_MASK) as u8 == vector {
+                self.redirect[pin] &= !REDIR_REMOTE_IRR;
+                if self.levels[pin] {
+                    self.service(pin);
+                }
+            }
+        }
+    }
+}
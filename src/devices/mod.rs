@@ -1,18 +1,29 @@
+#[cfg(feature = "audio")]
 pub mod ac97;
 pub mod serial;
 pub mod rtc;
+pub mod ioapic;
+pub mod rom;
 mod virtio_9p;
 mod virtio_serial;
 mod virtio_rng;
+#[cfg(feature = "wayland")]
 mod virtio_wl;
 mod virtio_block;
+#[cfg(feature = "network")]
 mod virtio_net;
-mod irq_event;
 
-pub use self::virtio_serial::VirtioSerial;
-pub use self::virtio_9p::VirtioP9;
+pub use self::virtio_serial::{VirtioSerial, ConsoleOptions, CtrlCPolicy};
+pub use self::virtio_9p::{VirtioP9, ShareControl, QuotaLimits, FidLimits};
 pub use self::virtio_9p::SyntheticFS;
 pub use self::virtio_rng::VirtioRandom;
-pub use self::virtio_wl::VirtioWayland;
+#[cfg(feature = "wayland")]
+pub use self::virtio_wl::{VirtioWayland, WaylandControl};
 pub use self::virtio_block::VirtioBlock;
-pub use self::virtio_net::VirtioNet;
+#[cfg(feature = "network")]
+pub use self::virtio_net::{VirtioNet, NetControl, NetRateLimit};
+
+#[cfg(feature = "fuzzing")]
+pub use self::virtio_9p::fuzz_pdu as fuzz_9p_pdu;
+#[cfg(all(feature = "fuzzing", feature = "wayland"))]
+pub use self::virtio_wl::fuzz_command as fuzz_wl_command;
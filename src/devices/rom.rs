@@ -0,0 +1,32 @@
+use std::cmp;
+use crate::io::bus::BusDevice;
+
+///
+/// A read-only image served from the MMIO bus, such as an option ROM or a
+/// firmware table. Reads past the end of the image return zeroes and writes
+/// are ignored.
+///
+pub struct RomDevice {
+    data: Vec<u8>,
+}
+
+impl RomDevice {
+    pub fn new(data: Vec<u8>) -> Self {
+        RomDevice { data }
+    }
+}
+
+impl BusDevice for RomDevice {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        self.peek(offset, data);
+    }
+
+    fn peek(&self, offset: u64, data: &mut [u8]) -> bool {
+        let start = cmp::min(offset, self.data.len() as u64) as usize;
+        let end = cmp::min(start + data.len(), self.data.len());
+        let n = end - start;
+        data[..n].copy_from_slice(&self.data[start..end]);
+        data[n..].fill(0);
+        true
+    }
+}
@@ -1,4 +1,4 @@
-use std::mem;
+use std::{cmp, mem};
 use libc;
 use crate::io::bus::BusDevice;
 use crate::io::ReadableInt;
@@ -15,19 +15,22 @@ const RTC_CENTURY: u8 = 0x32;
 const RTC_REG_C: u8 = 0x0C;
 const RTC_REG_D: u8 = 0x0D;
 
+// Memory size registers read by a BIOS, in the layout used by QEMU
+const CMOS_BASE_MEMORY: usize = 0x15;
+const CMOS_EXTENDED_MEMORY: usize = 0x17;
+const CMOS_EXTENDED_MEMORY2: usize = 0x30;
+const CMOS_MEMORY_ABOVE_16M: usize = 0x34;
+const CMOS_MEMORY_ABOVE_4G: usize = 0x5b;
+
 pub struct Rtc {
     idx: u8,
-    data: [u8; 128]
+    data: [u8; 128],
+    localtime: bool,
 }
 
 impl BusDevice for Rtc {
     fn read(&mut self, offset: u64, data: &mut [u8]) {
-        if offset == 1 && data.len() == 1 {
-            ReadableInt::new_byte(self.data_in())
-                .read(data);
-        } else {
-            data.fill(0);
-        }
+        self.peek(offset, data);
     }
 
     fn write(&mut self, offset: u64, data: &[u8]) {
@@ -39,24 +42,61 @@ impl BusDevice for Rtc {
             }
         }
     }
+
+    fn peek(&self, offset: u64, data: &mut [u8]) -> bool {
+        if offset == 1 && data.len() == 1 {
+            ReadableInt::new_byte(self.data_in())
+                .read(data);
+        } else {
+            data.fill(0);
+        }
+        true
+    }
 }
 
 impl Rtc {
 
-    pub fn new() -> Rtc {
+    /// Create the CMOS device. The clock reads as UTC unless `localtime` is
+    /// set, in which case it follows the local time of the host.
+    pub fn new(localtime: bool) -> Rtc {
         Rtc {
             idx:0,
-            data: [0; 128]
+            data: [0; 128],
+            localtime,
         }
     }
 
+    /// Store the size of guest RAM in the CMOS registers a BIOS reads to
+    /// size memory. `low_mem` is the RAM below 4GB and `high_mem` the RAM
+    /// above it.
+    pub fn set_memory_size(&mut self, low_mem: u64, high_mem: u64) {
+        const MB: u64 = 1 << 20;
+        // Conventional memory in KB
+        self.set_word(CMOS_BASE_MEMORY, 640);
+        // Memory above 1MB in KB, up to 64MB
+        let extended = cmp::min(low_mem.saturating_sub(MB) >> 10, 0xffff) as u16;
+        self.set_word(CMOS_EXTENDED_MEMORY, extended);
+        self.set_word(CMOS_EXTENDED_MEMORY2, extended);
+        // Memory above 16MB in 64KB units
+        let above_16m = cmp::min(low_mem.saturating_sub(16 * MB) >> 16, 0xffff) as u16;
+        self.set_word(CMOS_MEMORY_ABOVE_16M, above_16m);
+        // Memory above 4GB in 64KB units, three bytes wide
+        let above_4g = cmp::min(high_mem >> 16, 0xff_ffff) as u32;
+        self.data[CMOS_MEMORY_ABOVE_4G..CMOS_MEMORY_ABOVE_4G + 3]
+            .copy_from_slice(&above_4g.to_le_bytes()[..3]);
+    }
+
+    fn set_word(&mut self, idx: usize, val: u16) {
+        self.data[idx..idx + 2].copy_from_slice(&val.to_le_bytes());
+    }
+
     fn index_out(&mut self, data: u8) {
         let _nmi_disable = data & 0x80;
         self.idx = data & 0x7f;
     }
 
-    fn data_in(&mut self) -> u8 {
-        let now = RtcTime::now();
+    fn data_in(&self) -> u8 {
+        let now = RtcTime::now(self.localtime);
         match self.idx {
             RTC_SECONDS => now.seconds,
             RTC_MINUTES => now.minutes,
@@ -90,7 +130,7 @@ struct RtcTime {
 }
 
 impl RtcTime {
-    fn now() -> RtcTime {
+    fn now(localtime: bool) -> RtcTime {
         fn bcd(val: i32) -> u8 {
             (((val/10) << 4) + (val % 10)) as u8
         }
@@ -98,7 +138,11 @@ impl RtcTime {
             let mut tm: libc::tm = mem::zeroed();
             let mut time: libc::time_t = 0;
             libc::time(&mut time as *mut _);
-            libc::gmtime_r(&time, &mut tm as *mut _);
+            if localtime {
+                libc::localtime_r(&time, &mut tm as *mut _);
+            } else {
+                libc::gmtime_r(&time, &mut tm as *mut _);
+            }
             RtcTime {
                 seconds: bcd(tm.tm_sec),
                 minutes: bcd(tm.tm_min),
@@ -119,13 +119,24 @@ impl BusDevice for SerialDevice {
             self.serial_out(offset as u16, data[0])
         }
     }
+
+    fn peek(&self, offset: u64, data: &mut [u8]) -> bool {
+        if data.len() == 1 {
+            data[0] = self.register_value(offset as u16);
+        } else {
+            data.fill(0);
+        }
+        true
+    }
 }
 
 impl SerialDevice {
     fn flush_tx(&mut self) {
         self.lsr.set(UART_LSR_TEMT | UART_LSR_THRE);
         if self.txcnt > 0 {
-            io::stdout().write(&self.txbuf[..self.txcnt]).unwrap();
+            if let Err(e) = io::stdout().write_all(&self.txbuf[..self.txcnt]) {
+                warn_limited!("serial: error writing to stdout: {}", e);
+            }
             self.txcnt = 0;
         }
     }
@@ -156,12 +167,12 @@ impl SerialDevice {
         if iir == 0 {
             self.iir = UART_IIR_NO_INT;
             if self.irq_state != 0 {
-                self.kvm_vm.set_irq_line(self.irq as u32, false).unwrap();
+                self.set_irq_line(false);
             }
         } else {
             self.iir = iir;
             if self.irq_state == 0 {
-                self.kvm_vm.set_irq_line(self.irq as u32, true).unwrap();
+                self.set_irq_line(true);
             }
         }
         self.irq_state = iir;
@@ -171,6 +182,12 @@ impl SerialDevice {
         }
     }
 
+    fn set_irq_line(&self, active: bool) {
+        if let Err(e) = self.kvm_vm.set_irq_line(self.irq as u32, active) {
+            warn_limited!("serial: failed to set irq line {}: {}", self.irq, e);
+        }
+    }
+
     fn tx(&mut self, data: u8) {
         if self.lcr.is_set(UART_LCR_DLAB) {
             self.dll = data;
@@ -230,45 +247,44 @@ impl SerialDevice {
         self.update_irq();
     }
 
+    // Only reading the receive buffer changes the state of the device.
     fn serial_in(&mut self, port: u16) -> u8 {
-        let mut data = 0u8;
+        if port == UART_RX && !self.lcr.is_set(UART_LCR_DLAB) {
+            let mut data = 0u8;
+            self.rx(&mut data);
+            self.update_irq();
+            data
+        } else {
+            self.register_value(port)
+        }
+    }
+
+    fn register_value(&self, port: u16) -> u8 {
         match port {
             UART_RX => {
                 if self.lcr.is_set(UART_LCR_DLAB) {
-                    data = self.dll;
+                    self.dll
+                } else if self.rxdone == self.rxcnt || self.lsr.is_set(UART_LSR_BI) {
+                    0
                 } else {
-                    self.rx(&mut data);
+                    self.rxbuf[self.rxdone]
                 }
             },
             UART_IER => {
                 if self.lcr.is_set(UART_LCR_DLAB) {
-                    data = self.dlm;
+                    self.dlm
                 } else {
-                    data = self.ier;
+                    self.ier
                 }
             },
-            UART_IIR => {
-                data = self.iir & UART_IIR_TYPE_BITS;
-            },
-            UART_LCR => {
-                data = self.lcr;
-            },
-            UART_MCR => {
-                data = self.mcr;
-            },
-            UART_LSR => {
-                data = self.lsr;
-            },
-            UART_MSR => {
-                data = self.msr;
-            },
-            UART_SCR => {
-                data = self.scr;
-            },
-            _ => {},
+            UART_IIR => self.iir & UART_IIR_TYPE_BITS,
+            UART_LCR => self.lcr,
+            UART_MCR => self.mcr,
+            UART_LSR => self.lsr,
+            UART_MSR => self.msr,
+            UART_SCR => self.scr,
+            _ => 0,
         }
-        self.update_irq();
-        data
     }
 
 
@@ -0,0 +1,124 @@
+use std::io;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::devices::virtio_9p::filesystem::FileSystem;
+use crate::io::VirtQueue;
+
+// How often a server held by a quiesce checks whether its queue was stopped
+const HOLD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+///
+/// Coordinates replacing the host directory exported by a 9p share while the
+/// guest is running, for example when the home directory of a realm is
+/// unmounted and mounted again during an update.
+///
+/// `quiesce()` waits for the request being processed to complete and holds
+/// back any further requests. Every host file opened for the guest is closed
+/// along with the descriptor held for the exported directory, so nothing in
+/// pH keeps the directory busy. `resume()` opens the directory again and
+/// releases the held requests.
+///
+/// The fids the guest held before the quiesce fail with `ESTALE` afterwards,
+/// which ph-init takes as the signal to mount the share again.
+///
+pub struct ShareControl {
+    filesystem: FileSystem,
+    state: Mutex<ShareState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct ShareState {
+    // Set by quiesce() and cleared by resume()
+    quiesce: bool,
+    // The server has dropped its fids and is holding requests
+    held: bool,
+    // Queue of the most recently started server
+    queue: Option<VirtQueue>,
+}
+
+impl ShareControl {
+    pub fn new(filesystem: FileSystem) -> Self {
+        ShareControl {
+            filesystem,
+            state: Mutex::new(ShareState::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<ShareState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn is_quiesced(&self) -> bool {
+        self.state().quiesce
+    }
+
+    /// Stop serving the share and release the exported directory. Fails with
+    /// `TimedOut` if the request being processed does not complete within
+    /// `timeout`, in which case the share keeps running.
+    pub fn quiesce(&self, timeout: Duration) -> io::Result<()> {
+        let mut state = self.state();
+        if state.quiesce {
+            return Ok(());
+        }
+        state.quiesce = true;
+
+        let running = match state.queue {
+            Some(ref vq) if !vq.is_stopped() => {
+                // Wake the server if it is waiting for a request
+                let _ = vq.ioevent().write(1);
+                true
+            }
+            _ => false,
+        };
+        if running {
+            let deadline = Instant::now() + timeout;
+            while !state.held {
+                let now = Instant::now();
+                if now >= deadline {
+                    state.quiesce = false;
+                    return Err(io::Error::from(io::ErrorKind::TimedOut));
+                }
+                state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+            }
+        }
+        self.filesystem.detach();
+        Ok(())
+    }
+
+    /// Open the exported directory again and continue serving the share. If
+    /// the directory cannot be opened the share stays quiesced.
+    pub fn resume(&self) -> io::Result<()> {
+        let mut state = self.state();
+        if !state.quiesce {
+            return Ok(());
+        }
+        self.filesystem.reattach()?;
+        state.quiesce = false;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    pub(super) fn set_queue(&self, vq: VirtQueue) {
+        self.state().queue = Some(vq);
+    }
+
+    /// Called by the server before each request. While a quiesce is in
+    /// effect `release` is called and the server is held until `resume()` or
+    /// until `vq` is stopped.
+    pub(super) fn checkpoint<F: FnOnce()>(&self, vq: &VirtQueue, release: F) {
+        let mut state = self.state();
+        if !state.quiesce {
+            return;
+        }
+        release();
+        state.held = true;
+        self.changed.notify_all();
+        while state.quiesce && !vq.is_stopped() {
+            state = self.changed.wait_timeout(state, HOLD_POLL_INTERVAL).unwrap().0;
+        }
+        state.held = false;
+    }
+}
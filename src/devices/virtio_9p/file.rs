@@ -1,7 +1,9 @@
 use std::cell::{RefCell, RefMut, Cell};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use std::{io, fmt};
+use std::time::{Duration, Instant};
 use std::path::{Path, PathBuf, Component};
+use std::mem;
 use std::fs::{Metadata, File};
 use std::os::unix::io::{RawFd,AsRawFd};
 use std::os::linux::fs::MetadataExt;
@@ -12,6 +14,7 @@ use crate::devices::virtio_9p::{
 use std::io::{Cursor, SeekFrom, Seek};
 use std::sync::{RwLock, Arc};
 use vm_memory::{ReadVolatile, VolatileSlice, WriteVolatile};
+use crate::io::stats::{Counter, DeviceStats, Gauge};
 
 pub const P9_DOTL_RDONLY: u32        = 0o00000000;
 pub const P9_DOTL_WRONLY: u32        = 0o00000001;
@@ -109,6 +112,15 @@ impl P9File {
         Self::new(FileObject::BufferFile(buffer))
     }
 
+    /// The host file if this is backed by one, for I/O which goes directly
+    /// between the file and guest memory.
+    pub fn local_file(&self) -> Option<&File> {
+        match self.file {
+            FileObject::File(ref f) => Some(f),
+            _ => None,
+        }
+    }
+
     pub fn sync_all(&self) -> io::Result<()> {
         match self.file {
             FileObject::File(ref f) => f.sync_all(),
@@ -316,10 +328,85 @@ pub fn translate_p9_flags(flags: u32, is_root: bool) -> libc::c_int {
     custom
 }
 
+/// Limits on the fids a guest may hold on one mount.
+#[derive(Copy,Clone,Debug)]
+pub struct FidLimits {
+    /// Maximum number of fids.
+    pub max_fids: usize,
+    /// Maximum number of fids with an open host file.
+    pub max_open: usize,
+    /// Fids of files which have no open host file and have not been used for
+    /// this long are clunked by the server. Off by default, since the Linux
+    /// v9fs client keeps the fids of cached dentries and expects them to stay
+    /// valid for as long as it holds them.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for FidLimits {
+    fn default() -> Self {
+        FidLimits {
+            max_fids: 16384,
+            max_open: 512,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl FidLimits {
+    /// Parse limits of the form `fids=COUNT,open=COUNT,idle=SECONDS` where
+    /// any field may be omitted to keep the default.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut limits = FidLimits::default();
+        for field in s.split(',') {
+            let (key, value) = field.split_once('=')?;
+            match key {
+                "fids" => limits.max_fids = value.parse().ok()?,
+                "open" => limits.max_open = value.parse().ok()?,
+                "idle" => limits.idle_timeout = Some(Duration::from_secs(value.parse().ok()?)),
+                _ => return None,
+            }
+        }
+        Some(limits)
+    }
+}
+
+/// Fid accounting reported with the device statistics.
+#[derive(Clone,Default)]
+pub struct FidStats {
+    fids: Arc<Gauge>,
+    open_files: Arc<Gauge>,
+    rejected: Arc<Counter>,
+    expired: Arc<Counter>,
+}
+
+impl FidStats {
+    pub fn register(stats: &DeviceStats) -> Self {
+        let fid_stats = FidStats {
+            rejected: stats.counter("fids_rejected"),
+            expired: stats.counter("fids_expired"),
+            ..Default::default()
+        };
+        stats.add_gauge("fids", fid_stats.fids.clone());
+        stats.add_gauge("open_files", fid_stats.open_files.clone());
+        fid_stats
+    }
+}
+
+// How often idle fids are looked for when the fid limit has not been reached
+const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct Fids<T: FileSystemOps> {
     ops: T,
     root: PathBuf,
     fidmap: BTreeMap<u32, Fid<T>>,
+    limits: FidLimits,
+    stats: FidStats,
+    // Ids of fids clunked by the server which the guest has not clunked yet
+    expired: HashSet<u32>,
+    // Ids of fids dropped by invalidate() which the guest has not clunked yet
+    stale: HashSet<u32>,
+    last_sweep: Instant,
+    open_files: usize,
 }
 
 impl <T: FileSystemOps> Fids<T> {
@@ -328,15 +415,35 @@ impl <T: FileSystemOps> Fids<T> {
             ops,
             root,
             fidmap: BTreeMap::new(),
+            limits: FidLimits::default(),
+            stats: FidStats::default(),
+            expired: HashSet::new(),
+            stale: HashSet::new(),
+            last_sweep: Instant::now(),
+            open_files: 0,
         }
     }
 
+    pub fn set_limits(&mut self, limits: FidLimits) {
+        self.limits = limits;
+    }
+
+    pub fn set_stats(&mut self, stats: FidStats) {
+        self.stats = stats;
+        self.update_stats();
+    }
+
     pub fn fid(&self, id: u32) -> io::Result<&Fid<T>> {
-        self.fidmap.get(&id).ok_or(Self::bad_fd_error())
+        let fid = self.fidmap.get(&id).ok_or_else(|| Self::missing_fid_error(&self.stale, id))?;
+        fid.last_used.set(Instant::now());
+        Ok(fid)
     }
 
     pub fn fid_mut(&mut self, id: u32) -> io::Result<&mut Fid<T>> {
-        self.fidmap.get_mut(&id).ok_or(Self::bad_fd_error())
+        let stale = &self.stale;
+        let fid = self.fidmap.get_mut(&id).ok_or_else(|| Self::missing_fid_error(stale, id))?;
+        fid.last_used.set(Instant::now());
+        Ok(fid)
     }
 
     pub fn read_fid(&self, pp: &mut PduParser) -> io::Result<&Fid<T>> {
@@ -360,22 +467,114 @@ impl <T: FileSystemOps> Fids<T> {
     }
 
     pub fn clear(&mut self) {
-        self.fidmap.clear()
+        self.fidmap.clear();
+        self.expired.clear();
+        self.stale.clear();
+        self.open_files = 0;
+        self.update_stats();
+    }
+
+    /// Drop every fid and close the open host files. Requests using one of
+    /// the dropped fids fail with `ESTALE` until the guest clunks it.
+    pub fn invalidate(&mut self) {
+        let fidmap = mem::take(&mut self.fidmap);
+        self.stale.extend(fidmap.into_keys());
+        self.open_files = 0;
+        self.update_stats();
+    }
+
+    /// Add `fid`, replacing any fid with the same id. Fails with `EMFILE` if
+    /// this would exceed the fid limit even after idle fids are expired.
+    pub fn add(&mut self, fid: Fid<T>) -> io::Result<()> {
+        if !self.fidmap.contains_key(&fid.id) && self.fidmap.len() >= self.limits.max_fids {
+            self.expire_idle();
+            if self.fidmap.len() >= self.limits.max_fids {
+                self.stats.rejected.inc();
+                return system_error(libc::EMFILE);
+            }
+        } else if self.last_sweep.elapsed() >= IDLE_SWEEP_INTERVAL {
+            self.expire_idle();
+        }
+        self.expired.remove(&fid.id);
+        self.stale.remove(&fid.id);
+        if let Some(old) = self.fidmap.insert(fid.id, fid) {
+            self.file_closed(&old);
+        }
+        self.update_stats();
+        Ok(())
     }
 
-    pub fn add(&mut self, fid: Fid<T>) {
-        self.fidmap.insert(fid.id, fid);
+    /// Attach an open host file to the fid `id`. Fails with `EMFILE` if the
+    /// limit on open files has been reached.
+    pub fn set_file(&mut self, id: u32, file: P9File) -> io::Result<()> {
+        let has_file = self.fid(id)?.file.is_some();
+        if !has_file {
+            if self.open_files >= self.limits.max_open {
+                self.stats.rejected.inc();
+                return system_error(libc::EMFILE);
+            }
+            self.open_files += 1;
+        }
+        self.fid_mut(id)?.set_file(file);
+        self.update_stats();
+        Ok(())
     }
 
     pub fn exists(&self, id: u32) -> bool {
         self.fidmap.contains_key(&id)
     }
 
-    pub fn remove(&mut self, id: u32) -> io::Result<Fid<T>> {
-        match self.fidmap.remove(&id) {
-            Some(fid) => Ok(fid),
+    /// Remove the fid `id` for Tclunk or Tremove. Returns `None` if the fid
+    /// was already clunked by the server because it was idle, which is not
+    /// an error for the guest.
+    pub fn remove(&mut self, id: u32) -> io::Result<Option<Fid<T>>> {
+        let result = match self.fidmap.remove(&id) {
+            Some(fid) => {
+                self.file_closed(&fid);
+                Ok(Some(fid))
+            },
+            None if self.expired.remove(&id) || self.stale.remove(&id) => Ok(None),
             None => Err(Self::bad_fd_error())
+        };
+        self.update_stats();
+        result
+    }
+
+    fn file_closed(&mut self, fid: &Fid<T>) {
+        if fid.file.is_some() {
+            self.open_files -= 1;
+        }
+    }
+
+    /// Clunk fids without an open file which have been idle for longer than
+    /// the idle timeout. The ids are remembered so that a later Tclunk from
+    /// the guest still succeeds.
+    ///
+    /// Directory fids are never expired. This includes the fid of every
+    /// attach, and the fids the guest walks from to reach other files.
+    fn expire_idle(&mut self) {
+        self.last_sweep = Instant::now();
+        let timeout = match self.limits.idle_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let idle = self.fidmap.values()
+            .filter(|f| f.file.is_none() && !f.qid.is_dir() && f.last_used.get().elapsed() >= timeout)
+            .map(|f| f.id)
+            .collect::<Vec<_>>();
+        for id in idle {
+            self.fidmap.remove(&id);
+            if self.expired.len() < self.limits.max_fids {
+                self.expired.insert(id);
+            }
+            self.stats.expired.inc();
         }
+        self.update_stats();
+    }
+
+    fn update_stats(&self) {
+        self.stats.fids.set(self.fidmap.len() as u64);
+        self.stats.open_files.set(self.open_files as u64);
     }
 
     pub fn create<P: Into<PathBuf>>(&self, id: u32, path: P) -> io::Result<Fid<T>> {
@@ -389,6 +588,14 @@ impl <T: FileSystemOps> Fids<T> {
     fn bad_fd_error() -> io::Error {
         io::Error::from_raw_os_error(libc::EBADF)
     }
+
+    fn missing_fid_error(stale: &HashSet<u32>, id: u32) -> io::Error {
+        if stale.contains(&id) {
+            io::Error::from_raw_os_error(libc::ESTALE)
+        } else {
+            Self::bad_fd_error()
+        }
+    }
 }
 
 pub struct Fid<T: FileSystemOps> {
@@ -398,6 +605,7 @@ pub struct Fid<T: FileSystemOps> {
     qid: Qid,
     file: Option<P9File>,
     directory: RefCell<Option<Directory>>,
+    last_used: Cell<Instant>,
 }
 
 impl <T: FileSystemOps> Fid<T> {
@@ -408,6 +616,7 @@ impl <T: FileSystemOps> Fid<T> {
             ops, id, path, qid,
             file: None,
             directory: RefCell::new(None),
+            last_used: Cell::new(Instant::now()),
         })
     }
 
@@ -432,7 +641,7 @@ impl <T: FileSystemOps> Fid<T> {
         Ok(())
     }
 
-    pub fn set_file(&mut self, file: P9File) {
+    fn set_file(&mut self, file: P9File) {
         self.file = Some(file)
     }
 
@@ -2,11 +2,12 @@ use std::ffi::{CString,OsString};
 use std::fs::{self, File, Metadata, OpenOptions};
 use std::io;
 use std::mem;
-use std::os::unix;
-use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::{DirBuilderExt,OpenOptionsExt,PermissionsExt};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 use std::os::linux::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 
 use libc;
@@ -15,6 +16,8 @@ use crate::devices::virtio_9p::file::{
 };
 use crate::devices::virtio_9p::pdu::PduParser;
 use crate::devices::virtio_9p::directory::{Directory, P9DirEntry};
+use crate::devices::virtio_9p::resolve::{self, PathResolver};
+use crate::devices::virtio_9p::quota::ShareQuota;
 
 
 pub enum FsTouch {
@@ -42,35 +45,50 @@ pub trait FileSystemOps: Clone+Sync+Send {
     fn remove_dir(&self, path: &Path) -> io::Result<()>;
     fn create_dir(&self, path: &Path, mode: u32) -> io::Result<()>;
     fn readdir_populate(&self, path: &Path) -> io::Result<Directory>;
+
+    /// Check that writing `count` bytes at `offset` to `file` stays within
+    /// any quota on the filesystem. Returns the size of the file before the
+    /// write if `write_done()` must be called once it completes.
+    fn check_write(&self, _file: &P9File, _offset: u64, _count: u32) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Account for a write to `file` which was `old_size` bytes long before
+    fn write_done(&self, _file: &P9File, _old_size: u64) {}
 }
 
 #[derive(Clone)]
 pub struct FileSystem {
-    _root: PathBuf,
+    resolver: Arc<PathResolver>,
     _readonly: bool,
     euid_root: bool,
+    quota: Option<Arc<ShareQuota>>,
 }
 
 impl FileSystem {
-    pub fn new(root: PathBuf, readonly: bool) -> FileSystem {
+    pub fn new(root: PathBuf, readonly: bool) -> io::Result<FileSystem> {
         let euid_root = Self::is_euid_root();
-        FileSystem { _root: root, _readonly: readonly, euid_root }
+        let resolver = Arc::new(PathResolver::new(&root)?);
+        Ok(FileSystem { resolver, _readonly: readonly, euid_root, quota: None })
     }
 
-    pub fn is_euid_root() -> bool {
-        unsafe { libc::geteuid() == 0 }
+    /// Limit the space and number of files the guest may use below the root
+    pub fn set_quota(&mut self, quota: Arc<ShareQuota>) {
+        self.quota = Some(quota);
     }
 
-    pub fn create_with_flags(path: &Path, flags: u32, mode: u32, is_root: bool) -> io::Result<File> {
-        let rdwr = flags & libc::O_ACCMODE as u32;
-        let flags = translate_p9_flags(flags, is_root) &!libc::O_TRUNC;
-        OpenOptions::new()
-            .read(rdwr == P9_DOTL_RDONLY || rdwr == P9_DOTL_RDWR)
-            .write(rdwr == P9_DOTL_WRONLY || rdwr == P9_DOTL_RDWR)
-            .custom_flags(flags)
-            .create_new(true)
-            .mode(mode)
-            .open(path)
+    /// Close the descriptor held for the exported directory, see
+    /// `ShareControl::quiesce()`.
+    pub fn detach(&self) {
+        self.resolver.detach();
+    }
+
+    pub fn reattach(&self) -> io::Result<()> {
+        self.resolver.reattach()
+    }
+
+    pub fn is_euid_root() -> bool {
+        unsafe { libc::geteuid() == 0 }
     }
 
     pub fn open_with_flags(path: &Path, flags: u32, is_root: bool) -> io::Result<File> {
@@ -84,17 +102,69 @@ impl FileSystem {
             .open(path)
     }
 
+    fn access_flags(flags: u32) -> libc::c_int {
+        match flags & libc::O_ACCMODE as u32 {
+            P9_DOTL_WRONLY => libc::O_WRONLY,
+            P9_DOTL_RDWR => libc::O_RDWR,
+            _ => libc::O_RDONLY,
+        }
+    }
+
     fn new_file(&self, file: File) -> P9File {
         P9File::from_file(file)
     }
 
     fn metadata(&self, path: &Path) -> io::Result<Metadata> {
-        path.symlink_metadata()
+        self.resolver.open_path(path)?.metadata()
+    }
+
+    // Count a new inode against the quota while `f` creates it
+    fn with_new_inode<R, F>(&self, f: F) -> io::Result<R>
+        where F: FnOnce() -> io::Result<R>
+    {
+        let quota = match &self.quota {
+            Some(quota) => quota,
+            None => return f(),
+        };
+        quota.add_inode()?;
+        let result = f();
+        if result.is_err() {
+            quota.cancel_inode();
+        }
+        result
+    }
+
+    // Remove or replace the entry at `path` with `f`, releasing the quota
+    // usage of the entry if this succeeds
+    fn with_removed_entry<F>(&self, path: &Path, f: F) -> io::Result<()>
+        where F: FnOnce() -> io::Result<()>
+    {
+        let quota = match &self.quota {
+            Some(quota) => quota,
+            None => return f(),
+        };
+        let meta = self.metadata(path).ok();
+        f()?;
+        if let Some(meta) = meta {
+            quota.remove(&meta);
+        }
+        Ok(())
+    }
+
+    // Run `f` with the parent directory of `path` and the final name
+    fn with_parent<F>(&self, path: &Path, f: F) -> io::Result<()>
+        where F: FnOnce(libc::c_int, &CString) -> libc::c_int
+    {
+        let (dir, name) = self.resolver.parent(path)?;
+        if f(dir.as_raw_fd(), &name) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
     }
 }
 
 fn cstr(path: &Path) -> io::Result<CString> {
-    Ok(CString::new(path.as_os_str().as_bytes())?)
+    resolve::cstr(path.as_os_str())
 }
 
 impl FileSystemOps for FileSystem {
@@ -135,25 +205,37 @@ impl FileSystemOps for FileSystem {
     }
 
     fn open(&self, path: &Path, flags: u32) -> io::Result<P9File> {
-        let file =FileSystem::open_with_flags(&path, flags, self.euid_root)?;
+        let oflags = Self::access_flags(flags) | translate_p9_flags(flags, self.euid_root);
+        let truncated = match &self.quota {
+            Some(_) if oflags & libc::O_TRUNC != 0 => self.metadata(path).ok().filter(|m| m.is_file()),
+            _ => None,
+        };
+        let file = self.resolver.open(path, oflags, 0)?;
+        if let (Some(quota), Some(meta)) = (&self.quota, truncated) {
+            quota.resize(meta.len(), 0);
+        }
         Ok(self.new_file(file))
     }
 
     fn create(&self, path: &Path, flags: u32, mode: u32) -> io::Result<P9File> {
-        let file = FileSystem::create_with_flags(&path, flags, mode, self.euid_root)?;
+        let oflags = Self::access_flags(flags) | translate_p9_flags(flags, self.euid_root) & !libc::O_TRUNC;
+        let file = self.with_new_inode(|| self.resolver.open(path, oflags | libc::O_CREAT | libc::O_EXCL, mode))?;
         Ok(self.new_file(file))
     }
 
     fn write_statfs(&self, path: &Path, pp: &mut PduParser) -> io::Result<()> {
-        let path_cstr = cstr(&path)?;
+        let file = self.resolver.open_path(path)?;
 
         let mut statfs: libc::statfs64 = unsafe { mem::zeroed() };
         unsafe {
-            let ret = libc::statfs64(path_cstr.as_ptr(), &mut statfs);
+            let ret = libc::fstatfs64(file.as_raw_fd(), &mut statfs);
             if ret < 0 {
                 return Err(io::Error::last_os_error());
             }
         }
+        if let Some(quota) = &self.quota {
+            quota.adjust_statfs(&mut statfs);
+        }
         pp.w32(statfs.f_type as u32)?;
         pp.w32(statfs.f_bsize as u32)?;
         pp.w64(statfs.f_blocks)?;
@@ -167,22 +249,28 @@ impl FileSystemOps for FileSystem {
     }
 
     fn chown(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
-        let path_cstr = cstr(&path)?;
+        self.with_parent(path, |dirfd, name| unsafe {
+            libc::fchownat(dirfd, name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW)
+        })
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        // fchmodat() always follows a symlink, so change the mode through the
+        // O_PATH descriptor which cannot refer to anything outside the root.
+        let file = self.resolver.open_path(path)?;
+        if file.metadata()?.file_type().is_symlink() {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        let proc_path = cstr(&resolve::proc_fd_path(&file))?;
         unsafe {
-            if libc::chown(path_cstr.as_ptr(), uid, gid) < 0 {
+            if libc::chmod(proc_path.as_ptr(), mode & 0o7777) < 0 {
                 return Err(io::Error::last_os_error());
             }
-            Ok(())
         }
-    }
-
-    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
-        let meta = self.metadata(path)?;
-        Ok(meta.permissions().set_mode(mode))
+        Ok(())
     }
 
     fn touch(&self, path: &Path, which: FsTouch, tv: (u64, u64)) -> io::Result<()> {
-        let path_cstr = cstr(&path)?;
 
         let tval = libc::timespec {
             tv_sec: tv.0 as i64,
@@ -203,59 +291,84 @@ impl FileSystemOps for FileSystem {
             FsTouch::Mtime => [omit, tval ],
             FsTouch::MtimeNow => [omit, now],
         };
-        unsafe {
-            if libc::utimensat(-1, path_cstr.as_ptr(), times.as_ptr(), 0) < 0 {
-                return Err(io::Error::last_os_error());
-            }
-        }
-        Ok(())
+        self.with_parent(path, |dirfd, name| unsafe {
+            libc::utimensat(dirfd, name.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW)
+        })
     }
 
     fn truncate(&self, path: &Path, size: u64) -> io::Result<()> {
-        let path_cstr = cstr(&path)?;
-        unsafe {
-            if libc::truncate64(path_cstr.as_ptr(), size as i64) < 0 {
-                return Err(io::Error::last_os_error());
-            }
-        }
+        let file = self.resolver.open(path, libc::O_WRONLY, 0)?;
+        let quota = match &self.quota {
+            Some(quota) => quota,
+            None => return file.set_len(size),
+        };
+        let old_size = file.metadata()?.len();
+        quota.check_bytes(size.saturating_sub(old_size))?;
+        file.set_len(size)?;
+        quota.resize(old_size, size);
         Ok(())
     }
 
     fn readlink(&self, path: &Path) -> io::Result<OsString> {
-        fs::read_link(&path).map(|pbuf| pbuf.into_os_string())
+        let (dir, name) = self.resolver.parent(path)?;
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        let n = unsafe {
+            libc::readlinkat(dir.as_raw_fd(), name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(n as usize);
+        Ok(OsString::from_vec(buf))
     }
 
     fn symlink(&self, target: &Path, linkpath: &Path) -> io::Result<()> {
-        unix::fs::symlink(target, linkpath)
+        let target = cstr(target)?;
+        self.with_new_inode(|| self.with_parent(linkpath, |dirfd, name| unsafe {
+            libc::symlinkat(target.as_ptr(), dirfd, name.as_ptr())
+        }))
     }
 
     fn link(&self, target: &Path, newpath: &Path) -> io::Result<()> {
-        fs::hard_link(target, newpath)
+        let (olddir, oldname) = self.resolver.parent(target)?;
+        self.with_parent(newpath, |dirfd, name| unsafe {
+            libc::linkat(olddir.as_raw_fd(), oldname.as_ptr(), dirfd, name.as_ptr(), 0)
+        })
     }
 
     fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
-        fs::rename(from, to)
+        let (olddir, oldname) = self.resolver.parent(from)?;
+        if from == to {
+            return Ok(());
+        }
+        self.with_removed_entry(to, || self.with_parent(to, |dirfd, name| unsafe {
+            libc::renameat(olddir.as_raw_fd(), oldname.as_ptr(), dirfd, name.as_ptr())
+        }))
     }
 
     fn remove_file(&self, path: &Path) -> io::Result<()> {
-        fs::remove_file(path)
+        self.with_removed_entry(path, || self.with_parent(path, |dirfd, name| unsafe {
+            libc::unlinkat(dirfd, name.as_ptr(), 0)
+        }))
     }
 
     fn remove_dir(&self, path: &Path) -> io::Result<()> {
-        fs::remove_dir(path)
+        self.with_removed_entry(path, || self.with_parent(path, |dirfd, name| unsafe {
+            libc::unlinkat(dirfd, name.as_ptr(), libc::AT_REMOVEDIR)
+        }))
     }
 
     fn create_dir(&self, path: &Path, mode: u32) -> io::Result<()> {
-        fs::DirBuilder::new()
-            .recursive(false)
-            .mode(mode & 0o755)
-            .create(path)
+        self.with_new_inode(|| self.with_parent(path, |dirfd, name| unsafe {
+            libc::mkdirat(dirfd, name.as_ptr(), mode & 0o755)
+        }))
     }
 
     fn readdir_populate(&self, path: &Path) -> io::Result<Directory> {
         let mut directory = Directory::new();
         let mut offset = 0;
-        for dent in fs::read_dir(path)? {
+        let dir = self.resolver.open(path, libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+        for dent in fs::read_dir(resolve::proc_fd_path(&dir))? {
             let dent = dent?;
             let p9entry = P9DirEntry::from_direntry(dent, offset)?;
             offset = p9entry.offset();
@@ -263,6 +376,21 @@ impl FileSystemOps for FileSystem {
         }
         Ok(directory)
     }
+
+    fn check_write(&self, file: &P9File, offset: u64, count: u32) -> io::Result<Option<u64>> {
+        match (&self.quota, file.local_file()) {
+            (Some(quota), Some(f)) => quota.check_write(f, offset, count).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn write_done(&self, file: &P9File, old_size: u64) {
+        if let (Some(quota), Some(f)) = (&self.quota, file.local_file()) {
+            if let Ok(meta) = f.metadata() {
+                quota.resize(old_size, meta.len());
+            }
+        }
+    }
 }
 
 
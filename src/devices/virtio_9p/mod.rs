@@ -1,30 +1,45 @@
-use std::thread;
+use std::io;
+use std::sync::Arc;
+use std::thread::JoinHandle;
 
 use std::path::{PathBuf, Path};
-use vm_memory::GuestMemoryMmap;
 
 use crate::devices::virtio_9p::server::Server;
 use crate::devices::virtio_9p::filesystem::{FileSystem, FileSystemOps};
+use crate::devices::virtio_9p::file::FidStats;
+use crate::devices::virtio_9p::quota::ShareQuota;
 use self::pdu::PduParser;
 
 mod pdu;
 mod file;
 mod directory;
 mod filesystem;
+mod resolve;
 mod server;
 mod synthetic;
+mod control;
+mod quota;
 
 const VIRTIO_9P_MOUNT_TAG: u64 = 0x1;
 
 pub use synthetic::SyntheticFS;
+pub use file::FidLimits;
+pub use control::ShareControl;
+pub use quota::QuotaLimits;
 use crate::io::{FeatureBits, Queues, VirtioDevice, VirtioDeviceType, VirtQueue};
+use crate::util::JsonValue;
 
 pub struct VirtioP9<T: FileSystemOps> {
     filesystem: T,
+    tag_name: String,
     root_dir: PathBuf,
     features: FeatureBits,
     debug: bool,
     config: Vec<u8>,
+    fid_limits: FidLimits,
+    share_control: Option<Arc<ShareControl>>,
+    quota: Option<Arc<ShareQuota>>,
+    worker: Option<JoinHandle<()>>,
 }
 
 impl <T: FileSystemOps+'static> VirtioP9<T> {
@@ -41,19 +56,50 @@ impl <T: FileSystemOps+'static> VirtioP9<T> {
     pub fn new(filesystem: T, tag_name: &str, root_dir: &str, debug: bool) -> Self {
         VirtioP9 {
             filesystem,
+            tag_name: tag_name.to_string(),
             root_dir: PathBuf::from(root_dir),
             features: FeatureBits::new_default(VIRTIO_9P_MOUNT_TAG),
             debug,
             config: VirtioP9::<T>::create_config(tag_name),
+            fid_limits: FidLimits::default(),
+            share_control: None,
+            quota: None,
+            worker: None,
         }
     }
 
+    /// Replace the default limits on the number of fids and open files the
+    /// guest may hold on this mount.
+    pub fn set_fid_limits(&mut self, limits: FidLimits) {
+        self.fid_limits = limits;
+    }
+
+    /// Control for quiescing the share while the exported directory is
+    /// replaced. Only shares of a host directory have one.
+    pub fn share_control(&self) -> Option<Arc<ShareControl>> {
+        self.share_control.clone()
+    }
 }
 
 impl VirtioP9<FileSystem> {
-    pub fn new_filesystem(tag_name: &str, root_dir: &str, read_only: bool, debug: bool) -> Self {
-        let filesystem = FileSystem::new(PathBuf::from(root_dir), read_only);
-        Self::new(filesystem, tag_name, root_dir, debug)
+    pub fn new_filesystem(tag_name: &str, root_dir: &str, read_only: bool, debug: bool) -> io::Result<Self> {
+        let filesystem = FileSystem::new(PathBuf::from(root_dir), read_only)?;
+        let control = Arc::new(ShareControl::new(filesystem.clone()));
+        let mut p9 = Self::new(filesystem, tag_name, root_dir, debug);
+        p9.share_control = Some(control);
+        Ok(p9)
+    }
+
+    /// Limit the space and number of files the guest may use on this share.
+    /// Writes and creates which would exceed the limits fail with `EDQUOT`
+    /// and statfs reports the limits as the size of the filesystem.
+    ///
+    /// The current usage is measured by walking the shared directory.
+    pub fn set_quota(&mut self, limits: QuotaLimits) -> io::Result<()> {
+        let quota = Arc::new(ShareQuota::new(&self.root_dir, limits)?);
+        self.filesystem.set_quota(quota.clone());
+        self.quota = Some(quota);
+        Ok(())
     }
 }
 
@@ -85,22 +131,112 @@ impl <T: FileSystemOps+'static> VirtioDevice for VirtioP9<T> {
         let vq = queues.get_queue(0);
         let root_dir = self.root_dir.clone();
         let filesystem = self.filesystem.clone();
-        let memory = queues.guest_memory().clone();
         let debug = self.debug;
-        thread::spawn(move || run_device(memory, vq, &root_dir, filesystem, debug));
+        let limits = self.fid_limits;
+        let stats = FidStats::register(queues.device_stats());
+        let control = self.share_control.clone();
+        if let Some(control) = &control {
+            control.set_queue(vq.clone());
+        }
+        self.worker = queues.spawn_worker(move || run_device(vq, &root_dir, filesystem, limits, stats, control, debug));
+    }
+
+    fn stop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                warn!("virtio 9p worker thread panicked");
+            }
+        }
+    }
+
+    fn describe(&self) -> Option<JsonValue> {
+        Some(JsonValue::object()
+            .field("tag", self.tag_name.as_str())
+            .field("root", self.root_dir.display().to_string())
+            .field("max_fids", self.fid_limits.max_fids)
+            .field("max_open_files", self.fid_limits.max_open)
+            .field("quiesced", self.share_control.as_ref().map(|c| c.is_quiesced()).unwrap_or(false))
+            .field("quota", self.quota.as_ref().map(|q| describe_quota(q))))
     }
 }
 
-fn run_device<T: FileSystemOps>(memory: GuestMemoryMmap, vq: VirtQueue, root_dir: &Path, filesystem: T, debug: bool) {
+fn describe_quota(quota: &ShareQuota) -> JsonValue {
+    let limits = quota.limits();
+    JsonValue::object()
+        .field("max_bytes", limits.max_bytes)
+        .field("max_inodes", limits.max_inodes)
+        .field("used_bytes", quota.used_bytes())
+        .field("used_inodes", quota.used_inodes())
+}
+
+fn run_device<T: FileSystemOps>(vq: VirtQueue, root_dir: &Path, filesystem: T, limits: FidLimits, stats: FidStats, control: Option<Arc<ShareControl>>, debug: bool) {
     let mut server = Server::new(&root_dir, filesystem);
+    server.set_fid_limits(limits, stats);
 
     if debug {
         server.enable_debug();
     }
 
-    vq.on_each_chain(|mut chain| {
-        let mut pp = PduParser::new(&mut chain, memory.clone());
-        server.handle(&mut pp);
-    });
+    let control = match control {
+        Some(control) => control,
+        None => {
+            vq.on_each_chain(|mut chain| {
+                let mut pp = PduParser::new(&mut chain);
+                server.handle(&mut pp);
+            });
+            return;
+        }
+    };
+
+    // Like on_each_chain(), but a quiesce is also noticed while waiting for
+    // the next request
+    loop {
+        if let Err(err) = vq.wait_ready() {
+            if !vq.is_stopped() {
+                warn!("error waiting on virtqueue: {}", err);
+            }
+            return;
+        }
+        control.checkpoint(&vq, || server.invalidate_fids());
+        for mut chain in vq.iter() {
+            control.checkpoint(&vq, || server.invalidate_fids());
+            let mut pp = PduParser::new(&mut chain);
+            server.handle(&mut pp);
+        }
+    }
 }
 
+
+/// Fuzzing entry point for the 9p server. `data` is split into request
+/// messages using the size field of each PDU header and every message is
+/// handled in turn by a single server backed by a `SyntheticFS`.
+#[cfg(feature = "fuzzing")]
+pub fn fuzz_pdu(data: &[u8]) {
+    use std::cmp;
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+    use crate::io::Chain;
+
+    const MSG_MAX: usize = 0x10000;
+    const REPLY_ADDRESS: u64 = MSG_MAX as u64;
+
+    let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 2 * MSG_MAX)])
+        .expect("failed to allocate memory for 9p fuzzing");
+
+    let mut filesystem = SyntheticFS::new();
+    filesystem.mkdirs(&["/tmp", "/etc"]);
+    let _ = filesystem.add_memory_file("/etc", "hostname", 0o644, b"fuzz\n");
+    let mut server = Server::new(Path::new("/"), filesystem);
+
+    let mut rest = data;
+    while rest.len() >= 4 {
+        let size = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let (msg, tail) = rest.split_at(size.clamp(4, cmp::min(rest.len(), MSG_MAX)));
+        rest = tail;
+        if memory.write_slice(msg, GuestAddress(0)).is_err() {
+            return;
+        }
+        let mut chain = Chain::from_buffers(memory.clone(), &[(0, msg.len() as u32)], &[(REPLY_ADDRESS, MSG_MAX as u32)]);
+        let mut pp = PduParser::new(&mut chain);
+        server.handle(&mut pp);
+    }
+}
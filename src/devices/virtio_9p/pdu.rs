@@ -1,10 +1,10 @@
 use std::io::{self,Read,Write};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
 use std::ffi::OsStr;
 
 use libc;
 use byteorder::{LittleEndian,ReadBytesExt,WriteBytesExt};
-use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
 
 use crate::devices::virtio_9p::file::Qid;
 use crate::io::Chain;
@@ -13,13 +13,13 @@ const P9_HEADER_LEN: usize = 7;
 const P9_RLERROR: u8 = 7;
 
 pub struct PduParser<'a> {
-    memory: GuestMemoryMmap,
     pub chain: &'a mut Chain,
 
     size: u32,
     cmd: u8,
     tag: u16,
-    reply_start_addr: u64,
+    // offset of reply header in writeable part of chain
+    reply_start: Option<usize>,
 }
 
 #[derive(Default,Debug)]
@@ -107,8 +107,8 @@ impl P9Attr {
 }
 
 impl <'a> PduParser<'a> {
-    pub fn new(chain: &'a mut Chain, memory: GuestMemoryMmap) -> PduParser<'a> {
-        PduParser{ memory, chain, size: 0, cmd: 0, tag: 0, reply_start_addr: 0 }
+    pub fn new(chain: &'a mut Chain) -> PduParser<'a> {
+        PduParser{ chain, size: 0, cmd: 0, tag: 0, reply_start: None }
     }
 
     pub fn command(&mut self) -> io::Result<u8> {
@@ -119,8 +119,7 @@ impl <'a> PduParser<'a> {
     }
 
     pub fn read_done(&mut self) -> io::Result<()> {
-        self.reply_start_addr = self.chain.current_write_address(8)
-            .ok_or(io::Error::from_raw_os_error(libc::EIO))?;
+        self.reply_start = Some(self.chain.get_wlen());
 
         // reserve header
         self.w32(0)?;
@@ -163,7 +162,7 @@ impl <'a> PduParser<'a> {
     }
 
     pub fn write_err(&mut self, errno: u32) -> io::Result<()> {
-        if self.reply_start_addr == 0 {
+        if self.reply_start.is_none() {
             self.read_done()?;
         }
         self.w32(errno)?;
@@ -180,7 +179,7 @@ impl <'a> PduParser<'a> {
     }
 
     pub fn _w8_at(&self, offset: usize, val: u8) {
-        self.memory.write_obj::<u8>(val, GuestAddress(self.reply_start_addr + offset as u64)).unwrap();
+        self.write_reply_at(offset, &[val]);
     }
 
     #[allow(dead_code)]
@@ -189,7 +188,7 @@ impl <'a> PduParser<'a> {
     }
 
     pub fn _w16_at(&self, offset: usize, val: u16) {
-        self.memory.write_obj::<u16>(val, GuestAddress(self.reply_start_addr + offset as u64)).unwrap();
+        self.write_reply_at(offset, &val.to_le_bytes());
     }
 
     pub fn w32_at(&self, offset: usize, val: u32) {
@@ -197,7 +196,14 @@ impl <'a> PduParser<'a> {
     }
 
     pub fn _w32_at(&self, offset: usize, val: u32) {
-        self.memory.write_obj::<u32>(val, GuestAddress(self.reply_start_addr + offset as u64)).unwrap();
+        self.write_reply_at(offset, &val.to_le_bytes());
+    }
+
+    fn write_reply_at(&self, offset: usize, bytes: &[u8]) {
+        let start = self.reply_start.unwrap_or(0);
+        if let Err(e) = self.chain.write_all_at(bytes, start + offset) {
+            warn_limited!("virtio_9p: failed to write reply field at offset {}: {}", offset, e);
+        }
     }
 
     pub fn write_done(&mut self) -> io::Result<()> {
@@ -222,6 +228,47 @@ impl <'a> PduParser<'a> {
         Ok(s)
     }
 
+    /// Skip over a string which the server does not use without copying it
+    /// out of the chain.
+    pub fn skip_string(&mut self) -> io::Result<()> {
+        let len = self.r16()? as usize;
+        if len > self.chain.remaining_read() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        self.chain.inc_read_offset(len);
+        Ok(())
+    }
+
+    /// Write up to `count` bytes of payload from the request to `fd` at file
+    /// offset `offset`. The data is passed to `pwritev()` directly from guest
+    /// memory. Returns the number of bytes written.
+    pub fn read_payload_to<F: AsRawFd>(&mut self, fd: &F, offset: u64, count: u32) -> io::Result<u32> {
+        let mut nwritten = 0;
+        while nwritten < count {
+            let n = self.chain.pwritev_to(fd, offset + nwritten as u64, (count - nwritten) as usize)?;
+            if n == 0 {
+                break;
+            }
+            nwritten += n as u32;
+        }
+        Ok(nwritten)
+    }
+
+    /// Read up to `count` bytes from `fd` at file offset `offset` into the
+    /// reply. The data is read with `preadv()` directly into guest memory.
+    /// Returns the number of bytes read.
+    pub fn write_payload_from<F: AsRawFd>(&mut self, fd: &F, offset: u64, count: u32) -> io::Result<u32> {
+        let mut nread = 0;
+        while nread < count {
+            let n = self.chain.preadv_from(fd, offset + nread as u64, (count - nread) as usize)?;
+            if n == 0 {
+                break;
+            }
+            nread += n as u32;
+        }
+        Ok(nread)
+    }
+
     pub fn read_string_list(&mut self) -> io::Result<Vec<String>> {
         let count = self.r16()?;
         let mut strings = Vec::with_capacity(count as usize);
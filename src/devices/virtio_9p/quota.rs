@@ -0,0 +1,191 @@
+use std::collections::HashSet;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Limits on the space and number of files used by a 9p share.
+#[derive(Copy,Clone,Debug,Default,PartialEq)]
+pub struct QuotaLimits {
+    /// Total size in bytes of the regular files in the share
+    pub max_bytes: Option<u64>,
+    /// Number of files, directories and symlinks in the share
+    pub max_inodes: Option<u64>,
+}
+
+impl QuotaLimits {
+    /// Parse limits of the form `bytes=SIZE,inodes=COUNT` where either field
+    /// may be omitted and the size may have a K, M or G suffix.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut limits = QuotaLimits::default();
+        for field in s.split(',') {
+            let (key, value) = field.split_once('=')?;
+            match key {
+                "bytes" => limits.max_bytes = Some(parse_size(value)?),
+                "inodes" => limits.max_inodes = Some(value.parse().ok()?),
+                _ => return None,
+            }
+        }
+        if limits.max_bytes.is_none() && limits.max_inodes.is_none() {
+            return None;
+        }
+        Some(limits)
+    }
+}
+
+fn parse_size(s: &str) -> Option<u64> {
+    let (digits, shift) = match s.chars().last()? {
+        'K' | 'k' => (&s[..s.len() - 1], 10),
+        'M' | 'm' => (&s[..s.len() - 1], 20),
+        'G' | 'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+// Writes to a file opened with O_APPEND go to the end whatever the offset
+fn is_append(file: &File) -> bool {
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    flags >= 0 && flags & libc::O_APPEND != 0
+}
+
+fn quota_exceeded<T>() -> io::Result<T> {
+    Err(io::Error::from_raw_os_error(libc::EDQUOT))
+}
+
+///
+/// Usage of a 9p share checked against `QuotaLimits`.
+///
+/// The usage is measured by walking the shared directory when the quota is
+/// created and then follows the changes made by the guest. Changes made to
+/// the directory on the host are not noticed. Bytes are the apparent size of
+/// regular files, so a sparse file counts at its full length.
+///
+pub struct ShareQuota {
+    limits: QuotaLimits,
+    bytes: AtomicU64,
+    inodes: AtomicU64,
+}
+
+impl ShareQuota {
+    pub fn new(root: &Path, limits: QuotaLimits) -> io::Result<Self> {
+        let (bytes, inodes) = Self::measure(root)?;
+        Ok(ShareQuota {
+            limits,
+            bytes: AtomicU64::new(bytes),
+            inodes: AtomicU64::new(inodes),
+        })
+    }
+
+    // Size of the regular files and number of inodes below `root`, counting
+    // each hard linked file once
+    fn measure(root: &Path) -> io::Result<(u64, u64)> {
+        let mut bytes = 0;
+        let mut inodes = 0;
+        let mut linked = HashSet::new();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                if meta.st_nlink() > 1 && !meta.is_dir() && !linked.insert(meta.st_ino()) {
+                    continue;
+                }
+                inodes += 1;
+                if meta.is_dir() {
+                    dirs.push(entry.path());
+                } else if meta.is_file() {
+                    bytes += meta.len();
+                }
+            }
+        }
+        Ok((bytes, inodes))
+    }
+
+    pub fn limits(&self) -> QuotaLimits {
+        self.limits
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn used_inodes(&self) -> u64 {
+        self.inodes.load(Ordering::Relaxed)
+    }
+
+    /// Fails with `EDQUOT` if `len` more bytes would exceed the quota
+    pub fn check_bytes(&self, len: u64) -> io::Result<()> {
+        match self.limits.max_bytes {
+            Some(max) if len > 0 && self.used_bytes().saturating_add(len) > max => quota_exceeded(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record the change in size of a file from `old` to `new` bytes
+    pub fn resize(&self, old: u64, new: u64) {
+        if new > old {
+            self.bytes.fetch_add(new - old, Ordering::Relaxed);
+        } else {
+            self.release_bytes(old - new);
+        }
+    }
+
+    fn release_bytes(&self, len: u64) {
+        let _ = self.bytes.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| Some(b.saturating_sub(len)));
+    }
+
+    /// Count a new inode, failing with `EDQUOT` if this exceeds the quota
+    pub fn add_inode(&self) -> io::Result<()> {
+        if let Some(max) = self.limits.max_inodes {
+            if self.used_inodes() >= max {
+                return quota_exceeded();
+            }
+        }
+        self.inodes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Undo `add_inode()` when creating the inode failed
+    pub fn cancel_inode(&self) {
+        let _ = self.inodes.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n.saturating_sub(1)));
+    }
+
+    /// Release the usage of a file about to be removed or replaced, as
+    /// described by `meta`. Other names of a hard linked file keep it in use.
+    pub fn remove(&self, meta: &Metadata) {
+        if meta.is_dir() || meta.st_nlink() <= 1 {
+            self.cancel_inode();
+            if meta.is_file() {
+                self.release_bytes(meta.len());
+            }
+        }
+    }
+
+    /// Size of `file` before a write of `count` bytes at `offset`, failing
+    /// with `EDQUOT` if the write would extend it beyond the quota.
+    pub fn check_write(&self, file: &File, offset: u64, count: u32) -> io::Result<u64> {
+        let size = file.metadata()?.len();
+        let end = if is_append(file) { size } else { offset };
+        self.check_bytes((end + count as u64).saturating_sub(size))?;
+        Ok(size)
+    }
+
+    /// Fill in the block and file counts of a statfs result so that the
+    /// guest sees the quota as the size of the filesystem.
+    pub fn adjust_statfs(&self, statfs: &mut libc::statfs64) {
+        let bsize = (statfs.f_bsize as u64).max(1);
+        if let Some(max) = self.limits.max_bytes {
+            let free = max.saturating_sub(self.used_bytes()) / bsize;
+            statfs.f_blocks = max / bsize;
+            statfs.f_bfree = statfs.f_bfree.min(free);
+            statfs.f_bavail = statfs.f_bavail.min(free);
+        }
+        if let Some(max) = self.limits.max_inodes {
+            statfs.f_files = max;
+            statfs.f_ffree = statfs.f_ffree.min(max.saturating_sub(self.used_inodes()));
+        }
+    }
+}
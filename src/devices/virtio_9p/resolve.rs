@@ -0,0 +1,177 @@
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+use std::sync::RwLock;
+
+// From linux/openat2.h
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+const RESOLVE_BENEATH: u64 = 0x08;
+
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+/// Resolves fid paths to host files without leaving the exported directory.
+///
+/// Paths held by fids are host paths below the export root, but any
+/// directory along such a path may be replaced by a symlink (by the guest
+/// or by anything else with access to the host directory) between the walk
+/// and a later operation on the fid. Every operation therefore resolves the
+/// path again relative to a descriptor for the root, refusing to follow
+/// symlinks or `..` in any component. The guest resolves symlinks itself
+/// with Treadlink, so a correct client never asks the server to follow one.
+///
+/// `openat2()` with `RESOLVE_BENEATH` is used when the kernel supports it,
+/// otherwise the path is walked one component at a time with `O_NOFOLLOW`.
+///
+/// The descriptor for the root is closed by `detach()` so that the host can
+/// unmount the exported directory. Until `reattach()` opens it again every
+/// operation fails with `ESTALE`.
+pub struct PathResolver {
+    root: PathBuf,
+    root_fd: RwLock<Option<File>>,
+    has_openat2: bool,
+}
+
+impl PathResolver {
+    pub fn new(root: &Path) -> io::Result<Self> {
+        let root_fd = Self::open_root(root)?;
+        let has_openat2 = match Self::openat2(root_fd.as_raw_fd(), OsStr::new("."), libc::O_PATH, 0) {
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) => false,
+            _ => true,
+        };
+        Ok(PathResolver {
+            root: root.to_path_buf(),
+            root_fd: RwLock::new(Some(root_fd)),
+            has_openat2,
+        })
+    }
+
+    fn open_root(root: &Path) -> io::Result<File> {
+        open_at(libc::AT_FDCWD, &cstr(root.as_os_str())?, libc::O_PATH | libc::O_DIRECTORY, 0)
+    }
+
+    /// Close the descriptor held for the root directory.
+    pub fn detach(&self) {
+        *self.root_fd.write().unwrap() = None;
+    }
+
+    /// Open the root directory again after `detach()`, which may now be a
+    /// different directory mounted at the same path.
+    pub fn reattach(&self) -> io::Result<()> {
+        let root_fd = Self::open_root(&self.root)?;
+        *self.root_fd.write().unwrap() = Some(root_fd);
+        Ok(())
+    }
+
+    /// The part of `path` below the root. Every component must be a normal
+    /// name, an empty result refers to the root itself.
+    fn relative<'a>(&self, path: &'a Path) -> io::Result<&'a Path> {
+        let rel = path.strip_prefix(&self.root)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EACCES))?;
+        if rel.components().all(|c| matches!(c, Component::Normal(_))) {
+            Ok(rel)
+        } else {
+            Err(io::Error::from_raw_os_error(libc::EACCES))
+        }
+    }
+
+    /// Open `path` with `flags`. A symlink in any component, including the
+    /// last, fails with `ELOOP`.
+    pub fn open(&self, path: &Path, flags: libc::c_int, mode: u32) -> io::Result<File> {
+        let rel = self.relative(path)?;
+        let root_fd = self.root_fd.read().unwrap();
+        let dirfd = match root_fd.as_ref() {
+            Some(fd) => fd.as_raw_fd(),
+            None => return Err(io::Error::from_raw_os_error(libc::ESTALE)),
+        };
+        if rel.as_os_str().is_empty() {
+            return open_at(dirfd, &cstr(OsStr::new("."))?, flags, mode);
+        }
+        if self.has_openat2 {
+            Self::openat2(dirfd, rel.as_os_str(), flags, mode)
+        } else {
+            Self::walk_open(dirfd, rel, flags, mode)
+        }
+    }
+
+    /// Open `path` with `O_PATH` for operations which only need to refer to
+    /// the file such as `fstat()`. A symlink as the last component is opened
+    /// rather than followed.
+    pub fn open_path(&self, path: &Path) -> io::Result<File> {
+        self.open(path, libc::O_PATH | libc::O_NOFOLLOW, 0)
+    }
+
+    /// Open the directory containing `path` and return it with the final
+    /// name, for the `*at()` system calls which operate on a name in a
+    /// directory without following a symlink there.
+    pub fn parent(&self, path: &Path) -> io::Result<(File, CString)> {
+        let rel = self.relative(path)?;
+        let name = match rel.file_name() {
+            Some(name) => cstr(name)?,
+            None => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let parent = self.root.join(rel.parent().unwrap_or_else(|| Path::new("")));
+        let dir = self.open(&parent, libc::O_PATH | libc::O_DIRECTORY, 0)?;
+        Ok((dir, name))
+    }
+
+    fn openat2(dirfd: RawFd, path: &OsStr, flags: libc::c_int, mode: u32) -> io::Result<File> {
+        let path = cstr(path)?;
+        // openat2() rejects a mode unless a file may be created
+        let creates = flags & libc::O_CREAT != 0 || flags & libc::O_TMPFILE == libc::O_TMPFILE;
+        let how = OpenHow {
+            flags: (flags | libc::O_CLOEXEC) as u64,
+            mode: if creates { mode as u64 } else { 0 },
+            resolve: RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS | RESOLVE_NO_MAGICLINKS,
+        };
+        let fd = unsafe {
+            libc::syscall(libc::SYS_openat2, dirfd, path.as_ptr(), &how as *const OpenHow, std::mem::size_of::<OpenHow>())
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+    }
+
+    fn walk_open(root_fd: RawFd, rel: &Path, flags: libc::c_int, mode: u32) -> io::Result<File> {
+        let mut names = rel.components().map(|c| c.as_os_str()).collect::<Vec<_>>();
+        let last = match names.pop() {
+            Some(name) => name,
+            None => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let mut dir: Option<File> = None;
+        for name in names {
+            let dirfd = dir.as_ref().map(|d| d.as_raw_fd()).unwrap_or(root_fd);
+            let next = open_at(dirfd, &cstr(name)?, libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW, 0)?;
+            dir = Some(next);
+        }
+        let dirfd = dir.as_ref().map(|d| d.as_raw_fd()).unwrap_or(root_fd);
+        open_at(dirfd, &cstr(last)?, flags | libc::O_NOFOLLOW, mode)
+    }
+}
+
+fn open_at(dirfd: RawFd, path: &CString, flags: libc::c_int, mode: u32) -> io::Result<File> {
+    let fd = unsafe { libc::openat(dirfd, path.as_ptr(), flags | libc::O_CLOEXEC, mode as libc::c_uint) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+pub fn cstr(s: &OsStr) -> io::Result<CString> {
+    Ok(CString::new(s.as_bytes())?)
+}
+
+/// A path through which an `O_PATH` descriptor can be used with calls that
+/// only accept a path.
+pub fn proc_fd_path(file: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
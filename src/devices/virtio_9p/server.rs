@@ -4,7 +4,7 @@ use std::{io, cmp};
 use crate::devices::virtio_9p::{
     pdu::{PduParser, P9Attr},
     filesystem::{FileSystemOps, FsTouch},
-    file::{Fids, Fid, Qid},
+    file::{Fids, Fid, FidLimits, FidStats, P9File, Qid},
 };
 
 const P9_TSTATFS: u8      = 8;
@@ -68,6 +68,16 @@ impl <T: FileSystemOps> Server<T> {
         self.debug = true;
     }
 
+    pub fn set_fid_limits(&mut self, limits: FidLimits, stats: FidStats) {
+        self.fids.set_limits(limits);
+        self.fids.set_stats(stats);
+    }
+
+    /// Close every open file and make the fids held by the guest stale.
+    pub fn invalidate_fids(&mut self) {
+        self.fids.invalidate();
+    }
+
     fn fid_mut(&mut self, id: u32) -> io::Result<&mut Fid<T>> {
         self.fids.fid_mut(id)
     }
@@ -87,13 +97,13 @@ impl <T: FileSystemOps> Server<T> {
             Ok(cmd) => {
                 if let Err(err) = self.dispatch(cmd, pp) {
                     if self.debug {
-                        notify!("error handling command: {}", err);
+                        notify_limited!("error handling command: {}", err);
                     }
                     let _ = pp.bail_err(err);
                 }
             }
             Err(e) => {
-                warn!("Error reading p9 command: {}", e);
+                warn_limited!("Error reading p9 command: {}", e);
             }
         }
     }
@@ -127,7 +137,7 @@ impl <T: FileSystemOps> Server<T> {
             P9_TWRITE => self.p9_write(pp)?,
             P9_TCLUNK => self.p9_clunk(pp)?,
             P9_REMOVE => self.p9_remove(pp)?,
-            n => warn!("unhandled 9p command: {}", n),
+            n => warn_limited!("unhandled 9p command: {}", n),
         }
         Ok(())
     }
@@ -165,9 +175,9 @@ impl <T: FileSystemOps> Server<T> {
         let file = self.filesystem.open(fid.path(), flags)?;
 
         let id = fid.id();
+        self.fids.set_file(id, file)?;
         let fid = self.fid_mut(id)?;
 
-        fid.set_file(file);
         fid.write_qid(pp)?;
         // iounit
         pp.w32(0)?;
@@ -196,11 +206,10 @@ impl <T: FileSystemOps> Server<T> {
         let file = self.filesystem.create(&path, flags, mode)?;
 
         let id = dfid.id();
+        self.fid_mut(id)?.set_path(path)?;
+        self.fids.set_file(id, file)?;
         let dfid = self.fid_mut(id)?;
 
-        dfid.set_path(path)?;
-        dfid.set_file(file);
-
         dfid.write_qid(pp)?;
         // iounit
         pp.w32(0)?;
@@ -300,7 +309,7 @@ impl <T: FileSystemOps> Server<T> {
         // XXX mask?
         fid.write_stat(pp)?;
         if let Err(err) = fid.write_stat(pp) {
-            notify!("error from write_stat: {}", err);
+            notify_limited!("error from write_stat: {}", err);
             return Err(err);
         }
         pp.write_done()
@@ -411,7 +420,7 @@ impl <T: FileSystemOps> Server<T> {
         let _ = pp.r64()?;
         let _ = pp.r64()?;
         let _ = pp.r32()?;
-        let _ = pp.read_string()?;
+        pp.skip_string()?;
         pp.read_done()?;
         Ok((fid, ltype, flags))
     }
@@ -553,8 +562,8 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_attach_args(&self, pp: &mut PduParser) -> io::Result<u32> {
         let id = pp.r32()?;
         let _afid = pp.r32()?;
-        let _uname = pp.read_string()?;
-        let _aname = pp.read_string()?;
+        pp.skip_string()?; // uname
+        pp.skip_string()?; // aname
         let _uid = pp.r32()?;
         pp.read_done()?;
         Ok(id)
@@ -568,8 +577,9 @@ impl <T: FileSystemOps> Server<T> {
         }
 
         let fid = self.fids.create(id, &self.root)?;
-        fid.write_qid(pp)?;
-        self.fids.add(fid);
+        let qid = fid.qid();
+        self.fids.add(fid)?;
+        qid.write(pp)?;
         pp.write_done()
     }
 
@@ -627,7 +637,7 @@ impl <T: FileSystemOps> Server<T> {
         }
 
         let new_fid = self.fids.create(newfid_id, path)?;
-        self.fids.add(new_fid);
+        self.fids.add(new_fid)?;
 
         pp.write_qid_list(&qid_list)?;
         pp.write_done()
@@ -653,6 +663,12 @@ impl <T: FileSystemOps> Server<T> {
         // space for size field
         pp.w32(0)?;
 
+        if let Some(f) = file.local_file() {
+            let nread = pp.write_payload_from(f, offset, count)?;
+            pp.w32_at(0, nread);
+            return pp.write_done();
+        }
+
         let mut nread = 0;
 
         while nread < count {
@@ -660,7 +676,7 @@ impl <T: FileSystemOps> Server<T> {
             if current.len() == 0 {
                 break;
             }
-            let rlen = cmp::min(current.len(), count as usize);
+            let rlen = cmp::min(current.len(), (count - nread) as usize);
             let mut subslice = current.subslice(0, rlen).map_err(io::Error::other)?;
             let n = file.read_at(&mut subslice, offset + nread as u64)?;
             if n == 0 {
@@ -673,8 +689,8 @@ impl <T: FileSystemOps> Server<T> {
         pp.write_done()
     }
 
-    fn p9_write_args(&mut self, pp: &mut PduParser) -> io::Result<(&mut Fid<T>, u64, u32)> {
-        let fid = self.fids.read_fid_mut(pp)?;
+    fn p9_write_args<'a>(fids: &'a mut Fids<T>, pp: &mut PduParser) -> io::Result<(&'a mut Fid<T>, u64, u32)> {
+        let fid = fids.read_fid_mut(pp)?;
         let offset = pp.r64()?;
         let count = pp.r32()?;
         Ok((fid, offset, count))
@@ -682,13 +698,31 @@ impl <T: FileSystemOps> Server<T> {
 
     fn p9_write(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let debug = self.debug;
-        let (fid, offset, count) = self.p9_write_args(pp)?;
+        let (fid, offset, count) = Self::p9_write_args(&mut self.fids, pp)?;
 
         if debug {
             notify!("p9_write({}, offset={}, count={})", fid, offset, count);
         }
 
         let file = fid.file_mut()?;
+        let old_size = self.filesystem.check_write(file, offset, count)?;
+
+        let result = if let Some(f) = file.local_file() {
+            pp.read_payload_to(f, offset, count)
+        } else {
+            Self::write_chain_to(file, pp, offset, count)
+        };
+        // A write which fails part way may still have extended the file
+        if let Some(old_size) = old_size {
+            self.filesystem.write_done(file, old_size);
+        }
+        let nread = result?;
+        pp.read_done()?;
+        pp.w32(nread)?;
+        pp.write_done()
+    }
+
+    fn write_chain_to(file: &mut P9File, pp: &mut PduParser, offset: u64, count: u32) -> io::Result<u32> {
         let mut nread = 0;
         while nread < count {
             let buffer = pp.chain.current_read_slice();
@@ -699,12 +733,10 @@ impl <T: FileSystemOps> Server<T> {
             pp.chain.inc_read_offset(n);
             nread += n as u32;
         }
-        pp.read_done()?;
-        pp.w32(nread)?;
-        pp.write_done()
+        Ok(nread)
     }
 
-    fn remove_fid(&mut self, pp: &mut PduParser) -> io::Result<Fid<T>> {
+    fn remove_fid(&mut self, pp: &mut PduParser) -> io::Result<Option<Fid<T>>> {
         let id = pp.r32()?;
         pp.read_done()?;
         self.fids.remove(id)
@@ -713,13 +745,20 @@ impl <T: FileSystemOps> Server<T> {
     fn p9_clunk(&mut self, pp: &mut PduParser) -> io::Result<()> {
         let fid = self.remove_fid(pp)?;
         if self.debug {
-            notify!("p9_clunk({})", fid);
+            match fid {
+                Some(ref fid) => notify!("p9_clunk({})", fid),
+                None => notify!("p9_clunk(expired fid)"),
+            }
         }
         pp.write_done()
     }
 
     fn p9_remove(&mut self, pp: &mut PduParser) -> io::Result<()> {
-        let fid = self.remove_fid(pp)?;
+        let fid = match self.remove_fid(pp)? {
+            Some(fid) => fid,
+            // The path of an expired fid is gone, Tremove must still clunk it
+            None => return system_error(libc::EBADF),
+        };
         if self.debug {
             notify!("p9_remove({})", fid);
         }
@@ -1,4 +1,4 @@
-use std::collections::{HashSet, BTreeMap};
+use std::collections::{HashMap, HashSet, BTreeMap};
 use std::collections::btree_map::Entry;
 use std::ffi::{OsString, OsStr};
 use std::io;
@@ -10,7 +10,7 @@ use std::time::{UNIX_EPOCH, SystemTime};
 
 use crate::devices::virtio_9p::{
     directory::{Directory, P9DirEntry},
-    file::{P9File, Qid, P9_QTDIR, P9_QTFILE},
+    file::{P9File, Qid, P9_QTDIR, P9_QTFILE, P9_QTSYMLINK},
     filesystem::{FileSystemOps, FsTouch, FileSystem},
     pdu::PduParser,
 };
@@ -22,23 +22,28 @@ struct NodeData {
     qid: Qid,
     size: u64,
     mode: u32,
-    _inode: u32,
+    rdev: u64,
+    inode: u32,
 }
 
 impl NodeData {
 
-    fn name_str(&self) -> &str {
+    // Names are taken from host paths and may not be valid UTF-8
+    fn name_str(&self) -> io::Result<&str> {
         self.name.to_str()
-            .expect("SyntheticFS: unable to convert name to &str")
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))
     }
 
     fn dtype(&self) -> u8 {
-        if self.qid.is_dir() {
-            libc::DT_DIR
-        } else {
-            libc::DT_REG
+        match self.mode & libc::S_IFMT {
+            libc::S_IFDIR => libc::DT_DIR,
+            libc::S_IFLNK => libc::DT_LNK,
+            libc::S_IFCHR => libc::DT_CHR,
+            libc::S_IFBLK => libc::DT_BLK,
+            _ => libc::DT_REG,
         }
     }
+
 }
 
 #[derive(Clone)]
@@ -46,6 +51,8 @@ enum Node {
     File(PathBuf, NodeData),
     MemoryFile(Buffer<&'static [u8]>, NodeData),
     Dir(BTreeMap<OsString, Node>, NodeData),
+    Symlink(PathBuf, NodeData),
+    Device(NodeData),
 }
 
 impl Node {
@@ -70,24 +77,58 @@ impl Node {
         Node::MemoryFile(buffer, data)
     }
 
+    fn new_symlink(name: &OsStr, inode: u32, target: &Path) -> Node {
+        let mode = 0o777 | libc::S_IFLNK;
+        let size = target.as_os_str().len() as u64;
+        let data = NodeData::new(name, P9_QTSYMLINK, size, mode, inode);
+        Node::Symlink(target.to_path_buf(), data)
+    }
+
+    fn new_device(name: &OsStr, mode: u32, inode: u32, rdev: u64) -> Node {
+        let mut data = NodeData::new(name, P9_QTFILE, 0, mode, inode);
+        data.rdev = rdev;
+        Node::Device(data)
+    }
+
     fn node_data(&self) -> &NodeData {
         match self {
             Node::Dir(_, data) => data,
             Node::File(_, data) => data,
             Node::MemoryFile(_, data) => data,
+            Node::Symlink(_, data) => data,
+            Node::Device(data) => data,
+        }
+    }
+
+    fn node_data_mut(&mut self) -> &mut NodeData {
+        match self {
+            Node::Dir(_, data) => data,
+            Node::File(_, data) => data,
+            Node::MemoryFile(_, data) => data,
+            Node::Symlink(_, data) => data,
+            Node::Device(data) => data,
         }
     }
+
     fn qid(&self) -> Qid {
         self.node_data().qid
     }
 
-    fn write_stat(&self, pp: &mut PduParser) -> io::Result<()> {
-        self.node_data().write_stat(pp)
+    fn write_stat(&self, nlink: u64, pp: &mut PduParser) -> io::Result<()> {
+        match self {
+            // Host files such as resolv.conf can be replaced while the VM
+            // runs, so report their current size
+            Node::File(local, data) => {
+                let size = local.metadata().map(|m| m.len()).unwrap_or(data.size);
+                data.write_stat_with_size(nlink, size, pp)
+            }
+            _ => self.node_data().write_stat(nlink, pp),
+        }
     }
 
-    fn create_directory_entry(&self, offset: u64) -> P9DirEntry {
+    fn create_directory_entry(&self, offset: u64) -> io::Result<P9DirEntry> {
         let data = self.node_data();
-        P9DirEntry::new(data.qid, offset, data.dtype(), data.name_str())
+        Ok(P9DirEntry::new(data.qid, offset, data.dtype(), data.name_str()?))
     }
 
 
@@ -147,7 +188,7 @@ impl Node {
                 let mut offset = 0;
                 let mut directory = Directory::new();
                 for  node in nodes.values() {
-                    let entry = node.create_directory_entry(offset);
+                    let entry = node.create_directory_entry(offset)?;
                     offset = entry.offset();
                     directory.push_entry(entry);
                 }
@@ -163,7 +204,8 @@ impl NodeData {
         NodeData {
             name: name.into(),
             qid: Self::create_qid(qtype, inode),
-            size, mode, _inode: inode,
+            size, mode, inode,
+            rdev: 0,
         }
     }
 
@@ -180,7 +222,11 @@ impl NodeData {
         }
     }
 
-    fn write_stat(&self, pp: &mut PduParser) -> io::Result<()> {
+    fn write_stat(&self, nlink: u64, pp: &mut PduParser) -> io::Result<()> {
+        self.write_stat_with_size(nlink, self.size, pp)
+    }
+
+    fn write_stat_with_size(&self, nlink: u64, size: u64, pp: &mut PduParser) -> io::Result<()> {
         const P9_STATS_BASIC: u64 =  0x000007ff;
         pp.w64(P9_STATS_BASIC)?;
         self.qid.write(pp)?;
@@ -188,9 +234,9 @@ impl NodeData {
         pp.w32(self.mode)?;
         pp.w32(0)?;   // uid
         pp.w32(0)?;   // gid
-        pp.w64(1)?;   // nlink
-        pp.w64(0)?;   // rdev
-        pp.w64(self.size)?;  // size
+        pp.w64(nlink)?;
+        pp.w64(self.rdev)?;
+        pp.w64(size)?;  // size
         pp.w64(0)?;   // blksize
         pp.w64(0)?;   // blocks
         pp.w64(0)?;   // atime
@@ -252,6 +298,8 @@ pub struct SyntheticFS {
     paths_added: HashSet<PathBuf>,
     root: Node,
     inodes: Inodes,
+    // Link count of inodes with more than one name
+    links: HashMap<u32, u64>,
     euid_root: bool,
 }
 impl SyntheticFS {
@@ -263,6 +311,7 @@ impl SyntheticFS {
 
         SyntheticFS {
             root, inodes, euid_root, paths_added: HashSet::new(),
+            links: HashMap::new(),
         }
     }
 
@@ -322,6 +371,69 @@ impl SyntheticFS {
         Ok(())
     }
 
+    /// Add a symbolic link `linkname` in `dirpath` pointing to `target`.
+    pub fn add_symlink<S: AsRef<OsStr>, P: AsRef<Path>, Q: AsRef<Path>>(&mut self, dirpath: P, linkname: S, target: Q) -> io::Result<()> {
+        let inode = self.inodes.next_inode();
+        let node = Node::new_symlink(linkname.as_ref(), inode, target.as_ref());
+        self.insert_node(dirpath.as_ref(), node)
+    }
+
+    /// Add a character device node with permissions `mode` for device
+    /// `major`:`minor`. The guest kernel opens device nodes itself so only
+    /// the attributes are served.
+    pub fn add_char_device<S: AsRef<OsStr>, P: AsRef<Path>>(&mut self, dirpath: P, name: S, mode: u32, major: u32, minor: u32) -> io::Result<()> {
+        self.add_device(dirpath.as_ref(), name.as_ref(), mode | libc::S_IFCHR, major, minor)
+    }
+
+    /// Add a block device node with permissions `mode` for device
+    /// `major`:`minor`.
+    pub fn add_block_device<S: AsRef<OsStr>, P: AsRef<Path>>(&mut self, dirpath: P, name: S, mode: u32, major: u32, minor: u32) -> io::Result<()> {
+        self.add_device(dirpath.as_ref(), name.as_ref(), mode | libc::S_IFBLK, major, minor)
+    }
+
+    fn add_device(&mut self, dirpath: &Path, name: &OsStr, mode: u32, major: u32, minor: u32) -> io::Result<()> {
+        let inode = self.inodes.next_inode();
+        let rdev = libc::makedev(major, minor) as u64;
+        let node = Node::new_device(name, mode, inode, rdev);
+        self.insert_node(dirpath, node)
+    }
+
+    /// Add `linkname` in `dirpath` as another name for the existing file,
+    /// symlink or device node at `target`. Directories cannot be linked.
+    pub fn add_hardlink<S: AsRef<OsStr>, P: AsRef<Path>, Q: AsRef<Path>>(&mut self, target: Q, dirpath: P, linkname: S) -> io::Result<()> {
+        let existing = self.lookup(target.as_ref())?;
+        if let Node::Dir(..) = existing {
+            return syserr(libc::EPERM);
+        }
+        let mut node = existing.clone();
+        node.node_data_mut().name = linkname.as_ref().to_os_string();
+        let inode = node.node_data().inode;
+        self.insert_node(dirpath.as_ref(), node)?;
+        *self.links.entry(inode).or_insert(1) += 1;
+        Ok(())
+    }
+
+    fn insert_node(&mut self, dirpath: &Path, node: Node) -> io::Result<()> {
+        self.mkdir(dirpath, 0o755);
+        let entries = self.lookup_mut(dirpath)?
+            .entries_mut()
+            .ok_or(rawerr(libc::ENOTDIR))?;
+        match entries.entry(node.node_data().name.clone()) {
+            Entry::Occupied(_) => syserr(libc::EEXIST),
+            Entry::Vacant(entry) => {
+                entry.insert(node);
+                Ok(())
+            }
+        }
+    }
+
+    fn nlink(&self, node: &Node) -> u64 {
+        match node {
+            Node::Dir(..) => 1,
+            _ => self.links.get(&node.node_data().inode).copied().unwrap_or(1),
+        }
+    }
+
     fn parse_ldd_line(line: &str) -> Option<PathBuf> {
         for s in line.split_whitespace().take(3) {
             if s.starts_with('/') {
@@ -365,7 +477,8 @@ impl SyntheticFS {
             .arg(execpath.as_os_str())
             .stdout(Stdio::piped())
             .output()?;
-        let s = String::from_utf8(out.stdout).expect("");
+        let s = String::from_utf8(out.stdout)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "ldd output is not valid UTF-8"))?;
 
         for line in s.lines() {
             if let Some(path) = Self::parse_ldd_line(line) {
@@ -422,7 +535,7 @@ impl FileSystemOps for SyntheticFS {
 
     fn write_stat(&self, path: &Path, pp: &mut PduParser) -> io::Result<()> {
         let node = self.lookup(path)?;
-        node.write_stat(pp)
+        node.write_stat(self.nlink(node), pp)
     }
 
     fn open(&self, path: &Path, flags: u32) -> io::Result<P9File> {
@@ -438,6 +551,8 @@ impl FileSystemOps for SyntheticFS {
             Node::MemoryFile(buffer,..) => {
                 Ok(P9File::from_buffer(buffer.clone()))
             }
+            Node::Symlink(..) => syserr(libc::ELOOP),
+            Node::Device(..) => syserr(libc::ENXIO),
         }
     }
 
@@ -476,8 +591,11 @@ impl FileSystemOps for SyntheticFS {
         syserr(libc::EROFS)
     }
 
-    fn readlink(&self, _path: &Path) -> io::Result<OsString> {
-        syserr(libc::EROFS)
+    fn readlink(&self, path: &Path) -> io::Result<OsString> {
+        match self.lookup(path)? {
+            Node::Symlink(target, _) => Ok(target.as_os_str().to_os_string()),
+            _ => syserr(libc::EINVAL),
+        }
     }
 
     fn symlink(&self, _target: &Path, _linkpath: &Path) -> io::Result<()> {
@@ -1,17 +1,22 @@
 use std::io::Write;
-use std::{result, io, thread};
+use std::{cmp, result, io};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
 
 use crate::disk;
-use crate::disk::DiskImage;
+use crate::disk::{DiskImage, VIRTIO_BLK_ID_BYTES};
 
 use thiserror::Error;
 use crate::io::{Chain, FeatureBits, Queues, VirtioDevice, VirtioDeviceType, VirtioError, VirtQueue};
 use crate::io::virtio::DeviceConfigArea;
+use crate::util::JsonValue;
 
 const VIRTIO_BLK_F_RO: u64 = 1 << 5;
 const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
 const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
 const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_CONFIG_WCE: u64 = 1 << 11;
 
 const VIRTIO_BLK_T_IN: u32 = 0;
 const VIRTIO_BLK_T_OUT: u32 = 1;
@@ -39,16 +44,43 @@ enum Error {
     DiskFlush(disk::Error),
     #[error("error waiting on virtqueue: {0}")]
     VirtQueueWait(VirtioError),
-    #[error("virtqueue read descriptor size ({0}) is invalid. Not a multiple of sector size")]
-    InvalidReadDescriptor(usize),
+    #[error("request has no writeable status byte")]
+    MissingStatus,
+    #[error("request header is truncated ({0} bytes)")]
+    ShortHeader(usize),
+    #[error("request data length ({0}) is not a multiple of sector size")]
+    InvalidDataLength(usize),
+    #[error("virtqueue descriptor size ({0}) is invalid. Not a multiple of sector size")]
+    InvalidDescriptor(usize),
+    #[error("request for {1} sectors at sector {0} is beyond the end of the disk")]
+    OutOfRange(u64, u64),
+    #[error("write request to read-only disk")]
+    ReadOnly,
+    #[error("unsupported request type {0}")]
+    Unsupported(u32),
+}
+
+impl Error {
+    // The status reported to the driver for a request which failed with this error
+    fn status(&self) -> u8 {
+        match self {
+            Error::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+            _ => VIRTIO_BLK_S_IOERR,
+        }
+    }
 }
 
 type Result<T> = result::Result<T, Error>;
 
 pub struct VirtioBlock<D: DiskImage+'static> {
     disk_image: Option<D>,
+    disk_info: JsonValue,
     config: DeviceConfigArea,
     features: FeatureBits,
+    // Current cache mode, shared with the worker so that the driver can
+    // change it while the device is running.
+    writeback: Arc<AtomicBool>,
+    worker: Option<JoinHandle<D>>,
 }
 
 const HEADER_SIZE: usize = 16;
@@ -56,7 +88,8 @@ const HEADER_SIZE: usize = 16;
 const CAPACITY_OFFSET: usize = 0;
 const SEG_MAX_OFFSET: usize = 12;
 const BLK_SIZE_OFFSET: usize = 20;
-const CONFIG_SIZE: usize = 24;
+const WRITEBACK_OFFSET: usize = 32;
+const CONFIG_SIZE: usize = 36;
 impl <D: DiskImage + 'static> VirtioBlock<D> {
 
     pub fn new(disk_image: D) -> Self {
@@ -64,21 +97,36 @@ impl <D: DiskImage + 'static> VirtioBlock<D> {
         config.write_u64(CAPACITY_OFFSET, disk_image.sector_count());
         config.write_u32(SEG_MAX_OFFSET, QUEUE_SIZE as u32 - 2);
         config.write_u32(BLK_SIZE_OFFSET, 1024);
+        config.write_u8(WRITEBACK_OFFSET, 1);
+        config.set_writeable(WRITEBACK_OFFSET, 1);
         let features = FeatureBits::new_default( VIRTIO_BLK_F_FLUSH |
                 VIRTIO_BLK_F_BLK_SIZE |
                 VIRTIO_BLK_F_SEG_MAX  |
                 if disk_image.read_only() {
                     VIRTIO_BLK_F_RO
                 } else {
-                    0
+                    VIRTIO_BLK_F_CONFIG_WCE
                 }
         );
+        let disk_info = disk_image.describe();
         VirtioBlock {
             disk_image: Some(disk_image),
+            disk_info,
             config,
             features,
+            writeback: Arc::new(AtomicBool::new(true)),
+            worker: None,
         }
     }
+
+    // Without VIRTIO_BLK_F_FLUSH the guest expects every write to be durable
+    // when it completes. With VIRTIO_BLK_F_CONFIG_WCE the driver chooses the
+    // cache mode with the writeback field of the configuration area.
+    fn update_writeback(&self) {
+        let writeback = self.features.has_guest_bit(VIRTIO_BLK_F_FLUSH) &&
+            (!self.features.has_guest_bit(VIRTIO_BLK_F_CONFIG_WCE) || self.config.read_u8(WRITEBACK_OFFSET) != 0);
+        self.writeback.store(writeback, Ordering::Release);
+    }
 }
 
 impl <D: DiskImage> VirtioDevice for VirtioBlock<D> {
@@ -103,143 +151,256 @@ impl <D: DiskImage> VirtioDevice for VirtioBlock<D> {
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        self.config.write_config(offset, data);
+        if self.config.write_config(offset, data) && offset as usize == WRITEBACK_OFFSET {
+            self.update_writeback();
+        }
     }
 
     fn start(&mut self, queues: &Queues) {
         let vq = queues.get_queue(0);
 
-        let mut disk = self.disk_image.take().expect("No disk image?");
+        let mut disk = match self.disk_image.take() {
+            Some(disk) => disk,
+            None => {
+                // The disk is lost if a previous worker thread panicked or could not
+                // be started
+                vq.report_failure(&"no disk image available");
+                return;
+            }
+        };
+        disk.register_stats(queues.device_stats());
         if let Err(err) = disk.open() {
-            warn!("Unable to start virtio-block device: {}", err);
+            vq.report_failure(&format_args!("unable to open disk image: {}", err));
+            self.disk_image = Some(disk);
             return;
         }
-        let mut dev = VirtioBlockDevice::new(vq, disk);
-        thread::spawn(move || {
+        self.update_writeback();
+        let mut dev = VirtioBlockDevice::new(vq, disk, self.writeback.clone());
+        self.worker = queues.spawn_worker(move || {
             if let Err(err) = dev.run() {
-                warn!("Error running virtio block device: {}", err);
+                dev.vq.report_failure(&err);
             }
+            dev.disk
         });
     }
+
+    fn stop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            match worker.join() {
+                Ok(disk) => self.disk_image = Some(disk),
+                Err(_) => warn!("virtio block worker thread panicked"),
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(mut disk) = self.disk_image.take() {
+            if let Err(err) = disk.close() {
+                warn!("virtio_block: {}", err);
+            }
+        }
+    }
+
+    fn describe(&self) -> Option<JsonValue> {
+        Some(self.disk_info.clone())
+    }
 }
 
 struct VirtioBlockDevice<D: DiskImage> {
     vq: VirtQueue,
     disk: D,
+    writeback: Arc<AtomicBool>,
+    // Cache mode used for the previous request
+    was_writeback: bool,
 }
 
 impl <D: DiskImage> VirtioBlockDevice<D> {
-    fn new(vq: VirtQueue, disk: D) -> Self {
-        VirtioBlockDevice { vq, disk }
+    fn new(vq: VirtQueue, disk: D, writeback: Arc<AtomicBool>) -> Self {
+        let was_writeback = writeback.load(Ordering::Acquire);
+        VirtioBlockDevice { vq, disk, writeback, was_writeback }
+    }
+
+    // When the driver switches to writethrough mode any writes completed
+    // while in writeback mode are flushed before the next request so that
+    // they are as durable as the writes which follow.
+    fn check_writeback(&mut self) -> Result<bool> {
+        let writeback = self.writeback.load(Ordering::Acquire);
+        if self.was_writeback && !writeback {
+            self.disk.flush().map_err(Error::DiskFlush)?;
+        }
+        self.was_writeback = writeback;
+        Ok(writeback)
     }
 
     fn run(&mut self) -> Result<()> {
         loop {
-            let mut chain = self.vq.wait_next_chain()
-                .map_err(Error::VirtQueueWait)?;
-
-            while chain.remaining_read() >= HEADER_SIZE {
-                match MessageHandler::read_header(&mut self.disk, &mut chain) {
-                    Ok(mut handler) => handler.process_message(),
-                    Err(e) => {
-                        warn!("Error handling virtio_block message: {}", e);
-                    }
+            let mut chain = match self.vq.wait_next_chain() {
+                Ok(chain) => chain,
+                Err(VirtioError::QueueStopped) => {
+                    return self.disk.flush().map_err(Error::DiskFlush);
                 }
-            }
+                Err(e) => return Err(Error::VirtQueueWait(e)),
+            };
+
+            let writeback = self.check_writeback()?;
+            MessageHandler::new(&mut self.disk, &mut chain, writeback).process_request();
         }
     }
 }
 
+///
+/// Parses and completes a single request. A request is a chain holding the
+/// 16 byte header and, for writes, the data in the readable descriptors
+/// followed by the writeable descriptors holding the data for reads and
+/// ending with the status byte.
+///
+/// Requests which do not follow this layout, or which have a data length that
+/// is not a whole number of sectors or extends beyond the end of the disk,
+/// complete with `VIRTIO_BLK_S_IOERR` without accessing the disk. Unknown
+/// request types complete with `VIRTIO_BLK_S_UNSUPP`.
+///
 struct MessageHandler<'a,'b, D: DiskImage> {
     disk: &'a mut D,
     chain: &'b mut Chain,
+    writeback: bool,
     msg_type: u32,
     sector: u64,
 }
 
 impl <'a,'b, D: DiskImage> MessageHandler<'a,'b, D> {
 
-    fn read_header(disk: &'a mut D, chain: &'b mut Chain) -> Result<Self> {
-        let msg_type = chain.r32()?;
-        let _ = chain.r32()?;
-        let sector = chain.r64()?;
-        Ok(MessageHandler { disk, chain, msg_type, sector })
+    fn new(disk: &'a mut D, chain: &'b mut Chain, writeback: bool) -> Self {
+        MessageHandler { disk, chain, writeback, msg_type: 0, sector: 0 }
     }
 
-    fn process_message(&mut self)  {
-        let r = match self.msg_type {
+    fn process_request(&mut self) {
+        // Without a status byte there is no way to report anything, so the
+        // chain is returned to the driver untouched.
+        if self.chain.remaining_write() == 0 {
+            warn_limited!("virtio_block: {}", Error::MissingStatus);
+            self.chain.flush_chain();
+            return;
+        }
+        let result = self.read_header().and_then(|_| self.process_message());
+        let status = match result {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(e) => {
+                warn_limited!("virtio_block: {}", e);
+                e.status()
+            }
+        };
+        self.write_status(status);
+    }
+
+    fn read_header(&mut self) -> Result<()> {
+        let len = self.chain.remaining_read();
+        if len < HEADER_SIZE {
+            return Err(Error::ShortHeader(len));
+        }
+        self.msg_type = self.chain.r32()?;
+        let _ = self.chain.r32()?;
+        self.sector = self.chain.r64()?;
+        Ok(())
+    }
+
+    fn process_message(&mut self) -> Result<()> {
+        match self.msg_type {
             VIRTIO_BLK_T_IN => self.handle_io_in(),
             VIRTIO_BLK_T_OUT => self.handle_io_out(),
             VIRTIO_BLK_T_FLUSH => self.handle_io_flush(),
             VIRTIO_BLK_T_GET_ID => self.handle_get_id(),
-            cmd => {
-                warn!("virtio_block: unexpected command: {}", cmd);
-                self.write_status(VIRTIO_BLK_S_UNSUPP);
-                Ok(())
-            },
-        };
-        self.process_result(r);
+            cmd => Err(Error::Unsupported(cmd)),
+        }
     }
 
-    fn process_result(&mut self, result: Result<()>) {
-        match result {
-            Ok(()) => self.write_status(VIRTIO_BLK_S_OK),
-            Err(e) => {
-                warn!("virtio_block: disk error: {}", e);
-                self.write_status(VIRTIO_BLK_S_IOERR);
-            }
+    // Length of the data area written by the device, everything in the
+    // writeable part of the chain before the status byte
+    fn write_data_len(&self) -> usize {
+        self.chain.remaining_write() - 1
+    }
+
+    // Check that `len` bytes of data is a whole number of sectors which lie
+    // within the disk starting at the request sector
+    fn check_data_len(&self, len: usize) -> Result<()> {
+        if len & (SECTOR_SIZE-1) != 0 {
+            return Err(Error::InvalidDataLength(len));
+        }
+        let nsectors = (len >> SECTOR_SHIFT) as u64;
+        match self.sector.checked_add(nsectors) {
+            Some(end) if end <= self.disk.sector_count() => Ok(()),
+            _ => Err(Error::OutOfRange(self.sector, nsectors)),
         }
     }
 
     fn handle_io_in(&mut self) -> Result<()> {
-        loop {
-            let current = self.chain.current_write_slice();
-            let nsectors = current.len() >> SECTOR_SHIFT;
-            if nsectors == 0 {
-                return Ok(())
+        let len = self.write_data_len();
+        self.check_data_len(len)?;
+        let mut remaining = len;
+        for current in self.chain.writeable_slices()? {
+            if remaining == 0 {
+                break;
+            }
+            let size = cmp::min(current.len(), remaining);
+            if size & (SECTOR_SIZE-1) != 0 {
+                return Err(Error::InvalidDescriptor(current.len()));
             }
-            let len = nsectors << SECTOR_SHIFT;
-            let mut buffer = current.subslice(0, len)
+            let mut buffer = current.subslice(0, size)
                 .map_err(io::Error::other)?;
-
             self.disk.read_sectors(self.sector, &mut buffer)
                 .map_err(Error::DiskRead)?;
-            self.chain.inc_write_offset(len);
-            self.sector += nsectors as u64;
+            self.sector += (size >> SECTOR_SHIFT) as u64;
+            remaining -= size;
         }
+        self.chain.inc_write_offset(len);
+        Ok(())
     }
 
     fn handle_io_out(&mut self) -> Result<()> {
-        loop {
-            let current = self.chain.current_read_slice();
+        if self.disk.read_only() {
+            return Err(Error::ReadOnly);
+        }
+        self.check_data_len(self.chain.remaining_read())?;
+        let mut total = 0;
+        for current in self.chain.readable_slices()? {
             if current.len() & (SECTOR_SIZE-1) != 0 {
-                return Err(Error::InvalidReadDescriptor(current.len()));
-            }
-            let nsectors = current.len() >> SECTOR_SHIFT;
-            if nsectors == 0 {
-                return Ok(())
+                return Err(Error::InvalidDescriptor(current.len()));
             }
             self.disk.write_sectors(self.sector, &current)
                 .map_err(Error::DiskWrite)?;
-
-            self.chain.inc_read_offset(nsectors << SECTOR_SHIFT);
-            self.sector += nsectors as u64;
+            self.sector += (current.len() >> SECTOR_SHIFT) as u64;
+            total += current.len();
         }
+        self.chain.inc_read_offset(total);
+        if !self.writeback {
+            self.disk.flush().map_err(Error::DiskFlush)?;
+        }
+        Ok(())
     }
 
     fn handle_io_flush(&mut self) -> Result<()> {
         self.disk.flush().map_err(Error::DiskFlush)
     }
 
+    // The id is padded with zeroes to VIRTIO_BLK_ID_BYTES, and truncated if
+    // the driver supplied a shorter buffer
     fn handle_get_id(&mut self) -> Result<()> {
-        self.chain.write_all(self.disk.disk_image_id())?;
+        let mut id = [0u8; VIRTIO_BLK_ID_BYTES];
+        let disk_id = self.disk.disk_image_id();
+        let n = cmp::min(disk_id.len(), VIRTIO_BLK_ID_BYTES);
+        id[..n].copy_from_slice(&disk_id[..n]);
+        let len = cmp::min(self.write_data_len(), VIRTIO_BLK_ID_BYTES);
+        self.chain.write_all(&id[..len])?;
         Ok(())
     }
 
+    // The status is always the last writeable byte of the chain, so any part
+    // of the data area which was not written is skipped over.
     fn write_status(&mut self, status: u8) {
+        let skip = self.write_data_len();
+        self.chain.inc_write_offset(skip);
         if let Err(e) = self.chain.w8(status) {
-           warn!("Error writing block device status: {}", e);
+           warn_limited!("Error writing block device status: {}", e);
         }
         self.chain.flush_chain();
     }
-}
\ No newline at end of file
+}
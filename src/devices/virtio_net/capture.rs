@@ -0,0 +1,168 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+
+// Number of rotated files kept in addition to the current one
+const ROTATED_FILES: usize = 4;
+
+/// Direction of a captured frame, recorded from the point of view of the
+/// guest network interface.
+#[derive(Copy,Clone)]
+pub enum Direction {
+    /// Read from the tap device and delivered to the guest
+    Inbound,
+    /// Sent by the guest and written to the tap device
+    Outbound,
+}
+
+impl Direction {
+    fn epb_flags(self) -> u32 {
+        match self {
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        }
+    }
+}
+
+///
+/// Writes ethernet frames to a file in pcapng format.
+///
+/// When the file would grow beyond `max_size` bytes it is renamed with the
+/// suffix `.1` (shifting older files to `.2`, `.3` and so on, with the
+/// oldest removed) and a new file is started at `path`.
+///
+pub struct PacketCapture {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl PacketCapture {
+    pub fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        let (file, size) = Self::create_file(path)?;
+        Ok(PacketCapture {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn create_file(path: &Path) -> io::Result<(File, u64)> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        let mut header = Vec::new();
+        Self::section_header(&mut header);
+        Self::interface_description(&mut header);
+        file.write_all(&header)?;
+        Ok((file, header.len() as u64))
+    }
+
+    fn section_header(buf: &mut Vec<u8>) {
+        let len = 28u32;
+        put_u32(buf, BLOCK_SECTION_HEADER);
+        put_u32(buf, len);
+        put_u32(buf, BYTE_ORDER_MAGIC);
+        put_u16(buf, 1);
+        put_u16(buf, 0);
+        // Section length is not known
+        buf.extend_from_slice(&(-1i64).to_le_bytes());
+        put_u32(buf, len);
+    }
+
+    fn interface_description(buf: &mut Vec<u8>) {
+        let len = 20u32;
+        put_u32(buf, BLOCK_INTERFACE_DESCRIPTION);
+        put_u32(buf, len);
+        put_u16(buf, LINKTYPE_ETHERNET);
+        put_u16(buf, 0);
+        // No snapshot length limit
+        put_u32(buf, 0);
+        put_u32(buf, len);
+    }
+
+    /// Append `frame` as an enhanced packet block timestamped with the
+    /// current time.
+    pub fn write_frame(&mut self, frame: &[u8], direction: Direction) -> io::Result<()> {
+        let padded = (frame.len() + 3) & !3;
+        // header, timestamp and lengths, data, epb_flags option, end of options, length
+        let len = 28 + padded + 8 + 4 + 4;
+        if self.size + len as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        let mut buf = Vec::with_capacity(len);
+        put_u32(&mut buf, BLOCK_ENHANCED_PACKET);
+        put_u32(&mut buf, len as u32);
+        // Interface id
+        put_u32(&mut buf, 0);
+        put_u32(&mut buf, (micros >> 32) as u32);
+        put_u32(&mut buf, micros as u32);
+        put_u32(&mut buf, frame.len() as u32);
+        put_u32(&mut buf, frame.len() as u32);
+        buf.extend_from_slice(frame);
+        buf.resize(buf.len() + padded - frame.len(), 0);
+        put_u16(&mut buf, OPT_EPB_FLAGS);
+        put_u16(&mut buf, 4);
+        put_u32(&mut buf, direction.epb_flags());
+        put_u16(&mut buf, OPT_ENDOFOPT);
+        put_u16(&mut buf, 0);
+        put_u32(&mut buf, len as u32);
+
+        self.file.write_all(&buf)?;
+        self.size += len as u64;
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..ROTATED_FILES).rev() {
+            let from = self.rotated_path(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        let (file, size) = Self::create_file(&self.path)?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
@@ -0,0 +1,192 @@
+use std::convert::TryInto;
+
+use super::{VIRTIO_NET_F_CSUM, VIRTIO_NET_F_HOST_ECN, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_HDR_GSO_NONE};
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_UDP: u8 = 3;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const ETH_HLEN: usize = 14;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const ETH_P_8021Q: u16 = 0x8100;
+
+const IPV6_HLEN: usize = 40;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const TCP_HLEN: usize = 20;
+const UDP_HLEN: usize = 8;
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+/// The virtio net header in front of a frame transmitted by the guest.
+pub struct VnetHdr {
+    flags: u8,
+    gso_type: u8,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VnetHdr {
+    pub fn parse(buf: &[u8]) -> Self {
+        VnetHdr {
+            flags: buf[0],
+            gso_type: buf[1],
+            gso_size: le16(buf, 4),
+            csum_start: le16(buf, 6),
+            csum_offset: le16(buf, 8),
+        }
+    }
+
+    /// Whether the guest left the transport checksum of the frame to the
+    /// host, either directly or as part of segmentation offload.
+    pub fn is_offloaded(&self) -> bool {
+        self.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 || self.gso_type != VIRTIO_NET_HDR_GSO_NONE
+    }
+
+    /// Describe what is wrong with this header for a frame of `frame_len`
+    /// bytes, given the `features` negotiated by the guest, or return `None`
+    /// if it is valid. The kernel drops some of these frames silently and
+    /// transmits others with a corrupted checksum.
+    pub fn check(&self, frame_len: usize, features: u64) -> Option<String> {
+        if self.flags & !VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+            return Some(format!("unexpected flags 0x{:02x}", self.flags));
+        }
+        if self.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+            if features & VIRTIO_NET_F_CSUM == 0 {
+                return Some("checksum offload requested without VIRTIO_NET_F_CSUM".to_string());
+            }
+            let end = self.csum_start as usize + self.csum_offset as usize + 2;
+            if end > frame_len {
+                return Some(format!("checksum at {}+{} is outside of the {} byte frame",
+                                    self.csum_start, self.csum_offset, frame_len));
+            }
+        }
+        if self.gso_type == VIRTIO_NET_HDR_GSO_NONE {
+            return None;
+        }
+        let required = match self.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+            VIRTIO_NET_HDR_GSO_TCPV4 => VIRTIO_NET_F_HOST_TSO4,
+            VIRTIO_NET_HDR_GSO_TCPV6 => VIRTIO_NET_F_HOST_TSO6,
+            // UDP fragmentation offload is never offered
+            VIRTIO_NET_HDR_GSO_UDP => return Some("UDP fragmentation offload was not negotiated".to_string()),
+            _ => return Some(format!("unknown gso_type 0x{:02x}", self.gso_type)),
+        };
+        if features & required == 0 {
+            return Some(format!("segmentation offload gso_type 0x{:02x} was not negotiated", self.gso_type));
+        }
+        if self.gso_type & VIRTIO_NET_HDR_GSO_ECN != 0 && features & VIRTIO_NET_F_HOST_ECN == 0 {
+            return Some("ECN segmentation offload was not negotiated".to_string());
+        }
+        if self.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM == 0 {
+            return Some("segmentation offload without checksum offload".to_string());
+        }
+        if self.gso_size == 0 {
+            return Some("segmentation offload with a gso_size of 0".to_string());
+        }
+        None
+    }
+}
+
+// Ones' complement sum of `data` as big endian 16 bit words added to `sum`
+fn add_words(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(be16(word, 0));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+fn is_valid_sum(mut sum: u32) -> bool {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum == 0xffff
+}
+
+/// Check the IPv4 header checksum and the TCP or UDP checksum of an
+/// ethernet `frame` which the guest checksummed itself. Returns the name of
+/// the first checksum which is wrong. Frames which are not TCP or UDP over
+/// IP, fragments and truncated packets are not checked.
+pub fn bad_checksum(frame: &[u8]) -> Option<&'static str> {
+    if frame.len() < ETH_HLEN {
+        return None;
+    }
+    let (ethertype, l3) = match be16(frame, 12) {
+        ETH_P_8021Q if frame.len() >= ETH_HLEN + 4 => (be16(frame, 16), &frame[ETH_HLEN + 4..]),
+        ethertype => (ethertype, &frame[ETH_HLEN..]),
+    };
+    match ethertype {
+        ETH_P_IP => bad_ipv4_checksum(l3),
+        ETH_P_IPV6 => bad_ipv6_checksum(l3),
+        _ => None,
+    }
+}
+
+fn bad_ipv4_checksum(ip: &[u8]) -> Option<&'static str> {
+    if ip.len() < 20 || ip[0] >> 4 != 4 {
+        return None;
+    }
+    let ihl = usize::from(ip[0] & 0xf) * 4;
+    let total_len = usize::from(be16(ip, 2));
+    if ihl < 20 || total_len < ihl || total_len > ip.len() {
+        return None;
+    }
+    if !is_valid_sum(add_words(0, &ip[..ihl])) {
+        return Some("IPv4 header");
+    }
+    // Only the first fragment has a transport header, and the checksum
+    // covers the reassembled packet
+    if be16(ip, 6) & 0x3fff != 0 {
+        return None;
+    }
+    let payload = &ip[ihl..total_len];
+    let protocol = ip[9];
+    if protocol == IPPROTO_UDP && payload.len() >= UDP_HLEN && be16(payload, 6) == 0 {
+        // No UDP checksum
+        return None;
+    }
+    let sum = add_words(0, &ip[12..20]);
+    bad_transport_checksum(sum, protocol, payload)
+}
+
+fn bad_ipv6_checksum(ip: &[u8]) -> Option<&'static str> {
+    if ip.len() < IPV6_HLEN || ip[0] >> 4 != 6 {
+        return None;
+    }
+    let payload_len = usize::from(be16(ip, 4));
+    if IPV6_HLEN + payload_len > ip.len() {
+        return None;
+    }
+    // Packets with extension headers are not checked
+    let sum = add_words(0, &ip[8..IPV6_HLEN]);
+    bad_transport_checksum(sum, ip[6], &ip[IPV6_HLEN..IPV6_HLEN + payload_len])
+}
+
+// `sum` holds the source and destination addresses of the pseudo header
+fn bad_transport_checksum(sum: u32, protocol: u8, payload: &[u8]) -> Option<&'static str> {
+    let name = match protocol {
+        IPPROTO_TCP if payload.len() >= TCP_HLEN => "TCP",
+        IPPROTO_UDP if payload.len() >= UDP_HLEN => "UDP",
+        _ => return None,
+    };
+    let sum = sum + u32::from(protocol) + payload.len() as u32;
+    if is_valid_sum(add_words(sum, payload)) {
+        None
+    } else {
+        Some(name)
+    }
+}
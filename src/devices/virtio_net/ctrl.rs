@@ -0,0 +1,205 @@
+use std::io::{self, Read};
+
+use crate::io::Chain;
+
+pub const VIRTIO_NET_F_CTRL_VQ: u64 = 1 << 17;
+pub const VIRTIO_NET_F_CTRL_RX: u64 = 1 << 18;
+pub const VIRTIO_NET_F_CTRL_VLAN: u64 = 1 << 19;
+pub const VIRTIO_NET_F_CTRL_MAC_ADDR: u64 = 1 << 23;
+
+const VIRTIO_NET_OK: u8 = 0;
+const VIRTIO_NET_ERR: u8 = 1;
+
+const VIRTIO_NET_CTRL_RX: u8 = 0;
+const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+
+const VIRTIO_NET_CTRL_MAC: u8 = 1;
+const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+const VIRTIO_NET_CTRL_MAC_ADDR_SET: u8 = 1;
+
+const VIRTIO_NET_CTRL_VLAN: u8 = 2;
+const VIRTIO_NET_CTRL_VLAN_ADD: u8 = 0;
+const VIRTIO_NET_CTRL_VLAN_DEL: u8 = 1;
+
+// Longer MAC tables are treated as accepting every address of that type
+const MAC_TABLE_ENTRIES: usize = 64;
+const MAX_VLAN: u16 = 1 << 12;
+
+const ETH_ALEN: usize = 6;
+const ETH_HLEN: usize = 14;
+const ETH_P_8021Q: u16 = 0x8100;
+
+type MacAddr = [u8; ETH_ALEN];
+
+struct MacTable {
+    entries: Vec<MacAddr>,
+    overflow: bool,
+}
+
+impl MacTable {
+    fn new() -> Self {
+        MacTable { entries: Vec::new(), overflow: false }
+    }
+
+    fn read_from(chain: &mut Chain) -> io::Result<Self> {
+        let count = chain.r32()? as usize;
+        if count.saturating_mul(ETH_ALEN) > chain.remaining_read() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let mut table = MacTable::new();
+        table.overflow = count > MAC_TABLE_ENTRIES;
+        for _ in 0..count {
+            let mut mac = [0u8; ETH_ALEN];
+            chain.read_exact(&mut mac)?;
+            if !table.overflow {
+                table.entries.push(mac);
+            }
+        }
+        Ok(table)
+    }
+
+    fn contains(&self, mac: &[u8]) -> bool {
+        self.overflow || self.entries.iter().any(|m| m == mac)
+    }
+}
+
+///
+/// Receive filter state set by the driver through the control virtqueue.
+///
+/// Frames from the backend which the guest has not asked to receive are
+/// dropped before they are copied into the receive queue. Until the driver
+/// sends any receive mode commands every frame is accepted, and if the
+/// device has no MAC address and the guest has never told the device its
+/// address every unicast frame is accepted since the address cannot be
+/// checked.
+///
+/// When VLAN filtering is negotiated tagged frames are only accepted for
+/// VLAN ids the driver has added. Untagged frames are not affected.
+///
+pub struct RxFilter {
+    promisc: bool,
+    allmulti: bool,
+    mac: Option<MacAddr>,
+    unicast: MacTable,
+    multicast: MacTable,
+    vlan_filter: bool,
+    vlans: Vec<u32>,
+}
+
+impl RxFilter {
+    pub fn new(features: u64, mac: Option<MacAddr>) -> Self {
+        RxFilter {
+            promisc: true,
+            allmulti: true,
+            mac,
+            unicast: MacTable::new(),
+            multicast: MacTable::new(),
+            vlan_filter: features & VIRTIO_NET_F_CTRL_VLAN != 0,
+            vlans: vec![0; MAX_VLAN as usize / 32],
+        }
+    }
+
+    /// True if every frame is accepted and frames do not need to be checked.
+    pub fn accepts_all(&self) -> bool {
+        self.promisc
+    }
+
+    /// Decide whether the ethernet frame `frame` should be delivered to the guest.
+    pub fn accepts(&self, frame: &[u8]) -> bool {
+        if self.promisc || frame.len() < ETH_HLEN {
+            return true;
+        }
+        if self.vlan_filter && u16::from_be_bytes([frame[12], frame[13]]) == ETH_P_8021Q {
+            if frame.len() < ETH_HLEN + 2 {
+                return false;
+            }
+            let vid = u16::from_be_bytes([frame[14], frame[15]]) & (MAX_VLAN - 1);
+            if !self.has_vlan(vid) {
+                return false;
+            }
+        }
+        let dest = &frame[..ETH_ALEN];
+        if dest.iter().all(|&b| b == 0xff) {
+            true
+        } else if dest[0] & 1 != 0 {
+            self.allmulti || self.multicast.contains(dest)
+        } else {
+            self.mac.map_or(true, |mac| mac == dest) || self.unicast.contains(dest)
+        }
+    }
+
+    fn has_vlan(&self, vid: u16) -> bool {
+        self.vlans[vid as usize / 32] & (1 << (vid % 32)) != 0
+    }
+
+    fn set_vlan(&mut self, vid: u16, enabled: bool) {
+        let bit = 1 << (vid % 32);
+        let word = &mut self.vlans[vid as usize / 32];
+        if enabled {
+            *word |= bit;
+        } else {
+            *word &= !bit;
+        }
+    }
+
+    /// Process a single command from the control queue and write the
+    /// acknowledgement to the chain.
+    pub fn handle_command(&mut self, chain: &mut Chain) -> io::Result<()> {
+        let mut hdr = [0u8; 2];
+        chain.read_exact(&mut hdr)?;
+        let ack = match self.command(hdr[0], hdr[1], chain) {
+            Ok(true) => VIRTIO_NET_OK,
+            Ok(false) => {
+                notify_limited!("virtio_net: unsupported control command class={} command={}", hdr[0], hdr[1]);
+                VIRTIO_NET_ERR
+            }
+            Err(e) => {
+                notify_limited!("virtio_net: invalid control command class={} command={}: {}", hdr[0], hdr[1], e);
+                VIRTIO_NET_ERR
+            }
+        };
+        chain.w8(ack)?;
+        chain.flush_chain();
+        Ok(())
+    }
+
+    // Returns Ok(false) for commands which are not supported
+    fn command(&mut self, class: u8, cmd: u8, chain: &mut Chain) -> io::Result<bool> {
+        match (class, cmd) {
+            (VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC) => {
+                self.promisc = Self::read_on(chain)?;
+            }
+            (VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI) => {
+                self.allmulti = Self::read_on(chain)?;
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET) => {
+                let unicast = MacTable::read_from(chain)?;
+                let multicast = MacTable::read_from(chain)?;
+                self.unicast = unicast;
+                self.multicast = multicast;
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET) => {
+                let mut mac = [0u8; ETH_ALEN];
+                chain.read_exact(&mut mac)?;
+                self.mac = Some(mac);
+            }
+            (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_ADD) |
+            (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_DEL) => {
+                let vid = chain.r16()?;
+                if vid >= MAX_VLAN {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                self.set_vlan(vid, cmd == VIRTIO_NET_CTRL_VLAN_ADD);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn read_on(chain: &mut Chain) -> io::Result<bool> {
+        let mut on = [0u8; 1];
+        chain.read_exact(&mut on)?;
+        Ok(on[0] != 0)
+    }
+}
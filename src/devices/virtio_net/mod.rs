@@ -0,0 +1,1021 @@
+use crate::system;
+use std::{result, io};
+use std::thread::JoinHandle;
+use crate::system::{EPoll, PollAction, PollDispatcher, Trigger};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use crate::system::NetBackend;
+
+use thiserror::Error;
+use crate::io::{Chain, FeatureBits, Queues, VirtioDevice, VirtioDeviceType, VirtQueue};
+use crate::io::stats::Counter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use vmm_sys_util::eventfd::EventFd;
+use crate::util::JsonValue;
+use self::capture::{Direction, PacketCapture};
+use self::checksum::{bad_checksum, VnetHdr};
+use self::ctrl::{RxFilter, VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_RX, VIRTIO_NET_F_CTRL_VLAN, VIRTIO_NET_F_CTRL_VQ};
+use self::ratelimit::RateLimiter;
+use std::time::{Duration, Instant};
+
+pub use self::ratelimit::NetRateLimit;
+
+mod capture;
+mod checksum;
+mod ctrl;
+mod ratelimit;
+
+const MAC_ADDR_LEN: usize = 6;
+// mac[6] followed by le16 status, le16 max_virtqueue_pairs and le16 mtu
+const CONFIG_SIZE: usize = MAC_ADDR_LEN + 6;
+const CONFIG_MTU_OFFSET: usize = MAC_ADDR_LEN + 4;
+
+#[derive(Debug,Error)]
+pub enum Error {
+    #[error("Error writing to virtqueue chain: {0}")]
+    ChainWrite(io::Error),
+    #[error("Error reading from virtqueue ioevent: {0}")]
+    ChainIoEvent(io::Error),
+    #[error("Failed to set up Poll: {0}")]
+    SetupPoll(system::Error),
+    #[error("Error reading from tap device: {0}")]
+    TapRead(io::Error),
+    #[error("Error writing to tap device: {0}")]
+    TapWrite(io::Error),
+    #[error("Poll wait returned error: {0}")]
+    PollWait(system::Error),
+    #[error("Error reading network control event: {0}")]
+    ControlEvent(io::Error),
+    #[error("Error processing control queue command: {0}")]
+    CtrlCommand(io::Error),
+}
+
+type Result<T> = result::Result<T, Error>;
+
+
+const VIRTIO_NET_F_CSUM: u64 = 1;
+const VIRTIO_NET_F_GUEST_CSUM: u64 = 1 << 1;
+const VIRTIO_NET_F_MTU: u64 = 1 << 3;
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_GUEST_TSO4: u64 = 1 << 7;
+const VIRTIO_NET_F_GUEST_TSO6: u64 = 1 << 8;
+const VIRTIO_NET_F_GUEST_ECN : u64 = 1 << 9;
+const VIRTIO_NET_F_HOST_TSO4: u64 = 1 << 11;
+const VIRTIO_NET_F_HOST_TSO6: u64 = 1 << 12;
+const VIRTIO_NET_F_HOST_ECN: u64 = 1 << 13;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const VIRTIO_NET_HDR_SIZE: i32 = 12;
+// gso_type in the header of a frame which is not a GSO segment
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+
+// The device must not offer an MTU smaller than this
+const MIN_MTU: u32 = 68;
+// Ethernet header with a VLAN tag
+const ETH_HLEN_VLAN: usize = 18;
+
+// Delay before trying to attach again to an interface which has gone away,
+// doubling after each failure up to the maximum
+const REATTACH_MIN_DELAY: Duration = Duration::from_secs(1);
+const REATTACH_MAX_DELAY: Duration = Duration::from_secs(30);
+
+///
+/// Runtime control of the link state and traffic capture of a `VirtioNet`
+/// device.
+///
+/// The link state is reported to the guest in the status field of the
+/// configuration area and a configuration change interrupt is sent when it
+/// changes. While the link is down no frames are read from the backend and
+/// frames transmitted by the guest are dropped, so the guest behaves as if
+/// the cable had been pulled.
+///
+/// A reattach request closes the backend and opens the interface again by
+/// name, which allows a tap device that was deleted and created again on
+/// the host to be connected to the running guest. If the interface is
+/// deleted while the guest is running the backend is marked as lost, the
+/// link is reported down to the guest and the interface is opened again
+/// periodically until it reappears.
+///
+/// While a capture is running every frame passed between the backend and
+/// the guest is also written to a pcapng file.
+///
+/// Frames transmitted by the guest can be limited to a maximum rate. While
+/// the guest is over the limit frames are left in the transmit queue, so
+/// the guest driver sees a full queue rather than losing frames.
+///
+/// Frames transmitted by the guest which it has checksummed itself can be
+/// checked for a wrong IPv4, TCP or UDP checksum, which happens when the
+/// guest and the device disagree about the negotiated offloads.
+///
+pub struct NetControl {
+    link_up: AtomicBool,
+    backend_lost: AtomicBool,
+    reattach: AtomicBool,
+    event: EventFd,
+    tx_limit: Mutex<Option<NetRateLimit>>,
+    tx_limit_changed: AtomicBool,
+    capturing: AtomicBool,
+    capture: Mutex<CaptureState>,
+    check_checksums: AtomicBool,
+}
+
+struct CaptureState {
+    capture: Option<PacketCapture>,
+    max_size: u64,
+}
+
+// Default size at which capture files are rotated
+const DEFAULT_CAPTURE_SIZE: u64 = 64 * 1024 * 1024;
+
+impl NetControl {
+    fn new() -> io::Result<Self> {
+        Ok(NetControl {
+            link_up: AtomicBool::new(true),
+            backend_lost: AtomicBool::new(false),
+            reattach: AtomicBool::new(false),
+            event: EventFd::new(libc::EFD_NONBLOCK)?,
+            tx_limit: Mutex::new(None),
+            tx_limit_changed: AtomicBool::new(false),
+            capturing: AtomicBool::new(false),
+            capture: Mutex::new(CaptureState {
+                capture: None,
+                max_size: DEFAULT_CAPTURE_SIZE,
+            }),
+            check_checksums: AtomicBool::new(false),
+        })
+    }
+
+    fn capture_state(&self) -> MutexGuard<CaptureState> {
+        self.capture.lock().unwrap()
+    }
+
+    /// Set the size in bytes at which capture files are rotated.
+    pub fn set_capture_size(&self, max_size: u64) {
+        self.capture_state().max_size = max_size;
+    }
+
+    /// Start writing frames to a new capture file at `path`, replacing any
+    /// capture which is already running.
+    pub fn start_capture(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.capture_state();
+        state.capture = Some(PacketCapture::open(path, state.max_size)?);
+        self.capturing.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn stop_capture(&self) {
+        self.capturing.store(false, Ordering::SeqCst);
+        self.capture_state().capture = None;
+    }
+
+    /// The file frames are currently being written to, if any.
+    pub fn capture_path(&self) -> Option<PathBuf> {
+        self.capture_state().capture.as_ref().map(|c| c.path().to_path_buf())
+    }
+
+    fn is_capturing(&self) -> bool {
+        self.capturing.load(Ordering::Relaxed)
+    }
+
+    // Write a frame including the virtio net header to the capture file
+    fn capture_frame(&self, frame: &[u8], direction: Direction) {
+        let hdr_size = VIRTIO_NET_HDR_SIZE as usize;
+        if frame.len() < hdr_size {
+            return;
+        }
+        let mut state = self.capture_state();
+        if let Some(capture) = state.capture.as_mut() {
+            if let Err(e) = capture.write_frame(&frame[hdr_size..], direction) {
+                warn!("virtio_net: stopping capture to {}: {}", capture.path().display(), e);
+                state.capture = None;
+                self.capturing.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Enable or disable checking the checksums of frames transmitted by
+    /// the guest.
+    pub fn set_check_checksums(&self, enabled: bool) {
+        self.check_checksums.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_checking_checksums(&self) -> bool {
+        self.check_checksums.load(Ordering::Relaxed)
+    }
+
+    pub fn is_link_up(&self) -> bool {
+        self.link_up.load(Ordering::SeqCst)
+    }
+
+    pub fn set_link_up(&self, up: bool) {
+        self.link_up.store(up, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Whether the interface has gone away on the host and pH is waiting
+    /// for it to reappear.
+    pub fn is_backend_lost(&self) -> bool {
+        self.backend_lost.load(Ordering::SeqCst)
+    }
+
+    fn set_backend_lost(&self, lost: bool) {
+        self.backend_lost.store(lost, Ordering::SeqCst);
+    }
+
+    pub fn request_reattach(&self) {
+        self.reattach.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    fn take_reattach(&self) -> bool {
+        self.reattach.swap(false, Ordering::SeqCst)
+    }
+
+    /// Limit the rate at which the guest can transmit, or remove the limit
+    /// if `limit` is `None`.
+    pub fn set_tx_limit(&self, limit: Option<NetRateLimit>) {
+        *self.tx_limit.lock().unwrap() = limit;
+        self.tx_limit_changed.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    pub fn tx_limit(&self) -> Option<NetRateLimit> {
+        *self.tx_limit.lock().unwrap()
+    }
+
+    fn take_tx_limit_change(&self) -> bool {
+        self.tx_limit_changed.swap(false, Ordering::SeqCst)
+    }
+
+    fn wake(&self) {
+        if let Err(e) = self.event.write(1) {
+            warn!("virtio_net: failed to signal network control event: {}", e);
+        }
+    }
+}
+
+pub struct VirtioNet<B: NetBackend> {
+    features: FeatureBits,
+    backend_name: String,
+    mtu: Option<u16>,
+    mac: Option<[u8; MAC_ADDR_LEN]>,
+    tap: Option<B>,
+    control: Arc<NetControl>,
+    worker: Option<JoinHandle<B>>,
+}
+
+fn configure_backend<B: NetBackend>(tap: &B) {
+    if let Err(e) = tap.set_offload(TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6| TUN_F_TSO_ECN) {
+        warn!("virtio_net: failed to set offload flags on {}: {}", tap.name(), e);
+    }
+    if let Err(e) = tap.set_vnet_hdr_size(VIRTIO_NET_HDR_SIZE) {
+        warn!("virtio_net: failed to set vnet header size on {}: {}", tap.name(), e);
+    }
+}
+
+// The MTU of the backend interface, which is offered to the guest with
+// VIRTIO_NET_F_MTU so that a bridge configured for jumbo frames is usable
+fn backend_mtu<B: NetBackend>(tap: &B) -> Option<u16> {
+    match tap.mtu() {
+        Ok(mtu) if mtu >= MIN_MTU => Some(mtu.min(u16::MAX as u32) as u16),
+        Ok(mtu) => {
+            warn!("virtio_net: mtu {} of {} is too small to offer to the guest", mtu, tap.name());
+            None
+        }
+        Err(e) => {
+            warn!("virtio_net: failed to read mtu of {}: {}", tap.name(), e);
+            None
+        }
+    }
+}
+
+fn format_mac(mac: &[u8]) -> String {
+    mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
+
+impl <B: NetBackend> VirtioNet<B> {
+    pub fn new(tap: B) -> io::Result<Self> {
+        configure_backend(&tap);
+        let mtu = backend_mtu(&tap);
+        let mac = tap.mac_address();
+        let mut feature_bits =
+            VIRTIO_NET_F_CSUM |
+                VIRTIO_NET_F_GUEST_CSUM |
+                VIRTIO_NET_F_GUEST_TSO4 |
+                VIRTIO_NET_F_GUEST_TSO6 |
+                VIRTIO_NET_F_GUEST_ECN |
+                VIRTIO_NET_F_HOST_TSO4 |
+                VIRTIO_NET_F_HOST_TSO6 |
+                VIRTIO_NET_F_HOST_ECN |
+                VIRTIO_NET_F_STATUS |
+                VIRTIO_NET_F_CTRL_VQ |
+                VIRTIO_NET_F_CTRL_RX |
+                VIRTIO_NET_F_CTRL_VLAN |
+                VIRTIO_NET_F_CTRL_MAC_ADDR;
+        if mtu.is_some() {
+            feature_bits |= VIRTIO_NET_F_MTU;
+        }
+        if mac.is_some() {
+            feature_bits |= VIRTIO_NET_F_MAC;
+        }
+        let features = FeatureBits::new_default(feature_bits);
+        Ok(VirtioNet{
+            features,
+            backend_name: tap.name().to_string(),
+            mtu,
+            mac,
+            tap: Some(tap),
+            control: Arc::new(NetControl::new()?),
+            worker: None,
+        })
+    }
+
+    /// A handle for changing the link state of this device and capturing
+    /// its traffic at runtime.
+    pub fn net_control(&self) -> Arc<NetControl> {
+        self.control.clone()
+    }
+}
+
+impl <B: NetBackend + 'static> VirtioDevice for VirtioNet<B> {
+    fn features(&self) -> &FeatureBits {
+        &self.features
+    }
+
+    fn queue_sizes(&self) -> &[u16] {
+        &[256, 256, 64]
+    }
+
+    fn required_queues(&self, features: u64) -> usize {
+        // The control queue only exists if VIRTIO_NET_F_CTRL_VQ is negotiated
+        if features & VIRTIO_NET_F_CTRL_VQ != 0 { 3 } else { 2 }
+    }
+
+    fn device_type(&self) -> VirtioDeviceType {
+        VirtioDeviceType::Net
+    }
+
+    fn config_size(&self) -> usize {
+        CONFIG_SIZE
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let carrier = self.control.is_link_up() && !self.control.is_backend_lost();
+        let status = if carrier { VIRTIO_NET_S_LINK_UP } else { 0 };
+        let mut config = [0u8; CONFIG_SIZE];
+        config[..MAC_ADDR_LEN].copy_from_slice(&self.mac.unwrap_or_default());
+        config[MAC_ADDR_LEN..MAC_ADDR_LEN + 2].copy_from_slice(&status.to_le_bytes());
+        config[MAC_ADDR_LEN + 2..CONFIG_MTU_OFFSET].copy_from_slice(&1u16.to_le_bytes());
+        config[CONFIG_MTU_OFFSET..].copy_from_slice(&self.mtu.unwrap_or(0).to_le_bytes());
+        let offset = offset as usize;
+        if offset + data.len() <= CONFIG_SIZE {
+            data.copy_from_slice(&config[offset..offset + data.len()]);
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let (_,_) = (offset, data);
+    }
+
+    fn start(&mut self, queues: &Queues) {
+        let rx = queues.get_queue(0);
+        let tx = queues.get_queue(1);
+
+        let dispatcher = match PollDispatcher::new() {
+            Ok(dispatcher) => dispatcher,
+            Err(e) => {
+                rx.report_failure(&format_args!("unable to create epoll instance: {}", e));
+                return;
+            }
+        };
+        let tap = match self.tap.take() {
+            Some(tap) => tap,
+            None => {
+                // The backend is lost if a previous worker thread panicked
+                rx.report_failure(&"no network backend available");
+                return;
+            }
+        };
+        let mut dev = VirtioNetDevice::new(rx, tx, tap, self.control.clone());
+        if self.features.has_guest_bit(VIRTIO_NET_F_CTRL_VQ) {
+            dev.ctrl = Some(queues.get_queue(2));
+        }
+        let mac = self.mac.filter(|_| self.features.has_guest_bit(VIRTIO_NET_F_MAC));
+        dev.filter = RxFilter::new(self.features.guest_value(), mac);
+        dev.features = self.features.guest_value();
+        if let Some(mtu) = self.mtu.filter(|_| self.features.has_guest_bit(VIRTIO_NET_F_MTU)) {
+            dev.set_mtu(mtu);
+        }
+        dev.rx_frames = queues.device_stats().counter("rx_frames");
+        dev.tx_frames = queues.device_stats().counter("tx_frames");
+        dev.rx_filtered = queues.device_stats().counter("rx_filtered");
+        dev.tx_throttled = queues.device_stats().counter("tx_throttled");
+        dev.rx_oversize = queues.device_stats().counter("rx_oversize");
+        dev.tx_oversize = queues.device_stats().counter("tx_oversize");
+        dev.tx_bad_vnet_hdr = queues.device_stats().counter("tx_bad_vnet_hdr");
+        dev.tx_bad_csum = queues.device_stats().counter("tx_bad_csum");
+        dev.backend_lost_count = queues.device_stats().counter("backend_lost");
+        self.worker = queues.spawn_worker(move || dev.run(dispatcher));
+    }
+
+    fn stop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            match worker.join() {
+                Ok(tap) => self.tap = Some(tap),
+                Err(_) => warn!("virtio net worker thread panicked"),
+            }
+        }
+    }
+
+    fn describe(&self) -> Option<JsonValue> {
+        Some(JsonValue::object()
+            .field("interface", self.backend_name.as_str())
+            .field("link", if self.control.is_link_up() { "up" } else { "down" })
+            .field("mtu", self.mtu.map(u32::from))
+            .field("mac", self.mac.map(|mac| format_mac(&mac)))
+            .field("backend", if self.control.is_backend_lost() { "lost" } else { "attached" })
+            .field("capture", self.control.capture_path().map(|p| p.display().to_string()))
+            .field("check_checksums", self.control.is_checking_checksums())
+            .field("tx_limit", self.control.tx_limit().map(|l| l.to_json())))
+    }
+}
+pub const TUN_F_CSUM: u32 = 1;
+pub const TUN_F_TSO4: u32 = 2;
+pub const TUN_F_TSO6: u32 = 4;
+pub const TUN_F_TSO_ECN: u32 = 8;
+
+
+// Largest GSO frame with its virtio net header
+const MAX_BUFFER_SIZE: usize = 65562;
+
+struct VirtioNetDevice<B: NetBackend> {
+    tap: B,
+    tap_token: u64,
+    tap_event_enabled: bool,
+    control: Arc<NetControl>,
+    link_up: bool,
+    backend_lost: bool,
+    reattach_delay: Duration,
+    reattach_at: Option<Instant>,
+    rx: VirtQueue,
+    tx: VirtQueue,
+    // A frame held back by the rate limiter until tx_resume
+    tx_pending: Option<Chain>,
+    tx_resume: Option<Instant>,
+    tx_limiter: RateLimiter,
+    ctrl: Option<VirtQueue>,
+    filter: RxFilter,
+    // Features negotiated by the guest
+    features: u64,
+    // Negotiated with VIRTIO_NET_F_MTU
+    mtu: Option<u16>,
+    rx_bytes: usize,
+    rx_frame: Vec<u8>,
+    rx_frames: Arc<Counter>,
+    tx_frames: Arc<Counter>,
+    rx_filtered: Arc<Counter>,
+    tx_throttled: Arc<Counter>,
+    rx_oversize: Arc<Counter>,
+    tx_oversize: Arc<Counter>,
+    tx_bad_vnet_hdr: Arc<Counter>,
+    tx_bad_csum: Arc<Counter>,
+    backend_lost_count: Arc<Counter>,
+}
+
+// Errors returned by a tap or macvtap device once its interface has been deleted
+fn is_backend_gone(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(e) => e == libc::EBADFD || e == libc::ENODEV || e == libc::ENXIO || e == libc::ENOLINK,
+        None => false,
+    }
+}
+
+impl <B: NetBackend + 'static> VirtioNetDevice<B> {
+    fn new(rx: VirtQueue, tx: VirtQueue, tap: B, control: Arc<NetControl>) -> Self {
+        let link_up = control.is_link_up();
+        control.set_backend_lost(false);
+        let tx_limiter = RateLimiter::new(control.tx_limit());
+        VirtioNetDevice {
+            rx,
+            tx,
+            tx_pending: None,
+            tx_resume: None,
+            tx_limiter,
+            tap,
+            tap_token: 0,
+            tap_event_enabled: false,
+            control,
+            link_up,
+            backend_lost: false,
+            reattach_delay: REATTACH_MIN_DELAY,
+            reattach_at: None,
+            ctrl: None,
+            filter: RxFilter::new(0, None),
+            features: 0,
+            mtu: None,
+            rx_bytes: 0,
+            rx_frame: vec![0; MAX_BUFFER_SIZE],
+            rx_frames: Arc::new(Counter::default()),
+            tx_frames: Arc::new(Counter::default()),
+            rx_filtered: Arc::new(Counter::default()),
+            tx_throttled: Arc::new(Counter::default()),
+            rx_oversize: Arc::new(Counter::default()),
+            tx_oversize: Arc::new(Counter::default()),
+            tx_bad_vnet_hdr: Arc::new(Counter::default()),
+            tx_bad_csum: Arc::new(Counter::default()),
+            backend_lost_count: Arc::new(Counter::default()),
+        }
+    }
+
+    // Frames are only passed while the link is up and the interface exists
+    fn has_carrier(&self) -> bool {
+        self.link_up && !self.backend_lost
+    }
+
+    // Once an MTU has been negotiated, frames which are not GSO segments and
+    // do not fit in it are dropped in both directions. The receive buffer
+    // grows if a jumbo frame at the MTU is larger than a GSO frame.
+    fn set_mtu(&mut self, mtu: u16) {
+        self.mtu = Some(mtu);
+        let max_frame = self.max_frame().unwrap_or(0);
+        if max_frame > self.rx_frame.len() {
+            self.rx_frame.resize(max_frame, 0);
+        }
+    }
+
+    // Largest frame which is not a GSO segment, including the virtio net header
+    fn max_frame(&self) -> Option<usize> {
+        self.mtu.map(|mtu| VIRTIO_NET_HDR_SIZE as usize + ETH_HLEN_VLAN + mtu as usize)
+    }
+
+    fn is_oversize(&self, len: usize, gso_type: u8) -> bool {
+        match self.max_frame() {
+            Some(max) => len > max && gso_type == VIRTIO_NET_HDR_GSO_NONE,
+            None => false,
+        }
+    }
+
+    fn is_oversize_tx(&self, chain: &Chain) -> bool {
+        if !self.is_oversize(chain.remaining_read(), VIRTIO_NET_HDR_GSO_NONE) {
+            return false;
+        }
+        let mut hdr = [0u8; 2];
+        chain.read_exact_at(&mut hdr, 0).is_ok() && hdr[1] == VIRTIO_NET_HDR_GSO_NONE
+    }
+
+    fn is_oversize_rx(&self) -> bool {
+        self.rx_bytes > 1 && self.is_oversize(self.rx_bytes, self.rx_frame[1])
+    }
+
+    fn enable_tap_poll(&mut self, poll: &EPoll) {
+        if !self.tap_event_enabled && !self.backend_lost {
+            if let Err(e) = poll.modify(self.tap.as_raw_fd(), self.tap_token, EPoll::READ, Trigger::Level) {
+                warn!("virtio_net: error enabling tap poll event: {}", e);
+            } else {
+                self.tap_event_enabled = true;
+            }
+        }
+    }
+
+    fn disable_tap_events(&mut self, poll: &EPoll) {
+        if self.tap_event_enabled {
+            if let Err(e) = poll.modify(self.tap.as_raw_fd(), self.tap_token, 0, Trigger::Level) {
+                warn!("virtio_net: error disabling tap poll event: {}", e);
+            } else {
+                self.tap_event_enabled = false;
+            }
+        }
+    }
+
+    fn handle_tx_queue(&mut self) -> Result<()> {
+        self.tx.read_ioevent()
+            .map_err(Error::ChainIoEvent)?;
+        self.process_tx()
+    }
+
+    fn next_tx_chain(&mut self) -> Option<Chain> {
+        self.tx_pending.take().or_else(|| self.tx.next_chain())
+    }
+
+    fn process_tx(&mut self) -> Result<()> {
+        if self.tx_resume.is_some() {
+            // Waiting for the rate limiter
+            return Ok(());
+        }
+        while let Some(mut chain) = self.next_tx_chain() {
+            if !self.has_carrier() {
+                // Frames sent while the link is down are lost
+                chain.flush_chain();
+                continue;
+            }
+            if self.is_oversize_tx(&chain) {
+                self.tx_oversize.inc();
+                chain.flush_chain();
+                continue;
+            }
+            let len = chain.remaining_read().saturating_sub(VIRTIO_NET_HDR_SIZE as usize);
+            if let Some(delay) = self.tx_limiter.consume(len) {
+                self.tx_pending = Some(chain);
+                self.tx_resume = Some(Instant::now() + delay);
+                self.tx_throttled.inc();
+                return Ok(());
+            }
+            if self.control.is_capturing() {
+                self.capture_tx(&chain);
+            }
+            self.check_tx_frame(&chain);
+            // Each chain is a single frame and must be written to the tap
+            // device with a single call.
+            chain.writev_to(&self.tap)
+                .map_err(Error::TapWrite)?;
+            self.tx_frames.inc();
+            chain.flush_chain()
+        }
+        Ok(())
+    }
+
+    // Frames are counted and passed on unchanged, the checks only help to
+    // diagnose frames which the kernel drops or sends out corrupted.
+    fn check_tx_frame(&self, chain: &Chain) {
+        let mut hdr = [0u8; VIRTIO_NET_HDR_SIZE as usize];
+        if chain.read_exact_at(&mut hdr, 0).is_err() {
+            self.tx_bad_vnet_hdr.inc();
+            warn_limited!("virtio_net: transmitted frame of {} bytes is shorter than the vnet header", chain.remaining_read());
+            return;
+        }
+        let frame_len = chain.remaining_read() - hdr.len();
+        let hdr = VnetHdr::parse(&hdr);
+        if let Some(problem) = hdr.check(frame_len, self.features) {
+            self.tx_bad_vnet_hdr.inc();
+            warn_limited!("virtio_net: malformed vnet header on transmitted frame: {}", problem);
+            return;
+        }
+        if hdr.is_offloaded() || !self.control.is_checking_checksums() {
+            return;
+        }
+        let mut frame = vec![0u8; frame_len];
+        if chain.read_exact_at(&mut frame, VIRTIO_NET_HDR_SIZE as usize).is_err() {
+            return;
+        }
+        if let Some(checksum) = bad_checksum(&frame) {
+            self.tx_bad_csum.inc();
+            warn_limited!("virtio_net: transmitted frame of {} bytes has a bad {} checksum", frame_len, checksum);
+        }
+    }
+
+    fn capture_tx(&self, chain: &Chain) {
+        let mut frame = vec![0u8; chain.remaining_read()];
+        match chain.read_exact_at(&mut frame, 0) {
+            Ok(()) => self.control.capture_frame(&frame, Direction::Outbound),
+            Err(e) => warn!("virtio_net: failed to copy frame for capture: {}", e),
+        }
+    }
+
+    fn handle_ctrl_queue(&mut self) -> Result<()> {
+        let ctrl = match self.ctrl {
+            Some(ref ctrl) => ctrl,
+            None => return Ok(()),
+        };
+        ctrl.read_ioevent()
+            .map_err(Error::ChainIoEvent)?;
+        while let Some(mut chain) = ctrl.next_chain() {
+            self.filter.handle_command(&mut chain)
+                .map_err(Error::CtrlCommand)?;
+        }
+        Ok(())
+    }
+
+    fn handle_control_event(&mut self, poll: &EPoll) -> Result<()> {
+        self.control.event.read()
+            .map_err(Error::ControlEvent)?;
+        self.update_link(poll);
+        if self.control.take_tx_limit_change() {
+            self.tx_limiter = RateLimiter::new(self.control.tx_limit());
+            self.tx_resume = None;
+            self.process_tx()?;
+        }
+        Ok(())
+    }
+
+    // Send frames held back by the rate limiter once the delay has passed
+    fn resume_tx(&mut self) -> Result<()> {
+        match self.tx_resume {
+            Some(deadline) if deadline <= Instant::now() => {
+                self.tx_resume = None;
+                self.process_tx()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn update_link(&mut self, poll: &EPoll) {
+        let up = self.control.is_link_up();
+        if up == self.link_up {
+            return;
+        }
+        self.link_up = up;
+        if up {
+            self.enable_tap_poll(poll);
+        } else {
+            self.disable_tap_events(poll);
+        }
+        self.rx.notify_config();
+    }
+
+    // The interface was deleted on the host. Stop polling the backend, which
+    // would report EPOLLERR continuously, report the link down to the guest
+    // and try to open the interface again after a delay.
+    fn lose_backend(&mut self, poll: &EPoll) {
+        if self.backend_lost {
+            return;
+        }
+        warn!("virtio_net: interface {} has gone away, will try to reattach", self.tap.name());
+        self.backend_lost = true;
+        self.control.set_backend_lost(true);
+        self.backend_lost_count.inc();
+        if let Err(e) = poll.delete(self.tap.as_raw_fd()) {
+            warn!("virtio_net: error removing tap poll event: {}", e);
+        }
+        self.tap_event_enabled = false;
+        self.rx_bytes = 0;
+        self.reattach_delay = REATTACH_MIN_DELAY;
+        self.reattach_at = Some(Instant::now() + self.reattach_delay);
+        if self.link_up {
+            self.rx.notify_config();
+        }
+    }
+
+    fn retry_reattach(&mut self, dispatcher: &mut PollDispatcher<Self>) -> Result<()> {
+        match self.reattach_at {
+            Some(deadline) if deadline <= Instant::now() => self.reattach(dispatcher),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace the backend with a newly opened instance of the same interface.
+    fn reattach(&mut self, dispatcher: &mut PollDispatcher<Self>) -> Result<()> {
+        let tap = match self.tap.reopen() {
+            Ok(tap) => tap,
+            Err(_) if self.backend_lost => {
+                self.reattach_delay = (self.reattach_delay * 2).min(REATTACH_MAX_DELAY);
+                self.reattach_at = Some(Instant::now() + self.reattach_delay);
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("virtio_net: failed to reattach to {}: {}", self.tap.name(), e);
+                return Ok(());
+            }
+        };
+        configure_backend(&tap);
+        if let Some(mtu) = self.mtu {
+            match tap.mtu() {
+                Ok(new_mtu) if new_mtu != mtu as u32 =>
+                    warn!("virtio_net: mtu of {} is now {}, the guest still uses {}", tap.name(), new_mtu, mtu),
+                _ => {},
+            }
+        }
+        // The tap of a lost backend has already been removed from the epoll set
+        if let Err(e) = dispatcher.unregister(self.tap_token) {
+            if !self.backend_lost {
+                warn!("virtio_net: error removing tap poll event: {}", e);
+            }
+        }
+        self.tap = tap;
+        self.rx_bytes = 0;
+        let was_lost = self.backend_lost;
+        self.backend_lost = false;
+        self.control.set_backend_lost(false);
+        self.reattach_at = None;
+        self.register_tap(dispatcher)?;
+        if was_lost && self.link_up {
+            self.rx.notify_config();
+        }
+        notify!("virtio_net: reattached to {}", self.tap.name());
+        Ok(())
+    }
+
+    fn register_tap(&mut self, dispatcher: &mut PollDispatcher<Self>) -> Result<()> {
+        self.tap_token = dispatcher.register_read(self.tap.as_raw_fd(), |dev, poll, _| {
+            let result = dev.handle_rx_tap(poll);
+            dev.handle_result(poll, result)
+        }).map_err(Error::SetupPoll)?;
+        self.tap_event_enabled = true;
+        if !self.link_up {
+            self.disable_tap_events(dispatcher.poll());
+        }
+        Ok(())
+    }
+
+    fn pending_rx(&self) -> bool {
+        self.rx_bytes != 0
+    }
+
+    // Check the frame read into rx_frame against the receive filter
+    fn rx_frame_accepted(&self) -> bool {
+        let hdr_size = VIRTIO_NET_HDR_SIZE as usize;
+        self.rx_bytes < hdr_size || self.filter.accepts(&self.rx_frame[hdr_size..self.rx_bytes])
+    }
+
+    fn receive_frame(&mut self, chain: &mut Chain) -> Result<bool> {
+        if chain.remaining_write() < self.rx_bytes {
+            notify_limited!("virtio_net: not enough space for frame");
+            Ok(false)
+        } else {
+            chain.write_all(&self.rx_frame[..self.rx_bytes])
+                .map_err(Error::ChainWrite)?;
+            self.rx_bytes = 0;
+            self.rx_frames.inc();
+            Ok(true)
+        }
+    }
+
+    fn tap_read(&mut self) -> Result<bool> {
+        match self.tap.read(&mut self.rx_frame) {
+            Ok(n) => {
+                self.rx_bytes = n;
+                Ok(true)
+            },
+            Err(e) => if let Some(libc::EAGAIN) = e.raw_os_error() {
+                Ok(false)
+            } else {
+                Err(Error::TapRead(e))
+            },
+        }
+    }
+
+    fn next_rx_chain(&mut self, poll: &EPoll) -> Option<Chain> {
+        self.rx.next_chain().or_else(|| {
+            self.disable_tap_events(poll);
+            None
+        })
+    }
+
+    fn handle_rx_tap(&mut self, poll: &EPoll) -> Result<()> {
+        loop {
+            // tap wants to send packets to guest, is an rx chain available?
+            let chain = match self.next_rx_chain(poll) {
+                Some(chain) => chain,
+                None => return Ok(()),
+            };
+            // Frames can only be filtered or captured after reading them into rx_frame
+            if self.pending_rx() || chain.remaining_write() < self.rx_frame.len() ||
+                !self.filter.accepts_all() || self.control.is_capturing() {
+                return self.handle_rx_buffered(chain);
+            }
+            if !self.rx_direct(chain)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Read a frame from the tap directly into a chain which is large enough
+    /// to hold a frame of any size. Returns `false` if no frame was available,
+    /// in which case the chain is returned to the queue unused.
+    fn rx_direct(&mut self, mut chain: Chain) -> Result<bool> {
+        match chain.readv_from(&self.tap) {
+            Ok(_) => {
+                self.rx_frames.inc();
+                Ok(true)
+            }
+            Err(e) => if let Some(libc::EAGAIN) = e.raw_os_error() {
+                chain.return_unused();
+                Ok(false)
+            } else {
+                Err(Error::TapRead(e))
+            },
+        }
+    }
+
+    fn handle_rx_buffered(&mut self, mut chain: Chain) -> Result<()> {
+
+        // If there is already an rx packet pending to send to guest
+        // first write it to rx chain.
+        if self.pending_rx() {
+            if !self.receive_frame(&mut chain)? {
+                return Ok(())
+            }
+        }
+
+        while self.tap_read()? {
+            // Rather than waiting forever for a buffer large enough
+            if self.is_oversize_rx() {
+                self.rx_bytes = 0;
+                self.rx_oversize.inc();
+                continue;
+            }
+            if !self.rx_frame_accepted() {
+                self.rx_bytes = 0;
+                self.rx_filtered.inc();
+                continue;
+            }
+            if self.control.is_capturing() {
+                self.control.capture_frame(&self.rx_frame[..self.rx_bytes], Direction::Inbound);
+            }
+            if chain.remaining_write() < self.rx_bytes {
+                // chain is full but there is still data to deliver,
+                // see if there is another rx chain available.
+                chain = match self.rx.next_chain() {
+                    Some(chain) => chain,
+                    None => return Ok(()),
+                };
+            }
+
+            if !self.receive_frame(&mut chain)? {
+                return Ok(());
+            }
+        }
+        // The tap had nothing more to read, don't hand the guest an empty buffer
+        if chain.get_wlen() == 0 {
+            chain.return_unused();
+        }
+        Ok(())
+    }
+
+    fn handle_rx_queue(&mut self, poll: &EPoll) -> Result<()> {
+        self.rx.read_ioevent()
+            .map_err(Error::ChainIoEvent)?;
+        if !self.tap_event_enabled && self.has_carrier() {
+            self.enable_tap_poll(poll);
+        }
+
+        if self.pending_rx() {
+            self.handle_rx_tap(poll)?;
+        }
+        Ok(())
+    }
+
+    // A backend which has gone away is handled without logging every failed
+    // read and write
+    fn handle_result(&mut self, poll: &EPoll, result: Result<()>) -> PollAction {
+        match result {
+            Err(Error::TapRead(ref e)) | Err(Error::TapWrite(ref e)) if is_backend_gone(e) => {
+                self.lose_backend(poll);
+                PollAction::Continue
+            }
+            result => Self::log_error(result),
+        }
+    }
+
+    fn log_error(result: Result<()>) -> PollAction {
+        if let Err(err) = result {
+            warn!("virtio_net: error handling poll event: {}", err);
+        }
+        PollAction::Continue
+    }
+
+    /// Process events until the queues are stopped by a device reset and then
+    /// return the backend so that the device can be started again.
+    fn run(mut self, dispatcher: PollDispatcher<Self>) -> B {
+        if let Err(err) = self.poll_loop(dispatcher) {
+            self.rx.report_failure(&err);
+        }
+        self.tap
+    }
+
+    fn poll_loop(&mut self, mut dispatcher: PollDispatcher<Self>) -> Result<()> {
+        dispatcher.register_read(self.rx.ioevent().as_raw_fd(), |dev, poll, _|
+            Self::log_error(dev.handle_rx_queue(poll)))
+            .map_err(Error::SetupPoll)?;
+        dispatcher.register_read(self.tx.ioevent().as_raw_fd(), |dev, poll, _| {
+            let result = dev.handle_tx_queue();
+            dev.handle_result(poll, result)
+        }).map_err(Error::SetupPoll)?;
+        if let Some(ctrl) = &self.ctrl {
+            dispatcher.register_read(ctrl.ioevent().as_raw_fd(), |dev, _, _|
+                Self::log_error(dev.handle_ctrl_queue()))
+                .map_err(Error::SetupPoll)?;
+        }
+        dispatcher.register_read(self.control.event.as_raw_fd(), |dev, poll, _| {
+            let result = dev.handle_control_event(poll);
+            dev.handle_result(poll, result)
+        }).map_err(Error::SetupPoll)?;
+        self.register_tap(&mut dispatcher)?;
+
+        while !self.rx.is_stopped() {
+            if self.control.take_reattach() {
+                self.reattach(&mut dispatcher)?;
+            }
+            let deadline = match (self.tx_resume, self.reattach_at) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    dispatcher.dispatch_timeout(self, timeout).map_err(Error::PollWait)?;
+                    let result = self.resume_tx();
+                    self.handle_result(dispatcher.poll(), result);
+                    self.retry_reattach(&mut dispatcher)?;
+                }
+                None => {
+                    dispatcher.dispatch(self).map_err(Error::PollWait)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use std::time::{Duration, Instant};
+
+use crate::util::JsonValue;
+
+const DEFAULT_BURST: Duration = Duration::from_millis(250);
+
+///
+/// Limits on the rate at which the guest may transmit, in bytes and/or
+/// frames per second. Up to `burst` worth of traffic at the configured rate
+/// can be sent at once after the guest has been idle.
+///
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct NetRateLimit {
+    bytes_per_sec: Option<u64>,
+    packets_per_sec: Option<u64>,
+    burst: Duration,
+}
+
+impl NetRateLimit {
+    pub fn new(bytes_per_sec: Option<u64>, packets_per_sec: Option<u64>) -> Self {
+        NetRateLimit {
+            bytes_per_sec,
+            packets_per_sec,
+            burst: DEFAULT_BURST,
+        }
+    }
+
+    pub fn burst(mut self, burst: Duration) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    /// Parse a limit of the form `bytes=RATE,packets=RATE,burst=MS` where
+    /// each field is optional (but at least one rate must be given) and a
+    /// byte rate may have a K, M or G suffix.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut limit = NetRateLimit::new(None, None);
+        for field in s.split(',') {
+            let (key, value) = field.split_once('=')?;
+            match key {
+                "bytes" => limit.bytes_per_sec = Some(parse_size(value)?),
+                "packets" => limit.packets_per_sec = Some(value.parse().ok()?),
+                "burst" => limit.burst = Duration::from_millis(value.parse().ok()?),
+                _ => return None,
+            }
+        }
+        let valid = |rate: Option<u64>| rate != Some(0);
+        if (limit.bytes_per_sec.is_none() && limit.packets_per_sec.is_none()) ||
+            !valid(limit.bytes_per_sec) || !valid(limit.packets_per_sec) || limit.burst.is_zero() {
+            return None;
+        }
+        Some(limit)
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object()
+            .field("bytes_per_sec", self.bytes_per_sec)
+            .field("packets_per_sec", self.packets_per_sec)
+            .field("burst_ms", self.burst.as_millis() as u64)
+    }
+}
+
+fn parse_size(s: &str) -> Option<u64> {
+    let (digits, shift) = match s.chars().last()? {
+        'K' | 'k' => (&s[..s.len() - 1], 10),
+        'M' | 'm' => (&s[..s.len() - 1], 20),
+        'G' | 'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: Duration) -> Self {
+        let rate = rate as f64;
+        let capacity = (rate * burst.as_secs_f64()).max(1.0);
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    // A request larger than the bucket only has to wait for a full bucket
+    fn needed(&self, n: f64) -> f64 {
+        n.min(self.capacity)
+    }
+
+    fn wait_time(&self, n: f64) -> Duration {
+        let missing = self.needed(n) - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+
+    fn take(&mut self, n: f64) {
+        self.tokens -= self.needed(n);
+    }
+}
+
+///
+/// Token bucket rate limiter for transmitted frames.
+///
+pub struct RateLimiter {
+    bytes: Option<TokenBucket>,
+    packets: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Option<NetRateLimit>) -> Self {
+        let bucket = |rate: Option<u64>, burst| rate.map(|r| TokenBucket::new(r, burst));
+        match limit {
+            Some(limit) => RateLimiter {
+                bytes: bucket(limit.bytes_per_sec, limit.burst),
+                packets: bucket(limit.packets_per_sec, limit.burst),
+            },
+            None => RateLimiter { bytes: None, packets: None },
+        }
+    }
+
+    /// Account for sending a frame of `len` bytes. If the frame may be sent
+    /// now `None` is returned, otherwise the time to wait before trying again.
+    pub fn consume(&mut self, len: usize) -> Option<Duration> {
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        for (bucket, n) in [(&mut self.bytes, len as f64), (&mut self.packets, 1.0)] {
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                wait = wait.max(bucket.wait_time(n));
+            }
+        }
+        if !wait.is_zero() {
+            return Some(wait);
+        }
+        for (bucket, n) in [(&mut self.bytes, len as f64), (&mut self.packets, 1.0)] {
+            if let Some(bucket) = bucket {
+                bucket.take(n);
+            }
+        }
+        None
+    }
+}
@@ -1,30 +1,40 @@
 
-use std::thread;
 use std::fs::File;
+use std::thread::JoinHandle;
 use crate::io::{FeatureBits, Queues, VirtioDevice, VirtioDeviceType, VirtQueue};
 
 pub struct VirtioRandom {
     features: FeatureBits,
+    worker: Option<JoinHandle<()>>,
 }
 
 impl VirtioRandom {
     pub fn new() -> VirtioRandom {
         VirtioRandom {
             features: FeatureBits::new_default(0),
+            worker: None,
         }
     }
 }
 
 fn run(q: VirtQueue) {
-    let mut random = File::open("/dev/urandom").unwrap();
+    let mut random = match File::open("/dev/urandom") {
+        Ok(file) => file,
+        Err(e) => {
+            q.report_failure(&format_args!("failed to open /dev/urandom: {}", e));
+            return;
+        }
+    };
 
-    loop {
-        q.on_each_chain(|mut chain| {
-            while !chain.is_end_of_chain() {
-                let _ = chain.copy_from_reader(&mut random, 256).unwrap();
+    let mut failed = false;
+    q.on_each_chain(|mut chain| {
+        while !failed && !chain.is_end_of_chain() {
+            if let Err(e) = chain.copy_from_reader(&mut random, 256) {
+                q.report_failure(&format_args!("error filling buffer from /dev/urandom: {}", e));
+                failed = true;
             }
-        });
-    }
+        }
+    });
 }
 
 impl VirtioDevice for VirtioRandom {
@@ -42,8 +52,16 @@ impl VirtioDevice for VirtioRandom {
 
     fn start(&mut self, queues: &Queues) {
         let vq = queues.get_queue(0);
-        thread::spawn(move|| {
+        self.worker = queues.spawn_worker(move|| {
             run(vq)
         });
     }
+
+    fn stop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                warn!("virtio rng worker thread panicked");
+            }
+        }
+    }
 }
\ No newline at end of file
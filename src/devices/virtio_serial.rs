@@ -1,8 +1,15 @@
-use std::io::{self,Write,Read};
-use std::thread::spawn;
+use std::cmp;
+use std::io::{self,Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 use termios::*;
+use vmm_sys_util::eventfd::EventFd;
 
-use crate::io::{VirtioDevice, VirtioDeviceType, FeatureBits, VirtQueue, ReadableInt, Queues};
+use crate::io::{VirtioDevice, VirtioDeviceType, FeatureBits, VirtQueue, ReadableInt, Queues, Chain};
+use crate::system;
+use crate::system::{EPoll, PollAction, PollDispatcher, Trigger};
+use crate::vm::{VcpuControl, VmExitReason};
 
 const VIRTIO_CONSOLE_F_SIZE: u64 = 0x1;
 const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 0x2;
@@ -16,28 +23,129 @@ const VIRTIO_CONSOLE_RESIZE: u16        = 5;
 const VIRTIO_CONSOLE_PORT_OPEN: u16     = 6;
 const _VIRTIO_CONSOLE_PORT_NAME: u16     = 7;
 
+/// What happens when Ctrl-C is typed on the console.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum CtrlCPolicy {
+    /// Ctrl-C is sent to the guest, except that after three in a row with no
+    /// other input the host terminal settings are restored so that the next
+    /// Ctrl-C interrupts pH.
+    Restore,
+    /// Ctrl-C is always sent to the guest.
+    Forward,
+}
+
+impl CtrlCPolicy {
+    pub fn from_name(name: &str) -> Option<CtrlCPolicy> {
+        match name {
+            "restore" => Some(CtrlCPolicy::Restore),
+            "forward" => Some(CtrlCPolicy::Forward),
+            _ => None,
+        }
+    }
+}
+
+/// Handling of console input which controls pH rather than the guest.
+///
+/// When an escape character is configured, typing it followed by `.` exits
+/// pH and typing it followed by `d` detaches from the console: the host
+/// terminal is restored and no more input is read while guest output is
+/// still displayed. Typing the escape character twice sends it to the guest.
+///
+/// With `escape_at_line_start` the escape character is only recognized as
+/// the first character typed on a line, like the `~` escape of ssh, and is
+/// passed to the guest anywhere else. This allows a character which is also
+/// part of key sequences, such as `^[` which starts the sequences of Alt and
+/// the function keys, to be used as the escape character.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct ConsoleOptions {
+    pub ctrl_c: CtrlCPolicy,
+    pub escape: Option<u8>,
+    pub escape_at_line_start: bool,
+}
+
+impl Default for ConsoleOptions {
+    fn default() -> Self {
+        ConsoleOptions {
+            ctrl_c: CtrlCPolicy::Restore,
+            escape: None,
+            escape_at_line_start: false,
+        }
+    }
+}
+
+impl ConsoleOptions {
+    /// Parse an escape character given as `^X` for Ctrl-X, or as a single
+    /// character.
+    pub fn parse_escape(s: &str) -> Option<u8> {
+        match s.as_bytes() {
+            [b'^', c] if (b'@'..=b'_').contains(&c.to_ascii_uppercase()) => Some(c.to_ascii_uppercase() & 0x1f),
+            [c] if c.is_ascii() => Some(*c),
+            _ => None,
+        }
+    }
+}
+
 pub struct VirtioSerial {
     features: FeatureBits,
+    options: ConsoleOptions,
+    vm_control: Arc<VcpuControl>,
+    input: Option<(EventFd, JoinHandle<()>)>,
+    // Console output and control threads, which exit when their queues stop
+    workers: Vec<JoinHandle<()>>,
 }
 
 impl VirtioSerial {
-    pub fn new() -> VirtioSerial {
+    /// The exit escape stops the VM through `vm_control` so that devices are
+    /// shut down and disks flushed before pH exits.
+    pub(crate) fn new(options: ConsoleOptions, vm_control: Arc<VcpuControl>) -> VirtioSerial {
         let features = FeatureBits::new_default(VIRTIO_CONSOLE_F_MULTIPORT|VIRTIO_CONSOLE_F_SIZE);
         VirtioSerial{
             features,
+            options,
+            vm_control,
+            input: None,
+            workers: Vec::new(),
         }
     }
 
-    fn start_console(&self, q: VirtQueue) {
-        spawn(move || {
+    fn start_input(&mut self, queues: &Queues, port: Arc<Mutex<PortState>>) {
+        let vq = queues.get_queue(0);
+        let control_rx = if self.multiport() {
+            Some(queues.get_queue(2))
+        } else {
+            None
+        };
+        let result = EventFd::new(0).and_then(|kill_evt| {
+            let input = ConsoleInput::new(vq.clone(), control_rx, port, self.options, self.vm_control.clone(), kill_evt.try_clone()?)?;
+            Ok((kill_evt, input))
+        });
+        match result {
+            Ok((kill_evt, input)) => {
+                self.input = queues.spawn_worker(move || input.run())
+                    .map(|handle| (kill_evt, handle));
+            }
+            Err(err) => vq.report_failure(&format_args!("unable to set up console input: {}", err)),
+        }
+    }
+
+    fn start_console(&mut self, queues: &Queues) {
+        let q = queues.get_queue(1);
+        let handle = queues.spawn_worker(move || {
             loop {
-                q.wait_ready().unwrap();
+                if q.wait_ready().is_err() {
+                    return;
+                }
                 for mut chain in q.iter() {
-                    io::copy(&mut chain, &mut io::stdout()).unwrap();
-                    io::stdout().flush().unwrap();
+                    let result = io::copy(&mut chain, &mut io::stdout())
+                        .and_then(|_| io::stdout().flush());
+                    if let Err(err) = result {
+                        q.report_failure(&format_args!("error writing console output: {}", err));
+                        return;
+                    }
                 }
             }
         });
+        self.workers.extend(handle);
     }
 
     fn multiport(&self) -> bool {
@@ -90,16 +198,33 @@ impl VirtioDevice for VirtioSerial {
     }
 
     fn start(&mut self, queues: &Queues) {
-        let mut term = Terminal::create(queues.get_queue(0));
-        self.start_console(queues.get_queue(1));
-        spawn( move || {
-            term.read_loop();
-        });
+        let port = Arc::new(Mutex::new(PortState::default()));
+        self.start_input(queues, port.clone());
+        self.start_console(queues);
         if self.multiport() {
-            let mut control = Control::new(queues.get_queue(2), queues.get_queue(3));
-            spawn(move || {
+            let mut control = Control::new(queues.get_queue(2), queues.get_queue(3), port);
+            let handle = queues.spawn_worker(move || {
                 control.run();
             });
+            self.workers.extend(handle);
+        }
+    }
+
+    // The input thread must be gone before the device is started again so
+    // that only one thread reads stdin, and so that it restores the terminal
+    // before the next one saves it.
+    fn stop(&mut self) {
+        if let Some((kill_evt, worker)) = self.input.take() {
+            if let Err(e) = kill_evt.write(1) {
+                warn!("virtio_serial: failed to signal input thread to stop: {}", e);
+            } else if worker.join().is_err() {
+                warn!("virtio_serial: input thread panicked");
+            }
+        }
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                warn!("virtio_serial: worker thread panicked");
+            }
         }
     }
 }
@@ -107,34 +232,65 @@ impl VirtioDevice for VirtioSerial {
 struct Control {
     rx_vq: VirtQueue,
     tx_vq: VirtQueue,
+    port: Arc<Mutex<PortState>>,
 }
 
 impl Control {
-    fn new(rx: VirtQueue, tx: VirtQueue) -> Control {
-        Control { rx_vq: rx, tx_vq: tx }
+    fn new(rx: VirtQueue, tx: VirtQueue, port: Arc<Mutex<PortState>>) -> Control {
+        Control { rx_vq: rx, tx_vq: tx, port }
     }
 
     fn run(&mut self) {
         let mut rx = self.rx_vq.clone();
+        let port = self.port.clone();
         self.tx_vq.on_each_chain(|mut chain| {
-            let _id = chain.r32().unwrap();
-            let event = chain.r16().unwrap();
-            let _value = chain.r16().unwrap();
-            if event == VIRTIO_CONSOLE_DEVICE_READY {
-                Control::send_msg(&mut rx,0, VIRTIO_CONSOLE_DEVICE_ADD, 1).unwrap();
-            }
-            if event == VIRTIO_CONSOLE_PORT_READY {
-                Control::send_msg(&mut rx,0, VIRTIO_CONSOLE_CONSOLE_PORT, 1).unwrap();
-                Control::send_msg(&mut rx,0, VIRTIO_CONSOLE_PORT_OPEN, 1).unwrap();
-                Control::send_resize(&mut rx, 0).unwrap();
+            let event = match Control::read_event(&mut chain) {
+                Ok(event) => event,
+                Err(err) => {
+                    warn_limited!("virtio_serial: error reading control message: {}", err);
+                    chain.flush_chain();
+                    return;
+                }
+            };
+            if let Err(err) = Control::handle_event(&mut rx, &port, event) {
+                if !rx.is_stopped() {
+                    warn!("virtio_serial: error sending control message: {}", err);
+                }
             }
             chain.flush_chain();
         });
 
     }
 
+    fn read_event(chain: &mut Chain) -> io::Result<u16> {
+        let _id = chain.r32()?;
+        let event = chain.r16()?;
+        let _value = chain.r16()?;
+        Ok(event)
+    }
+
+    fn handle_event(rx: &mut VirtQueue, port: &Mutex<PortState>, event: u16) -> io::Result<()> {
+        if event == VIRTIO_CONSOLE_DEVICE_READY {
+            Control::send_msg(rx,0, VIRTIO_CONSOLE_DEVICE_ADD, 1)?;
+        }
+        if event == VIRTIO_CONSOLE_PORT_READY {
+            let mut port = port.lock().unwrap();
+            port.ready = true;
+            let open = if port.input_closed { 0 } else { 1 };
+            Control::send_msg(rx,0, VIRTIO_CONSOLE_CONSOLE_PORT, 1)?;
+            Control::send_msg(rx,0, VIRTIO_CONSOLE_PORT_OPEN, open)?;
+            Control::send_resize(rx, 0)?;
+        }
+        Ok(())
+    }
+
+    fn next_chain(vq: &mut VirtQueue) -> io::Result<Chain> {
+        vq.wait_next_chain()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
     fn send_msg(vq: &mut VirtQueue, id: u32, event: u16, val: u16) -> io::Result<()> {
-        let mut chain = vq.wait_next_chain().unwrap();
+        let mut chain = Control::next_chain(vq)?;
         chain.w32(id)?;
         chain.w16(event)?;
         chain.w16(val)?;
@@ -144,7 +300,7 @@ impl Control {
 
     fn send_resize(vq: &mut VirtQueue, id: u32) -> io::Result<()> {
         let (cols, rows) = Control::stdin_terminal_size()?;
-        let mut chain = vq.wait_next_chain().unwrap();
+        let mut chain = Control::next_chain(vq)?;
         chain.w32(id)?;
         chain.w16(VIRTIO_CONSOLE_RESIZE)?;
         chain.w16(0)?;
@@ -167,65 +323,330 @@ impl Control {
 
 }
 
+/// Whether the guest has been told the console port exists and whether host
+/// input has reached end of file. Both the control thread and the input
+/// thread update this while holding the lock so that the `PORT_OPEN` messages
+/// they send are not reordered.
+#[derive(Default)]
+struct PortState {
+    ready: bool,
+    input_closed: bool,
+}
+
 struct Terminal {
     saved: Option<Termios>,
-    vq: VirtQueue,
 }
 
 impl Terminal {
-    fn create(vq: VirtQueue) -> Terminal {
-        let termios = Termios::from_fd(0).unwrap();
-        Terminal {
-            saved: Some(termios),
-            vq,
-        }
+    fn create() -> Terminal {
+        let saved = match Termios::from_fd(0) {
+            Ok(termios) => Some(termios),
+            Err(err) => {
+                warn!("virtio_serial: unable to read terminal attributes of stdin: {}", err);
+                None
+            }
+        };
+        Terminal { saved }
     }
 
+    // Pass every byte typed to the guest unchanged, including the control
+    // characters which the host terminal would otherwise handle itself such
+    // as Ctrl-S, Ctrl-Q and Ctrl-V. Output processing is left enabled.
     fn setup_term(&self) {
         if let Some(mut termios) = self.saved {
-            termios.c_iflag &= !(ICRNL);
-            termios.c_lflag &= !(ISIG | ICANON | ECHO);
+            termios.c_iflag &= !(IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON | IXOFF);
+            termios.c_lflag &= !(ISIG | ICANON | ECHO | ECHONL | IEXTEN);
+            termios.c_cc[VMIN] = 1;
+            termios.c_cc[VTIME] = 0;
             let _ = tcsetattr(0, TCSANOW, &termios);
         }
     }
+
     fn restore_term(&mut self) {
         if let Some(termios) = self.saved.take() {
             let _ = tcsetattr(0, TCSANOW, &termios);
         }
     }
+}
 
-    fn read_loop(&mut self) {
-        self.setup_term();
-        let mut abort_cnt = 0;
-        let mut buf = vec![0u8; 32];
-        loop {
-            let n = io::stdin().read(&mut buf).unwrap();
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.restore_term();
+    }
+}
 
-            if n > 0 {
-                // XXX write_all
-                let mut chain = self.vq.wait_next_chain().unwrap();
-                chain.write_all(&mut buf[..n]).unwrap();
-                chain.flush_chain();
-                if n > 1 || buf[0] != 3 {
-                    abort_cnt = 0;
-                } else {
-                    abort_cnt += 1;
-                }
-            } else {
-                println!("n = {}", n);
-            }
+/// Puts stdin in non-blocking mode for as long as it exists.
+struct NonBlockingStdin {
+    saved_flags: Option<i32>,
+}
 
-            if abort_cnt == 3 {
-                self.restore_term();
-            }
+impl NonBlockingStdin {
+    fn new() -> io::Result<Self> {
+        let flags = unsafe { libc::fcntl(0, libc::F_GETFL) };
+        if flags == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::fcntl(0, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NonBlockingStdin { saved_flags: Some(flags) })
+    }
 
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe { libc::read(0, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
         }
+    }
 
+    fn restore(&mut self) {
+        if let Some(flags) = self.saved_flags.take() {
+            unsafe { libc::fcntl(0, libc::F_SETFL, flags); }
+        }
     }
 }
 
-impl Drop for Terminal {
+impl Drop for NonBlockingStdin {
     fn drop(&mut self) {
-        self.restore_term();
+        self.restore();
+    }
+}
+
+const INPUT_BUFFER_SIZE: usize = 4096;
+
+enum EscapeCommand {
+    None,
+    Exit,
+    Detach,
+}
+
+/// Copies host stdin into the console receive queue.
+///
+/// Input is read from a non-blocking stdin only while there is room to
+/// deliver it. When the guest stops posting receive buffers, stdin is
+/// removed from the poll set until the guest notifies the queue again so
+/// that input is neither lost nor buffered without limit.
+struct ConsoleInput {
+    options: ConsoleOptions,
+    escape_pending: bool,
+    // Nothing has been typed since the last Enter
+    at_line_start: bool,
+    vq: VirtQueue,
+    control_rx: Option<VirtQueue>,
+    port: Arc<Mutex<PortState>>,
+    vm_control: Arc<VcpuControl>,
+    terminal: Terminal,
+    stdin: NonBlockingStdin,
+    kill_evt: EventFd,
+    stdin_token: u64,
+    stdin_enabled: bool,
+    pending: Vec<u8>,
+    pending_offset: usize,
+    abort_cnt: usize,
+}
+
+impl ConsoleInput {
+    fn new(vq: VirtQueue, control_rx: Option<VirtQueue>, port: Arc<Mutex<PortState>>, options: ConsoleOptions, vm_control: Arc<VcpuControl>, kill_evt: EventFd) -> io::Result<Self> {
+        Ok(ConsoleInput {
+            options,
+            escape_pending: false,
+            at_line_start: true,
+            vq,
+            control_rx,
+            port,
+            vm_control,
+            terminal: Terminal::create(),
+            stdin: NonBlockingStdin::new()?,
+            kill_evt,
+            stdin_token: 0,
+            stdin_enabled: false,
+            pending: Vec::with_capacity(INPUT_BUFFER_SIZE),
+            pending_offset: 0,
+            abort_cnt: 0,
+        })
+    }
+
+    fn run(mut self) {
+        self.terminal.setup_term();
+        let mut dispatcher = match PollDispatcher::new() {
+            Ok(dispatcher) => dispatcher,
+            Err(err) => {
+                self.vq.report_failure(&format_args!("unable to create epoll instance: {}", err));
+                return;
+            }
+        };
+        if let Err(err) = self.register(&mut dispatcher) {
+            self.vq.report_failure(&format_args!("unable to poll console input: {}", err));
+            return;
+        }
+        // The queue is stopped before VirtioSerial::stop() signals kill_evt
+        while !self.vq.is_stopped() {
+            if let Err(err) = dispatcher.dispatch(&mut self) {
+                self.vq.report_failure(&format_args!("error waiting for console input: {}", err));
+                return;
+            }
+        }
+    }
+
+    fn register(&mut self, dispatcher: &mut PollDispatcher<Self>) -> system::Result<()> {
+        dispatcher.register_read(self.vq.ioevent().as_raw_fd(), |input, poll, _| input.handle_queue(poll))?;
+        dispatcher.register_read(self.kill_evt.as_raw_fd(), |input, _, _| {
+            let _ = input.kill_evt.read();
+            PollAction::Continue
+        })?;
+        self.stdin_token = dispatcher.register_read(0, |input, poll, _| input.handle_stdin(poll))?;
+        self.stdin_enabled = true;
+        Ok(())
+    }
+
+    fn set_stdin_enabled(&mut self, poll: &EPoll, enabled: bool) {
+        if self.stdin_enabled == enabled || self.stdin_token == 0 {
+            return;
+        }
+        let events = if enabled { EPoll::READ } else { 0 };
+        match poll.modify(0, self.stdin_token, events, Trigger::Level) {
+            Ok(()) => self.stdin_enabled = enabled,
+            Err(err) => warn!("virtio_serial: error changing stdin poll events: {}", err),
+        }
+    }
+
+    fn handle_queue(&mut self, poll: &EPoll) -> PollAction {
+        if let Err(err) = self.vq.read_ioevent() {
+            self.vq.report_failure(&format_args!("error reading queue notification: {}", err));
+            return PollAction::Remove;
+        }
+        if self.deliver_pending() {
+            self.set_stdin_enabled(poll, true);
+        }
+        PollAction::Continue
+    }
+
+    fn handle_stdin(&mut self, poll: &EPoll) -> PollAction {
+        let mut buf = [0u8; INPUT_BUFFER_SIZE];
+        let n = match self.stdin.read(&mut buf) {
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return PollAction::Continue,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => return PollAction::Continue,
+            Err(err) => {
+                warn!("virtio_serial: error reading stdin: {}", err);
+                self.close_input();
+                return PollAction::Remove;
+            }
+        };
+        if n == 0 {
+            info!("virtio_serial: end of file on stdin, closing console input");
+            self.close_input();
+            return PollAction::Remove;
+        }
+        if self.options.ctrl_c == CtrlCPolicy::Restore {
+            self.update_abort_count(&buf[..n]);
+        }
+        match self.filter_escapes(&buf[..n]) {
+            EscapeCommand::Exit => {
+                self.terminal.restore_term();
+                self.stdin.restore();
+                notify!("Exiting on console escape");
+                self.vm_control.request_exit(VmExitReason::Requested);
+                return PollAction::Remove;
+            }
+            EscapeCommand::Detach => {
+                self.terminal.restore_term();
+                self.close_input();
+                return PollAction::Remove;
+            }
+            EscapeCommand::None => {},
+        }
+        if !self.deliver_pending() {
+            // Wait for the guest to make room before reading more input
+            self.set_stdin_enabled(poll, false);
+        }
+        PollAction::Continue
+    }
+
+    // Three consecutive Ctrl-C with no other input restores the host terminal
+    // settings so that the next Ctrl-C interrupts pH itself.
+    fn update_abort_count(&mut self, input: &[u8]) {
+        if input.len() == 1 && input[0] == 3 {
+            self.abort_cnt += 1;
+        } else {
+            self.abort_cnt = 0;
+        }
+        if self.abort_cnt == 3 {
+            self.terminal.restore_term();
+        }
+    }
+
+    /// Append `input` to the pending input, removing escape sequences. Returns
+    /// the command for the first complete escape sequence, input after it is
+    /// discarded.
+    fn filter_escapes(&mut self, input: &[u8]) -> EscapeCommand {
+        let escape = match self.options.escape {
+            Some(escape) => escape,
+            None => {
+                self.pending.extend_from_slice(input);
+                return EscapeCommand::None;
+            }
+        };
+        for &b in input {
+            if self.escape_pending {
+                self.escape_pending = false;
+                match b {
+                    b'.' => return EscapeCommand::Exit,
+                    b'd' => return EscapeCommand::Detach,
+                    b if b == escape => self.pending.push(escape),
+                    b => self.pending.extend_from_slice(&[escape, b]),
+                }
+            } else if b == escape && (self.at_line_start || !self.options.escape_at_line_start) {
+                self.escape_pending = true;
+            } else {
+                self.pending.push(b);
+            }
+            self.at_line_start = b == b'\r' || b == b'\n';
+        }
+        EscapeCommand::None
+    }
+
+    /// Copy as much pending input as possible into the receive queue. Returns
+    /// `true` if all pending input has been delivered.
+    fn deliver_pending(&mut self) -> bool {
+        while self.pending_offset < self.pending.len() {
+            let mut chain = match self.vq.next_chain() {
+                Some(chain) => chain,
+                None => return false,
+            };
+            let n = cmp::min(chain.remaining_write(), self.pending.len() - self.pending_offset);
+            let data = &self.pending[self.pending_offset..self.pending_offset + n];
+            if let Err(err) = chain.write_all(data) {
+                self.vq.report_failure(&format_args!("error writing console input: {}", err));
+                self.pending.clear();
+                self.pending_offset = 0;
+                return false;
+            }
+            chain.flush_chain();
+            self.pending_offset += n;
+        }
+        self.pending.clear();
+        self.pending_offset = 0;
+        true
+    }
+
+    /// Stdin has reached end of file. Tell the guest the port has been closed
+    /// if the port has already been announced, otherwise the control thread
+    /// reports it closed when the guest asks.
+    fn close_input(&mut self) {
+        let mut port = self.port.lock().unwrap();
+        port.input_closed = true;
+        if !port.ready {
+            return;
+        }
+        if let Some(rx) = self.control_rx.as_mut() {
+            if let Err(err) = Control::send_msg(rx, 0, VIRTIO_CONSOLE_PORT_OPEN, 0) {
+                if !rx.is_stopped() {
+                    warn!("virtio_serial: error sending port close message: {}", err);
+                }
+            }
+        }
     }
 }
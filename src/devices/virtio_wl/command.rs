@@ -0,0 +1,108 @@
+use std::io::Read;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::devices::virtio_wl::{consts::*, Error, Result};
+
+///
+/// A command sent by the guest on the virtio-wl out queue.
+///
+/// Decoding is kept separate from executing the command so that the parsing
+/// of guest controlled data can be exercised on plain byte buffers.
+///
+#[derive(Debug,Clone,PartialEq)]
+pub enum WlCommand {
+    NewAlloc { id: u32, flags: u32, size: u32 },
+    Close { id: u32 },
+    /// The data to send follows the vfd ids and is left unread in the source.
+    Send { id: u32, vfd_ids: Vec<u32> },
+    NewDmabuf { id: u32, width: u32, height: u32, format: u32 },
+    DmabufSync { id: u32, flags: u32 },
+    NewCtx { id: u32 },
+    /// An empty name selects the default socket.
+    NewCtxNamed { id: u32, name: String },
+    NewPipe { id: u32, flags: u32 },
+    Unknown(u32),
+}
+
+impl WlCommand {
+
+    /// Decode a single command from `r`. The dmabuf commands are reported as
+    /// `Unknown` unless `enable_dmabuf` is set.
+    pub fn decode<R: Read>(r: &mut R, enable_dmabuf: bool) -> Result<WlCommand> {
+        let msg_type = r32(r)?;
+        // Flags are always zero
+        let _flags = r32(r)?;
+
+        let cmd = match msg_type {
+            VIRTIO_WL_CMD_VFD_NEW => {
+                let id = r32(r)?;
+                let flags = r32(r)?;
+                let _pfn = r.read_u64::<LittleEndian>()?;
+                let size = r32(r)?;
+                WlCommand::NewAlloc { id, flags, size }
+            },
+            VIRTIO_WL_CMD_VFD_CLOSE => WlCommand::Close { id: r32(r)? },
+            VIRTIO_WL_CMD_VFD_SEND => {
+                let id = r32(r)?;
+                let vfd_ids = Self::decode_vfd_ids(r)?;
+                WlCommand::Send { id, vfd_ids }
+            },
+            VIRTIO_WL_CMD_VFD_NEW_DMABUF if enable_dmabuf => {
+                let id = r32(r)?;
+                let _flags = r32(r)?;
+                let _pfn = r.read_u64::<LittleEndian>()?;
+                let _size = r32(r)?;
+                let width = r32(r)?;
+                let height = r32(r)?;
+                let format = r32(r)?;
+                WlCommand::NewDmabuf { id, width, height, format }
+            },
+            VIRTIO_WL_CMD_VFD_DMABUF_SYNC if enable_dmabuf => {
+                let id = r32(r)?;
+                let flags = r32(r)?;
+                WlCommand::DmabufSync { id, flags }
+            },
+            VIRTIO_WL_CMD_VFD_NEW_CTX => WlCommand::NewCtx { id: r32(r)? },
+            VIRTIO_WL_CMD_VFD_NEW_CTX_NAMED => {
+                let id = r32(r)?;
+                let _flags = r32(r)?;
+                let _pfn = r.read_u64::<LittleEndian>()?;
+                let _size = r32(r)?;
+                let name = Self::decode_name(r)?;
+                WlCommand::NewCtxNamed { id, name }
+            },
+            VIRTIO_WL_CMD_VFD_NEW_PIPE => {
+                let id = r32(r)?;
+                let flags = r32(r)?;
+                WlCommand::NewPipe { id, flags }
+            },
+            v => WlCommand::Unknown(v),
+        };
+        Ok(cmd)
+    }
+
+    fn decode_vfd_ids<R: Read>(r: &mut R) -> Result<Vec<u32>> {
+        let vfd_count = r32(r)? as usize;
+        if vfd_count > VIRTWL_SEND_MAX_ALLOCS {
+            return Err(Error::TooManySendVfds(vfd_count))
+        }
+        let mut vfd_ids = Vec::with_capacity(vfd_count);
+        for _ in 0..vfd_count {
+            vfd_ids.push(r32(r)?);
+        }
+        Ok(vfd_ids)
+    }
+
+    // Socket names are a fixed size field padded with nul bytes
+    fn decode_name<R: Read>(r: &mut R) -> Result<String> {
+        let mut buf = [0u8; WL_SOCKET_NAME_LEN];
+        r.read_exact(&mut buf)?;
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+}
+
+fn r32<R: Read>(r: &mut R) -> Result<u32> {
+    Ok(r.read_u32::<LittleEndian>()?)
+}
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+///
+/// Changes the compositor socket of a running wayland device, for example
+/// after the desktop session was restarted and the new compositor listens on
+/// a different socket.
+///
+/// Connections the guest already has are left as they are and hang up when
+/// the old compositor exits. Every connection the guest makes afterwards goes
+/// to the socket set with `set_socket_path()`.
+///
+/// Guests may also ask for a socket by name. Names other than the default
+/// `wayland-0` are mapped to host sockets with `add_named_socket()`.
+///
+pub struct WaylandControl {
+    socket_path: Mutex<PathBuf>,
+    named_sockets: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl WaylandControl {
+    pub fn new<P: Into<PathBuf>>(socket_path: P) -> Self {
+        WaylandControl {
+            socket_path: Mutex::new(socket_path.into()),
+            named_sockets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn socket_path(&self) -> PathBuf {
+        self.socket_path.lock().unwrap().clone()
+    }
+
+    pub fn set_socket_path<P: Into<PathBuf>>(&self, path: P) {
+        *self.socket_path.lock().unwrap() = path.into();
+    }
+
+    /// Connect the guest to the socket at `path` when it asks for `name`.
+    pub fn add_named_socket<P: Into<PathBuf>>(&self, name: &str, path: P) {
+        self.named_sockets.lock().unwrap().insert(name.to_string(), path.into());
+    }
+
+    pub fn named_socket_path(&self, name: &str) -> Option<PathBuf> {
+        self.named_sockets.lock().unwrap().get(name).cloned()
+    }
+}
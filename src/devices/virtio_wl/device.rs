@@ -1,37 +1,43 @@
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::thread;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::JoinHandle;
 
 use crate::system;
 use crate::system::EPoll;
-use crate::system::drm::DrmDescriptor;
+use crate::system::drm::{self, DrmDescriptor};
 
-use crate::devices::virtio_wl::{vfd::VfdManager, consts::*, Error, Result, VfdObject};
-use crate::system::ioctl::ioctl_with_ref;
-use std::os::raw::{c_ulong, c_uint, c_ulonglong};
+use crate::devices::virtio_wl::{vfd::VfdManager, consts::*, Error, Result, VfdObject, WaylandControl};
+use crate::devices::virtio_wl::command::WlCommand;
+use crate::devices::virtio_wl::stats::VfdStats;
 use vmm_sys_util::eventfd::EventFd;
 use crate::io::{Chain, FeatureBits, Queues, VirtioDevice, VirtioDeviceType, VirtQueue};
 use crate::io::shm_mapper::DeviceSharedMemoryManager;
-
-#[repr(C)]
-struct dma_buf_sync {
-    flags: c_ulonglong,
-}
-const DMA_BUF_IOCTL_BASE: c_uint = 0x62;
-const DMA_BUF_IOCTL_SYNC: c_ulong = iow!(DMA_BUF_IOCTL_BASE, 0, ::std::mem::size_of::<dma_buf_sync>() as i32);
+use crate::util::JsonValue;
 
 pub struct VirtioWayland {
-    dev_shm_manager: Option<DeviceSharedMemoryManager>,
+    dev_shm_manager: DeviceSharedMemoryManager,
     features: FeatureBits,
     enable_dmabuf: bool,
+    max_vfds: Option<usize>,
+    control: Arc<WaylandControl>,
+    vfd_stats: Option<Arc<VfdStats>>,
+    worker: Option<(EventFd, JoinHandle<()>)>,
 }
 
 impl VirtioWayland {
-    pub fn new(enable_dmabuf: bool , dev_shm_manager: DeviceSharedMemoryManager) -> Self {
+    /// Create a wayland device which connects the guest to the compositor
+    /// listening on `socket_path`.
+    pub fn new<P: Into<PathBuf>>(enable_dmabuf: bool, max_vfds: Option<usize>, socket_path: P, dev_shm_manager: DeviceSharedMemoryManager) -> Self {
         let features = FeatureBits::new_default(VIRTIO_WL_F_TRANS_FLAGS as u64);
         VirtioWayland {
-            dev_shm_manager: Some(dev_shm_manager),
+            dev_shm_manager,
             features,
-            enable_dmabuf
+            enable_dmabuf,
+            max_vfds,
+            control: Arc::new(WaylandControl::new(socket_path)),
+            vfd_stats: None,
+            worker: None,
         }
     }
 
@@ -39,9 +45,19 @@ impl VirtioWayland {
         self.features.has_guest_bit(VIRTIO_WL_F_TRANS_FLAGS as u64)
     }
 
-    fn create_device(in_vq: VirtQueue, out_vq: VirtQueue, transition: bool, enable_dmabuf: bool, dev_shm_manager: DeviceSharedMemoryManager) -> Result<WaylandDevice> {
+    /// Control for changing the compositor socket while the device runs.
+    pub fn wayland_control(&self) -> Arc<WaylandControl> {
+        self.control.clone()
+    }
+
+    fn create_kill_evt() -> Result<(EventFd, EventFd)> {
         let kill_evt = EventFd::new(0).map_err(Error::EventFdCreate)?;
-        let dev = WaylandDevice::new(in_vq, out_vq, kill_evt, transition, enable_dmabuf, dev_shm_manager)?;
+        let worker_evt = kill_evt.try_clone().map_err(Error::EventFdCreate)?;
+        Ok((kill_evt, worker_evt))
+    }
+
+    fn create_device(in_vq: VirtQueue, out_vq: VirtQueue, kill_evt: EventFd, transition: bool, enable_dmabuf: bool, dev_shm_manager: DeviceSharedMemoryManager, control: Arc<WaylandControl>, max_vfds: Option<usize>, stats: Arc<VfdStats>) -> Result<WaylandDevice> {
+        let dev = WaylandDevice::new(in_vq, out_vq, kill_evt, transition, enable_dmabuf, dev_shm_manager, control, max_vfds, stats)?;
         Ok(dev)
     }
 }
@@ -60,25 +76,58 @@ impl VirtioDevice for VirtioWayland {
     }
 
     fn start(&mut self, queues: &Queues) {
-        thread::spawn({
+        let (kill_evt, worker_evt) = match Self::create_kill_evt() {
+            Ok(evts) => evts,
+            Err(e) => {
+                warn!("Error creating virtio wayland device: {}", e);
+                return;
+            }
+        };
+        let stats = Arc::new(VfdStats::register(queues.device_stats()));
+        self.vfd_stats = Some(stats.clone());
+        let handle = queues.spawn_worker({
             let transition = self.transition_flags();
+            let max_vfds = self.max_vfds;
             let enable_dmabuf = self.enable_dmabuf;
-            let dev_shm_manager = self.dev_shm_manager.take().expect("No dev_shm_manager");
+            let dev_shm_manager = self.dev_shm_manager.clone();
+            let control = self.control.clone();
             let in_vq = queues.get_queue(0);
             let out_vq = queues.get_queue(1);
             move || {
-                let mut dev = match Self::create_device(in_vq, out_vq,transition, enable_dmabuf, dev_shm_manager) {
+                let mut dev = match Self::create_device(in_vq.clone(), out_vq, worker_evt, transition, enable_dmabuf, dev_shm_manager, control, max_vfds, stats) {
                     Err(e) => {
-                        warn!("Error creating virtio wayland device: {}", e);
+                        in_vq.report_failure(&format_args!("error creating device: {}", e));
                         return;
                     }
                     Ok(dev) => dev,
                 };
                 if let Err(e) = dev.run() {
-                    warn!("Error running virtio-wl device: {}", e);
+                    dev.out_vq.report_failure(&e);
                 };
+                dev.vfd_manager.close_all();
             }
         });
+        self.worker = handle.map(|handle| (kill_evt, handle));
+    }
+
+    fn stop(&mut self) {
+        if let Some((kill_evt, worker)) = self.worker.take() {
+            if let Err(e) = kill_evt.write(1) {
+                warn!("virtio_wl: failed to signal worker to stop: {}", e);
+                return;
+            }
+            if worker.join().is_err() {
+                warn!("virtio wayland worker thread panicked");
+            }
+        }
+    }
+
+    fn describe(&self) -> Option<JsonValue> {
+        Some(JsonValue::object()
+            .field("dmabuf", self.enable_dmabuf)
+            .field("socket", self.control.socket_path().display().to_string())
+            .field("max_vfds", self.max_vfds)
+            .field("vfds", self.vfd_stats.as_ref().map(|stats| stats.to_json())))
     }
 }
 
@@ -95,8 +144,8 @@ impl WaylandDevice {
     const KILL_TOKEN: u64 = 2;
     const VFDS_TOKEN: u64 = 3;
 
-    fn new(in_vq: VirtQueue, out_vq: VirtQueue, kill_evt: EventFd, use_transition: bool, enable_dmabuf: bool, dev_shm_manager: DeviceSharedMemoryManager) -> Result<Self> {
-        let vfd_manager = VfdManager::new(dev_shm_manager, use_transition, in_vq, "/run/user/1000/wayland-0")?;
+    fn new(in_vq: VirtQueue, out_vq: VirtQueue, kill_evt: EventFd, use_transition: bool, enable_dmabuf: bool, dev_shm_manager: DeviceSharedMemoryManager, control: Arc<WaylandControl>, max_vfds: Option<usize>, stats: Arc<VfdStats>) -> Result<Self> {
+        let vfd_manager = VfdManager::new(dev_shm_manager, use_transition, in_vq, control, max_vfds, stats)?;
 
         Ok(WaylandDevice {
             vfd_manager,
@@ -139,14 +188,14 @@ impl WaylandDevice {
                         self.vfd_manager.in_vq_ready()?;
                     },
                     Self::OUT_VQ_TOKEN => {
-                        self.out_vq.ioevent().read().map_err(Error::IoEventError)?;
+                        self.out_vq.read_ioevent().map_err(Error::IoEventError)?;
                         if let Some(chain) = self.out_vq.next_chain() {
                             let mut handler = MessageHandler::new(self, chain, self.enable_dmabuf);
                             match handler.run() {
                                 Ok(()) => {
                                 },
                                 Err(err) => {
-                                    warn!("virtio_wl: error handling request: {}", err);
+                                    warn_limited!("virtio_wl: error handling request: {}", err);
                                     if !handler.responded {
                                         let _ = handler.send_err();
                                     }
@@ -179,19 +228,16 @@ impl <'a> MessageHandler<'a> {
     }
 
     fn run(&mut self) -> Result<()> {
-        let msg_type = self.chain.r32()?;
-        // Flags are always zero
-        let _flags = self.chain.r32()?;
-
-        match msg_type {
-            VIRTIO_WL_CMD_VFD_NEW => self.cmd_new_alloc(),
-            VIRTIO_WL_CMD_VFD_CLOSE => self.cmd_close(),
-            VIRTIO_WL_CMD_VFD_SEND => self.cmd_send(),
-            VIRTIO_WL_CMD_VFD_NEW_DMABUF  if self.enable_dmabuf => self.cmd_new_dmabuf(),
-            VIRTIO_WL_CMD_VFD_DMABUF_SYNC if self.enable_dmabuf => self.cmd_dmabuf_sync(),
-            VIRTIO_WL_CMD_VFD_NEW_CTX => self.cmd_new_ctx(),
-            VIRTIO_WL_CMD_VFD_NEW_PIPE => self.cmd_new_pipe(),
-            v => {
+        match WlCommand::decode(&mut self.chain, self.enable_dmabuf)? {
+            WlCommand::NewAlloc { id, flags, size } => self.cmd_new_alloc(id, flags, size),
+            WlCommand::Close { id } => self.cmd_close(id),
+            WlCommand::Send { id, vfd_ids } => self.cmd_send(id, &vfd_ids),
+            WlCommand::NewDmabuf { id, width, height, format } => self.cmd_new_dmabuf(id, width, height, format),
+            WlCommand::DmabufSync { id, flags } => self.cmd_dmabuf_sync(id, flags),
+            WlCommand::NewCtx { id } => self.cmd_new_ctx(id),
+            WlCommand::NewCtxNamed { id, name } => self.cmd_new_ctx_named(id, &name),
+            WlCommand::NewPipe { id, flags } => self.cmd_new_pipe(id, flags),
+            WlCommand::Unknown(v) => {
                 self.send_invalid_command()?;
                 if v == VIRTIO_WL_CMD_VFD_NEW_DMABUF && !self.enable_dmabuf {
                     // Sommelier probes this command to determine if dmabuf is supported
@@ -204,15 +250,10 @@ impl <'a> MessageHandler<'a> {
         }
     }
 
-    fn cmd_new_alloc(&mut self) -> Result<()> {
-        let id = self.chain.r32()?;
-        let flags = self.chain.r32()?;
-        let _pfn = self.chain.r64()?;
-        let size = self.chain.r32()?;
-
+    fn cmd_new_alloc(&mut self, id: u32, flags: u32, size: u32) -> Result<()> {
         match self.device.vfd_manager.create_shm(id, size) {
             Ok((pfn,size)) => self.resp_vfd_new(id, flags, pfn, size as u32),
-            Err(Error::ShmAllocFailed(_)) => self.send_simple_resp(VIRTIO_WL_RESP_OUT_OF_MEMORY),
+            Err(Error::ShmAllocFailed(_)) | Err(Error::TooManyVfds(_)) => self.send_simple_resp(VIRTIO_WL_RESP_OUT_OF_MEMORY),
             Err(e) => Err(e),
         }
     }
@@ -228,15 +269,7 @@ impl <'a> MessageHandler<'a> {
         Ok(())
     }
 
-    fn cmd_new_dmabuf(&mut self) -> Result<()> {
-        let id = self.chain.r32()?;
-        let _flags = self.chain.r32()?;
-        let _pfn = self.chain.r64()?;
-        let _size = self.chain.r32()?;
-        let width = self.chain.r32()?;
-        let height = self.chain.r32()?;
-        let format = self.chain.r32()?;
-
+    fn cmd_new_dmabuf(&mut self, id: u32, width: u32, height: u32, format: u32) -> Result<()> {
         match self.device.vfd_manager.create_dmabuf(id, width,height, format) {
             Ok((pfn, size, desc)) => self.resp_dmabuf_new(id, pfn, size as u32, desc),
             Err(e) => {
@@ -269,10 +302,7 @@ impl <'a> MessageHandler<'a> {
         Ok(())
     }
 
-    fn cmd_dmabuf_sync(&mut self) -> Result<()> {
-        let id = self.chain.r32()?;
-        let flags = self.chain.r32()?;
-
+    fn cmd_dmabuf_sync(&mut self, id: u32, flags: u32) -> Result<()> {
         let vfd = match self.device.get_mut_vfd(id) {
             Some(vfd) => vfd,
             None => return self.send_invalid_id(),
@@ -282,53 +312,51 @@ impl <'a> MessageHandler<'a> {
             None => return self.send_invalid_id(),
         };
 
-        unsafe {
-            let sync = dma_buf_sync {
-                flags: flags as u64,
-            };
-            ioctl_with_ref(fd, DMA_BUF_IOCTL_SYNC, &sync).map_err(Error::DmaSync)?;
-        }
+        drm::dmabuf_sync(fd, flags as u64).map_err(Error::DmaSync)?;
 
         self.send_ok()
     }
 
-    fn cmd_close(&mut self) -> Result<()> {
-        let id = self.chain.r32()?;
+    fn cmd_close(&mut self, id: u32) -> Result<()> {
         self.device.vfd_manager.close_vfd(id)?;
         self.send_ok()
     }
 
-    fn cmd_send(&mut self) -> Result<()> {
-        let id = self.chain.r32()?;
-
-        let send_fds = self.read_vfd_ids()?;
-        let data = self.chain.current_read_slice();
+    fn cmd_send(&mut self, id: u32, vfd_ids: &[u32]) -> Result<()> {
+        let send_fds = self.send_fds(vfd_ids)?;
 
         let vfd = match self.device.get_mut_vfd(id) {
             Some(vfd) => vfd,
             None => return self.send_invalid_id(),
         };
 
-        if let Some(fds) = send_fds.as_ref() {
-            vfd.send_with_fds(&data, fds)?;
-        } else {
-            vfd.send(&data)?;
-        }
+        let len = {
+            let data = self.chain.readable_slices()?;
+            let sent = if let Some(fds) = send_fds.as_ref() {
+                vfd.send_with_fds(&data, fds)
+            } else {
+                vfd.send(&data)
+            };
+            match sent {
+                Ok(()) => {},
+                Err(Error::PipeSendBufferFull(..)) => return self.send_simple_resp(VIRTIO_WL_RESP_OUT_OF_MEMORY),
+                Err(e) => return Err(e),
+            }
+            data.iter().map(|slice| slice.len()).sum()
+        };
+        self.chain.inc_read_offset(len);
+        self.device.vfd_manager.stats().sent(id, len);
+        self.device.vfd_manager.update_write_interest(id)?;
         self.send_ok()
     }
 
-    fn read_vfd_ids(&mut self) -> Result<Option<Vec<RawFd>>> {
-        let vfd_count = self.chain.r32()? as usize;
-        if vfd_count > VIRTWL_SEND_MAX_ALLOCS {
-            return Err(Error::TooManySendVfds(vfd_count))
-        }
-        if vfd_count == 0 {
+    fn send_fds(&mut self, vfd_ids: &[u32]) -> Result<Option<Vec<RawFd>>> {
+        if vfd_ids.is_empty() {
             return Ok(None);
         }
 
-        let mut raw_fds = Vec::with_capacity(vfd_count);
-        for _ in 0..vfd_count {
-            let vfd_id = self.chain.r32()?;
+        let mut raw_fds = Vec::with_capacity(vfd_ids.len());
+        for &vfd_id in vfd_ids {
             if let Some(fd) = self.vfd_id_to_raw_fd(vfd_id)? {
                 raw_fds.push(fd);
             }
@@ -340,7 +368,7 @@ impl <'a> MessageHandler<'a> {
         let vfd = match self.device.get_vfd(vfd_id) {
             Some(vfd) => vfd,
             None => {
-                warn!("virtio_wl: Received unexpected vfd id 0x{:08x}", vfd_id);
+                warn_limited!("virtio_wl: Received unexpected vfd id 0x{:08x}", vfd_id);
                 return Ok(None);
             }
         };
@@ -353,8 +381,7 @@ impl <'a> MessageHandler<'a> {
         }
     }
 
-    fn cmd_new_ctx(&mut self) -> Result<()> {
-        let id = self.chain.r32()?;
+    fn cmd_new_ctx(&mut self, id: u32) -> Result<()> {
         if !Self::is_valid_id(id) {
             return self.send_invalid_id();
         }
@@ -363,10 +390,21 @@ impl <'a> MessageHandler<'a> {
         Ok(())
     }
 
-    fn cmd_new_pipe(&mut self) -> Result<()> {
-        let id = self.chain.r32()?;
-        let flags = self.chain.r32()?;
+    fn cmd_new_ctx_named(&mut self, id: u32, name: &str) -> Result<()> {
+        if !Self::is_valid_id(id) {
+            return self.send_invalid_id();
+        }
+        match self.device.vfd_manager.create_named_socket(id, name) {
+            Ok(flags) => self.resp_vfd_new(id, flags, 0, 0),
+            Err(Error::UnknownSocketName(name)) => {
+                warn_limited!("virtio_wl: Guest requested unknown wayland socket '{}'", name);
+                self.send_err()
+            }
+            Err(e) => Err(e),
+        }
+    }
 
+    fn cmd_new_pipe(&mut self, id: u32, flags: u32) -> Result<()> {
         if !Self::is_valid_id(id) {
             return self.send_invalid_id();
         }
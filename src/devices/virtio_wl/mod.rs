@@ -12,10 +12,11 @@ mod shm;
 mod pipe;
 mod socket;
 mod device;
+mod command;
+mod stats;
+mod control;
 
 mod consts {
-    use std::mem;
-
     pub const VIRTWL_SEND_MAX_ALLOCS: usize = 28;
     pub const VIRTIO_WL_CMD_VFD_NEW: u32 = 256;
     pub const VIRTIO_WL_CMD_VFD_CLOSE: u32 = 257;
@@ -26,6 +27,7 @@ mod consts {
     pub const VIRTIO_WL_CMD_VFD_HUP: u32 = 262;
     pub const VIRTIO_WL_CMD_VFD_NEW_DMABUF: u32 = 263;
     pub const VIRTIO_WL_CMD_VFD_DMABUF_SYNC: u32 = 264;
+    pub const VIRTIO_WL_CMD_VFD_NEW_CTX_NAMED: u32 = 265;
     pub const VIRTIO_WL_RESP_OK: u32 = 4096;
     pub const VIRTIO_WL_RESP_VFD_NEW: u32 = 4097;
     pub const VIRTIO_WL_RESP_VFD_NEW_DMABUF: u32 = 4098;
@@ -43,16 +45,45 @@ mod consts {
     pub const VIRTIO_WL_VFD_CONTROL: u32 = 0x4;
     pub const VIRTIO_WL_F_TRANS_FLAGS: u32 = 0x01;
 
+    // Upper bound on the number of vfds open at once, the actual limit also
+    // depends on the open file limit of the process.
+    pub const MAX_VFD_COUNT: usize = 4096;
+
+    // Percentage of the vfd limit in use at which a warning is logged
+    pub const VFD_WARN_PERCENT: usize = 80;
+
     pub const NEXT_VFD_ID_BASE: u32 = 0x40000000;
     pub const VFD_ID_HOST_MASK: u32 = NEXT_VFD_ID_BASE;
 
+    pub const WL_SOCKET_NAME_LEN: usize = 32;
+    pub const DEFAULT_SOCKET_NAME: &str = "wayland-0";
+
     pub const VFD_RECV_HDR_SIZE: usize = 16;
-    pub const IN_BUFFER_LEN: usize =
-        0x1000 - VFD_RECV_HDR_SIZE - VIRTWL_SEND_MAX_ALLOCS * mem::size_of::<u32>();
+
+    // Set in the header flags of a VFD_RECV message when the data received
+    // from the vfd did not fit in one in-queue buffer and continues in the
+    // next VFD_RECV message for the same vfd.
+    pub const VIRTIO_WL_RECV_FLAG_MORE: u32 = 0x1;
+
+    // Maximum amount of data read from a vfd at once. Data which does not fit
+    // in a single in-queue buffer is split across several VFD_RECV messages.
+    pub const RECV_BUFFER_LEN: usize = 0x10000;
 }
 
 pub use device::VirtioWayland;
+pub use control::WaylandControl;
+
+/// Fuzzing entry point for virtio-wl command decoding. The first byte of
+/// `data` selects whether dmabuf commands are enabled and the remainder is
+/// decoded as a command message.
+#[cfg(feature = "fuzzing")]
+pub fn fuzz_command(data: &[u8]) {
+    if let Some((&first, mut msg)) = data.split_first() {
+        let _ = command::WlCommand::decode(&mut msg, first & 1 != 0);
+    }
+}
 use crate::devices::virtio_wl::shm_mapper::SharedMemoryAllocation;
+use crate::devices::virtio_wl::stats::VfdType;
 use crate::io::shm_mapper;
 
 pub type Result<T> = result::Result<T, Error>;
@@ -71,13 +102,35 @@ impl VfdRecv {
     }
 }
 
+/// Data received straight into guest memory by `VfdObject::recv_into()`
+pub struct VfdRecvInto {
+    len: usize,
+    fds: Vec<File>,
+}
+
+impl VfdRecvInto {
+    fn new(len: usize, fds: Vec<File>) -> Self {
+        VfdRecvInto { len, fds }
+    }
+}
+
 pub trait VfdObject {
     fn id(&self) -> u32;
+    fn vfd_type(&self) -> VfdType;
     fn send_fd(&self) -> Option<RawFd> { None }
     fn poll_fd(&self) -> Option<RawFd> { None }
     fn recv(&mut self) -> Result<Option<VfdRecv>> { Ok(None) }
-    fn send(&mut self, _data: &VolatileSlice) -> Result<()> { Err(Error::InvalidSendVfd) }
-    fn send_with_fds(&mut self, _data: &VolatileSlice, _fds: &[RawFd]) -> Result<()> { Err(Error::InvalidSendVfd) }
+    /// True if `recv_into()` can be used instead of `recv()`
+    fn can_recv_into(&self) -> bool { false }
+    /// Like `recv()` but the data is placed in `bufs` rather than a new buffer.
+    fn recv_into(&mut self, _bufs: &[VolatileSlice]) -> Result<Option<VfdRecvInto>> { Ok(None) }
+    /// Send `data`, which may be spread across several descriptors of the chain.
+    fn send(&mut self, _data: &[VolatileSlice]) -> Result<()> { Err(Error::InvalidSendVfd) }
+    fn send_with_fds(&mut self, _data: &[VolatileSlice], _fds: &[RawFd]) -> Result<()> { Err(Error::InvalidSendVfd) }
+    /// True while data accepted by `send()` is waiting for `poll_fd()` to become writable
+    fn has_pending_writes(&self) -> bool { false }
+    /// Write as much of the waiting data as possible without blocking
+    fn flush_writes(&mut self) -> Result<()> { Ok(()) }
     fn flags(&self) -> u32;
     fn shared_memory(&self) -> Option<SharedMemoryAllocation> { None }
     fn close(&mut self) -> Result<()> { Ok(()) }
@@ -106,10 +159,14 @@ pub enum Error {
     SocketConnect(io::Error),
     #[error("error reading from pipe: {0}")]
     PipeReceive(io::Error),
+    #[error("error reading volatile memory from vfd: {0}")]
+    VolatileRecvVfd(VolatileMemoryError),
     #[error("error writing to vfd: {0}")]
     SendVfd(io::Error),
     #[error("error writing volatile memory to vfd: {0}")]
     VolatileSendVfd(VolatileMemoryError),
+    #[error("{1} bytes sent to pipe vfd 0x{0:08x} are still waiting to be read")]
+    PipeSendBufferFull(u32, usize),
     #[error("attempt to send to incorrect vfd type")]
     InvalidSendVfd,
     #[error("message has too many vfd ids: {0}")]
@@ -118,6 +175,16 @@ pub enum Error {
     FailedPollContextCreate(system::Error),
     #[error("failed adding fd to poll context: {0}")]
     FailedPollAdd(system::Error),
+    #[error("failed changing poll events of fd: {0}")]
+    FailedPollModify(system::Error),
+    #[error("too many open vfds ({0})")]
+    TooManyVfds(usize),
+    #[error("new vfd 0x{0:08x} has no {1}")]
+    IncompleteVfd(u32, &'static str),
+    #[error("no wayland socket configured with name: {0}")]
+    UnknownSocketName(String),
     #[error("error calling dma sync: {0}")]
     DmaSync(system::ErrnoError),
+    #[error("in-queue buffer too small for received message ({0} bytes)")]
+    InBufferTooSmall(usize),
 }
@@ -1,36 +1,45 @@
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read, Write};
 use std::os::fd::FromRawFd;
 use std::os::unix::io::{AsRawFd, RawFd};
-use vm_memory::{VolatileSlice, WriteVolatile};
+use vm_memory::{ReadVolatile, VolatileSlice};
 
-use crate::system;
+use crate::system::{self, fd_audit};
 
 use crate::devices::virtio_wl::{
-    consts::{VIRTIO_WL_VFD_WRITE, VIRTIO_WL_VFD_READ, IN_BUFFER_LEN},
-    Error, Result, VfdObject, VfdRecv,
+    consts::{VIRTIO_WL_VFD_WRITE, VIRTIO_WL_VFD_READ, RECV_BUFFER_LEN},
+    Error, Result, VfdObject, VfdRecv, VfdRecvInto, stats::VfdType,
 };
 
+// Most data sent by the guest that may be waiting for a pipe to be read
+const MAX_PENDING_WRITE: usize = 4 * 1024 * 1024;
 
 pub struct VfdPipe {
     vfd_id: u32,
     flags: u32,
     local: Option<File>,
     remote: Option<File>,
+    // Data sent by the guest which the pipe did not have room for yet
+    pending: VecDeque<u8>,
 }
 
 impl VfdPipe {
 
     pub fn new(vfd_id: u32, read_pipe: File, write_pipe: File, local_write: bool) -> Self {
         if local_write {
-            VfdPipe { vfd_id, local: Some(write_pipe), remote: Some(read_pipe), flags: VIRTIO_WL_VFD_WRITE }
+            set_nonblocking(&write_pipe);
+            VfdPipe { vfd_id, local: Some(write_pipe), remote: Some(read_pipe), flags: VIRTIO_WL_VFD_WRITE, pending: VecDeque::new() }
         } else {
-            VfdPipe { vfd_id, local: Some(read_pipe), remote: Some(write_pipe), flags: VIRTIO_WL_VFD_READ}
+            VfdPipe { vfd_id, local: Some(read_pipe), remote: Some(write_pipe), flags: VIRTIO_WL_VFD_READ, pending: VecDeque::new() }
         }
     }
 
     pub fn local_only(vfd_id: u32, local_pipe: File, flags: u32) -> Self {
-        VfdPipe { vfd_id, local: Some(local_pipe), remote: None, flags }
+        if flags & VIRTIO_WL_VFD_WRITE != 0 {
+            set_nonblocking(&local_pipe);
+        }
+        VfdPipe { vfd_id, local: Some(local_pipe), remote: None, flags, pending: VecDeque::new() }
     }
 
     pub fn create(vfd_id: u32, local_write: bool) -> Result<Self> {
@@ -39,6 +48,8 @@ impl VfdPipe {
             if libc::pipe2(pipe_fds.as_mut_ptr(), libc::O_CLOEXEC) < 0 {
                 return Err(Error::CreatePipesFailed(system::Error::last_os_error()));
             }
+            fd_audit::register(pipe_fds[0], "virtio-wl pipe");
+            fd_audit::register(pipe_fds[1], "virtio-wl pipe");
             let read_pipe = File::from_raw_fd(pipe_fds[0]);
             let write_pipe = File::from_raw_fd(pipe_fds[1]);
             Ok(Self::new(vfd_id, read_pipe, write_pipe, local_write))
@@ -46,11 +57,29 @@ impl VfdPipe {
     }
 }
 
+// A write to a full pipe must not stall the device thread until the reader
+// catches up. With O_NONBLOCK the data which does not fit is kept in
+// `pending` and written when the pipe polls as writable.
+fn set_nonblocking(pipe: &File) {
+    let fd = pipe.as_raw_fd();
+    let ok = unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        flags >= 0 && libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) >= 0
+    };
+    if !ok {
+        warn!("virtio_wl: failed to set O_NONBLOCK on pipe: {}", io::Error::last_os_error());
+    }
+}
+
 impl VfdObject for VfdPipe {
     fn id(&self) -> u32 {
         self.vfd_id
     }
 
+    fn vfd_type(&self) -> VfdType {
+        VfdType::Pipe
+    }
+
     fn send_fd(&self) -> Option<RawFd> {
         self.remote.as_ref().map(|p| p.as_raw_fd())
     }
@@ -61,10 +90,11 @@ impl VfdObject for VfdPipe {
 
     fn recv(&mut self) -> Result<Option<VfdRecv>> {
         if let Some(pipe) = self.local.take() {
-            let mut buf = vec![0; IN_BUFFER_LEN];
-            let len = (&pipe).read(&mut buf[..IN_BUFFER_LEN])
+            let mut buf = vec![0; RECV_BUFFER_LEN];
+            let len = (&pipe).read(&mut buf)
                 .map_err(Error::PipeReceive)?;
             buf.truncate(len);
+            buf.shrink_to_fit();
             if buf.len() > 0 {
                 self.local.replace(pipe);
                 return Ok(Some(VfdRecv::new(buf)));
@@ -73,12 +103,68 @@ impl VfdObject for VfdPipe {
         Ok(None)
     }
 
-    fn send(&mut self, data: &VolatileSlice) -> Result<()> {
-        if let Some(pipe) = self.local.as_mut() {
-            pipe.write_all_volatile(data).map_err(Error::VolatileSendVfd)
-        } else {
-            Err(Error::InvalidSendVfd)
+    fn can_recv_into(&self) -> bool {
+        self.local.is_some()
+    }
+
+    // A single read as in recv(), so only into the first buffer
+    fn recv_into(&mut self, bufs: &[VolatileSlice]) -> Result<Option<VfdRecvInto>> {
+        let mut buf = match bufs.first() {
+            Some(buf) => buf.clone(),
+            None => return Err(Error::InBufferTooSmall(0)),
+        };
+        if let Some(mut pipe) = self.local.take() {
+            let len = pipe.read_volatile(&mut buf)
+                .map_err(Error::VolatileRecvVfd)?;
+            if len > 0 {
+                self.local.replace(pipe);
+                return Ok(Some(VfdRecvInto::new(len, Vec::new())));
+            }
+        }
+        Ok(None)
+    }
+
+    fn send(&mut self, data: &[VolatileSlice]) -> Result<()> {
+        if self.local.is_none() {
+            return Err(Error::InvalidSendVfd);
+        }
+        let len: usize = data.iter().map(|slice| slice.len()).sum();
+        if self.pending.len() + len > MAX_PENDING_WRITE {
+            return Err(Error::PipeSendBufferFull(self.vfd_id, self.pending.len()));
+        }
+        for slice in data {
+            let mut buf = vec![0u8; slice.len()];
+            slice.copy_to(&mut buf[..]);
+            self.pending.extend(buf.iter());
+        }
+        self.flush_writes()
+    }
+
+    fn has_pending_writes(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn flush_writes(&mut self) -> Result<()> {
+        let mut pipe = match self.local.as_ref() {
+            Some(pipe) => pipe,
+            None => return Ok(()),
+        };
+        while !self.pending.is_empty() {
+            let (front, _) = self.pending.as_slices();
+            match pipe.write(front) {
+                Ok(n) => {
+                    self.pending.drain(..n);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => {
+                    // Nothing more can be written once the reader is gone
+                    self.pending.clear();
+                    return Err(Error::SendVfd(e));
+                },
+            }
         }
+        Ok(())
     }
 
     fn flags(&self) -> u32 {
@@ -88,6 +174,7 @@ impl VfdObject for VfdPipe {
     fn close(&mut self) -> Result<()> {
         self.local = None;
         self.remote = None;
+        self.pending.clear();
         Ok(())
     }
 }
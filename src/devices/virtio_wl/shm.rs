@@ -2,7 +2,7 @@ use std::os::unix::io::RawFd;
 
 use crate::devices::virtio_wl::{
     consts::{VIRTIO_WL_VFD_MAP, VIRTIO_WL_VFD_WRITE},
-    Error, Result, VfdObject
+    Error, Result, VfdObject, stats::VfdType,
 };
 use crate::io::shm_mapper::{DeviceSharedMemoryManager, SharedMemoryAllocation};
 
@@ -19,7 +19,10 @@ impl VfdSharedMemory {
     }
 
     pub fn new(vfd_id: u32, transition_flags: bool, shm: SharedMemoryAllocation) -> Self {
-        let flags = if transition_flags { 0 } else { VIRTIO_WL_VFD_WRITE | VIRTIO_WL_VFD_MAP};
+        let mut flags = if transition_flags { 0 } else { VIRTIO_WL_VFD_WRITE | VIRTIO_WL_VFD_MAP};
+        if !shm.is_writable() {
+            flags &= !VIRTIO_WL_VFD_WRITE;
+        }
         VfdSharedMemory { vfd_id, flags, shm }
     }
 
@@ -42,6 +45,10 @@ impl VfdObject for VfdSharedMemory {
         self.vfd_id
     }
 
+    fn vfd_type(&self) -> VfdType {
+        VfdType::SharedMemory
+    }
+
     fn send_fd(&self) -> Option<RawFd> {
         Some(self.shm.raw_fd())
     }
@@ -6,7 +6,7 @@ use std::os::unix::{net::UnixStream, io::{AsRawFd, RawFd}};
 use vm_memory::{VolatileSlice, WriteVolatile};
 
 use crate::system::ScmSocket;
-use crate::devices::virtio_wl::{consts:: *, Error, Result, VfdObject, VfdRecv};
+use crate::devices::virtio_wl::{consts:: *, Error, Result, VfdObject, VfdRecv, VfdRecvInto, stats::VfdType};
 
 pub struct VfdSocket {
     vfd_id: u32,
@@ -33,11 +33,12 @@ impl VfdSocket {
         })
     }
     fn socket_recv(socket: &mut UnixStream) -> Result<(Vec<u8>, Vec<File>)> {
-        let mut buf = vec![0; IN_BUFFER_LEN];
+        let mut buf = vec![0; RECV_BUFFER_LEN];
         let mut fd_buf = [0; VIRTWL_SEND_MAX_ALLOCS];
         let (len, fd_len) = socket.recv_with_fds(&mut buf, &mut fd_buf)
             .map_err(Error::SocketReceive)?;
         buf.truncate(len);
+        buf.shrink_to_fit();
         let files = fd_buf[..fd_len].iter()
             .map(|&fd| unsafe {
                 File::from_raw_fd(fd)
@@ -50,6 +51,10 @@ impl VfdObject for VfdSocket {
         self.vfd_id
     }
 
+    fn vfd_type(&self) -> VfdType {
+        VfdType::Socket
+    }
+
     fn send_fd(&self) -> Option<RawFd> {
         self.socket.as_ref().map(|s| s.as_raw_fd())
     }
@@ -73,18 +78,49 @@ impl VfdObject for VfdSocket {
         Ok(None)
     }
 
-    fn send(&mut self, data: &VolatileSlice) -> Result<()> {
+    fn can_recv_into(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    fn recv_into(&mut self, bufs: &[VolatileSlice]) -> Result<Option<VfdRecvInto>> {
+        if let Some(sock) = self.socket.take() {
+            let mut fd_buf = [0; VIRTWL_SEND_MAX_ALLOCS];
+            let (len, fd_len) = sock.recv_volatile_with_fds(bufs, &mut fd_buf)
+                .map_err(Error::SocketReceive)?;
+            let files: Vec<File> = fd_buf[..fd_len].iter()
+                .map(|&fd| unsafe {
+                    File::from_raw_fd(fd)
+                }).collect();
+            if !(len == 0 && files.is_empty()) {
+                self.socket.replace(sock);
+                return Ok(Some(VfdRecvInto::new(len, files)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn send(&mut self, data: &[VolatileSlice]) -> Result<()> {
         if let Some(s) = self.socket.as_mut() {
-            s.write_all_volatile(data).map_err(Error::VolatileSendVfd)
+            for slice in data {
+                s.write_all_volatile(slice).map_err(Error::VolatileSendVfd)?;
+            }
+            Ok(())
         } else {
             Err(Error::InvalidSendVfd)
         }
     }
 
-    fn send_with_fds(&mut self, data: &VolatileSlice, fds: &[RawFd]) -> Result<()> {
+    fn send_with_fds(&mut self, data: &[VolatileSlice], fds: &[RawFd]) -> Result<()> {
         if let Some(s) = self.socket.as_mut() {
-            let mut buffer = vec![0u8; data.len()];
-            data.copy_to(&mut buffer);
+            // The file descriptors must arrive with the message they belong to
+            // so the data is gathered into a single buffer for one sendmsg().
+            let len = data.iter().map(|slice| slice.len()).sum();
+            let mut buffer = vec![0u8; len];
+            let mut offset = 0;
+            for slice in data {
+                slice.copy_to(&mut buffer[offset..offset + slice.len()]);
+                offset += slice.len();
+            }
             s.send_with_fds(&buffer, fds)
                 .map_err(|_| Error::SendVfd(io::Error::last_os_error()))?;
             Ok(())
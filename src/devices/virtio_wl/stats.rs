@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::io::stats::{Counter, DeviceStats, Gauge};
+use crate::util::JsonValue;
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum VfdType {
+    SharedMemory,
+    Pipe,
+    Socket,
+}
+
+impl VfdType {
+    fn name(self) -> &'static str {
+        match self {
+            VfdType::SharedMemory => "shm",
+            VfdType::Pipe => "pipe",
+            VfdType::Socket => "socket",
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            VfdType::SharedMemory => 0,
+            VfdType::Pipe => 1,
+            VfdType::Socket => 2,
+        }
+    }
+}
+
+struct VfdRecord {
+    vfd_type: VfdType,
+    created: Instant,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+///
+/// Accounting of the vfds which are currently open.
+///
+/// The number of live vfds of each type is reported as the `vfds_shm`,
+/// `vfds_pipe` and `vfds_socket` gauges of the device statistics, and the
+/// data passed between the guest and the host through vfds as the
+/// `vfd_bytes_in` (host to guest) and `vfd_bytes_out` counters. The type, age
+/// and traffic of each individual vfd is shown by `describe`, which makes it
+/// possible to spot vfds leaked by the guest.
+///
+pub struct VfdStats {
+    vfds: Mutex<HashMap<u32, VfdRecord>>,
+    live: [Arc<Gauge>; 3],
+    bytes_in: Arc<Counter>,
+    bytes_out: Arc<Counter>,
+}
+
+impl VfdStats {
+    pub fn register(stats: &DeviceStats) -> Self {
+        let vfd_stats = VfdStats {
+            vfds: Mutex::new(HashMap::new()),
+            live: Default::default(),
+            bytes_in: stats.counter("vfd_bytes_in"),
+            bytes_out: stats.counter("vfd_bytes_out"),
+        };
+        stats.add_gauge("vfds_shm", vfd_stats.live[VfdType::SharedMemory.index()].clone());
+        stats.add_gauge("vfds_pipe", vfd_stats.live[VfdType::Pipe.index()].clone());
+        stats.add_gauge("vfds_socket", vfd_stats.live[VfdType::Socket.index()].clone());
+        vfd_stats
+    }
+
+    fn vfds(&self) -> MutexGuard<HashMap<u32, VfdRecord>> {
+        self.vfds.lock().unwrap()
+    }
+
+    fn update_live(&self, vfds: &HashMap<u32, VfdRecord>) {
+        let mut counts = [0u64; 3];
+        for record in vfds.values() {
+            counts[record.vfd_type.index()] += 1;
+        }
+        for (gauge, count) in self.live.iter().zip(counts.iter()) {
+            gauge.set(*count);
+        }
+    }
+
+    pub fn add(&self, vfd_id: u32, vfd_type: VfdType) {
+        let mut vfds = self.vfds();
+        vfds.insert(vfd_id, VfdRecord {
+            vfd_type,
+            created: Instant::now(),
+            bytes_in: 0,
+            bytes_out: 0,
+        });
+        self.update_live(&vfds);
+    }
+
+    pub fn remove(&self, vfd_id: u32) {
+        let mut vfds = self.vfds();
+        if vfds.remove(&vfd_id).is_some() {
+            self.update_live(&vfds);
+        }
+    }
+
+    /// Record `len` bytes received from the host on vfd `vfd_id`
+    pub fn received(&self, vfd_id: u32, len: usize) {
+        if let Some(record) = self.vfds().get_mut(&vfd_id) {
+            record.bytes_in += len as u64;
+        }
+        self.bytes_in.add(len as u64);
+    }
+
+    /// Record `len` bytes sent by the guest on vfd `vfd_id`
+    pub fn sent(&self, vfd_id: u32, len: usize) {
+        if let Some(record) = self.vfds().get_mut(&vfd_id) {
+            record.bytes_out += len as u64;
+        }
+        self.bytes_out.add(len as u64);
+    }
+
+    /// List every live vfd, oldest first
+    pub fn to_json(&self) -> JsonValue {
+        let vfds = self.vfds();
+        let mut records: Vec<_> = vfds.iter().collect();
+        records.sort_by_key(|(id, record)| (record.created, **id));
+
+        let now = Instant::now();
+        let mut list = JsonValue::array();
+        for (id, record) in records {
+            list.push(JsonValue::object()
+                .field("id", *id)
+                .field("type", record.vfd_type.name())
+                .field("age_secs", now.duration_since(record.created).as_secs())
+                .field("bytes_in", record.bytes_in)
+                .field("bytes_out", record.bytes_out));
+        }
+        list
+    }
+}
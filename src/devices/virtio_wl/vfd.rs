@@ -1,47 +1,104 @@
-use std::collections::{HashMap, VecDeque};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
-use std::io;
+use std::{io, mem};
 use std::io::{Write, SeekFrom, Seek};
 use std::os::unix::io::{AsRawFd,RawFd};
-use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 
-use crate::system::drm::DrmDescriptor;
-use crate::system::EPoll;
+use crate::system::drm::{self, DrmDescriptor};
+use crate::system::{EPoll, Trigger};
+use crate::system::limits;
 
 use crate::devices::virtio_wl::{
-    consts::*, Error, Result, shm::VfdSharedMemory, pipe::VfdPipe, socket::VfdSocket, VfdObject
+    consts::*, Error, Result, shm::VfdSharedMemory, pipe::VfdPipe, socket::VfdSocket, stats::VfdStats, VfdObject,
+    WaylandControl,
 };
 use crate::io::{Chain, VirtQueue};
 use crate::io::shm_mapper::DeviceSharedMemoryManager;
 use crate::system::errno::cvt;
 
+// Set in the poll id of a pipe which the guest closed while data it sent was
+// still waiting to be written
+const DRAINING_TOKEN: u64 = 1 << 32;
+
 pub struct VfdManager {
-    wayland_path: PathBuf,
+    control: Arc<WaylandControl>,
     dev_shm_manager: DeviceSharedMemoryManager,
     use_transition_flags: bool,
     vfd_map: HashMap<u32, Box<dyn VfdObject>>,
+    // Vfds polled for writable because they hold data they could not write yet
+    write_waiting: HashSet<u32>,
+    // Closed pipes which still have data to write
+    draining: HashMap<u32, Box<dyn VfdObject>>,
+    max_vfds: usize,
     next_vfd_id: u32,
     poll_ctx: EPoll,
     in_vq: VirtQueue,
     in_queue_pending: VecDeque<PendingInput>,
+    stats: Arc<VfdStats>,
+    // Set once the vfd count warning has been logged, until the count drops again
+    vfd_warning: bool,
 }
 
 impl VfdManager {
-    pub fn new<P: Into<PathBuf>>(dev_shm_manager: DeviceSharedMemoryManager, use_transition_flags: bool, in_vq: VirtQueue, wayland_path: P) -> Result<Self> {
+    pub fn new(dev_shm_manager: DeviceSharedMemoryManager, use_transition_flags: bool, in_vq: VirtQueue, control: Arc<WaylandControl>, max_vfds: Option<usize>, stats: Arc<VfdStats>) -> Result<Self> {
         let poll_ctx = EPoll::new().map_err(Error::FailedPollContextCreate)?;
         Ok(VfdManager {
-            wayland_path: wayland_path.into(),
+            control,
             dev_shm_manager,
             use_transition_flags,
             vfd_map: HashMap::new(),
+            write_waiting: HashSet::new(),
+            draining: HashMap::new(),
+            max_vfds: max_vfds.map_or(Self::vfd_budget(), |max| cmp::min(max, Self::vfd_budget())),
             next_vfd_id: NEXT_VFD_ID_BASE,
             poll_ctx,
             in_vq,
             in_queue_pending: VecDeque::new(),
+            stats,
+            vfd_warning: false,
         })
     }
 
+    // A vfd uses up to two file descriptors, leave the other half of the
+    // limit for the rest of the process.
+    fn vfd_budget() -> usize {
+        let limit = limits::nofile_limit() as usize / 4;
+        cmp::min(MAX_VFD_COUNT, limit)
+    }
+
+    fn check_vfd_budget(&self) -> Result<()> {
+        let count = self.vfd_map.len() + self.draining.len();
+        if count >= self.max_vfds {
+            return Err(Error::TooManyVfds(count));
+        }
+        Ok(())
+    }
+
+    // Warn once when the number of open vfds reaches VFD_WARN_PERCENT of the
+    // limit, and again only after it has dropped back below half of the limit.
+    fn check_vfd_warning(&mut self) {
+        let count = self.vfd_map.len();
+        if !self.vfd_warning && count * 100 >= self.max_vfds * VFD_WARN_PERCENT {
+            warn!("virtio_wl: {} of at most {} vfds are open", count, self.max_vfds);
+            self.vfd_warning = true;
+        } else if self.vfd_warning && count * 2 < self.max_vfds {
+            self.vfd_warning = false;
+        }
+    }
+
+    fn insert_vfd(&mut self, vfd_id: u32, vfd: Box<dyn VfdObject>) {
+        self.stats.add(vfd_id, vfd.vfd_type());
+        self.vfd_map.insert(vfd_id, vfd);
+        self.check_vfd_warning();
+    }
+
+    pub fn stats(&self) -> &VfdStats {
+        &self.stats
+    }
+
     pub fn get_vfd(&self, vfd_id: u32) -> Option<&dyn VfdObject> {
         self.vfd_map.get(&vfd_id).map(|vfd| vfd.as_ref())
     }
@@ -53,36 +110,127 @@ impl VfdManager {
 
 
     pub fn create_pipe(&mut self, vfd_id: u32, is_local_write: bool) -> Result<()> {
+        self.check_vfd_budget()?;
         let pipe = VfdPipe::create(vfd_id, is_local_write)?;
-        // XXX unwrap
-        self.poll_ctx.add_read(pipe.poll_fd().unwrap(), vfd_id as u64)
+        let fd = pipe.poll_fd().ok_or(Error::IncompleteVfd(vfd_id, "file descriptor"))?;
+        self.poll_ctx.add_read(fd, vfd_id as u64)
             .map_err(Error::FailedPollAdd)?;
-        self.vfd_map.insert(vfd_id, Box::new(pipe));
+        self.insert_vfd(vfd_id, Box::new(pipe));
         Ok(())
     }
 
     pub fn create_shm(&mut self, vfd_id: u32, size: u32) -> Result<(u64,usize)> {
+        self.check_vfd_budget()?;
         let vfd = VfdSharedMemory::create(vfd_id, self.use_transition_flags, size, &self.dev_shm_manager)?;
-        let shm = vfd.shared_memory().unwrap();
-        self.vfd_map.insert(vfd_id, Box::new(vfd));
+        let shm = vfd.shared_memory().ok_or(Error::IncompleteVfd(vfd_id, "shared memory"))?;
+        self.insert_vfd(vfd_id, Box::new(vfd));
         Ok((shm.pfn(),shm.size()))
     }
 
     pub fn create_dmabuf(&mut self, vfd_id: u32, width: u32, height: u32, format: u32) -> Result<(u64, usize, DrmDescriptor)> {
+        self.check_vfd_budget()?;
         let vfd = VfdSharedMemory::create_dmabuf(vfd_id, self.use_transition_flags, width, height, format, &self.dev_shm_manager)?;
-        let shm = vfd.shared_memory().unwrap();
-        self.vfd_map.insert(vfd_id, Box::new(vfd));
-        Ok((shm.pfn(), shm.size(), shm.drm_descriptor().unwrap()))
+        let shm = vfd.shared_memory().ok_or(Error::IncompleteVfd(vfd_id, "shared memory"))?;
+        let descriptor = shm.drm_descriptor().ok_or(Error::IncompleteVfd(vfd_id, "dmabuf descriptor"))?;
+        self.insert_vfd(vfd_id, Box::new(vfd));
+        Ok((shm.pfn(), shm.size(), descriptor))
     }
 
     pub fn create_socket(&mut self, vfd_id: u32) -> Result<u32> {
-        let sock = VfdSocket::open(vfd_id, self.use_transition_flags,&self.wayland_path)?;
-        self.poll_ctx.add_read(sock.poll_fd().unwrap(), vfd_id as u64)
+        self.create_named_socket(vfd_id, DEFAULT_SOCKET_NAME)
+    }
+
+    /// Connect to the host socket configured for `name`, or to the default
+    /// socket if `name` is empty. The path is looked up on each connection as
+    /// the default socket may be changed through the `WaylandControl`.
+    pub fn create_named_socket(&mut self, vfd_id: u32, name: &str) -> Result<u32> {
+        self.check_vfd_budget()?;
+        let path = match name {
+            "" | DEFAULT_SOCKET_NAME => self.control.socket_path(),
+            name => self.control.named_socket_path(name)
+                .ok_or_else(|| Error::UnknownSocketName(name.to_string()))?,
+        };
+        let sock = VfdSocket::open(vfd_id, self.use_transition_flags, path)?;
+        let fd = sock.poll_fd().ok_or(Error::IncompleteVfd(vfd_id, "file descriptor"))?;
+        self.poll_ctx.add_read(fd, vfd_id as u64)
             .map_err(Error::FailedPollAdd)?;
         let flags = sock.flags();
-        self.vfd_map.insert(vfd_id, Box::new(sock));
+        self.insert_vfd(vfd_id, Box::new(sock));
         Ok(flags)
+    }
+
+    /// Poll `vfd_id` for writable while it has data waiting to be written,
+    /// and only for readable otherwise.
+    pub fn update_write_interest(&mut self, vfd_id: u32) -> Result<()> {
+        let (fd, pending) = match self.vfd_map.get(&vfd_id) {
+            Some(vfd) => match vfd.poll_fd() {
+                Some(fd) => (fd, vfd.has_pending_writes()),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        if pending == self.write_waiting.contains(&vfd_id) {
+            return Ok(());
+        }
+        let events = if pending { EPoll::READ | EPoll::WRITE } else { EPoll::READ };
+        self.poll_ctx.modify(fd, vfd_id as u64, events, Trigger::Level)
+            .map_err(Error::FailedPollModify)?;
+        if pending {
+            self.write_waiting.insert(vfd_id);
+        } else {
+            self.write_waiting.remove(&vfd_id);
+        }
+        Ok(())
+    }
+
+    fn flush_vfd(&mut self, vfd_id: u32) {
+        if let Some(vfd) = self.vfd_map.get_mut(&vfd_id) {
+            if let Err(e) = vfd.flush_writes() {
+                warn_limited!("virtio_wl: error writing to vfd 0x{:08x}: {}", vfd_id, e);
+            }
+        }
+        if let Err(e) = self.update_write_interest(vfd_id) {
+            warn!("virtio_wl: {}", e);
+        }
+    }
+
+    // The guest may close a pipe right after sending the last of the data,
+    // so keep writing it out after the vfd id is gone. The pipe is polled
+    // with a separate id in case the guest reuses the vfd id.
+    fn start_draining(&mut self, vfd_id: u32, vfd: Box<dyn VfdObject>) -> Result<()> {
+        // An earlier pipe closed with the same id is given up on
+        self.finish_draining(vfd_id);
+        if let Some(fd) = vfd.poll_fd() {
+            self.poll_ctx.modify(fd, DRAINING_TOKEN | vfd_id as u64, EPoll::WRITE, Trigger::Level)
+                .map_err(Error::FailedPollModify)?;
+        }
+        self.draining.insert(vfd_id, vfd);
+        Ok(())
+    }
 
+    fn drain_closed_vfd(&mut self, vfd_id: u32) {
+        let done = match self.draining.get_mut(&vfd_id) {
+            Some(vfd) => match vfd.flush_writes() {
+                Ok(()) => !vfd.has_pending_writes(),
+                Err(e) => {
+                    warn_limited!("virtio_wl: error writing to closed vfd 0x{:08x}: {}", vfd_id, e);
+                    true
+                }
+            },
+            None => return,
+        };
+        if done {
+            self.finish_draining(vfd_id);
+        }
+    }
+
+    fn finish_draining(&mut self, vfd_id: u32) {
+        if let Some(mut vfd) = self.draining.remove(&vfd_id) {
+            if let Some(fd) = vfd.poll_fd() {
+                let _ = self.poll_ctx.delete(fd);
+            }
+            let _ = vfd.close();
+        }
     }
 
     pub fn poll_fd(&self) -> RawFd {
@@ -102,6 +250,13 @@ impl VfdManager {
             }
         };
         for ev in events.iter() {
+            if ev.id() & DRAINING_TOKEN != 0 {
+                self.drain_closed_vfd(ev.id() as u32);
+                continue;
+            }
+            if ev.is_writable() {
+                self.flush_vfd(ev.id() as u32);
+            }
             if ev.is_readable() {
                 if let Err(e) = self.recv_from_vfd(ev.id() as u32) {
                     warn!("Error on wayland vfd recv(0x{:08x}): {}", ev.id() as u32, e);
@@ -138,10 +293,21 @@ impl VfdManager {
                 }
             }
         }
+        self.write_waiting.remove(&vfd_id);
         self.in_queue_pending.push_back(PendingInput::new_hup(vfd_id));
     }
 
     fn recv_from_vfd(&mut self, vfd_id: u32) -> Result<()> {
+        let can_recv_into = match self.vfd_map.get(&vfd_id) {
+            Some(vfd) => vfd.can_recv_into(),
+            None => return Ok(())
+        };
+        // Nothing may overtake input which is already waiting for the in-queue
+        if can_recv_into && self.in_queue_pending.is_empty() {
+            if let Some(mut chain) = self.in_vq.next_chain() {
+                return self.recv_into_chain(vfd_id, &mut chain);
+            }
+        }
         let vfd = match self.vfd_map.get_mut(&vfd_id) {
             Some(vfd) => vfd,
             None => return Ok(())
@@ -153,17 +319,68 @@ impl VfdManager {
                 return Ok(())
             }
         };
+        self.stats.received(vfd_id, recv.buf.len());
+        self.queue_received(vfd_id, recv.buf, recv.fds)
+    }
+
+    // Receive from the vfd straight into the data area of a VFD_RECV message
+    // in `chain` rather than through an intermediate buffer. Whether any file
+    // descriptors arrive with the data is only known afterwards, and their
+    // VFD_NEW messages must come first, so in that case the data is copied
+    // out and queued as recv_from_vfd() would have.
+    fn recv_into_chain(&mut self, vfd_id: u32, chain: &mut Chain) -> Result<()> {
+        let space = chain.remaining_write().saturating_sub(VFD_RECV_HDR_SIZE);
+        if space == 0 {
+            return Err(Error::InBufferTooSmall(chain.remaining_write()));
+        }
+        let slices = chain.writeable_slices_at(VFD_RECV_HDR_SIZE, cmp::min(space, RECV_BUFFER_LEN))?;
+        let vfd = match self.vfd_map.get_mut(&vfd_id) {
+            Some(vfd) => vfd,
+            None => return Ok(())
+        };
+        let recv = match vfd.recv_into(&slices)? {
+            Some(recv) => recv,
+            None => {
+                self.in_queue_pending.push_back(PendingInput::new_hup(vfd_id));
+                return self.send_next_input_message(chain);
+            }
+        };
+        self.stats.received(vfd_id, recv.len);
+
+        if !recv.fds.is_empty() {
+            let mut buf = vec![0u8; recv.len];
+            let mut offset = 0;
+            for slice in &slices {
+                let n = cmp::min(slice.len(), recv.len - offset);
+                slice.copy_to(&mut buf[offset..offset + n]);
+                offset += n;
+            }
+            self.queue_received(vfd_id, buf, Some(recv.fds))?;
+            return self.send_next_input_message(chain);
+        }
+
+        chain.w32(VIRTIO_WL_CMD_VFD_RECV)?;
+        chain.w32(0)?;
+        chain.w32(vfd_id)?;
+        chain.w32(0)?;
+        chain.inc_write_offset(recv.len);
+        chain.flush_chain();
+        Ok(())
+    }
 
-        if let Some(fds) = recv.fds {
+    fn queue_received(&mut self, vfd_id: u32, buf: Vec<u8>, fds: Option<Vec<File>>) -> Result<()> {
+        if let Some(fds) = fds {
             let mut vfd_ids = Vec::new();
             for fd in fds {
+                // Any remaining received fds are closed when returning an error
+                self.check_vfd_budget()?;
                 let vfd = self.vfd_from_file(self.next_vfd_id, fd)?;
                 let id = self.add_vfd_device(vfd)?;
                 vfd_ids.push(id);
             }
-            self.in_queue_pending.push_back(PendingInput::new(vfd_id, Some(recv.buf), Some(vfd_ids)));
+            self.in_queue_pending.push_back(PendingInput::new(vfd_id, Some(buf), Some(vfd_ids)));
         } else {
-            self.in_queue_pending.push_back(PendingInput::new(vfd_id, Some(recv.buf), None));
+            self.in_queue_pending.push_back(PendingInput::new(vfd_id, Some(buf), None));
         }
         Ok(())
     }
@@ -174,13 +391,13 @@ impl VfdManager {
             self.poll_ctx.add_read(poll_fd, id as u64)
                 .map_err(Error::FailedPollAdd)?;
         }
-        self.vfd_map.insert(id, vfd);
+        self.insert_vfd(id, vfd);
         self.next_vfd_id += 1;
         Ok(id)
     }
 
     pub fn in_vq_ready(&mut self) -> Result<()> {
-        self.in_vq.ioevent().read().map_err(Error::IoEventError)?;
+        self.in_vq.read_ioevent().map_err(Error::IoEventError)?;
         self.drain_pending()
     }
 
@@ -200,8 +417,11 @@ impl VfdManager {
             fd.seek(SeekFrom::End(0)).is_ok()
         }
 
-
-        if has_size(&fd) {
+        if drm::is_dmabuf(fd.as_raw_fd()) {
+            let shm = self.dev_shm_manager.allocate_buffer_from_dmabuf(fd)
+                .map_err(Error::ShmAllocFailed)?;
+            Ok(Box::new(VfdSharedMemory::new(vfd_id, self.use_transition_flags, shm)))
+        } else if has_size(&fd) {
             let shm = self.dev_shm_manager.allocate_buffer_from_file(fd)
                 .map_err(Error::ShmAllocFailed)?;
             Ok(Box::new(VfdSharedMemory::new(vfd_id, self.use_transition_flags,shm)))
@@ -218,12 +438,32 @@ impl VfdManager {
         }
     }
 
+    /// Close every vfd and release any shared memory mapped into the guest
+    pub fn close_all(&mut self) {
+        let ids: Vec<u32> = self.vfd_map.keys().copied().collect();
+        for id in ids {
+            if let Err(e) = self.close_vfd(id) {
+                warn!("virtio_wl: error closing vfd {}: {}", id, e);
+            }
+        }
+        let draining: Vec<u32> = self.draining.keys().copied().collect();
+        for id in draining {
+            self.finish_draining(id);
+        }
+        self.in_queue_pending.clear();
+    }
+
     pub fn close_vfd(&mut self, vfd_id: u32) -> Result<()> {
         if let Some(mut vfd) = self.vfd_map.remove(&vfd_id) {
+            self.stats.remove(vfd_id);
+            self.check_vfd_warning();
             if let Some(shm) = vfd.shared_memory() {
                 self.dev_shm_manager.free_buffer(shm.slot())
                     .map_err(Error::ShmFreeFailed)?;
             }
+            if self.write_waiting.remove(&vfd_id) && vfd.has_pending_writes() {
+                return self.start_draining(vfd_id, vfd);
+            }
             vfd.close()?;
         }
         // XXX remove any matching fds from in_queue_pending
@@ -237,6 +477,8 @@ struct PendingInput {
     vfds: Option<Vec<u32>>,
     // next index to transmit from vfds vector
     vfd_current: usize,
+    // offset in buf of the data not yet sent to the guest
+    buf_offset: usize,
 }
 
 impl PendingInput {
@@ -245,7 +487,7 @@ impl PendingInput {
     }
 
     fn new(vfd_id: u32, buf: Option<Vec<u8>>, vfds: Option<Vec<u32>>) -> Self {
-        PendingInput { vfd_id, buf, vfds, vfd_current: 0 }
+        PendingInput { vfd_id, buf, vfds, vfd_current: 0, buf_offset: 0 }
     }
 
     fn is_hup(&self) -> bool {
@@ -276,8 +518,7 @@ impl PendingInput {
             false
 
         } else {
-            self.send_recv_message(chain)?;
-            true
+            self.send_recv_message(chain)?
         };
         Ok(pop)
     }
@@ -304,23 +545,37 @@ impl PendingInput {
         Ok(())
     }
 
-    fn send_recv_message(&self, chain: &mut Chain) -> Result<bool> {
+    // Send as much of the received data as fits in the chain. The vfd ids
+    // are sent with the first fragment only. Returns true once all of the
+    // data has been sent.
+    fn send_recv_message(&mut self, chain: &mut Chain) -> Result<bool> {
+        let vfds: &[u32] = match self.vfds.as_ref() {
+            Some(vfds) if self.buf_offset == 0 => vfds,
+            _ => &[],
+        };
+        let data = match self.buf.as_ref() {
+            Some(buf) => &buf[self.buf_offset..],
+            None => &[],
+        };
+        let header_len = VFD_RECV_HDR_SIZE + vfds.len() * mem::size_of::<u32>();
+        let space = chain.remaining_write().saturating_sub(header_len);
+        if space == 0 && !data.is_empty() {
+            return Err(Error::InBufferTooSmall(chain.remaining_write()));
+        }
+        let len = cmp::min(data.len(), space);
+        let more = len < data.len();
+
         chain.w32(VIRTIO_WL_CMD_VFD_RECV)?;
-        chain.w32(0)?;
+        chain.w32(if more { VIRTIO_WL_RECV_FLAG_MORE } else { 0 })?;
         chain.w32(self.vfd_id)?;
-        if let Some(vfds) = self.vfds.as_ref() {
-            chain.w32(vfds.len() as u32)?;
-            for vfd_id in vfds {
-                chain.w32(*vfd_id)?;
-            }
-        } else {
-            chain.w32(0)?;
-        }
-        if let Some(buf) = self.buf.as_ref() {
-            chain.write_all(buf)?;
+        chain.w32(vfds.len() as u32)?;
+        for vfd_id in vfds {
+            chain.w32(*vfd_id)?;
         }
+        chain.write_all(&data[..len])?;
         chain.flush_chain();
-        Ok(true)
+        self.buf_offset += len;
+        Ok(!more)
     }
 }
 
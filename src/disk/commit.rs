@@ -0,0 +1,85 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::disk::{Error, Result};
+use crate::disk::memory::MemoryOverlay;
+use crate::disk::provision;
+
+///
+/// Write the sectors changed in `overlay` back to the image at `path`.
+///
+/// The image is copied to a temporary file in the same directory, as a
+/// reflink where the filesystem supports it, the changes are applied to the
+/// copy and synced, and the copy is renamed over the image. A crash during
+/// the commit leaves the original image intact and other VMs which still
+/// have the image open keep reading the old contents.
+///
+/// If the copy cannot be created, for example because the directory is not
+/// writable, the changes are written to the image in place instead.
+///
+pub fn commit_overlay(overlay: &MemoryOverlay, path: &Path) -> Result<()> {
+    // Replace the file a symlink points to rather than the symlink
+    let path = fs::canonicalize(path)
+        .map_err(|e| Error::DiskOpen(path.to_path_buf(), e))?;
+    let tmp_path = temp_path(&path);
+
+    match copy_image(&path, &tmp_path) {
+        Ok(tmp) => {
+            let result = apply(overlay, &tmp)
+                .and_then(|()| replace(&tmp_path, &path));
+            if result.is_err() {
+                let _ = fs::remove_file(&tmp_path);
+            }
+            result
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&tmp_path);
+            warn!("Unable to copy {} ({}), writing changes in place", path.display(), err);
+            let file = OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .map_err(|e| Error::DiskOpen(path.clone(), e))?;
+            apply(overlay, &file)
+        }
+    }
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    parent_dir(path).join(format!(".{}.commit-{}", name, process::id()))
+}
+
+fn copy_image(path: &Path, tmp_path: &Path) -> io::Result<File> {
+    let image = File::open(path)?;
+    let tmp = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(tmp_path)?;
+
+    provision::reflink_or_copy(&image, &tmp)?;
+    tmp.set_permissions(image.metadata()?.permissions())?;
+    Ok(tmp)
+}
+
+fn apply(overlay: &MemoryOverlay, file: &File) -> Result<()> {
+    overlay.write_to(file)?;
+    file.sync_all().map_err(Error::DiskFlush)
+}
+
+fn replace(tmp_path: &Path, path: &Path) -> Result<()> {
+    fs::rename(tmp_path, path).map_err(Error::DiskWrite)?;
+    // Sync the directory so that the rename is also durable
+    File::open(parent_dir(path))
+        .and_then(|dir| dir.sync_all())
+        .map_err(Error::DiskFlush)
+}
@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use vm_memory::VolatileSlice;
+
+/// Alignment of file offsets, lengths and buffer addresses for I/O on a
+/// file opened with `O_DIRECT`. 4096 bytes satisfies storage with either
+/// 512 byte or 4K logical blocks.
+pub const DIRECT_ALIGN: usize = 4096;
+
+fn align_down(n: u64) -> u64 {
+    n & !(DIRECT_ALIGN as u64 - 1)
+}
+
+fn align_up(n: u64) -> u64 {
+    align_down(n + DIRECT_ALIGN as u64 - 1)
+}
+
+///
+/// Bounce buffer for reads and writes to an image opened with `O_DIRECT`.
+///
+/// Guest buffers have no particular alignment and requests are in 512 byte
+/// sectors, so every transfer goes through an aligned buffer covering the
+/// whole blocks of the request. A write which only covers part of a block
+/// first reads the block so that the rest of it is preserved.
+///
+pub struct DirectIo {
+    buffer: Vec<u8>,
+}
+
+impl DirectIo {
+    pub fn new() -> Self {
+        DirectIo { buffer: Vec::new() }
+    }
+
+    // An aligned slice of `len` bytes from the bounce buffer
+    fn aligned(&mut self, len: usize) -> &mut [u8] {
+        if self.buffer.len() < len + DIRECT_ALIGN {
+            self.buffer = vec![0; len + DIRECT_ALIGN];
+        }
+        let start = self.buffer.as_ptr().align_offset(DIRECT_ALIGN);
+        &mut self.buffer[start..start + len]
+    }
+
+    /// Read `dst.len()` bytes at `offset` of `file` into `dst`.
+    pub fn read(&mut self, file: &File, offset: u64, dst: &VolatileSlice) -> io::Result<()> {
+        let start = align_down(offset);
+        let end = align_up(offset + dst.len() as u64);
+        let skip = (offset - start) as usize;
+        let buf = self.aligned((end - start) as usize);
+        read_blocks(file, buf, start)?;
+        dst.copy_from(&buf[skip..skip + dst.len()]);
+        Ok(())
+    }
+
+    /// Write the contents of `src` at `offset` of `file`.
+    pub fn write(&mut self, file: &File, offset: u64, src: &VolatileSlice) -> io::Result<()> {
+        let start = align_down(offset);
+        let end = align_up(offset + src.len() as u64);
+        let skip = (offset - start) as usize;
+        let buf = self.aligned((end - start) as usize);
+        if skip != 0 {
+            read_blocks(file, &mut buf[..DIRECT_ALIGN], start)?;
+        }
+        let tail = buf.len() - DIRECT_ALIGN;
+        if offset + src.len() as u64 != end && (tail != 0 || skip == 0) {
+            read_blocks(file, &mut buf[tail..], end - DIRECT_ALIGN as u64)?;
+        }
+        src.copy_to(&mut buf[skip..skip + src.len()]);
+        file.write_all_at(buf, start)
+    }
+}
+
+// Fill `buf` from `offset` of `file`, with zeroes past the end of the file
+fn read_blocks(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match file.read_at(&mut buf[pos..], offset + pos as u64) {
+            Ok(0) => break,
+            Ok(n) => pos += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    buf[pos..].iter_mut().for_each(|b| *b = 0);
+    Ok(())
+}
@@ -1,77 +1,243 @@
+use std::cmp;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
-use crate::util::BitSet;
-use crate::disk::{Result, Error, SECTOR_SIZE, DiskImage};
-use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use crate::disk::{Result, Error, SECTOR_SIZE};
+use crate::io::stats::Gauge;
 use memfd::MemfdOptions;
-use vm_memory::{ReadVolatile, VolatileSlice, WriteVolatile};
+use vm_memory::{FileOffset, MmapRegion, VolatileMemory, VolatileSlice};
 
+const PAGE_SIZE: usize = 4096;
+const SECTORS_PER_PAGE: usize = PAGE_SIZE / SECTOR_SIZE;
+
+// Overlay state of one page sized block of the disk
+#[derive(Default)]
+struct Block {
+    // Bit n is set if sector n of the block has been written
+    written: u8,
+    // The memfd page backing this block has been allocated
+    allocated: bool,
+}
+
+///
+/// Copy-on-write overlay kept in memory for a disk image opened with
+/// `OpenType::MemoryOverlay`.
+///
+/// The base image is mapped read-only and sectors written by the guest are
+/// stored in a mapping of a memfd, so requests are served by copying directly
+/// between guest memory and the two mappings without any file I/O.
+///
+/// Pages of the memfd are only allocated for blocks containing written data.
+/// Writing a page of zeros releases the page again, reads of the resulting
+/// hole return zeros. The number of allocated bytes is reported through the
+/// `overlay_size` gauge.
+///
 pub struct MemoryOverlay {
-    memory: File,
-    written_sectors: BitSet,
+    base: MmapRegion,
+    base_offset: usize,
+    overlay: MmapRegion,
+    blocks: HashMap<u64, Block>,
+    allocated_pages: u64,
+    overlay_size: Arc<Gauge>,
+    nsectors: u64,
 }
 
 impl MemoryOverlay {
-    pub fn new() -> Result<Self> {
+    /// Create an overlay for `nsectors` sectors of `base` starting at byte
+    /// `base_offset` of the file.
+    pub fn new(base: &File, base_offset: usize, nsectors: u64, overlay_size: Arc<Gauge>) -> Result<Self> {
+        let size = nsectors as usize * SECTOR_SIZE;
+
+        // Map from the start of the file since the offset may not be page aligned
+        let base_file = base.try_clone().map_err(Error::MemoryOverlayFile)?;
+        let base = MmapRegion::build(Some(FileOffset::new(base_file, 0)), base_offset + size, libc::PROT_READ, libc::MAP_PRIVATE)
+            .map_err(Error::MemoryOverlayMap)?;
+
         let memory = MemfdOptions::new()
             .allow_sealing(true)
             .create("disk-overlay-memfd")
             .map_err(Error::MemoryOverlayCreate)?;
         let memory = memory.into_file();
-        let written_sectors = BitSet::new();
-        Ok(MemoryOverlay { memory, written_sectors })
+        memory.set_len(size as u64)
+            .map_err(Error::MemoryOverlayFile)?;
+        let overlay = MmapRegion::from_file(FileOffset::new(memory, 0), size)
+            .map_err(Error::MemoryOverlayMap)?;
+
+        overlay_size.set(0);
+        Ok(MemoryOverlay {
+            base, base_offset, overlay,
+            blocks: HashMap::new(),
+            allocated_pages: 0,
+            overlay_size,
+            nsectors,
+        })
     }
 
-    pub fn write_sectors(&mut self, start: u64, buffer: &VolatileSlice) -> Result<()> {
-        let sector_count = buffer.len() / SECTOR_SIZE;
-        let len = sector_count * SECTOR_SIZE;
-        let seek_offset = SeekFrom::Start(start * SECTOR_SIZE as u64);
+    fn is_written(&self, sector: u64) -> bool {
+        let block = sector / SECTORS_PER_PAGE as u64;
+        let bit = (sector % SECTORS_PER_PAGE as u64) as u8;
+        self.blocks.get(&block)
+            .map(|b| b.written & (1 << bit) != 0)
+            .unwrap_or(false)
+    }
+
+    fn mark_written(&mut self, block: u64, first: usize, count: usize, allocated: bool) {
+        let mask = (((1u16 << count) - 1) << first) as u8;
+        let b = self.blocks.entry(block).or_default();
+        b.written |= mask;
+        if b.allocated != allocated {
+            b.allocated = allocated;
+            if allocated {
+                self.allocated_pages += 1;
+            } else {
+                self.allocated_pages -= 1;
+            }
+            self.overlay_size.set(self.allocated_pages * PAGE_SIZE as u64);
+        }
+    }
 
-        self.memory.seek(seek_offset)
-            .map_err(Error::DiskSeek)?;
+    fn is_block_allocated(&self, block: u64) -> bool {
+        self.blocks.get(&block).map(|b| b.allocated).unwrap_or(false)
+    }
 
-        let slice = buffer.subslice(0, len)
-            .expect("Out of bounds in MemoryOverlay::write_sectors()");
+    // Release the memfd page backing `block`
+    fn punch_block(&self, block: u64) -> Result<()> {
+        let fd = self.overlay.file_offset()
+            .expect("Memory overlay mapping has no file")
+            .file()
+            .as_raw_fd();
+        let offset = (block as usize * PAGE_SIZE) as libc::off_t;
+        let ret = unsafe {
+            libc::fallocate(fd, libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE, offset, PAGE_SIZE as libc::off_t)
+        };
+        if ret < 0 {
+            return Err(Error::DiskWrite(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
 
-        self.memory.write_all_volatile(&slice)
-            .map_err(io::Error::other)
-            .map_err(Error::DiskWrite)?;
+    fn is_zero(slice: &VolatileSlice) -> bool {
+        let mut buf = [0u8; PAGE_SIZE];
+        let n = slice.copy_to(&mut buf[..]);
+        buf[..n].iter().all(|&b| b == 0)
+    }
 
-        for n in 0..sector_count {
-            let idx = start as usize + n;
-            self.written_sectors.insert(idx);
+    fn check_range(&self, start: u64, sector_count: usize) -> Result<()> {
+        match start.checked_add(sector_count as u64) {
+            Some(end) if end <= self.nsectors => Ok(()),
+            _ => Err(Error::BadSectorOffset(start)),
+        }
+    }
+
+    fn overlay_slice(&self, sector: u64, sector_count: usize) -> Result<VolatileSlice> {
+        self.overlay.get_slice(sector as usize * SECTOR_SIZE, sector_count * SECTOR_SIZE)
+            .map_err(|_| Error::BadSectorOffset(sector))
+    }
+
+    fn base_slice(&self, sector: u64, sector_count: usize) -> Result<VolatileSlice> {
+        let offset = self.base_offset + sector as usize * SECTOR_SIZE;
+        self.base.get_slice(offset, sector_count * SECTOR_SIZE)
+            .map_err(|_| Error::BadSectorOffset(sector))
+    }
+
+    /// Returns `true` if the guest has written to any sector
+    pub fn is_modified(&self) -> bool {
+        !self.blocks.is_empty()
+    }
+
+    /// Write every sector written by the guest to `file` at the same
+    /// position it has in the base image.
+    pub fn write_to(&self, file: &File) -> Result<()> {
+        let mut blocks: Vec<u64> = self.blocks.keys().copied().collect();
+        blocks.sort_unstable();
+
+        let mut buf = [0u8; PAGE_SIZE];
+        for block in blocks {
+            let b = &self.blocks[&block];
+            let is_written = |n: usize| b.written & (1 << n) != 0;
+            let mut n = 0;
+            while n < SECTORS_PER_PAGE {
+                if !is_written(n) {
+                    n += 1;
+                    continue;
+                }
+                let mut end = n + 1;
+                while end < SECTORS_PER_PAGE && is_written(end) {
+                    end += 1;
+                }
+                let sector = block * SECTORS_PER_PAGE as u64 + n as u64;
+                let len = (end - n) * SECTOR_SIZE;
+                // Written blocks without a page in the memfd were zeroed
+                if b.allocated {
+                    self.overlay_slice(sector, end - n)?.copy_to(&mut buf[..len]);
+                } else {
+                    buf[..len].fill(0);
+                }
+                let offset = self.base_offset as u64 + sector * SECTOR_SIZE as u64;
+                file.write_all_at(&buf[..len], offset)
+                    .map_err(Error::DiskWrite)?;
+                n = end;
+            }
         }
         Ok(())
     }
 
-    pub fn read_sectors<D: DiskImage>(&mut self, disk: &mut D, start: u64, buffer: &mut VolatileSlice) -> Result<()> {
+    pub fn write_sectors(&mut self, start: u64, buffer: &VolatileSlice) -> Result<()> {
         let sector_count = buffer.len() / SECTOR_SIZE;
-        if (0..sector_count).all(|i| !self.written_sectors.get(i)) {
-            return disk.read_sectors(start, buffer);
-        }
+        self.check_range(start, sector_count)?;
 
-        for n in 0..sector_count {
+        // Split the write into pieces which do not cross a block boundary
+        let mut n = 0;
+        while n < sector_count {
             let sector = start + n as u64;
-            let offset = n * SECTOR_SIZE;
-            let mut sector_buffer = buffer.subslice(offset, SECTOR_SIZE)
-                .expect("Out of bounds in MemoryOverlay::read_sectors()");
-            if self.written_sectors.get(sector as usize) {
-                self.read_single_sector(sector, &mut sector_buffer)?;
+            let block = sector / SECTORS_PER_PAGE as u64;
+            let first = (sector % SECTORS_PER_PAGE as u64) as usize;
+            let count = cmp::min(SECTORS_PER_PAGE - first, sector_count - n);
+            let slice = buffer.subslice(n * SECTOR_SIZE, count * SECTOR_SIZE)
+                .expect("Out of bounds in MemoryOverlay::write_sectors()");
+
+            if count == SECTORS_PER_PAGE && Self::is_zero(&slice) {
+                if self.is_block_allocated(block) {
+                    self.punch_block(block)?;
+                }
+                self.mark_written(block, first, count, false);
             } else {
-                disk.read_sectors(sector, &mut sector_buffer)?;
+                slice.copy_to_volatile_slice(self.overlay_slice(sector, count)?);
+                self.mark_written(block, first, count, true);
             }
+            n += count;
         }
         Ok(())
     }
 
-    fn read_single_sector(&mut self, sector: u64, buffer: &mut VolatileSlice) -> Result<()> {
-        assert_eq!(buffer.len(), SECTOR_SIZE);
-        let offset = SeekFrom::Start(sector * SECTOR_SIZE as u64);
-        self.memory.seek(offset)
-            .map_err(Error::DiskSeek)?;
-        self.memory.read_exact_volatile(buffer).map_err(io::Error::other)
-            .map_err(Error::DiskRead)?;
+    pub fn read_sectors(&self, start: u64, buffer: &mut VolatileSlice) -> Result<()> {
+        let sector_count = buffer.len() / SECTOR_SIZE;
+        self.check_range(start, sector_count)?;
+
+        // Copy runs of consecutive sectors which are all either in the overlay
+        // or in the base image.
+        let is_written = |n: usize| self.is_written(start + n as u64);
+        let mut n = 0;
+        while n < sector_count {
+            let written = is_written(n);
+            let mut end = n + 1;
+            while end < sector_count && is_written(end) == written {
+                end += 1;
+            }
+            let sector = start + n as u64;
+            let src = if written {
+                self.overlay_slice(sector, end - n)?
+            } else {
+                self.base_slice(sector, end - n)?
+            };
+            let dst = buffer.subslice(n * SECTOR_SIZE, (end - n) * SECTOR_SIZE)
+                .expect("Out of bounds in MemoryOverlay::read_sectors()");
+            src.copy_to_volatile_slice(dst);
+            n = end;
+        }
         Ok(())
     }
-
-}
\ No newline at end of file
+}
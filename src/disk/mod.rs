@@ -1,17 +1,29 @@
 use std::{io, result, cmp};
 use std::fs::File;
 use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::FileExt;
 use std::io::{SeekFrom, Seek};
 
 mod realmfs;
 mod raw;
 mod memory;
+mod readahead;
+mod probe;
+mod commit;
+mod direct;
+mod provision;
 
 pub use raw::RawDiskImage;
+pub use raw::CacheMode;
 pub use realmfs::RealmFSImage;
+pub use probe::{DiskFormat, probe_format, probe_supported_format};
+pub use provision::{CloneMethod, create_sparse_image, clone_image};
 use std::path::PathBuf;
 use thiserror::Error;
 use vm_memory::VolatileSlice;
+use vm_memory::mmap::MmapRegionError;
+use crate::util::JsonValue;
+use crate::io::stats::DeviceStats;
 
 const SECTOR_SIZE: usize = 512;
 
@@ -22,6 +34,16 @@ pub enum OpenType {
     MemoryOverlay,
 }
 
+impl OpenType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpenType::ReadOnly => "read-only",
+            OpenType::ReadWrite => "read-write",
+            OpenType::MemoryOverlay => "memory-overlay",
+        }
+    }
+}
+
 pub trait DiskImage: Sync+Send {
     fn open(&mut self) -> Result<()>;
     fn read_only(&self) -> bool;
@@ -40,13 +62,37 @@ pub trait DiskImage: Sync+Send {
     }
     fn write_sectors(&mut self, start_sector: u64, buffer: &VolatileSlice) -> Result<()>;
     fn read_sectors(&mut self, start_sector: u64, buffer: &mut VolatileSlice) -> Result<()>;
+    /// Make all completed writes durable on the underlying storage.
     fn flush(&mut self) -> Result<()> { Ok(()) }
 
+    /// Called once after the VM has stopped. Images opened with a memory
+    /// overlay write the changes back to the image here if requested.
+    fn close(&mut self) -> Result<()> { Ok(()) }
+
     fn disk_image_id(&self) -> &[u8];
+
+    /// The label assigned to the disk by the user, which is reported to the
+    /// guest as the serial number of the device.
+    fn label(&self) -> Option<&str> { None }
+
+    /// The volume label of the filesystem on the image, if it has one which
+    /// can be recognized.
+    fn volume_label(&self) -> Option<String> { None }
+
+    /// Add any statistics maintained by the disk image to `stats`.
+    fn register_stats(&self, _stats: &DeviceStats) {}
+
+    fn describe(&self) -> JsonValue {
+        JsonValue::object()
+            .field("read_only", self.read_only())
+            .field("sectors", self.sector_count())
+    }
 }
 
+/// Maximum length of the device serial number, and so of a disk label.
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
+
 fn generate_disk_image_id(disk_file: &File) -> Vec<u8> {
-    const VIRTIO_BLK_ID_BYTES: usize = 20;
     let meta = match disk_file.metadata() {
         Ok(meta) => meta,
         Err(_) => return vec![0u8; VIRTIO_BLK_ID_BYTES]
@@ -57,6 +103,28 @@ fn generate_disk_image_id(disk_file: &File) -> Vec<u8> {
     Vec::from(&bytes[..len])
 }
 
+// Read the volume label from an ext2/3/4 superblock of a filesystem starting
+// at byte `offset` of `file`
+fn read_ext4_label(file: &File, offset: u64) -> io::Result<Option<String>> {
+    const SUPERBLOCK_OFFSET: u64 = 1024;
+    const MAGIC_OFFSET: usize = 0x38;
+    const LABEL_OFFSET: usize = 0x78;
+    const LABEL_LEN: usize = 16;
+    const EXT4_MAGIC: u16 = 0xEF53;
+
+    let mut sb = [0u8; LABEL_OFFSET + LABEL_LEN];
+    file.read_exact_at(&mut sb, offset + SUPERBLOCK_OFFSET)?;
+    if u16::from_le_bytes([sb[MAGIC_OFFSET], sb[MAGIC_OFFSET + 1]]) != EXT4_MAGIC {
+        return Ok(None);
+    }
+    let label = &sb[LABEL_OFFSET..];
+    let len = label.iter().position(|&b| b == 0).unwrap_or(LABEL_LEN);
+    if len == 0 {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&label[..len]).into_owned()))
+}
+
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug,Error)]
@@ -73,12 +141,28 @@ pub enum Error {
     DiskRead(io::Error),
     #[error("error writing to disk image: {0}")]
     DiskWrite(io::Error),
+    #[error("error flushing disk image: {0}")]
+    DiskFlush(io::Error),
     #[error("error seeking to offset on disk image: {0}")]
     DiskSeek(io::Error),
     #[error("attempt to access invalid sector offset {0}")]
     BadSectorOffset(u64),
     #[error("failed to create memory overlay: {0}")]
     MemoryOverlayCreate(memfd::Error),
+    #[error("failed to prepare memory overlay: {0}")]
+    MemoryOverlayFile(io::Error),
+    #[error("failed to map memory overlay: {0}")]
+    MemoryOverlayMap(MmapRegionError),
+    #[error("failed to commit changes to {0}: {1}")]
+    OverlayCommit(PathBuf, Box<Error>),
     #[error("disk not open")]
     NotOpen,
+    #[error("disk image {0} is in use by another VM, use --force to open it anyway")]
+    DiskLocked(PathBuf),
+    #[error("{0} is not a block device")]
+    NotBlockDevice(PathBuf),
+    #[error("{0} is a host block device, which can only be attached read-only")]
+    BlockDeviceWritable(PathBuf),
+    #[error("disk image {0} is in {1} format, which is not supported. Convert it to a raw image first, for example with qemu-img convert -O raw")]
+    UnsupportedFormat(PathBuf, &'static str),
 }
\ No newline at end of file
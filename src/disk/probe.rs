@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::disk::{Error, Result};
+
+// Citadel image header at the start of a realmfs image
+const REALMFS_MAGIC: &[u8] = b"SGOS";
+const QCOW_MAGIC: &[u8] = b"QFI\xfb";
+const VMDK_MAGIC: &[u8] = b"KDMV";
+const VHDX_MAGIC: &[u8] = b"vhdxfile";
+// Standard identifier of the first volume descriptor, after its type byte
+const ISO9660_MAGIC: &[u8] = b"CD001";
+const ISO9660_MAGIC_OFFSET: usize = 0x8001;
+
+/// The format of a disk image as recognized from its first bytes
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum DiskFormat {
+    Raw,
+    RealmFS,
+    Qcow2,
+    Vmdk,
+    Vhdx,
+    Iso9660,
+}
+
+impl DiskFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            DiskFormat::Raw => "raw",
+            DiskFormat::RealmFS => "realmfs",
+            DiskFormat::Qcow2 => "qcow2",
+            DiskFormat::Vmdk => "vmdk",
+            DiskFormat::Vhdx => "vhdx",
+            DiskFormat::Iso9660 => "iso9660",
+        }
+    }
+
+    /// Formats pH can attach as a block device
+    pub fn is_supported(self) -> bool {
+        matches!(self, DiskFormat::Raw | DiskFormat::RealmFS | DiskFormat::Iso9660)
+    }
+
+    fn from_header(header: &[u8]) -> DiskFormat {
+        if header.starts_with(REALMFS_MAGIC) {
+            DiskFormat::RealmFS
+        } else if header.starts_with(QCOW_MAGIC) {
+            DiskFormat::Qcow2
+        } else if header.starts_with(VMDK_MAGIC) {
+            DiskFormat::Vmdk
+        } else if header.starts_with(VHDX_MAGIC) {
+            DiskFormat::Vhdx
+        } else if header.get(ISO9660_MAGIC_OFFSET..).map_or(false, |h| h.starts_with(ISO9660_MAGIC)) {
+            DiskFormat::Iso9660
+        } else {
+            DiskFormat::Raw
+        }
+    }
+}
+
+fn read_header(path: &Path) -> io::Result<Vec<u8>> {
+    let len = ISO9660_MAGIC_OFFSET + ISO9660_MAGIC.len();
+    let mut header = Vec::with_capacity(len);
+    File::open(path)?
+        .take(len as u64)
+        .read_to_end(&mut header)?;
+    Ok(header)
+}
+
+/// Recognize the format of the disk image at `path`. Any image without a
+/// known signature is treated as raw.
+pub fn probe_format(path: &Path) -> Result<DiskFormat> {
+    if !path.exists() {
+        return Err(Error::ImageDoesntExit(path.to_path_buf()));
+    }
+    let header = read_header(path)
+        .map_err(|e| Error::DiskOpen(path.to_path_buf(), e))?;
+    Ok(DiskFormat::from_header(&header))
+}
+
+/// Like `probe_format()`, but fails for formats which cannot be attached
+pub fn probe_supported_format(path: &Path) -> Result<DiskFormat> {
+    let format = probe_format(path)?;
+    if !format.is_supported() {
+        return Err(Error::UnsupportedFormat(path.to_path_buf(), format.name()));
+    }
+    Ok(format)
+}
@@ -0,0 +1,129 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use libc::c_ulong;
+
+use crate::disk::SECTOR_SIZE;
+use crate::system::ioctl::ioctl_with_val;
+
+// _IOW(0x94, 9, int), share the extents of another file
+const FICLONE: c_ulong = 0x4004_9409;
+
+// Size of the buffer used to copy data between holes
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+
+/// How `clone_image()` created the copy of an image
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum CloneMethod {
+    /// The copy shares the extents of the original until either is written
+    Reflink,
+    /// The data was copied, leaving the holes of the original as holes
+    SparseCopy,
+}
+
+///
+/// Create a raw disk image of `size` bytes at `path` without allocating any
+/// space for it. The image reads as zeros until the guest writes to it.
+///
+/// `size` must be a multiple of the 512 byte sector size, and the file must
+/// not already exist.
+///
+pub fn create_sparse_image<P: AsRef<Path>>(path: P, size: u64) -> io::Result<()> {
+    if size == 0 || size % SECTOR_SIZE as u64 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("image size {} is not a non-zero multiple of {} bytes", size, SECTOR_SIZE)));
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path.as_ref())?;
+    file.set_len(size)?;
+    file.sync_all()
+}
+
+///
+/// Copy the disk image at `src` to a new file at `dst`.
+///
+/// Where the filesystem supports it (btrfs, xfs) the copy is a reflink which
+/// takes no time or space. Otherwise the data is copied and any holes in
+/// `src` stay unallocated in `dst`. If the copy fails `dst` is removed.
+///
+pub fn clone_image<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> io::Result<CloneMethod> {
+    let image = File::open(src.as_ref())?;
+    let copy = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(dst.as_ref())?;
+
+    let result = reflink_or_copy(&image, &copy)
+        .and_then(|method| {
+            copy.set_permissions(image.metadata()?.permissions())?;
+            copy.sync_all()?;
+            Ok(method)
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(dst.as_ref());
+    }
+    result
+}
+
+/// Make the empty file `dst` a copy of `src`, as a reflink if possible
+pub(crate) fn reflink_or_copy(src: &File, dst: &File) -> io::Result<CloneMethod> {
+    let cloned = unsafe { ioctl_with_val(dst.as_raw_fd(), FICLONE, src.as_raw_fd() as c_ulong) };
+    if cloned.is_ok() {
+        return Ok(CloneMethod::Reflink);
+    }
+    sparse_copy(src, dst)?;
+    Ok(CloneMethod::SparseCopy)
+}
+
+// Copy each range of data which SEEK_DATA and SEEK_HOLE find in `src`. On a
+// filesystem which does not track holes the whole file is one data range.
+fn sparse_copy(src: &File, dst: &File) -> io::Result<()> {
+    let len = src.metadata()?.len();
+    let mut buffer = vec![0u8; COPY_CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < len {
+        let start = match seek(src, offset, libc::SEEK_DATA)? {
+            Some(start) => start,
+            // Only a hole remains
+            None => break,
+        };
+        let end = seek(src, start, libc::SEEK_HOLE)?.unwrap_or(len);
+        copy_range(src, dst, start, end, &mut buffer)?;
+        offset = end;
+    }
+    // Extends the copy over a hole at the end of the image
+    dst.set_len(len)
+}
+
+fn copy_range(src: &File, dst: &File, start: u64, end: u64, buffer: &mut [u8]) -> io::Result<()> {
+    let mut offset = start;
+    while offset < end {
+        let n = buffer.len().min((end - offset) as usize);
+        src.read_exact_at(&mut buffer[..n], offset)?;
+        dst.write_all_at(&buffer[..n], offset)?;
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+// lseek() with SEEK_DATA or SEEK_HOLE, returning `None` if there is no more
+// data after `offset`
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    let pos = unsafe { libc::lseek64(file.as_raw_fd(), offset as libc::off64_t, whence) };
+    if pos >= 0 {
+        Ok(Some(pos as u64))
+    } else {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENXIO) {
+            Ok(None)
+        } else {
+            Err(err)
+        }
+    }
+}
@@ -1,74 +1,292 @@
-use crate::disk::{Result, Error, DiskImage, SECTOR_SIZE, generate_disk_image_id, OpenType};
+use crate::disk::{Result, Error, DiskImage, SECTOR_SIZE, VIRTIO_BLK_ID_BYTES, generate_disk_image_id, read_ext4_label, OpenType};
 use std::fs::{File, OpenOptions};
-use std::io;
+use std::{io, mem};
 use std::io::{SeekFrom, Seek};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
 use crate::disk::Error::DiskRead;
 use crate::disk::memory::MemoryOverlay;
+use crate::disk::commit::commit_overlay;
+use crate::disk::readahead::ReadAhead;
+use crate::disk::direct::{DirectIo, DIRECT_ALIGN};
 use std::path::{PathBuf, Path};
+use std::sync::Arc;
 use vm_memory::{ReadVolatile, VolatileSlice, WriteVolatile};
+use crate::util::JsonValue;
+use crate::io::stats::{Counter, DeviceStats, Gauge};
+use crate::system::ioctl::ioctl_with_mut_ref;
+
+// _IOR(0x12, 114, size_t), size of a block device in bytes
+const BLKGETSIZE64: libc::c_ulong = 0x8008_1272;
+
+/// How writes to a read-write disk image are made durable.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum CacheMode {
+    /// Flush requests from the guest and closing the image sync the file to storage.
+    WriteBack,
+    /// Never sync the file. Data may be lost or the image left inconsistent if the
+    /// host crashes, but guest flushes complete immediately.
+    Unsafe,
+    /// Open the image with `O_DIRECT` so that reads and writes bypass the host page
+    /// cache, and sync it on flush requests as with `WriteBack`. Images attached with
+    /// a memory overlay are read through a mapping and still use the page cache.
+    Direct,
+}
+
+impl CacheMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CacheMode::WriteBack => "writeback",
+            CacheMode::Unsafe => "unsafe",
+            CacheMode::Direct => "direct",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CacheMode> {
+        match name {
+            "writeback" => Some(CacheMode::WriteBack),
+            "unsafe" => Some(CacheMode::Unsafe),
+            "direct" => Some(CacheMode::Direct),
+            _ => None,
+        }
+    }
+}
 
 pub struct RawDiskImage {
     path: PathBuf,
     open_type: OpenType,
+    block_device: bool,
+    cache_mode: CacheMode,
     file: Option<File>,
     offset: usize,
     nsectors: u64,
     disk_image_id: Vec<u8>,
+    label: Option<String>,
     overlay: Option<MemoryOverlay>,
+    overlay_size: Arc<Gauge>,
+    commit_overlay: bool,
+    ignore_lock: bool,
+    direct: Option<DirectIo>,
+    readahead: Option<ReadAhead>,
+    readahead_bytes: Arc<Counter>,
+}
+
+// Take an OFD lock on the whole image, exclusive when it is opened for
+// writing and shared otherwise, so that an image written by one VM cannot be
+// opened by another. The lock is released when the file is closed.
+fn lock_image(file: &File, exclusive: bool) -> io::Result<()> {
+    let mut lock: libc::flock = unsafe { mem::zeroed() };
+    lock.l_type = if exclusive { libc::F_WRLCK } else { libc::F_RDLCK } as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    // l_start and l_len of 0 cover the whole file
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_SETLK, &lock) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn is_block_device(path: &Path) -> bool {
+    path.metadata()
+        .map(|meta| meta.file_type().is_block_device())
+        .unwrap_or(false)
+}
+
+// Size in bytes of the image at `path`. The metadata of a host block device
+// has a size of 0, so the kernel is asked for the size of the device instead.
+fn image_size(path: &Path) -> io::Result<u64> {
+    let meta = path.metadata()?;
+    if !meta.file_type().is_block_device() {
+        return Ok(meta.len());
+    }
+    let file = File::open(path)?;
+    let mut size = 0u64;
+    unsafe {
+        ioctl_with_mut_ref(file.as_raw_fd(), BLKGETSIZE64, &mut size)?;
+    }
+    Ok(size)
 }
 
 impl RawDiskImage {
     fn get_nsectors(path: &Path, offset: usize) -> Result<u64> {
-        if let Ok(meta) = path.metadata() {
-            Ok((meta.len() - offset as u64) / SECTOR_SIZE as u64)
-        } else {
-            Err(Error::ImageDoesntExit(path.to_path_buf()))
+        match image_size(path) {
+            Ok(len) => Ok((len - offset as u64) / SECTOR_SIZE as u64),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::ImageDoesntExit(path.to_path_buf())),
+            Err(e) => Err(Error::DiskOpen(path.to_path_buf(), e)),
         }
     }
 
-    #[allow(dead_code)]
     pub fn new<P: Into<PathBuf>>(path: P, open_type: OpenType) -> Result<Self> {
         Self::new_with_offset(path, open_type, 0)
     }
 
+    /// Attach the host block device at `path`, such as a logical volume or a
+    /// partition, read-only. Fails if `path` is not a block device.
+    pub fn new_block_device<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        if !path.exists() {
+            return Err(Error::ImageDoesntExit(path));
+        }
+        if !is_block_device(&path) {
+            return Err(Error::NotBlockDevice(path));
+        }
+        Self::new(path, OpenType::ReadOnly)
+    }
+
     pub fn new_with_offset<P: Into<PathBuf>>(path: P, open_type: OpenType, offset: usize) -> Result<Self> {
         let path = path.into();
+        // A host block device is never written, not even through a memory
+        // overlay which could be committed back to it
+        let block_device = is_block_device(&path);
+        if block_device && open_type != OpenType::ReadOnly {
+            return Err(Error::BlockDeviceWritable(path));
+        }
         let nsectors = Self::get_nsectors(&path, offset)?;
         Ok(RawDiskImage {
             path,
             open_type,
+            block_device,
+            cache_mode: CacheMode::WriteBack,
             file: None,
             offset,
             nsectors,
             disk_image_id: Vec::new(),
+            label: None,
             overlay: None,
+            overlay_size: Arc::new(Gauge::default()),
+            commit_overlay: false,
+            ignore_lock: false,
+            direct: None,
+            readahead: None,
+            readahead_bytes: Arc::new(Counter::default()),
         })
     }
 
+    /// Label the disk so that the guest can find it by name rather than by
+    /// its position. Labels longer than `VIRTIO_BLK_ID_BYTES` are truncated.
+    pub fn set_label(&mut self, label: &str) {
+        let mut len = label.len().min(VIRTIO_BLK_ID_BYTES);
+        while !label.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.label = Some(label[..len].to_string());
+    }
+
+    pub fn set_cache_mode(&mut self, cache_mode: CacheMode) {
+        self.cache_mode = cache_mode;
+    }
+
+    /// Write the changes kept in a memory overlay back to the image when the
+    /// VM stops. Has no effect unless the image is opened with
+    /// `OpenType::MemoryOverlay`.
+    pub fn set_commit_overlay(&mut self, commit: bool) {
+        self.commit_overlay = commit;
+    }
+
+    /// Open the image even if another VM holds a conflicting lock on it.
+    pub fn set_ignore_lock(&mut self, ignore: bool) {
+        self.ignore_lock = ignore;
+    }
+
+    fn lock(&self, file: &File) -> Result<()> {
+        match lock_image(file, self.open_type == OpenType::ReadWrite) {
+            Ok(()) => Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::EAGAIN) || e.raw_os_error() == Some(libc::EACCES) => {
+                if !self.ignore_lock {
+                    return Err(Error::DiskLocked(self.path.clone()));
+                }
+                warn!("Disk image {} is in use by another VM, opening it anyway", self.path.display());
+                Ok(())
+            }
+            // Some filesystems do not support locks
+            Err(e) => {
+                warn!("Unable to lock disk image {}: {}", self.path.display(), e);
+                Ok(())
+            }
+        }
+    }
+
+    // O_DIRECT transfers whole blocks, so the image and the data in it must
+    // start and end on a block boundary
+    fn use_direct(&self, len: u64) -> bool {
+        if self.cache_mode != CacheMode::Direct || self.open_type == OpenType::MemoryOverlay {
+            return false;
+        }
+        if len % DIRECT_ALIGN as u64 != 0 || self.offset % DIRECT_ALIGN != 0 {
+            warn!("Disk image {} is not a multiple of {} bytes, not using direct I/O", self.path.display(), DIRECT_ALIGN);
+            return false;
+        }
+        true
+    }
+
+    fn open_file(&self, direct: bool) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(self.open_type == OpenType::ReadWrite)
+            .custom_flags(if direct { libc::O_DIRECT } else { 0 })
+            .open(&self.path)
+    }
+
+    // Only writes which go directly to the image file need to be synced
+    fn needs_sync(&self) -> bool {
+        self.open_type == OpenType::ReadWrite && self.cache_mode != CacheMode::Unsafe
+    }
+
+    pub(super) fn describe_with_format(&self, format: &str) -> JsonValue {
+        JsonValue::object()
+            .field("format", format)
+            .field("path", self.path.display().to_string())
+            .field("mode", self.open_type.name())
+            .field("cache", self.cache_mode.name())
+            .field("direct", self.direct.is_some())
+            .field("read_only", self.read_only())
+            .field("block_device", self.block_device)
+            .field("offset", self.offset)
+            .field("sectors", self.nsectors)
+            .field("label", self.label.clone())
+            .field("commit_overlay", self.commit_overlay)
+    }
 }
 
 impl DiskImage for RawDiskImage {
     fn open(&mut self) -> Result<()> {
-        let meta = self.path.metadata()
+        // Already open, the device is being restarted after a reset
+        if self.file.is_some() {
+            return Ok(());
+        }
+        let len = image_size(&self.path)
             .map_err(|e| Error::DiskOpen(self.path.clone(), e))?;
 
-        if meta.len() < self.offset as u64 {
+        if len < self.offset as u64 {
             return Err(Error::DiskOpenTooShort(self.path.clone()))
         }
 
-        let file = OpenOptions::new()
-            .read(true)
-            .write(self.open_type == OpenType::ReadWrite)
-            .open(&self.path)
-            .map_err(|e| Error::DiskOpen(self.path.clone(), e))?;
+        let mut direct = self.use_direct(len);
+        let file = match self.open_file(direct) {
+            // Filesystems such as tmpfs do not support O_DIRECT
+            Err(e) if direct && e.raw_os_error() == Some(libc::EINVAL) => {
+                warn!("Disk image {} cannot be opened for direct I/O, using the page cache", self.path.display());
+                direct = false;
+                self.open_file(false)
+            }
+            result => result,
+        }.map_err(|e| Error::DiskOpen(self.path.clone(), e))?;
+        self.lock(&file)?;
 
-        self.disk_image_id = generate_disk_image_id(&file);
-        self.file = Some(file);
+        self.disk_image_id = match self.label {
+            Some(ref label) => label.as_bytes().to_vec(),
+            None => generate_disk_image_id(&file),
+        };
 
         if self.open_type == OpenType::MemoryOverlay {
-            let overlay = MemoryOverlay::new()?;
+            let overlay = MemoryOverlay::new(&file, self.offset, self.nsectors, self.overlay_size.clone())?;
             self.overlay = Some(overlay);
         }
+        if direct {
+            self.direct = Some(DirectIo::new());
+        } else {
+            self.readahead = Some(ReadAhead::new(len, self.readahead_bytes.clone()));
+        }
+        self.file = Some(file);
         Ok(())
     }
 
@@ -102,11 +320,16 @@ impl DiskImage for RawDiskImage {
         if self.read_only() {
             return Err(Error::ReadOnly)
         }
-        self.seek_to_sector(start_sector)?;
         let len = (buffer.len() / SECTOR_SIZE) * SECTOR_SIZE;
-        let file = self.disk_file()?;
         let buffer = buffer.subslice(0, len)
             .expect("Out of bounds in RawDiskImage::write_sectors()");
+        if let (Some(direct), Some(file)) = (self.direct.as_mut(), self.file.as_ref()) {
+            let offset = start_sector * SECTOR_SIZE as u64 + self.offset as u64;
+            return direct.write(file, offset, &buffer)
+                .map_err(Error::DiskWrite);
+        }
+        self.seek_to_sector(start_sector)?;
+        let file = self.disk_file()?;
         file.write_all_volatile(&buffer)
             .map_err(io::Error::other)
             .map_err(Error::DiskWrite)?;
@@ -114,24 +337,83 @@ impl DiskImage for RawDiskImage {
     }
 
     fn read_sectors(&mut self, start_sector: u64, buffer: &mut VolatileSlice) -> Result<()> {
-        if let Some(mut overlay) = self.overlay.take() {
-            let ret = overlay.read_sectors(self, start_sector, buffer);
-            self.overlay.replace(overlay);
-            return ret;
+        // Read-ahead fills the host page cache, which also backs the base
+        // mapping of a memory overlay
+        if let (Some(readahead), Some(file)) = (self.readahead.as_mut(), self.file.as_ref()) {
+            let offset = start_sector * SECTOR_SIZE as u64 + self.offset as u64;
+            readahead.on_read(file, offset, buffer.len() as u64);
+        }
+        if let Some(ref overlay) = self.overlay {
+            return overlay.read_sectors(start_sector, buffer);
         }
 
-        self.seek_to_sector(start_sector)?;
         let len = (buffer.len() / SECTOR_SIZE) * SECTOR_SIZE;
-        let file = self.disk_file()?;
         let mut buffer = buffer.subslice(0, len)
             .expect("Out of bounds in RawDiskImage::read_sectors()");
+        if let (Some(direct), Some(file)) = (self.direct.as_mut(), self.file.as_ref()) {
+            let offset = start_sector * SECTOR_SIZE as u64 + self.offset as u64;
+            return direct.read(file, offset, &buffer)
+                .map_err(DiskRead);
+        }
+        self.seek_to_sector(start_sector)?;
+        let file = self.disk_file()?;
         file.read_exact_volatile(&mut buffer)
             .map_err(io::Error::other)
             .map_err(DiskRead)?;
         Ok(())
     }
 
+    fn flush(&mut self) -> Result<()> {
+        if !self.needs_sync() {
+            return Ok(());
+        }
+        self.disk_file()?
+            .sync_data()
+            .map_err(Error::DiskFlush)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        match self.overlay.take() {
+            Some(overlay) if self.commit_overlay && overlay.is_modified() => {
+                notify!("Committing changes to {}", self.path.display());
+                commit_overlay(&overlay, &self.path)
+                    .map_err(|e| Error::OverlayCommit(self.path.clone(), Box::new(e)))
+            }
+            _ => Ok(()),
+        }
+    }
+
     fn disk_image_id(&self) -> &[u8] {
         &self.disk_image_id
     }
-}
\ No newline at end of file
+
+    fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    fn volume_label(&self) -> Option<String> {
+        let file = File::open(&self.path).ok()?;
+        read_ext4_label(&file, self.offset as u64).ok()?
+    }
+
+    fn register_stats(&self, stats: &DeviceStats) {
+        stats.add_counter("readahead_bytes", self.readahead_bytes.clone());
+        if self.open_type == OpenType::MemoryOverlay {
+            stats.add_gauge("overlay_bytes", self.overlay_size.clone());
+        }
+    }
+
+    fn describe(&self) -> JsonValue {
+        self.describe_with_format("raw")
+    }
+}
+
+impl Drop for RawDiskImage {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            if let Err(err) = self.flush() {
+                warn!("Failed to flush disk image {}: {}", self.path.display(), err);
+            }
+        }
+    }
+}
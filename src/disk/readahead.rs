@@ -0,0 +1,77 @@
+use std::cmp;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use crate::io::stats::Counter;
+
+// Number of back to back sequential reads before read-ahead starts
+const SEQUENTIAL_THRESHOLD: u32 = 2;
+
+const MIN_WINDOW: u64 = 256 * 1024;
+const MAX_WINDOW: u64 = 8 * 1024 * 1024;
+
+///
+/// Detects sequential reads of a disk image and asks the host kernel to read
+/// ahead of them with `posix_fadvise(POSIX_FADV_WILLNEED)`.
+///
+/// The guest issues reads no larger than its request size limit, so without
+/// this a mostly sequential scan of an image (such as booting from a RealmFS
+/// image) waits on the host disk for every request. Once a run of sequential
+/// reads is seen, the window read ahead of the guest starts at `MIN_WINDOW`
+/// and doubles each time the guest catches up with half of it, up to
+/// `MAX_WINDOW`. A read anywhere else ends the run.
+///
+pub struct ReadAhead {
+    // Byte offset in the image file following the previous read
+    next_offset: u64,
+    run: u32,
+    window: u64,
+    // Read-ahead has been requested up to this byte offset
+    ahead_until: u64,
+    file_end: u64,
+    readahead_bytes: Arc<Counter>,
+}
+
+impl ReadAhead {
+    pub fn new(file_end: u64, readahead_bytes: Arc<Counter>) -> Self {
+        ReadAhead {
+            next_offset: 0,
+            run: 0,
+            window: MIN_WINDOW,
+            ahead_until: 0,
+            file_end,
+            readahead_bytes,
+        }
+    }
+
+    /// Record a read of `len` bytes at byte `offset` of `file` and issue
+    /// read-ahead if it continues a sequential run.
+    pub fn on_read(&mut self, file: &File, offset: u64, len: u64) {
+        if offset == self.next_offset {
+            self.run = self.run.saturating_add(1);
+        } else {
+            self.run = 0;
+            self.window = MIN_WINDOW;
+            self.ahead_until = 0;
+        }
+        let end = offset + len;
+        self.next_offset = end;
+
+        if self.run < SEQUENTIAL_THRESHOLD || end + self.window / 2 < self.ahead_until {
+            return;
+        }
+        let start = cmp::max(end, self.ahead_until);
+        let size = cmp::min(self.window, self.file_end.saturating_sub(start));
+        if size == 0 {
+            return;
+        }
+        unsafe {
+            libc::posix_fadvise(file.as_raw_fd(), start as libc::off_t, size as libc::off_t, libc::POSIX_FADV_WILLNEED);
+        }
+        self.readahead_bytes.add(size);
+        self.ahead_until = start + size;
+        if self.window < MAX_WINDOW {
+            self.window *= 2;
+        }
+    }
+}
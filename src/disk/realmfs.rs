@@ -2,6 +2,8 @@ use crate::disk::{Result, DiskImage, SECTOR_SIZE, RawDiskImage, OpenType};
 use std::fs::File;
 use std::path::PathBuf;
 use vm_memory::VolatileSlice;
+use crate::util::JsonValue;
+use crate::io::stats::DeviceStats;
 
 // skip 4096 byte realmfs header
 const HEADER_SECTOR_COUNT: usize = 8;
@@ -18,6 +20,19 @@ impl RealmFSImage {
         let raw = RawDiskImage::new_with_offset(path, open_type, offset)?;
         Ok(RealmFSImage { raw })
     }
+
+    pub fn set_commit_overlay(&mut self, commit: bool) {
+        self.raw.set_commit_overlay(commit)
+    }
+
+    pub fn set_ignore_lock(&mut self, ignore: bool) {
+        self.raw.set_ignore_lock(ignore)
+    }
+
+    /// Label the image as with `RawDiskImage::set_label()`.
+    pub fn set_label(&mut self, label: &str) {
+        self.raw.set_label(label)
+    }
 }
 
 impl DiskImage for RealmFSImage {
@@ -44,7 +59,31 @@ impl DiskImage for RealmFSImage {
         self.raw.read_sectors(start_sector, buffer)
     }
 
+    fn flush(&mut self) -> Result<()> {
+        self.raw.flush()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.raw.close()
+    }
+
     fn disk_image_id(&self) -> &[u8] {
         self.raw.disk_image_id()
     }
+
+    fn label(&self) -> Option<&str> {
+        self.raw.label()
+    }
+
+    fn volume_label(&self) -> Option<String> {
+        self.raw.volume_label()
+    }
+
+    fn register_stats(&self, stats: &DeviceStats) {
+        self.raw.register_stats(stats)
+    }
+
+    fn describe(&self) -> JsonValue {
+        self.raw.describe_with_format("realmfs")
+    }
 }
@@ -0,0 +1,20 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`.
+//!
+//! Each function feeds an arbitrary byte buffer to one of the parsers which
+//! handle guest controlled data. Only available with the `fuzzing` feature.
+
+/// Decode `data` as a sequence of 9p requests handled by a single server.
+pub fn p9_pdu(data: &[u8]) {
+    crate::devices::fuzz_9p_pdu(data)
+}
+
+/// Decode `data` as a virtio-wl command message.
+#[cfg(feature = "wayland")]
+pub fn wl_command(data: &[u8]) {
+    crate::devices::fuzz_wl_command(data)
+}
+
+/// Apply `data` as a sequence of reads and writes to PCI configuration space.
+pub fn pci_config(data: &[u8]) {
+    crate::io::pci::fuzz_config_access(data)
+}
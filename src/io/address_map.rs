@@ -0,0 +1,212 @@
+use std::fmt;
+use std::result;
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use thiserror::Error;
+use crate::io::address::AddressRange;
+use crate::vm::arch;
+
+#[derive(Debug,Error)]
+pub enum Error {
+    #[error("region {0} at {1} overlaps region {2} at {3}")]
+    Collision(String, AddressRange, String, AddressRange),
+    #[error("no space for region {0} of size {1}")]
+    NoSpace(String, usize),
+    #[error("no region named {0}")]
+    NoSuchRegion(String),
+    #[error("region {0} at 0x{1:x} with size {2} is not page aligned")]
+    Misaligned(String, u64, usize),
+    #[error("region {0} has size 0 or extends past the end of the address space")]
+    InvalidSize(String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum RegionKind {
+    /// Guest RAM
+    Ram,
+    /// Window from which PCI BARs are allocated
+    PciMmio,
+    /// Fixed platform devices such as the IOAPIC and local APIC
+    System,
+    /// Host memory shared with devices and mapped on demand
+    DeviceShm,
+    /// Reserved by the user and reported as reserved to the guest
+    Reserved,
+    /// Read-only image supplied by the user, also reported as reserved
+    Rom,
+}
+
+impl fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            RegionKind::Ram => "ram",
+            RegionKind::PciMmio => "pci_mmio",
+            RegionKind::System => "system",
+            RegionKind::DeviceShm => "device_shm",
+            RegionKind::Reserved => "reserved",
+            RegionKind::Rom => "rom",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct MappedRegion {
+    name: String,
+    kind: RegionKind,
+    range: AddressRange,
+}
+
+impl MappedRegion {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    pub fn range(&self) -> AddressRange {
+        self.range
+    }
+}
+
+/// The layout of the guest physical address space.
+///
+/// Every range that is used for something (RAM, the PCI MMIO window, fixed
+/// platform devices, device shared memory) is added here under a name, and
+/// adding a range which overlaps an existing one fails. The allocators for
+/// PCI BARs and device shared memory are created from the ranges recorded
+/// here rather than computing their own.
+#[derive(Clone,Default)]
+pub struct AddressSpaceMap {
+    // Sorted by base address
+    regions: Vec<MappedRegion>,
+}
+
+impl AddressSpaceMap {
+    /// Name of the PCI MMIO window region.
+    pub const PCI_MMIO: &'static str = "pci_mmio";
+    /// Name of the device shared memory region.
+    pub const DEVICE_SHM: &'static str = "device_shm";
+
+    pub fn new() -> Self {
+        AddressSpaceMap { regions: Vec::new() }
+    }
+
+    /// Build the map for the standard layout: RAM from `memory`, the PCI
+    /// MMIO window and fixed platform devices in the hole below 4GB.
+    pub fn with_layout(memory: &GuestMemoryMmap) -> Result<Self> {
+        let mut map = Self::new();
+        for (i, r) in memory.iter().enumerate() {
+            let range = AddressRange::new(r.start_addr().0, r.len() as usize);
+            map.add(&format!("ram{}", i), RegionKind::Ram, range)?;
+        }
+        map.add(Self::PCI_MMIO, RegionKind::PciMmio,
+                AddressRange::new(arch::PCI_MMIO_RESERVED_BASE, arch::PCI_MMIO_WINDOW_SIZE))?;
+        map.add("system", RegionKind::System,
+                AddressRange::new(arch::SYSTEM_RESERVED_BASE, arch::SYSTEM_RESERVED_SIZE))?;
+        Ok(map)
+    }
+
+    /// Add a region at a fixed address.
+    pub fn add(&mut self, name: &str, kind: RegionKind, range: AddressRange) -> Result<()> {
+        if let Some(r) = self.regions.iter().find(|r| overlaps(&r.range, &range)) {
+            return Err(Error::Collision(name.to_string(), range, r.name.clone(), r.range));
+        }
+        let idx = self.regions.iter()
+            .position(|r| r.range.base() > range.base())
+            .unwrap_or(self.regions.len());
+        self.regions.insert(idx, MappedRegion { name: name.to_string(), kind, range });
+        Ok(())
+    }
+
+    /// Add a region of `size` bytes at the lowest address at or above
+    /// `min_base` aligned to `align` which does not overlap any existing
+    /// region.
+    pub fn add_anywhere(&mut self, name: &str, kind: RegionKind, size: usize, align: u64, min_base: u64) -> Result<AddressRange> {
+        let no_space = || Error::NoSpace(name.to_string(), size);
+        let mut base = align_up(min_base, align).ok_or_else(no_space)?;
+        loop {
+            let range = AddressRange::checked_new(base, size).ok_or_else(no_space)?;
+            match self.regions.iter().find(|r| overlaps(&r.range, &range)) {
+                Some(r) => base = align_up(r.range.end(), align).ok_or_else(no_space)?,
+                None => {
+                    self.add(name, kind, range)?;
+                    return Ok(range);
+                }
+            }
+        }
+    }
+
+    /// Add a user requested reserved region. It must be page aligned and may
+    /// not overlap RAM or any region used by pH.
+    pub fn reserve(&mut self, name: &str, base: u64, size: usize) -> Result<()> {
+        check_page_aligned(name, base, size)?;
+        self.add_user_region(name, RegionKind::Reserved, base, size)
+    }
+
+    /// Add the region of a read-only image supplied by the user. An image
+    /// mapped as guest memory must be page aligned, an image served from the
+    /// MMIO bus may have any address and size.
+    pub fn add_rom(&mut self, name: &str, base: u64, size: usize, page_aligned: bool) -> Result<()> {
+        if page_aligned {
+            check_page_aligned(name, base, size)?;
+        }
+        self.add_user_region(name, RegionKind::Rom, base, size)
+    }
+
+    fn add_user_region(&mut self, name: &str, kind: RegionKind, base: u64, size: usize) -> Result<()> {
+        let range = AddressRange::checked_new(base, size)
+            .ok_or_else(|| Error::InvalidSize(name.to_string()))?;
+        self.add(name, kind, range)
+    }
+
+    /// Reserved and ROM regions as (base, size) pairs for the guest memory map.
+    pub fn reserved_ranges(&self) -> Vec<(u64, u64)> {
+        self.regions.iter()
+            .filter(|r| r.kind == RegionKind::Reserved || r.kind == RegionKind::Rom)
+            .map(|r| (r.range.base(), r.range.size() as u64))
+            .collect()
+    }
+
+    pub fn region(&self, name: &str) -> Result<AddressRange> {
+        self.regions.iter()
+            .find(|r| r.name == name)
+            .map(|r| r.range)
+            .ok_or_else(|| Error::NoSuchRegion(name.to_string()))
+    }
+
+    pub fn regions(&self) -> &[MappedRegion] {
+        &self.regions
+    }
+
+    /// The regions as text for guest tooling, one region per line with the
+    /// name, type, base and size separated by spaces and the numbers in hex.
+    /// ph-init reads this from the boot filesystem.
+    pub fn layout_file(&self) -> String {
+        let mut s = String::from("# name type base size\n");
+        for r in &self.regions {
+            s.push_str(&format!("{} {} 0x{:x} 0x{:x}\n", r.name, r.kind, r.range.base(), r.range.size()));
+        }
+        s
+    }
+}
+
+fn check_page_aligned(name: &str, base: u64, size: usize) -> Result<()> {
+    const PAGE_MASK: u64 = 0xfff;
+    if base & PAGE_MASK != 0 || size as u64 & PAGE_MASK != 0 {
+        return Err(Error::Misaligned(name.to_string(), base, size));
+    }
+    Ok(())
+}
+
+fn overlaps(a: &AddressRange, b: &AddressRange) -> bool {
+    a.base() < b.end() && b.base() < a.end()
+}
+
+fn align_up(addr: u64, align: u64) -> Option<u64> {
+    let mask = align - 1;
+    addr.checked_add(mask).map(|a| a & !mask)
+}
@@ -14,6 +14,15 @@ pub enum Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// A device which handles accesses to a range of a `Bus`.
+///
+/// `read()` and `write()` are accesses made by the guest. A read may have side
+/// effects, such as removing a byte from a receive FIFO or acknowledging an
+/// interrupt. `peek()` returns the value a read would return without changing
+/// any device state so that devices can be inspected from outside the guest.
+///
+/// Accesses are 1, 2, 4 or 8 bytes wide. Devices ignore writes of a width they
+/// do not support and return zero for such reads.
 pub trait BusDevice {
     fn read(&mut self, offset: u64, data: &mut [u8]) {
         let (_,_) = (offset, data);
@@ -22,6 +31,13 @@ pub trait BusDevice {
     fn write(&mut self, offset: u64, data: &[u8]) {
         let (_,_) = (offset, data);
     }
+
+    /// Read without side effects. Returns `false` if the device does not
+    /// support peeking, in which case `data` is untouched.
+    fn peek(&self, offset: u64, data: &mut [u8]) -> bool {
+        let (_,_) = (offset, data);
+        false
+    }
 }
 
 #[derive(Debug,Copy,Clone)]
@@ -127,6 +143,20 @@ impl Bus {
         }
     }
 
+    /// Reads data from the device that owns the range containing `addr` without
+    /// side effects on the device.
+    ///
+    /// Returns true on success, otherwise `data` is untouched.
+    pub fn peek(&self, addr: u64, data: &mut [u8]) -> bool {
+        if let Some((offset, dev)) = self.get_device(addr) {
+            dev.lock()
+                .expect("Failed to acquire device lock")
+                .peek(offset, data)
+        } else {
+            false
+        }
+    }
+
     /// Writes `data` to the device that owns the range containing `addr`.
     ///
     /// Returns true on success, otherwise `data` is untouched.
@@ -73,6 +73,7 @@ pub enum ReadableInt {
     Byte(u8, [u8; 1]),
     Word(u16, [u8; 2]),
     DWord(u32, [u8; 4]),
+    QWord(u64, [u8; 8]),
 }
 
 impl ReadableInt {
@@ -86,19 +87,26 @@ impl ReadableInt {
     pub fn new_dword(n: u32) -> Self {
         Self::DWord(n, n.to_le_bytes())
     }
+    pub fn new_qword(n: u64) -> Self {
+        Self::QWord(n, n.to_le_bytes())
+    }
 
     fn as_bytes(&self) -> &[u8] {
         match self {
             ReadableInt::Byte(_, bs) => bs,
             ReadableInt::Word(_, bs) => bs,
             ReadableInt::DWord(_, bs) => bs,
+            ReadableInt::QWord(_, bs) => bs,
         }
     }
 
+    /// Copy the value into `buffer`. If the buffer is wider than the value
+    /// the remaining bytes are zeroed, and if it is narrower nothing is copied.
     pub fn read(&self, buffer: &mut [u8]) {
         let bs = self.as_bytes();
         if buffer.len() >= bs.len() {
             buffer[..bs.len()].copy_from_slice(bs);
+            buffer[bs.len()..].fill(0);
         }
     }
 }
@@ -119,6 +127,11 @@ impl From<u32> for ReadableInt {
         ReadableInt::new_dword(value)
     }
 }
+impl From<u64> for ReadableInt {
+    fn from(value: u64) -> Self {
+        ReadableInt::new_qword(value)
+    }
+}
 
 impl Debug for ReadableInt {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -126,6 +139,7 @@ impl Debug for ReadableInt {
             ReadableInt::Byte(n, _) => write!(f, "Byte({})", n),
             ReadableInt::Word(n, _) => write!(f, "Word({})", n),
             ReadableInt::DWord(n, _) => write!(f, "DWord({})", n),
+            ReadableInt::QWord(n, _) => write!(f, "QWord({})", n),
         }
     }
 }
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+use crate::devices::ioapic::Ioapic;
+use crate::system::fd_audit;
+use crate::vm::KvmVm;
+
+/// Creates level triggered interrupt lines. Each IRQ is registered with KVM
+/// only once, so several devices can share an IRQ by each requesting a
+/// `LevelIrq` for it.
+///
+/// With a split irqchip the lines drive the pins of the userspace IOAPIC
+/// directly instead of going through irqfds.
+#[derive(Clone)]
+pub struct IrqManager {
+    kvm_vm: KvmVm,
+    lines: Arc<Mutex<HashMap<u8, Arc<LevelIrqLine>>>>,
+    ioapic: Option<Arc<Mutex<Ioapic>>>,
+}
+
+impl IrqManager {
+    pub fn new(kvm_vm: KvmVm) -> Self {
+        let ioapic = if kvm_vm.is_split_irqchip() {
+            Some(Arc::new(Mutex::new(Ioapic::new(kvm_vm.clone()))))
+        } else {
+            None
+        };
+        IrqManager {
+            kvm_vm,
+            lines: Arc::new(Mutex::new(HashMap::new())),
+            ioapic,
+        }
+    }
+
+    /// The userspace IOAPIC if the VM was created with a split irqchip.
+    pub fn ioapic(&self) -> Option<Arc<Mutex<Ioapic>>> {
+        self.ioapic.clone()
+    }
+
+    /// Forward an EOI exit for a level triggered `vector` to the userspace
+    /// IOAPIC.
+    pub fn end_of_interrupt(&self, vector: u8) {
+        if let Some(ioapic) = self.ioapic.as_ref() {
+            ioapic.lock().unwrap().end_of_interrupt(vector);
+        }
+    }
+
+    /// Return a new handle for a device to raise level triggered interrupt
+    /// `irq`. Other devices may hold handles for the same IRQ.
+    pub fn level_irq(&self, irq: u8) -> io::Result<LevelIrq> {
+        let mut lines = self.lines.lock().unwrap();
+        let line = match lines.get(&irq) {
+            Some(line) => line.clone(),
+            None => {
+                let line = match self.ioapic.as_ref() {
+                    Some(ioapic) => LevelIrqLine::new_ioapic(ioapic.clone(), irq),
+                    None => LevelIrqLine::register(&self.kvm_vm, irq)?,
+                };
+                lines.insert(irq, line.clone());
+                line
+            }
+        };
+        Ok(LevelIrq {
+            line,
+            asserted: AtomicBool::new(false),
+        })
+    }
+}
+
+enum LineBackend {
+    Irqfd {
+        trigger_event: EventFd,
+        resample_event: EventFd,
+    },
+    Ioapic(Arc<Mutex<Ioapic>>),
+}
+
+/// A shared interrupt line together with a count of the devices currently
+/// asserting it.
+///
+/// For an irqfd registered with a resample eventfd, KVM lowers the line when
+/// the guest acknowledges the interrupt and signals the resample eventfd. If
+/// any device is still asserting the line at that point it is raised again.
+///
+/// For a userspace IOAPIC pin the level is set from the count whenever it
+/// changes and the IOAPIC handles redelivery itself.
+struct LevelIrqLine {
+    irq: u8,
+    backend: LineBackend,
+    asserted: AtomicUsize,
+}
+
+impl LevelIrqLine {
+    fn register(kvm_vm: &KvmVm, irq: u8) -> io::Result<Arc<Self>> {
+        let trigger_event = EventFd::new(0)?;
+        let resample_event = EventFd::new(0)?;
+        fd_audit::register(trigger_event.as_raw_fd(), "level irq trigger eventfd");
+        fd_audit::register(resample_event.as_raw_fd(), "level irq resample eventfd");
+        kvm_vm.vm_fd()
+            .register_irqfd_with_resample(&trigger_event, &resample_event, irq as u32)?;
+
+        let line = Arc::new(LevelIrqLine {
+            irq,
+            backend: LineBackend::Irqfd { trigger_event, resample_event },
+            asserted: AtomicUsize::new(0),
+        });
+        thread::spawn({
+            let line = line.clone();
+            move || line.resample_loop()
+        });
+        Ok(line)
+    }
+
+    fn new_ioapic(ioapic: Arc<Mutex<Ioapic>>, irq: u8) -> Arc<Self> {
+        Arc::new(LevelIrqLine {
+            irq,
+            backend: LineBackend::Ioapic(ioapic),
+            asserted: AtomicUsize::new(0),
+        })
+    }
+
+    fn trigger(&self) {
+        match &self.backend {
+            LineBackend::Irqfd { trigger_event, .. } => {
+                if let Err(e) = trigger_event.write(1) {
+                    warn!("Failed to raise IRQ {}: {}", self.irq, e);
+                }
+            }
+            LineBackend::Ioapic(..) => self.update_ioapic(),
+        }
+    }
+
+    // Called after every change to the assert count. The count is read with the
+    // IOAPIC locked so the last update always leaves the pin at the right level.
+    fn update_ioapic(&self) {
+        if let LineBackend::Ioapic(ioapic) = &self.backend {
+            let mut ioapic = ioapic.lock().unwrap();
+            ioapic.set_level(self.irq, self.asserted.load(Ordering::SeqCst) > 0);
+        }
+    }
+
+    fn resample_loop(&self) {
+        let resample_event = match &self.backend {
+            LineBackend::Irqfd { resample_event, .. } => resample_event,
+            LineBackend::Ioapic(..) => return,
+        };
+        loop {
+            if let Err(e) = resample_event.read() {
+                warn!("Failed to read resample event for IRQ {}: {}", self.irq, e);
+                return;
+            }
+            if self.asserted.load(Ordering::SeqCst) > 0 {
+                self.trigger();
+            }
+        }
+    }
+}
+
+/// One device's connection to a possibly shared level triggered interrupt
+/// line. The line stays raised while any device sharing it has asserted
+/// it. Dropping the handle deasserts it.
+pub struct LevelIrq {
+    line: Arc<LevelIrqLine>,
+    asserted: AtomicBool,
+}
+
+impl LevelIrq {
+    pub fn irq(&self) -> u8 {
+        self.line.irq
+    }
+
+    pub fn assert(&self) {
+        if !self.asserted.swap(true, Ordering::SeqCst) {
+            self.line.asserted.fetch_add(1, Ordering::SeqCst);
+        }
+        self.line.trigger();
+    }
+
+    pub fn deassert(&self) {
+        if self.asserted.swap(false, Ordering::SeqCst) {
+            self.line.asserted.fetch_sub(1, Ordering::SeqCst);
+            self.line.update_ioapic();
+        }
+    }
+
+    pub fn set_level(&self, level: bool) {
+        if level {
+            self.assert();
+        } else {
+            self.deassert();
+        }
+    }
+}
+
+impl Drop for LevelIrq {
+    fn drop(&mut self) {
+        self.deassert();
+    }
+}
@@ -1,32 +1,140 @@
+use std::collections::HashMap;
+use std::{cmp, fs, io, result};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, MutexGuard};
+use thiserror::Error;
 use vm_allocator::{AddressAllocator, AllocPolicy, IdAllocator, RangeInclusive};
-use vm_memory::GuestMemoryMmap;
+use vm_memory::{guest_memory, Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 use vmm_sys_util::eventfd::EventFd;
+use crate::devices::ioapic::{IOAPIC_BASE, IOAPIC_SIZE};
+use crate::devices::rom::RomDevice;
 use crate::devices::rtc::Rtc;
 use crate::devices::serial::{SerialDevice, SerialPort};
 use crate::io::bus::{Bus, BusDevice};
 use crate::io::pci::{MmioHandler, PciBarAllocation, PciBus, PciDevice};
 use crate::io::{PciIrq, virtio};
 use crate::io::address::AddressRange;
-use crate::io::shm_mapper::DeviceSharedMemoryManager;
-use crate::io::virtio::{VirtioDeviceState,VirtioDevice};
-use crate::vm::{arch, KvmVm};
+use crate::io::address_map::{self, AddressSpaceMap, RegionKind};
+use crate::io::irq::IrqManager;
+use crate::io::shm_mapper::{self, DeviceSharedMemoryManager};
+use crate::io::stats::{GuestMemoryReport, GuestMemoryStats, StatsRegistry};
+use crate::io::virtio::{DmaRanges, VirtioDeviceState, VirtioDevice};
+use crate::system::sched::ThreadPriority;
+use crate::util::JsonValue;
+use crate::vm::{arch, KvmVm, RomMapping, VcpuControl};
+
+// Device shared memory goes at a 2MB boundary above RAM and at least at 4GB
+const DEVICE_SHM_ALIGN: u64 = 2 << 20;
+const DEVICE_SHM_MIN_BASE: u64 = 1 << 32;
+
+// Firmware images are mapped so that they end at 4GB. The size limit keeps
+// them clear of the IOAPIC and local APIC below.
+const FIRMWARE_END: u64 = 1 << 32;
+const FIRMWARE_MAX_SIZE: usize = 16 << 20;
+// The end of a firmware image is also copied into RAM below 1MB, where a
+// legacy BIOS expects to find itself after the jump from the reset vector.
+const FIRMWARE_LOW_ALIAS_END: u64 = 0x100000;
+const FIRMWARE_LOW_ALIAS_SIZE: usize = 128 << 10;
+
+// A write to this port powers off the VM. ph-init writes to it through
+// /dev/port when the shell exits so that pH can tell a clean shutdown from a
+// reset by the guest kernel.
+const SHUTDOWN_PORT: u64 = 0x0501;
+// Reads return the number of vcpus ph-init should keep online
+const CPU_HOTPLUG_PORT: u64 = 0x0502;
+// ph-init writes to this port once it has started the services and the shell
+const BOOT_COMPLETE_PORT: u64 = 0x0503;
+// ph-init periodically writes the memory usage of the guest to these ports,
+// as five little endian u64 values in the order of GuestMemoryReport
+const MEMORY_STATS_PORT: u64 = 0x0504;
+const MEMORY_STATS_LEN: usize = 40;
+
+#[derive(Debug,Error)]
+pub enum PlacementError {
+    #[error("PCI slot {0} is out of range or already assigned")]
+    SlotUnavailable(u8),
+    #[error("IRQ {0} is out of range ({}-{})", arch::IRQ_BASE, arch::IRQ_MAX)]
+    IrqOutOfRange(u8),
+    #[error("IRQ {0} is already assigned")]
+    IrqUnavailable(u8),
+}
+
+#[derive(Debug,Error)]
+pub enum RomError {
+    #[error("failed to read {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("{0} is empty")]
+    Empty(PathBuf),
+    #[error("{0}")]
+    AddressMap(address_map::Error),
+    #[error("failed to map image into guest memory: {0}")]
+    Map(shm_mapper::Error),
+    #[error("firmware image {0} is {1} bytes, expected a multiple of 4096 up to {}", FIRMWARE_MAX_SIZE)]
+    FirmwareSize(PathBuf, usize),
+    #[error("failed to copy firmware into guest memory: {0}")]
+    GuestMemory(guest_memory::Error),
+}
+
+/// A fixed PCI slot and/or IRQ for a device so that guest device naming
+/// (eg. enp0s4) stays the same when other devices are added or removed.
+#[derive(Copy,Clone,Debug,Default,PartialEq)]
+pub struct DevicePlacement {
+    slot: Option<u8>,
+    irq: Option<u8>,
+}
+
+impl DevicePlacement {
+    pub fn new(slot: Option<u8>, irq: Option<u8>) -> Self {
+        DevicePlacement { slot, irq }
+    }
+
+    /// Parse a placement in the form `SLOT`, `SLOT:IRQ` or `:IRQ`
+    pub fn parse(s: &str) -> Option<Self> {
+        fn parse_field(s: &str) -> Option<Option<u8>> {
+            if s.is_empty() {
+                Some(None)
+            } else {
+                s.parse().ok().map(Some)
+            }
+        }
+        let (slot, irq) = match s.split_once(':') {
+            Some((slot, irq)) => (parse_field(slot)?, parse_field(irq)?),
+            None => (parse_field(s)?, None),
+        };
+        if slot.is_none() && irq.is_none() {
+            return None;
+        }
+        Some(DevicePlacement::new(slot, irq))
+    }
+
+    pub fn slot(&self) -> Option<u8> {
+        self.slot
+    }
+
+    pub fn irq(&self) -> Option<u8> {
+        self.irq
+    }
+}
 
 #[derive(Clone)]
 pub struct IoAllocator {
     mmio_allocator: Arc<Mutex<AddressAllocator>>,
     irq_allocator: Arc<Mutex<IdAllocator>>,
+    reserved_irqs: Arc<Mutex<Vec<u8>>>,
 }
 
 impl IoAllocator {
-    fn new() -> Self {
-        let mmio_allocator = AddressAllocator::new(arch::PCI_MMIO_RESERVED_BASE, arch::PCI_MMIO_RESERVED_SIZE as u64)
+    fn new(address_map: &AddressSpaceMap) -> Self {
+        let window = address_map.region(AddressSpaceMap::PCI_MMIO)
+            .expect("No PCI MMIO window in address space map");
+        let mmio_allocator = AddressAllocator::new(window.base(), window.size() as u64)
             .expect("Failed to create address allocator");
         let irq_allocator = IdAllocator::new(arch::IRQ_BASE, arch::IRQ_MAX)
             .expect("Failed to create IRQ allocator");
         IoAllocator {
             mmio_allocator: Arc::new(Mutex::new(mmio_allocator)),
             irq_allocator: Arc::new(Mutex::new(irq_allocator)),
+            reserved_irqs: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
@@ -37,7 +145,28 @@ impl IoAllocator {
 
     pub fn allocate_irq(&self) -> u8 {
         let mut allocator = self.irq_allocator.lock().unwrap();
-        allocator.allocate_id().unwrap() as u8
+        let reserved = self.reserved_irqs.lock().unwrap();
+        loop {
+            let irq = allocator.allocate_id().unwrap() as u8;
+            if !reserved.contains(&irq) {
+                return irq;
+            }
+        }
+    }
+
+    /// Remove `irq` from the pool used by `allocate_irq()` so that it can be
+    /// assigned to a specific device. Must be called before any IRQs are
+    /// allocated.
+    pub fn reserve_irq(&self, irq: u8) -> result::Result<(), PlacementError> {
+        if (irq as u32) < arch::IRQ_BASE || (irq as u32) > arch::IRQ_MAX {
+            return Err(PlacementError::IrqOutOfRange(irq));
+        }
+        let mut reserved = self.reserved_irqs.lock().unwrap();
+        if reserved.contains(&irq) {
+            return Err(PlacementError::IrqUnavailable(irq));
+        }
+        reserved.push(irq);
+        Ok(())
     }
 }
 
@@ -50,6 +179,13 @@ pub struct IoManager {
     mmio_bus: Bus,
     pci_bus: Arc<Mutex<PciBus>>,
     allocator: IoAllocator,
+    address_map: Arc<Mutex<AddressSpaceMap>>,
+    placements: HashMap<String, DevicePlacement>,
+    thread_priorities: HashMap<String, ThreadPriority>,
+    stats: StatsRegistry,
+    irqs: IrqManager,
+    virtio_devices: Vec<Arc<Mutex<VirtioDeviceState>>>,
+    dma_ranges: DmaRanges,
 }
 
 impl IoManager {
@@ -59,25 +195,73 @@ impl IoManager {
         pio_bus.insert(pci_bus.clone(), PciBus::PCI_CONFIG_ADDRESS as u64, 8)
             .expect("Failed to add PCI configuration to PIO");
 
-        let dev_shm_manager = DeviceSharedMemoryManager::new(&kvm_vm, &memory);
+        let mut address_map = AddressSpaceMap::with_layout(&memory)
+            .expect("Failed to create guest address space map");
+        let shm_range = address_map.add_anywhere(AddressSpaceMap::DEVICE_SHM, RegionKind::DeviceShm,
+                                                 DeviceSharedMemoryManager::ADDRESS_RANGE_SIZE, DEVICE_SHM_ALIGN, DEVICE_SHM_MIN_BASE)
+            .expect("Failed to reserve device shared memory range");
+        let dev_shm_manager = DeviceSharedMemoryManager::new(Arc::new(kvm_vm.clone()), &memory, shm_range);
+        let allocator = IoAllocator::new(&address_map);
+        let irqs = IrqManager::new(kvm_vm.clone());
+        let mut mmio_bus = Bus::new();
+        if let Some(ioapic) = irqs.ioapic() {
+            mmio_bus.insert(ioapic, IOAPIC_BASE, IOAPIC_SIZE as u64)
+                .expect("Failed to add IOAPIC to MMIO");
+        }
 
         IoManager {
             kvm_vm,
             memory,
             dev_shm_manager,
             pio_bus,
-            mmio_bus: Bus::new(),
+            mmio_bus,
             pci_bus,
-            allocator: IoAllocator::new(),
+            allocator,
+            address_map: Arc::new(Mutex::new(address_map)),
+            placements: HashMap::new(),
+            thread_priorities: HashMap::new(),
+            stats: StatsRegistry::new(),
+            irqs,
+            virtio_devices: Vec::new(),
+            dma_ranges: DmaRanges::unrestricted(),
         }
     }
 
-    pub fn register_legacy_devices(&mut self, reset_evt: EventFd) {
-        let rtc = Arc::new(Mutex::new(Rtc::new()));
+    pub fn register_legacy_devices(&mut self, reset_evt: EventFd, shutdown_evt: EventFd, rtc_localtime: bool) {
+        let mut rtc = Rtc::new(rtc_localtime);
+        let (low_mem, high_mem) = self.memory.iter()
+            .fold((0, 0), |(low, high), r| {
+                if r.start_addr().raw_value() < FIRMWARE_END {
+                    (low + r.len(), high)
+                } else {
+                    (low, high + r.len())
+                }
+            });
+        rtc.set_memory_size(low_mem, high_mem);
+        let rtc = Arc::new(Mutex::new(rtc));
         self.pio_bus.insert(rtc, 0x0070, 2).unwrap();
 
         let i8042 = Arc::new(Mutex::new(I8042Device::new(reset_evt)));
         self.pio_bus.insert(i8042, 0x0060, 8).unwrap();
+
+        let shutdown = Arc::new(Mutex::new(ShutdownPort { shutdown_evt }));
+        self.pio_bus.insert(shutdown, SHUTDOWN_PORT, 1).unwrap();
+    }
+
+    pub fn register_boot_complete(&mut self, control: Arc<VcpuControl>) {
+        let port = Arc::new(Mutex::new(BootCompletePort { control }));
+        self.pio_bus.insert(port, BOOT_COMPLETE_PORT, 1).unwrap();
+    }
+
+    pub fn register_memory_stats(&mut self) {
+        let port = MemoryStatsPort::new(self.stats.guest_memory());
+        let port = Arc::new(Mutex::new(port));
+        self.pio_bus.insert(port, MEMORY_STATS_PORT, MEMORY_STATS_LEN as u64).unwrap();
+    }
+
+    pub fn register_cpu_hotplug(&mut self, control: Arc<VcpuControl>) {
+        let port = Arc::new(Mutex::new(CpuHotplugPort { control }));
+        self.pio_bus.insert(port, CPU_HOTPLUG_PORT, 1).unwrap();
     }
 
     pub fn register_serial_port(&mut self, port: SerialPort) {
@@ -91,6 +275,72 @@ impl IoManager {
         self.allocator.clone()
     }
 
+    pub fn address_map(&self) -> MutexGuard<AddressSpaceMap> {
+        self.address_map.lock().unwrap()
+    }
+
+    /// Reserve a range of guest physical addresses which will be marked as
+    /// reserved in the memory map passed to the guest kernel.
+    pub fn reserve_memory(&self, name: &str, base: u64, size: usize) -> address_map::Result<()> {
+        self.address_map().reserve(name, base, size)
+    }
+
+    /// Map the contents of the file at `path` read-only into the guest at
+    /// `base`, either as a KVM memory region or as a device on the MMIO bus.
+    /// The range is recorded in the address map under `name`, which fails if
+    /// it overlaps RAM or any other range.
+    pub fn add_rom(&mut self, name: &str, base: u64, path: &Path, mapping: RomMapping) -> result::Result<(), RomError> {
+        let data = fs::read(path)
+            .map_err(|e| RomError::Read(path.to_path_buf(), e))?;
+        if data.is_empty() {
+            return Err(RomError::Empty(path.to_path_buf()));
+        }
+        match mapping {
+            RomMapping::Memory => {
+                // The last page is padded with zeroes
+                let size = (data.len() + 0xfff) & !0xfff;
+                self.address_map().add_rom(name, base, size, true)
+                    .map_err(RomError::AddressMap)?;
+                self.dev_shm_manager.map_rom(base, &data)
+                    .map_err(RomError::Map)?;
+            }
+            RomMapping::Mmio => {
+                let size = data.len();
+                self.address_map().add_rom(name, base, size, false)
+                    .map_err(RomError::AddressMap)?;
+                let rom = Arc::new(Mutex::new(RomDevice::new(data)));
+                // The address map has already rejected any overlap
+                self.mmio_bus.insert(rom, base, size as u64)
+                    .expect("Failed to add ROM to MMIO");
+            }
+        }
+        Ok(())
+    }
+
+    /// Map the firmware image at `path` read-only so that it ends at 4GB,
+    /// where the vcpus begin executing it from the reset vector. The range
+    /// lies inside the system region of the address map, so it is not
+    /// recorded separately.
+    pub fn add_firmware(&mut self, path: &Path) -> result::Result<(), RomError> {
+        let data = fs::read(path)
+            .map_err(|e| RomError::Read(path.to_path_buf(), e))?;
+        if data.is_empty() {
+            return Err(RomError::Empty(path.to_path_buf()));
+        }
+        if data.len() % 4096 != 0 || data.len() > FIRMWARE_MAX_SIZE {
+            return Err(RomError::FirmwareSize(path.to_path_buf(), data.len()));
+        }
+        let base = FIRMWARE_END - data.len() as u64;
+        self.dev_shm_manager.map_rom(base, &data)
+            .map_err(RomError::Map)?;
+
+        let alias_size = cmp::min(data.len(), FIRMWARE_LOW_ALIAS_SIZE);
+        let alias_base = FIRMWARE_LOW_ALIAS_END - alias_size as u64;
+        self.memory.write_slice(&data[data.len() - alias_size..], GuestAddress(alias_base))
+            .map_err(RomError::GuestMemory)?;
+        Ok(())
+    }
+
     pub fn mmio_read(&self, addr: u64, data: &mut [u8]) -> bool {
         self.mmio_bus.read(addr, data)
     }
@@ -99,6 +349,16 @@ impl IoManager {
         self.mmio_bus.write(addr, data)
     }
 
+    /// Read from an MMIO device without side effects. Returns false if no
+    /// device is mapped at `addr` or the device does not support peeking.
+    pub fn mmio_peek(&self, addr: u64, data: &mut [u8]) -> bool {
+        self.mmio_bus.peek(addr, data)
+    }
+
+    pub fn pio_peek(&self, port: u16, data: &mut [u8]) -> bool {
+        self.pio_bus.peek(port as u64, data)
+    }
+
     pub fn pio_read(&self, port: u16, data: &mut [u8]) -> bool {
         self.pio_bus.read(port as u64, data)
     }
@@ -138,21 +398,137 @@ impl IoManager {
     }
 
     pub fn add_pci_device(&mut self, device: Arc<Mutex<dyn PciDevice+Send>>) {
+        self.add_pci_device_at(device, None);
+    }
+
+    fn add_pci_device_at(&mut self, device: Arc<Mutex<dyn PciDevice+Send>>, slot: Option<u8>) -> u8 {
         self.allocate_pci_bars(&device);
         let mut pci = self.pci_bus.lock().unwrap();
-        pci.add_device(device);
+        match slot {
+            Some(slot) => {
+                pci.add_device_at(device, slot);
+                slot
+            }
+            None => pci.add_device(device),
+        }
     }
 
-    pub fn add_virtio_device<D: VirtioDevice+'static>(&mut self, dev: D) -> virtio::Result<()> {
-        let irq = self.allocator.allocate_irq();
-        let devstate = VirtioDeviceState::new(dev, self.kvm_vm.clone(), self.memory.clone(), irq)?;
-        self.add_pci_device(Arc::new(Mutex::new(devstate)));
+    /// Pin the virtio device `name` to a PCI slot and/or IRQ. Device names are
+    /// the same names used for statistics (eg: `net`, `block`, `block-1`) and
+    /// placements must be set before any virtio devices are added.
+    pub fn set_device_placement(&mut self, name: &str, placement: DevicePlacement) -> result::Result<(), PlacementError> {
+        if let Some(slot) = placement.slot() {
+            if !self.pci_bus().reserve_slot(slot) {
+                return Err(PlacementError::SlotUnavailable(slot));
+            }
+        }
+        if let Some(irq) = placement.irq() {
+            self.allocator.reserve_irq(irq)?;
+        }
+        self.placements.insert(name.to_string(), placement);
         Ok(())
     }
 
+    /// Set the scheduling of the worker threads of the virtio device `name`,
+    /// named as for `set_device_placement()`. Must be called before the
+    /// device is added.
+    pub fn set_thread_priority(&mut self, name: &str, priority: ThreadPriority) {
+        self.thread_priorities.insert(name.to_string(), priority);
+    }
+
+    /// Require the buffers passed to virtio devices added after this call to
+    /// lie in the RAM entries of the e820 map given to the guest and outside
+    /// of the rings of their queue. A device given any other buffer marks
+    /// itself as needing a reset.
+    pub fn enable_strict_dma(&mut self) {
+        let ram: Vec<(u64, u64)> = arch::e820_map(&self.memory, &[]).iter()
+            .filter(|e| e.kind == arch::E820Type::Ram)
+            .map(|e| (e.addr, e.size))
+            .collect();
+        self.dma_ranges = DmaRanges::strict(&ram);
+    }
+
+    /// Add a virtio PCI device for `dev`. Devices are placed in the order
+    /// they are added unless a placement was configured for the device name,
+    /// and are stopped by `shutdown_virtio_devices()` when the VM exits.
+    /// Returns the PCI slot the device was placed in.
+    /// See `VirtioDevice` for what a device needs to implement.
+    pub fn add_virtio_device<D: VirtioDevice+'static>(&mut self, dev: D) -> virtio::Result<u8> {
+        let stats = self.stats.register_device(dev.device_type().name());
+        let placement = self.placements.get(stats.name()).copied().unwrap_or_default();
+        let priority = self.thread_priorities.get(stats.name()).copied();
+        let irq = placement.irq().unwrap_or_else(|| self.allocator.allocate_irq());
+        let mut devstate = VirtioDeviceState::new(dev, Arc::new(self.kvm_vm.clone()), self.memory.clone(), irq, stats)?;
+        devstate.set_dma_ranges(self.dma_ranges.clone());
+        devstate.set_thread_priority(priority);
+        let devstate = Arc::new(Mutex::new(devstate));
+        self.virtio_devices.push(devstate.clone());
+        Ok(self.add_pci_device_at(devstate, placement.slot()))
+    }
+
+    /// Stop every virtio device once the vcpus have exited so that devices
+    /// can complete work which must happen at shutdown, such as committing
+    /// disk overlays.
+    pub fn shutdown_virtio_devices(&self) {
+        for dev in &self.virtio_devices {
+            dev.lock().unwrap().shutdown();
+        }
+    }
+
+    /// Level triggered interrupt lines which can be shared between devices.
+    pub fn irqs(&self) -> &IrqManager {
+        &self.irqs
+    }
+
+    pub fn stats(&self) -> &StatsRegistry {
+        &self.stats
+    }
+
     pub fn dev_shm_manager(&self) -> &DeviceSharedMemoryManager {
         &self.dev_shm_manager
     }
+
+    /// Describe the guest physical memory layout and the PCI devices
+    /// attached to the VM.
+    pub fn describe(&self) -> JsonValue {
+        JsonValue::object()
+            .field("memory", self.memory_layout())
+            .field("e820", self.describe_e820())
+            .field("pci", self.pci_bus().describe())
+    }
+
+    fn describe_e820(&self) -> JsonValue {
+        let reserved = self.address_map().reserved_ranges();
+        let mut entries = JsonValue::array();
+        for e in arch::e820_map(&self.memory, &reserved) {
+            entries.push(JsonValue::object()
+                .field("type", e.kind.name())
+                .field("base", format!("0x{:x}", e.addr))
+                .field("end", format!("0x{:x}", e.addr + e.size - 1))
+                .field("size", e.size));
+        }
+        entries
+    }
+
+    /// The named regions of the guest physical address space, the same
+    /// regions which are passed to ph-init in the layout file.
+    pub fn memory_layout(&self) -> JsonValue {
+        let mut regions = JsonValue::array();
+        for r in self.address_map().regions() {
+            let range = r.range();
+            let mut region = JsonValue::object()
+                .field("name", r.name())
+                .field("type", r.kind().to_string())
+                .field("base", format!("0x{:x}", range.base()))
+                .field("end", format!("0x{:x}", range.end() - 1))
+                .field("size", range.size());
+            if r.kind() == RegionKind::DeviceShm {
+                region = region.field("mappings", self.dev_shm_manager.mapping_count());
+            }
+            regions.push(region);
+        }
+        regions
+    }
 }
 
 pub struct I8042Device {
@@ -166,6 +542,10 @@ impl I8042Device {
 
 impl BusDevice for I8042Device {
     fn read(&mut self, offset: u64, data: &mut [u8]) {
+        self.peek(offset, data);
+    }
+
+    fn peek(&self, offset: u64, data: &mut [u8]) -> bool {
         if data.len() == 1 {
             match offset {
                 0 => data[0] = 0x20,
@@ -173,6 +553,7 @@ impl BusDevice for I8042Device {
                 _ => {},
             }
         }
+        true
     }
 
     fn write(&mut self, offset: u64, data: &[u8]) {
@@ -184,4 +565,89 @@ impl BusDevice for I8042Device {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+struct ShutdownPort {
+    shutdown_evt: EventFd,
+}
+
+impl BusDevice for ShutdownPort {
+    fn write(&mut self, _offset: u64, _data: &[u8]) {
+        if let Err(err) = self.shutdown_evt.write(1) {
+            warn!("Error triggering shutdown event: {}", err);
+        }
+    }
+}
+
+struct BootCompletePort {
+    control: Arc<VcpuControl>,
+}
+
+impl BusDevice for BootCompletePort {
+    fn write(&mut self, _offset: u64, _data: &[u8]) {
+        self.control.guest_ready();
+    }
+}
+
+// Writing a byte to each port in turn through /dev/port exits to pH once per
+// byte. A report is only taken once all of the bytes have been written in
+// order, starting at the first port.
+struct MemoryStatsPort {
+    stats: Arc<GuestMemoryStats>,
+    buf: [u8; MEMORY_STATS_LEN],
+    next: usize,
+}
+
+impl MemoryStatsPort {
+    fn new(stats: Arc<GuestMemoryStats>) -> Self {
+        MemoryStatsPort { stats, buf: [0; MEMORY_STATS_LEN], next: 0 }
+    }
+
+    fn value(&self, idx: usize) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&self.buf[idx * 8..(idx + 1) * 8]);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+impl BusDevice for MemoryStatsPort {
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let offset = offset as usize;
+        if offset == 0 {
+            self.next = 0;
+        }
+        if offset != self.next || offset + data.len() > MEMORY_STATS_LEN {
+            self.next = usize::MAX;
+            return;
+        }
+        self.buf[offset..offset + data.len()].copy_from_slice(data);
+        self.next += data.len();
+        if self.next == MEMORY_STATS_LEN {
+            self.stats.update(GuestMemoryReport {
+                total: self.value(0),
+                free: self.value(1),
+                available: self.value(2),
+                swap_total: self.value(3),
+                swap_free: self.value(4),
+            });
+            self.next = usize::MAX;
+        }
+    }
+}
+
+struct CpuHotplugPort {
+    control: Arc<VcpuControl>,
+}
+
+impl BusDevice for CpuHotplugPort {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        self.peek(offset, data);
+    }
+
+    fn peek(&self, _offset: u64, data: &mut [u8]) -> bool {
+        if data.len() == 1 {
+            data[0] = self.control.online_cpus() as u8;
+        }
+        true
+    }
+}
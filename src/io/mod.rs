@@ -1,10 +1,13 @@
 pub mod bus;
+pub mod irq;
 pub mod busdata;
 pub mod pci;
 pub mod manager;
 pub mod virtio;
-mod address;
+pub(crate) mod address;
+pub mod address_map;
 pub mod shm_mapper;
+pub mod stats;
 
 pub use virtio::{VirtioDevice,FeatureBits,VirtioDeviceType,VirtQueue,Chain,Queues};
 pub use virtio::Error as VirtioError;
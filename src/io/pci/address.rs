@@ -1,3 +1,4 @@
+use std::fmt;
 
 #[derive(Copy,Clone,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
 pub struct PciAddress(u16);
@@ -23,10 +24,19 @@ impl PciAddress {
     }
 
     pub fn device(&self) -> u8 {
-        ((self.0 & 0xF) >> 3) as u8
+        ((self.0 >> 3) & 0x1f) as u8
     }
 
     pub fn address(&self) -> u16 {
         self.0
     }
-}
\ No newline at end of file
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bus = self.0 >> 8;
+        let device = (self.0 >> 3) & 0x1f;
+        let function = self.0 & 0x7;
+        write!(f, "{:02x}:{:02x}.{}", bus, device, function)
+    }
+}
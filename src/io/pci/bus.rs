@@ -5,6 +5,7 @@ use crate::io::pci::address::PciAddress;
 use crate::io::pci::config::PciConfiguration;
 use crate::io::pci::consts::{PCI_CLASS_BRIDGE_HOST, PCI_MAX_DEVICES, PCI_VENDOR_ID_INTEL};
 use crate::io::pci::PciDevice;
+use crate::util::JsonValue;
 
 /// Current address to read/write from (io port 0xcf8)
 struct PciConfigAddress([u8; 4]);
@@ -94,8 +95,15 @@ impl PciBus {
 
     }
 
-    pub fn add_device(&mut self, device: Arc<Mutex<dyn PciDevice>>) {
+    /// Add a device at the first free slot and return the slot
+    pub fn add_device(&mut self, device: Arc<Mutex<dyn PciDevice>>) -> u8 {
         let id = self.allocate_id().unwrap();
+        self.add_device_at(device, id);
+        id
+    }
+
+    /// Add a device at a slot which was previously reserved with `reserve_slot()`
+    pub fn add_device_at(&mut self, device: Arc<Mutex<dyn PciDevice>>, id: u8) {
         let address = PciAddress::new(0, id, 0);
         device.lock().unwrap().config_mut().set_address(address);
         self.devices.insert(address, device);
@@ -112,6 +120,45 @@ impl PciBus {
         irqs
     }
 
+    pub fn describe(&self) -> JsonValue {
+        let mut devices = JsonValue::array();
+        for (addr, dev) in &self.devices {
+            let dev = dev.lock().unwrap();
+            let config = dev.config();
+            let mut bars = JsonValue::array();
+            for (bar, range) in config.mmio_bars() {
+                bars.push(JsonValue::object()
+                    .field("bar", bar.idx())
+                    .field("type", "mmio")
+                    .field("base", format!("0x{:x}", range.base()))
+                    .field("size", range.size()));
+            }
+            let mut info = JsonValue::object()
+                .field("address", addr.to_string())
+                .field("vendor_id", format!("0x{:04x}", config.vendor_id()))
+                .field("device_id", format!("0x{:04x}", config.device_id()))
+                .field("class", format!("0x{:04x}", config.class_id()))
+                .field("irq", dev.irq())
+                .field("bars", bars);
+            if let Some(device_info) = dev.device_info() {
+                info.set("device", device_info);
+            }
+            devices.push(info);
+        }
+        devices
+    }
+
+    /// Mark a slot as used so that it will not be assigned by `add_device()`.
+    /// Returns `false` if the slot is out of range or already in use.
+    pub fn reserve_slot(&mut self, id: u8) -> bool {
+        let idx = id as usize;
+        if idx >= PCI_MAX_DEVICES || self.used_device_ids[idx] {
+            return false;
+        }
+        self.used_device_ids[idx] = true;
+        true
+    }
+
     fn allocate_id(&mut self) -> Option<u8> {
         for i in 0..PCI_MAX_DEVICES {
             if !self.used_device_ids[i] {
@@ -147,6 +194,10 @@ impl PciBus {
 
 impl BusDevice for PciBus {
     fn read(&mut self, offset: u64, data: &mut [u8]) {
+        self.peek(offset, data);
+    }
+
+    fn peek(&self, offset: u64, data: &mut [u8]) -> bool {
         if PciBus::is_config_address(offset, data.len()) {
             self.config_address.read(offset, data);
         } else if PciBus::is_config_data(offset, data.len()) {
@@ -158,6 +209,7 @@ impl BusDevice for PciBus {
                 data.fill(0xff)
             }
         }
+        true
     }
     fn write(&mut self, offset: u64, data: &[u8]) {
         if PciBus::is_config_address(offset, data.len()) {
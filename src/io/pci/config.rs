@@ -37,6 +37,7 @@ pub struct PciConfiguration {
     irq: u8,
     bytes: [u8; PCI_CONFIG_SPACE_SIZE],
     bar_write_masks: [u32; 6],
+    mmio_bars: Vec<(PciBar, AddressRange)>,
     next_capability_offset: usize,
 }
 
@@ -47,6 +48,7 @@ impl PciConfiguration {
             irq,
             bytes: [0; PCI_CONFIG_SPACE_SIZE],
             bar_write_masks: [0; 6],
+            mmio_bars: Vec::new(),
             next_capability_offset: PCI_CAP_BASE_OFFSET,
         };
 
@@ -75,6 +77,27 @@ impl PciConfiguration {
         self.irq
     }
 
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(PCI_VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(PCI_DEVICE_ID)
+    }
+
+    pub fn class_id(&self) -> u16 {
+        self.read_u16(PCI_CLASS_DEVICE)
+    }
+
+    /// Memory ranges which have been assigned to BARs with `set_mmio_bar()`
+    pub fn mmio_bars(&self) -> &[(PciBar, AddressRange)] {
+        &self.mmio_bars
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]])
+    }
+
     fn buffer(&mut self) -> ByteBuffer<&mut[u8]> {
         ByteBuffer::from_bytes_mut(&mut self.bytes).little_endian()
     }
@@ -211,6 +234,8 @@ impl PciConfiguration {
         let offset = PCI_BAR0 + (bar.idx() * 4);
         let address = (range.base() as u32).to_le_bytes();
         self.write_bytes(offset, &address);
+        self.mmio_bars.retain(|(b,_)| *b != bar);
+        self.mmio_bars.push((bar, range));
     }
 
     pub fn read(&self, offset: u64, data: &mut [u8]) {
@@ -226,4 +251,28 @@ impl PciConfiguration {
             self.write_config(offset as usize, data);
         }
     }
-}
\ No newline at end of file
+}
+/// Fuzzing entry point for guest accesses to PCI configuration space. `data`
+/// is a sequence of 4 byte access headers (kind, offset, size) each followed
+/// by the bytes written when the access is a write.
+#[cfg(feature = "fuzzing")]
+pub fn fuzz_config_access(data: &[u8]) {
+    let mut config = PciConfiguration::new(5, 0x1af4, 0x1042, 0x0180);
+    config.set_mmio_bar(PciBar::Bar0, AddressRange::new(0xe000_0000, 0x4000));
+
+    let mut rest = data;
+    while rest.len() >= 4 {
+        let is_write = rest[0] & 1 != 0;
+        let offset = u16::from_le_bytes([rest[1], rest[2]]) as u64;
+        let size = 1usize << (rest[3] & 3);
+        rest = &rest[4..];
+        if is_write {
+            let n = size.min(rest.len());
+            config.write(offset, &rest[..n]);
+            rest = &rest[n..];
+        } else {
+            let mut buf = [0u8; 8];
+            config.read(offset, &mut buf[..size]);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::sync::{Arc, Mutex};
 use crate::io::bus::BusDevice;
 use crate::io::pci::PciConfiguration;
+use crate::util::JsonValue;
 
 #[derive(Copy,Clone,Eq,PartialEq)]
 #[repr(u8)]
@@ -39,7 +40,16 @@ pub trait PciDevice: Send {
 
     fn bar_allocations(&self) -> Vec<PciBarAllocation> { vec![] }
 
+    /// Read from a BAR without side effects, see `BusDevice::peek()`.
+    fn peek_bar(&self, bar: PciBar, offset: u64, data: &mut [u8]) -> bool {
+        let (_,_,_) = (bar, offset, data);
+        false
+    }
+
     fn configure_bars(&mut self, allocations: Vec<(PciBar, u64)>) { let _ = allocations; }
+
+    /// Device specific state to include when describing the machine layout
+    fn device_info(&self) -> Option<JsonValue> { None }
 }
 
 pub struct MmioHandler {
@@ -65,4 +75,9 @@ impl BusDevice for MmioHandler {
         let mut lock = self.device.lock().unwrap();
         lock.write_bar(self.bar, offset, data)
     }
+
+    fn peek(&self, offset: u64, data: &mut [u8]) -> bool {
+        let lock = self.device.lock().unwrap();
+        lock.peek_bar(self.bar, offset, data)
+    }
 }
\ No newline at end of file
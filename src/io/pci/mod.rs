@@ -6,4 +6,6 @@ mod consts;
 mod device;
 pub use bus::{PciBus,PciIrq};
 pub use config::PciConfiguration;
+#[cfg(feature = "fuzzing")]
+pub use config::fuzz_config_access;
 pub use device::{PciDevice,PciBar,PciBarAllocation,MmioHandler};
@@ -1,14 +1,20 @@
+// Buffers for the wayland device are the main user, ROM images are mapped without it
+#![cfg_attr(not(feature = "wayland"), allow(dead_code))]
+
 use std::collections::HashMap;
 use std::fs::File;
 use std::os::fd::{AsRawFd, RawFd};
 use std::result;
 use std::sync::{Arc, Mutex, MutexGuard};
 use vm_allocator::{AddressAllocator, AllocPolicy, RangeInclusive};
-use vm_memory::{Address, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, MmapRegion};
+use vm_memory::{FileOffset, GuestMemory, GuestMemoryMmap, MmapRegion};
+#[cfg(feature = "wayland")]
 use crate::system::drm::{DrmBufferAllocator, DrmDescriptor};
+#[cfg(feature = "wayland")]
 use crate::system::drm;
 use crate::util::BitSet;
-use crate::vm::KvmVm;
+use crate::io::address::AddressRange;
+use crate::vm::VmOps;
 
 use thiserror::Error;
 use std::io::{Seek, SeekFrom};
@@ -21,8 +27,10 @@ pub type Result<T> = result::Result<T, Error>;
 pub enum Error {
     #[error("failed to create SharedMemory: {0}")]
     SharedMemoryCreation(system::Error),
+    #[cfg(feature = "wayland")]
     #[error("failed to allocate DRM buffer: {0}")]
     DrmAllocateFailed(drm::Error),
+    #[cfg(feature = "wayland")]
     #[error("no DRM memory allocator")]
     NoDrmAllocator,
     #[error("failed to register memory with hypervisor: {0}")]
@@ -48,9 +56,13 @@ pub struct DeviceSharedMemoryManager {
 }
 
 impl DeviceSharedMemoryManager {
+    /// Size of the guest address range reserved for device shared memory
+    pub const ADDRESS_RANGE_SIZE: usize = 1 << 32;
 
-    pub fn new(kvm_vm: &KvmVm, memory: &GuestMemoryMmap) -> Self {
-        let device_memory = DeviceSharedMemory::new(kvm_vm.clone(), memory);
+    /// Create a manager which maps buffers into `range`, as reserved in the
+    /// `AddressSpaceMap`.
+    pub fn new(vm: Arc<dyn VmOps>, memory: &GuestMemoryMmap, range: AddressRange) -> Self {
+        let device_memory = DeviceSharedMemory::new(vm, memory, range);
         DeviceSharedMemoryManager {
             device_memory: Arc::new(Mutex::new(device_memory)),
         }
@@ -67,6 +79,15 @@ impl DeviceSharedMemoryManager {
         self.dev_memory().register(memory)
     }
 
+    /// Map a dma-buf received from the compositor. If `fd` was opened
+    /// read-only the buffer is mapped read-only into the guest.
+    pub fn allocate_buffer_from_dmabuf(&self, fd: File) -> Result<SharedMemoryAllocation> {
+        let memory = SharedMemoryMapping::from_dmabuf(fd)
+            .map_err(Error::SharedMemoryCreation)?;
+
+        self.dev_memory().register(memory)
+    }
+
     pub fn allocate_buffer(&self, size: usize) -> Result<SharedMemoryAllocation> {
         let memory = SharedMemoryMapping::create_memfd(size, "ph-dev-shm")
             .map_err(Error::SharedMemoryCreation)?;
@@ -74,10 +95,28 @@ impl DeviceSharedMemoryManager {
         self.dev_memory().register(memory)
     }
 
+    #[cfg(feature = "wayland")]
     pub fn allocate_drm_buffer(&self, width: u32, height: u32, format: u32) -> Result<SharedMemoryAllocation> {
         self.dev_memory().allocate_drm_buffer(width, height, format)
     }
 
+    /// Map a copy of `data` read-only into the guest at the fixed address
+    /// `base`, outside of the device shared memory range. Used for ROM images,
+    /// which stay mapped until the VM exits.
+    pub fn map_rom(&self, base: u64, data: &[u8]) -> Result<()> {
+        self.dev_memory().map_rom(base, data)
+    }
+
+    /// Guest physical address range reserved for device shared memory
+    pub fn address_range(&self) -> AddressRange {
+        self.dev_memory().range
+    }
+
+    /// Number of buffers currently mapped into the device memory range
+    pub fn mapping_count(&self) -> usize {
+        self.dev_memory().mappings.len()
+    }
+
     fn dev_memory(&self) -> MutexGuard<DeviceSharedMemory> {
         self.device_memory.lock().unwrap()
     }
@@ -89,17 +128,21 @@ pub struct SharedMemoryAllocation {
     size: usize,
     slot: u32,
     raw_fd: RawFd,
+    writable: bool,
+    #[cfg(feature = "wayland")]
     drm_descriptor: Option<DrmDescriptor>,
 }
 
 impl SharedMemoryAllocation {
-    fn new(pfn: u64, size: usize, slot: u32, raw_fd: RawFd) -> Self {
+    fn new(pfn: u64, size: usize, slot: u32, raw_fd: RawFd, writable: bool) -> Self {
         SharedMemoryAllocation {
-            pfn, size, slot, raw_fd,
+            pfn, size, slot, raw_fd, writable,
+            #[cfg(feature = "wayland")]
             drm_descriptor: None,
         }
     }
 
+    #[cfg(feature = "wayland")]
     fn set_drm_descriptor(&mut self, drm_descriptor: DrmDescriptor) {
         self.drm_descriptor.replace(drm_descriptor);
     }
@@ -116,65 +159,61 @@ impl SharedMemoryAllocation {
         self.slot
     }
 
+    /// False if the guest may only read this buffer
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
     pub fn raw_fd(&self) -> RawFd {
         self.raw_fd
     }
 
+    #[cfg(feature = "wayland")]
     pub fn drm_descriptor(&self) -> Option<DrmDescriptor> {
         self.drm_descriptor
     }
 }
 
 struct DeviceSharedMemory {
-    kvm_vm: KvmVm,
+    vm: Arc<dyn VmOps>,
     slots: BitSet,
     mappings: HashMap<u32, SharedMemoryMapping>,
+    // Only held so that the host mappings of ROM images stay valid
+    #[allow(dead_code)]
+    roms: Vec<SharedMemoryMapping>,
+    range: AddressRange,
     allocator: AddressAllocator,
+    #[cfg(feature = "wayland")]
     drm_allocator: Option<DrmBufferAllocator>
 }
 
 impl DeviceSharedMemory {
-    const WL_SHM_SIZE: u64 = 1 << 32;
-
-    fn create_allocator(memory: &GuestMemoryMmap) -> AddressAllocator {
-        let device_memory_base = || -> GuestAddress {
-            // Put device memory at a 2MB boundary after physical memory or at 4GB,
-            // whichever is higher.
-            const ONE_MB: u64 = 1 << 20;
-            const FOUR_GB: u64 = 4 * 1024 * ONE_MB;
-
-            memory.iter()
-                .map(|addr| addr.last_addr().unchecked_align_up(2 * ONE_MB))
-                .max()
-                .map(|top| std::cmp::max(top, GuestAddress(FOUR_GB)))
-                .expect("Failed to compute device memory base")
-        };
-        let base = device_memory_base();
-        AddressAllocator::new(base.raw_value(), Self::WL_SHM_SIZE)
-            .expect("Failed to create wayland shared memory allocator")
-
-    }
-
-    fn new(kvm_vm: KvmVm, memory: &GuestMemoryMmap) -> Self {
-        let allocator = Self::create_allocator(memory);
+    fn new(vm: Arc<dyn VmOps>, memory: &GuestMemoryMmap, range: AddressRange) -> Self {
+        let allocator = AddressAllocator::new(range.base(), range.size() as u64)
+            .expect("Failed to create wayland shared memory allocator");
         let mut slots = BitSet::new();
         for idx in 0..memory.num_regions() {
             slots.insert(idx);
         }
 
         DeviceSharedMemory {
-            kvm_vm,
+            vm,
             slots,
             mappings: HashMap::new(),
+            roms: Vec::new(),
+            range,
             allocator,
+            #[cfg(feature = "wayland")]
             drm_allocator: None,
         }
     }
 
+    #[cfg(feature = "wayland")]
     fn is_drm_enabled(&self) -> bool {
         self.drm_allocator.is_some()
     }
 
+    #[cfg(feature = "wayland")]
     fn enable_drm_allocator(&mut self) {
         if !self.is_drm_enabled() {
             match DrmBufferAllocator::open() {
@@ -186,6 +225,7 @@ impl DeviceSharedMemory {
         }
     }
 
+    #[cfg(feature = "wayland")]
     fn allocate_drm_buffer(&mut self, width: u32, height: u32, format: u32) -> Result<SharedMemoryAllocation> {
         if !self.is_drm_enabled() {
             self.enable_drm_allocator();
@@ -206,32 +246,49 @@ impl DeviceSharedMemory {
     }
 
     fn register(&mut self, mut memory: SharedMemoryMapping) -> Result<SharedMemoryAllocation> {
-
-        fn round_to_page_size(n: usize) -> usize {
-            let mask = 4096 - 1;
-            (n + mask) & !mask
-        }
-
-
         let size = round_to_page_size(memory.size());
         let (range, slot) = self.allocate_addr_and_slot(size)?;
         memory.set_guest_range(range.clone());
 
-        if let Err(e) = self.kvm_vm.add_memory_region(slot, range.start(), memory.mapping_host_address(), size) {
+        let host_address = memory.mapping_host_address();
+        let result = if memory.is_writable() {
+            self.vm.add_memory_region(slot, range.start(), host_address, size)
+        } else {
+            self.vm.add_readonly_memory_region(slot, range.start(), host_address, size)
+        };
+
+        if let Err(e) = result {
             self.free_range_and_slot(&range, slot);
             Err(Error::RegisterMemoryFailed(e))
         } else {
             let pfn = range.start() >> 12;
             let size = memory.size();
             let raw_fd = memory.raw_fd();
+            let writable = memory.is_writable();
             self.mappings.insert(slot, memory);
-            Ok(SharedMemoryAllocation::new(pfn, size, slot, raw_fd))
+            Ok(SharedMemoryAllocation::new(pfn, size, slot, raw_fd, writable))
+        }
+    }
+
+    fn map_rom(&mut self, base: u64, data: &[u8]) -> Result<()> {
+        let size = round_to_page_size(data.len());
+        let memory = SharedMemoryMapping::create_memfd(size, "ph-rom")
+            .map_err(Error::SharedMemoryCreation)?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), memory.mapping.as_ptr(), data.len());
+        }
+        let slot = self.allocate_slot();
+        if let Err(e) = self.vm.add_readonly_memory_region(slot, base, memory.mapping_host_address(), size) {
+            self.free_slot(slot);
+            return Err(Error::RegisterMemoryFailed(e));
         }
+        self.roms.push(memory);
+        Ok(())
     }
 
     fn unregister(&mut self, slot: u32) -> Result<()> {
         if let Some(registration) = self.mappings.remove(&slot) {
-            self.kvm_vm.remove_memory_region(slot)
+            self.vm.remove_memory_region(slot)
                 .map_err(Error::UnregisterMemoryFailed)?;
             if let Some(range) = registration.guest_range() {
                 self.free_range_and_slot(range, slot);
@@ -273,9 +330,15 @@ impl DeviceSharedMemory {
     }
 }
 
+fn round_to_page_size(n: usize) -> usize {
+    let mask = 4096 - 1;
+    (n + mask) & !mask
+}
+
 struct SharedMemoryMapping {
     mapping: MmapRegion,
     guest_range: Option<RangeInclusive>,
+    writable: bool,
 }
 
 impl SharedMemoryMapping {
@@ -288,6 +351,30 @@ impl SharedMemoryMapping {
         Ok(SharedMemoryMapping {
             mapping,
             guest_range: None,
+            writable: true,
+        })
+    }
+
+    fn from_dmabuf(fd: File) -> system::Result<Self> {
+        let size = (&fd).seek(SeekFrom::End(0))? as usize;
+        let access = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+        if access < 0 {
+            return Err(system::Error::last_os_error());
+        }
+        let writable = access & libc::O_ACCMODE == libc::O_RDWR;
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+
+        let file_offset = FileOffset::new(fd, 0);
+        let mapping = MmapRegion::build(Some(file_offset), size, prot, libc::MAP_SHARED)
+            .map_err(system::Error::MmapRegionCreate)?;
+        Ok(SharedMemoryMapping {
+            mapping,
+            guest_range: None,
+            writable,
         })
     }
 
@@ -313,9 +400,14 @@ impl SharedMemoryMapping {
         Ok(SharedMemoryMapping {
             mapping,
             guest_range: None,
+            writable: true,
         })
     }
 
+    fn is_writable(&self) -> bool {
+        self.writable
+    }
+
     fn size(&self) -> usize {
         self.mapping.size()
     }
@@ -0,0 +1,298 @@
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::Logger;
+use crate::util::JsonValue;
+
+/// A monotonically increasing event or byte counter which can be shared
+/// between a device worker thread and the stats reader.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value which can go up as well as down, such as an amount of memory in use.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, n: u64) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters for a single virtqueue.
+///
+/// `chains` and the byte counters are updated when a chain is returned to the
+/// used ring, `notifications` when the device reads the queue ioeventfd, and
+/// `interrupts` each time returning a chain results in an interrupt being
+/// injected into the guest.
+#[derive(Default)]
+pub struct QueueStats {
+    pub chains: Counter,
+    pub bytes_read: Counter,
+    pub bytes_written: Counter,
+    pub notifications: Counter,
+    pub interrupts: Counter,
+}
+
+/// A source for the instantaneous number of chains the guest has made
+/// available on a queue which the device has not processed yet.
+pub trait QueueDepth: Send + Sync {
+    fn queue_depth(&self) -> u16;
+}
+
+struct QueueEntry {
+    stats: Arc<QueueStats>,
+    depth: Option<Arc<dyn QueueDepth>>,
+}
+
+/// Statistics for one device. Holds the counters of every virtqueue of the
+/// device as well as any extra named counters maintained by the device worker.
+pub struct DeviceStats {
+    name: String,
+    queues: Mutex<Vec<QueueEntry>>,
+    counters: Mutex<Vec<(&'static str, Arc<Counter>)>>,
+    gauges: Mutex<Vec<(&'static str, Arc<Gauge>)>>,
+}
+
+impl DeviceStats {
+    pub fn new(name: &str) -> Self {
+        DeviceStats {
+            name: name.to_string(),
+            queues: Mutex::new(Vec::new()),
+            counters: Mutex::new(Vec::new()),
+            gauges: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn add_queue(&self, stats: Arc<QueueStats>, depth: Option<Arc<dyn QueueDepth>>) {
+        self.queues.lock().unwrap().push(QueueEntry { stats, depth });
+    }
+
+    /// Return the device specific counter `name`, creating it if it does not
+    /// exist yet.
+    pub fn counter(&self, name: &'static str) -> Arc<Counter> {
+        let mut counters = self.counters.lock().unwrap();
+        if let Some((_, c)) = counters.iter().find(|(n,_)| *n == name) {
+            return c.clone();
+        }
+        let c = Arc::new(Counter::default());
+        counters.push((name, c.clone()));
+        c
+    }
+
+    /// Report `counter` as `name`, replacing any counter previously added or
+    /// created with the same name.
+    pub fn add_counter(&self, name: &'static str, counter: Arc<Counter>) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.retain(|(n,_)| *n != name);
+        counters.push((name, counter));
+    }
+
+    /// Report `gauge` as `name`, replacing any gauge previously added with
+    /// the same name.
+    pub fn add_gauge(&self, name: &'static str, gauge: Arc<Gauge>) {
+        let mut gauges = self.gauges.lock().unwrap();
+        gauges.retain(|(n,_)| *n != name);
+        gauges.push((name, gauge));
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut queues = JsonValue::array();
+        for (idx, q) in self.queues.lock().unwrap().iter().enumerate() {
+            let depth = q.depth.as_ref().map(|d| d.queue_depth());
+            queues.push(JsonValue::object()
+                .field("index", idx)
+                .field("depth", depth)
+                .field("chains", q.stats.chains.get())
+                .field("bytes_read", q.stats.bytes_read.get())
+                .field("bytes_written", q.stats.bytes_written.get())
+                .field("notifications", q.stats.notifications.get())
+                .field("interrupts", q.stats.interrupts.get()));
+        }
+        let mut counters = JsonValue::object();
+        for (name, c) in self.counters.lock().unwrap().iter() {
+            counters.set(name, c.get());
+        }
+        let mut gauges = JsonValue::object();
+        for (name, g) in self.gauges.lock().unwrap().iter() {
+            gauges.set(name, g.get());
+        }
+        JsonValue::object()
+            .field("name", self.name.as_str())
+            .field("queues", queues)
+            .field("counters", counters)
+            .field("gauges", gauges)
+    }
+
+    fn write_prometheus(&self, out: &mut String) {
+        for (idx, q) in self.queues.lock().unwrap().iter().enumerate() {
+            let labels = format!("device=\"{}\",queue=\"{}\"", self.name, idx);
+            let _ = writeln!(out, "ph_virtqueue_chains_total{{{}}} {}", labels, q.stats.chains.get());
+            let _ = writeln!(out, "ph_virtqueue_read_bytes_total{{{}}} {}", labels, q.stats.bytes_read.get());
+            let _ = writeln!(out, "ph_virtqueue_written_bytes_total{{{}}} {}", labels, q.stats.bytes_written.get());
+            let _ = writeln!(out, "ph_virtqueue_notifications_total{{{}}} {}", labels, q.stats.notifications.get());
+            let _ = writeln!(out, "ph_virtqueue_interrupts_total{{{}}} {}", labels, q.stats.interrupts.get());
+            if let Some(depth) = q.depth.as_ref() {
+                let _ = writeln!(out, "ph_virtqueue_depth{{{}}} {}", labels, depth.queue_depth());
+            }
+        }
+        for (name, c) in self.counters.lock().unwrap().iter() {
+            let _ = writeln!(out, "ph_device_{}_total{{device=\"{}\"}} {}", name, self.name, c.get());
+        }
+        for (name, g) in self.gauges.lock().unwrap().iter() {
+            let _ = writeln!(out, "ph_device_{}{{device=\"{}\"}} {}", name, self.name, g.get());
+        }
+    }
+}
+
+/// Memory usage of the guest in bytes, as found in `/proc/meminfo` by ph-init.
+#[derive(Copy,Clone,Debug,Default,Eq,PartialEq)]
+pub struct GuestMemoryReport {
+    pub total: u64,
+    pub free: u64,
+    pub available: u64,
+    pub swap_total: u64,
+    pub swap_free: u64,
+}
+
+/// The latest `GuestMemoryReport` sent by ph-init and when it arrived. Lets
+/// the host decide which VMs to shrink or pause without a balloon device.
+#[derive(Default)]
+pub struct GuestMemoryStats {
+    last: Mutex<Option<(GuestMemoryReport, Instant)>>,
+}
+
+impl GuestMemoryStats {
+    pub fn update(&self, report: GuestMemoryReport) {
+        *self.last.lock().unwrap() = Some((report, Instant::now()));
+    }
+
+    /// The last report and the time since it arrived, or `None` if the guest
+    /// has not sent one.
+    pub fn last(&self) -> Option<(GuestMemoryReport, f64)> {
+        self.last.lock().unwrap()
+            .map(|(report, at)| (report, at.elapsed().as_secs_f64()))
+    }
+
+    fn to_json(&self) -> Option<JsonValue> {
+        self.last().map(|(r, age)| JsonValue::object()
+            .field("total", r.total)
+            .field("free", r.free)
+            .field("available", r.available)
+            .field("swap_total", r.swap_total)
+            .field("swap_free", r.swap_free)
+            .field("age_secs", age))
+    }
+
+    fn write_prometheus(&self, out: &mut String) {
+        let (r, age) = match self.last() {
+            Some(last) => last,
+            None => return,
+        };
+        let _ = writeln!(out, "ph_guest_memory_total_bytes {}", r.total);
+        let _ = writeln!(out, "ph_guest_memory_free_bytes {}", r.free);
+        let _ = writeln!(out, "ph_guest_memory_available_bytes {}", r.available);
+        let _ = writeln!(out, "ph_guest_swap_total_bytes {}", r.swap_total);
+        let _ = writeln!(out, "ph_guest_swap_free_bytes {}", r.swap_free);
+        let _ = writeln!(out, "ph_guest_memory_report_age_seconds {:.3}", age);
+    }
+}
+
+/// The set of `DeviceStats` for every device in the VM, and the memory
+/// usage reported by the guest.
+#[derive(Clone,Default)]
+pub struct StatsRegistry {
+    devices: Arc<Mutex<Vec<Arc<DeviceStats>>>>,
+    guest_memory: Arc<GuestMemoryStats>,
+}
+
+impl StatsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn devices(&self) -> MutexGuard<Vec<Arc<DeviceStats>>> {
+        self.devices.lock().unwrap()
+    }
+
+    /// Create and register stats for a new device. If a device with the same
+    /// name already exists a numeric suffix is added to keep names unique.
+    pub fn register_device(&self, name: &str) -> Arc<DeviceStats> {
+        let mut devices = self.devices();
+        let count = devices.iter()
+            .filter(|d| d.name() == name || d.name().starts_with(&format!("{}-", name)))
+            .count();
+        let name = if count == 0 {
+            name.to_string()
+        } else {
+            format!("{}-{}", name, count)
+        };
+        let stats = Arc::new(DeviceStats::new(&name));
+        devices.push(stats.clone());
+        stats
+    }
+
+    pub fn guest_memory(&self) -> Arc<GuestMemoryStats> {
+        self.guest_memory.clone()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut devices = JsonValue::array();
+        for d in self.devices().iter() {
+            devices.push(d.to_json());
+        }
+        JsonValue::object()
+            .field("devices", devices)
+            .field("guest_memory", self.guest_memory.to_json())
+            .field("log", JsonValue::object()
+                .field("suppressed", Logger::suppressed_count()))
+    }
+
+    /// Format all counters in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# TYPE ph_virtqueue_chains_total counter");
+        let _ = writeln!(out, "# TYPE ph_virtqueue_read_bytes_total counter");
+        let _ = writeln!(out, "# TYPE ph_virtqueue_written_bytes_total counter");
+        let _ = writeln!(out, "# TYPE ph_virtqueue_notifications_total counter");
+        let _ = writeln!(out, "# TYPE ph_virtqueue_interrupts_total counter");
+        let _ = writeln!(out, "# TYPE ph_virtqueue_depth gauge");
+        let _ = writeln!(out, "# TYPE ph_log_suppressed_total counter");
+        let _ = writeln!(out, "ph_log_suppressed_total {}", Logger::suppressed_count());
+        let _ = writeln!(out, "# TYPE ph_guest_memory_total_bytes gauge");
+        let _ = writeln!(out, "# TYPE ph_guest_memory_free_bytes gauge");
+        let _ = writeln!(out, "# TYPE ph_guest_memory_available_bytes gauge");
+        let _ = writeln!(out, "# TYPE ph_guest_swap_total_bytes gauge");
+        let _ = writeln!(out, "# TYPE ph_guest_swap_free_bytes gauge");
+        let _ = writeln!(out, "# TYPE ph_guest_memory_report_age_seconds gauge");
+        self.guest_memory.write_prometheus(&mut out);
+        for d in self.devices().iter() {
+            d.write_prometheus(&mut out);
+        }
+        out
+    }
+}
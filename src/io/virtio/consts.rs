@@ -24,6 +24,18 @@ impl VirtioDeviceType {
         Self::PCI_VIRTIO_DEVICE_ID_BASE + (*self as u16)
     }
 
+    /// Short name used to identify devices of this type in statistics
+    pub fn name(&self) -> &'static str {
+        match self {
+            VirtioDeviceType::Net => "net",
+            VirtioDeviceType::Block => "block",
+            VirtioDeviceType::Console => "console",
+            VirtioDeviceType::Rng => "rng",
+            VirtioDeviceType::NineP => "9p",
+            VirtioDeviceType::Wl => "wl",
+        }
+    }
+
     pub fn class_id(&self) -> u16 {
         match self {
             VirtioDeviceType::Net => Self::PCI_CLASS_NETWORK_ETHERNET,
@@ -56,6 +68,7 @@ pub const _VIRTIO_CONFIG_S_ACKNOWLEDGE : u8 = 1;
 pub const _VIRTIO_CONFIG_S_DRIVER      : u8 = 2;
 pub const VIRTIO_CONFIG_S_DRIVER_OK   : u8 = 4;
 pub const VIRTIO_CONFIG_S_FEATURES_OK : u8 = 8;
+pub const VIRTIO_CONFIG_S_NEEDS_RESET : u8 = 0x40;
 pub const VIRTIO_CONFIG_S_FAILED      : u8 = 0x80;
 
 pub const MAX_QUEUE_SIZE: u16 = 1024;
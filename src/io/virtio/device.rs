@@ -9,16 +9,70 @@ use crate::io::pci::{PciBar, PciBarAllocation, PciConfiguration, PciDevice};
 use crate::io::virtio::consts::*;
 use crate::io::virtio::features::FeatureBits;
 use crate::io::virtio::queues::Queues;
-use crate::io::virtio::Result;
+use crate::io::virtio::{DmaRanges, Result};
 use crate::io::PCI_VENDOR_ID_REDHAT;
-use crate::vm::KvmVm;
-
+use crate::io::stats::DeviceStats;
+use crate::system::sched::ThreadPriority;
+use crate::util::JsonValue;
+use crate::vm::VmOps;
+
+///
+/// A virtio device model. This is the only interface a device implements,
+/// everything to do with the virtio PCI transport (the common configuration
+/// registers, feature negotiation, queue setup, notifications, interrupts and
+/// device status) is handled by `VirtioDeviceState`, which wraps the device
+/// when it is added with `IoManager::add_virtio_device()`.
+///
+/// A minimal device only describes itself and starts a worker for each queue
+/// once the driver has finished initialization:
+///
+/// ```ignore
+/// struct VirtioExample { features: FeatureBits }
+///
+/// impl VirtioDevice for VirtioExample {
+///     fn features(&self) -> &FeatureBits { &self.features }
+///     fn queue_sizes(&self) -> &[u16] { &[VirtQueue::DEFAULT_QUEUE_SIZE] }
+///     fn device_type(&self) -> VirtioDeviceType { VirtioDeviceType::Rng }
+///
+///     fn start(&mut self, queues: &Queues) {
+///         let vq = queues.get_queue(0);
+///         queues.spawn_worker(move || vq.on_each_chain(|chain| {
+///             // read requests from and write replies to the chain
+///         }));
+///     }
+/// }
+/// ```
+///
+/// Workers should run on threads created with `Queues::spawn_worker()` so a
+/// panic is reported with the device name, and report errors caused by the
+/// guest with `VirtQueue::report_failure()`, which marks the device as needing
+/// a reset instead of stopping the VM. Devices which keep state across a
+/// driver reset implement `stop()`, and devices with a configuration area
+/// implement `config_size()`, `read_config()` and `write_config()`.
+///
 pub trait VirtioDevice: Send {
 
+    /// Feature bits offered to the driver. The bits accepted by the driver
+    /// can be read from here once `start()` is called.
     fn features(&self) -> &FeatureBits;
+
+    /// Called when the driver sets `FEATURES_OK`, return `false` to reject
+    /// the negotiated features.
     fn features_ok(&self) -> bool { true }
 
+    /// The maximum size of each queue the device uses.
     fn queue_sizes(&self) -> &[u16];
+
+    /// The number of queues at the start of `queue_sizes()` which the driver
+    /// must enable before the device can be started. Any later queues are
+    /// only used when the driver negotiates some feature (such as a control
+    /// queue) and are configured only if the driver enabled them.
+    fn required_queues(&self, features: u64) -> usize {
+        let _ = features;
+        self.queue_sizes().len()
+    }
+
+    /// The virtio device id, also used to name the device in `stats`.
     fn device_type(&self) -> VirtioDeviceType;
 
     fn config_size(&self) -> usize { 0 }
@@ -27,28 +81,47 @@ pub trait VirtioDevice: Send {
         let (_,_) = (offset, data);
     }
 
+    /// Called when the driver writes to the device configuration area.
+    /// Devices with writable fields should update their behavior here.
     fn write_config(&mut self, offset: u64, data: &[u8]) {
         let (_,_) = (offset, data);
     }
 
+    /// Called when the driver sets `DRIVER_OK` and the queues are ready.
     fn start(&mut self, queues: &Queues);
+
+    /// Called when the driver resets the device after `start()`. The queues
+    /// have already been stopped so workers waiting on a queue will wake up and
+    /// receive `Error::QueueStopped`. Implementations should wait for workers to
+    /// exit and recover any state they need so that `start()` can be called again.
+    fn stop(&mut self) {}
+
+    /// Called once after the VM has stopped, following `stop()` if the device
+    /// was running. Devices should finish writing any state they keep for the
+    /// host here.
+    fn shutdown(&mut self) {}
+
+    /// Describe device configuration (backing files, host interfaces) for
+    /// the `describe` control command.
+    fn describe(&self) -> Option<JsonValue> { None }
 }
 
 pub struct VirtioDeviceState {
     pci_config: PciConfiguration,
     device: Arc<Mutex<dyn VirtioDevice>>,
     status: u8,
+    started: bool,
     queues: Queues,
 }
 
 impl VirtioDeviceState {
 
-    pub fn new<T: VirtioDevice+'static>(device: T, kvm_vm: KvmVm, guest_memory: GuestMemoryMmap, irq: u8) -> Result<Self> {
+    pub fn new<T: VirtioDevice+'static>(device: T, vm: Arc<dyn VmOps>, guest_memory: GuestMemoryMmap, irq: u8, stats: Arc<DeviceStats>) -> Result<Self> {
         let devtype = device.device_type();
         let config_size = device.config_size();
 
         let device = Arc::new(Mutex::new(device));
-        let queues = Queues::new(kvm_vm, guest_memory, irq)?;
+        let queues = Queues::new(vm, guest_memory, irq, stats)?;
         let mut pci_config = PciConfiguration::new(queues.irq(), PCI_VENDOR_ID_REDHAT, devtype.device_id(), devtype.class_id());
         Self::add_pci_capabilities::<T>(&mut pci_config, config_size);
 
@@ -56,10 +129,23 @@ impl VirtioDeviceState {
             pci_config,
             device,
             status: 0,
+            started: false,
             queues,
         })
     }
 
+    /// Restrict the guest addresses of the buffers the driver passes to the
+    /// device. Must be called before the BARs are configured, when the queues
+    /// are created.
+    pub fn set_dma_ranges(&mut self, dma: DmaRanges) {
+        self.queues.set_dma_ranges(dma);
+    }
+
+    /// Scheduling hint for the worker threads of the device.
+    pub fn set_thread_priority(&mut self, priority: Option<ThreadPriority>) {
+        self.queues.set_thread_priority(priority);
+    }
+
     fn add_pci_capabilities<T: VirtioDevice>(pci_config: &mut PciConfiguration, config_size: usize) {
         VirtioPciCapability::new(VIRTIO_PCI_CAP_COMMON_CFG)
             .set_mmio_range(VIRTIO_MMIO_OFFSET_COMMON_CFG, VIRTIO_MMIO_COMMON_CFG_SIZE)
@@ -86,12 +172,36 @@ impl VirtioDeviceState {
     }
 
     fn reset(&mut self) {
+        if self.started {
+            self.queues.stop();
+            self.device().stop();
+            self.started = false;
+        }
         self.queues.reset();
         self.device().features().reset();
         self.status = 0;
     }
 
+    /// Stop the device if it is running and let it release its resources.
+    /// Used when the VM has stopped.
+    pub fn shutdown(&mut self) {
+        self.reset();
+        self.device().shutdown();
+    }
+
+    /// The status register as read by the driver, with NEEDS_RESET set if a
+    /// device worker has failed.
+    fn device_status(&self) -> u8 {
+        if self.queues.needs_reset() {
+            self.status | VIRTIO_CONFIG_S_NEEDS_RESET
+        } else {
+            self.status
+        }
+    }
+
     fn status_write(&mut self, val: u8) {
+        // NEEDS_RESET is only ever set by the device
+        let val = val & !VIRTIO_CONFIG_S_NEEDS_RESET;
         let new_bits = val & !self.status;
 
         let has_new_bit = |bit| -> bool {
@@ -111,10 +221,12 @@ impl VirtioDeviceState {
             }
         } else if has_new_bit(VIRTIO_CONFIG_S_DRIVER_OK) {
             let features = self.device().features().guest_value();
-            if let Err(err) = self.queues.configure_queues(features) {
+            let required = self.device().required_queues(features);
+            if let Err(err) = self.queues.configure_queues(features, required) {
                 warn!("Error configuring virtqueue: {}", err);
             } else {
-                self.device().start(&self.queues)
+                self.device().start(&self.queues);
+                self.started = true;
             }
         } else if has_new_bit(VIRTIO_CONFIG_S_FAILED) {
             // XXX print a warning
@@ -126,7 +238,7 @@ impl VirtioDeviceState {
             WriteableInt::Byte(n) => match offset {
                 /* device_status */
                 20 => self.status_write(n),
-                _ => warn!("VirtioDeviceState: common_config_write: unhandled byte offset {}", offset),
+                _ => warn_limited!("VirtioDeviceState: common_config_write: unhandled byte offset {}", offset),
             },
             WriteableInt::Word(n) => match offset {
                 /* queue_select */
@@ -135,7 +247,7 @@ impl VirtioDeviceState {
                 24 => self.queues.set_size(n),
                 /* queue_enable */
                 28 => self.queues.enable_current(),
-                _ => warn!("VirtioDeviceState: common_config_write: unhandled word offset {}", offset),
+                _ => warn_limited!("VirtioDeviceState: common_config_write: unhandled word offset {}", offset),
             }
             WriteableInt::DWord(n) => match offset {
                 /* device_feature_select */
@@ -156,14 +268,45 @@ impl VirtioDeviceState {
                 48 => self.queues.set_used_area(n, false),
                 /* queue_used_hi */
                 52 => self.queues.set_used_area(n, true),
-                _ => warn!("VirtioDeviceState: common_config_write: unhandled dword offset {}", offset),
+                _ => warn_limited!("VirtioDeviceState: common_config_write: unhandled dword offset {}", offset),
             },
-            WriteableInt::QWord(_) => warn!("VirtioDeviceState: common_config_write: unhandled qword offset {}", offset),
-            WriteableInt::Data(bs) => warn!("VirtioDeviceState: common_config_write: unhandled raw bytes offset {}, len {}", offset, bs.len()),
+            WriteableInt::QWord(n) => match offset {
+                /* queue_desc */
+                32 => {
+                    self.queues.set_current_descriptor_area(n as u32, false);
+                    self.queues.set_current_descriptor_area((n >> 32) as u32, true);
+                }
+                /* queue_avail */
+                40 => {
+                    self.queues.set_avail_area(n as u32, false);
+                    self.queues.set_avail_area((n >> 32) as u32, true);
+                }
+                /* queue_used */
+                48 => {
+                    self.queues.set_used_area(n as u32, false);
+                    self.queues.set_used_area((n >> 32) as u32, true);
+                }
+                _ => warn_limited!("VirtioDeviceState: common_config_write: unhandled qword offset {}", offset),
+            },
+            WriteableInt::Data(bs) => warn_limited!("VirtioDeviceState: common_config_write: unhandled raw bytes offset {}, len {}", offset, bs.len()),
         }
     }
 
-    fn common_config_read(&self, offset: u64) -> ReadableInt {
+    fn common_config_read(&self, offset: u64, size: usize) -> ReadableInt {
+        fn qword(lo: u32, hi: u32) -> ReadableInt {
+            ((u64::from(hi) << 32) | u64::from(lo)).into()
+        }
+        if size == 8 {
+            return match offset {
+                /* queue_desc */
+                32 => qword(self.queues.get_current_descriptor_area(false), self.queues.get_current_descriptor_area(true)),
+                /* queue_avail */
+                40 => qword(self.queues.get_avail_area(false), self.queues.get_avail_area(true)),
+                /* queue_used */
+                48 => qword(self.queues.get_used_area(false), self.queues.get_used_area(true)),
+                _ => ReadableInt::new_qword(0),
+            };
+        }
         match offset {
             /* device_feature_select */
             0 => self.device().features().device_selected().into(),
@@ -178,7 +321,7 @@ impl VirtioDeviceState {
             /* num_queues */
             18 => self.queues.num_queues().into(),
             /* device_status */
-            20 => self.status.into(),
+            20 => self.device_status().into(),
             /* config_generation */
             21 => (0u8).into(),
             /* queue_select */
@@ -239,25 +382,40 @@ impl PciDevice for VirtioDeviceState {
 
     fn read_bar(&mut self, bar: PciBar, offset: u64, data: &mut [u8]) {
         if bar != PciBar::Bar0 {
-            warn!("Virtio PciDevice: read_bar() expected bar0!");
+            warn_limited!("Virtio PciDevice: read_bar() expected bar0!");
             return;
 
         }
 
+        if offset == VIRTIO_MMIO_OFFSET_ISR && data.len() == 1 {
+            data[0] = self.isr_read();
+        } else {
+            self.peek_bar(bar, offset, data);
+        }
+    }
+
+    // Only reading the ISR register has a side effect
+    fn peek_bar(&self, bar: PciBar, offset: u64, data: &mut [u8]) -> bool {
+        if bar != PciBar::Bar0 {
+            return false;
+        }
         if self.is_common_cfg_range(offset, data.len()) {
-            let v = self.common_config_read(offset);
+            let v = self.common_config_read(offset, data.len());
             v.read(data);
         } else if offset == VIRTIO_MMIO_OFFSET_ISR && data.len() == 1 {
-            data[0] = self.isr_read();
+            data[0] = self.queues.isr_peek() as u8;
         } else if self.is_device_config_range(offset, data.len()) {
             let dev = self.device();
             dev.read_config(offset - VIRTIO_MMIO_OFFSET_DEV_CFG, data);
+        } else {
+            data.fill(0);
         }
+        true
     }
 
     fn write_bar(&mut self, bar: PciBar, offset: u64, data: &[u8]) {
         if bar != PciBar::Bar0 {
-            warn!("Virtio PciDevice: write_bar() expected bar0!");
+            warn_limited!("Virtio PciDevice: write_bar() expected bar0!");
             return;
         }
         if self.is_common_cfg_range(offset, data.len()) {
@@ -277,6 +435,23 @@ impl PciDevice for VirtioDeviceState {
         vec![PciBarAllocation::Mmio(PciBar::Bar0, VIRTIO_MMIO_AREA_SIZE)]
     }
 
+    fn device_info(&self) -> Option<JsonValue> {
+        let dev = self.device();
+        let features = dev.features();
+        let mut info = JsonValue::object()
+            .field("type", dev.device_type().name())
+            .field("status", self.device_status())
+            .field("driver_ok", self.status & VIRTIO_CONFIG_S_DRIVER_OK != 0)
+            .field("needs_reset", self.queues.needs_reset())
+            .field("device_features", format!("0x{:x}", features.device_value()))
+            .field("guest_features", format!("0x{:x}", features.guest_value()))
+            .field("queue_sizes", dev.queue_sizes().to_vec());
+        if let Some(config) = dev.describe() {
+            info.set("config", config);
+        }
+        Some(info)
+    }
+
     fn configure_bars(&mut self, allocations: Vec<(PciBar, u64)>) {
         for (bar,base) in allocations {
             if bar == PciBar::Bar0 {
@@ -369,13 +544,26 @@ impl DeviceConfigArea {
             data.copy_from_slice(&self.buffer[offset..offset+data.len()]);
         }
     }
-    pub fn write_config(&mut self, offset: u64, data: &[u8]) {
+    ///
+    /// Store a write from the driver if it falls entirely inside a range marked
+    /// with `set_writeable()`. Returns `true` if the write was accepted so that
+    /// the device can react to the new value of the field.
+    ///
+    pub fn write_config(&mut self, offset: u64, data: &[u8]) -> bool {
         let offset = offset as usize;
         if self.write_filter.is_writeable(offset, data.len()) {
             self.buffer[offset..offset+data.len()].copy_from_slice(data);
+            true
+        } else {
+            false
         }
     }
 
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        assert!(offset + 1 <= self.buffer.len());
+        self.buffer[offset]
+    }
+
     pub fn set_writeable(&mut self, offset: usize, size: usize) {
         self.write_filter.set_writable(offset, size)
     }
@@ -91,6 +91,10 @@ impl FeatureBits {
         }
     }
 
+    pub fn device_value(&self) -> u64 {
+        self.device().bits
+    }
+
     pub fn set_device_selected(&self, val: u32) {
         self.device().selected = val;
     }
@@ -11,6 +11,7 @@ pub use features::FeatureBits;
 pub use consts::VirtioDeviceType;
 pub use vq::virtqueue::VirtQueue;
 pub use vq::chain::Chain;
+pub use vq::dma::DmaRanges;
 use crate::io::bus::Error as BusError;
 
 use thiserror::Error;
@@ -28,6 +29,8 @@ pub enum Error {
     ReadIoEventFd(std::io::Error),
     #[error("VirtQueue not enabled")]
     QueueNotEnabled,
+    #[error("VirtQueue stopped by device reset")]
+    QueueStopped,
     #[error("VirtQueue descriptor table range is invalid 0x{0:x}")]
     RangeInvalid(u64),
     #[error("VirtQueue avail ring range range is invalid 0x{0:x}")]
@@ -1,29 +1,42 @@
+use std::fmt;
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use kvm_ioctls::{IoEventAddress, NoDatamatch};
+use std::thread::{self, JoinHandle};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use vm_memory::GuestMemoryMmap;
 use vmm_sys_util::eventfd::EventFd;
 use crate::io::virtio::{Error, Result};
 use crate::io::virtio::consts::VIRTIO_MMIO_OFFSET_NOTIFY;
+use crate::io::virtio::DmaRanges;
+use crate::system::sched::ThreadPriority;
 use crate::io::VirtQueue;
-use crate::vm::KvmVm;
+use crate::io::stats::{Counter, DeviceStats};
+use crate::system::fd_audit;
+use crate::vm::VmOps;
 
 pub struct InterruptLine {
     irqfd: EventFd,
     irq: u8,
     isr: AtomicUsize,
+    needs_reset: AtomicBool,
+    device_name: String,
+    failures: Arc<Counter>,
 }
 
 impl InterruptLine {
-    fn new(kvm_vm: &KvmVm, irq: u8) -> Result<InterruptLine> {
+    fn new(vm: &dyn VmOps, irq: u8, stats: &DeviceStats) -> Result<InterruptLine> {
         let irqfd = EventFd::new(0)
             .map_err(Error::CreateEventFd)?;
-        kvm_vm.vm_fd().register_irqfd(&irqfd, irq as u32)
+        fd_audit::register(irqfd.as_raw_fd(), "virtio irqfd");
+        vm.register_irqfd(&irqfd, irq as u32)
             .map_err(Error::IrqFd)?;
         Ok(InterruptLine{
             irqfd,
             irq,
-            isr: AtomicUsize::new(0)
+            isr: AtomicUsize::new(0),
+            needs_reset: AtomicBool::new(false),
+            device_name: stats.name().to_string(),
+            failures: stats.counter("failures"),
         })
 
     }
@@ -37,38 +50,84 @@ impl InterruptLine {
         self.isr.swap(0, Ordering::SeqCst) as u64
     }
 
+    fn isr_peek(&self) -> u64 {
+        self.isr.load(Ordering::SeqCst) as u64
+    }
+
     pub fn notify_queue(&self) {
         self.isr.fetch_or(0x1, Ordering::SeqCst);
-        self.irqfd.write(1).unwrap();
+        self.signal();
     }
 
     pub fn notify_config(&self) {
         self.isr.fetch_or(0x2, Ordering::SeqCst);
-        self.irqfd.write(1).unwrap();
+        self.signal();
+    }
+
+    fn signal(&self) {
+        if let Err(e) = self.irqfd.write(1) {
+            warn!("{}: failed to signal interrupt: {}", self.device_name, e);
+        }
+    }
+
+    /// Report a fatal device error. The device sets VIRTIO_CONFIG_S_NEEDS_RESET
+    /// in the status register and sends a configuration change interrupt so that
+    /// the driver can reset it. The rest of the VM keeps running.
+    pub fn set_needs_reset(&self, err: &dyn fmt::Display) {
+        warn!("{}: device failed and needs reset: {}", self.device_name, err);
+        self.failures.inc();
+        if !self.needs_reset.swap(true, Ordering::SeqCst) {
+            self.notify_config();
+        }
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.needs_reset.load(Ordering::SeqCst)
+    }
+
+    fn clear_needs_reset(&self) {
+        self.needs_reset.store(false, Ordering::SeqCst);
     }
 }
 
 pub struct Queues {
-    kvm_vm: KvmVm,
+    vm: Arc<dyn VmOps>,
     guest_memory: GuestMemoryMmap,
     selected_queue: u16,
     queues: Vec<VirtQueue>,
     interrupt: Arc<InterruptLine>,
+    stats: Arc<DeviceStats>,
+    dma: DmaRanges,
+    thread_priority: Option<ThreadPriority>,
 }
 
 impl Queues {
-    pub fn new(kvm_vm: KvmVm, guest_memory: GuestMemoryMmap, irq: u8) -> Result<Self> {
-        let interrupt = InterruptLine::new(&kvm_vm, irq)?;
+    pub fn new(vm: Arc<dyn VmOps>, guest_memory: GuestMemoryMmap, irq: u8, stats: Arc<DeviceStats>) -> Result<Self> {
+        let interrupt = InterruptLine::new(vm.as_ref(), irq, &stats)?;
         let queues = Queues {
-            kvm_vm,
+            vm,
             guest_memory,
             selected_queue: 0,
             queues: Vec::new(),
             interrupt: Arc::new(interrupt),
+            stats,
+            dma: DmaRanges::unrestricted(),
+            thread_priority: None,
         };
         Ok(queues)
     }
 
+    /// Restrict the buffers the driver may pass to queues created after this
+    /// call to `dma`.
+    pub fn set_dma_ranges(&mut self, dma: DmaRanges) {
+        self.dma = dma;
+    }
+
+    /// Scheduling hint applied to worker threads started after this call.
+    pub fn set_thread_priority(&mut self, priority: Option<ThreadPriority>) {
+        self.thread_priority = priority;
+    }
+
     pub fn get_queue(&self, idx: usize) -> VirtQueue {
         self.queues
             .get(idx)
@@ -84,20 +143,64 @@ impl Queues {
         &self.guest_memory
     }
 
-    pub fn kvm_vm(&self) -> &KvmVm {
-        &self.kvm_vm
+    /// Statistics for the device these queues belong to. Device workers can
+    /// use this to register additional device specific counters.
+    pub fn device_stats(&self) -> &Arc<DeviceStats> {
+        &self.stats
+    }
+
+    /// Run `f` on a new thread named after the device, so that a panic in a
+    /// device worker is reported with the device it belongs to. The thread
+    /// first applies the priority configured for the device, if any.
+    ///
+    /// If the thread cannot be created the device is marked as needing a
+    /// reset and `None` is returned.
+    pub fn spawn_worker<F, T>(&self, f: F) -> Option<JoinHandle<T>>
+        where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+    {
+        let name = self.stats.name().to_string();
+        let priority = self.thread_priority;
+        let result = thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                if let Some(priority) = priority {
+                    if let Err(e) = priority.apply() {
+                        warn!("{}: failed to set worker thread to {}: {}", name, priority, e);
+                    }
+                }
+                f()
+            });
+        match result {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                self.interrupt.set_needs_reset(&format_args!("failed to spawn worker thread: {}", e));
+                None
+            }
+        }
     }
 
-    pub fn configure_queues(&self, features: u64) -> Result<()> {
-        for q in &self.queues {
-            q.configure(features)?;
+    /// Configure the first `required` queues, which must all have been
+    /// enabled by the driver, and any later queues which are enabled.
+    pub fn configure_queues(&self, features: u64, required: usize) -> Result<()> {
+        for (idx, q) in self.queues.iter().enumerate() {
+            if idx < required || q.is_enabled() {
+                q.configure(features)?;
+            }
         }
         Ok(())
     }
 
+    /// Stop all queues so that device workers exit, see `VirtQueue::stop()`
+    pub fn stop(&self) {
+        for vq in &self.queues {
+            vq.stop();
+        }
+    }
+
     pub fn reset(&mut self) {
         self.selected_queue = 0;
         let _ = self.isr_read();
+        self.interrupt.clear_needs_reset();
         for vr in &mut self.queues {
             vr.reset();
         }
@@ -111,6 +214,16 @@ impl Queues {
         self.interrupt.isr_read()
     }
 
+    /// Current ISR value without clearing it as a guest read does
+    pub fn isr_peek(&self) -> u64 {
+        self.interrupt.isr_peek()
+    }
+
+    /// True if a device worker has reported a fatal error since the last reset.
+    pub fn needs_reset(&self) -> bool {
+        self.interrupt.needs_reset()
+    }
+
     pub fn num_queues(&self) -> u16 {
         self.queues.len() as u16
     }
@@ -119,7 +232,8 @@ impl Queues {
         let mut idx = 0;
         for &sz in queue_sizes {
             let ioevent = self.create_ioevent(idx, mmio_base)?;
-            let vq = VirtQueue::new(self.guest_memory.clone(), sz, self.interrupt.clone(), ioevent);
+            let vq = VirtQueue::new(self.guest_memory.clone(), sz, self.interrupt.clone(), ioevent, self.dma.clone());
+            self.stats.add_queue(vq.stats().clone(), Some(vq.depth()));
             self.queues.push(vq);
             idx += 1;
         }
@@ -129,14 +243,13 @@ impl Queues {
     fn create_ioevent(&self, index: usize, mmio_base: u64) -> Result<Arc<EventFd>> {
         let evt = EventFd::new(0)
             .map_err(Error::CreateEventFd)?;
+        fd_audit::register(evt.as_raw_fd(), "virtqueue ioeventfd");
 
         let notify_address = mmio_base +
             VIRTIO_MMIO_OFFSET_NOTIFY +
             (4 * index as u64);
 
-        let addr = IoEventAddress::Mmio(notify_address);
-
-        self.kvm_vm.vm_fd().register_ioevent(&evt, &addr, NoDatamatch)
+        self.vm.register_mmio_ioevent(&evt, notify_address)
             .map_err(Error::CreateIoEventFd)?;
 
         Ok(Arc::new(evt))
@@ -1,13 +1,21 @@
-use std::{fmt, io};
+use std::{cmp, fmt, io};
 use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
 use std::sync::{Arc, Mutex};
-use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, ReadVolatile, VolatileSlice};
+use std::sync::atomic::{AtomicBool, Ordering};
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, ReadVolatile, VolatileSlice};
 use crate::io::virtio::vq::descriptor::Descriptor;
 use crate::io::virtio::vq::virtqueue::QueueBackend;
+use crate::io::stats::QueueStats;
+
+// Maximum number of iovecs passed to a single readv()/writev() call
+const IOV_MAX: usize = 1024;
 
 pub struct DescriptorList {
     memory: GuestMemoryMmap,
     descriptors: Vec<Descriptor>,
+    // Descriptors which have been fully consumed, in chain order
+    consumed: Vec<Descriptor>,
     offset: usize,
     total_size: usize,
     consumed_size: usize,
@@ -18,6 +26,7 @@ impl DescriptorList {
         DescriptorList {
             memory,
             descriptors: Vec::new(),
+            consumed: Vec::new(),
             offset: 0,
             total_size: 0,
             consumed_size: 0,
@@ -35,6 +44,7 @@ impl DescriptorList {
 
     fn clear(&mut self) {
         self.descriptors.clear();
+        self.consumed.clear();
         self.offset = 0;
     }
 
@@ -46,16 +56,6 @@ impl DescriptorList {
         self.descriptors.last()
     }
 
-    fn current_address(&self, size: usize) -> Option<u64> {
-        self.current().and_then(|d| {
-            if d.remaining(self.offset) >= size {
-                Some(d.address() + self.offset as u64)
-            } else {
-                None
-            }
-        })
-    }
-
     fn inc(&mut self, len: usize) {
         let d = match self.current() {
             Some(d) => d,
@@ -66,34 +66,36 @@ impl DescriptorList {
         };
         let remaining = d.remaining(self.offset);
         if len > remaining {
-            warn!("Virtqueue descriptor buffer increment exceeds current size");
+            warn_limited!("Virtqueue descriptor buffer increment exceeds current size");
         }
         if len >= remaining {
             self.consumed_size += remaining;
             self.offset = 0;
-            self.descriptors.pop();
+            if let Some(d) = self.descriptors.pop() {
+                self.consumed.push(d);
+            }
         } else {
             self.consumed_size += len;
             self.offset += len;
         }
     }
 
-    fn read(&mut self, buf: &mut [u8]) -> usize {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if let Some(d) = self.current() {
-            let n = d.read_from(&self.memory, self.offset, buf);
+            let n = d.read_from(&self.memory, self.offset, buf)?;
             self.inc(n);
-            return n;
+            return Ok(n);
         }
-        0
+        Ok(0)
     }
 
-    fn write(&mut self, buf: &[u8]) -> usize {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         if let Some(d) = self.current() {
-            let n = d.write_to(&self.memory, self.offset, buf);
+            let n = d.write_to(&self.memory, self.offset, buf)?;
             self.inc(n);
-            return n;
+            return Ok(n);
         }
-        0
+        Ok(0)
     }
 
     fn write_from_reader<R>(&mut self, reader: &mut R, size: usize) -> io::Result<usize>
@@ -128,6 +130,107 @@ impl DescriptorList {
     fn remaining(&self) -> usize {
         self.total_size - self.consumed_size
     }
+
+    /// Move the current position forward by `len` bytes, possibly across
+    /// several descriptors.
+    fn advance(&mut self, mut len: usize) {
+        while len > 0 {
+            let remaining = match self.current() {
+                Some(d) => d.remaining(self.offset),
+                None => return,
+            };
+            let n = cmp::min(len, remaining);
+            self.inc(n);
+            len -= n;
+        }
+    }
+
+    /// Volatile slices covering the unconsumed part of the list starting at the
+    /// current position. At most `max` slices are returned.
+    fn slices(&self, max: usize) -> io::Result<Vec<VolatileSlice>> {
+        self.slices_limited(max, usize::MAX)
+    }
+
+    /// Like `slices()` but covering no more than `len` bytes in total.
+    fn slices_limited(&self, max: usize, len: usize) -> io::Result<Vec<VolatileSlice>> {
+        self.slices_at(max, 0, len)
+    }
+
+    /// Like `slices_limited()` but starting `skip` bytes past the current
+    /// position.
+    fn slices_at(&self, max: usize, mut skip: usize, len: usize) -> io::Result<Vec<VolatileSlice>> {
+        let mut slices = Vec::new();
+        let mut offset = self.offset;
+        let mut needed = len;
+        for d in self.descriptors.iter().rev() {
+            if needed == 0 || slices.len() == max {
+                break;
+            }
+            let remaining = d.remaining(offset);
+            if skip >= remaining {
+                skip -= remaining;
+                offset = 0;
+                continue;
+            }
+            offset += skip;
+            skip = 0;
+            let size = cmp::min(d.remaining(offset), needed);
+            if size > 0 {
+                needed -= size;
+                let addr = d.address() + offset as u64;
+                let slice = self.memory.get_slice(GuestAddress(addr), size)
+                    .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+                slices.push(slice);
+            }
+            offset = 0;
+        }
+        Ok(slices)
+    }
+
+    /// Split the range of `len` bytes starting `offset` bytes from the beginning
+    /// of the list into guest memory (address, length) pairs.
+    fn ranges_at(&self, offset: usize, len: usize) -> io::Result<Vec<(GuestAddress, usize)>> {
+        let mut ranges = Vec::new();
+        let mut skip = offset;
+        let mut needed = len;
+        for d in self.consumed.iter().chain(self.descriptors.iter().rev()) {
+            if needed == 0 {
+                break;
+            }
+            if skip >= d.length() {
+                skip -= d.length();
+                continue;
+            }
+            let n = cmp::min(needed, d.length() - skip);
+            ranges.push((GuestAddress(d.address() + skip as u64), n));
+            needed -= n;
+            skip = 0;
+        }
+        if needed > 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        Ok(ranges)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: usize) -> io::Result<()> {
+        let mut pos = 0;
+        for (addr, n) in self.ranges_at(offset, buf.len())? {
+            self.memory.read_slice(&mut buf[pos..pos + n], addr)
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+            pos += n;
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: usize) -> io::Result<()> {
+        let mut pos = 0;
+        for (addr, n) in self.ranges_at(offset, buf.len())? {
+            self.memory.write_slice(&buf[pos..pos + n], addr)
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))?;
+            pos += n;
+        }
+        Ok(())
+    }
 }
 
 impl fmt::Debug for DescriptorList {
@@ -142,15 +245,19 @@ impl fmt::Debug for DescriptorList {
 
 pub struct Chain {
     backend: Arc<Mutex<dyn QueueBackend>>,
+    stats: Arc<QueueStats>,
+    stopped: Arc<AtomicBool>,
     head: Option<u16>,
     readable: DescriptorList,
     writeable: DescriptorList,
 }
 
 impl Chain {
-    pub fn new(backend: Arc<Mutex<dyn QueueBackend>>, head: u16, readable: DescriptorList, writeable: DescriptorList) -> Self {
+    pub fn new(backend: Arc<Mutex<dyn QueueBackend>>, stats: Arc<QueueStats>, stopped: Arc<AtomicBool>, head: u16, readable: DescriptorList, writeable: DescriptorList) -> Self {
         Chain {
             backend,
+            stats,
+            stopped,
             head: Some(head),
             readable,
             writeable,
@@ -197,12 +304,32 @@ impl Chain {
             self.readable.clear();
             self.writeable.clear();
             let backend = self.backend.lock().unwrap();
-            backend.put_used(head, self.writeable.consumed_size as u32);
+            // A chain which was taken from the queue before the device was reset
+            // must not be placed on the used ring once the queue is reconfigured.
+            // Checking while holding the backend lock orders this against reset.
+            if self.stopped.load(Ordering::Acquire) {
+                return;
+            }
+            if backend.put_used(head, self.writeable.consumed_size as u32) {
+                self.stats.interrupts.inc();
+            }
+            self.stats.chains.inc();
+            self.stats.bytes_read.add(self.readable.consumed_size as u64);
+            self.stats.bytes_written.add(self.writeable.consumed_size as u64);
         }
     }
 
-    pub fn current_write_address(&mut self, size: usize) -> Option<u64> {
-        self.writeable.current_address(size)
+    /// Give back a chain which nothing has been written to so that it is
+    /// returned by the next call to `next_chain()` rather than being placed
+    /// on the used ring with a length of 0. Only the chain most recently
+    /// taken from the queue can be returned.
+    pub fn return_unused(mut self) {
+        if self.head.take().is_some() {
+            let backend = self.backend.lock().unwrap();
+            if !self.stopped.load(Ordering::Acquire) {
+                backend.undo_next();
+            }
+        }
     }
 
     pub fn remaining_read(&self) -> usize {
@@ -226,14 +353,14 @@ impl Chain {
     }
 
     pub fn inc_read_offset(&mut self, sz: usize) {
-        self.readable.inc(sz);
+        self.readable.advance(sz);
     }
 
     pub fn inc_write_offset(&mut self, sz: usize) {
         if !self.readable.is_empty() {
             self.readable.clear();
         }
-        self.writeable.inc(sz);
+        self.writeable.advance(sz);
     }
 
     pub fn current_write_slice(&mut self) -> VolatileSlice {
@@ -245,13 +372,162 @@ impl Chain {
     {
         self.writeable.write_from_reader(r, size)
     }
+
+    /// Slices of guest memory covering the remaining readable part of the chain.
+    pub fn readable_slices(&self) -> io::Result<Vec<VolatileSlice>> {
+        self.readable.slices(usize::MAX)
+    }
+
+    /// Slices of guest memory covering the remaining writeable part of the chain.
+    pub fn writeable_slices(&self) -> io::Result<Vec<VolatileSlice>> {
+        self.writeable.slices(usize::MAX)
+    }
+
+    /// Slices of guest memory covering up to `len` bytes of the writeable part
+    /// of the chain starting `offset` bytes past the current write position.
+    /// The write position is not changed, so this allows data to be placed
+    /// after a header which can only be written once the size of the data is
+    /// known.
+    pub fn writeable_slices_at(&self, offset: usize, len: usize) -> io::Result<Vec<VolatileSlice>> {
+        self.writeable.slices_at(IOV_MAX, offset, len)
+    }
+
+    /// Write the remaining readable part of the chain to `fd` with a single
+    /// `writev()` call and advance the read position by the number of bytes
+    /// written.
+    pub fn writev_to<F: AsRawFd>(&mut self, fd: &F) -> io::Result<usize> {
+        let n = {
+            let slices = self.readable.slices(IOV_MAX)?;
+            io_slices(&slices, |iov, cnt| unsafe {
+                libc::writev(fd.as_raw_fd(), iov, cnt)
+            })?
+        };
+        self.readable.advance(n);
+        Ok(n)
+    }
+
+    /// Fill the remaining writeable part of the chain from `fd` with a single
+    /// `readv()` call and advance the write position by the number of bytes
+    /// read.
+    pub fn readv_from<F: AsRawFd>(&mut self, fd: &F) -> io::Result<usize> {
+        let n = {
+            let slices = self.writeable.slices(IOV_MAX)?;
+            io_slices(&slices, |iov, cnt| unsafe {
+                libc::readv(fd.as_raw_fd(), iov, cnt)
+            })?
+        };
+        self.writeable.advance(n);
+        Ok(n)
+    }
+
+    /// Write up to `len` bytes of the readable part of the chain to `fd` at
+    /// file offset `offset` with a single `pwritev()` call, directly from guest
+    /// memory. The read position is advanced by the number of bytes written.
+    pub fn pwritev_to<F: AsRawFd>(&mut self, fd: &F, offset: u64, len: usize) -> io::Result<usize> {
+        let n = {
+            let slices = self.readable.slices_limited(IOV_MAX, len)?;
+            io_slices(&slices, |iov, cnt| unsafe {
+                libc::pwritev64(fd.as_raw_fd(), iov, cnt, offset as libc::off64_t)
+            })?
+        };
+        self.readable.advance(n);
+        Ok(n)
+    }
+
+    /// Read up to `len` bytes from `fd` at file offset `offset` directly into
+    /// the writeable part of the chain with a single `preadv()` call. The
+    /// write position is advanced by the number of bytes read.
+    pub fn preadv_from<F: AsRawFd>(&mut self, fd: &F, offset: u64, len: usize) -> io::Result<usize> {
+        let n = {
+            let slices = self.writeable.slices_limited(IOV_MAX, len)?;
+            io_slices(&slices, |iov, cnt| unsafe {
+                libc::preadv64(fd.as_raw_fd(), iov, cnt, offset as libc::off64_t)
+            })?
+        };
+        self.inc_write_offset(n);
+        Ok(n)
+    }
+
+    /// Read exactly `buf.len()` bytes from the readable part of the chain
+    /// starting `offset` bytes from the beginning of the chain. The current read
+    /// position is not changed.
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: usize) -> io::Result<()> {
+        self.readable.read_exact_at(buf, offset)
+    }
+
+    /// Write all of `buf` to the writeable part of the chain starting `offset`
+    /// bytes from the beginning of the writeable area. Neither the current write
+    /// position nor the length reported to the guest are changed, so this is
+    /// intended for filling in header fields after the rest of a reply has been
+    /// written.
+    pub fn write_all_at(&self, buf: &[u8], offset: usize) -> io::Result<()> {
+        self.writeable.write_all_at(buf, offset)
+    }
+}
+
+// Build an iovec array for `slices` and pass it to `f`, which performs the
+// vectored I/O call and returns its raw result.
+fn io_slices<F>(slices: &[VolatileSlice], f: F) -> io::Result<usize>
+    where F: FnOnce(*const libc::iovec, libc::c_int) -> isize
+{
+    if slices.is_empty() {
+        return Ok(0);
+    }
+    let guards: Vec<_> = slices.iter().map(|s| s.ptr_guard_mut()).collect();
+    let iovecs: Vec<libc::iovec> = guards.iter().zip(slices.iter())
+        .map(|(g, s)| libc::iovec { iov_base: g.as_ptr() as *mut libc::c_void, iov_len: s.len() })
+        .collect();
+    let ret = f(iovecs.as_ptr(), iovecs.len() as libc::c_int);
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Queue backend for chains which are not attached to a virtqueue. Returning
+/// the chain to the used ring does nothing.
+#[cfg(feature = "fuzzing")]
+struct DetachedBackend;
+
+#[cfg(feature = "fuzzing")]
+impl QueueBackend for DetachedBackend {
+    fn configure(&mut self, _descriptor_area: u64, _driver_area: u64, _device_area: u64, _size: u16, _features: u64) -> crate::io::virtio::Result<()> {
+        Ok(())
+    }
+    fn reset(&mut self) {}
+    fn is_empty(&self) -> bool { true }
+    fn next_descriptors(&self) -> Option<(u16, DescriptorList, DescriptorList)> { None }
+    fn put_used(&self, _id: u16, _size: u32) -> bool { false }
+    fn undo_next(&self) {}
+    fn pending(&self) -> u16 { 0 }
+}
+
+#[cfg(feature = "fuzzing")]
+impl Chain {
+    /// Build a chain over buffers in `memory` given as `(address, length)` pairs
+    /// rather than taking one from a virtqueue. This allows device request
+    /// parsers to be driven directly from byte buffers.
+    pub fn from_buffers(memory: GuestMemoryMmap, readable: &[(u64, u32)], writeable: &[(u64, u32)]) -> Chain {
+        fn descriptor_list(memory: &GuestMemoryMmap, buffers: &[(u64, u32)]) -> DescriptorList {
+            let mut list = DescriptorList::new(memory.clone());
+            for &(address, length) in buffers {
+                list.add_descriptor(Descriptor::new(address, length, 0, 0));
+            }
+            list.reverse();
+            list
+        }
+        let readable = descriptor_list(&memory, readable);
+        let writeable = descriptor_list(&memory, writeable);
+        let backend: Arc<Mutex<dyn QueueBackend>> = Arc::new(Mutex::new(DetachedBackend));
+        Chain::new(backend, Arc::new(QueueStats::default()), Arc::new(AtomicBool::new(false)), 0, readable, writeable)
+    }
 }
 
 impl Read for Chain {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let mut nread = 0usize;
         while nread < buf.len() {
-            nread += match self.readable.read(&mut buf[nread..]) {
+            nread += match self.readable.read(&mut buf[nread..])? {
                 0 => return Ok(nread),
                 n => n,
             };
@@ -263,7 +539,7 @@ impl Write for Chain {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let mut nwrote = 0;
         while nwrote < buf.len() {
-            match self.writeable.write(&buf[nwrote..]) {
+            match self.writeable.write(&buf[nwrote..])? {
                 0 => return Ok(nwrote),
                 n => nwrote += n,
             };
@@ -1,5 +1,5 @@
 use std::{cmp, io};
-use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, ReadVolatile};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, ReadVolatile, VolatileMemoryError};
 
 #[repr(u16)]
 enum DescriptorFlag {
@@ -82,32 +82,48 @@ impl Descriptor {
         (used != avail) && (avail == wrap_counter)
     }
 
-    pub fn read_from(&self, memory: &GuestMemoryMmap, offset: usize, buf: &mut[u8]) -> usize {
+    fn guest_address(&self, offset: usize) -> io::Result<GuestAddress> {
+        GuestAddress(self.address).checked_add(offset as u64)
+            .ok_or_else(efault)
+    }
+
+    pub fn read_from(&self, memory: &GuestMemoryMmap, offset: usize, buf: &mut[u8]) -> io::Result<usize> {
         let sz = cmp::min(buf.len(), self.remaining(offset));
         if sz > 0 {
-            let address = GuestAddress(self.address).checked_add(offset as u64).unwrap();
-            memory.read_slice(&mut buf[..sz], address).unwrap();
+            let address = self.guest_address(offset)?;
+            memory.read_slice(&mut buf[..sz], address)
+                .map_err(|_| efault())?;
         }
-        sz
+        Ok(sz)
     }
 
-    pub fn write_to(&self, memory: &GuestMemoryMmap, offset: usize, buf: &[u8]) -> usize {
+    pub fn write_to(&self, memory: &GuestMemoryMmap, offset: usize, buf: &[u8]) -> io::Result<usize> {
         let sz = cmp::min(buf.len(), self.remaining(offset));
         if sz > 0 {
-            let address = GuestAddress(self.address).checked_add(offset as u64).unwrap();
-            memory.write_slice(&buf[..sz], address).unwrap();
+            let address = self.guest_address(offset)?;
+            memory.write_slice(&buf[..sz], address)
+                .map_err(|_| efault())?;
         }
-        sz
+        Ok(sz)
     }
 
     pub fn write_from_reader<R: ReadVolatile+Sized>(&self, memory: &GuestMemoryMmap, offset: usize, r: &mut R, size: usize) -> io::Result<usize> {
         let sz = cmp::min(size, self.remaining(offset));
         if sz > 0 {
-            let address = GuestAddress(self.address).checked_add(offset as u64).unwrap();
-            let mut slice = memory.get_slice(address, sz).unwrap();
-            let sz = r.read_volatile(&mut slice).unwrap();
-            return Ok(sz)
+            let address = self.guest_address(offset)?;
+            let mut slice = memory.get_slice(address, sz)
+                .map_err(|_| efault())?;
+            return r.read_volatile(&mut slice).map_err(|e| match e {
+                VolatileMemoryError::IOError(e) => e,
+                e => io::Error::other(e),
+            });
         }
         Ok(0)
     }
-}
\ No newline at end of file
+}
+
+/// Descriptors are supplied by the guest so a descriptor which does not refer
+/// to guest memory is an error rather than a bug.
+fn efault() -> io::Error {
+    io::Error::from_raw_os_error(libc::EFAULT)
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+/// Guest physical ranges which buffers passed to a device may refer to.
+///
+/// By default a descriptor may point anywhere in guest RAM. With strict DMA
+/// checking the buffers must also fall inside one of a fixed list of ranges,
+/// normally the RAM entries of the memory map given to the guest, so that a
+/// driver bug which hands a device a stray address is caught rather than
+/// letting the device write over memory the guest does not manage.
+#[derive(Clone,Default)]
+pub struct DmaRanges {
+    // Sorted (start, end) pairs with an exclusive end, or `None` when
+    // checking is disabled
+    ranges: Option<Arc<Vec<(u64, u64)>>>,
+}
+
+impl DmaRanges {
+    /// Accept any address in guest RAM
+    pub fn unrestricted() -> Self {
+        DmaRanges::default()
+    }
+
+    /// Only accept buffers which fall inside one of `ranges` (base, size)
+    pub fn strict(ranges: &[(u64, u64)]) -> Self {
+        let mut ranges: Vec<(u64, u64)> = ranges.iter()
+            .filter(|&&(_, size)| size > 0)
+            .map(|&(base, size)| (base, base.saturating_add(size)))
+            .collect();
+        ranges.sort();
+        DmaRanges { ranges: Some(Arc::new(ranges)) }
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.ranges.is_some()
+    }
+
+    /// True if `size` bytes at `address` are inside a single allowed range.
+    /// Adjacent ranges are not merged, a buffer may not span two of them.
+    pub fn contains(&self, address: u64, size: usize) -> bool {
+        let ranges = match self.ranges {
+            Some(ref ranges) => ranges,
+            None => return true,
+        };
+        let end = match address.checked_add(size as u64) {
+            Some(end) => end,
+            None => return false,
+        };
+        ranges.iter().any(|&(start, limit)| address >= start && end <= limit)
+    }
+}
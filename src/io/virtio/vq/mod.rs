@@ -3,6 +3,7 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub mod chain;
 mod descriptor;
+pub mod dma;
 mod splitqueue;
 pub mod virtqueue;
 
@@ -22,6 +23,9 @@ impl SharedIndex {
     fn inc(&self) {
         self.0.fetch_add(1, Ordering::SeqCst);
     }
+    fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
     fn set(&self, v: u16) {
         self.0.store(v as usize, Ordering::SeqCst);
     }
@@ -6,6 +6,7 @@ use crate::io::virtio::features::ReservedFeatureBit;
 use crate::io::virtio::queues::InterruptLine;
 use crate::io::virtio::vq::chain::DescriptorList;
 use crate::io::virtio::vq::descriptor::Descriptor;
+use crate::io::virtio::vq::dma::DmaRanges;
 use crate::io::virtio::vq::SharedIndex;
 use crate::io::virtio::vq::virtqueue::QueueBackend;
 
@@ -13,6 +14,7 @@ use crate::io::virtio::vq::virtqueue::QueueBackend;
 pub struct SplitQueue {
     memory: GuestMemoryMmap,
     interrupt: Arc<InterruptLine>,
+    dma: DmaRanges,
 
     queue_size: u16,
     features: u64,
@@ -29,10 +31,11 @@ pub struct SplitQueue {
 }
 
 impl SplitQueue {
-    pub fn new(memory: GuestMemoryMmap, interrupt: Arc<InterruptLine>) -> Self {
+    pub fn new(memory: GuestMemoryMmap, interrupt: Arc<InterruptLine>, dma: DmaRanges) -> Self {
         SplitQueue {
             memory,
             interrupt,
+            dma,
             queue_size: 0,
             features: 0,
             descriptor_base: 0,
@@ -50,7 +53,7 @@ impl SplitQueue {
     ///
     fn load_descriptor(&self, idx: u16) -> Option<Descriptor> {
         if idx >= self.queue_size {
-            panic!("load_descriptor called with index larger than queue size");
+            return None;
         }
         let head = self.descriptor_base + (idx as u64 * 16);
 
@@ -59,10 +62,38 @@ impl SplitQueue {
         let flags = self.memory.read_obj::<u16>(GuestAddress(head + 12)).ok()?;
         let next = self.memory.read_obj::<u16>(GuestAddress(head + 14)).ok()?;
 
-        if self.memory.check_range(GuestAddress(addr), len as usize) && next < self.queue_size {
-            return Some(Descriptor::new(addr, len, flags, next));
+        if !self.memory.check_range(GuestAddress(addr), len as usize) || next >= self.queue_size {
+            return None;
         }
-        None
+        if let Err(reason) = self.check_dma(addr, len) {
+            self.interrupt.set_needs_reset(&format_args!("descriptor {} at 0x{:x} with length {} {}", idx, addr, len, reason));
+            return None;
+        }
+        Some(Descriptor::new(addr, len, flags, next))
+    }
+
+    ///
+    /// With strict DMA checking, reject a buffer which is outside of the
+    /// allowed ranges or which overlaps one of the rings of this queue.
+    ///
+    fn check_dma(&self, addr: u64, len: u32) -> Result<(), &'static str> {
+        if !self.dma.is_strict() {
+            return Ok(());
+        }
+        if !self.dma.contains(addr, len as usize) {
+            return Err("is outside of the DMA ranges");
+        }
+        let size = self.queue_size as u64;
+        let rings = [
+            (self.descriptor_base, 16 * size),
+            (self.avail_base, 6 + 2 * size),
+            (self.used_base, 6 + 8 * size),
+        ];
+        let end = addr + len as u64;
+        if rings.iter().any(|&(base, sz)| addr < base + sz && base < end) {
+            return Err("overlaps the virtqueue rings");
+        }
+        Ok(())
     }
 
     fn load_descriptor_lists(&self, head: u16) -> (DescriptorList,DescriptorList) {
@@ -71,9 +102,14 @@ impl SplitQueue {
         let mut idx = head;
         let mut ttl = self.queue_size;
 
+        if head >= self.queue_size {
+            // The head index comes from the avail ring written by the guest
+            self.interrupt.set_needs_reset(&format_args!("avail ring entry {} is larger than queue size", head));
+        }
+
         while let Some(d) = self.load_descriptor(idx) {
             if ttl == 0 {
-                warn!("Descriptor chain length exceeded ttl");
+                warn_limited!("Descriptor chain length exceeded ttl");
                 break;
             } else {
                 ttl -= 1;
@@ -82,7 +118,7 @@ impl SplitQueue {
                 writeable.add_descriptor(d);
             } else {
                 if !writeable.is_empty() {
-                    warn!("Guest sent readable virtqueue descriptor after writeable descriptor in violation of specification");
+                    warn_limited!("Guest sent readable virtqueue descriptor after writeable descriptor in violation of specification");
                 }
                 readable.add_descriptor(d);
             }
@@ -132,6 +168,17 @@ impl SplitQueue {
         Some(avail_entry)
     }
 
+    ///
+    /// Step `next_avail` back by one so that the last entry returned by
+    /// `pop_avail_entry()` is returned again.
+    ///
+    fn unpop_avail_entry(&self) {
+        self.next_avail.dec();
+        if self.has_event_idx() {
+            self.write_avail_event(self.next_avail.get());
+        }
+    }
+
     fn read_avail_flags(&self) -> u16 {
         self.memory.read_obj::<u16>(GuestAddress(self.avail_base)).unwrap()
     }
@@ -255,11 +302,29 @@ impl QueueBackend for SplitQueue {
         })
     }
 
-    fn put_used(&self, id: u16, size: u32) {
+    fn put_used(&self, id: u16, size: u32) -> bool {
         let used = self.next_used_idx.get();
         self.put_used_entry(id, size);
         if self.need_interrupt(used) {
             self.interrupt.notify_queue();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn undo_next(&self) {
+        self.unpop_avail_entry();
+    }
+
+    fn pending(&self) -> u16 {
+        if self.queue_size == 0 {
+            return 0;
         }
+        // Read directly rather than with load_avail_idx() so that calling
+        // this from another thread does not disturb cached_avail_idx
+        let avail_idx = self.memory.read_obj::<u16>(GuestAddress(self.avail_base + 2))
+            .unwrap_or(0);
+        avail_idx.wrapping_sub(self.next_avail.get())
     }
 }
\ No newline at end of file
@@ -1,4 +1,6 @@
+use std::{fmt, io};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
 use vm_memory::GuestMemoryMmap;
 
 use vmm_sys_util::eventfd::EventFd;
@@ -7,7 +9,9 @@ use crate::io::virtio::{Error, Result};
 use crate::io::virtio::consts::MAX_QUEUE_SIZE;
 use crate::io::virtio::queues::InterruptLine;
 use crate::io::virtio::vq::chain::{Chain, DescriptorList};
+use crate::io::virtio::vq::dma::DmaRanges;
 use crate::io::virtio::vq::splitqueue::SplitQueue;
+use crate::io::stats::{QueueDepth, QueueStats};
 
 pub trait QueueBackend: Send {
 
@@ -18,7 +22,26 @@ pub trait QueueBackend: Send {
 
 
     fn next_descriptors(&self) -> Option<(u16, DescriptorList,DescriptorList)>;
-    fn put_used(&self, id: u16, size: u32);
+
+    /// Place chain `id` on the used ring and return `true` if the guest was
+    /// notified with an interrupt.
+    fn put_used(&self, id: u16, size: u32) -> bool;
+
+    /// Return the chain most recently retrieved with `next_descriptors()` to
+    /// the available ring without placing anything on the used ring.
+    fn undo_next(&self);
+
+    /// Number of chains available from the guest which have not been
+    /// retrieved with `next_descriptors()` yet.
+    fn pending(&self) -> u16;
+}
+
+struct BackendDepth(Arc<Mutex<dyn QueueBackend>>);
+
+impl QueueDepth for BackendDepth {
+    fn queue_depth(&self) -> u16 {
+        self.0.lock().unwrap().pending()
+    }
 }
 
 #[derive(Clone)]
@@ -39,14 +62,23 @@ pub struct VirtQueue {
 
     /// Has this virtqueue been enabled?
     enabled: bool,
+
+    stats: Arc<QueueStats>,
+
+    interrupt: Arc<InterruptLine>,
+
+    /// Set when the device is reset. Shared by every clone handed out to
+    /// device workers until `reset()` replaces it for the next start.
+    stopped: Arc<AtomicBool>,
 }
 
 impl VirtQueue {
     pub const DEFAULT_QUEUE_SIZE: u16 = 128;
 
-    pub fn new(memory: GuestMemoryMmap, default_size: u16, interrupt: Arc<InterruptLine>, ioeventfd: Arc<EventFd>) -> Self {
-        let backend = Arc::new(Mutex::new(SplitQueue::new(memory, interrupt)));
+    pub fn new(memory: GuestMemoryMmap, default_size: u16, interrupt: Arc<InterruptLine>, ioeventfd: Arc<EventFd>, dma: DmaRanges) -> Self {
+        let backend = Arc::new(Mutex::new(SplitQueue::new(memory, interrupt.clone(), dma)));
         VirtQueue {
+            stats: Arc::new(QueueStats::default()),
             ioeventfd,
             default_size,
             queue_size: default_size,
@@ -55,6 +87,8 @@ impl VirtQueue {
             device_area: 0,
             backend,
             enabled: false,
+            interrupt,
+            stopped: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -62,6 +96,16 @@ impl VirtQueue {
         self.backend.lock().unwrap()
     }
 
+    pub fn stats(&self) -> &Arc<QueueStats> {
+        &self.stats
+    }
+
+    /// Returns an object which reports the current number of pending chains
+    /// on this queue.
+    pub fn depth(&self) -> Arc<dyn QueueDepth> {
+        Arc::new(BackendDepth(self.backend.clone()))
+    }
+
     pub fn descriptor_area(&self) -> u64 {
         self.descriptor_area
     }
@@ -124,6 +168,38 @@ impl VirtQueue {
         self.device_area = 0;
         self.enabled = false;
         self.backend().reset();
+        self.stopped = Arc::new(AtomicBool::new(false));
+    }
+
+    ///
+    /// Stop processing this queue because the device is being reset. Any worker
+    /// blocked in `wait_ready()` is woken and receives `Error::QueueStopped`, and
+    /// no further chains are returned from `next_chain()` on clones of this queue.
+    ///
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        let _ = self.ioeventfd.write(1);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Acquire)
+    }
+
+    ///
+    /// Report an error which prevents the device worker using this queue from
+    /// continuing. The device is marked as needing a reset and the driver is
+    /// notified. Errors caused by the queue being stopped are not failures
+    /// and are ignored.
+    ///
+    pub fn report_failure(&self, err: &dyn fmt::Display) {
+        if !self.is_stopped() {
+            self.interrupt.set_needs_reset(err);
+        }
+    }
+
+    /// Notify the driver that the device configuration area has changed.
+    pub fn notify_config(&self) {
+        self.interrupt.notify_config();
     }
 
     pub fn configure(&self, features: u64) -> Result<()> {
@@ -141,13 +217,28 @@ impl VirtQueue {
     }
 
     pub fn wait_ready(&self) -> Result<()> {
+        if self.is_stopped() {
+            return Err(Error::QueueStopped);
+        }
         if self.is_empty() {
-            let _ = self.ioeventfd.read()
+            let _ = self.read_ioevent()
                 .map_err(Error::ReadIoEventFd)?;
+            if self.is_stopped() {
+                return Err(Error::QueueStopped);
+            }
         }
         Ok(())
     }
 
+    /// Read (and clear) the queue notification eventfd. Device workers which
+    /// poll on `ioevent()` should call this instead of reading the eventfd
+    /// directly so that notifications are counted.
+    pub fn read_ioevent(&self) -> io::Result<u64> {
+        let n = self.ioeventfd.read()?;
+        self.stats.notifications.add(n);
+        Ok(n)
+    }
+
     pub fn wait_next_chain(&self) -> Result<Chain> {
         loop {
             self.wait_ready()?;
@@ -158,15 +249,25 @@ impl VirtQueue {
     }
 
     pub fn next_chain(&self) -> Option<Chain> {
+        if self.is_stopped() {
+            return None;
+        }
         self.backend().next_descriptors().map(|(id, r, w)| {
-            Chain::new(self.backend.clone(), id, r, w)
+            Chain::new(self.backend.clone(), self.stats.clone(), self.stopped.clone(), id, r, w)
         })
     }
 
+    /// Call `f` for each chain as it becomes available. Returns when the queue
+    /// is stopped by a device reset.
     pub fn on_each_chain<F>(&self, mut f: F)
         where F: FnMut(Chain) {
         loop {
-            self.wait_ready().unwrap();
+            if let Err(err) = self.wait_ready() {
+                if !self.is_stopped() {
+                    warn!("error waiting on virtqueue: {}", err);
+                }
+                return;
+            }
             for chain in self.iter() {
                 f(chain);
             }
@@ -8,7 +8,24 @@ mod vm;
 mod devices;
 mod disk;
 mod io;
+// Only the configuration types are used without the audio device
+#[cfg_attr(not(feature = "audio"), allow(dead_code))]
 mod audio;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+#[cfg(feature = "mock-kvm")]
+pub mod testing;
 
 pub use util::{Logger,LogLevel};
-pub use vm::VmConfig;
+pub use vm::{VmConfig, RootDevice, RomMapping, RealmProvider, RealmInfo, RealmDisk, MsrPolicy, ServiceLimits, CapabilityReport};
+pub use vm::{VmHandle, VmEvent, VmExitReason, Error, Result};
+pub use disk::{OpenType, CacheMode, CloneMethod, create_sparse_image, clone_image};
+pub use vm::warm_asset_caches;
+pub use devices::{CtrlCPolicy, QuotaLimits, SyntheticFS};
+#[cfg(feature = "network")]
+pub use devices::NetRateLimit;
+pub use audio::StreamEffect;
+#[cfg(feature = "network")]
+pub use system::net_helper::run_helper as run_net_helper;
+#[cfg(feature = "citadel")]
+pub use vm::CitadelRealms;
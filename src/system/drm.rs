@@ -6,7 +6,7 @@ use std::path::Path;
 use std::{io, result};
 use std::sync::Arc;
 
-use crate::system::{self, ioctl::ioctl_with_mut_ref};
+use crate::system::{self, fd_audit, ioctl::{ioctl_with_mut_ref, ioctl_with_ref}};
 
 use thiserror::Error;
 
@@ -84,6 +84,7 @@ impl DrmDevice {
             .write(true)
             .open(path)
             .map_err(Error::OpenRenderNode)?;
+        fd_audit::register(file.as_raw_fd(), "drm render node");
         Self::create(file)
     }
 
@@ -183,6 +184,36 @@ struct DrmPrimeHandle {
 const DRM_IOCTL_BASE: c_uint = 0x64;
 const DRM_IOCTL_PRIME_HANDLE_TO_FD: c_ulong = iorw!(DRM_IOCTL_BASE, 0x2d, ::std::mem::size_of::<DrmPrimeHandle>() as i32);
 
+#[repr(C)]
+struct DmaBufSync {
+    flags: u64,
+}
+
+const DMA_BUF_IOCTL_BASE: c_uint = 0x62;
+const DMA_BUF_IOCTL_SYNC: c_ulong = iow!(DMA_BUF_IOCTL_BASE, 0, ::std::mem::size_of::<DmaBufSync>() as i32);
+
+/// Start or end CPU access to the dma-buf `fd` as described by the
+/// `DMA_BUF_SYNC_*` bits in `flags`.
+pub fn dmabuf_sync(fd: RawFd, flags: u64) -> system::errno::Result<()> {
+    let sync = DmaBufSync { flags };
+    unsafe {
+        ioctl_with_ref(fd, DMA_BUF_IOCTL_SYNC, &sync)?;
+    }
+    Ok(())
+}
+
+/// Returns `true` if `fd` is a dma-buf.
+///
+/// A sync request with no access direction is rejected with `EINVAL` by
+/// the dma-buf driver without doing anything, while any other kind of file
+/// does not recognize the ioctl at all.
+pub fn is_dmabuf(fd: RawFd) -> bool {
+    match dmabuf_sync(fd, 0) {
+        Err(e) => e.errno() == libc::EINVAL,
+        Ok(()) => false,
+    }
+}
+
 #[repr(C)]
 struct GbmDevice([u8; 0]);
 
@@ -1,12 +1,33 @@
+use std::collections::HashMap;
 use std::os::unix::io::{RawFd,AsRawFd};
-use std::ptr;
-use crate::system::{Result,Error};
+use std::{cmp, ptr};
+use crate::system::{fd_audit, Result, Error};
 use std::time::Duration;
 
-use libc::{epoll_event, c_int, EPOLLIN, EPOLLHUP, EPOLL_CTL_DEL, EPOLL_CTL_ADD, EPOLL_CLOEXEC, EINTR, EINVAL};
+use libc::{epoll_event, c_int, EPOLLIN, EPOLLOUT, EPOLLHUP, EPOLLET, EPOLL_CTL_DEL, EPOLL_CTL_ADD, EPOLL_CTL_MOD, EPOLL_CLOEXEC, EINTR, EINVAL, ENOENT};
 
 const MAX_EVENTS: usize = 32;
 
+/// Selects whether a registered file descriptor is reported for as long as
+/// it remains ready (`Level`) or only when its readiness changes (`Edge`).
+///
+/// Edge triggered descriptors must be drained until `EAGAIN` every time they
+/// are reported or further events will not be delivered.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Trigger {
+    Level,
+    Edge,
+}
+
+impl Trigger {
+    fn flags(self) -> u32 {
+        match self {
+            Trigger::Level => 0,
+            Trigger::Edge => EPOLLET as u32,
+        }
+    }
+}
+
 pub struct Event(epoll_event);
 
 impl Event {
@@ -17,6 +38,10 @@ impl Event {
         self.is_event(EPOLLIN)
     }
 
+    pub fn is_writable(&self) -> bool {
+        self.is_event(EPOLLOUT)
+    }
+
     pub fn is_hangup(&self) -> bool {
         self.is_event(EPOLLHUP)
     }
@@ -35,21 +60,44 @@ pub struct EPoll {
 }
 
 impl EPoll {
+    pub const READ: u32 = EPOLLIN as u32;
+    pub const WRITE: u32 = EPOLLOUT as u32;
+
     pub fn new() -> Result<EPoll> {
         match unsafe { libc::epoll_create1(EPOLL_CLOEXEC) } {
             -1 => Err(Error::last_os_error()),
-            fd => Ok(EPoll {
-                fd,
-            })
+            fd => {
+                fd_audit::register(fd, "epoll");
+                Ok(EPoll {
+                    fd,
+                })
+            }
         }
     }
 
     pub fn add_read(&self, fd: RawFd, id: u64) -> Result<()> {
+        self.add(fd, id, Self::READ, Trigger::Level)
+    }
+
+    /// Register `fd` for the set of `events` (`EPoll::READ`, `EPoll::WRITE`)
+    /// which will be reported with token `id`.
+    pub fn add(&self, fd: RawFd, id: u64, events: u32, trigger: Trigger) -> Result<()> {
+        self.ctl(EPOLL_CTL_ADD, fd, id, events | trigger.flags())
+    }
+
+    /// Change the token, event set or trigger mode of an already registered
+    /// `fd`. Passing an empty event set keeps the descriptor registered but
+    /// only error and hangup conditions will be reported.
+    pub fn modify(&self, fd: RawFd, id: u64, events: u32, trigger: Trigger) -> Result<()> {
+        self.ctl(EPOLL_CTL_MOD, fd, id, events | trigger.flags())
+    }
+
+    fn ctl(&self, op: c_int, fd: RawFd, id: u64, events: u32) -> Result<()> {
         let mut evt = epoll_event {
-            events: EPOLLIN as u32,
+            events,
             u64: id
         };
-        match unsafe { libc::epoll_ctl(self.fd, EPOLL_CTL_ADD, fd, &mut evt) } {
+        match unsafe { libc::epoll_ctl(self.fd, op, fd, &mut evt) } {
             -1 => Err(Error::last_os_error()),
             _ => Ok(()),
         }
@@ -63,7 +111,7 @@ impl EPoll {
     }
 
     pub fn wait_timeout(&mut self,timeout: Duration) -> Result<PollEvents> {
-        let ms = timeout.as_millis() as u32;
+        let ms = cmp::min(timeout.as_millis(), c_int::MAX as u128);
         self.wait_ms(ms as c_int)
     }
 
@@ -158,3 +206,126 @@ impl <'a> Iterator for PollEventIter<'a> {
 }
 
 
+
+/// Value returned by a `PollDispatcher` handler to indicate whether the
+/// registration should be kept or removed after the handler returns.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum PollAction {
+    Continue,
+    Remove,
+}
+
+type PollHandler<S> = Box<dyn FnMut(&mut S, &EPoll, &Event) -> PollAction + Send>;
+
+struct Registration<S> {
+    fd: RawFd,
+    handler: PollHandler<S>,
+}
+
+/// Owns an `EPoll` instance together with a handler closure for every
+/// registered file descriptor.
+///
+/// Tokens are allocated by the dispatcher when a file descriptor is registered
+/// and the associated handler is dropped when the file descriptor is
+/// unregistered. Handlers receive the state object passed to `dispatch()` and
+/// a reference to the `EPoll` so that they can `modify()` registrations (for
+/// example to temporarily stop listening for input) while running.
+///
+/// The virtio_wl vfd manager still uses a bare `EPoll`, since it registers
+/// descriptors with the vfd ids chosen by the guest as tokens.
+pub struct PollDispatcher<S> {
+    poll: EPoll,
+    handlers: HashMap<u64, Registration<S>>,
+    next_token: u64,
+}
+
+impl <S> PollDispatcher<S> {
+    pub fn new() -> Result<Self> {
+        let poll = EPoll::new()?;
+        Ok(PollDispatcher {
+            poll,
+            handlers: HashMap::new(),
+            next_token: 1,
+        })
+    }
+
+    pub fn poll(&self) -> &EPoll {
+        &self.poll
+    }
+
+    /// Register `fd` for `events` and return the token which identifies it.
+    pub fn register<F>(&mut self, fd: RawFd, events: u32, trigger: Trigger, handler: F) -> Result<u64>
+        where F: FnMut(&mut S, &EPoll, &Event) -> PollAction + Send + 'static
+    {
+        let token = self.next_token;
+        self.poll.add(fd, token, events, trigger)?;
+        self.next_token += 1;
+        self.handlers.insert(token, Registration {
+            fd,
+            handler: Box::new(handler),
+        });
+        Ok(token)
+    }
+
+    /// Shortcut for registering a level triggered read handler.
+    pub fn register_read<F>(&mut self, fd: RawFd, handler: F) -> Result<u64>
+        where F: FnMut(&mut S, &EPoll, &Event) -> PollAction + Send + 'static
+    {
+        self.register(fd, EPoll::READ, Trigger::Level, handler)
+    }
+
+    pub fn modify(&self, token: u64, events: u32, trigger: Trigger) -> Result<()> {
+        let fd = self.fd(token)
+            .ok_or(Error::from_raw_os_error(ENOENT))?;
+        self.poll.modify(fd, token, events, trigger)
+    }
+
+    /// Remove the registration for `token` and drop the handler.
+    pub fn unregister(&mut self, token: u64) -> Result<()> {
+        match self.handlers.remove(&token) {
+            Some(reg) => self.poll.delete(reg.fd),
+            None => Err(Error::from_raw_os_error(ENOENT)),
+        }
+    }
+
+    pub fn fd(&self, token: u64) -> Option<RawFd> {
+        self.handlers.get(&token).map(|reg| reg.fd)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Block until at least one registered file descriptor is ready and run
+    /// the handlers for all reported events.
+    pub fn dispatch(&mut self, state: &mut S) -> Result<usize> {
+        let events = self.poll.wait()?;
+        Ok(self.run_handlers(state, &events))
+    }
+
+    /// Like `dispatch()` but returns after `timeout` if no events arrive.
+    /// Returns the number of events which were handled.
+    pub fn dispatch_timeout(&mut self, state: &mut S, timeout: Duration) -> Result<usize> {
+        let events = self.poll.wait_timeout(timeout)?;
+        Ok(self.run_handlers(state, &events))
+    }
+
+    fn run_handlers(&mut self, state: &mut S, events: &PollEvents) -> usize {
+        let mut count = 0;
+        for ev in events.iter() {
+            let token = ev.id();
+            let action = match self.handlers.get_mut(&token) {
+                Some(reg) => (reg.handler)(state, &self.poll, &ev),
+                // removed by an earlier handler in this batch
+                None => continue,
+            };
+            count += 1;
+            if action == PollAction::Remove {
+                // The handler may already have closed the file descriptor in
+                // which case it has been removed from the epoll set already.
+                let _ = self.unregister(token);
+            }
+        }
+        count
+    }
+}
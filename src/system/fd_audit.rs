@@ -0,0 +1,97 @@
+//! Reports file descriptors which would be inherited by programs pH starts.
+//!
+//! `std::process::Command` closes nothing on exec, so any descriptor without
+//! `FD_CLOEXEC` (a KVM eventfd, a wayland socket, a tap device) ends up in a
+//! helper such as `ph-net-helper` which may run as root. With auditing
+//! enabled the helpers in `system` and the device code record where each
+//! descriptor they create comes from, so that `open_fds()` can list each one
+//! still open without `FD_CLOEXEC` along with its origin.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref ORIGINS: Mutex<HashMap<RawFd, Origin>> = Mutex::new(HashMap::new());
+}
+
+// The file a descriptor referred to when it was registered, so that a later
+// descriptor reusing the number is not reported with a stale origin.
+struct Origin {
+    name: &'static str,
+    dev: u64,
+    ino: u64,
+}
+
+/// An open file descriptor of the process
+#[derive(Clone,Debug)]
+pub struct OpenFd {
+    pub fd: RawFd,
+    pub cloexec: bool,
+    /// Target of the `/proc/self/fd` link, eg. `anon_inode:[eventfd]`
+    pub target: String,
+    /// What created the descriptor, if it was registered
+    pub origin: Option<&'static str>,
+}
+
+/// Start recording the origin of new file descriptors.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Record that `fd` was created by `origin`. Does nothing unless auditing
+/// is enabled.
+pub fn register(fd: RawFd, origin: &'static str) {
+    if !is_enabled() {
+        return;
+    }
+    if let Some((dev, ino)) = file_id(fd) {
+        ORIGINS.lock().unwrap().insert(fd, Origin { name: origin, dev, ino });
+    }
+}
+
+/// List every open file descriptor of the process.
+pub fn open_fds() -> io::Result<Vec<OpenFd>> {
+    let origins = ORIGINS.lock().unwrap();
+    let mut fds = Vec::new();
+    for entry in fs::read_dir("/proc/self/fd")? {
+        let entry = entry?;
+        let fd = match entry.file_name().to_str().and_then(|s| s.parse::<RawFd>().ok()) {
+            Some(fd) => fd,
+            None => continue,
+        };
+        // Closed since the directory was read
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags < 0 {
+            continue;
+        }
+        let target = fs::read_link(entry.path())
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let origin = match (origins.get(&fd), file_id(fd)) {
+            (Some(o), Some((dev, ino))) if o.dev == dev && o.ino == ino => Some(o.name),
+            _ => None,
+        };
+        fds.push(OpenFd { fd, cloexec: flags & libc::FD_CLOEXEC != 0, target, origin });
+    }
+    fds.sort_by_key(|f| f.fd);
+    Ok(fds)
+}
+
+fn file_id(fd: RawFd) -> Option<(u64, u64)> {
+    let mut st: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut st) } < 0 {
+        return None;
+    }
+    Some((st.st_dev as u64, st.st_ino as u64))
+}
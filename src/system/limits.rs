@@ -0,0 +1,28 @@
+use crate::system::errno::{cvt, Result};
+
+// Used when the limit cannot be read
+const DEFAULT_NOFILE_LIMIT: u64 = 1024;
+
+fn get_nofile() -> Result<libc::rlimit> {
+    let mut rlim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    unsafe { cvt(libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim))?; }
+    Ok(rlim)
+}
+
+/// The current soft limit on the number of open file descriptors.
+pub fn nofile_limit() -> u64 {
+    get_nofile()
+        .map(|rlim| rlim.rlim_cur as u64)
+        .unwrap_or(DEFAULT_NOFILE_LIMIT)
+}
+
+/// Raise the soft limit on open file descriptors to the hard limit and
+/// return the new soft limit.
+pub fn raise_nofile_limit() -> Result<u64> {
+    let mut rlim = get_nofile()?;
+    if rlim.rlim_cur < rlim.rlim_max {
+        rlim.rlim_cur = rlim.rlim_max;
+        unsafe { cvt(libc::setrlimit(libc::RLIMIT_NOFILE, &rlim))?; }
+    }
+    Ok(rlim.rlim_cur as u64)
+}
@@ -0,0 +1,112 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+use crate::system::fd_audit;
+use crate::system::netlink::MAC_ADDR_LEN;
+use crate::system::tap::{
+    self, interface_mac, IfReq, NetBackend, IFF_NO_PI, IFF_TAP, IFF_VNET_HDR, TUNSETIFF,
+};
+
+/// A network backend which uses the character device of an existing macvtap
+/// interface. Frames written to the device are transmitted on the lower
+/// device of the macvtap interface so no bridge needs to be configured on
+/// the host.
+///
+/// The macvtap interface must be created in advance, for example with:
+///
+/// ```text
+/// ip link add link eth0 name macvtap0 type macvtap mode bridge
+/// ```
+///
+/// and `/dev/tapN` (where N is the interface index) must be accessible
+/// by the user running pH.
+///
+/// A macvtap interface only receives unicast frames sent to its own MAC
+/// address, so the guest is given that address.
+pub struct MacVTapBackend {
+    file: File,
+    name: String,
+    mac: [u8; MAC_ADDR_LEN],
+}
+
+impl MacVTapBackend {
+    pub fn open(if_name: &str) -> io::Result<Self> {
+        let path = Self::device_path(if_name)?;
+        let mac = interface_mac(if_name)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK|libc::O_CLOEXEC)
+            .open(&path)?;
+        fd_audit::register(file.as_raw_fd(), "macvtap device");
+
+        // The interface name is ignored by macvtap, only the flags are used.
+        IfReq::new("")
+            .set_flags(IFF_TAP | IFF_NO_PI | IFF_VNET_HDR)
+            .ioctl_mut(&file, TUNSETIFF)?;
+
+        Ok(MacVTapBackend {
+            file,
+            name: if_name.to_string(),
+            mac,
+        })
+    }
+
+    fn device_path(if_name: &str) -> io::Result<String> {
+        let sysfs = Path::new("/sys/class/net").join(if_name);
+        if !sysfs.join("macvtap").exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("{} is not a macvtap interface", if_name)));
+        }
+        let ifindex = fs::read_to_string(sysfs.join("ifindex"))?;
+        let ifindex = ifindex.trim().parse::<u32>()
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        Ok(format!("/dev/tap{}", ifindex))
+    }
+}
+
+impl NetBackend for MacVTapBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn set_offload(&self, flags: libc::c_uint) -> io::Result<()> {
+        tap::set_offload(&self.file, flags)
+    }
+
+    fn set_vnet_hdr_size(&self, size: libc::c_int) -> io::Result<()> {
+        tap::set_vnet_hdr_size(&self.file, size)
+    }
+
+    fn reopen(&self) -> io::Result<Self> {
+        Self::open(&self.name)
+    }
+
+    fn mac_address(&self) -> Option<[u8; MAC_ADDR_LEN]> {
+        Some(self.mac)
+    }
+}
+
+impl Read for MacVTapBackend {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for MacVTapBackend {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for MacVTapBackend {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
@@ -1,15 +1,32 @@
 #[macro_use]pub mod ioctl;
 mod epoll;
 pub mod errno;
+#[cfg(any(feature = "network", feature = "wayland"))]
 mod socket;
+#[cfg(feature = "network")]
 mod tap;
+#[cfg(feature = "network")]
+mod macvtap;
+#[cfg(feature = "network")]
 pub mod netlink;
+#[cfg(feature = "wayland")]
 pub mod drm;
+pub mod numa;
+pub mod limits;
+pub mod sched;
+pub mod fd_audit;
+#[cfg(feature = "network")]
+pub mod net_helper;
 
-pub use epoll::{EPoll,Event};
+pub use epoll::{EPoll,Event,PollAction,PollDispatcher,Trigger};
+#[cfg(any(feature = "network", feature = "wayland"))]
 pub use socket::ScmSocket;
+#[cfg(feature = "network")]
 pub use netlink::NetlinkSocket;
-pub use tap::Tap;
+#[cfg(feature = "network")]
+pub use tap::{interface_mac, interface_mtu, tap_name, NetBackend, Tap, TapOptions};
+#[cfg(feature = "network")]
+pub use macvtap::MacVTapBackend;
 use std::{result, io};
 
 pub use errno::Error as ErrnoError;
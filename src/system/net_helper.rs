@@ -0,0 +1,224 @@
+//! A small privileged helper which creates a tap device, adds it to a bridge
+//! and passes the open device back to pH, so that pH does not need to run as
+//! root to use networking.
+//!
+//! The helper is installed setuid root (or started through `pkexec`) and is
+//! started by pH with one end of a unix socket pair as its standard input.
+//! pH writes a single request line
+//!
+//!     tap BRIDGE
+//!
+//! and the helper answers with `ok IFNAME` and the tap file descriptor
+//! attached, or with `error MESSAGE`, and exits.
+//!
+//! To move a tap device it created earlier to another bridge, or add it
+//! again after the bridge was recreated, pH writes
+//!
+//!     join BRIDGE
+//!
+//! with the open tap device attached, which shows that the caller owns the
+//! device, and the helper answers with `ok IFNAME` or `error MESSAGE`.
+//!
+//! Only bridges listed in `/etc/ph/net-helper.conf` with a line `allow NAME`
+//! may be used. Without the file only the default bridge is allowed.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
+
+use crate::system::{tap_name, NetlinkSocket, ScmSocket, Tap, TapOptions};
+
+const CONFIG_PATH: &str = "/etc/ph/net-helper.conf";
+const DEFAULT_BRIDGE: &str = "vz-clear";
+
+// Longest request and reply line
+const MAX_LINE: usize = 256;
+
+// IFNAMSIZ without the terminating nul
+const MAX_IFNAME: usize = 15;
+
+fn helper_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+/// Run the helper `command` (a program with optional arguments, such as
+/// `pkexec /usr/libexec/ph-net-helper`) to create a tap device attached to
+/// `bridge`.
+pub fn request_tap(command: &str, bridge: &str) -> io::Result<Tap> {
+    run_command(command, |socket| exchange(socket, bridge))
+}
+
+/// Run the helper `command` to add the tap device `tap` to `bridge`.
+pub fn request_join<F: AsRawFd>(command: &str, tap: &F, bridge: &str) -> io::Result<()> {
+    run_command(command, |socket| {
+        socket.send_with_fd(format!("join {}\n", bridge).as_bytes(), tap.as_raw_fd())?;
+        read_reply(socket).map(|_| ())
+    })
+}
+
+fn run_command<T, F>(command: &str, exchange: F) -> io::Result<T>
+    where F: FnOnce(&UnixStream) -> io::Result<T>
+{
+    let mut words = command.split_whitespace();
+    let program = words.next()
+        .ok_or_else(|| helper_error("empty helper command".to_string()))?;
+
+    let (socket, helper_end) = UnixStream::pair()?;
+    let helper_end = unsafe { File::from_raw_fd(helper_end.into_raw_fd()) };
+    let mut child = Command::new(program)
+        .args(words)
+        .stdin(Stdio::from(helper_end))
+        .spawn()?;
+
+    let result = exchange(&socket);
+    drop(socket);
+    let status = child.wait()?;
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !status.success() =>
+            Err(helper_error(format!("{} exited with {}", program, status))),
+        result => result,
+    }
+}
+
+fn exchange(mut socket: &UnixStream, bridge: &str) -> io::Result<Tap> {
+    socket.write_all(format!("tap {}\n", bridge).as_bytes())?;
+    match read_reply(socket)? {
+        (name, Some(file)) => Ok(Tap::from_file(file, &name, true)),
+        (name, None) => Err(helper_error(format!("unexpected reply 'ok {}'", name))),
+    }
+}
+
+// Returns the interface name of an `ok` reply and the file descriptor sent
+// with it, if any.
+fn read_reply(socket: &UnixStream) -> io::Result<(String, Option<File>)> {
+    let mut buf = [0u8; MAX_LINE];
+    let (n, file) = socket.recv_with_fd(&mut buf)
+        .map_err(|e| helper_error(format!("error receiving reply: {}", e)))?;
+    if n == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no reply from helper"));
+    }
+    let reply = String::from_utf8_lossy(&buf[..n]);
+    let reply = reply.trim_end();
+    if let Some(msg) = reply.strip_prefix("error ") {
+        return Err(helper_error(msg.to_string()));
+    }
+    match reply.strip_prefix("ok ") {
+        Some(name) if valid_ifname(name) => Ok((name.to_string(), file)),
+        _ => Err(helper_error(format!("unexpected reply '{}'", reply))),
+    }
+}
+
+fn valid_ifname(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_IFNAME &&
+        name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+fn allowed_bridges() -> Vec<String> {
+    match fs::read_to_string(CONFIG_PATH) {
+        Ok(config) => config.lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter_map(|line| line.strip_prefix("allow "))
+            .map(|name| name.trim().to_string())
+            .collect(),
+        Err(_) => vec![DEFAULT_BRIDGE.to_string()],
+    }
+}
+
+// Reads the request line and the file descriptor sent with it, if any. The
+// descriptor arrives with the first part of the line.
+fn read_request(socket: &mut UnixStream) -> io::Result<(String, Option<File>)> {
+    let mut buf = [0u8; MAX_LINE];
+    let (n, file) = socket.recv_with_fd(&mut buf)?;
+    let mut line = buf[..n].to_vec();
+    let mut byte = [0u8];
+    while n > 0 && !line.contains(&b'\n') && line.len() < MAX_LINE {
+        if socket.read(&mut byte)? == 0 {
+            break;
+        }
+        line.push(byte[0]);
+    }
+    let end = line.iter().position(|&b| b == b'\n').unwrap_or(line.len());
+    Ok((String::from_utf8_lossy(&line[..end]).into_owned(), file))
+}
+
+fn check_bridge(bridge: &str) -> Result<(), String> {
+    if !valid_ifname(bridge) {
+        return Err(format!("invalid bridge name '{}'", bridge));
+    }
+    if !allowed_bridges().iter().any(|b| b == bridge) {
+        return Err(format!("bridge {} is not allowed by {}", bridge, CONFIG_PATH));
+    }
+    Ok(())
+}
+
+fn join_bridge(ifname: &str, bridge: &str) -> Result<(), String> {
+    let nl = NetlinkSocket::open()
+        .map_err(|e| e.to_string())?;
+    nl.join_bridge(ifname, bridge)
+        .map_err(|e| format!("failed to add {} to bridge {}: {}", ifname, bridge, e))
+}
+
+fn create_tap(bridge: &str) -> Result<Tap, String> {
+    check_bridge(bridge)?;
+    let tap = TapOptions::new("vmtap%d").open()
+        .map_err(|e| format!("failed to create tap device: {}", e))?;
+    join_bridge(tap.name(), bridge)?;
+    Ok(tap)
+}
+
+// Adds the tap device sent with the request to `bridge`. Only a tap device
+// that is still open in the caller can be moved this way.
+fn join_tap(tap: Option<File>, bridge: &str) -> Result<String, String> {
+    check_bridge(bridge)?;
+    let tap = tap.ok_or_else(|| "no tap device sent with request".to_string())?;
+    let ifname = tap_name(&tap)
+        .map_err(|e| format!("file sent with request is not a tap device: {}", e))?;
+    join_bridge(&ifname, bridge)?;
+    Ok(ifname)
+}
+
+// Returns the interface name for the reply and the tap device to send with
+// it, if a new one was created.
+fn handle_request(request: &str, file: Option<File>) -> Result<(String, Option<Tap>), String> {
+    if let Some(bridge) = request.strip_prefix("tap ") {
+        let tap = create_tap(bridge)?;
+        Ok((tap.name().to_string(), Some(tap)))
+    } else if let Some(bridge) = request.strip_prefix("join ") {
+        Ok((join_tap(file, bridge)?, None))
+    } else {
+        Err(format!("unknown request '{}'", request))
+    }
+}
+
+/// Entry point of the `ph-net-helper` program. Answers one request on the
+/// socket passed as standard input and returns the exit code.
+pub fn run_helper() -> i32 {
+    let mut socket = unsafe { UnixStream::from_raw_fd(0) };
+    let (request, file) = match read_request(&mut socket) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("ph-net-helper: standard input must be a unix socket from pH: {}", e);
+            return 1;
+        }
+    };
+    let sent = match handle_request(&request, file) {
+        Ok((ifname, Some(tap))) =>
+            socket.send_with_fd(format!("ok {}\n", ifname).as_bytes(), tap.as_raw_fd())
+                .map_err(io::Error::from),
+        Ok((ifname, None)) => socket.write_all(format!("ok {}\n", ifname).as_bytes()).map(|_| 0),
+        Err(msg) => {
+            eprintln!("ph-net-helper: {}", msg);
+            let _ = socket.write_all(format!("error {}\n", msg).as_bytes());
+            return 1;
+        }
+    };
+    match sent {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("ph-net-helper: failed to send reply: {}", e);
+            1
+        }
+    }
+}
@@ -12,12 +12,20 @@ use libc::{
 
 use thiserror::Error;
 
+use crate::system::fd_audit;
+use crate::system::{interface_mac, interface_mtu};
+
 const NETLINK_ROUTE: i32 = 0;
 
+const IFLA_ADDRESS: u16 = 1;
 const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const IFLA_LINK: u16 = 5;
 const IFLA_MASTER: u16 = 10;
 const IFLA_LINKINFO: u16 = 18;
 const IFLA_INFO_KIND: u16 = 1;
+const IFLA_INFO_DATA: u16 = 2;
+const IFLA_VLAN_ID: u16 = 1;
 
 const NLA_F_NESTED: u16 = 1 << 15;
 const IFA_ADDRESS: u16 = 1;
@@ -49,6 +57,10 @@ const RTN_UNICAST: u8 = 1;
 
 const RTA_GATEWAY: u16 = 5;
 
+pub const MAC_ADDR_LEN: usize = 6;
+// First byte of the address given to tap devices added to a bridge
+const TAP_MAC_PREFIX: u8 = 0xfe;
+const VLAN_ID_MAX: u16 = 4094;
 
 const MESSAGE_ALIGN: usize = 4;
 
@@ -74,6 +86,8 @@ pub enum Error {
     UnexpectedResponse,
     #[error("failed to transmit entire netlink message")]
     ShortSend,
+    #[error("invalid VLAN id {0} (must be between 1 and 4094)")]
+    InvalidVlanId(u16),
 }
 
 pub struct NetlinkSocket {
@@ -117,6 +131,33 @@ impl NetlinkSocket {
         self.send_message(msg)
     }
 
+    /// Add `iface` to `bridge` and bring it up, creating the bridge first if
+    /// it does not exist. A new tap device has an MTU of 1500, and adding it
+    /// would lower the MTU of a bridge configured for jumbo frames, so `iface`
+    /// is given the MTU of the bridge.
+    ///
+    /// A bridge without an address of its own takes the lowest address of
+    /// its ports, so the random address of a new tap device would change the
+    /// address of the bridge whenever a VM starts or stops. The first byte of
+    /// the tap address is set to 0xfe to keep it above the other ports.
+    pub fn join_bridge(&self, iface: &str, bridge: &str) -> Result<()> {
+        if !self.interface_exists(bridge) {
+            self.create_bridge(bridge)?;
+            self.set_interface_up(bridge)?;
+        }
+        if let Ok(mtu) = interface_mtu(bridge) {
+            self.set_mtu(iface, mtu)?;
+        }
+        if let Ok(mut mac) = interface_mac(iface) {
+            if mac[0] != TAP_MAC_PREFIX {
+                mac[0] = TAP_MAC_PREFIX;
+                self.set_mac_address(iface, &mac)?;
+            }
+        }
+        self.add_interface_to_bridge(iface, bridge)?;
+        self.set_interface_up(iface)
+    }
+
     #[allow(dead_code)]
     pub fn create_bridge(&self, name: &str) -> Result<()> {
         let msg =  self.message_create(RTM_NEWLINK)
@@ -162,6 +203,55 @@ impl NetlinkSocket {
         self.send_message(msg)
     }
 
+    /// Set the MTU of interface `iface`.
+    pub fn set_mtu(&self, iface: &str, mtu: u32) -> Result<()> {
+        let idx = self.name_to_index(iface)?;
+        let msg = self.message(RTM_SETLINK)
+            .with_ifinfomsg(AF_UNSPEC, |hdr| {
+                hdr.index(idx);
+            })
+            .attr_u32(IFLA_MTU, mtu)
+            .done();
+
+        self.send_message(msg)
+    }
+
+    /// Set the hardware address of interface `iface`. The kernel requires
+    /// most interface types to be down when the address is changed.
+    pub fn set_mac_address(&self, iface: &str, mac: &[u8; MAC_ADDR_LEN]) -> Result<()> {
+        let idx = self.name_to_index(iface)?;
+        let msg = self.message(RTM_SETLINK)
+            .with_ifinfomsg(AF_UNSPEC, |hdr| {
+                hdr.index(idx);
+            })
+            .append_attr(IFLA_ADDRESS, mac)
+            .done();
+
+        self.send_message(msg)
+    }
+
+    /// Create an 802.1Q VLAN sub-interface `name` on top of interface `parent`.
+    #[allow(dead_code)]
+    pub fn create_vlan(&self, parent: &str, name: &str, vlan_id: u16) -> Result<()> {
+        if vlan_id == 0 || vlan_id > VLAN_ID_MAX {
+            return Err(Error::InvalidVlanId(vlan_id));
+        }
+        let parent_idx = self.name_to_index(parent)?;
+        let msg = self.message_create(RTM_NEWLINK)
+            .ifinfomsg(AF_UNSPEC)
+            .attr_u32(IFLA_LINK, parent_idx)
+            .attr_str(IFLA_IFNAME, name)
+            .with_nested(IFLA_LINKINFO, |a| {
+                a.attr_str(IFLA_INFO_KIND, "vlan")
+                    .nested(IFLA_INFO_DATA, |d| {
+                        d.attr_u16(IFLA_VLAN_ID, vlan_id);
+                    });
+            })
+            .done();
+
+        self.send_message(msg)
+    }
+
     fn open_protocol(protocol: i32) -> Result<NetlinkSocket> {
         let sock = sys_socket(PF_NETLINK,
                                 SOCK_RAW | SOCK_CLOEXEC | SOCK_NONBLOCK,
@@ -228,7 +318,8 @@ impl NetlinkSocket {
             match resp.read_u32() {
                 0 => Ok(()),
                 errno => {
-                    let e = io::Error::from_raw_os_error(errno as i32);
+                    // error field of nlmsgerr is a negative errno value
+                    let e = io::Error::from_raw_os_error(-(errno as i32));
                     Err(Error::ErrorResponse(e))
                 }
             }
@@ -303,16 +394,11 @@ impl NetlinkMessage {
 
     }
 
-    fn with_nested<F>(self, atype: u16, mut f: F) -> Self
+    fn with_nested<F>(mut self, atype: u16, f: F) -> Self
     where F: FnMut(&mut Buffer<Vec<u8>>)
     {
-        let mut nested = Buffer::new_empty();
-        nested.write_u16(0);
-        nested.write_u16(atype | NLA_F_NESTED);
-        f(&mut nested);
-        nested.align();
-        nested.write_u16_at(0, nested.len() as u16);
-        self.write(nested.as_bytes())
+        self.0.nested(atype, f);
+        self
     }
 
     fn attr_u32(mut self, atype: u16, val: u32) -> Self {
@@ -330,11 +416,6 @@ impl NetlinkMessage {
         self
     }
 
-    fn write(mut self, data: &[u8]) -> Self {
-        self.0.write(data).align();
-        self
-    }
-
     fn update_len(&mut self) {
         self.0.align();
         self.0.write_u32_at(0, self.0.len() as u32);
@@ -426,10 +507,28 @@ impl Buffer<Vec<u8>> {
         self
     }
 
+    fn attr_u16(&mut self, atype: u16, val: u16) -> &mut Self {
+        self.append_attr(atype, &val.to_ne_bytes())
+    }
+
     fn attr_u32(&mut self, atype: u16, val: u32) -> &mut Self {
         self.append_attr(atype, &val.to_ne_bytes())
     }
 
+    fn nested<F>(&mut self, atype: u16, mut f: F) -> &mut Self
+    where F: FnMut(&mut Buffer<Vec<u8>>)
+    {
+        let mut nested = Buffer::new_empty();
+        nested.write_u16(0);
+        nested.write_u16(atype | NLA_F_NESTED);
+        f(&mut nested);
+        nested.align();
+        nested.write_u16_at(0, nested.len() as u16);
+        self.write(nested.as_bytes())
+            .align();
+        self
+    }
+
     fn attr_str(&mut self, atype: u16, val: &str) -> &mut Self {
         self.append_attr(atype, val.as_bytes())
     }
@@ -593,6 +692,7 @@ fn sys_socket(domain: i32, stype: i32, protocol: i32) -> Result<RawFd> {
         if fd < 0 {
             Err(Error::Socket(io::Error::last_os_error()))
         } else {
+            fd_audit::register(fd, "netlink socket");
             Ok(fd)
         }
     }
@@ -0,0 +1,34 @@
+use std::path::Path;
+
+use crate::system::errno::{Error, Result};
+
+const MPOL_BIND: libc::c_int = 2;
+// Size of the node mask passed to mbind(), enough for 1024 nodes
+const NODE_MASK_WORDS: usize = 16;
+const NODE_MASK_BITS: usize = NODE_MASK_WORDS * 64;
+
+/// Returns `true` if the host has a NUMA node with id `node`.
+pub fn node_exists(node: u32) -> bool {
+    Path::new("/sys/devices/system/node").join(format!("node{}", node)).exists()
+}
+
+/// Restrict the pages of the mapping at `addr` with length `len` to be
+/// allocated from host NUMA node `node`. Must be called before the memory is
+/// first touched for the policy to apply to every page.
+pub fn mbind_node(addr: *mut u8, len: usize, node: u32) -> Result<()> {
+    let node = node as usize;
+    if node >= NODE_MASK_BITS {
+        return Err(Error::from_raw_os_error(libc::EINVAL));
+    }
+    let mut mask = [0u64; NODE_MASK_WORDS];
+    mask[node / 64] |= 1 << (node % 64);
+
+    // The kernel ignores the last bit of maxnode so pass one more than the mask size
+    let ret = unsafe {
+        libc::syscall(libc::SYS_mbind, addr, len, MPOL_BIND, mask.as_ptr(), NODE_MASK_BITS + 1, 0)
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+
+use crate::system::errno::{Error, Result};
+
+// Not defined by the libc crate
+const SCHED_RESET_ON_FORK: libc::c_int = 0x40000000;
+
+/// Lowest real time priority accepted for `ThreadPriority::RoundRobin`
+pub const MIN_RT_PRIORITY: i32 = 1;
+/// Highest real time priority accepted for `ThreadPriority::RoundRobin`
+pub const MAX_RT_PRIORITY: i32 = 99;
+
+/// Scheduling hint for a device thread, applied by the thread itself when it
+/// starts.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum ThreadPriority {
+    /// Normal scheduling with a nice value from -20 (most favourable) to 19
+    Nice(i32),
+    /// Real time round robin scheduling (`SCHED_RR`) with a priority from 1 to 99
+    RoundRobin(i32),
+}
+
+impl ThreadPriority {
+    /// Parse a nice value, returning `None` if it is out of range.
+    pub fn parse_nice(s: &str) -> Option<ThreadPriority> {
+        s.parse::<i32>().ok()
+            .filter(|n| (-20..=19).contains(n))
+            .map(ThreadPriority::Nice)
+    }
+
+    /// Parse a real time priority, returning `None` if it is out of range.
+    pub fn parse_rt_priority(s: &str) -> Option<ThreadPriority> {
+        s.parse::<i32>().ok()
+            .filter(|n| (MIN_RT_PRIORITY..=MAX_RT_PRIORITY).contains(n))
+            .map(ThreadPriority::RoundRobin)
+    }
+
+    /// Change the scheduling of the calling thread. A negative nice value or
+    /// real time scheduling needs `CAP_SYS_NICE` or a sufficient
+    /// `RLIMIT_NICE`/`RLIMIT_RTPRIO`.
+    pub fn apply(self) -> Result<()> {
+        let ret = match self {
+            // With the id of a thread rather than a process setpriority()
+            // changes only that thread on Linux.
+            ThreadPriority::Nice(nice) => unsafe {
+                let tid = libc::syscall(libc::SYS_gettid);
+                libc::syscall(libc::SYS_setpriority, libc::PRIO_PROCESS, tid, nice) as libc::c_int
+            },
+            // Programs started from the thread do not inherit the real time
+            // policy.
+            ThreadPriority::RoundRobin(priority) => unsafe {
+                let param = libc::sched_param { sched_priority: priority };
+                libc::sched_setscheduler(0, libc::SCHED_RR | SCHED_RESET_ON_FORK, &param)
+            },
+        };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ThreadPriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThreadPriority::Nice(nice) => write!(f, "nice {}", nice),
+            ThreadPriority::RoundRobin(priority) => write!(f, "SCHED_RR priority {}", priority),
+        }
+    }
+}
@@ -11,11 +11,14 @@ use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::{UnixDatagram, UnixStream};
 use std::ptr::{copy_nonoverlapping, null_mut, write_unaligned};
 
+use vm_memory::VolatileSlice;
+
 use libc::{
     c_long, c_void, cmsghdr, iovec, msghdr, recvmsg, sendmsg, MSG_NOSIGNAL, SCM_RIGHTS, SOL_SOCKET,
 };
 
 use crate::system::errno::{Error,Result};
+use crate::system::fd_audit;
 
 // Each of the following macros performs the same function as their C counterparts. They are each
 // macros because they are used to size statically allocated arrays.
@@ -153,19 +156,25 @@ fn raw_sendmsg(fd: RawFd, out_data: &[u8], out_fds: &[RawFd]) -> Result<usize> {
 }
 
 fn raw_recvmsg(fd: RawFd, in_data: &mut [u8], in_fds: &mut [RawFd]) -> Result<(usize, usize)> {
-    let cmsg_capacity = CMSG_SPACE!(size_of::<RawFd>() * in_fds.len());
-    let mut cmsg_buffer = CmsgBuffer::with_capacity(cmsg_capacity);
-
     let mut iovec = iovec {
         iov_base: in_data.as_mut_ptr() as *mut c_void,
         iov_len: in_data.len(),
     };
+    // Safe because the iovec describes the whole of `in_data`.
+    unsafe { raw_recvmsg_iov(fd, std::slice::from_mut(&mut iovec), in_fds) }
+}
+
+// Caller must ensure that every iovec refers to memory which is valid for
+// writes of `iov_len` bytes.
+unsafe fn raw_recvmsg_iov(fd: RawFd, iovecs: &mut [iovec], in_fds: &mut [RawFd]) -> Result<(usize, usize)> {
+    let cmsg_capacity = CMSG_SPACE!(size_of::<RawFd>() * in_fds.len());
+    let mut cmsg_buffer = CmsgBuffer::with_capacity(cmsg_capacity);
 
     let mut msg = msghdr {
         msg_name: null_mut(),
         msg_namelen: 0,
-        msg_iov: &mut iovec as *mut iovec,
-        msg_iovlen: 1,
+        msg_iov: iovecs.as_mut_ptr(),
+        msg_iovlen: iovecs.len(),
         msg_control: null_mut(),
         msg_controllen: 0,
         msg_flags: 0,
@@ -204,6 +213,9 @@ fn raw_recvmsg(fd: RawFd, in_data: &mut [u8], in_fds: &mut [RawFd]) -> Result<(u
                     fd_count,
                 );
             }
+            for &fd in &in_fds[in_fds_count..(in_fds_count + fd_count)] {
+                fd_audit::register(fd, "fd received on unix socket");
+            }
             in_fds_count += fd_count;
         }
 
@@ -279,6 +291,20 @@ pub trait ScmSocket {
     fn recv_with_fds(&self, buf: &mut [u8], fds: &mut [RawFd]) -> Result<(usize, usize)> {
         raw_recvmsg(self.socket_fd(), buf, fds)
     }
+
+    /// Receives data and file descriptors from the socket directly into
+    /// volatile memory such as guest memory, scattering the data across
+    /// `bufs` in order.
+    ///
+    /// Returns the same tuple as `recv_with_fds`.
+    fn recv_volatile_with_fds(&self, bufs: &[VolatileSlice], fds: &mut [RawFd]) -> Result<(usize, usize)> {
+        let guards: Vec<_> = bufs.iter().map(|b| b.ptr_guard_mut()).collect();
+        let mut iovecs: Vec<iovec> = guards.iter().zip(bufs.iter())
+            .map(|(g, b)| iovec { iov_base: g.as_ptr() as *mut c_void, iov_len: b.len() })
+            .collect();
+        // Safe because the guards keep every slice valid until recvmsg returns.
+        unsafe { raw_recvmsg_iov(self.socket_fd(), &mut iovecs, fds) }
+    }
 }
 
 impl ScmSocket for UnixDatagram {
@@ -1,70 +1,242 @@
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Write};
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd,RawFd};
+use std::path::Path;
 
-use crate::system;
+use crate::system::{self, fd_audit};
+use crate::system::netlink::MAC_ADDR_LEN;
 use crate::system::ioctl::{
     ioctl_with_ref, ioctl_with_val, ioctl_with_mut_ref
 };
 
+/// A host network device which exchanges ethernet frames (prefixed with a
+/// virtio net header) with `VirtioNet`.
+pub trait NetBackend: Read + Write + AsRawFd + Send {
+    fn name(&self) -> &str;
+    fn set_offload(&self, flags: libc::c_uint) -> io::Result<()>;
+    fn set_vnet_hdr_size(&self, size: libc::c_int) -> io::Result<()>;
+    /// Open the interface again by name, for example after it has been
+    /// deleted and created again on the host.
+    fn reopen(&self) -> io::Result<Self> where Self: Sized;
+
+    /// The MTU of the interface on the host, which is offered to the guest.
+    fn mtu(&self) -> io::Result<u32> {
+        interface_mtu(self.name())
+    }
+
+    /// The MAC address the guest must use, if the interface only delivers
+    /// frames sent to a particular address.
+    fn mac_address(&self) -> Option<[u8; MAC_ADDR_LEN]> {
+        None
+    }
+}
+
+/// Read the MTU of the network interface `name` from sysfs.
+pub fn interface_mtu(name: &str) -> io::Result<u32> {
+    let mtu = fs::read_to_string(Path::new("/sys/class/net").join(name).join("mtu"))?;
+    mtu.trim().parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid mtu for {}: {}", name, mtu.trim())))
+}
+
+/// Read the hardware address of the network interface `name` from sysfs.
+pub fn interface_mac(name: &str) -> io::Result<[u8; MAC_ADDR_LEN]> {
+    let address = fs::read_to_string(Path::new("/sys/class/net").join(name).join("address"))?;
+    let address = address.trim();
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid address for {}: {}", name, address));
+    let mut mac = [0u8; MAC_ADDR_LEN];
+    let mut parts = address.split(':');
+    for b in mac.iter_mut() {
+        let part = parts.next().ok_or_else(invalid)?;
+        *b = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+    }
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(mac)
+}
+
+/// Name of the interface of the open tap device `fd`.
+pub fn tap_name<F: AsRawFd>(fd: &F) -> io::Result<String> {
+    let mut ifreq = IfReq::new("");
+    ifreq.ioctl_mut(fd, TUNGETIFF)?;
+    Ok(ifreq.name().to_string())
+}
+
 pub struct Tap {
     file: File,
     name: String,
+    vnet_hdr: bool,
+    multi_queue: bool,
 }
 
-const IFF_TAP: u16      = 0x0002;
-const IFF_NO_PI: u16    = 0x1000;
-const IFF_VNET_HDR: u16 = 0x4000;
+pub(super) const IFF_TAP: u16         = 0x0002;
+pub(super) const IFF_NO_PI: u16       = 0x1000;
+pub(super) const IFF_VNET_HDR: u16    = 0x4000;
+const IFF_MULTI_QUEUE: u16 = 0x0100;
 
 const TAPTUN: u64 = 0x54;
-const TUNSETIFF: libc::c_ulong = iow!(TAPTUN, 202, 4);
+pub(super) const TUNSETIFF: libc::c_ulong = iow!(TAPTUN, 202, 4);
 const TUNSETOFFLOAD: libc::c_ulong = iow!(TAPTUN, 208, 4);
 const TUNSETVNETHDRSZ: libc::c_ulong = iow!(TAPTUN, 216, 4);
+const TUNGETIFF: libc::c_ulong = ior!(TAPTUN, 210, 4);
 
-impl Tap {
-    pub fn new_default() -> io::Result<Self> {
-        Self::new("vmtap%d")
+/// Options for creating a new tap device or attaching to an existing one.
+///
+/// A tap device which has been created as persistent and assigned to an
+/// owner user or group (with `ip tuntap add mode tap user ...`) can later be
+/// attached to by name without any special privileges, as long as the
+/// multi-queue flag requested when attaching matches the flag the device
+/// was created with.
+pub struct TapOptions {
+    name: String,
+    vnet_hdr: bool,
+    multi_queue: bool,
+}
+
+impl TapOptions {
+    pub fn new(name: &str) -> Self {
+        TapOptions {
+            name: name.to_string(),
+            vnet_hdr: true,
+            multi_queue: false,
+        }
     }
 
-    pub fn new(if_name: &str) -> io::Result<Self> {
-        let file = Self::open_tun()?;
-        let mut ifreq = IfReq::new(if_name);
+    pub fn vnet_hdr(mut self, enabled: bool) -> Self {
+        self.vnet_hdr = enabled;
+        self
+    }
+
+    /// Open one queue of a device created with `ip tuntap add ... multi_queue`.
+    /// Attaching to such a device fails without this flag.
+    pub fn multi_queue(mut self, enabled: bool) -> Self {
+        self.multi_queue = enabled;
+        self
+    }
+
+    fn flags(&self) -> u16 {
+        let mut flags = IFF_TAP | IFF_NO_PI;
+        if self.vnet_hdr {
+            flags |= IFF_VNET_HDR;
+        }
+        if self.multi_queue {
+            flags |= IFF_MULTI_QUEUE;
+        }
+        flags
+    }
+
+    /// Create the tap device, or attach to it if an interface with this
+    /// name already exists.
+    pub fn open(&self) -> io::Result<Tap> {
+        let file = Tap::open_tun()?;
+        let mut ifreq = IfReq::new(&self.name);
 
         ifreq
-            .set_flags(IFF_TAP | IFF_NO_PI| IFF_VNET_HDR)
+            .set_flags(self.flags())
             .ioctl_mut(&file, TUNSETIFF)?;
 
         let name = ifreq.name().to_string();
-        let tap = Tap { file, name };
+        Ok(Tap { file, name, vnet_hdr: self.vnet_hdr, multi_queue: self.multi_queue })
+    }
+
+    /// Attach to an existing (usually persistent) tap device. Unlike `open()`
+    /// this fails with `NotFound` rather than creating a new interface.
+    pub fn attach(&self) -> io::Result<Tap> {
+        if !Path::new("/sys/class/net").join(&self.name).exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("tap device {} does not exist", self.name)));
+        }
+        self.open()
+    }
+}
+
+impl Tap {
+    pub fn new_default() -> io::Result<Self> {
+        Self::new("vmtap%d")
+    }
+
+    pub fn new(if_name: &str) -> io::Result<Self> {
+        TapOptions::new(if_name).open()
+    }
 
-        Ok(tap)
+    /// A tap device `if_name` opened by another process, such as the network
+    /// helper, and passed to pH as `file`.
+    pub fn from_file(file: File, if_name: &str, vnet_hdr: bool) -> Self {
+        Tap { file, name: if_name.to_string(), vnet_hdr, multi_queue: false }
+    }
+
+    /// Attach to a pre-created tap device `if_name`. `multi_queue` must be
+    /// set if the device was created with the multi-queue flag.
+    pub fn attach(if_name: &str, multi_queue: bool) -> io::Result<Self> {
+        TapOptions::new(if_name)
+            .multi_queue(multi_queue)
+            .attach()
     }
 
     fn open_tun() -> io::Result<File> {
-        OpenOptions::new()
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .custom_flags(libc::O_NONBLOCK|libc::O_CLOEXEC)
-            .open("/dev/net/tun")
+            .open("/dev/net/tun")?;
+        fd_audit::register(file.as_raw_fd(), "tap device");
+        Ok(file)
     }
 
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// A second file descriptor for the device which keeps it from being
+    /// removed and can be passed to the network helper.
+    pub fn try_clone_file(&self) -> io::Result<File> {
+        let file = self.file.try_clone()?;
+        fd_audit::register(file.as_raw_fd(), "tap device");
+        Ok(file)
+    }
+
     pub fn set_offload(&self, flags: libc::c_uint) -> io::Result<()> {
-        unsafe {
-            ioctl_with_val(self.file.as_raw_fd(), TUNSETOFFLOAD, flags.into())?;
-        }
-        Ok(())
+        set_offload(&self.file, flags)
     }
 
     pub fn set_vnet_hdr_size(&self, size: libc::c_int) -> io::Result<()> {
-        unsafe {
-            ioctl_with_ref(self.file.as_raw_fd(), TUNSETVNETHDRSZ, &size)?;
-        }
-        Ok(())
+        set_vnet_hdr_size(&self.file, size)
+    }
+}
+
+pub(super) fn set_offload(file: &File, flags: libc::c_uint) -> io::Result<()> {
+    unsafe {
+        ioctl_with_val(file.as_raw_fd(), TUNSETOFFLOAD, flags.into())?;
+    }
+    Ok(())
+}
+
+pub(super) fn set_vnet_hdr_size(file: &File, size: libc::c_int) -> io::Result<()> {
+    unsafe {
+        ioctl_with_ref(file.as_raw_fd(), TUNSETVNETHDRSZ, &size)?;
+    }
+    Ok(())
+}
+
+impl NetBackend for Tap {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn set_offload(&self, flags: libc::c_uint) -> io::Result<()> {
+        set_offload(&self.file, flags)
+    }
+
+    fn set_vnet_hdr_size(&self, size: libc::c_int) -> io::Result<()> {
+        set_vnet_hdr_size(&self.file, size)
+    }
+
+    fn reopen(&self) -> io::Result<Self> {
+        TapOptions::new(&self.name)
+            .vnet_hdr(self.vnet_hdr)
+            .multi_queue(self.multi_queue)
+            .attach()
     }
 }
 
@@ -91,13 +263,13 @@ impl AsRawFd for Tap {
 
 #[repr(C)]
 #[derive(Copy,Clone,Default)]
-struct IfReq {
+pub(super) struct IfReq {
     pub ireqn: IrReqN,
     pub irequ: IfReqU,
 }
 
 impl IfReq {
-    fn new(ifname: &str) -> Self {
+    pub(super) fn new(ifname: &str) -> Self {
         let ifname = ifname.as_bytes();
         assert!(ifname.len() < 16);
         let mut ifreq = Self::default();
@@ -114,12 +286,12 @@ impl IfReq {
         }
     }
 
-    fn set_flags(&mut self, flags: u16) -> &mut Self {
+    pub(super) fn set_flags(&mut self, flags: u16) -> &mut Self {
         self.irequ.flags = flags;
         self
     }
 
-    fn ioctl_mut<R: AsRawFd>(&mut self, fd: &R, request: libc::c_ulong) -> system::Result<()> {
+    pub(super) fn ioctl_mut<R: AsRawFd>(&mut self, fd: &R, request: libc::c_ulong) -> system::Result<()> {
         unsafe {
             ioctl_with_mut_ref(fd.as_raw_fd(), request, self)?;
         }
@@ -0,0 +1,43 @@
+//! Building blocks for testing virtio devices without KVM.
+//!
+//! `MockVm` stands in for the KVM VM so that a `VirtioDeviceState` and its
+//! queues can be created on machines without /dev/kvm. Tests drive the device
+//! through its PCI BARs as the guest driver would, signal queue notifications
+//! with `MockVm::notify()` and observe interrupts with
+//! `MockVm::take_interrupts()`. Only available with the `mock-kvm` feature.
+
+use std::sync::Arc;
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+use crate::io::address::AddressRange;
+
+pub use crate::vm::{MockVm, MockMemoryRegion, VmOps};
+pub use crate::vm::irq_routing::MsiMessage;
+pub use crate::io::virtio::{VirtioDeviceState, VirtioDevice, DeviceConfigArea, DmaRanges, Queues, VirtQueue, Chain};
+pub use crate::io::pci::{PciDevice, PciBar};
+pub use crate::io::stats::DeviceStats;
+pub use crate::devices::{VirtioBlock, VirtioP9};
+pub use crate::disk::{DiskImage, RawDiskImage};
+#[cfg(feature = "wayland")]
+pub use crate::devices::VirtioWayland;
+pub use crate::io::shm_mapper::DeviceSharedMemoryManager;
+pub use crate::system::ScmSocket;
+
+/// Anonymous guest memory of `size` bytes starting at guest address 0
+pub fn guest_memory(size: usize) -> GuestMemoryMmap {
+    GuestMemoryMmap::from_ranges(&[(GuestAddress(0), size)])
+        .expect("failed to allocate guest memory")
+}
+
+/// Create the state of a virtio device on `vm` using interrupt `irq`
+pub fn virtio_device<T: VirtioDevice + 'static>(device: T, vm: Arc<MockVm>, memory: GuestMemoryMmap, irq: u8) -> VirtioDeviceState {
+    let stats = Arc::new(DeviceStats::new(device.device_type().name()));
+    VirtioDeviceState::new(device, vm, memory, irq, stats)
+        .expect("failed to create virtio device")
+}
+
+/// Device shared memory for `vm` in the range above 4GiB, clear of `memory`
+pub fn device_shm_manager(vm: Arc<MockVm>, memory: &GuestMemoryMmap) -> DeviceSharedMemoryManager {
+    let range = AddressRange::new(1 << 32, DeviceSharedMemoryManager::ADDRESS_RANGE_SIZE);
+    DeviceSharedMemoryManager::new(vm, memory, range)
+}
@@ -0,0 +1,155 @@
+use std::fmt::{self, Write};
+
+/// A minimal JSON value used to format responses on the control socket.
+///
+/// Only serialization is supported. Objects preserve the order in which
+/// fields were added.
+#[derive(Clone,Debug)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(u64),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub fn object() -> Self {
+        JsonValue::Object(Vec::new())
+    }
+
+    pub fn array() -> Self {
+        JsonValue::Array(Vec::new())
+    }
+
+    /// Add a field to an object value. Panics if `self` is not an object.
+    pub fn field<V: Into<JsonValue>>(mut self, name: &str, value: V) -> Self {
+        self.set(name, value);
+        self
+    }
+
+    /// Add a field to an object value. Panics if `self` is not an object.
+    pub fn set<V: Into<JsonValue>>(&mut self, name: &str, value: V) {
+        match self {
+            JsonValue::Object(fields) => fields.push((name.to_string(), value.into())),
+            _ => panic!("JsonValue::set() called on non-object value"),
+        }
+    }
+
+    /// Append an element to an array value. Panics if `self` is not an array.
+    pub fn push<V: Into<JsonValue>>(&mut self, value: V) {
+        match self {
+            JsonValue::Array(elements) => elements.push(value.into()),
+            _ => panic!("JsonValue::push() called on non-array value"),
+        }
+    }
+
+    fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+        f.write_char('"')?;
+        for c in s.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonValue::Null => f.write_str("null"),
+            JsonValue::Bool(b) => write!(f, "{}", b),
+            JsonValue::Number(n) => write!(f, "{}", n),
+            JsonValue::Int(n) => write!(f, "{}", n),
+            JsonValue::Float(n) if n.is_finite() => write!(f, "{}", n),
+            JsonValue::Float(_) => f.write_str("null"),
+            JsonValue::String(s) => Self::write_string(f, s),
+            JsonValue::Array(elements) => {
+                f.write_char('[')?;
+                for (i, e) in elements.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                f.write_char(']')
+            }
+            JsonValue::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    Self::write_string(f, name)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+impl From<bool> for JsonValue {
+    fn from(v: bool) -> Self { JsonValue::Bool(v) }
+}
+
+impl From<u64> for JsonValue {
+    fn from(v: u64) -> Self { JsonValue::Number(v) }
+}
+
+impl From<u32> for JsonValue {
+    fn from(v: u32) -> Self { JsonValue::Number(v.into()) }
+}
+
+impl From<u16> for JsonValue {
+    fn from(v: u16) -> Self { JsonValue::Number(v.into()) }
+}
+
+impl From<u8> for JsonValue {
+    fn from(v: u8) -> Self { JsonValue::Number(v.into()) }
+}
+
+impl From<usize> for JsonValue {
+    fn from(v: usize) -> Self { JsonValue::Number(v as u64) }
+}
+
+impl From<i64> for JsonValue {
+    fn from(v: i64) -> Self { JsonValue::Int(v) }
+}
+
+impl From<f64> for JsonValue {
+    fn from(v: f64) -> Self { JsonValue::Float(v) }
+}
+
+impl From<&str> for JsonValue {
+    fn from(v: &str) -> Self { JsonValue::String(v.to_string()) }
+}
+
+impl From<String> for JsonValue {
+    fn from(v: String) -> Self { JsonValue::String(v) }
+}
+
+impl <T: Into<JsonValue>> From<Option<T>> for JsonValue {
+    fn from(v: Option<T>) -> Self {
+        match v {
+            Some(v) => v.into(),
+            None => JsonValue::Null,
+        }
+    }
+}
+
+impl <T: Into<JsonValue>> From<Vec<T>> for JsonValue {
+    fn from(v: Vec<T>) -> Self {
+        JsonValue::Array(v.into_iter().map(|e| e.into()).collect())
+    }
+}
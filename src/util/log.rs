@@ -1,10 +1,16 @@
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::io::{self,Write};
+use std::time::Instant;
 
 lazy_static! {
     static ref LOGGER: Mutex<Logger> = Mutex::new(Logger::new());
+    static ref LOG_CLOCK: Instant = Instant::now();
 }
 
+// Messages dropped by all rate limited log statements
+static SUPPRESSED_TOTAL: AtomicU64 = AtomicU64::new(0);
+
 #[macro_export]
 macro_rules! debug {
     ($e:expr) => { $crate::Logger::log($crate::LogLevel::Debug, String::from($e)) };
@@ -35,6 +41,84 @@ macro_rules! warn {
     ($fmt:expr, $($arg:tt)+) => { $crate::Logger::log($crate::LogLevel::Warn, format!($fmt, $($arg)+)) };
 }
 
+///
+/// Like `warn!` but for paths which the guest can trigger, such as a bad
+/// request from a driver. Each call site logs at most `LogLimit::BURST`
+/// messages every `LogLimit::WINDOW_SECS` seconds and counts the messages
+/// it drops, so that a misbehaving guest cannot flood the host log.
+///
+#[macro_export]
+macro_rules! warn_limited {
+    ($e:expr) => {{
+        static LIMIT: $crate::util::LogLimit = $crate::util::LogLimit::new();
+        $crate::Logger::log_limited(&LIMIT, $crate::LogLevel::Warn, || String::from($e))
+    }};
+    ($fmt:expr, $($arg:tt)+) => {{
+        static LIMIT: $crate::util::LogLimit = $crate::util::LogLimit::new();
+        $crate::Logger::log_limited(&LIMIT, $crate::LogLevel::Warn, || format!($fmt, $($arg)+))
+    }};
+}
+
+/// Rate limited version of `notify!`, see `warn_limited!`
+#[macro_export]
+macro_rules! notify_limited {
+    ($e:expr) => {{
+        static LIMIT: $crate::util::LogLimit = $crate::util::LogLimit::new();
+        $crate::Logger::log_limited(&LIMIT, $crate::LogLevel::Notice, || String::from($e))
+    }};
+    ($fmt:expr, $($arg:tt)+) => {{
+        static LIMIT: $crate::util::LogLimit = $crate::util::LogLimit::new();
+        $crate::Logger::log_limited(&LIMIT, $crate::LogLevel::Notice, || format!($fmt, $($arg)+))
+    }};
+}
+
+/// Rate limit state of a single `warn_limited!` or `notify_limited!` call site.
+pub struct LogLimit {
+    // Start of the current window in seconds since the first log message
+    window_start: AtomicU64,
+    // Messages logged or dropped in the current window
+    count: AtomicU64,
+    // Messages dropped since the last one which was logged
+    suppressed: AtomicU64,
+}
+
+impl LogLimit {
+    pub const WINDOW_SECS: u64 = 60;
+    pub const BURST: u64 = 10;
+
+    pub const fn new() -> Self {
+        LogLimit {
+            window_start: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    // Returns the number of messages dropped since the last one logged if
+    // this message should be logged, or `None` if it is dropped.
+    fn check(&self) -> Option<u64> {
+        let now = LOG_CLOCK.elapsed().as_secs();
+        let start = self.window_start.load(Ordering::Relaxed);
+        if now >= start + Self::WINDOW_SECS &&
+            self.window_start.compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            self.count.store(0, Ordering::Relaxed);
+        }
+        if self.count.fetch_add(1, Ordering::Relaxed) < Self::BURST {
+            Some(self.suppressed.swap(0, Ordering::Relaxed))
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            SUPPRESSED_TOTAL.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+impl Default for LogLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(PartialOrd,PartialEq,Copy,Clone)]
 pub enum LogLevel {
     Warn,
@@ -69,6 +153,37 @@ impl Logger {
         logger.log_message(level, message.as_ref());
     }
 
+    /// Like `log()`, but gives up rather than waiting if the logger is in
+    /// use, as it may be by the thread calling a panic hook. Returns `false`
+    /// if the message was not logged.
+    pub fn try_log(level: LogLevel, message: impl AsRef<str>) -> bool {
+        match LOGGER.try_lock() {
+            Ok(mut logger) => {
+                logger.log_message(level, message.as_ref());
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Log the message built by `message` unless `limit` has used up the
+    /// messages allowed in the current window. The message is not
+    /// formatted at all when it is dropped.
+    pub fn log_limited<F>(limit: &LogLimit, level: LogLevel, message: F)
+        where F: FnOnce() -> String
+    {
+        match limit.check() {
+            Some(0) => Self::log(level, message()),
+            Some(n) => Self::log(level, format!("{} ({} similar messages suppressed)", message(), n)),
+            None => {},
+        }
+    }
+
+    /// Number of messages dropped by rate limited log statements
+    pub fn suppressed_count() -> u64 {
+        SUPPRESSED_TOTAL.load(Ordering::Relaxed)
+    }
+
     fn new() -> Self {
         Self { level: LogLevel::Notice, output: Box::new(DefaultLogOutput) }
     }
@@ -1,8 +1,10 @@
 mod bitvec;
 mod buffer;
+mod json;
 #[macro_use]
 mod log;
 
 pub use bitvec::BitSet;
 pub use buffer::{ByteBuffer,Writeable};
-pub use log::{Logger,LogLevel};
+pub use log::{Logger,LogLevel,LogLimit};
+pub use json::JsonValue;
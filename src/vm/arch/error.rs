@@ -11,6 +11,10 @@ pub enum Error {
     MemoryManagerCreate(vm_memory::Error),
     #[error("failed to register memory region: {0}")]
     MemoryRegister(kvm_ioctls::Error),
+    #[error("host NUMA node {0} does not exist")]
+    NumaNodeMissing(u32),
+    #[error("failed to bind guest memory to NUMA node {0}: {1}")]
+    NumaBind(u32, ErrnoError),
     #[error("failed to create memory region: {0}")]
     MemoryRegionCreate(system::Error),
     #[error("error loading kernel: {0}")]
@@ -6,7 +6,7 @@ pub use crate::vm::arch::x86::X86ArchSetup;
 mod error;
 mod x86;
 
-pub use x86::{PCI_MMIO_RESERVED_BASE,PCI_MMIO_RESERVED_SIZE,IRQ_BASE,IRQ_MAX};
+pub use x86::{PCI_MMIO_RESERVED_BASE,PCI_MMIO_WINDOW_SIZE,SYSTEM_RESERVED_BASE,SYSTEM_RESERVED_SIZE,IRQ_BASE,IRQ_MAX,e820_map,E820Type};
 
 
 pub use error::{Error,Result};
@@ -21,7 +21,9 @@ pub fn create_setup(config: &VmConfig) -> X86ArchSetup {
 
 pub trait ArchSetup {
     fn create_memory(&mut self, kvm_vm: KvmVm) -> Result<GuestMemoryMmap>;
-    fn setup_memory(&mut self, cmdline: &KernelCmdLine, pci_irqs: &[PciIrq]) -> Result<()>;
+    /// Write boot data into guest memory. `reserved` ranges (base, size) are
+    /// reported to the guest as reserved in the memory map.
+    fn setup_memory(&mut self, cmdline: &KernelCmdLine, pci_irqs: &[PciIrq], reserved: &[(u64, u64)]) -> Result<()>;
     fn setup_vcpu(&self, vcpu: &VcpuFd, cpuid: CpuId) -> Result<()>;
 }
 
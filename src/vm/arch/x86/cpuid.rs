@@ -9,12 +9,16 @@ const EBX_CPUID_SHIFT: u32 = 24; // Index of this CPU.
 const _ECX_EPB_SHIFT: u32 = 3; // "Energy Performance Bias" bit.
 const _ECX_HYPERVISOR_SHIFT: u32 = 31; // Flag to be set when the cpu is running on a hypervisor.
 const _EDX_HTT_SHIFT: u32 = 28; // Hyper Threading Enabled.
+const ECX_PDCM_SHIFT: u32 = 15; // IA32_PERF_CAPABILITIES MSR is available.
 
 const INTEL_EBX: u32 = u32::from_le_bytes([b'G', b'e', b'n', b'u']);
 const INTEL_EDX: u32 = u32::from_le_bytes([b'i', b'n', b'e', b'I']);
 const INTEL_ECX: u32 = u32::from_le_bytes([b'n', b't', b'e', b'l']);
 
-pub fn setup_cpuid(vcpu: &VcpuFd, cpuid: CpuId) -> Result<()> {
+/// Adjust the CPUID reported by KVM for the guest. Unless `pmu` is set the
+/// architectural performance monitoring leaf and the PERF_CAPABILITIES MSR
+/// are hidden.
+pub fn setup_cpuid(vcpu: &VcpuFd, cpuid: CpuId, pmu: bool) -> Result<()> {
     let mut cpuid = cpuid;
 
     let cpu_id = 0u32; // first vcpu
@@ -30,6 +34,9 @@ pub fn setup_cpuid(vcpu: &VcpuFd, cpuid: CpuId) -> Result<()> {
                 if e.index == 0 {
                     e.ecx |= 1<<31;
                 }
+                if !pmu {
+                    e.ecx &= !(1 << ECX_PDCM_SHIFT);
+                }
                 e.ebx = (cpu_id << EBX_CPUID_SHIFT) as u32 |
                     (EBX_CLFLUSH_CACHELINE << EBX_CLFLUSH_SIZE_SHIFT);
                 /*
@@ -44,14 +51,13 @@ pub fn setup_cpuid(vcpu: &VcpuFd, cpuid: CpuId) -> Result<()> {
 
             }
             10 => {
-                if e.eax > 0 {
-                    let version = e.eax & 0xFF;
-                    let ncounters = (e.eax >> 8) & 0xFF;
-                    if version != 2 || ncounters == 0 {
-                        e.eax = 0;
-                    }
+                let ncounters = (e.eax >> 8) & 0xFF;
+                if !pmu || ncounters == 0 {
+                    e.eax = 0;
+                    e.ebx = 0;
+                    e.ecx = 0;
+                    e.edx = 0;
                 }
-
             }
             _ => {}
         }
@@ -1,10 +1,8 @@
-use std::io;
-use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use std::{cmp, io};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
 use crate::system;
 use crate::util::ByteBuffer;
-use crate::vm::arch::PCI_MMIO_RESERVED_BASE;
-use crate::vm::arch::x86::memory::HIMEM_BASE;
 use crate::vm::KERNEL;
 
 pub const KVM_KERNEL_LOAD_ADDRESS: u64 = 0x1000000;
@@ -32,31 +30,90 @@ const KERNEL_LOADER_OTHER: u8 = 0xff;
 const KERNEL_MIN_ALIGNMENT_BYTES: u32 = 0x1000000;
 
 const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
+const E820_MAX_ENTRIES: usize = 128;
 
-fn setup_e820(ram_size: usize, zero: &mut ByteBuffer<Vec<u8>>) -> system::Result<()> {
-    let ram_size = ram_size as u64;
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum E820Type {
+    Ram,
+    Reserved,
+}
 
-    let mut e820_ranges = Vec::new();
-    e820_ranges.push((0u64, EBDA_START));
+impl E820Type {
+    fn value(&self) -> u32 {
+        match self {
+            E820Type::Ram => E820_RAM,
+            E820Type::Reserved => E820_RESERVED,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            E820Type::Ram => "ram",
+            E820Type::Reserved => "reserved",
+        }
+    }
+}
+
+#[derive(Copy,Clone,Debug)]
+pub struct E820Entry {
+    pub addr: u64,
+    pub size: u64,
+    pub kind: E820Type,
+}
+
+impl E820Entry {
+    fn new(addr: u64, size: u64, kind: E820Type) -> Self {
+        E820Entry { addr, size, kind }
+    }
+}
+
+/// The memory map passed to the guest kernel in the zero page.
+///
+/// RAM ranges are taken from the guest memory regions so that the map always
+/// matches the memory actually registered with KVM. With more RAM than fits
+/// below the PCI hole there is a low region ending at `PCI_MMIO_RESERVED_BASE`
+/// and a high region starting at 4GB. `reserved` ranges (base, size) are
+/// added as reserved entries.
+pub fn e820_map(memory: &GuestMemoryMmap, reserved: &[(u64, u64)]) -> Vec<E820Entry> {
+    let mut entries = Vec::new();
+    for r in memory.iter() {
+        let start = r.start_addr().raw_value();
+        let end = start + r.len();
+        if start == 0 {
+            // Leave out the EBDA and everything up to where the kernel is loaded
+            entries.push(E820Entry::new(0, cmp::min(EBDA_START, end), E820Type::Ram));
+            if end > KVM_KERNEL_LOAD_ADDRESS {
+                entries.push(E820Entry::new(KVM_KERNEL_LOAD_ADDRESS, end - KVM_KERNEL_LOAD_ADDRESS, E820Type::Ram));
+            }
+        } else {
+            entries.push(E820Entry::new(start, r.len(), E820Type::Ram));
+        }
+    }
+    for &(addr, size) in reserved {
+        entries.push(E820Entry::new(addr, size, E820Type::Reserved));
+    }
+    entries.sort_by_key(|e| e.addr);
+    entries
+}
 
-    if ram_size < PCI_MMIO_RESERVED_BASE {
-        e820_ranges.push((KVM_KERNEL_LOAD_ADDRESS, ram_size - KVM_KERNEL_LOAD_ADDRESS));
-    } else {
-        e820_ranges.push((KVM_KERNEL_LOAD_ADDRESS, PCI_MMIO_RESERVED_BASE - KVM_KERNEL_LOAD_ADDRESS));
-        e820_ranges.push((HIMEM_BASE, ram_size - HIMEM_BASE));
+fn setup_e820(memory: &GuestMemoryMmap, reserved: &[(u64, u64)], zero: &mut ByteBuffer<Vec<u8>>) -> system::Result<()> {
+    let e820_entries = e820_map(memory, reserved);
+    if e820_entries.len() > E820_MAX_ENTRIES {
+        return Err(system::Error::from_raw_os_error(libc::E2BIG));
     }
-    zero.write_at(BOOT_PARAM_E820_ENTRIES , e820_ranges.len() as u8);
+    zero.write_at(BOOT_PARAM_E820_ENTRIES , e820_entries.len() as u8);
 
     zero.set_offset(BOOT_PARAM_E820_MAP);
-    for i in 0..e820_ranges.len() {
-        zero.write(e820_ranges[i].0)
-            .write(e820_ranges[i].1)
-            .write(E820_RAM);
+    for e in &e820_entries {
+        zero.write(e.addr)
+            .write(e.size)
+            .write(e.kind.value());
     }
     Ok(())
 }
 
-fn setup_zero_page(ram_size: usize, memory: &GuestMemoryMmap, cmdline_addr: u64, cmdline_size: usize) -> system::Result<()> {
+fn setup_zero_page(memory: &GuestMemoryMmap, reserved: &[(u64, u64)], cmdline_addr: u64, cmdline_size: usize) -> system::Result<()> {
     let mut zero = ByteBuffer::new(4096);
     zero.write_at(HDR_BOOT_FLAG, KERNEL_BOOT_FLAG_MAGIC)
         .write_at(HDR_HEADER, KERNEL_HDR_MAGIC)
@@ -65,15 +122,15 @@ fn setup_zero_page(ram_size: usize, memory: &GuestMemoryMmap, cmdline_addr: u64,
         .write_at(HDR_CMDLINE_SIZE, cmdline_size as u32)
         .write_at(HDR_KERNEL_ALIGNMENT, KERNEL_MIN_ALIGNMENT_BYTES);
 
-    setup_e820(ram_size, &mut zero)?;
+    setup_e820(memory, reserved, &mut zero)?;
     memory.write_slice(zero.as_ref(), GuestAddress(KERNEL_ZERO_PAGE))?;
     Ok(())
 
 }
 
-pub fn load_pm_kernel(ram_size: usize, memory: &GuestMemoryMmap, cmdline_addr: u64, cmdline_size: usize) -> system::Result<()> {
+pub fn load_pm_kernel(memory: &GuestMemoryMmap, reserved: &[(u64, u64)], cmdline_addr: u64, cmdline_size: usize) -> system::Result<()> {
     load_elf_kernel(memory)?;
-    setup_zero_page(ram_size, memory,  cmdline_addr, cmdline_size)
+    setup_zero_page(memory, reserved, cmdline_addr, cmdline_size)
 }
 
 fn load_elf_segment(memory: &GuestMemoryMmap, hdr: ElfPhdr) {
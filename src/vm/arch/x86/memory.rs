@@ -9,6 +9,11 @@ use crate::vm::arch::x86::mptable::setup_mptable;
 pub const HIMEM_BASE: u64 = 1 << 32;
 pub const PCI_MMIO_RESERVED_SIZE: usize = 512 << 20;
 pub const PCI_MMIO_RESERVED_BASE: u64 = HIMEM_BASE - PCI_MMIO_RESERVED_SIZE as u64;
+/// IOAPIC, local APIC and other fixed devices at the top of the 32-bit hole
+pub const SYSTEM_RESERVED_BASE: u64 = 0xfec00000;
+pub const SYSTEM_RESERVED_SIZE: usize = (HIMEM_BASE - SYSTEM_RESERVED_BASE) as usize;
+/// Part of the 32-bit hole available for PCI BARs
+pub const PCI_MMIO_WINDOW_SIZE: usize = (SYSTEM_RESERVED_BASE - PCI_MMIO_RESERVED_BASE) as usize;
 pub const IRQ_BASE: u32 = 5;
 pub const IRQ_MAX: u32 = 23;
 
@@ -19,8 +24,8 @@ const BOOT_PML4: u64 = 0x9000;
 const BOOT_PDPTE: u64 = 0xA000;
 const BOOT_PDE: u64 = 0xB000;
 
-pub fn x86_setup_memory(ram_size: usize, memory: &GuestMemoryMmap, cmdline: &KernelCmdLine, ncpus: usize, pci_irqs: &[PciIrq]) -> Result<()> {
-    load_pm_kernel(ram_size, memory, KERNEL_CMDLINE_ADDRESS, cmdline.size())
+pub fn x86_setup_memory(memory: &GuestMemoryMmap, reserved: &[(u64, u64)], cmdline: &KernelCmdLine, ncpus: usize, pci_irqs: &[PciIrq]) -> Result<()> {
+    load_pm_kernel(memory, reserved, KERNEL_CMDLINE_ADDRESS, cmdline.size())
         .map_err(Error::LoadKernel)?;
     setup_gdt(memory)?;
     setup_boot_pagetables(memory).map_err(Error::SystemError)?;
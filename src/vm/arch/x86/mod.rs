@@ -8,4 +8,5 @@ mod kernel;
 mod setup;
 
 pub use setup::X86ArchSetup;
-pub use memory::{PCI_MMIO_RESERVED_BASE,PCI_MMIO_RESERVED_SIZE,IRQ_BASE,IRQ_MAX};
\ No newline at end of file
+pub use memory::{PCI_MMIO_RESERVED_BASE,PCI_MMIO_WINDOW_SIZE,SYSTEM_RESERVED_BASE,SYSTEM_RESERVED_SIZE,IRQ_BASE,IRQ_MAX};
+pub use kernel::{e820_map, E820Type};
\ No newline at end of file
@@ -1,3 +1,4 @@
+use std::cmp;
 use kvm_bindings::CpuId;
 use kvm_ioctls::VcpuFd;
 use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
@@ -11,10 +12,17 @@ use crate::vm::arch::x86::registers::{setup_pm_sregs, setup_pm_regs, setup_fpu,
 use crate::vm::arch::x86::interrupts::setup_lapic;
 use crate::vm::arch::x86::kernel::KVM_KERNEL_LOAD_ADDRESS;
 use crate::vm::kvm_vm::KvmVm;
+use crate::system::numa;
+
+// Guest RAM is split between NUMA nodes on huge page boundaries
+const NUMA_SPLIT_ALIGN: usize = 2 * 1024 * 1024;
 
 pub struct X86ArchSetup {
     ram_size: usize,
     ncpus: usize,
+    numa_nodes: Vec<u32>,
+    pmu: bool,
+    firmware: bool,
     memory: Option<GuestMemoryMmap>,
 }
 
@@ -23,12 +31,17 @@ impl X86ArchSetup {
         let ram_size = config.ram_size();
         X86ArchSetup {
             ram_size,
-            ncpus: config.ncpus(),
+            ncpus: config.get_max_cpus(),
+            numa_nodes: config.get_numa_nodes().to_vec(),
+            pmu: config.is_pmu_enabled(),
+            firmware: config.get_firmware().is_some(),
             memory: None,
         }
     }
 }
 
+/// Guest RAM ranges for `mem_size` bytes of memory. RAM which does not fit
+/// below the PCI hole at `PCI_MMIO_RESERVED_BASE` is placed above 4GB.
 fn x86_memory_ranges(mem_size: usize) -> Vec<(GuestAddress, usize)> {
     match mem_size.checked_sub(PCI_MMIO_RESERVED_BASE as usize) {
         None | Some(0) => vec![(GuestAddress(0), mem_size)],
@@ -39,6 +52,39 @@ fn x86_memory_ranges(mem_size: usize) -> Vec<(GuestAddress, usize)> {
     }
 }
 
+/// Divide guest RAM into equal parts, one for each entry of `nodes`, and bind
+/// each part to the corresponding host NUMA node. Parts are counted across all
+/// memory regions so a part may span the hole below 4GB.
+fn bind_numa_nodes(memory: &GuestMemoryMmap, nodes: &[u32]) -> Result<()> {
+    if let Some(&node) = nodes.iter().find(|&&n| !numa::node_exists(n)) {
+        return Err(Error::NumaNodeMissing(node));
+    }
+    let total: usize = memory.iter().map(|r| r.len() as usize).sum();
+    let per_node = total / nodes.len();
+    let per_node = cmp::max(NUMA_SPLIT_ALIGN, (per_node + NUMA_SPLIT_ALIGN - 1) & !(NUMA_SPLIT_ALIGN - 1));
+
+    let mut ram_offset = 0;
+    for r in memory.iter() {
+        let host_address = memory.get_host_address(r.start_addr()).unwrap();
+        let len = r.len() as usize;
+        let mut offset = 0;
+        while offset < len {
+            let idx = cmp::min((ram_offset + offset) / per_node, nodes.len() - 1);
+            let part_end = if idx == nodes.len() - 1 {
+                ram_offset + len
+            } else {
+                (idx + 1) * per_node
+            };
+            let n = cmp::min(len - offset, part_end - (ram_offset + offset));
+            let addr = unsafe { host_address.add(offset) };
+            numa::mbind_node(addr, n, nodes[idx])
+                .map_err(|e| Error::NumaBind(nodes[idx], e))?;
+            offset += n;
+        }
+        ram_offset += len;
+    }
+    Ok(())
+}
 
 impl ArchSetup for X86ArchSetup {
     fn create_memory(&mut self, kvm_vm: KvmVm) -> Result<GuestMemoryMmap> {
@@ -46,6 +92,10 @@ impl ArchSetup for X86ArchSetup {
         let guest_memory = GuestMemoryMmap::from_ranges(&ranges)
             .map_err(Error::MemoryManagerCreate)?;
 
+        if !self.numa_nodes.is_empty() {
+            bind_numa_nodes(&guest_memory, &self.numa_nodes)?;
+        }
+
         for (i, r) in guest_memory.iter().enumerate() {
             let slot = i as u32;
             let guest_address = r.start_addr().raw_value();
@@ -57,16 +107,24 @@ impl ArchSetup for X86ArchSetup {
         Ok(guest_memory)
     }
 
-    fn setup_memory(&mut self, cmdline: &KernelCmdLine, pci_irqs: &[PciIrq]) -> Result<()> {
+    fn setup_memory(&mut self, cmdline: &KernelCmdLine, pci_irqs: &[PciIrq], reserved: &[(u64, u64)]) -> Result<()> {
+        if self.firmware {
+            // The firmware builds its own boot tables
+            return Ok(());
+        }
         let memory = self.memory.as_mut().expect("No memory created");
-        x86_setup_memory(self.ram_size, memory, cmdline, self.ncpus, pci_irqs)?;
+        x86_setup_memory(memory, reserved, cmdline, self.ncpus, pci_irqs)?;
         Ok(())
     }
 
     fn setup_vcpu(&self, vcpu_fd: &VcpuFd, cpuid: CpuId) -> Result<()> {
-        setup_cpuid(vcpu_fd, cpuid)?;
-        setup_pm_sregs(vcpu_fd)?;
-        setup_pm_regs(&vcpu_fd, KVM_KERNEL_LOAD_ADDRESS)?;
+        setup_cpuid(vcpu_fd, cpuid, self.pmu)?;
+        // Firmware starts in real mode at the reset vector, which is the
+        // state KVM creates the vcpu in
+        if !self.firmware {
+            setup_pm_sregs(vcpu_fd)?;
+            setup_pm_regs(&vcpu_fd, KVM_KERNEL_LOAD_ADDRESS)?;
+        }
         setup_fpu(vcpu_fd)?;
         setup_msrs(vcpu_fd)?;
         setup_lapic(vcpu_fd)?;
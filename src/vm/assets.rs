@@ -0,0 +1,90 @@
+//! The assets volume, a host directory shared read-only with every guest
+//! which has font and icon caches built once on the host rather than in the
+//! home directory of each realm on the first launch of a GUI application.
+//!
+//!     fonts/        font files, added to the fontconfig search path
+//!     fontconfig/   caches for fonts/, built by warm_asset_caches()
+//!     icons/        icon themes with their icon-theme.cache, bound over
+//!                   /usr/local/share/icons
+//!
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// 9p tag of the assets share
+pub const ASSETS_TAG: &str = "assets";
+
+// Where ph-init mounts the share. The font caches are only valid for the
+// fonts at this path.
+const GUEST_ASSETS_PATH: &str = "/run/ph/assets";
+
+const SUBDIRS: &[&str] = &["fonts", "fontconfig", "icons"];
+
+///
+/// Create the layout of an assets volume in `dir` and build the fontconfig
+/// cache for `dir/fonts` and the GTK icon cache of each theme in `dir/icons`.
+/// Run again after adding fonts or icons.
+///
+/// Needs `fc-cache` from fontconfig 2.13.1 or later, and
+/// `gtk-update-icon-cache` if there are icon themes.
+///
+pub fn warm_asset_caches<P: AsRef<Path>>(dir: P) -> io::Result<()> {
+    let dir = dir.as_ref();
+    for sub in SUBDIRS {
+        fs::create_dir_all(dir.join(sub))?;
+    }
+    build_font_cache(dir)?;
+    build_icon_caches(&dir.join("icons"))
+}
+
+// The caches name the directory they describe, so fc-cache is given a
+// configuration which scans the fonts as if they were at the guest path.
+fn build_font_cache(dir: &Path) -> io::Result<()> {
+    let conf_path = std::env::temp_dir()
+        .join(format!("ph-assets-fonts-{}.conf", std::process::id()));
+    let conf = format!(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE fontconfig SYSTEM \"urn:fontconfig:fonts.dtd\">\n\
+         <fontconfig>\n  \
+           <remap-dir as-path=\"{}/fonts\">{}</remap-dir>\n  \
+           <cachedir>{}</cachedir>\n\
+         </fontconfig>\n",
+        GUEST_ASSETS_PATH,
+        xml_escape(&dir.join("fonts").to_string_lossy()),
+        xml_escape(&dir.join("fontconfig").to_string_lossy()));
+    fs::write(&conf_path, conf)?;
+    let result = run(Command::new("fc-cache")
+        .arg("--force")
+        .env("FONTCONFIG_FILE", &conf_path));
+    let _ = fs::remove_file(&conf_path);
+    result
+}
+
+fn build_icon_caches(icons: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(icons)? {
+        let theme = entry?.path();
+        if theme.join("index.theme").exists() {
+            run(Command::new("gtk-update-icon-cache")
+                .args(&["--force", "--quiet"])
+                .arg(&theme))?;
+        }
+    }
+    Ok(())
+}
+
+fn run(command: &mut Command) -> io::Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command.status()
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to run {}: {}", program, e)))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, format!("{} exited with {}", program, status)))
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
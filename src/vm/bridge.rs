@@ -0,0 +1,52 @@
+use std::fs::File;
+use std::io;
+use std::sync::Mutex;
+
+use crate::system::{net_helper, tap_name, NetlinkSocket};
+
+///
+/// Adds the tap device of a running VM to a host bridge again, for example
+/// after the bridge was deleted and recreated by the network configuration
+/// of the host, or moves it to a different bridge.
+///
+/// When the tap device was created by the network helper the helper is run
+/// again to change the bridge, since pH no longer has the privileges to do
+/// it itself. Otherwise the change is made directly, which only succeeds if
+/// pH still has `CAP_NET_ADMIN`.
+///
+pub struct BridgeControl {
+    tap: File,
+    helper: Option<String>,
+    bridge: Mutex<String>,
+}
+
+impl BridgeControl {
+    pub fn new(tap: File, bridge: &str, helper: Option<&str>) -> Self {
+        BridgeControl {
+            tap,
+            helper: helper.map(|s| s.to_string()),
+            bridge: Mutex::new(bridge.to_string()),
+        }
+    }
+
+    pub fn bridge(&self) -> String {
+        self.bridge.lock().unwrap().clone()
+    }
+
+    /// Add the tap device to `bridge`, or to the current bridge if `None`.
+    pub fn rejoin(&self, bridge: Option<&str>) -> io::Result<()> {
+        let mut current = self.bridge.lock().unwrap();
+        let bridge = bridge.unwrap_or(current.as_str()).to_string();
+        match self.helper {
+            Some(ref command) => net_helper::request_join(command, &self.tap, &bridge)?,
+            None => {
+                let ifname = tap_name(&self.tap)?;
+                NetlinkSocket::open()
+                    .and_then(|nl| nl.join_bridge(&ifname, &bridge))
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            }
+        }
+        *current = bridge;
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+#[cfg(feature = "network")]
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+#[cfg(feature = "network")]
+use crate::system::{MacVTapBackend, Tap};
+use crate::util::JsonValue;
+use crate::vm::VmConfig;
+
+// Bit of CAP_NET_ADMIN in the capability sets of /proc/self/status
+const CAP_NET_ADMIN: u32 = 12;
+
+// Audio is always sent to the session of uid 1000, see `VmSetup::create_vm()`
+const PULSE_SOCKET: &str = "/run/user/1000/pulse/native";
+
+/// Whether the process has CAP_NET_ADMIN, which is needed to create tap
+/// devices and bridges.
+pub fn has_net_admin() -> bool {
+    let status = match fs::read_to_string("/proc/self/status") {
+        Ok(status) => status,
+        Err(_) => return false,
+    };
+    status.lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .map(|caps| caps & (1 << CAP_NET_ADMIN) != 0)
+        .unwrap_or(false)
+}
+
+fn access(path: &Path, mode: libc::c_int) -> io::Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    if unsafe { libc::access(cpath.as_ptr(), mode) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn open_rdwr(path: &str) -> io::Result<()> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_CLOEXEC)
+        .open(path)
+        .map(|_| ())
+}
+
+#[derive(Copy,Clone,PartialEq)]
+enum Status {
+    Available,
+    Unavailable,
+    // Turned off in the configuration, so not probed
+    Disabled,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Available => "available",
+            Status::Unavailable => "unavailable",
+            Status::Disabled => "disabled",
+        }
+    }
+}
+
+///
+/// Whether a feature of pH can be used by the current user, with the reason
+/// when it can not and what to do about it.
+///
+struct Capability {
+    name: &'static str,
+    status: Status,
+    required: bool,
+    detail: String,
+    hint: Option<String>,
+}
+
+impl Capability {
+    fn new(name: &'static str, status: Status, detail: String, hint: Option<&str>) -> Self {
+        Capability { name, status, required: false, detail, hint: hint.map(str::to_string) }
+    }
+
+    fn available(name: &'static str, detail: String) -> Self {
+        Self::new(name, Status::Available, detail, None)
+    }
+
+    fn unavailable(name: &'static str, detail: String, hint: &str) -> Self {
+        Self::new(name, Status::Unavailable, detail, Some(hint))
+    }
+
+    fn disabled(name: &'static str, detail: &str) -> Self {
+        Self::new(name, Status::Disabled, detail.to_string(), None)
+    }
+
+    fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    fn to_json(&self) -> JsonValue {
+        JsonValue::object()
+            .field("name", self.name)
+            .field("status", self.status.name())
+            .field("required", self.required)
+            .field("detail", self.detail.as_str())
+            .field("hint", self.hint.clone())
+    }
+}
+
+///
+/// What pH can and can not do for the current user with a configuration,
+/// shown by `pH --check`.
+///
+/// The probes only open and inspect devices, sockets and directories. They do
+/// not create tap devices or a VM.
+///
+pub struct CapabilityReport {
+    uid: u32,
+    euid: u32,
+    net_admin: bool,
+    capabilities: Vec<Capability>,
+}
+
+impl CapabilityReport {
+    pub fn probe(config: &VmConfig) -> Self {
+        let (uid, euid) = unsafe { (libc::getuid(), libc::geteuid()) };
+        let capabilities = vec![
+            Self::probe_kvm(),
+            Self::probe_network(config),
+            Self::probe_wayland(config),
+            Self::probe_dmabuf(config),
+            Self::probe_audio(config),
+            Self::probe_home(config),
+            Self::probe_privileges(euid),
+        ];
+        CapabilityReport { uid, euid, net_admin: has_net_admin(), capabilities }
+    }
+
+    fn probe_kvm() -> Capability {
+        match open_rdwr("/dev/kvm") {
+            Ok(()) => Capability::available("kvm", "/dev/kvm can be opened".to_string()),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Capability::unavailable("kvm",
+                "/dev/kvm does not exist".to_string(),
+                "load the kvm_intel or kvm_amd kernel module and check virtualization is enabled in the firmware"),
+            Err(e) => Capability::unavailable("kvm",
+                format!("cannot open /dev/kvm: {}", e),
+                "add the user to the group which owns /dev/kvm, usually kvm"),
+        }.required()
+    }
+
+    #[cfg(not(feature = "network"))]
+    fn probe_network(_config: &VmConfig) -> Capability {
+        Capability::disabled("network", "pH was built without the network feature")
+    }
+
+    #[cfg(feature = "network")]
+    fn probe_network(config: &VmConfig) -> Capability {
+        const NAME: &str = "network";
+        if !config.network() {
+            return Capability::disabled(NAME, "turned off with --no-network");
+        }
+        if let Some(name) = config.macvtap_name() {
+            return match MacVTapBackend::open(name) {
+                Ok(_) => Capability::available(NAME, format!("macvtap interface {} can be opened", name)),
+                Err(e) => Capability::unavailable(NAME,
+                    format!("cannot open macvtap interface {}: {}", name, e),
+                    "give the user read and write access to /dev/tapN, where N is the ifindex of the interface"),
+            };
+        }
+        if let Some(name) = config.tap_name() {
+            return match Tap::attach(name, config.get_tap_multi_queue()) {
+                Ok(_) => Capability::available(NAME, format!("tap device {} can be attached", name)),
+                Err(e) => Capability::unavailable(NAME,
+                    format!("cannot attach to tap device {}: {}", name, e),
+                    "create it with 'ip tuntap add dev NAME mode tap vnet_hdr user USER'"),
+            };
+        }
+        if let Some(command) = config.get_net_helper() {
+            return Self::probe_net_helper(command);
+        }
+        if !has_net_admin() {
+            return Capability::unavailable(NAME,
+                "creating a tap device requires CAP_NET_ADMIN".to_string(),
+                "run pH as root, use --net-helper, or create a persistent tap device owned by the user and pass --tap NAME");
+        }
+        match open_rdwr("/dev/net/tun") {
+            Ok(()) => Capability::available(NAME, format!("a tap device can be created and added to bridge {}", config.bridge())),
+            Err(e) => Capability::unavailable(NAME,
+                format!("cannot open /dev/net/tun: {}", e),
+                "load the tun kernel module"),
+        }
+    }
+
+    // The helper is not run, only checked to be an executable which will run
+    // with privileges, either setuid root or started by another program such
+    // as pkexec
+    #[cfg(feature = "network")]
+    fn probe_net_helper(command: &str) -> Capability {
+        const NAME: &str = "network";
+        let mut words = command.split_whitespace();
+        let program = words.next().unwrap_or("");
+        let helper = if program.ends_with("pkexec") { words.next().unwrap_or("") } else { program };
+        let meta = match fs::metadata(helper) {
+            Ok(meta) => meta,
+            Err(e) => return Capability::unavailable(NAME,
+                format!("cannot use network helper {}: {}", helper, e),
+                "install ph-net-helper and pass its full path with --net-helper"),
+        };
+        if let Err(e) = access(Path::new(helper), libc::X_OK) {
+            return Capability::unavailable(NAME,
+                format!("cannot execute network helper {}: {}", helper, e),
+                "give the user execute permission on the helper");
+        }
+        if helper == program && (meta.uid() != 0 || meta.mode() & libc::S_ISUID == 0) {
+            return Capability::unavailable(NAME,
+                format!("network helper {} is not setuid root", helper),
+                "run 'chown root: HELPER && chmod u+s HELPER', or start it with pkexec");
+        }
+        Capability::available(NAME, format!("tap devices are created by {}", command))
+    }
+
+    fn probe_wayland(config: &VmConfig) -> Capability {
+        const NAME: &str = "wayland";
+        if !cfg!(feature = "wayland") {
+            return Capability::disabled(NAME, "pH was built without the wayland feature");
+        }
+        if !config.is_wayland_requested() {
+            return Capability::disabled(NAME, "turned off with --no-wayland");
+        }
+        let socket = config.get_wayland_socket();
+        match access(&socket, libc::R_OK | libc::W_OK) {
+            Ok(()) => Capability::available(NAME, format!("compositor socket {} is accessible", socket.display())),
+            Err(e) => Capability::unavailable(NAME,
+                format!("cannot use compositor socket {}: {}", socket.display(), e),
+                "set WAYLAND_DISPLAY and XDG_RUNTIME_DIR for the session, or pass --wayland-socket PATH"),
+        }
+    }
+
+    fn probe_dmabuf(config: &VmConfig) -> Capability {
+        const NAME: &str = "dmabuf";
+        if !cfg!(feature = "wayland") {
+            return Capability::disabled(NAME, "pH was built without the wayland feature");
+        }
+        if !config.is_dmabuf_enabled() {
+            return Capability::disabled(NAME, "not enabled, see --use-dmabuf");
+        }
+        match open_rdwr("/dev/dri/renderD128") {
+            Ok(()) => Capability::available(NAME, "/dev/dri/renderD128 can be opened".to_string()),
+            Err(e) => Capability::unavailable(NAME,
+                format!("cannot open /dev/dri/renderD128: {}", e),
+                "add the user to the group which owns /dev/dri/renderD128, usually render"),
+        }
+    }
+
+    fn probe_audio(config: &VmConfig) -> Capability {
+        const NAME: &str = "audio";
+        if !cfg!(feature = "audio") {
+            return Capability::disabled(NAME, "pH was built without the audio feature");
+        }
+        if !config.is_audio_enable() {
+            return Capability::disabled(NAME, "turned off in the configuration");
+        }
+        match access(Path::new(PULSE_SOCKET), libc::R_OK | libc::W_OK) {
+            Ok(()) => Capability::available(NAME, format!("pulseaudio socket {} is accessible", PULSE_SOCKET)),
+            Err(e) => Capability::unavailable(NAME,
+                format!("cannot use pulseaudio socket {}: {}", PULSE_SOCKET, e),
+                "start a pulseaudio or pipewire-pulse server in the session of uid 1000"),
+        }
+    }
+
+    fn probe_home(config: &VmConfig) -> Capability {
+        const NAME: &str = "home";
+        let home = Path::new(config.homedir());
+        match access(home, libc::R_OK | libc::W_OK | libc::X_OK) {
+            Ok(()) => Capability::available(NAME, format!("{} can be shared read-write", home.display())),
+            Err(e) => Capability::unavailable(NAME,
+                format!("cannot share {}: {}", home.display(), e),
+                "pass a directory the user can write to with --home PATH"),
+        }.required()
+    }
+
+    fn probe_privileges(euid: u32) -> Capability {
+        const NAME: &str = "privileges";
+        if euid == 0 {
+            Capability::available(NAME, "running as root, privileges are dropped to uid 1000 after network setup".to_string())
+        } else {
+            Capability::available(NAME, format!("running as uid {}, no privileges to drop", euid))
+        }
+    }
+
+    /// Whether every capability needed to boot a VM is available
+    pub fn can_boot(&self) -> bool {
+        self.capabilities.iter().all(|c| c.status == Status::Available || !c.required)
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut capabilities = JsonValue::array();
+        for c in &self.capabilities {
+            capabilities.push(c.to_json());
+        }
+        JsonValue::object()
+            .field("uid", self.uid)
+            .field("euid", self.euid)
+            .field("cap_net_admin", self.net_admin)
+            .field("can_boot", self.can_boot())
+            .field("capabilities", capabilities)
+    }
+
+    pub fn print(&self) {
+        println!("Running as uid {} (euid {}), CAP_NET_ADMIN {}",
+                 self.uid, self.euid, if self.net_admin { "present" } else { "absent" });
+        println!();
+        for c in &self.capabilities {
+            let status = match c.status {
+                Status::Available => "yes",
+                Status::Unavailable if c.required => "NO (required)",
+                Status::Unavailable => "no",
+                Status::Disabled => "off",
+            };
+            println!("  {:<12} {:<14} {}", c.name, status, c.detail);
+            if let Some(hint) = &c.hint {
+                println!("  {:<12} {:<14} -> {}", "", "", hint);
+            }
+        }
+        println!();
+        if self.can_boot() {
+            println!("pH can boot a VM with this configuration");
+        } else {
+            println!("pH cannot boot a VM with this configuration");
+        }
+    }
+}
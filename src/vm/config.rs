@@ -1,59 +1,278 @@
 use std::path::{PathBuf, Path};
-use crate::vm::{VmSetup, arch};
-use std::{env, process};
-use crate::devices::SyntheticFS;
-use crate::disk::{RawDiskImage, RealmFSImage, OpenType};
-use libcitadel::Realms;
-use libcitadel::terminal::{TerminalPalette, AnsiTerminal, Base16Scheme};
+use crate::vm::{VmSetup, VmHandle, VmExitReason, arch};
+use std::{env, fs, process};
+use std::net::{IpAddr, Ipv4Addr};
+use crate::devices::{SyntheticFS, ConsoleOptions, CtrlCPolicy, QuotaLimits, FidLimits};
+#[cfg(feature = "network")]
+use crate::devices::NetRateLimit;
+use crate::disk::{self, CacheMode, DiskFormat, RawDiskImage, RealmFSImage, OpenType, VIRTIO_BLK_ID_BYTES};
 use crate::vm::arch::X86ArchSetup;
+use crate::vm::msr::MsrPolicy;
+use crate::vm::capabilities::CapabilityReport;
+use crate::vm::terminal::TerminalTheme;
+use crate::vm::panic_hook;
+use crate::vm::assets;
+use crate::vm::realm::{self, RealmDisk, RealmInfo, RealmProvider};
+use crate::io::manager::DevicePlacement;
+use crate::system::sched::ThreadPriority;
+use crate::audio::{AudioLatency, StreamEffect};
+
+// Terminal color scheme for realms which do not configure one
+const DEFAULT_REALM_COLOR_SCHEME: &str = "dracula";
+
+// Hostname of a guest which is not a realm
+const DEFAULT_HOSTNAME: &str = "airwolf";
+// The online count is reported to the guest in a single byte
+const MAX_CPUS: usize = 64;
+// Guest tmpfs mounts which can be given a size limit with --tmpfs-size
+const GUEST_TMPFS_NAMES: &[&str] = &["tmp", "run", "shm", "overlay"];
+// Range of --net-mtu, the smallest IPv4 MTU up to the largest frame a tap
+// device accepts
+const MIN_NET_MTU: u32 = 68;
+const MAX_NET_MTU: u32 = 65535;
+const MAX_VLAN_ID: u16 = 4094;
+
+/// Which block device the guest mounts as its root filesystem.
+#[derive(Clone,Debug,PartialEq)]
+pub enum RootDevice {
+    /// The disk at this position, counting realmfs images first and then raw
+    /// disk images in the order they were added.
+    Index(usize),
+    /// The disk added with this label, or else the disk holding an ext4
+    /// filesystem with this volume label.
+    Label(String),
+    /// The host root filesystem shared read-only over 9p, as when no disks
+    /// are attached. Disks are still available to the guest.
+    HostRoot,
+}
+
+impl RootDevice {
+    fn parse(arg: &str) -> Option<RootDevice> {
+        if arg == "host" {
+            return Some(RootDevice::HostRoot);
+        }
+        match arg.strip_prefix("LABEL=") {
+            Some(label) if !label.is_empty() => Some(RootDevice::Label(label.to_string())),
+            Some(_) => None,
+            None => arg.parse().ok().map(RootDevice::Index),
+        }
+    }
+}
+
+/// How a read-only image added with `VmConfig::rom_image()` is presented to
+/// the guest.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum RomMapping {
+    /// A read-only KVM memory region which the guest reads at memory speed.
+    /// The base address must be page aligned and the last page is padded with
+    /// zeroes.
+    Memory,
+    /// A device on the MMIO bus which pH answers on every access, at any
+    /// address and size.
+    Mmio,
+}
+
+/// Resource limits which ph-init applies to a service it launches in the
+/// guest by placing it in its own cgroup.
+#[derive(Copy,Clone,Debug,Default,PartialEq)]
+pub struct ServiceLimits {
+    /// Maximum memory use in bytes
+    pub memory: Option<u64>,
+    /// Maximum CPU use as a percentage of one guest cpu. Values above 100
+    /// allow the service to use more than one cpu.
+    pub cpu_percent: Option<u32>,
+}
+
+impl ServiceLimits {
+    /// Parse limits of the form `memory=SIZE,cpu=PERCENT` where either field
+    /// may be omitted and the size may have a K, M or G suffix.
+    fn parse(s: &str) -> Option<ServiceLimits> {
+        let mut limits = ServiceLimits::default();
+        for field in s.split(',') {
+            let (key, value) = field.split_once('=')?;
+            match key {
+                "memory" => limits.memory = Some(Self::parse_size(value)?),
+                "cpu" => limits.cpu_percent = Some(value.trim_end_matches('%').parse().ok()?),
+                _ => return None,
+            }
+        }
+        if limits.memory == Some(0) || limits.cpu_percent == Some(0) {
+            return None;
+        }
+        Some(limits)
+    }
+
+    fn parse_size(s: &str) -> Option<u64> {
+        let (digits, shift) = match s.chars().last()? {
+            'K' | 'k' => (&s[..s.len() - 1], 10),
+            'M' | 'm' => (&s[..s.len() - 1], 20),
+            'G' | 'g' => (&s[..s.len() - 1], 30),
+            _ => (s, 0),
+        };
+        digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+    }
+}
 
 pub struct VmConfig {
     ram_size: usize,
     ncpus: usize,
+    max_cpus: Option<usize>,
+    cpu_quota: Option<u32>,
+    service_limits: Vec<(String, ServiceLimits)>,
+    guest_services: Vec<(String, String)>,
+    tmpfs_sizes: Vec<(String, String)>,
+    zram_swap: Option<u64>,
+    numa_nodes: Vec<u32>,
     verbose: bool,
     rootshell: bool,
     wayland: bool,
     dmabuf: bool,
+    wl_max_vfds: Option<usize>,
+    wayland_socket: Option<PathBuf>,
+    wayland_named_sockets: Vec<(String, PathBuf)>,
     network: bool,
     audio: bool,
+    audio_latency: AudioLatency,
+    audio_effects: Vec<StreamEffect>,
+    split_irqchip: bool,
+    strict_dma: bool,
+    audit_fds: bool,
+    exit_on_device_panic: bool,
+    pmu: bool,
+    msr_policy: MsrPolicy,
+    console: ConsoleOptions,
     home: String,
-    colorscheme: String,
+    home_quota: Option<QuotaLimits>,
+    home_fid_limits: Option<FidLimits>,
+    assets: Option<String>,
+    colorscheme: Option<String>,
     bridge_name: String,
+    net_helper: Option<String>,
+    tap_name: Option<String>,
+    tap_multi_queue: bool,
+    macvtap_name: Option<String>,
+    net_capture: Option<PathBuf>,
+    net_capture_size: Option<u64>,
+    net_check_checksums: bool,
+    #[cfg(feature = "network")]
+    net_tx_limit: Option<NetRateLimit>,
+    net_mtu: Option<u32>,
+    guest_vlan: Option<u16>,
+    guest_ip: Option<Ipv4Addr>,
+    dns_servers: Vec<IpAddr>,
+    dns_search: Vec<String>,
+    control_socket: Option<PathBuf>,
+    metrics_address: Option<String>,
+    device_placements: Vec<(String, DevicePlacement)>,
+    thread_priorities: Vec<(String, ThreadPriority)>,
+    reserved_memory: Vec<(String, u64, usize)>,
+    rom_images: Vec<(String, PathBuf, u64, RomMapping)>,
     kernel_path: Option<PathBuf>,
+    firmware: Option<PathBuf>,
     init_path: Option<PathBuf>,
     init_cmd: Option<String>,
+    guest_command: Option<String>,
     raw_disks: Vec<RawDiskImage>,
+    host_disks: Vec<RawDiskImage>,
+    disk_cache: CacheMode,
+    commit_overlays: bool,
+    ignore_disk_locks: bool,
+    root_device: Option<RootDevice>,
 
     realmfs_images: Vec<RealmFSImage>,
     realm_name: Option<String>,
+    hostname: Option<String>,
+    machine_id: Option<String>,
+    timezone: Option<String>,
+    rtc_localtime: bool,
     synthetic: Option<SyntheticFS>,
 }
 
 #[allow(dead_code)]
 impl VmConfig {
+    /// Create a configuration from the command line arguments of the process.
     pub fn new() -> VmConfig {
-        let mut config = VmConfig {
+        let mut config = Self::new_default();
+        config.parse_args();
+        config
+    }
+
+    /// Create a configuration with default settings, ignoring the command
+    /// line. Use this when pH is embedded in another program.
+    pub fn new_default() -> VmConfig {
+        VmConfig {
             ram_size: 256 * 1024 * 1024,
             ncpus: 4,
+            max_cpus: None,
+            cpu_quota: None,
+            service_limits: Vec::new(),
+            guest_services: Vec::new(),
+            tmpfs_sizes: Vec::new(),
+            zram_swap: None,
+            numa_nodes: Vec::new(),
             verbose: false,
             rootshell: false,
-            wayland: true,
+            wayland: cfg!(feature = "wayland"),
             dmabuf: false,
-            network: true,
-            audio: true,
+            wl_max_vfds: None,
+            wayland_socket: None,
+            wayland_named_sockets: Vec::new(),
+            network: cfg!(feature = "network"),
+            audio: cfg!(feature = "audio"),
+            audio_latency: AudioLatency::default(),
+            audio_effects: Vec::new(),
+            split_irqchip: false,
+            strict_dma: false,
+            audit_fds: false,
+            exit_on_device_panic: false,
+            pmu: false,
+            msr_policy: MsrPolicy::Fault,
+            console: ConsoleOptions::default(),
             bridge_name: "vz-clear".to_string(),
+            net_helper: None,
+            tap_name: None,
+            tap_multi_queue: false,
+            macvtap_name: None,
+            net_capture: None,
+            net_capture_size: None,
+            net_check_checksums: false,
+            #[cfg(feature = "network")]
+            net_tx_limit: None,
+            net_mtu: None,
+            guest_vlan: None,
+            guest_ip: None,
+            dns_servers: Vec::new(),
+            dns_search: Vec::new(),
             home: Self::default_homedir(),
-            colorscheme: "dracula".to_string(),
+            home_quota: None,
+            home_fid_limits: None,
+            assets: None,
+            colorscheme: None,
+            control_socket: None,
+            metrics_address: None,
+            device_placements: Vec::new(),
+            thread_priorities: Vec::new(),
+            reserved_memory: Vec::new(),
+            rom_images: Vec::new(),
             kernel_path: None,
+            firmware: None,
             init_path: None,
             init_cmd: None,
+            guest_command: None,
             realm_name: None,
+            hostname: None,
+            machine_id: None,
+            timezone: None,
+            rtc_localtime: false,
             raw_disks: Vec::new(),
+            host_disks: Vec::new(),
+            disk_cache: CacheMode::WriteBack,
+            commit_overlays: false,
+            ignore_disk_locks: false,
+            root_device: None,
             realmfs_images: Vec::new(),
             synthetic: None,
-        };
-        config.parse_args();
-        config
+        }
     }
 
     fn default_homedir() -> String {
@@ -70,6 +289,51 @@ impl VmConfig {
         self
     }
 
+    /// Add a disk image, choosing how to open it from its format. Raw images
+    /// are opened read-only or read-write, ISO 9660 images always read-only
+    /// and realmfs images with a memory overlay. Images in other formats are
+    /// rejected.
+    pub fn disk_image<P: AsRef<Path>>(mut self, path: P, read_only: bool) -> Self {
+        if let Err(e) = self.add_disk_image(path.as_ref(), read_only, None) {
+            warn!("Could not add disk: {}", e);
+        }
+        self
+    }
+
+    /// Add a disk image as with `disk_image()`, with a label which the guest
+    /// sees as the serial number of the device.
+    pub fn disk_image_labeled<P: AsRef<Path>>(mut self, path: P, read_only: bool, label: &str) -> Self {
+        if let Err(e) = self.add_disk_image(path.as_ref(), read_only, Some(label)) {
+            warn!("Could not add disk: {}", e);
+        }
+        self
+    }
+
+    fn add_disk_image(&mut self, path: &Path, read_only: bool, label: Option<&str>) -> disk::Result<()> {
+        match disk::probe_supported_format(path)? {
+            DiskFormat::RealmFS => {
+                let mut image = RealmFSImage::new(path, OpenType::MemoryOverlay)?;
+                if let Some(label) = label {
+                    image.set_label(label);
+                }
+                self.realmfs_images.push(image);
+            }
+            format => {
+                let open_type = match format {
+                    DiskFormat::Iso9660 => OpenType::ReadOnly,
+                    _ if read_only => OpenType::ReadOnly,
+                    _ => OpenType::ReadWrite,
+                };
+                let mut image = RawDiskImage::new(path, open_type)?;
+                if let Some(label) = label {
+                    image.set_label(label);
+                }
+                self.raw_disks.push(image);
+            }
+        }
+        Ok(())
+    }
+
     pub fn raw_disk_image<P: Into<PathBuf>>(self, path: P, open_type: OpenType) -> Self {
         self.raw_disk_image_with_offset(path, open_type, 0)
     }
@@ -82,6 +346,61 @@ impl VmConfig {
         self
     }
 
+    /// Add a raw disk image with a label which the guest sees as the serial
+    /// number of the device. ph-init links `/dev/disk/by-id/virtio-LABEL` to
+    /// the device so it can be mounted by name.
+    pub fn raw_disk_image_labeled<P: Into<PathBuf>>(mut self, path: P, open_type: OpenType, label: &str) -> Self {
+        match RawDiskImage::new(path, open_type) {
+            Ok(mut disk) => {
+                disk.set_label(label);
+                self.raw_disks.push(disk);
+            }
+            Err(e) => warn!("Could not add disk: {}", e),
+        };
+        self
+    }
+
+    /// Attach a host block device such as `/dev/mapper/NAME` read-only, so
+    /// that its contents can be inspected from inside the guest. The device is
+    /// attached as a raw disk whatever it contains, after all other disks, and
+    /// is never used as the root filesystem.
+    pub fn host_block_device<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        match RawDiskImage::new_block_device(path) {
+            Ok(disk) => self.host_disks.push(disk),
+            Err(e) => warn!("Could not add disk: {}", e),
+        }
+        self
+    }
+
+    /// Set how writes to read-write disk images are synced to storage. With
+    /// `CacheMode::Unsafe` guest flush requests are ignored.
+    pub fn disk_cache(mut self, cache_mode: CacheMode) -> Self {
+        self.disk_cache = cache_mode;
+        self
+    }
+
+    /// Write the changes made by the guest to disk images attached with a
+    /// memory overlay back to the images when the VM stops.
+    pub fn commit_overlays(mut self, val: bool) -> Self {
+        self.commit_overlays = val;
+        self
+    }
+
+    /// Open disk images even if another VM has locked them. Images opened
+    /// read-write are locked exclusively, and other images with a shared lock,
+    /// so by default an image cannot be written by two VMs at once or read by
+    /// one VM while another writes it.
+    pub fn ignore_disk_locks(mut self, val: bool) -> Self {
+        self.ignore_disk_locks = val;
+        self
+    }
+
+    /// Boot from the selected disk instead of the first one.
+    pub fn root_device(mut self, root_device: RootDevice) -> Self {
+        self.root_device = Some(root_device);
+        self
+    }
+
     pub fn realmfs_image<P: Into<PathBuf>>(mut self, path: P) -> Self {
         match RealmFSImage::new(path, OpenType::MemoryOverlay) {
             Ok(disk) => self.realmfs_images.push(disk),
@@ -90,271 +409,1661 @@ impl VmConfig {
         self
     }
 
+    /// Boot the realm `name` as described by `provider`. The disks, home
+    /// directory, network zone and color scheme of the realm are added to
+    /// this configuration.
+    pub fn realm(mut self, name: &str, provider: &dyn RealmProvider) -> Self {
+        match provider.lookup(name) {
+            Some(info) => self.add_realm(info),
+            None => warn!("Realm {} does not exist", name),
+        }
+        self
+    }
+
     pub fn num_cpus(mut self, ncpus: usize) -> Self {
         self.ncpus = ncpus;
         self
     }
 
-    pub fn init_cmdline(mut self, val: &str) -> Self {
-        self.init_cmd = Some(val.to_owned());
+    /// Create up to `max_cpus` vcpus, of which `num_cpus` are online when the
+    /// guest boots. The others are parked until they are brought online
+    /// with the `cpus` control command.
+    pub fn max_cpus(mut self, max_cpus: usize) -> Self {
+        self.max_cpus = Some(max_cpus);
         self
     }
 
-    pub fn kernel_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
-        self.kernel_path = Some(path.into());
+    /// Limit each vcpu to `percent` of one host CPU by making its thread
+    /// sleep once it has used its share of an accounting period. This works
+    /// without permission to manage cgroups. Values outside 1 to 100 are
+    /// clamped.
+    pub fn cpu_quota_percent(mut self, percent: u32) -> Self {
+        self.cpu_quota = Some(percent.max(1).min(100));
         self
     }
 
-    pub fn init_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
-        self.init_path = Some(path.into());
+    /// Have ph-init run the guest service `name` (such as `sommelier`,
+    /// `dbus-daemon` or `shell`) in a cgroup with these limits, so that a
+    /// runaway application cannot make the whole guest unresponsive.
+    pub fn service_limits(mut self, name: &str, limits: ServiceLimits) -> Self {
+        self.service_limits.push((name.to_string(), limits));
         self
     }
 
-    pub fn synthetic_fs(mut self, sfs: SyntheticFS) -> Self {
-        self.synthetic = Some(sfs);
+    /// Have ph-init start an additional service `name` in the guest.
+    /// `definition` is in the format of the default service files in
+    /// `ph-init/services`, and replaces the default service of the same name.
+    pub fn guest_service(mut self, name: &str, definition: &str) -> Self {
+        self.guest_services.push((name.to_string(), definition.to_string()));
         self
     }
 
-    pub fn boot(self) {
+    /// Limit the size of a tmpfs mounted by ph-init in the guest. `name` is
+    /// `tmp`, `run`, `shm` (for /dev/shm) or `overlay` (holding changes to a
+    /// read-only root filesystem), and `size` is in bytes with an optional
+    /// k, m or g suffix, or a percentage of guest RAM such as `25%`. Without
+    /// a limit each can grow to half of guest RAM.
+    pub fn tmpfs_size(mut self, name: &str, size: &str) -> Self {
+        self.tmpfs_sizes.push((name.to_string(), size.to_string()));
+        self
+    }
 
-        let _terminal_restore = TerminalRestore::save();
+    /// Give the guest compressed swap of `megs` megabytes in a zram device,
+    /// so that memory pressure is relieved by compressing pages rather than
+    /// by the OOM killer.
+    pub fn zram_swap_megs(mut self, megs: u64) -> Self {
+        self.zram_swap = Some(megs * 1024 * 1024);
+        self
+    }
 
-        if let Some(scheme) = Base16Scheme::by_name(&self.colorscheme) {
-            let mut term = AnsiTerminal::new().unwrap();
-            if let Err(err) = term.apply_base16(scheme) {
-                warn!("Failed to set terminal color scheme: {}", err);
-            }
-        }
-        let mut setup = self.setup();
-        let mut vm = match setup.create_vm() {
-            Ok(vm) => vm,
-            Err(err) => {
-                warn!("Failed to create VM: {}", err);
-                return;
-            }
-        };
+    /// Spread guest RAM evenly across the listed host NUMA nodes, binding each
+    /// part to its node. By default memory is allocated with the host policy.
+    pub fn numa_nodes(mut self, nodes: &[u32]) -> Self {
+        self.numa_nodes = nodes.to_vec();
+        self
+    }
 
-        if let Err(err) = vm.start() {
-            warn!("Failed to start VM: {}", err);
-        }
+    pub fn init_cmdline(mut self, val: &str) -> Self {
+        self.init_cmd = Some(val.to_owned());
+        self
     }
 
-    pub fn setup(self) -> VmSetup<X86ArchSetup> {
-        let arch_setup = arch::create_setup(&self);
-        VmSetup::new(self, arch_setup)
+    /// Have ph-init run the script at `path` in the guest instead of an
+    /// interactive shell and shut the VM down when it exits.
+    pub fn guest_command(mut self, path: &str) -> Self {
+        self.guest_command = Some(path.to_owned());
+        self
     }
 
-    pub fn ram_size(&self) -> usize {
-        self.ram_size
+    /// Run the console shell (or the guest command) as root
+    pub fn root_shell(mut self, val: bool) -> Self {
+        self.rootshell = val;
+        self
     }
 
-    pub fn ncpus(&self) -> usize {
-        self.ncpus
+    /// Directory shared with the guest as the home directory
+    pub fn home_dir<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.home = path.as_ref().display().to_string();
+        self
     }
 
-    pub fn verbose(&self) -> bool {
-        self.verbose
+    /// Limit the space and number of files the guest may use in the shared
+    /// home directory. Writes beyond the limits fail with `EDQUOT`.
+    pub fn home_quota(mut self, limits: QuotaLimits) -> Self {
+        self.home_quota = Some(limits);
+        self
     }
 
-    pub fn rootshell(&self) -> bool {
-        self.rootshell
+    /// Replace the default limits on the fids and open files the guest may
+    /// hold on the shared home directory, and optionally expire idle fids.
+    pub fn home_fid_limits(mut self, limits: FidLimits) -> Self {
+        self.home_fid_limits = Some(limits);
+        self
     }
 
-    pub fn network(&self) -> bool {
-        if unsafe { libc::geteuid() } != 0 {
-            false
-        } else {
-            self.network
+    /// Directory shared read-only with the guest as the assets volume. Its
+    /// `fonts` are added to the font path together with the caches in
+    /// `fontconfig`, and `icons` is mounted on /usr/local/share/icons. Use
+    /// `warm_asset_caches()` to build the caches.
+    pub fn assets_dir<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.assets = Some(path.as_ref().display().to_string());
+        self
+    }
+
+    /// Add the wayland device. It is added by default when pH is built with
+    /// the `wayland` feature and cannot be enabled without it.
+    pub fn enable_wayland(mut self, val: bool) -> Self {
+        self.wayland = val && cfg!(feature = "wayland");
+        if !self.wayland {
+            self.dmabuf = false;
         }
+        self
     }
 
-    pub fn homedir(&self) -> &str {
-        &self.home
+    /// Add the audio device. It is added by default when pH is built with
+    /// the `audio` feature and cannot be enabled without it.
+    pub fn enable_audio(mut self, val: bool) -> Self {
+        self.audio = val && cfg!(feature = "audio");
+        self
     }
 
-    pub fn has_block_image(&self) -> bool {
-        !(self.realmfs_images.is_empty() && self.raw_disks.is_empty())
+    /// Add a network device. It is added by default when pH is built with
+    /// the `network` feature and cannot be enabled without it.
+    pub fn enable_network(mut self, val: bool) -> Self {
+        self.network = val && cfg!(feature = "network");
+        self
     }
 
-    pub fn get_realmfs_images(&mut self) -> Vec<RealmFSImage> {
-        self.realmfs_images.drain(..).collect()
+    pub fn kernel_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.kernel_path = Some(path.into());
+        self
     }
 
-    pub fn get_raw_disk_images(&mut self) -> Vec<RawDiskImage> {
-        self.raw_disks.drain(..).collect()
+    /// Boot the firmware image at `path`, such as SeaBIOS or OVMF, instead of
+    /// the embedded kernel. The firmware is mapped read-only below 4GB so that
+    /// the vcpus start executing it from the reset vector.
+    pub fn firmware<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.firmware = Some(path.into());
+        self
     }
 
-    pub fn get_synthetic_fs(&self) -> Option<SyntheticFS> {
-        self.synthetic.clone()
+    pub fn init_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.init_path = Some(path.into());
+        self
     }
 
-    pub fn get_init_cmdline(&self) -> Option<&str> {
-        self.init_cmd.as_ref().map(|s| s.as_str())
+    /// Limit the number of vfds (shared memory buffers, pipes and compositor
+    /// connections) the guest may have open on the wayland device. A warning
+    /// is logged when 80% of the limit is in use.
+    pub fn wl_max_vfds(mut self, max: usize) -> Self {
+        self.wl_max_vfds = Some(max);
+        self
     }
 
-    pub fn realm_name(&self) -> Option<&str> {
-        self.realm_name.as_ref().map(|s| s.as_str())
+    /// Connect the wayland device to the compositor socket at `path` instead
+    /// of the socket named by `WAYLAND_DISPLAY` in `XDG_RUNTIME_DIR`. A realm
+    /// manager running as another user can use this to give each VM a socket
+    /// the owner of the compositor has granted it access to.
+    pub fn wayland_socket<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.wayland_socket = Some(path.into());
+        self
     }
 
-    pub fn is_realm(&self) -> bool {
-        self.realm_name.is_some()
+    /// Connect the wayland device to the compositor socket at `path` when
+    /// the guest asks for the socket `name`, as newer guests do for each
+    /// socket sommelier is configured with. The default socket `wayland-0`
+    /// is the one set with `wayland_socket()` and cannot be given here.
+    pub fn wayland_named_socket<P: Into<PathBuf>>(mut self, name: &str, path: P) -> Self {
+        self.wayland_named_sockets.push((name.to_string(), path.into()));
+        self
     }
 
-    pub fn is_wayland_enabled(&self) -> bool {
-        if !self.wayland {
-            return false;
-        }
-        let display = env::var("WAYLAND_DISPLAY").unwrap_or("wayland-0".to_string());
-        let xdg_runtime = env::var("XDG_RUNTIME_DIR").unwrap_or("/run/user/1000".to_string());
+    /// Have the privileged helper `command` (such as
+    /// `/usr/libexec/ph-net-helper` installed setuid root, or
+    /// `pkexec /usr/libexec/ph-net-helper`) create the tap device and pass it
+    /// to pH, so that networking works without running pH as root.
+    pub fn net_helper(mut self, command: &str) -> Self {
+        self.net_helper = Some(command.to_string());
+        self
+    }
 
-        let socket= Path::new(xdg_runtime.as_str()).join(display);
-        socket.exists()
+    /// Attach to an existing tap device instead of creating a new one. The
+    /// tap device is expected to already be configured and added to a bridge,
+    /// which allows networking to be used without running as root.
+    pub fn tap_device(mut self, name: &str) -> Self {
+        self.tap_name = Some(name.to_string());
+        self
     }
 
-    pub fn is_dmabuf_enabled(&self) -> bool {
-        self.dmabuf
+    /// The tap device passed to `tap_device()` was created with the
+    /// multi-queue flag (`ip tuntap add ... multi_queue`). Only one queue is
+    /// used, but the device cannot be attached to without requesting it.
+    pub fn tap_multi_queue(mut self, enabled: bool) -> Self {
+        self.tap_multi_queue = enabled;
+        self
     }
 
-    pub fn is_audio_enable(&self) -> bool {
-        self.audio
+    /// Use the character device of an existing macvtap interface for
+    /// networking instead of a tap device added to a bridge.
+    pub fn macvtap_device(mut self, name: &str) -> Self {
+        self.macvtap_name = Some(name.to_string());
+        self
     }
 
-    pub fn bridge(&self) -> &str {
-        &self.bridge_name
+    /// Write all network traffic of the guest to a pcapng file at `path`.
+    pub fn net_capture<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.net_capture = Some(path.into());
+        self
     }
 
-    fn add_realmfs_by_name(&mut self, realmfs: &str) {
-        let path = Path::new("/realms/realmfs-images")
-            .join(format!("{}-realmfs.img", realmfs));
-        if !path.exists() {
-            eprintln!("Realmfs image does not exist at {}", path.display());
-            process::exit(1);
-        }
-        match RealmFSImage::new(path, OpenType::MemoryOverlay) {
-            Ok(disk) => self.realmfs_images.push(disk),
-            Err(e) => {
-                warn!("Could not add disk: {}", e);
-                process::exit(1);
-            },
-        };
+    /// Rotate network capture files when they reach `megs` megabytes.
+    pub fn net_capture_size_megs(mut self, megs: u64) -> Self {
+        self.net_capture_size = Some(megs * 1024 * 1024);
+        self
     }
 
-    fn add_realm_by_name(&mut self, realm: &str) {
-        let realms = Realms::load().unwrap();
-        if let Some(realm) = realms.by_name(realm) {
-            let config = realm.config();
-            let realmfs = config.realmfs();
-            self.add_realmfs_by_name(realmfs);
-            self.home = realm.base_path().join("home").display().to_string();
-            self.realm_name = Some(realm.name().to_string());
-            self.bridge_name = format!("vz-{}", config.network_zone());
-            if let Some(scheme) = config.terminal_scheme() {
-                self.colorscheme = scheme.to_string();
-            }
-        }
+    /// Check the IPv4, TCP and UDP checksums of frames the guest transmits
+    /// without checksum offload and count the frames where they are wrong.
+    pub fn net_check_checksums(mut self, val: bool) -> Self {
+        self.net_check_checksums = val;
+        self
     }
 
-    fn parse_args(&mut self) {
-        let args = ProgramArgs::new();
-        if args.has_arg("-v") {
-            self.verbose = true;
-        }
-        if args.has_arg("--root") {
-            self.rootshell = true;
-        }
-        if args.has_arg("--no-wayland") {
-            self.wayland = false;
-            self.dmabuf = false;
-        }
-        if args.has_arg("--use-dmabuf") {
-            self.dmabuf = true;
-        }
-        if args.has_arg("--no-network") {
-            self.network = false;
-        }
-        if let Some(home) = args.arg_with_value("--home") {
-            self.home = home.to_string();
-        }
-        if let Some(realmfs) = args.arg_with_value("--realmfs") {
-            self.add_realmfs_by_name(realmfs);
-        }
-        if let Some(realm) = args.arg_with_value("--realm") {
-            self.add_realm_by_name(realm);
-        }
+    /// Limit the rate at which the guest can transmit network traffic.
+    #[cfg(feature = "network")]
+    pub fn net_tx_limit(mut self, limit: NetRateLimit) -> Self {
+        self.net_tx_limit = Some(limit);
+        self
     }
-}
 
-struct ProgramArgs {
-    args: Vec<String>,
-}
+    /// Set the MTU of the host interface used for networking and of `eth0`
+    /// in the guest. Changing the MTU of an existing tap or macvtap
+    /// interface requires CAP_NET_ADMIN.
+    pub fn net_mtu(mut self, mtu: u32) -> Self {
+        self.net_mtu = Some(mtu);
+        self
+    }
 
-impl ProgramArgs {
-    fn new() -> Self {
-        ProgramArgs {
-            args: env::args().skip(1).collect(),
-        }
+    /// Set the IPv4 address of the guest. The network is a /24 with the
+    /// gateway at .1. Without an address the guest uses 172.17.0.22 on the
+    /// bridge network, and on a macvtap interface has no address configured
+    /// by ph-init.
+    pub fn guest_ip(mut self, address: Ipv4Addr) -> Self {
+        self.guest_ip = Some(address);
+        self
     }
 
-    fn has_arg(&self, name: &str) -> bool {
-        self.args.iter().any(|arg| arg.as_str() == name)
+    /// Configure the guest address on the 802.1Q VLAN `id` on top of `eth0`,
+    /// for tap devices on a VLAN aware bridge or trunk port.
+    pub fn guest_vlan(mut self, id: u16) -> Self {
+        self.guest_vlan = Some(id);
+        self
     }
 
-    fn arg_with_value(&self, name: &str) -> Option<&str> {
-        let mut iter = self.args.iter();
-        while let Some(arg) = iter.next() {
-            if arg.as_str() == name {
-                match iter.next() {
-                    Some(val) => return Some(val.as_str()),
-                    None => {
-                        eprintln!("Expected value for {} argument", name);
-                        process::exit(1);
-                    }
-                }
-            }
-        }
-        None
+    /// Add a nameserver to the guest resolv.conf. Once any are set they
+    /// replace the nameservers of the host resolv.conf, which is otherwise
+    /// followed by the guest as it changes.
+    pub fn dns_server(mut self, address: IpAddr) -> Self {
+        self.dns_servers.push(address);
+        self
     }
-}
 
-pub struct TerminalRestore {
-    saved: Option<TerminalPalette>,
-}
+    /// Add a domain to the search list of the guest resolv.conf, replacing
+    /// the search list of the host.
+    pub fn dns_search(mut self, domain: &str) -> Self {
+        self.dns_search.push(domain.to_string());
+        self
+    }
 
-impl TerminalRestore {
-    pub fn save() -> Self {
-        let mut term = match AnsiTerminal::new() {
-            Ok(term) => term,
-            Err(e) => {
-                warn!("failed to open terminal: {}", e);
-                return TerminalRestore { saved: None }
-            }
-        };
+    /// Create a unix socket at `path` which accepts commands for querying
+    /// the running VM.
+    pub fn control_socket<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.control_socket = Some(path.into());
+        self
+    }
 
-        let mut palette = TerminalPalette::default();
-        if let Err(e) = palette.load(&mut term) {
-            warn!("failed to load palette: {}", e);
-            return TerminalRestore { saved: None }
-        }
-        if let Err(e) = term.clear_screen() {
-            warn!("failed to clear screen: {}", e);
-            return TerminalRestore { saved: None }
-        }
-        TerminalRestore { saved: Some(palette) }
+    /// Serve device statistics in Prometheus text format on `address` which
+    /// is either `HOST:PORT` or `unix:PATH`.
+    pub fn metrics_listen(mut self, address: &str) -> Self {
+        self.metrics_address = Some(address.to_string());
+        self
     }
 
-    fn restore(&self) {
-        if let Some(p) = self.saved.as_ref() {
-            let mut term = match AnsiTerminal::new() {
-                Ok(term) => term,
-                _ => return,
-            };
-            let _ = p.apply(&mut term);
-        }
+    /// Assign virtio device `name` to a fixed PCI slot and/or IRQ rather than
+    /// the next free one, so that guest device names such as `enp0s4` remain
+    /// stable when the device configuration changes.
+    ///
+    /// Device names are the virtio device type (`console`, `rng`, `wl`, `9p`,
+    /// `block`, `net`) with `-N` appended for the second and later devices of
+    /// the same type, in the order the devices are created.
+    pub fn device_placement(mut self, name: &str, slot: Option<u8>, irq: Option<u8>) -> Self {
+        self.device_placements.push((name.to_string(), DevicePlacement::new(slot, irq)));
+        self
+    }
+
+    /// Schedule the worker threads of device `name` with `priority` so that
+    /// a busy device does not starve another. Devices are named as for
+    /// `device_placement()`, and `audio` names the audio stream threads.
+    pub fn thread_priority(mut self, name: &str, priority: ThreadPriority) -> Self {
+        self.thread_priorities.push((name.to_string(), priority));
+        self
     }
-}
 
-impl Drop for TerminalRestore {
-    fn drop(&mut self) {
-        self.restore();
+    /// Reserve `size` bytes of guest physical address space at `base` and mark
+    /// it as reserved in the memory map passed to the guest kernel, for
+    /// example to hold a shared memory device. The range must be page aligned
+    /// and is checked against RAM and the ranges used by pH when the VM is
+    /// created.
+    pub fn reserve_memory(mut self, name: &str, base: u64, size: usize) -> Self {
+        self.reserved_memory.push((name.to_string(), base, size));
+        self
+    }
+
+    /// Map the contents of the file at `path` read-only into guest physical
+    /// memory at `base`, for example an option ROM, a firmware table or a
+    /// test payload. The range may not overlap RAM or any range used by pH
+    /// and is marked as reserved in the memory map passed to the guest kernel.
+    pub fn rom_image<P: Into<PathBuf>>(mut self, name: &str, path: P, base: u64, mapping: RomMapping) -> Self {
+        self.rom_images.push((name.to_string(), path.into(), base, mapping));
+        self
+    }
+
+    /// Request `target_ms` milliseconds of playback buffering from the audio
+    /// server and have it ask for at least `min_request_ms` milliseconds of
+    /// audio at a time. Lower values reduce latency but make underruns more
+    /// likely. `None` leaves the value to the audio server.
+    pub fn audio_latency(mut self, target_ms: Option<u32>, min_request_ms: Option<u32>) -> Self {
+        self.audio_latency = AudioLatency { target_ms, min_request_ms };
+        self
+    }
+
+    /// Ask the audio server to process guest audio streams with `effect`,
+    /// for example echo cancellation for voice calls.
+    pub fn audio_effect(mut self, effect: StreamEffect) -> Self {
+        if !self.audio_effects.contains(&effect) {
+            self.audio_effects.push(effect);
+        }
+        self
+    }
+
+    /// Choose whether Ctrl-C on the console is always sent to the guest or
+    /// whether three in a row restore the host terminal so that pH can be
+    /// interrupted.
+    pub fn ctrl_c_policy(mut self, policy: CtrlCPolicy) -> Self {
+        self.console.ctrl_c = policy;
+        self
+    }
+
+    /// Set a console escape character. The escape character followed by `.`
+    /// exits pH, followed by `d` detaches from the console.
+    pub fn console_escape(mut self, escape: Option<u8>) -> Self {
+        self.console.escape = escape;
+        self
+    }
+
+    /// Only recognize the console escape character as the first character
+    /// of a line so that it can also be typed as part of other input.
+    pub fn console_escape_at_line_start(mut self, val: bool) -> Self {
+        self.console.escape_at_line_start = val;
+        self
+    }
+
+    /// Emulate the IOAPIC in userspace and leave only the local APICs to
+    /// KVM. There is no PIC or PIT in this mode.
+    pub fn split_irqchip(mut self, val: bool) -> Self {
+        self.split_irqchip = val;
+        self
+    }
+
+    /// Only let virtio devices access buffers in the RAM described by the
+    /// memory map passed to the guest kernel, and never their own virtqueue
+    /// rings. A device given any other buffer asks the driver to reset it.
+    /// Has no effect when booting firmware, which builds its own memory map.
+    pub fn strict_dma(mut self, val: bool) -> Self {
+        self.strict_dma = val;
+        self
+    }
+
+    /// Log every open file descriptor without close-on-exec, and what
+    /// created it, once the VM is set up and before starting a helper
+    /// program such as the network helper.
+    pub fn audit_fds(mut self, val: bool) -> Self {
+        self.audit_fds = val;
+        self
+    }
+
+    /// Give the guest a virtual PMU so that `perf` and other profiling tools
+    /// can use the hardware performance counters. By default the counters are
+    /// hidden from the guest.
+    pub fn pmu(mut self, val: bool) -> Self {
+        self.pmu = val;
+        self
+    }
+
+    /// Set how guest accesses to MSRs which KVM does not implement are
+    /// handled. The default `MsrPolicy::Fault` injects a #GP fault.
+    pub fn msr_policy(mut self, policy: MsrPolicy) -> Self {
+        self.msr_policy = policy;
+        self
+    }
+
+    /// Set the terminal to a base16 color scheme while the VM runs. Realms
+    /// use the scheme from their configuration. Without the `terminal-theme`
+    /// feature this has no effect.
+    pub fn color_scheme(mut self, name: &str) -> Self {
+        self.colorscheme = Some(name.to_string());
+        self
+    }
+
+    /// Name the guest uses for itself in `/etc/hosts`, the shell prompt and
+    /// its `.Xauthority` entry. Realms default to the realm name and other
+    /// VMs to `airwolf`.
+    pub fn hostname(mut self, name: &str) -> Self {
+        self.hostname = Some(name.to_string());
+        self
+    }
+
+    /// Set `/etc/machine-id` in the guest to `id`, which is 32 lowercase hex
+    /// digits. By default the id is derived from the hostname, so a realm
+    /// keeps the same id every time it boots.
+    pub fn machine_id(mut self, id: &str) -> Self {
+        self.machine_id = Some(id.to_string());
+        self
+    }
+
+    /// Timezone of the guest as a name from the tz database such as
+    /// `Europe/Berlin`, ignored if it is not a valid name. By default the
+    /// guest uses the timezone of the host from `/etc/localtime`.
+    pub fn timezone(mut self, name: &str) -> Self {
+        self.timezone = Some(name.to_string());
+        self
+    }
+
+    /// Have the CMOS clock return local time of the host rather than UTC,
+    /// as expected by firmware and some guest operating systems.
+    pub fn rtc_localtime(mut self, val: bool) -> Self {
+        self.rtc_localtime = val;
+        self
+    }
+
+    pub fn synthetic_fs(mut self, sfs: SyntheticFS) -> Self {
+        self.synthetic = Some(sfs);
+        self
+    }
+
+    /// Boot the VM and block until it stops. SIGTERM, SIGINT and SIGHUP
+    /// stop the VM.
+    pub fn boot(self) -> VmExitReason {
+
+        panic_hook::install();
+        let exit_on_device_panic = self.exit_on_device_panic;
+        let _terminal_theme = self.colorscheme.as_deref().map(TerminalTheme::apply);
+
+        let mut handle = match self.start() {
+            Ok(handle) => handle,
+            Err(err) => {
+                warn!("Failed to create VM: {}", err);
+                return VmExitReason::HostError(err.to_string());
+            }
+        };
+
+        panic_hook::watch_vm(handle.control().clone(), exit_on_device_panic);
+        if let Err(err) = handle.exit_on_signals(&[libc::SIGTERM, libc::SIGINT, libc::SIGHUP]) {
+            warn!("Failed to register signal handlers: {}", err);
+        }
+        handle.wait()
+    }
+
+    /// Create the VM and start running it in the background. The returned
+    /// handle controls the VM and reports changes in its state.
+    pub fn start(self) -> crate::vm::Result<VmHandle> {
+        let mut setup = self.setup();
+        let vm = setup.create_vm()?;
+        Ok(vm.start())
+    }
+
+    pub fn setup(self) -> VmSetup<X86ArchSetup> {
+        let arch_setup = arch::create_setup(&self);
+        VmSetup::new(self, arch_setup)
+    }
+
+    pub fn ram_size(&self) -> usize {
+        self.ram_size
+    }
+
+    pub fn ncpus(&self) -> usize {
+        self.ncpus
+    }
+
+    pub fn get_cpu_quota_percent(&self) -> Option<u32> {
+        self.cpu_quota
+    }
+
+    pub fn get_max_cpus(&self) -> usize {
+        self.max_cpus.unwrap_or(0).max(self.ncpus)
+    }
+
+    pub fn get_service_limits(&self) -> &[(String, ServiceLimits)] {
+        &self.service_limits
+    }
+
+    pub fn get_guest_services(&self) -> &[(String, String)] {
+        &self.guest_services
+    }
+
+    pub fn get_tmpfs_sizes(&self) -> &[(String, String)] {
+        &self.tmpfs_sizes
+    }
+
+    pub fn get_zram_swap(&self) -> Option<u64> {
+        self.zram_swap
+    }
+
+    pub fn get_numa_nodes(&self) -> &[u32] {
+        &self.numa_nodes
+    }
+
+    pub fn verbose(&self) -> bool {
+        self.verbose
+    }
+
+    pub fn rootshell(&self) -> bool {
+        self.rootshell
+    }
+
+    pub fn network(&self) -> bool {
+        self.network
+    }
+
+    /// Whether a new tap device is created for the guest rather than using
+    /// an existing tap or macvtap interface
+    pub fn creates_tap(&self) -> bool {
+        self.tap_name.is_none() && self.macvtap_name.is_none()
+    }
+
+    pub fn homedir(&self) -> &str {
+        &self.home
+    }
+
+    pub fn get_home_quota(&self) -> Option<QuotaLimits> {
+        self.home_quota
+    }
+
+    pub fn get_home_fid_limits(&self) -> Option<FidLimits> {
+        self.home_fid_limits
+    }
+
+    pub fn get_assets_dir(&self) -> Option<&str> {
+        self.assets.as_deref()
+    }
+
+    pub fn has_block_image(&self) -> bool {
+        !(self.realmfs_images.is_empty() && self.raw_disks.is_empty())
+    }
+
+    pub fn get_root_device(&self) -> Option<&RootDevice> {
+        self.root_device.as_ref()
+    }
+
+    pub fn get_realmfs_images(&mut self) -> Vec<RealmFSImage> {
+        let commit = self.commit_overlays;
+        let ignore_lock = self.ignore_disk_locks;
+        self.realmfs_images.drain(..)
+            .map(|mut disk| { disk.set_commit_overlay(commit); disk.set_ignore_lock(ignore_lock); disk })
+            .collect()
+    }
+
+    pub fn get_raw_disk_images(&mut self) -> Vec<RawDiskImage> {
+        let cache_mode = self.disk_cache;
+        let commit = self.commit_overlays;
+        let ignore_lock = self.ignore_disk_locks;
+        self.raw_disks.drain(..)
+            .map(|mut disk| {
+                disk.set_cache_mode(cache_mode);
+                disk.set_commit_overlay(commit);
+                disk.set_ignore_lock(ignore_lock);
+                disk
+            })
+            .collect()
+    }
+
+    pub fn get_host_block_devices(&mut self) -> Vec<RawDiskImage> {
+        let cache_mode = self.disk_cache;
+        let ignore_lock = self.ignore_disk_locks;
+        self.host_disks.drain(..)
+            .map(|mut disk| {
+                disk.set_cache_mode(cache_mode);
+                disk.set_ignore_lock(ignore_lock);
+                disk
+            })
+            .collect()
+    }
+
+    pub fn get_synthetic_fs(&self) -> Option<SyntheticFS> {
+        self.synthetic.clone()
+    }
+
+    pub fn get_guest_command(&self) -> Option<&str> {
+        self.guest_command.as_deref()
+    }
+
+    pub fn get_init_cmdline(&self) -> Option<&str> {
+        self.init_cmd.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn realm_name(&self) -> Option<&str> {
+        self.realm_name.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn is_realm(&self) -> bool {
+        self.realm_name.is_some()
+    }
+
+    pub fn get_hostname(&self) -> String {
+        let name = self.hostname.as_deref()
+            .or(self.realm_name.as_deref())
+            .unwrap_or(DEFAULT_HOSTNAME);
+        sanitize_hostname(name)
+    }
+
+    pub fn get_machine_id(&self) -> String {
+        self.machine_id.clone()
+            .unwrap_or_else(|| derive_machine_id(&self.get_hostname()))
+    }
+
+    /// The timezone set with `timezone()`, or else the timezone of the
+    /// host if it can be found.
+    pub fn get_timezone(&self) -> Option<String> {
+        self.timezone.clone()
+            .filter(|tz| is_timezone_name(tz))
+            .or_else(host_timezone)
+    }
+
+    pub fn is_rtc_localtime(&self) -> bool {
+        self.rtc_localtime
+    }
+
+    /// Whether the wayland device was asked for, whether or not the
+    /// compositor socket exists
+    pub fn is_wayland_requested(&self) -> bool {
+        self.wayland
+    }
+
+    pub fn is_wayland_enabled(&self) -> bool {
+        if !self.wayland {
+            return false;
+        }
+        let socket = self.get_wayland_socket();
+        if !socket.exists() {
+            if self.wayland_socket.is_some() {
+                warn!("Wayland socket {} does not exist, disabling wayland", socket.display());
+            }
+            return false;
+        }
+        true
+    }
+
+    pub fn get_wayland_named_sockets(&self) -> &[(String, PathBuf)] {
+        &self.wayland_named_sockets
+    }
+
+    /// Compositor socket the wayland device connects to, either the socket
+    /// set with `wayland_socket()` or the one found the way a wayland client
+    /// started from the environment of pH would find it.
+    pub fn get_wayland_socket(&self) -> PathBuf {
+        if let Some(path) = &self.wayland_socket {
+            return path.clone();
+        }
+        let display = env::var("WAYLAND_DISPLAY").unwrap_or_else(|_| "wayland-0".to_string());
+        // libwayland-client also accepts an absolute path
+        if display.starts_with('/') {
+            return PathBuf::from(display);
+        }
+        let xdg_runtime = env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| Self::default_runtime_dir());
+        Path::new(&xdg_runtime).join(display)
+    }
+
+    // Without a session environment pH started as root uses the session of
+    // uid 1000, the user it drops privileges to
+    fn default_runtime_dir() -> String {
+        let uid = match unsafe { libc::getuid() } {
+            0 => 1000,
+            uid => uid,
+        };
+        format!("/run/user/{}", uid)
+    }
+
+    pub fn is_dmabuf_enabled(&self) -> bool {
+        self.dmabuf
+    }
+
+    pub fn get_wl_max_vfds(&self) -> Option<usize> {
+        self.wl_max_vfds
+    }
+
+    pub fn is_audio_enable(&self) -> bool {
+        self.audio
+    }
+
+    pub fn get_audio_latency(&self) -> AudioLatency {
+        self.audio_latency
+    }
+
+    pub fn get_audio_effects(&self) -> &[StreamEffect] {
+        &self.audio_effects
+    }
+
+    pub fn console_options(&self) -> ConsoleOptions {
+        self.console
+    }
+
+    pub fn is_split_irqchip(&self) -> bool {
+        self.split_irqchip
+    }
+
+    /// Stop the VM when a device thread panics instead of leaving the device
+    /// without a worker while the guest keeps running. Panics on vcpu threads
+    /// always stop the VM. Only used by `boot()`, which installs the panic
+    /// hook.
+    pub fn exit_on_device_panic(mut self, val: bool) -> Self {
+        self.exit_on_device_panic = val;
+        self
+    }
+
+    pub fn is_strict_dma(&self) -> bool {
+        self.strict_dma
+    }
+
+    pub fn is_audit_fds(&self) -> bool {
+        self.audit_fds
+    }
+
+    pub fn is_pmu_enabled(&self) -> bool {
+        self.pmu
+    }
+
+    pub fn get_msr_policy(&self) -> MsrPolicy {
+        self.msr_policy
+    }
+
+    pub fn bridge(&self) -> &str {
+        &self.bridge_name
+    }
+
+    pub fn get_net_helper(&self) -> Option<&str> {
+        self.net_helper.as_deref()
+    }
+
+    pub fn get_dns_servers(&self) -> &[IpAddr] {
+        &self.dns_servers
+    }
+
+    pub fn get_dns_search(&self) -> &[String] {
+        &self.dns_search
+    }
+
+    pub fn tap_name(&self) -> Option<&str> {
+        self.tap_name.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn get_tap_multi_queue(&self) -> bool {
+        self.tap_multi_queue
+    }
+
+    pub fn macvtap_name(&self) -> Option<&str> {
+        self.macvtap_name.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn get_control_socket(&self) -> Option<&Path> {
+        self.control_socket.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn get_net_capture(&self) -> Option<&Path> {
+        self.net_capture.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn get_net_capture_size(&self) -> Option<u64> {
+        self.net_capture_size
+    }
+
+    pub fn is_net_check_checksums(&self) -> bool {
+        self.net_check_checksums
+    }
+
+    #[cfg(feature = "network")]
+    pub fn get_net_tx_limit(&self) -> Option<NetRateLimit> {
+        self.net_tx_limit
+    }
+
+    pub fn get_net_mtu(&self) -> Option<u32> {
+        self.net_mtu
+    }
+
+    pub fn get_guest_vlan(&self) -> Option<u16> {
+        self.guest_vlan
+    }
+
+    pub fn get_guest_ip(&self) -> Option<Ipv4Addr> {
+        self.guest_ip
+    }
+
+    pub fn get_metrics_address(&self) -> Option<&str> {
+        self.metrics_address.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn device_placements(&self) -> &[(String, DevicePlacement)] {
+        &self.device_placements
+    }
+
+    pub fn thread_priorities(&self) -> &[(String, ThreadPriority)] {
+        &self.thread_priorities
+    }
+
+    /// The priority set last for device `name`
+    pub fn get_thread_priority(&self, name: &str) -> Option<ThreadPriority> {
+        self.thread_priorities.iter().rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, p)| *p)
+    }
+
+    pub fn reserved_memory(&self) -> &[(String, u64, usize)] {
+        &self.reserved_memory
+    }
+
+    pub fn rom_images(&self) -> &[(String, PathBuf, u64, RomMapping)] {
+        &self.rom_images
+    }
+
+    pub fn get_firmware(&self) -> Option<&Path> {
+        self.firmware.as_deref()
+    }
+
+    // A decimal or 0x prefixed hexadecimal number with an optional K, M or G suffix
+    fn parse_address(s: &str) -> Option<u64> {
+        let (s, shift) = match s.chars().last()? {
+            'K' | 'k' => (&s[..s.len() - 1], 10),
+            'M' | 'm' => (&s[..s.len() - 1], 20),
+            'G' | 'g' => (&s[..s.len() - 1], 30),
+            _ => (s, 0),
+        };
+        let n = match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+            None => s.parse().ok()?,
+        };
+        n.checked_mul(1 << shift)
+    }
+
+    fn add_memory_reservation(&mut self, arg: &str) {
+        let reservation = arg.split_once('=')
+            .and_then(|(name, range)| range.split_once(':')
+                .map(|(base, size)| (name, base, size)))
+            .and_then(|(name, base, size)| Some((name.to_string(), Self::parse_address(base)?, Self::parse_address(size)? as usize)));
+        match reservation {
+            Some(reservation) => self.reserved_memory.push(reservation),
+            None => {
+                eprintln!("Invalid --reserve-memory argument '{}', expected NAME=BASE:SIZE", arg);
+                process::exit(1);
+            }
+        }
+    }
+
+    fn add_rom_image(&mut self, option: &str, arg: &str, mapping: RomMapping) {
+        let rom = arg.split_once('=')
+            .and_then(|(name, rom)| rom.split_once(':')
+                .map(|(base, path)| (name, base, path)))
+            .filter(|(name, _, path)| !name.is_empty() && !path.is_empty())
+            .and_then(|(name, base, path)| Some((name.to_string(), PathBuf::from(path), Self::parse_address(base)?, mapping)));
+        match rom {
+            Some(rom) => self.rom_images.push(rom),
+            None => {
+                eprintln!("Invalid {} argument '{}', expected NAME=BASE:PATH", option, arg);
+                process::exit(1);
+            }
+        }
+    }
+
+    fn add_device_placement(&mut self, arg: &str) {
+        let placement = arg.split_once('=')
+            .and_then(|(name, placement)| DevicePlacement::parse(placement)
+                .map(|p| (name.to_string(), p)));
+        match placement {
+            Some(placement) => self.device_placements.push(placement),
+            None => {
+                eprintln!("Invalid --pci-slot argument '{}', expected NAME=SLOT[:IRQ]", arg);
+                process::exit(1);
+            }
+        }
+    }
+
+    fn add_thread_nice(&mut self, arg: &str) {
+        let priority = arg.split_once('=')
+            .filter(|(name, _)| !name.is_empty())
+            .and_then(|(name, nice)| ThreadPriority::parse_nice(nice)
+                .map(|p| (name.to_string(), p)));
+        match priority {
+            Some(priority) => self.thread_priorities.push(priority),
+            None => {
+                eprintln!("Invalid --thread-nice argument '{}', expected NAME=NICE with NICE from -20 to 19", arg);
+                process::exit(1);
+            }
+        }
+    }
+
+    fn add_service_limits(&mut self, arg: &str) {
+        let limits = arg.split_once(':')
+            .and_then(|(name, limits)| ServiceLimits::parse(limits)
+                .map(|l| (name.to_string(), l)));
+        match limits {
+            Some(limits) => self.service_limits.push(limits),
+            None => {
+                eprintln!("Invalid --service-limit argument '{}', expected NAME:memory=SIZE,cpu=PERCENT", arg);
+                process::exit(1);
+            }
+        }
+    }
+
+    fn add_service_file(&mut self, arg: &str) {
+        let path = Path::new(arg);
+        let name = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(name) => name.to_string(),
+            None => {
+                eprintln!("Invalid --service-file argument '{}', expected a path to NAME.toml", arg);
+                process::exit(1);
+            }
+        };
+        match fs::read_to_string(path) {
+            Ok(definition) => self.guest_services.push((name, definition)),
+            Err(e) => {
+                eprintln!("Could not read service file {}: {}", arg, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    fn add_wayland_named_socket(&mut self, arg: &str) {
+        // The guest sends the name in a 32 byte field
+        match arg.split_once('=') {
+            Some((name, path)) if !name.is_empty() && name.len() <= 32 && name != "wayland-0" && !path.is_empty() => {
+                self.wayland_named_sockets.push((name.to_string(), PathBuf::from(path)));
+            }
+            _ => {
+                eprintln!("Invalid --wayland-named-socket argument '{}', expected NAME=PATH with NAME other than wayland-0 and at most 32 bytes", arg);
+                process::exit(1);
+            }
+        }
+    }
+
+    fn add_tmpfs_size(&mut self, arg: &str) {
+        fn valid_size(size: &str) -> bool {
+            let digits = size.trim_end_matches(|c| "kKmMgG%".contains(c));
+            !digits.is_empty() && size.len() - digits.len() <= 1 && digits.chars().all(|c| c.is_ascii_digit())
+        }
+        match arg.split_once('=') {
+            Some((name, size)) if GUEST_TMPFS_NAMES.contains(&name) && valid_size(size) => {
+                self.tmpfs_sizes.push((name.to_string(), size.to_string()));
+            }
+            _ => {
+                eprintln!("Invalid --tmpfs-size argument '{}', expected NAME=SIZE with NAME one of {}", arg, GUEST_TMPFS_NAMES.join(", "));
+                process::exit(1);
+            }
+        }
+    }
+
+    fn set_numa_nodes(&mut self, arg: &str) {
+        let nodes: Result<Vec<u32>, _> = arg.split(',')
+            .map(|n| n.trim().parse::<u32>())
+            .collect();
+        match nodes {
+            Ok(nodes) => self.numa_nodes = nodes,
+            Err(_) => {
+                eprintln!("Invalid --numa-nodes argument '{}', expected a list of node ids such as 0,1", arg);
+                process::exit(1);
+            }
+        }
+    }
+
+    fn parse_millis(name: &str, arg: &str) -> u32 {
+        match arg.parse::<u32>() {
+            Ok(ms) => ms,
+            Err(_) => {
+                eprintln!("Invalid {} argument '{}', expected a number of milliseconds", name, arg);
+                process::exit(1);
+            }
+        }
+    }
+
+    fn add_realmfs_by_name(&mut self, realmfs: &str) {
+        let path = realm::realmfs_image_path(realmfs);
+        if !path.exists() {
+            eprintln!("Realmfs image does not exist at {}", path.display());
+            process::exit(1);
+        }
+        match RealmFSImage::new(path, OpenType::MemoryOverlay) {
+            Ok(disk) => self.realmfs_images.push(disk),
+            Err(e) => {
+                warn!("Could not add disk: {}", e);
+                process::exit(1);
+            },
+        };
+    }
+
+    fn add_realm_by_name(&mut self, name: &str) {
+        let provider = match realm::default_provider() {
+            Some(provider) => provider,
+            None => {
+                eprintln!("Cannot boot realm {}: pH was built without realm support", name);
+                process::exit(1);
+            }
+        };
+        match provider.lookup(name) {
+            Some(info) => self.add_realm(info),
+            None => {
+                eprintln!("Realm {} does not exist", name);
+                process::exit(1);
+            }
+        }
+    }
+
+    fn add_realm(&mut self, info: RealmInfo) {
+        for disk in info.disks {
+            match disk {
+                RealmDisk::RealmFS(path) => match RealmFSImage::new(path, OpenType::MemoryOverlay) {
+                    Ok(disk) => self.realmfs_images.push(disk),
+                    Err(e) => warn!("Could not add disk: {}", e),
+                },
+                RealmDisk::Raw(path, read_only) => {
+                    let open_type = if read_only { OpenType::ReadOnly } else { OpenType::ReadWrite };
+                    match RawDiskImage::new(path, open_type) {
+                        Ok(disk) => self.raw_disks.push(disk),
+                        Err(e) => warn!("Could not add disk: {}", e),
+                    }
+                }
+            }
+        }
+        self.home = info.home.display().to_string();
+        self.realm_name = Some(info.name);
+        self.bridge_name = format!("vz-{}", info.network_zone);
+        let scheme = info.color_scheme.unwrap_or_else(|| DEFAULT_REALM_COLOR_SCHEME.to_string());
+        self.colorscheme = Some(scheme);
+    }
+
+    fn print_usage() {
+        println!("Usage: pH [OPTIONS]
+
+Options:
+  -h, --help                      Show this help and exit
+  --check                         Report which features can be used by this user with
+                                  the other options given and exit
+  --check-json                    Like --check with the report as JSON
+  -v                              Verbose output, kernel messages on the serial console
+  --root                          Start a root shell instead of the normal init
+  --home PATH                     Directory shared with the guest as /home/user
+  --home-quota LIMITS             Limit the space and files used in the home directory,
+                                  eg. bytes=10G,inodes=100000
+  --home-fids LIMITS              Limit the fids and open files of the home directory
+                                  share and clunk fids of files idle for SECONDS,
+                                  eg. fids=16384,open=512,idle=600
+  --assets PATH                   Share fonts, icon themes and their caches in PATH
+                                  read-only with the guest
+  --warm-assets PATH              Build the font and icon caches of the assets
+                                  volume in PATH and exit
+  --realm NAME                    Boot the named realm
+  --realmfs NAME                  Use the named realmfs image as the root filesystem
+  --color-scheme NAME             Set the terminal to a base16 color scheme while running
+  --hostname NAME                 Hostname of the guest (default: the realm name)
+  --machine-id ID                 Guest /etc/machine-id as 32 hex digits (default: derived
+                                  from the hostname)
+  --timezone NAME                 Timezone of the guest, eg. Europe/Berlin (default: the
+                                  timezone of the host)
+  --rtc-localtime                 Keep the CMOS clock in local time instead of UTC
+  --no-wayland                    Disable the wayland device
+  --use-dmabuf                    Share graphics buffers with the compositor as dmabufs
+  --wl-max-vfds N                 Limit the number of open wayland vfds (default 4096)
+  --wayland-socket PATH           Connect to the compositor socket at PATH instead of
+                                  $XDG_RUNTIME_DIR/$WAYLAND_DISPLAY
+  --wayland-named-socket NAME=PATH
+                                  Connect to the compositor socket at PATH when the guest
+                                  asks for the socket NAME, may be repeated
+  --no-network                    Disable networking
+  --tap NAME                      Use an existing tap interface
+  --tap-multiqueue                The --tap interface was created with multi_queue
+  --macvtap NAME                  Use an existing macvtap interface
+  --net-helper COMMAND            Create the tap device with a privileged helper, eg.
+                                  /usr/libexec/ph-net-helper installed setuid root
+  --dns ADDR                      Nameserver for the guest, may be repeated (default: the
+                                  nameservers of the host, updated as they change)
+  --dns-search DOMAIN             Search domain for the guest, may be repeated
+  --net-capture PATH              Write network traffic to a pcapng file
+  --net-capture-size MB           Rotate capture files at this size (default 64)
+  --net-check-csum                Count frames sent by the guest with a bad checksum
+  --net-tx-limit LIMIT            Limit guest transmit rate, eg. bytes=10M,packets=5000
+                                  with an optional burst=MS (default 250)
+  --net-mtu MTU                   MTU of the tap or macvtap interface and of the guest
+                                  eth0 (default: the MTU of the bridge or interface)
+  --guest-ip ADDR                 IPv4 address of the guest on a /24 network with the
+                                  gateway at .1 (default: 172.17.0.22, none with --macvtap)
+  --guest-vlan ID                 Configure the guest address on VLAN ID over eth0
+  --disk PATH                     Attach a disk image read-write. The format is detected,
+                                  realmfs images are attached with a memory overlay
+  --ro-disk PATH                  Attach a disk image read-only
+  --labeled-disk LABEL=PATH       Attach a disk image like --disk with LABEL as the serial
+                                  number seen by the guest, linked from
+                                  /dev/disk/by-id/virtio-LABEL
+  --host-disk PATH                Attach a host block device read-only, eg. for
+                                  inspecting /dev/mapper/NAME in the guest
+  --disk-cache MODE               writeback (default), direct to bypass the host page
+                                  cache, or unsafe
+  --commit-overlay                Write changes to disks attached with a memory overlay,
+                                  such as realmfs images, back to the image on shutdown
+  --force                         Open disk images even if another VM has locked them
+  --root-disk INDEX|LABEL=NAME    Boot from the disk at INDEX (from 0) or the disk with
+                                  the ext4 volume label NAME instead of the first disk,
+                                  or with 'host' from the host root filesystem
+  --audio-latency MS              Target audio buffer length
+  --audio-min-request MS          Minimum audio request size
+  --audio-echo-cancel             Ask the audio server to cancel echo from guest recordings
+  --audio-rt-priority N           Run the audio stream threads with real time (SCHED_RR)
+                                  priority N from 1 to 99
+  --max-cpus N                    Create N vcpus so that cpus can be brought online
+                                  while the guest is running with the 'cpus' control
+                                  command
+  --cpu-quota PERCENT             Limit each vcpu to this percentage of a host cpu
+  --thread-nice NAME=NICE         Set the nice value of the threads of a device (named
+                                  as for --pci-slot, or audio), eg. block=10
+  --service-limit NAME:LIMITS     Run the guest service NAME (dbus-daemon, sommelier,
+                                  sommelier-x or shell) in a cgroup with the limits
+                                  memory=SIZE,cpu=PERCENT, eg. shell:memory=1G,cpu=150
+  --service-file PATH             Have ph-init start the service described in PATH,
+                                  replacing a default service with the same file name
+  --tmpfs-size NAME=SIZE          Limit the guest tmpfs NAME (tmp, run, shm or overlay
+                                  for changes to a read-only root) to SIZE, eg. 512M
+                                  or 25%
+  --zram-swap MB                  Add compressed swap of this size in guest RAM
+  --numa-nodes LIST               Spread guest RAM across host NUMA nodes, eg. 0,1
+  --pci-slot NAME=SLOT[:IRQ]      Place a device at a fixed PCI slot and IRQ
+  --reserve-memory NAME=BASE:SIZE Reserve a range of guest physical memory
+  --rom NAME=BASE:PATH            Map a file read-only into guest memory at BASE
+  --mmio-rom NAME=BASE:PATH       Serve a file read-only from the MMIO bus at BASE
+  --firmware PATH                 Boot a firmware image such as SeaBIOS or OVMF instead
+                                  of the built-in kernel
+  --split-irqchip                 Emulate the IOAPIC in userspace
+  --exit-on-device-panic          Stop the VM if a device thread panics instead of
+                                  running on without the device
+  --strict-dma                    Reject virtio buffers outside of the guest RAM in the
+                                  memory map or overlapping the virtqueue rings
+  --pmu                           Expose the hardware performance counters to the guest
+  --audit-fds                     Log open file descriptors which are not close-on-exec
+                                  and would leak into programs started by pH
+  --msr-policy POLICY             Handling of guest accesses to MSRs unknown to KVM:
+                                  fault (default), ignore, or log to log and ignore them
+  --control-socket PATH           Listen for control commands on a unix socket
+  --metrics-listen ADDRESS        Export counters in Prometheus format
+
+Console:
+  --ctrl-c restore|forward        With 'restore' (the default) Ctrl-C is sent to the
+                                  guest, but three in a row restore the host terminal
+                                  so that a fourth interrupts pH. With 'forward' Ctrl-C
+                                  is always sent to the guest.
+  --console-escape CHAR|none      Escape character, eg. ^] for Ctrl-]. The escape
+                                  character followed by '.' exits pH and followed by
+                                  'd' detaches from the console. Type it twice to send
+                                  it to the guest. There is no escape character by
+                                  default.
+  --console-escape-at-line-start  Only recognize the escape character at the start of
+                                  a line, elsewhere it is sent to the guest");
+    }
+
+    fn parse_args(&mut self) {
+        let args = ProgramArgs::new();
+        if args.has_arg("-h") || args.has_arg("--help") {
+            Self::print_usage();
+            process::exit(0);
+        }
+        if args.has_arg("-v") {
+            self.verbose = true;
+        }
+        if args.has_arg("--root") {
+            self.rootshell = true;
+        }
+        if let Some(scheme) = args.arg_with_value("--color-scheme") {
+            self.colorscheme = Some(scheme.to_string());
+        }
+        if let Some(name) = args.arg_with_value("--hostname") {
+            self.hostname = Some(name.to_string());
+        }
+        if let Some(id) = args.arg_with_value("--machine-id") {
+            if !is_machine_id(id) {
+                eprintln!("Invalid --machine-id argument '{}', expected 32 lowercase hex digits", id);
+                process::exit(1);
+            }
+            self.machine_id = Some(id.to_string());
+        }
+        if let Some(tz) = args.arg_with_value("--timezone") {
+            if !is_timezone_name(tz) {
+                eprintln!("Invalid --timezone argument '{}', expected a name such as Europe/Berlin", tz);
+                process::exit(1);
+            }
+            self.timezone = Some(tz.to_string());
+        }
+        if args.has_arg("--rtc-localtime") {
+            self.rtc_localtime = true;
+        }
+        if args.has_arg("--no-wayland") {
+            self.wayland = false;
+            self.dmabuf = false;
+        }
+        if args.has_arg("--use-dmabuf") {
+            self.dmabuf = true;
+        }
+        if let Some(max) = args.arg_with_value("--wl-max-vfds") {
+            match max.parse::<usize>() {
+                Ok(max) if max > 0 => self.wl_max_vfds = Some(max),
+                _ => {
+                    eprintln!("Invalid --wl-max-vfds argument '{}', expected a number of vfds", max);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(path) = args.arg_with_value("--wayland-socket") {
+            self.wayland_socket = Some(PathBuf::from(path));
+        }
+        for arg in args.args_with_value("--wayland-named-socket") {
+            self.add_wayland_named_socket(arg);
+        }
+        if args.has_arg("--no-network") {
+            self.network = false;
+        }
+        if let Some(tap) = args.arg_with_value("--tap") {
+            self.tap_name = Some(tap.to_string());
+        }
+        if args.has_arg("--tap-multiqueue") {
+            self.tap_multi_queue = true;
+        }
+        if let Some(macvtap) = args.arg_with_value("--macvtap") {
+            self.macvtap_name = Some(macvtap.to_string());
+        }
+        if let Some(command) = args.arg_with_value("--net-helper") {
+            self.net_helper = Some(command.to_string());
+        }
+        for address in args.args_with_value("--dns") {
+            match address.parse::<IpAddr>() {
+                Ok(address) => self.dns_servers.push(address),
+                Err(_) => {
+                    eprintln!("Invalid --dns argument '{}', expected an IP address", address);
+                    process::exit(1);
+                }
+            }
+        }
+        for domain in args.args_with_value("--dns-search") {
+            if !is_search_domain(domain) {
+                eprintln!("Invalid --dns-search argument '{}', expected a domain name", domain);
+                process::exit(1);
+            }
+            self.dns_search.push(domain.to_string());
+        }
+        if let Some(path) = args.arg_with_value("--net-capture") {
+            self.net_capture = Some(PathBuf::from(path));
+        }
+        if let Some(size) = args.arg_with_value("--net-capture-size") {
+            match size.parse::<u64>() {
+                Ok(megs) if megs > 0 => self.net_capture_size = Some(megs * 1024 * 1024),
+                _ => {
+                    eprintln!("Invalid --net-capture-size argument '{}', expected a size in megabytes", size);
+                    process::exit(1);
+                }
+            }
+        }
+        if args.has_arg("--net-check-csum") {
+            self.net_check_checksums = true;
+        }
+        #[cfg(feature = "network")]
+        if let Some(limit) = args.arg_with_value("--net-tx-limit") {
+            match NetRateLimit::parse(limit) {
+                Some(limit) => self.net_tx_limit = Some(limit),
+                None => {
+                    eprintln!("Invalid --net-tx-limit argument '{}', expected bytes=RATE,packets=RATE,burst=MS", limit);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(mtu) = args.arg_with_value("--net-mtu") {
+            match mtu.parse::<u32>() {
+                Ok(mtu) if mtu >= MIN_NET_MTU && mtu <= MAX_NET_MTU => self.net_mtu = Some(mtu),
+                _ => {
+                    eprintln!("Invalid --net-mtu argument '{}', expected a number from {} to {}", mtu, MIN_NET_MTU, MAX_NET_MTU);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(address) = args.arg_with_value("--guest-ip") {
+            match address.parse::<Ipv4Addr>() {
+                Ok(address) => self.guest_ip = Some(address),
+                Err(_) => {
+                    eprintln!("Invalid --guest-ip argument '{}', expected an IPv4 address", address);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(id) = args.arg_with_value("--guest-vlan") {
+            match id.parse::<u16>() {
+                Ok(id) if id >= 1 && id <= MAX_VLAN_ID => self.guest_vlan = Some(id),
+                _ => {
+                    eprintln!("Invalid --guest-vlan argument '{}', expected a VLAN id from 1 to {}", id, MAX_VLAN_ID);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(max) = args.arg_with_value("--max-cpus") {
+            match max.parse::<usize>() {
+                Ok(max) if max > 0 && max <= MAX_CPUS => self.max_cpus = Some(max),
+                _ => {
+                    eprintln!("Invalid --max-cpus argument '{}', expected a number of cpus up to {}", max, MAX_CPUS);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(quota) = args.arg_with_value("--cpu-quota") {
+            match quota.trim_end_matches('%').parse::<u32>() {
+                Ok(percent) if percent > 0 && percent <= 100 => self.cpu_quota = Some(percent),
+                _ => {
+                    eprintln!("Invalid --cpu-quota argument '{}', expected a percentage from 1 to 100", quota);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(path) = args.arg_with_value("--control-socket") {
+            self.control_socket = Some(PathBuf::from(path));
+        }
+        if let Some(address) = args.arg_with_value("--metrics-listen") {
+            self.metrics_address = Some(address.to_string());
+        }
+        if let Some(cache) = args.arg_with_value("--disk-cache") {
+            match CacheMode::from_name(cache) {
+                Some(cache_mode) => self.disk_cache = cache_mode,
+                None => {
+                    eprintln!("Invalid --disk-cache argument '{}', expected writeback, direct or unsafe", cache);
+                    process::exit(1);
+                }
+            }
+        }
+        if args.has_arg("--commit-overlay") {
+            self.commit_overlays = true;
+        }
+        if args.has_arg("--force") {
+            self.ignore_disk_locks = true;
+        }
+        let disks = args.args_with_value("--disk").into_iter().map(|p| (p, false))
+            .chain(args.args_with_value("--ro-disk").into_iter().map(|p| (p, true)));
+        for (path, read_only) in disks {
+            if let Err(e) = self.add_disk_image(Path::new(path), read_only, None) {
+                eprintln!("Could not add disk: {}", e);
+                process::exit(1);
+            }
+        }
+        for arg in args.args_with_value("--labeled-disk") {
+            let (label, path) = match arg.split_once('=') {
+                Some((label, path)) if !label.is_empty() && label.len() <= VIRTIO_BLK_ID_BYTES && !path.is_empty() => (label, path),
+                _ => {
+                    eprintln!("Invalid --labeled-disk argument '{}', expected LABEL=PATH with a LABEL of at most {} bytes", arg, VIRTIO_BLK_ID_BYTES);
+                    process::exit(1);
+                }
+            };
+            if let Err(e) = self.add_disk_image(Path::new(path), false, Some(label)) {
+                eprintln!("Could not add disk: {}", e);
+                process::exit(1);
+            }
+        }
+        for path in args.args_with_value("--host-disk") {
+            match RawDiskImage::new_block_device(path) {
+                Ok(disk) => self.host_disks.push(disk),
+                Err(e) => {
+                    eprintln!("Could not add disk: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(root) = args.arg_with_value("--root-disk") {
+            match RootDevice::parse(root) {
+                Some(root_device) => self.root_device = Some(root_device),
+                None => {
+                    eprintln!("Invalid --root-disk argument '{}', expected a disk index, LABEL=NAME or host", root);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(ms) = args.arg_with_value("--audio-latency") {
+            self.audio_latency.target_ms = Some(Self::parse_millis("--audio-latency", ms));
+        }
+        if let Some(ms) = args.arg_with_value("--audio-min-request") {
+            self.audio_latency.min_request_ms = Some(Self::parse_millis("--audio-min-request", ms));
+        }
+        if let Some(prio) = args.arg_with_value("--audio-rt-priority") {
+            match ThreadPriority::parse_rt_priority(prio) {
+                Some(priority) => self.thread_priorities.push(("audio".to_string(), priority)),
+                None => {
+                    eprintln!("Invalid --audio-rt-priority argument '{}', expected a priority from 1 to 99", prio);
+                    process::exit(1);
+                }
+            }
+        }
+        if args.has_arg("--audio-echo-cancel") {
+            self.audio_effects.push(StreamEffect::EchoCancellation);
+        }
+        if let Some(policy) = args.arg_with_value("--ctrl-c") {
+            match CtrlCPolicy::from_name(policy) {
+                Some(policy) => self.console.ctrl_c = policy,
+                None => {
+                    eprintln!("Invalid --ctrl-c argument '{}', expected restore or forward", policy);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(escape) = args.arg_with_value("--console-escape") {
+            if escape == "none" {
+                self.console.escape = None;
+            } else {
+                match ConsoleOptions::parse_escape(escape) {
+                    Some(escape) => self.console.escape = Some(escape),
+                    None => {
+                        eprintln!("Invalid --console-escape argument '{}', expected a character such as ^]", escape);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
+        if args.has_arg("--console-escape-at-line-start") {
+            self.console.escape_at_line_start = true;
+        }
+        if args.has_arg("--split-irqchip") {
+            self.split_irqchip = true;
+        }
+        if args.has_arg("--exit-on-device-panic") {
+            self.exit_on_device_panic = true;
+        }
+        if args.has_arg("--strict-dma") {
+            self.strict_dma = true;
+        }
+        if args.has_arg("--audit-fds") {
+            self.audit_fds = true;
+        }
+        if args.has_arg("--pmu") {
+            self.pmu = true;
+        }
+        if let Some(policy) = args.arg_with_value("--msr-policy") {
+            match MsrPolicy::from_name(policy) {
+                Some(policy) => self.msr_policy = policy,
+                None => {
+                    eprintln!("Invalid --msr-policy argument '{}', expected fault, ignore or log", policy);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(nodes) = args.arg_with_value("--numa-nodes") {
+            self.set_numa_nodes(nodes);
+        }
+        for limits in args.args_with_value("--service-limit") {
+            self.add_service_limits(limits);
+        }
+        for size in args.args_with_value("--tmpfs-size") {
+            self.add_tmpfs_size(size);
+        }
+        if let Some(size) = args.arg_with_value("--zram-swap") {
+            match size.parse::<u64>() {
+                Ok(megs) if megs > 0 => self.zram_swap = Some(megs * 1024 * 1024),
+                _ => {
+                    eprintln!("Invalid --zram-swap argument '{}', expected a size in megabytes", size);
+                    process::exit(1);
+                }
+            }
+        }
+        for path in args.args_with_value("--service-file") {
+            self.add_service_file(path);
+        }
+        for placement in args.args_with_value("--pci-slot") {
+            self.add_device_placement(placement);
+        }
+        for nice in args.args_with_value("--thread-nice") {
+            self.add_thread_nice(nice);
+        }
+        for reservation in args.args_with_value("--reserve-memory") {
+            self.add_memory_reservation(reservation);
+        }
+        for rom in args.args_with_value("--rom") {
+            self.add_rom_image("--rom", rom, RomMapping::Memory);
+        }
+        for rom in args.args_with_value("--mmio-rom") {
+            self.add_rom_image("--mmio-rom", rom, RomMapping::Mmio);
+        }
+        if let Some(path) = args.arg_with_value("--firmware") {
+            self.firmware = Some(PathBuf::from(path));
+        }
+        if let Some(home) = args.arg_with_value("--home") {
+            self.home = home.to_string();
+        }
+        if let Some(quota) = args.arg_with_value("--home-quota") {
+            match QuotaLimits::parse(quota) {
+                Some(limits) => self.home_quota = Some(limits),
+                None => {
+                    eprintln!("Invalid --home-quota argument '{}', expected bytes=SIZE,inodes=COUNT", quota);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(fids) = args.arg_with_value("--home-fids") {
+            match FidLimits::parse(fids) {
+                Some(limits) => self.home_fid_limits = Some(limits),
+                None => {
+                    eprintln!("Invalid --home-fids argument '{}', expected fids=COUNT,open=COUNT,idle=SECONDS", fids);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(dir) = args.arg_with_value("--assets") {
+            self.assets = Some(dir.to_string());
+        }
+        if let Some(dir) = args.arg_with_value("--warm-assets") {
+            if let Err(e) = assets::warm_asset_caches(dir) {
+                eprintln!("Failed to build the caches of the assets volume {}: {}", dir, e);
+                process::exit(1);
+            }
+            process::exit(0);
+        }
+        if let Some(realmfs) = args.arg_with_value("--realmfs") {
+            self.add_realmfs_by_name(realmfs);
+        }
+        if let Some(realm) = args.arg_with_value("--realm") {
+            self.add_realm_by_name(realm);
+        }
+        if args.has_arg("--check") || args.has_arg("--check-json") {
+            let report = CapabilityReport::probe(self);
+            if args.has_arg("--check-json") {
+                println!("{}", report.to_json());
+            } else {
+                report.print();
+            }
+            process::exit(if report.can_boot() { 0 } else { 1 });
+        }
+    }
+}
+
+// Passed to the guest as a comma separated list on the kernel command line
+fn is_search_domain(domain: &str) -> bool {
+    !domain.is_empty() && domain.len() <= 253 &&
+        domain.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+}
+
+// Replace characters which are not allowed in a hostname with '-'
+fn sanitize_hostname(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' })
+        .take(63)
+        .collect();
+    let name = name.trim_matches('-');
+    if name.is_empty() {
+        DEFAULT_HOSTNAME.to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+// A relative path below /usr/share/zoneinfo, which is passed to the guest on
+// the kernel command line
+fn is_timezone_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && !name.starts_with('/') &&
+        name.split('/').all(|part| !part.is_empty() && part != "." && part != "..") &&
+        name.bytes().all(|b| b.is_ascii_alphanumeric() || b"/_+-".contains(&b))
+}
+
+// The name of the zone /etc/localtime links to, eg. Europe/Berlin
+fn host_timezone() -> Option<String> {
+    let target = fs::read_link("/etc/localtime").ok()?;
+    let target = target.to_str()?;
+    let (_, name) = target.split_once("zoneinfo/")?;
+    if is_timezone_name(name) {
+        Some(name.to_string())
+    } else {
+        None
+    }
+}
+
+fn is_machine_id(id: &str) -> bool {
+    id.len() == 32 && id.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+// Two 64-bit FNV-1a hashes of the hostname with different offsets. Unlike
+// the std hashers the result is stable across Rust versions.
+fn derive_machine_id(hostname: &str) -> String {
+    const FNV_PRIME: u64 = 0x100000001b3;
+    let hash = |offset: u64| hostname.bytes()
+        .fold(offset, |h, b| (h ^ b as u64).wrapping_mul(FNV_PRIME));
+    format!("{:016x}{:016x}", hash(0xcbf29ce484222325), hash(0x6c62272e07bb0142))
+}
+
+struct ProgramArgs {
+    args: Vec<String>,
+}
+
+impl ProgramArgs {
+    fn new() -> Self {
+        ProgramArgs {
+            args: env::args().skip(1).collect(),
+        }
+    }
+
+    fn has_arg(&self, name: &str) -> bool {
+        self.args.iter().any(|arg| arg.as_str() == name)
+    }
+
+    fn arg_with_value(&self, name: &str) -> Option<&str> {
+        let mut iter = self.args.iter();
+        while let Some(arg) = iter.next() {
+            if arg.as_str() == name {
+                match iter.next() {
+                    Some(val) => return Some(val.as_str()),
+                    None => {
+                        eprintln!("Expected value for {} argument", name);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn args_with_value(&self, name: &str) -> Vec<&str> {
+        let mut values = Vec::new();
+        let mut iter = self.args.iter();
+        while let Some(arg) = iter.next() {
+            if arg.as_str() == name {
+                match iter.next() {
+                    Some(val) => values.push(val.as_str()),
+                    None => {
+                        eprintln!("Expected value for {} argument", name);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
+        values
     }
 }
@@ -0,0 +1,486 @@
+use std::{fs, io, thread};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use crate::devices::ShareControl;
+#[cfg(feature = "network")]
+use crate::devices::{NetControl, NetRateLimit};
+#[cfg(feature = "wayland")]
+use crate::devices::WaylandControl;
+#[cfg(feature = "audio")]
+use crate::audio::AudioControl;
+use crate::io::manager::IoManager;
+use crate::util::JsonValue;
+#[cfg(feature = "network")]
+use crate::vm::bridge::BridgeControl;
+use crate::vm::{VcpuControl, VmEvent};
+
+// How long `home quiesce` waits for a 9p request in progress to complete
+const QUIESCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A unix socket which accepts simple line oriented commands for querying
+/// the state of a running VM.
+///
+/// Each line received on a connection is a command followed by optional
+/// whitespace separated arguments. Every command produces a single line of
+/// JSON in response, either:
+///
+/// ```text
+/// {"status":"ok","data":...}
+/// {"status":"error","message":"..."}
+/// ```
+///
+/// Supported commands:
+///
+///   `stats`     Per-device virtqueue counters
+///   `describe`  Machine layout: memory map, PCI devices with BARs and IRQs,
+///               backing files and negotiated virtio features
+///   `link`      Network link state. With an argument `up` or `down` changes
+///               the link state reported to the guest, `reattach` opens the
+///               host network interface again after it has been recreated
+///   `capture`   Network traffic capture. `capture start PATH` writes every
+///               frame to a pcapng file at PATH, `capture stop` ends it
+///   `txlimit`   Network transmit rate limit. `txlimit off` removes the limit
+///               and `txlimit bytes=RATE,packets=RATE,burst=MS` replaces it
+///   `home`      State of the home directory share. `home quiesce` closes
+///               every file pH holds open in the directory so that it can be
+///               unmounted and replaced, `home resume` opens it again and
+///               makes the guest remount the share
+///   `cpus`      Number of vcpus the guest keeps online and the maximum. With
+///               a count as argument the guest brings vcpus online or takes
+///               them offline until that many are online
+///   `ready`     Whether ph-init has reported that the guest finished booting.
+///               `ready wait` responds once it has, or once the VM stops
+///   `reload`    Connect to the host services again without restarting the
+///               VM. Takes any of `wayland[=PATH]`, which sends new wayland
+///               connections to the compositor socket PATH or the current
+///               one, `audio`, which reconnects to the PulseAudio server, and
+///               `bridge[=NAME]`, which adds the tap device to bridge NAME or
+///               to the current bridge again. Without arguments reloads all
+///               of them which the VM uses
+///
+pub struct ControlServer {
+    path: PathBuf,
+    listener: UnixListener,
+    io_manager: IoManager,
+    #[cfg(feature = "network")]
+    net_control: Option<Arc<NetControl>>,
+    #[cfg(feature = "network")]
+    bridge_control: Option<Arc<BridgeControl>>,
+    #[cfg(feature = "wayland")]
+    wayland_control: Option<Arc<WaylandControl>>,
+    #[cfg(feature = "audio")]
+    audio_control: Option<Arc<AudioControl>>,
+    home_control: Option<Arc<ShareControl>>,
+    vcpu_control: Option<Arc<VcpuControl>>,
+    cpu_hotplug: bool,
+}
+
+impl ControlServer {
+    pub fn bind<P: AsRef<Path>>(path: P, io_manager: IoManager) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        // Remove stale socket left behind by a previous instance
+        Self::remove_socket(&path)?;
+        let listener = UnixListener::bind(&path)?;
+        Ok(ControlServer {
+            path, listener, io_manager,
+            #[cfg(feature = "network")]
+            net_control: None,
+            #[cfg(feature = "network")]
+            bridge_control: None,
+            #[cfg(feature = "wayland")]
+            wayland_control: None,
+            #[cfg(feature = "audio")]
+            audio_control: None,
+            home_control: None, vcpu_control: None, cpu_hotplug: false,
+        })
+    }
+
+    /// Remove the socket at `path` if there is one. Any other kind of file
+    /// is left alone so that a mistyped path cannot delete it.
+    pub fn remove_socket(path: &Path) -> io::Result<()> {
+        match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path),
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(feature = "network")]
+    pub fn set_net_control(&mut self, control: Arc<NetControl>) {
+        self.net_control = Some(control);
+    }
+
+    #[cfg(feature = "network")]
+    pub fn set_bridge_control(&mut self, control: Arc<BridgeControl>) {
+        self.bridge_control = Some(control);
+    }
+
+    #[cfg(feature = "wayland")]
+    pub fn set_wayland_control(&mut self, control: Arc<WaylandControl>) {
+        self.wayland_control = Some(control);
+    }
+
+    #[cfg(feature = "audio")]
+    pub fn set_audio_control(&mut self, control: Arc<AudioControl>) {
+        self.audio_control = Some(control);
+    }
+
+    pub fn set_home_control(&mut self, control: Arc<ShareControl>) {
+        self.home_control = Some(control);
+    }
+
+    pub fn set_vcpu_control(&mut self, control: Arc<VcpuControl>, cpu_hotplug: bool) {
+        self.vcpu_control = Some(control);
+        self.cpu_hotplug = cpu_hotplug;
+    }
+
+    pub fn spawn(self) {
+        let server = Arc::new(self);
+        thread::spawn(move || server.accept_loop());
+    }
+
+    fn accept_loop(self: Arc<Self>) {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let server = self.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = server.handle_connection(stream) {
+                            info!("error on control socket connection: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("error accepting connection on control socket {}: {}", self.path.display(), e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let reader = BufReader::new(stream);
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let response = self.handle_command(line);
+            writeln!(writer, "{}", response)?;
+        }
+        Ok(())
+    }
+
+    fn handle_command(&self, line: &str) -> JsonValue {
+        let mut args = line.split_whitespace();
+        let command = args.next().unwrap_or("");
+        match command {
+            "stats" => Self::ok(self.io_manager.stats().to_json()),
+            "describe" => Self::ok(self.io_manager.describe()),
+            "layout" => Self::ok(self.io_manager.memory_layout()),
+            #[cfg(feature = "network")]
+            "link" => self.link_command(args.next()),
+            #[cfg(feature = "network")]
+            "capture" => self.capture_command(args.next(), args.next()),
+            #[cfg(feature = "network")]
+            "txlimit" => self.tx_limit_command(args.next()),
+            #[cfg(not(feature = "network"))]
+            "link" | "capture" | "txlimit" => Self::error("pH was built without network support".to_string()),
+            "peek" => self.peek_command(args.next(), args.next(), args.next()),
+            "home" => self.home_command(args.next()),
+            "cpus" => self.cpus_command(args.next()),
+            "ready" => self.ready_command(args.next()),
+            "reload" => self.reload_command(args.collect()),
+            cmd => Self::error(format!("unknown command: {}", cmd)),
+        }
+    }
+
+    #[cfg(feature = "network")]
+    fn net_control(&self) -> Option<&NetControl> {
+        self.net_control.as_ref().map(|c| c.as_ref())
+    }
+
+    #[cfg(feature = "network")]
+    fn link_command(&self, arg: Option<&str>) -> JsonValue {
+        let link = match self.net_control() {
+            Some(link) => link,
+            None => return Self::error("no network device".to_string()),
+        };
+        match arg {
+            None => {},
+            Some("up") => link.set_link_up(true),
+            Some("down") => link.set_link_up(false),
+            Some("reattach") => link.request_reattach(),
+            Some(arg) => return Self::error(format!("invalid link argument: {}", arg)),
+        }
+        let state = if link.is_link_up() { "up" } else { "down" };
+        let backend = if link.is_backend_lost() { "lost" } else { "attached" };
+        Self::ok(JsonValue::object()
+            .field("link", state)
+            .field("backend", backend))
+    }
+
+    fn home_command(&self, arg: Option<&str>) -> JsonValue {
+        let control = match self.home_control.as_ref() {
+            Some(control) => control,
+            None => return Self::error("no home directory share".to_string()),
+        };
+        let result = match arg {
+            None => Ok(()),
+            Some("quiesce") => control.quiesce(QUIESCE_TIMEOUT),
+            Some("resume") => control.resume(),
+            Some(arg) => return Self::error(format!("invalid home argument: {}", arg)),
+        };
+        if let Err(e) = result {
+            return Self::error(format!("failed to {} home share: {}", arg.unwrap_or(""), e));
+        }
+        let state = if control.is_quiesced() { "quiesced" } else { "running" };
+        Self::ok(JsonValue::object().field("home", state))
+    }
+
+    fn cpus_command(&self, arg: Option<&str>) -> JsonValue {
+        let control = match self.vcpu_control.as_ref() {
+            Some(control) if self.cpu_hotplug => control,
+            _ => return Self::error("cpu hotplug is not enabled".to_string()),
+        };
+        if let Some(arg) = arg {
+            let result = match arg.parse::<usize>() {
+                Ok(count) => control.set_online_cpus(count),
+                Err(_) => return Self::error(format!("invalid cpu count: {}", arg)),
+            };
+            if let Err(e) = result {
+                return Self::error(e.to_string());
+            }
+        }
+        Self::ok(JsonValue::object()
+            .field("online", control.online_cpus())
+            .field("max", control.max_cpus()))
+    }
+
+    fn ready_command(&self, arg: Option<&str>) -> JsonValue {
+        let control = match self.vcpu_control.as_ref() {
+            Some(control) => control,
+            None => return Self::error("vcpu control is not available".to_string()),
+        };
+        match arg {
+            None => {},
+            Some("wait") => Self::wait_ready(control),
+            Some(arg) => return Self::error(format!("invalid ready argument: {}", arg)),
+        }
+        Self::ok(JsonValue::object().field("ready", control.is_ready()))
+    }
+
+    // reload [wayland[=PATH]] [audio] [bridge[=NAME]]
+    fn reload_command(&self, args: Vec<&str>) -> JsonValue {
+        let reload_all = args.is_empty();
+        let mut wayland = None;
+        let mut audio = false;
+        let mut bridge = None;
+        for arg in args {
+            let mut parts = arg.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("wayland"), value) => wayland = Some(value),
+                (Some("audio"), None) => audio = true,
+                (Some("bridge"), value) => bridge = Some(value),
+                _ => return Self::error(format!("invalid reload argument: {}", arg)),
+            }
+        }
+        let mut result = JsonValue::object();
+        if wayland.is_some() || reload_all {
+            match self.reload_wayland(wayland.flatten(), reload_all) {
+                Ok(Some(value)) => result = result.field("wayland", value),
+                Ok(None) => {},
+                Err(e) => return Self::error(e),
+            }
+        }
+        if audio || reload_all {
+            match self.reload_audio(reload_all) {
+                Ok(Some(value)) => result = result.field("audio", value),
+                Ok(None) => {},
+                Err(e) => return Self::error(e),
+            }
+        }
+        if bridge.is_some() || reload_all {
+            match self.reload_bridge(bridge.flatten(), reload_all) {
+                Ok(Some(value)) => result = result.field("bridge", value),
+                Ok(None) => {},
+                Err(e) => return Self::error(e),
+            }
+        }
+        Self::ok(result)
+    }
+
+    // Each reload_* function returns Ok(None) if the VM does not use the
+    // service and `optional` is set, which is the case when every service
+    // is reloaded.
+
+    #[cfg(feature = "wayland")]
+    fn reload_wayland(&self, path: Option<&str>, optional: bool) -> Result<Option<JsonValue>, String> {
+        let control = match self.wayland_control.as_ref() {
+            Some(control) => control,
+            None if optional => return Ok(None),
+            None => return Err("no wayland device".to_string()),
+        };
+        if let Some(path) = path {
+            control.set_socket_path(path);
+        }
+        let path = control.socket_path();
+        Ok(Some(JsonValue::object()
+            .field("socket", path.display().to_string())
+            .field("exists", path.exists())))
+    }
+
+    #[cfg(not(feature = "wayland"))]
+    fn reload_wayland(&self, _path: Option<&str>, optional: bool) -> Result<Option<JsonValue>, String> {
+        if optional {
+            return Ok(None);
+        }
+        Err("pH was built without wayland support".to_string())
+    }
+
+    #[cfg(feature = "audio")]
+    fn reload_audio(&self, optional: bool) -> Result<Option<JsonValue>, String> {
+        match self.audio_control.as_ref() {
+            Some(control) => {
+                control.request_reconnect();
+                Ok(Some(JsonValue::from("reconnecting")))
+            }
+            None if optional => Ok(None),
+            None => Err("no audio device".to_string()),
+        }
+    }
+
+    #[cfg(not(feature = "audio"))]
+    fn reload_audio(&self, optional: bool) -> Result<Option<JsonValue>, String> {
+        if optional {
+            return Ok(None);
+        }
+        Err("pH was built without audio support".to_string())
+    }
+
+    #[cfg(feature = "network")]
+    fn reload_bridge(&self, name: Option<&str>, optional: bool) -> Result<Option<JsonValue>, String> {
+        let control = match self.bridge_control.as_ref() {
+            Some(control) => control,
+            None if optional => return Ok(None),
+            None => return Err("network device is not attached to a bridge by pH".to_string()),
+        };
+        if let Err(e) = control.rejoin(name) {
+            return Err(format!("failed to add tap device to bridge {}: {}",
+                               name.map(|s| s.to_string()).unwrap_or_else(|| control.bridge()), e));
+        }
+        Ok(Some(JsonValue::from(control.bridge())))
+    }
+
+    #[cfg(not(feature = "network"))]
+    fn reload_bridge(&self, _name: Option<&str>, optional: bool) -> Result<Option<JsonValue>, String> {
+        if optional {
+            return Ok(None);
+        }
+        Err("pH was built without network support".to_string())
+    }
+
+    // Block until the guest is ready or the VM stops
+    fn wait_ready(control: &VcpuControl) {
+        let (tx, rx) = mpsc::channel();
+        control.add_listener(tx);
+        while !control.is_ready() && control.exit_reason().is_none() {
+            match rx.recv() {
+                Ok(VmEvent::Ready) | Ok(VmEvent::Stopped(_)) | Err(_) => break,
+                Ok(_) => {},
+            }
+        }
+    }
+
+    #[cfg(feature = "network")]
+    fn tx_limit_command(&self, arg: Option<&str>) -> JsonValue {
+        let control = match self.net_control() {
+            Some(control) => control,
+            None => return Self::error("no network device".to_string()),
+        };
+        match arg {
+            None => {},
+            Some("off") => control.set_tx_limit(None),
+            Some(arg) => match NetRateLimit::parse(arg) {
+                Some(limit) => control.set_tx_limit(Some(limit)),
+                None => return Self::error(format!("invalid rate limit: {}", arg)),
+            }
+        }
+        let limit = control.tx_limit().map(|l| l.to_json());
+        Self::ok(JsonValue::object().field("tx_limit", limit))
+    }
+
+    #[cfg(feature = "network")]
+    fn capture_command(&self, arg: Option<&str>, path: Option<&str>) -> JsonValue {
+        let control = match self.net_control() {
+            Some(control) => control,
+            None => return Self::error("no network device".to_string()),
+        };
+        match (arg, path) {
+            (None, _) => {},
+            (Some("start"), Some(path)) => if let Err(e) = control.start_capture(Path::new(path)) {
+                return Self::error(format!("failed to open capture file {}: {}", path, e));
+            },
+            (Some("stop"), None) => control.stop_capture(),
+            (Some(arg), _) => return Self::error(format!("invalid capture arguments: {}", arg)),
+        }
+        let path = control.capture_path().map(|p| p.display().to_string());
+        Self::ok(JsonValue::object().field("capture", path))
+    }
+
+    // peek mmio|pio ADDRESS [SIZE]
+    fn peek_command(&self, space: Option<&str>, address: Option<&str>, size: Option<&str>) -> JsonValue {
+        let address = match address.and_then(parse_number) {
+            Some(address) => address,
+            None => return Self::error("expected peek mmio|pio ADDRESS [SIZE]".to_string()),
+        };
+        let size = match size.map(parse_number) {
+            None => 4,
+            Some(Some(size)) if [1, 2, 4, 8].contains(&size) => size as usize,
+            Some(_) => return Self::error("peek size must be 1, 2, 4 or 8".to_string()),
+        };
+        let mut data = [0u8; 8];
+        let data = &mut data[..size];
+        let ok = match space {
+            Some("mmio") => self.io_manager.mmio_peek(address, data),
+            Some("pio") if address <= u16::MAX as u64 => self.io_manager.pio_peek(address as u16, data),
+            _ => return Self::error("expected peek mmio|pio ADDRESS [SIZE]".to_string()),
+        };
+        if !ok {
+            return Self::error(format!("no device supporting peek at 0x{:x}", address));
+        }
+        let mut value = [0u8; 8];
+        value[..size].copy_from_slice(data);
+        Self::ok(JsonValue::object()
+            .field("address", address)
+            .field("size", size)
+            .field("value", u64::from_le_bytes(value)))
+    }
+
+    fn ok(data: JsonValue) -> JsonValue {
+        JsonValue::object()
+            .field("status", "ok")
+            .field("data", data)
+    }
+
+    fn error(message: String) -> JsonValue {
+        JsonValue::object()
+            .field("status", "error")
+            .field("message", message)
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
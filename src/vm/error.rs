@@ -1,11 +1,14 @@
 use std::{result, io};
 use kvm_ioctls::Cap;
 use crate::system;
+#[cfg(feature = "network")]
 use crate::system::netlink;
 use crate::vm::arch;
 
 use thiserror::Error;
 use crate::io::virtio;
+use crate::io::address_map;
+use crate::io::manager::{PlacementError, RomError};
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -31,8 +34,11 @@ pub enum Error {
     IoError(#[from] io::Error),
     #[error("{0}")]
     ArchError(arch::Error),
+    #[cfg(feature = "network")]
     #[error("error setting up network: {0}")]
     NetworkSetup(#[from] netlink::Error),
+    #[error("network unavailable: {0}")]
+    NetworkUnavailable(String),
     #[error("setting up boot fs failed: {0}")]
     SetupBootFs(io::Error),
     #[error("setting up virtio devices failed: {0}")]
@@ -41,4 +47,22 @@ pub enum Error {
     CreateVcpu(kvm_ioctls::Error),
     #[error("{0}")]
     VirtioError(#[from]crate::io::VirtioError),
+    #[error("failed to create control socket: {0}")]
+    ControlSocket(io::Error),
+    #[error("invalid metrics listen address: {0}")]
+    MetricsAddress(String),
+    #[error("failed to start metrics listener: {0}")]
+    MetricsListener(io::Error),
+    #[error("cannot assign fixed placement for device {0}: {1}")]
+    DevicePlacement(String, PlacementError),
+    #[error("cannot reserve guest memory: {0}")]
+    MemoryReservation(address_map::Error),
+    #[error("cannot map rom image {0}: {1}")]
+    RomImage(String, RomError),
+    #[error("cannot load firmware: {0}")]
+    Firmware(RomError),
+    #[error("cannot select root disk: {0}")]
+    RootDevice(String),
+    #[error("{0}")]
+    DiskImage(crate::disk::Error),
 }
\ No newline at end of file
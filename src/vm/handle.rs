@@ -0,0 +1,186 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use libc::c_int;
+
+use crate::vm::setup::Vm;
+use crate::vm::vcpu::VcpuControl;
+
+// How often wait() checks for signals and signals vcpus again while a
+// shutdown is pending
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Why a VM stopped running
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum VmExitReason {
+    /// The guest powered off. ph-init does this when the shell exits.
+    GuestShutdown,
+    /// The guest kernel rebooted, either because of a `reboot` in the guest
+    /// or because the kernel panicked.
+    GuestReset,
+    /// A vcpu triple faulted or was left in a state KVM cannot run.
+    GuestCrash,
+    /// `VmHandle::shutdown()` was called or the console exit escape was typed.
+    Requested,
+    /// pH received this signal, see `VmHandle::exit_on_signals()`.
+    Signal(c_int),
+    /// The VM could not be created or kept running.
+    HostError(String),
+}
+
+impl VmExitReason {
+    /// Exit status of the pH process when the VM stops for this reason.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            VmExitReason::GuestShutdown | VmExitReason::Requested => 0,
+            VmExitReason::HostError(_) => 1,
+            VmExitReason::GuestReset => 3,
+            VmExitReason::GuestCrash => 4,
+            VmExitReason::Signal(signal) => 128 + signal,
+        }
+    }
+}
+
+/// Changes in the run state of a VM reported to `VmHandle::events()`
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub enum VmEvent {
+    /// Every vcpu has started running
+    Started,
+    /// ph-init has started the guest services and the shell, so the guest
+    /// is ready to use. Sent at most once.
+    Ready,
+    /// Every vcpu has stopped after a call to `VmHandle::pause()`
+    Paused,
+    /// The vcpus are running again after `VmHandle::resume()`
+    Resumed,
+    /// The last vcpu has exited
+    Stopped(VmExitReason),
+}
+
+///
+/// A running VM returned by `VmConfig::start()`.
+///
+/// The control methods only make a request and return immediately, use
+/// `events()` to learn when the request has taken effect or `wait()` to block
+/// until the VM stops. Dropping the handle shuts the VM down.
+///
+pub struct VmHandle {
+    vm: Vm,
+    threads: Vec<JoinHandle<()>>,
+    control: Arc<VcpuControl>,
+    signals: Vec<(c_int, Arc<AtomicBool>)>,
+}
+
+impl VmHandle {
+    pub(crate) fn new(vm: Vm, threads: Vec<JoinHandle<()>>, control: Arc<VcpuControl>) -> Self {
+        VmHandle { vm, threads, control, signals: Vec::new() }
+    }
+
+    pub(crate) fn control(&self) -> &Arc<VcpuControl> {
+        &self.control
+    }
+
+    /// Returns a channel on which every following `VmEvent` is received.
+    pub fn events(&self) -> Receiver<VmEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.control.add_listener(tx);
+        rx
+    }
+
+    /// Stop running every vcpu until `resume()` is called. Devices keep
+    /// running, so pending I/O still completes while the VM is paused.
+    pub fn pause(&self) {
+        self.control.request_pause();
+    }
+
+    pub fn resume(&self) {
+        self.control.request_resume();
+    }
+
+    /// Ask the guest to keep `count` vcpus online. Only vcpus created with
+    /// `VmConfig::max_cpus()` can be added.
+    pub fn set_online_cpus(&self, count: usize) -> io::Result<()> {
+        self.control.set_online_cpus(count)
+    }
+
+    /// Stop the VM as if it had powered off. The guest is not notified.
+    pub fn shutdown(&self) {
+        self.control.request_exit(VmExitReason::Requested);
+    }
+
+    /// Stop the VM with `VmExitReason::Signal` when pH receives one of
+    /// `signals`. Signals are only noticed while `wait()` is running.
+    pub fn exit_on_signals(&mut self, signals: &[c_int]) -> io::Result<()> {
+        for &signal in signals {
+            let flag = Arc::new(AtomicBool::new(false));
+            signal_hook::flag::register(signal, flag.clone())?;
+            self.signals.push((signal, flag));
+        }
+        Ok(())
+    }
+
+    fn check_signals(&self) {
+        for (signal, flag) in &self.signals {
+            if flag.swap(false, Ordering::Relaxed) {
+                notify!("Stopping VM on signal {}", signal);
+                self.control.request_exit(VmExitReason::Signal(*signal));
+            }
+        }
+    }
+
+    /// Returns `true` once the `Ready` event has been sent
+    pub fn is_ready(&self) -> bool {
+        self.control.is_ready()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.control.is_paused()
+    }
+
+    /// Returns `false` once every vcpu has exited
+    pub fn is_running(&self) -> bool {
+        self.control.is_running()
+    }
+
+    /// Block until the VM stops, shut down the devices, restore the terminal
+    /// settings and return why the VM stopped.
+    pub fn wait(mut self) -> VmExitReason {
+        self.join_vcpus();
+        self.vm.shutdown_devices();
+        self.vm.remove_sockets();
+        if let Err(err) = self.vm.restore_terminal() {
+            warn!("{}", err);
+        }
+        self.control.exit_reason()
+            .unwrap_or_else(|| VmExitReason::HostError("vcpus exited".to_string()))
+    }
+
+    fn join_vcpus(&mut self) {
+        while self.control.is_running() {
+            self.check_signals();
+            self.control.kick_if_shutdown();
+            self.control.wait_for_exit(WAIT_POLL_INTERVAL);
+        }
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                self.control.request_exit(VmExitReason::HostError("vcpu thread panicked".to_string()));
+            }
+        }
+    }
+}
+
+impl Drop for VmHandle {
+    fn drop(&mut self) {
+        if !self.threads.is_empty() {
+            self.control.request_exit(VmExitReason::Requested);
+            self.join_vcpus();
+            self.vm.shutdown_devices();
+            self.vm.remove_sockets();
+            let _ = self.vm.restore_terminal();
+        }
+    }
+}
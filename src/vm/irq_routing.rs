@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::{mem, result};
+use kvm_bindings::{kvm_irq_routing, kvm_irq_routing_entry, kvm_msi, KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI};
+use kvm_ioctls::VmFd;
+
+/// Number of pins on the IOAPIC. GSIs below this are reserved for IOAPIC
+/// (and PIC) routes, MSI routes are allocated above it.
+pub const IOAPIC_NUM_PINS: u32 = 24;
+
+/// Highest GSI that can be allocated for MSI routes.
+const MAX_GSI: u32 = 1023;
+
+const IRQCHIP_MASTER_PIC: u32 = 0;
+const IRQCHIP_SLAVE_PIC: u32 = 1;
+const IRQCHIP_IOAPIC: u32 = 2;
+
+/// An MSI address/data pair as programmed by the guest into an MSI or MSI-X
+/// capability or produced by the userspace IOAPIC from a redirection entry.
+#[derive(Copy,Clone,Debug,Default,PartialEq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+impl MsiMessage {
+    pub fn new(address: u64, data: u32) -> Self {
+        MsiMessage { address, data }
+    }
+
+    pub fn to_kvm_msi(&self) -> kvm_msi {
+        kvm_msi {
+            address_lo: self.address as u32,
+            address_hi: (self.address >> 32) as u32,
+            data: self.data,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum Route {
+    Irqchip { chip: u32, pin: u32 },
+    Msi(MsiMessage),
+}
+
+impl Route {
+    fn to_entry(&self, gsi: u32) -> kvm_irq_routing_entry {
+        let mut entry = kvm_irq_routing_entry {
+            gsi,
+            ..Default::default()
+        };
+        match *self {
+            Route::Irqchip { chip, pin } => {
+                entry.type_ = KVM_IRQ_ROUTING_IRQCHIP;
+                entry.u.irqchip.irqchip = chip;
+                entry.u.irqchip.pin = pin;
+            }
+            Route::Msi(msg) => {
+                entry.type_ = KVM_IRQ_ROUTING_MSI;
+                entry.u.msi.address_lo = msg.address as u32;
+                entry.u.msi.address_hi = (msg.address >> 32) as u32;
+                entry.u.msi.data = msg.data;
+            }
+        }
+        entry
+    }
+}
+
+/// The GSI routing table of the VM.
+///
+/// `KVM_SET_GSI_ROUTING` replaces the whole table, so a copy of every route is
+/// kept here and the complete table is submitted whenever it changes.
+pub struct GsiRouting {
+    // A GSI may have more than one route (eg. both PIC and IOAPIC for legacy IRQs)
+    routes: BTreeMap<u32, Vec<Route>>,
+    free_msi_gsis: Vec<u32>,
+    next_msi_gsi: u32,
+}
+
+impl GsiRouting {
+    /// An empty routing table, as KVM creates for a split irqchip.
+    pub fn new() -> Self {
+        GsiRouting {
+            routes: BTreeMap::new(),
+            free_msi_gsis: Vec::new(),
+            next_msi_gsi: IOAPIC_NUM_PINS,
+        }
+    }
+
+    /// The routing table KVM installs when creating an in-kernel irqchip:
+    /// GSIs 0-15 go to both PICs and the IOAPIC, and GSIs 16-23 to the IOAPIC
+    /// only. GSI 0 is wired to IOAPIC pin 2 as on real hardware.
+    pub fn new_with_irqchip_defaults() -> Self {
+        let mut routing = Self::new();
+        for gsi in 0..IOAPIC_NUM_PINS {
+            if gsi < 8 {
+                routing.push(gsi, Route::Irqchip { chip: IRQCHIP_MASTER_PIC, pin: gsi });
+            } else if gsi < 16 {
+                routing.push(gsi, Route::Irqchip { chip: IRQCHIP_SLAVE_PIC, pin: gsi - 8 });
+            }
+            let pin = if gsi == 0 { 2 } else { gsi };
+            routing.push(gsi, Route::Irqchip { chip: IRQCHIP_IOAPIC, pin });
+        }
+        routing
+    }
+
+    fn push(&mut self, gsi: u32, route: Route) {
+        self.routes.entry(gsi).or_default().push(route);
+    }
+
+    /// Reserve a GSI above the IOAPIC pins for an MSI route.
+    pub fn allocate_msi_gsi(&mut self) -> Option<u32> {
+        if let Some(gsi) = self.free_msi_gsis.pop() {
+            return Some(gsi);
+        }
+        if self.next_msi_gsi > MAX_GSI {
+            return None;
+        }
+        let gsi = self.next_msi_gsi;
+        self.next_msi_gsi += 1;
+        Some(gsi)
+    }
+
+    /// Remove any route for a GSI allocated with `allocate_msi_gsi()` and
+    /// make it available to be allocated again.
+    pub fn release_msi_gsi(&mut self, gsi: u32) {
+        self.routes.remove(&gsi);
+        if gsi >= IOAPIC_NUM_PINS && !self.free_msi_gsis.contains(&gsi) {
+            self.free_msi_gsis.push(gsi);
+        }
+    }
+
+    /// Replace any existing routes for `gsi` with a single MSI route.
+    pub fn set_msi_route(&mut self, gsi: u32, msg: MsiMessage) {
+        self.routes.insert(gsi, vec![Route::Msi(msg)]);
+    }
+
+    pub fn remove_route(&mut self, gsi: u32) {
+        self.routes.remove(&gsi);
+    }
+
+    fn entries(&self) -> Vec<kvm_irq_routing_entry> {
+        self.routes.iter()
+            .flat_map(|(&gsi, routes)| routes.iter().map(move |r| r.to_entry(gsi)))
+            .collect()
+    }
+
+    /// Submit the complete table to KVM.
+    pub fn commit(&self, vm_fd: &VmFd) -> result::Result<(), kvm_ioctls::Error> {
+        let entries = self.entries();
+        let mut buffer = routing_buffer(entries.len());
+        buffer[0].nr = entries.len() as u32;
+        unsafe {
+            buffer[0].entries.as_mut_slice(entries.len())
+                .copy_from_slice(&entries);
+        }
+        vm_fd.set_gsi_routing(&buffer[0])
+    }
+}
+
+// kvm_irq_routing ends with a flexible array of entries. Allocate enough
+// kvm_irq_routing structs to hold the header followed by `count` entries.
+fn routing_buffer(count: usize) -> Vec<kvm_irq_routing> {
+    let header_size = mem::size_of::<kvm_irq_routing>();
+    let total_size = header_size + count * mem::size_of::<kvm_irq_routing_entry>();
+    let len = (total_size + header_size - 1) / header_size;
+    let mut buffer = Vec::with_capacity(len);
+    buffer.resize_with(len, kvm_irq_routing::default);
+    buffer
+}
@@ -1,14 +1,24 @@
 use std::result;
-use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
-use kvm_bindings::{CpuId, KVM_MAX_CPUID_ENTRIES, kvm_pit_config, KVM_PIT_SPEAKER_DUMMY, kvm_userspace_memory_region};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use kvm_bindings::{CpuId, KVM_MAX_CPUID_ENTRIES, kvm_pit_config, KVM_PIT_SPEAKER_DUMMY, kvm_userspace_memory_region, KVM_MEM_READONLY, kvm_enable_cap, KVM_CAP_SPLIT_IRQCHIP};
 use kvm_ioctls::{Cap, Kvm, VmFd};
 use kvm_ioctls::Cap::*;
 use crate::io::manager::IoManager;
-use crate::vm::vcpu::Vcpu;
+use crate::vm::vcpu::{Vcpu, VcpuControl};
+use crate::vm::msr::{MsrHandler, MsrPolicy, KvmRunMap};
+use crate::vm::irq_routing::{GsiRouting, MsiMessage, IOAPIC_NUM_PINS};
+use crate::system::ioctl::ioctl_with_val;
 use crate::vm::{Result, Error, ArchSetup};
 
 const KVM_API_VERSION: i32 = 12;
+
+// Not known to kvm-ioctls, so checked and enabled by number
+const KVM_CHECK_EXTENSION: libc::c_ulong = 0xAE03;
+const KVM_CAP_PMU_CAPABILITY: u32 = 232;
+const KVM_PMU_CAP_DISABLE: u64 = 1;
+const KVM_CAP_X86_USER_SPACE_MSR: u32 = 188;
+const KVM_MSR_EXIT_REASON_UNKNOWN: u64 = 1 << 1;
 type KvmResult<T> = result::Result<T, kvm_ioctls::Error>;
 
 static REQUIRED_EXTENSIONS: &[Cap] = &[
@@ -48,6 +58,9 @@ pub struct KvmVm {
     vm_fd: Arc<VmFd>,
     supported_cpuid: Arc<CpuId>,
     //supported_msrs: MsrList,
+    routing: Arc<Mutex<GsiRouting>>,
+    split_irqchip: bool,
+    msr_handler: Option<Arc<MsrHandler>>,
 }
 
 impl KvmVm {
@@ -65,7 +78,10 @@ impl KvmVm {
 
         Ok(KvmVm {
             vm_fd: Arc::new(vm_fd),
-            supported_cpuid : Arc::new(supported_cpuid)
+            supported_cpuid : Arc::new(supported_cpuid),
+            routing: Arc::new(Mutex::new(GsiRouting::new())),
+            split_irqchip: false,
+            msr_handler: None,
         })
     }
 
@@ -73,10 +89,10 @@ impl KvmVm {
         &self.vm_fd
     }
 
-    fn set_memory_region(&self, slot: u32, guest_phys_addr: u64, userspace_addr: u64, memory_size: u64) -> KvmResult<()> {
+    fn set_memory_region(&self, slot: u32, flags: u32, guest_phys_addr: u64, userspace_addr: u64, memory_size: u64) -> KvmResult<()> {
         let memory_region = kvm_userspace_memory_region {
             slot,
-            flags: 0,
+            flags,
             guest_phys_addr,
             memory_size,
             userspace_addr,
@@ -89,11 +105,17 @@ impl KvmVm {
     }
 
     pub fn add_memory_region(&self, slot: u32, guest_address: u64, host_address: u64, size: usize) -> KvmResult<()> {
-        self.set_memory_region(slot, guest_address, host_address, size as u64)
+        self.set_memory_region(slot, 0, guest_address, host_address, size as u64)
+    }
+
+    /// Map a region which the guest may only read. Guest writes to the
+    /// region exit to userspace as MMIO writes.
+    pub fn add_readonly_memory_region(&self, slot: u32, guest_address: u64, host_address: u64, size: usize) -> KvmResult<()> {
+        self.set_memory_region(slot, KVM_MEM_READONLY, guest_address, host_address, size as u64)
     }
 
     pub fn remove_memory_region(&self, slot: u32) -> KvmResult<()> {
-        self.set_memory_region(slot, 0, 0, 0)
+        self.set_memory_region(slot, 0, 0, 0, 0)
     }
 
     pub fn set_irq_line(&self, irq: u32, active: bool) -> KvmResult<()> {
@@ -104,9 +126,18 @@ impl KvmVm {
         (*self.supported_cpuid).clone()
     }
 
-    pub fn create_irqchip(&self) -> Result<()> {
+    /// Create the interrupt controllers.
+    ///
+    /// With `split` set only the local APICs are emulated by KVM and the
+    /// IOAPIC is left to userspace (see `devices::ioapic`). The PIC and PIT
+    /// are not available in this mode.
+    pub fn create_irqchip(&mut self, split: bool) -> Result<()> {
+        if split {
+            return self.create_split_irqchip();
+        }
         self.vm_fd.create_irq_chip()
             .map_err(Error::VmSetup)?;
+        *self.routing.lock().unwrap() = GsiRouting::new_with_irqchip_defaults();
 
         let pit_config = kvm_pit_config {
             flags: KVM_PIT_SPEAKER_DUMMY,
@@ -116,10 +147,128 @@ impl KvmVm {
             .map_err(Error::VmSetup)
     }
 
-    pub fn create_vcpu<A: ArchSetup>(&self, id: u64, io_manager: IoManager, shutdown: Arc<AtomicBool>, arch: &mut A) -> Result<Vcpu> {
+    fn create_split_irqchip(&mut self) -> Result<()> {
+        if !self.vm_fd.check_extension(SplitIrqchip) {
+            return Err(Error::MissingRequiredExtension(SplitIrqchip));
+        }
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_SPLIT_IRQCHIP,
+            ..Default::default()
+        };
+        cap.args[0] = IOAPIC_NUM_PINS as u64;
+        self.vm_fd.enable_cap(&cap)
+            .map_err(Error::VmSetup)?;
+        *self.routing.lock().unwrap() = GsiRouting::new();
+        self.split_irqchip = true;
+        Ok(())
+    }
+
+    /// Hide the PMU from the guest unless `enabled` is set. Must be called
+    /// before any vcpu is created.
+    ///
+    /// Without KVM_CAP_PMU_CAPABILITY the counters can only be hidden in the
+    /// CPUID given to the guest and KVM still accepts accesses to the PMU
+    /// MSRs.
+    pub fn configure_pmu(&self, enabled: bool) -> Result<()> {
+        let pmu_capability = unsafe {
+            ioctl_with_val(self.vm_fd.as_raw_fd(), KVM_CHECK_EXTENSION, KVM_CAP_PMU_CAPABILITY as libc::c_ulong)
+        };
+        if enabled {
+            if self.supported_cpuid.as_slice().iter().all(|e| e.function != 0xa || e.eax & 0xff == 0) {
+                warn!("KVM does not support a virtual PMU on this host, performance counters are not available in the guest");
+            }
+            return Ok(());
+        }
+        if let Ok(caps) = pmu_capability {
+            if caps as u64 & KVM_PMU_CAP_DISABLE != 0 {
+                let mut cap = kvm_enable_cap {
+                    cap: KVM_CAP_PMU_CAPABILITY,
+                    ..Default::default()
+                };
+                cap.args[0] = KVM_PMU_CAP_DISABLE;
+                self.vm_fd.enable_cap(&cap)
+                    .map_err(Error::VmSetup)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply `policy` to guest accesses of MSRs which KVM does not know.
+    /// Must be called before any vcpu is created.
+    pub fn configure_msr_policy(&mut self, policy: MsrPolicy) -> Result<()> {
+        if !policy.needs_exits() {
+            return Ok(());
+        }
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_X86_USER_SPACE_MSR,
+            ..Default::default()
+        };
+        cap.args[0] = KVM_MSR_EXIT_REASON_UNKNOWN;
+        self.vm_fd.enable_cap(&cap)
+            .map_err(Error::VmSetup)?;
+        self.msr_handler = Some(Arc::new(MsrHandler::new(policy)));
+        Ok(())
+    }
+
+    pub fn is_split_irqchip(&self) -> bool {
+        self.split_irqchip
+    }
+
+    /// Allocate a GSI and route it to the MSI message `msg`. The returned GSI
+    /// can be used to register an irqfd.
+    pub fn allocate_msi_route(&self, msg: MsiMessage) -> KvmResult<u32> {
+        let mut routing = self.routing.lock().unwrap();
+        let gsi = routing.allocate_msi_gsi()
+            .ok_or_else(|| kvm_ioctls::Error::new(libc::ENOSPC))?;
+        routing.set_msi_route(gsi, msg);
+        if let Err(e) = routing.commit(&self.vm_fd) {
+            routing.release_msi_gsi(gsi);
+            return Err(e);
+        }
+        Ok(gsi)
+    }
+
+    /// Change the MSI message for a GSI returned by `allocate_msi_route()`,
+    /// for example when the guest reprograms an MSI-X table entry.
+    pub fn update_msi_route(&self, gsi: u32, msg: MsiMessage) -> KvmResult<()> {
+        let mut routing = self.routing.lock().unwrap();
+        routing.set_msi_route(gsi, msg);
+        routing.commit(&self.vm_fd)
+    }
+
+    /// Remove the route of a GSI returned by `allocate_msi_route()` and make
+    /// the GSI available again.
+    pub fn release_msi_route(&self, gsi: u32) -> KvmResult<()> {
+        let mut routing = self.routing.lock().unwrap();
+        routing.release_msi_gsi(gsi);
+        routing.commit(&self.vm_fd)
+    }
+
+    /// Route IOAPIC pin `pin` to `msg`, or remove the route while the pin is
+    /// masked. Only used with a split irqchip where the routes for GSIs below
+    /// `IOAPIC_NUM_PINS` tell KVM which vectors need an EOI exit.
+    pub fn set_ioapic_route(&self, pin: u32, msg: Option<MsiMessage>) -> KvmResult<()> {
+        let mut routing = self.routing.lock().unwrap();
+        match msg {
+            Some(msg) => routing.set_msi_route(pin, msg),
+            None => routing.remove_route(pin),
+        }
+        routing.commit(&self.vm_fd)
+    }
+
+    pub fn signal_msi(&self, msg: MsiMessage) -> KvmResult<()> {
+        self.vm_fd.signal_msi(msg.to_kvm_msi())?;
+        Ok(())
+    }
+
+    pub fn create_vcpu<A: ArchSetup>(&self, id: u64, io_manager: IoManager, control: Arc<VcpuControl>, arch: &mut A) -> Result<Vcpu> {
         let vcpu_fd = self.vm_fd.create_vcpu(id)
             .map_err(Error::CreateVcpu)?;
-        let vcpu = Vcpu::new(vcpu_fd, io_manager, shutdown);
+        let msr_exits = match self.msr_handler {
+            Some(ref handler) => Some((handler.clone(), KvmRunMap::new(&vcpu_fd)?)),
+            None => None,
+        };
+        let vcpu = Vcpu::new(vcpu_fd, io_manager, control, msr_exits);
         arch.setup_vcpu(vcpu.vcpu_fd(), self.supported_cpuid().clone()).map_err(Error::ArchError)?;
         Ok(vcpu)
     }
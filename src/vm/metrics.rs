@@ -0,0 +1,110 @@
+use std::{io, thread};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::io::stats::StatsRegistry;
+use crate::vm::control::ControlServer;
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Address to serve Prometheus metrics on, parsed from either `unix:PATH`
+/// or a `HOST:PORT` TCP address (optionally prefixed with `tcp:`).
+#[derive(Clone,Debug)]
+pub enum MetricsAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl MetricsAddress {
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Some(MetricsAddress::Unix(PathBuf::from(path)))
+        } else {
+            let s = s.strip_prefix("tcp:").unwrap_or(s);
+            s.parse().ok().map(MetricsAddress::Tcp)
+        }
+    }
+}
+
+/// Serves the counters in a `StatsRegistry` in the Prometheus text format
+/// over a minimal HTTP/1.0 responder. Any request on the listener receives
+/// the full set of metrics.
+pub struct MetricsExporter {
+    stats: StatsRegistry,
+}
+
+impl MetricsExporter {
+    pub fn spawn(address: &MetricsAddress, stats: StatsRegistry) -> io::Result<()> {
+        let exporter = MetricsExporter { stats };
+        match address {
+            MetricsAddress::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        match stream {
+                            Ok(stream) => {
+                                let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+                                exporter.respond(stream)
+                            },
+                            Err(e) => warn!("metrics listener accept failed: {}", e),
+                        }
+                    }
+                });
+            }
+            MetricsAddress::Unix(path) => {
+                // Remove stale socket left behind by a previous instance
+                ControlServer::remove_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+                thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        match stream {
+                            Ok(stream) => {
+                                let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+                                exporter.respond(stream)
+                            },
+                            Err(e) => warn!("metrics listener accept failed: {}", e),
+                        }
+                    }
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn respond<S: Read + Write>(&self, mut stream: S) {
+        if let Err(e) = self.read_request(&mut stream).and_then(|_| self.write_response(&mut stream)) {
+            info!("error serving metrics request: {}", e);
+        }
+    }
+
+    // Discard the request headers. The same response is sent for any request.
+    fn read_request<S: Read>(&self, stream: &mut S) -> io::Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+            if request.len() > MAX_REQUEST_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request too large"));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_response<S: Write>(&self, stream: &mut S) -> io::Result<()> {
+        let body = self.stats.to_prometheus();
+        write!(stream, "HTTP/1.0 200 OK\r\n\
+                        Content-Type: text/plain; version=0.0.4\r\n\
+                        Content-Length: {}\r\n\
+                        Connection: close\r\n\r\n", body.len())?;
+        stream.write_all(body.as_bytes())?;
+        stream.flush()
+    }
+}
@@ -1,6 +1,14 @@
 static KERNEL: &[u8] = include_bytes!("../../kernel/ph_linux");
 static PHINIT: &[u8] = include_bytes!("../../ph-init/target/release/ph-init");
+#[cfg(feature = "wayland")]
 static SOMMELIER: &[u8] = include_bytes!("../../sommelier/build/sommelier");
+static DEFAULT_SERVICES: &[(&str, &[u8])] = &[
+    ("dbus-daemon", include_bytes!("../../ph-init/services/dbus-daemon.toml")),
+    #[cfg(feature = "wayland")]
+    ("sommelier", include_bytes!("../../ph-init/services/sommelier.toml")),
+    #[cfg(feature = "wayland")]
+    ("sommelier-x", include_bytes!("../../ph-init/services/sommelier-x.toml")),
+];
 
 pub mod arch;
 mod setup;
@@ -8,11 +16,36 @@ mod error;
 mod kernel_cmdline;
 mod config;
 mod kvm_vm;
+pub mod irq_routing;
 mod vcpu;
+mod control;
+#[cfg(feature = "network")]
+mod bridge;
+mod metrics;
+mod terminal;
+mod realm;
+mod handle;
+mod vm_ops;
+mod msr;
+mod throttle;
+mod capabilities;
+mod panic_hook;
+mod assets;
 
-pub use config::VmConfig;
+pub use config::{VmConfig, RootDevice, RomMapping, ServiceLimits};
+pub use realm::{RealmProvider, RealmInfo, RealmDisk};
+#[cfg(feature = "citadel")]
+pub use realm::CitadelRealms;
 pub use setup::VmSetup;
+pub use handle::{VmHandle, VmEvent, VmExitReason};
 pub use kvm_vm::KvmVm;
+pub use msr::MsrPolicy;
+pub use capabilities::CapabilityReport;
+pub use assets::warm_asset_caches;
+pub(crate) use vcpu::VcpuControl;
+pub use vm_ops::VmOps;
+#[cfg(feature = "mock-kvm")]
+pub use vm_ops::{MockVm, MockMemoryRegion};
 
 pub use self::error::{Result,Error};
 pub use arch::ArchSetup;
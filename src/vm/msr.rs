@@ -0,0 +1,147 @@
+use std::collections::HashSet;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::Mutex;
+
+use kvm_ioctls::VcpuFd;
+
+// Exit reasons when KVM_CAP_X86_USER_SPACE_MSR is enabled
+pub const KVM_EXIT_X86_RDMSR: u32 = 29;
+pub const KVM_EXIT_X86_WRMSR: u32 = 30;
+
+// Offset of the exit data union in struct kvm_run
+const KVM_RUN_EXIT_DATA_OFFSET: usize = 32;
+
+/// How guest accesses to MSRs unknown to KVM are handled
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum MsrPolicy {
+    /// KVM injects a #GP fault into the guest. This is what KVM does without
+    /// a policy and what a real CPU does for an MSR it does not implement.
+    Fault,
+    /// Reads return 0 and writes are discarded.
+    Ignore,
+    /// Like `Ignore`, but the first read and first write of each MSR is
+    /// logged.
+    Log,
+}
+
+impl MsrPolicy {
+    pub fn from_name(name: &str) -> Option<MsrPolicy> {
+        match name {
+            "fault" => Some(MsrPolicy::Fault),
+            "ignore" => Some(MsrPolicy::Ignore),
+            "log" => Some(MsrPolicy::Log),
+            _ => None,
+        }
+    }
+
+    /// Whether KVM must exit to pH on unknown MSR accesses
+    pub fn needs_exits(self) -> bool {
+        self != MsrPolicy::Fault
+    }
+}
+
+// struct kvm_run.msr
+#[repr(C)]
+#[allow(dead_code)]
+struct MsrExit {
+    error: u8,
+    pad: [u8; 7],
+    reason: u32,
+    index: u32,
+    data: u64,
+}
+
+///
+/// Applies an `MsrPolicy` to the MSR accesses KVM passes to userspace.
+///
+/// Shared by every vcpu so that an MSR is only logged once for the VM.
+///
+pub struct MsrHandler {
+    policy: MsrPolicy,
+    // (index, is_write) of accesses already logged
+    logged: Mutex<HashSet<(u32, bool)>>,
+}
+
+impl MsrHandler {
+    pub fn new(policy: MsrPolicy) -> Self {
+        MsrHandler {
+            policy,
+            logged: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn log_access(&self, index: u32, write: Option<u64>) {
+        if self.policy != MsrPolicy::Log {
+            return;
+        }
+        if !self.logged.lock().unwrap().insert((index, write.is_some())) {
+            return;
+        }
+        match write {
+            Some(data) => notify!("Guest wrote unknown MSR 0x{:08x} (0x{:x}), ignored", index, data),
+            None => notify!("Guest read unknown MSR 0x{:08x}, returning 0", index),
+        }
+    }
+
+    /// Complete the MSR exit `reason` described in `run`
+    pub fn handle_exit(&self, run: &KvmRunMap, reason: u32) {
+        let exit = run.msr_exit();
+        if self.policy == MsrPolicy::Fault {
+            exit.error = 1;
+            return;
+        }
+        if reason == KVM_EXIT_X86_RDMSR {
+            self.log_access(exit.index, None);
+            exit.data = 0;
+        } else {
+            self.log_access(exit.index, Some(exit.data));
+        }
+        exit.error = 0;
+    }
+}
+
+///
+/// A second mapping of the `kvm_run` structure of a vcpu, used to answer
+/// exits which `VcpuFd::run()` does not decode.
+///
+pub struct KvmRunMap {
+    addr: *mut u8,
+    size: usize,
+}
+
+unsafe impl Send for KvmRunMap {}
+unsafe impl Sync for KvmRunMap {}
+
+impl KvmRunMap {
+    pub fn new(vcpu_fd: &VcpuFd) -> io::Result<Self> {
+        let size = page_size();
+        let addr = unsafe {
+            libc::mmap(ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, vcpu_fd.as_raw_fd(), 0)
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(KvmRunMap { addr: addr as *mut u8, size })
+    }
+
+    // Only called from the thread running the vcpu while KVM_RUN is not in
+    // progress, so nothing else accesses the structure.
+    #[allow(clippy::mut_from_ref)]
+    fn msr_exit(&self) -> &mut MsrExit {
+        unsafe { &mut *(self.addr.add(KVM_RUN_EXIT_DATA_OFFSET) as *mut MsrExit) }
+    }
+}
+
+impl Drop for KvmRunMap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
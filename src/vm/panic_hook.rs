@@ -0,0 +1,129 @@
+use std::any::Any;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::panic;
+use std::sync::{Arc, Mutex, MutexGuard, Once};
+use std::thread;
+
+use termios::Termios;
+
+use crate::{Logger, LogLevel};
+use crate::vm::handle::VmExitReason;
+use crate::vm::vcpu::VcpuControl;
+
+// Prefix of the names of vcpu threads, followed by the vcpu index
+pub const VCPU_THREAD_PREFIX: &str = "vcpu";
+// Thread which stops the VM after a panic
+const EXIT_THREAD: &str = "panic-exit";
+
+lazy_static! {
+    static ref PANIC_STATE: Mutex<PanicState> = Mutex::new(PanicState::default());
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+#[derive(Default)]
+struct PanicState {
+    // Terminal settings from before the console was put in raw mode
+    termios: Option<Termios>,
+    control: Option<Arc<VcpuControl>>,
+    exit_on_device_panic: bool,
+}
+
+fn panic_state() -> MutexGuard<'static, PanicState> {
+    // Nothing panics while holding the lock, but never fail in the hook
+    PANIC_STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+///
+/// Install a panic hook which logs the panic with the name of the thread,
+/// the location and a backtrace if `RUST_BACKTRACE` is set.
+///
+/// A panic on the main thread or a vcpu thread is fatal for the VM, so the
+/// hook restores the terminal settings saved with `save_terminal()` and asks
+/// the VM registered with `watch_vm()` to exit. Device threads only stop the
+/// device, and the VM keeps running unless `exit_on_device_panic` was set.
+///
+/// The hook replaces any hook installed before, so it is only installed by
+/// `VmConfig::boot()` and not when pH is embedded with `VmConfig::start()`.
+///
+pub fn install() {
+    INSTALL_HOOK.call_once(|| {
+        panic::set_hook(Box::new(|info| {
+            let current = thread::current();
+            let name = current.name().unwrap_or("<unnamed>");
+            let location = info.location()
+                .map(|l| format!("{}:{}", l.file(), l.line()))
+                .unwrap_or_else(|| "unknown location".to_string());
+            let critical = is_critical_thread(name);
+
+            if critical {
+                restore_terminal();
+            }
+            log_panic(&format!("thread '{}' panicked at {}: {}", name, location, payload_message(info.payload())));
+            let backtrace = Backtrace::capture();
+            if backtrace.status() == BacktraceStatus::Captured {
+                log_panic(&format!("backtrace:\n{}", backtrace));
+            }
+
+            let state = panic_state();
+            // Nothing more can be done if stopping the VM itself panics
+            if let Some(control) = state.control.as_ref().filter(|_| name != EXIT_THREAD) {
+                if critical || state.exit_on_device_panic {
+                    request_exit(control.clone(), name);
+                } else {
+                    log_panic("VM keeps running without the thread");
+                }
+            }
+        }));
+    });
+}
+
+/// Remember the terminal settings to restore if the VM panics
+pub fn save_terminal(termios: Termios) {
+    panic_state().termios = Some(termios);
+}
+
+/// Stop the VM controlled by `control` on a fatal panic, and also on a panic
+/// in a device thread if `exit_on_device_panic` is set.
+pub fn watch_vm(control: Arc<VcpuControl>, exit_on_device_panic: bool) {
+    let mut state = panic_state();
+    state.control = Some(control);
+    state.exit_on_device_panic = exit_on_device_panic;
+}
+
+fn is_critical_thread(name: &str) -> bool {
+    name == "main" || name.starts_with(VCPU_THREAD_PREFIX)
+}
+
+fn restore_terminal() {
+    if let Some(termios) = panic_state().termios.as_ref() {
+        let _ = termios::tcsetattr(0, termios::TCSANOW, termios);
+    }
+}
+
+// The panicking thread may hold the logger lock, so fall back to stderr
+// rather than waiting for it
+fn log_panic(message: &str) {
+    if !Logger::try_log(LogLevel::Warn, message) {
+        eprintln!("{}", message);
+    }
+}
+
+// The panicking thread may hold the vcpu state lock, so the exit is
+// requested from another thread once that lock has been released
+fn request_exit(control: Arc<VcpuControl>, name: &str) {
+    let reason = VmExitReason::HostError(format!("thread '{}' panicked", name));
+    let _ = thread::Builder::new()
+        .name(EXIT_THREAD.to_string())
+        .spawn(move || control.request_exit(reason));
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "Box<dyn Any>"
+    }
+}
@@ -0,0 +1,80 @@
+use std::path::{Path, PathBuf};
+
+/// A disk image attached to a realm.
+#[derive(Clone,Debug)]
+pub enum RealmDisk {
+    /// A realmfs image, which is booted with writes kept in memory.
+    RealmFS(PathBuf),
+    /// A raw disk image, opened read-only if the flag is set.
+    Raw(PathBuf, bool),
+}
+
+/// What pH needs to know to boot a realm.
+#[derive(Clone,Debug)]
+pub struct RealmInfo {
+    pub name: String,
+    /// Disks in the order they are attached. The guest boots from the first one.
+    pub disks: Vec<RealmDisk>,
+    /// Directory shared with the guest as /home/user
+    pub home: PathBuf,
+    /// The guest network is attached to the bridge `vz-ZONE`
+    pub network_zone: String,
+    /// Terminal color scheme, see `VmConfig::color_scheme()`
+    pub color_scheme: Option<String>,
+}
+
+///
+/// A source of realm metadata used to look up the realm passed with `--realm`
+/// or `VmConfig::realm()`.
+///
+/// On Citadel realms are described by libcitadel (with the `citadel` feature),
+/// other systems can implement this trait to boot realms from their own
+/// configuration.
+///
+pub trait RealmProvider {
+    /// Returns `None` if there is no realm called `name`.
+    fn lookup(&self, name: &str) -> Option<RealmInfo>;
+}
+
+/// The realm provider used for the `--realm` option, if pH was built with one.
+#[cfg(feature = "citadel")]
+pub fn default_provider() -> Option<Box<dyn RealmProvider>> {
+    Some(Box::new(CitadelRealms))
+}
+
+#[cfg(not(feature = "citadel"))]
+pub fn default_provider() -> Option<Box<dyn RealmProvider>> {
+    None
+}
+
+/// Path of the realmfs image `name` on Citadel
+pub fn realmfs_image_path(name: &str) -> PathBuf {
+    Path::new("/realms/realmfs-images")
+        .join(format!("{}-realmfs.img", name))
+}
+
+/// Realms configured with the Citadel realm tools.
+#[cfg(feature = "citadel")]
+pub struct CitadelRealms;
+
+#[cfg(feature = "citadel")]
+impl RealmProvider for CitadelRealms {
+    fn lookup(&self, name: &str) -> Option<RealmInfo> {
+        let realms = match libcitadel::Realms::load() {
+            Ok(realms) => realms,
+            Err(err) => {
+                warn!("Failed to load realms: {}", err);
+                return None;
+            }
+        };
+        let realm = realms.by_name(name)?;
+        let config = realm.config();
+        Some(RealmInfo {
+            name: realm.name().to_string(),
+            disks: vec![RealmDisk::RealmFS(realmfs_image_path(config.realmfs()))],
+            home: realm.base_path().join("home"),
+            network_zone: config.network_zone().to_string(),
+            color_scheme: config.terminal_scheme().map(|s| s.to_string()),
+        })
+    }
+}
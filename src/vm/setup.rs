@@ -1,22 +1,60 @@
-use crate::vm::{VmConfig, Result, Error, PHINIT, SOMMELIER};
+use crate::vm::{VmConfig, RootDevice, Result, Error, PHINIT, DEFAULT_SERVICES};
+#[cfg(feature = "wayland")]
+use crate::vm::SOMMELIER;
 use crate::vm::arch::ArchSetup;
 use crate::vm::kernel_cmdline::KernelCmdLine;
+use crate::vm::assets::ASSETS_TAG;
 use termios::Termios;
-use crate::devices::{SyntheticFS, VirtioBlock, VirtioNet, VirtioP9, VirtioRandom, VirtioSerial, VirtioWayland};
-use std::{env, fs, thread};
-use crate::system::{Tap, NetlinkSocket};
+use crate::devices::{ShareControl, SyntheticFS, VirtioBlock, VirtioP9, VirtioRandom, VirtioSerial};
+#[cfg(feature = "network")]
+use crate::devices::{NetControl, VirtioNet};
+#[cfg(feature = "wayland")]
+use crate::devices::{VirtioWayland, WaylandControl};
+use std::{fs, thread};
+#[cfg(feature = "network")]
+use crate::system::{MacVTapBackend, NetBackend, Tap, NetlinkSocket, net_helper};
 use crate::disk::DiskImage;
-use std::sync::{Arc, Barrier, Mutex};
-use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Barrier};
+use std::path::PathBuf;
+#[cfg(feature = "network")]
+use std::net::Ipv4Addr;
 use kvm_ioctls::VmFd;
 use vm_memory::GuestMemoryMmap;
-use vmm_sys_util::eventfd::EventFd;
+#[cfg(feature = "audio")]
+use std::{env, sync::Mutex};
+#[cfg(feature = "audio")]
 use crate::devices::ac97::Ac97Dev;
+#[cfg(feature = "audio")]
+use crate::audio::AudioControl;
 use crate::devices::serial::SerialPort;
 use crate::io::manager::IoManager;
 use crate::{Logger, LogLevel};
 use crate::vm::kvm_vm::KvmVm;
-use crate::vm::vcpu::Vcpu;
+use crate::vm::vcpu::{Vcpu, VcpuControl};
+use crate::vm::handle::VmHandle;
+use crate::vm::throttle;
+#[cfg(feature = "network")]
+use crate::vm::capabilities;
+use crate::vm::panic_hook;
+use crate::vm::control::ControlServer;
+#[cfg(feature = "network")]
+use crate::vm::bridge::BridgeControl;
+use crate::vm::metrics::{MetricsAddress, MetricsExporter};
+use crate::system::limits;
+use crate::system::fd_audit;
+
+// Directory of the boot filesystem where ph-init reads service definitions
+const SERVICES_DIR: &str = "/etc/ph-init/services.d";
+// Guest physical address layout read by ph-init, in the boot filesystem
+const LAYOUT_DIR: &str = "/etc/ph-init";
+const LAYOUT_FILE: &str = "layout";
+
+// Address of the guest on the bridge network
+#[cfg(feature = "network")]
+const DEFAULT_GUEST_IP: Ipv4Addr = Ipv4Addr::new(172, 17, 0, 22);
+
+// Warn if fewer file descriptors than this can be opened
+const MIN_NOFILE_LIMIT: u64 = 4096;
 
 pub struct Vm {
     kvm_vm: KvmVm,
@@ -24,12 +62,14 @@ pub struct Vm {
     memory: GuestMemoryMmap,
     io_manager: IoManager,
     termios: Option<Termios>,
+    control: Arc<VcpuControl>,
+    sockets: Vec<PathBuf>,
 }
 
 impl Vm {
-    fn create<A: ArchSetup>(arch: &mut A) -> Result<Self> {
-        let kvm_vm = KvmVm::open()?;
-        kvm_vm.create_irqchip()?;
+    fn create<A: ArchSetup>(arch: &mut A, config: &VmConfig) -> Result<Self> {
+        let mut kvm_vm = KvmVm::open()?;
+        kvm_vm.create_irqchip(config.is_split_irqchip())?;
         kvm_vm.vm_fd().set_tss_address(0xfffbd000)
             .map_err(Error::KvmError)?;
 
@@ -38,37 +78,63 @@ impl Vm {
 
         let io_manager = IoManager::new(kvm_vm.clone(), memory.clone());
 
+        let mut control = VcpuControl::new(config.get_max_cpus(), config.ncpus())?;
+        control.set_cpu_quota(config.get_cpu_quota_percent());
+
         Ok(Vm {
             kvm_vm,
             memory,
             io_manager,
             vcpus: Vec::new(),
             termios: None,
+            control: Arc::new(control),
+            sockets: Vec::new(),
         })
     }
 
-    pub fn start(&mut self) -> Result<()> {
+    /// Start running the vcpus and return a handle for controlling the VM
+    /// without waiting for it to stop.
+    pub fn start(mut self) -> VmHandle {
         let barrier = Arc::new(Barrier::new(self.vcpus.len()));
         let mut handles = Vec::new();
-        for vcpu in self.vcpus.drain(..) {
-            let h = thread::spawn({
-                let barrier = barrier.clone();
-                move || {
-                    vcpu.run(&barrier);
-                }
-            });
+        for (id, vcpu) in self.vcpus.drain(..).enumerate() {
+            let h = thread::Builder::new()
+                .name(format!("{}{}", panic_hook::VCPU_THREAD_PREFIX, id))
+                .spawn({
+                    let barrier = barrier.clone();
+                    move || {
+                        vcpu.run(&barrier);
+                    }
+                })
+                .expect("failed to spawn vcpu thread");
             handles.push(h);
         }
-
-        for h in handles {
-            h.join().expect("...");
+        let control = self.control.clone();
+        if control.cpu_quota().is_some() {
+            throttle::spawn_ticker(control.clone());
         }
+        VmHandle::new(self, handles, control)
+    }
+
+    pub fn restore_terminal(&self) -> Result<()> {
         if let Some(termios) = self.termios {
-            let _ = termios::tcsetattr(0, termios::TCSANOW, &termios)
+            termios::tcsetattr(0, termios::TCSANOW, &termios)
                 .map_err(Error::TerminalTermios)?;
         }
         Ok(())
+    }
 
+    pub fn shutdown_devices(&self) {
+        self.io_manager.shutdown_virtio_devices();
+    }
+
+    /// Remove the control and metrics sockets so that they do not outlive the VM
+    pub fn remove_sockets(&self) {
+        for path in &self.sockets {
+            if let Err(e) = ControlServer::remove_socket(path) {
+                warn!("failed to remove socket {}: {}", path.display(), e);
+            }
+        }
     }
 
     pub fn vm_fd(&self) -> &VmFd {
@@ -85,6 +151,15 @@ pub struct VmSetup <T: ArchSetup> {
     config: VmConfig,
     cmdline: KernelCmdLine,
     arch: T,
+    #[cfg(feature = "network")]
+    net_control: Option<Arc<NetControl>>,
+    #[cfg(feature = "network")]
+    bridge_control: Option<Arc<BridgeControl>>,
+    #[cfg(feature = "wayland")]
+    wayland_control: Option<Arc<WaylandControl>>,
+    #[cfg(feature = "audio")]
+    audio_control: Option<Arc<AudioControl>>,
+    home_control: Option<Arc<ShareControl>>,
 }
 
 impl <T: ArchSetup> VmSetup <T> {
@@ -94,15 +169,76 @@ impl <T: ArchSetup> VmSetup <T> {
             config,
             cmdline: KernelCmdLine::new_default(),
             arch,
+            #[cfg(feature = "network")]
+            net_control: None,
+            #[cfg(feature = "network")]
+            bridge_control: None,
+            #[cfg(feature = "wayland")]
+            wayland_control: None,
+            #[cfg(feature = "audio")]
+            audio_control: None,
+            home_control: None,
         }
     }
 
     pub fn create_vm(&mut self) -> Result<Vm> {
-        let exit_evt = EventFd::new(libc::EFD_NONBLOCK)?;
-        let mut vm = Vm::create(&mut self.arch)?;
+        if self.config.is_audit_fds() {
+            fd_audit::enable();
+        }
+        Self::raise_fd_limit();
+        let ncpus = self.config.ncpus();
+        let max_cpus = self.config.get_max_cpus();
+        let mut vm = Vm::create(&mut self.arch, &self.config)?;
+
+        vm.kvm_vm.configure_pmu(self.config.is_pmu_enabled())?;
+        vm.kvm_vm.configure_msr_policy(self.config.get_msr_policy())?;
+
+        let reset_evt = vm.control.reset_event()?;
+        let shutdown_evt = vm.control.shutdown_event()?;
+        vm.io_manager.register_legacy_devices(reset_evt, shutdown_evt, self.config.is_rtc_localtime());
+        vm.io_manager.register_boot_complete(vm.control.clone());
+        vm.io_manager.register_memory_stats();
+        self.cmdline.push("phinit.memory_stats");
+
+        // The extra vcpus are present but only the first ncpus are brought
+        // up at boot
+        if max_cpus > ncpus {
+            vm.io_manager.register_cpu_hotplug(vm.control.clone());
+            self.cmdline.push_set_val("maxcpus", &ncpus.to_string());
+            self.cmdline.push("phinit.cpu_hotplug");
+        }
+
+        for (name, placement) in self.config.device_placements() {
+            vm.io_manager.set_device_placement(name, *placement)
+                .map_err(|e| Error::DevicePlacement(name.clone(), e))?;
+        }
+
+        for (name, priority) in self.config.thread_priorities() {
+            vm.io_manager.set_thread_priority(name, *priority);
+        }
 
-        let reset_evt = exit_evt.try_clone()?;
-        vm.io_manager.register_legacy_devices(reset_evt);
+        for (name, base, size) in self.config.reserved_memory() {
+            vm.io_manager.reserve_memory(name, *base, *size)
+                .map_err(Error::MemoryReservation)?;
+        }
+
+        for (name, path, base, mapping) in self.config.rom_images() {
+            vm.io_manager.add_rom(name, *base, path, *mapping)
+                .map_err(|e| Error::RomImage(name.clone(), e))?;
+        }
+
+        if let Some(path) = self.config.get_firmware() {
+            vm.io_manager.add_firmware(path)
+                .map_err(Error::Firmware)?;
+        }
+
+        if self.config.is_strict_dma() {
+            if self.config.get_firmware().is_some() {
+                warn!("Ignoring --strict-dma, the firmware builds the guest memory map");
+            } else {
+                vm.io_manager.enable_strict_dma();
+            }
+        }
 
 
         if self.config.verbose() {
@@ -122,14 +258,41 @@ impl <T: ArchSetup> VmSetup <T> {
         if let Some(realm) = self.config.realm_name() {
             self.cmdline.push_set_val("phinit.realm", realm);
         }
+        self.cmdline.push_set_val("phinit.hostname", &self.config.get_hostname());
+        self.cmdline.push_set_val("phinit.machine_id", &self.config.get_machine_id());
+        if let Some(tz) = self.config.get_timezone() {
+            self.cmdline.push_set_val("phinit.timezone", &tz);
+        }
+        if let Some(command) = self.config.get_guest_command() {
+            self.cmdline.push_set_val("phinit.run", command);
+        }
+        for (name, size) in self.config.get_tmpfs_sizes() {
+            self.cmdline.push_set_val(&format!("phinit.tmpfs.{}", name), size);
+        }
+        if let Some(size) = self.config.get_zram_swap() {
+            self.cmdline.push_set_val("phinit.zram", &size.to_string());
+        }
+        for (name, limits) in self.config.get_service_limits() {
+            if let Some(memory) = limits.memory {
+                self.cmdline.push_set_val(&format!("phinit.cgroup.{}.memory", name), &memory.to_string());
+            }
+            if let Some(percent) = limits.cpu_percent {
+                self.cmdline.push_set_val(&format!("phinit.cgroup.{}.cpu", name), &percent.to_string());
+            }
+        }
 
-        let saved= Termios::from_fd(0)
-            .map_err(Error::TerminalTermios)?;
-        vm.termios = Some(saved);
+        // Not a terminal when pH is embedded in a program running without one
+        if unsafe { libc::isatty(0) } == 1 {
+            let saved= Termios::from_fd(0)
+                .map_err(Error::TerminalTermios)?;
+            vm.termios = Some(saved);
+            panic_hook::save_terminal(saved);
+        }
 
         self.setup_synthetic_bootfs(&mut vm.io_manager)?;
-        self.setup_virtio(&mut vm.io_manager)?;
+        self.setup_virtio(&mut vm.io_manager, &vm.control)?;
 
+        #[cfg(feature = "audio")]
         if self.config.is_audio_enable() {
 
             if unsafe { libc::geteuid() } == 0 {
@@ -139,7 +302,9 @@ impl <T: ArchSetup> VmSetup <T> {
             env::set_var("XDG_RUNTIME_DIR", "/run/user/1000");
             let irq = vm.io_manager.allocator().allocate_irq();
             // XXX expect()
-            let ac97 = Ac97Dev::try_new(&vm.kvm_vm, irq, vm.guest_memory()).expect("audio initialize error");
+            let stats = vm.io_manager.stats().register_device("audio");
+            let ac97 = Ac97Dev::try_new(vm.io_manager.irqs(), irq, vm.guest_memory(), self.config.get_audio_latency(), self.config.get_audio_effects(), self.config.get_thread_priority("audio"), &stats).expect("audio initialize error");
+            self.audio_control = Some(ac97.audio_control());
             vm.io_manager.add_pci_device(Arc::new(Mutex::new(ac97)));
 
         }
@@ -148,62 +313,130 @@ impl <T: ArchSetup> VmSetup <T> {
             self.cmdline.push_set_val("init", init_cmd);
         }
 
+        vm.sockets = self.setup_control(&vm.io_manager, &vm.control)?;
+
         let pci_irqs = vm.io_manager.pci_irqs();
-        self.arch.setup_memory(&self.cmdline, &pci_irqs)
+        let reserved = vm.io_manager.address_map().reserved_ranges();
+        self.arch.setup_memory(&self.cmdline, &pci_irqs, &reserved)
             .map_err(Error::ArchError)?;
 
-        let shutdown = Arc::new(AtomicBool::new(false));
-        for id in 0..self.config.ncpus() {
-            let vcpu = vm.kvm_vm.create_vcpu(id as u64, vm.io_manager.clone(), shutdown.clone(), &mut self.arch)?;
+        for id in 0..max_cpus {
+            let vcpu = vm.kvm_vm.create_vcpu(id as u64, vm.io_manager.clone(), vm.control.clone(), &mut self.arch)?;
             vm.vcpus.push(vcpu);
         }
+        report_fd_audit("after setup");
         Ok(vm)
     }
 
-    fn setup_virtio(&mut self, io_manager: &mut IoManager) -> Result<()> {
-        io_manager.add_virtio_device(VirtioSerial::new())?;
+    /// Choose the disk to boot from, returning its index and whether it is
+    /// read-only, or `None` to boot from the host root filesystem.
+    fn select_root_disk(&self, disks: &[&dyn DiskImage]) -> Result<Option<(usize, bool)>> {
+        let index = match self.config.get_root_device() {
+            None if disks.is_empty() => return Ok(None),
+            None => 0,
+            Some(RootDevice::Index(index)) => {
+                if *index >= disks.len() {
+                    return Err(Error::RootDevice(format!("disk index {} requested but there are {} disks", index, disks.len())));
+                }
+                *index
+            }
+            Some(RootDevice::HostRoot) => return Ok(None),
+            Some(RootDevice::Label(label)) => {
+                disks.iter().position(|d| d.label() == Some(label.as_str()))
+                    .or_else(|| disks.iter().position(|d| d.volume_label().as_deref() == Some(label.as_str())))
+                    .ok_or_else(|| Error::RootDevice(format!("no disk with label {}", label)))?
+            }
+        };
+        Ok(Some((index, disks[index].read_only())))
+    }
+
+    fn setup_virtio(&mut self, io_manager: &mut IoManager, control: &Arc<VcpuControl>) -> Result<()> {
+        io_manager.add_virtio_device(VirtioSerial::new(self.config.console_options(), control.clone()))?;
         io_manager.add_virtio_device(VirtioRandom::new())?;
 
+        #[cfg(feature = "wayland")]
         if self.config.is_wayland_enabled() {
             let dev_shm_manager = io_manager.dev_shm_manager().clone();
-            io_manager.add_virtio_device(VirtioWayland::new(self.config.is_dmabuf_enabled(), dev_shm_manager))?;
+            let wayland = VirtioWayland::new(self.config.is_dmabuf_enabled(), self.config.get_wl_max_vfds(), self.config.get_wayland_socket(), dev_shm_manager);
+            let wl_control = wayland.wayland_control();
+            for (name, path) in self.config.get_wayland_named_sockets() {
+                wl_control.add_named_socket(name, path.clone());
+            }
+            self.wayland_control = Some(wl_control);
+            io_manager.add_virtio_device(wayland)?;
         }
 
         let homedir = self.config.homedir();
-        io_manager.add_virtio_device(VirtioP9::new_filesystem("home", homedir, false, false))?;
+        let mut home = VirtioP9::new_filesystem("home", homedir, false, false)?;
+        if let Some(limits) = self.config.get_home_quota() {
+            home.set_quota(limits)?;
+        }
+        if let Some(limits) = self.config.get_home_fid_limits() {
+            home.set_fid_limits(limits);
+        }
+        self.home_control = home.share_control();
+        io_manager.add_virtio_device(home)?;
         if homedir != "/home/user" && !self.config.is_realm() {
             self.cmdline.push_set_val("phinit.home", homedir);
         }
 
-        let mut block_root = None;
+        if let Some(assets) = self.config.get_assets_dir() {
+            io_manager.add_virtio_device(VirtioP9::new_filesystem(ASSETS_TAG, assets, true, false)?)?;
+            self.cmdline.push("phinit.assets");
+        }
 
-        for disk in self.config.get_realmfs_images() {
-            if block_root == None {
-                block_root = Some(disk.read_only());
-            }
-            io_manager.add_virtio_device(VirtioBlock::new(disk))?;
+        let mut realmfs_images = self.config.get_realmfs_images();
+        let mut raw_disks = self.config.get_raw_disk_images();
+        let mut host_disks = self.config.get_host_block_devices();
+
+        // Open and lock the images now rather than when the guest driver
+        // starts the devices, so that an image in use by another VM stops
+        // this one from starting
+        for disk in realmfs_images.iter_mut() {
+            disk.open().map_err(Error::DiskImage)?;
+        }
+        for disk in raw_disks.iter_mut().chain(host_disks.iter_mut()) {
+            disk.open().map_err(Error::DiskImage)?;
         }
 
-        for disk in self.config.get_raw_disk_images() {
-            if block_root == None {
-                block_root = Some(disk.read_only());
-            }
-            io_manager.add_virtio_device(VirtioBlock::new(disk))?;
+        let disks: Vec<&dyn DiskImage> = realmfs_images.iter().map(|d| d as &dyn DiskImage)
+            .chain(raw_disks.iter().map(|d| d as &dyn DiskImage))
+            .collect();
+        let block_root = self.select_root_disk(&disks)?;
+
+        // PCI slot of each block device, in the same order as `disks`
+        let mut block_slots = Vec::new();
+        for disk in realmfs_images {
+            block_slots.push(io_manager.add_virtio_device(VirtioBlock::new(disk))?);
+        }
+        for disk in raw_disks {
+            block_slots.push(io_manager.add_virtio_device(VirtioBlock::new(disk))?);
+        }
+        // Host block devices come last so that they never change the index of
+        // another disk, and are left out of the choice of root disk
+        for disk in host_disks {
+            block_slots.push(io_manager.add_virtio_device(VirtioBlock::new(disk))?);
         }
 
-        if let Some(read_only) = block_root {
+        if let Some((index, read_only)) = block_root {
             if !read_only {
                 self.cmdline.push("phinit.root_rw");
             }
-            self.cmdline.push("phinit.root=/dev/vda");
+            // The guest names block devices in the order it finds them on the
+            // PCI bus, which differs from the order they were added when
+            // --pci-slot places a block device
+            let root_slot = block_slots[index];
+            let guest_index = block_slots.iter().filter(|&&slot| slot < root_slot).count();
+            self.cmdline.push_set_val("phinit.root", &virtio_disk_name(guest_index));
             self.cmdline.push("phinit.rootfstype=ext4");
         } else {
-            io_manager.add_virtio_device(VirtioP9::new_filesystem("9proot", "/", true, false))?;
+            io_manager.add_virtio_device(VirtioP9::new_filesystem("9proot", "/", true, false)?)?;
             self.cmdline.push_set_val("phinit.root", "9proot");
             self.cmdline.push_set_val("phinit.rootfstype", "9p");
             self.cmdline.push_set_val("phinit.rootflags", "trans=virtio");
         }
 
+        #[cfg(feature = "network")]
         if self.config.network() {
             self.setup_network(io_manager)?;
             self.drop_privs();
@@ -212,6 +445,61 @@ impl <T: ArchSetup> VmSetup <T> {
         Ok(())
     }
 
+    // Returns the paths of the unix sockets created, to be removed when the VM stops
+    fn setup_control(&self, io_manager: &IoManager, vcpu_control: &Arc<VcpuControl>) -> Result<Vec<PathBuf>> {
+        let mut sockets = Vec::new();
+        if let Some(path) = self.config.get_control_socket() {
+            let mut server = ControlServer::bind(path, io_manager.clone())
+                .map_err(Error::ControlSocket)?;
+            let cpu_hotplug = vcpu_control.max_cpus() > vcpu_control.online_cpus();
+            server.set_vcpu_control(vcpu_control.clone(), cpu_hotplug);
+            #[cfg(feature = "network")]
+            if let Some(control) = &self.net_control {
+                server.set_net_control(control.clone());
+            }
+            #[cfg(feature = "network")]
+            if let Some(control) = &self.bridge_control {
+                server.set_bridge_control(control.clone());
+            }
+            #[cfg(feature = "wayland")]
+            if let Some(control) = &self.wayland_control {
+                server.set_wayland_control(control.clone());
+            }
+            #[cfg(feature = "audio")]
+            if let Some(control) = &self.audio_control {
+                server.set_audio_control(control.clone());
+            }
+            if let Some(control) = &self.home_control {
+                server.set_home_control(control.clone());
+            }
+            server.spawn();
+            sockets.push(path.to_path_buf());
+        }
+        if let Some(address) = self.config.get_metrics_address() {
+            let address = MetricsAddress::parse(address)
+                .ok_or_else(|| Error::MetricsAddress(address.to_string()))?;
+            MetricsExporter::spawn(&address, io_manager.stats().clone())
+                .map_err(Error::MetricsListener)?;
+            if let MetricsAddress::Unix(path) = address {
+                sockets.push(path);
+            }
+        }
+        Ok(sockets)
+    }
+
+    // Every virtio-wl vfd, disk image, eventfd and tap device holds open file
+    // descriptors, so allow as many as the hard limit permits.
+    fn raise_fd_limit() {
+        match limits::raise_nofile_limit() {
+            Ok(n) if n < MIN_NOFILE_LIMIT => {
+                warn!("Open file limit is only {}, devices may fail to allocate resources", n);
+            }
+            Ok(_) => {},
+            Err(err) => warn!("Failed to raise open file limit: {}", err),
+        }
+    }
+
+    #[cfg(any(feature = "audio", feature = "network"))]
     fn drop_privs(&self) {
         unsafe {
             libc::setgid(1000);
@@ -223,7 +511,8 @@ impl <T: ArchSetup> VmSetup <T> {
     }
 
     fn setup_synthetic_bootfs(&mut self, io_manager: &mut IoManager) -> Result<()> {
-        let bootfs = self.create_bootfs()
+        let layout = io_manager.address_map().layout_file();
+        let bootfs = self.create_bootfs(layout)
             .map_err(Error::SetupBootFs)?;
 
         io_manager.add_virtio_device(VirtioP9::new(bootfs, "/dev/root", "/", false))?;
@@ -236,7 +525,7 @@ impl <T: ArchSetup> VmSetup <T> {
         Ok(())
     }
 
-    fn create_bootfs(&self) -> std::io::Result<SyntheticFS> {
+    fn create_bootfs(&self, layout: String) -> std::io::Result<SyntheticFS> {
         let mut s = SyntheticFS::new();
         s.mkdirs(&["/tmp", "/proc", "/sys", "/dev", "/home/user", "/bin", "/etc"]);
 
@@ -245,37 +534,181 @@ impl <T: ArchSetup> VmSetup <T> {
         fs::remove_file("/tmp/ph-init")?;
 
         s.add_memory_file("/usr/bin", "ph-init", 0o755, PHINIT)?;
+        #[cfg(feature = "wayland")]
         s.add_memory_file("/usr/bin", "sommelier", 0o755, SOMMELIER)?;
 
         s.add_file("/etc", "ld.so.cache", 0o644, "/etc/ld.so.cache");
         s.add_file("/etc", "resolv.conf", 0o644, "/run/NetworkManager/resolv.conf");
+
+        // Services added to the configuration replace default services with the same name
+        for &(name, definition) in DEFAULT_SERVICES {
+            s.add_memory_file(SERVICES_DIR, format!("{}.toml", name), 0o644, definition)?;
+        }
+        for (name, definition) in self.config.get_guest_services() {
+            // Memory files are never freed, they are served for the life of the VM
+            let definition: &'static [u8] = Box::leak(definition.clone().into_bytes().into_boxed_slice());
+            s.add_memory_file(SERVICES_DIR, format!("{}.toml", name), 0o644, definition)?;
+        }
+        let layout: &'static [u8] = Box::leak(layout.into_bytes().into_boxed_slice());
+        s.add_memory_file(LAYOUT_DIR, LAYOUT_FILE, 0o644, layout)?;
         Ok(s)
     }
 
+    #[cfg(feature = "network")]
     fn setup_network(&mut self, io_manager: &mut IoManager) -> Result<()> {
-        let tap = match self.setup_tap() {
-            Ok(tap) => tap,
-            Err(e) => {
-                warn!("failed to create tap device: {}", e);
+        if let Some(name) = self.config.macvtap_name() {
+            let macvtap = MacVTapBackend::open(name).map_err(|e| Error::NetworkUnavailable(
+                format!("cannot open macvtap interface {}: {} (the user needs read and write access to /dev/tapN \
+                         where N is the ifindex of the interface)", name, e)))?;
+            self.set_net_mtu(macvtap.name());
+            self.add_net_device(io_manager, VirtioNet::new(macvtap)?)?;
+        } else if let Some(name) = self.config.tap_name() {
+            let tap = Tap::attach(name, self.config.get_tap_multi_queue()).map_err(|e| Error::NetworkUnavailable(
+                format!("cannot attach to tap device {}: {} (create it with \
+                         'ip tuntap add dev {} mode tap vnet_hdr user USER')", name, e, name)))?;
+            self.set_net_mtu(tap.name());
+            self.add_net_device(io_manager, VirtioNet::new(tap)?)?;
+        } else if let Some(command) = self.config.get_net_helper() {
+            report_fd_audit("before starting network helper");
+            let tap = net_helper::request_tap(command, self.config.bridge()).map_err(|e| Error::NetworkUnavailable(
+                format!("network helper '{}' failed: {}", command, e)))?;
+            self.bridge_control = Self::bridge_control(&tap, self.config.bridge(), Some(command));
+            self.set_net_mtu(tap.name());
+            self.add_net_device(io_manager, VirtioNet::new(tap)?)?;
+        } else {
+            if !capabilities::has_net_admin() {
+                warn!("Networking disabled: creating a tap device requires CAP_NET_ADMIN. Run pH as root, \
+                       pass --tap NAME with a persistent tap device owned by this user, use a privileged \
+                       helper with --net-helper, or use --no-network");
                 return Ok(());
             }
+            let tap = self.setup_tap().map_err(|e| Error::NetworkUnavailable(
+                format!("failed to create tap device: {}", e)))?;
+            self.bridge_control = Self::bridge_control(&tap, self.config.bridge(), None);
+            self.set_net_mtu(tap.name());
+            self.add_net_device(io_manager, VirtioNet::new(tap)?)?;
+        }
+        // The bridge network has a fixed address for the guest, a macvtap
+        // interface is on the LAN of the host where it would be wrong
+        let ip = match self.config.get_guest_ip() {
+            Some(ip) => Some(ip),
+            None if self.config.macvtap_name().is_some() => None,
+            None => Some(DEFAULT_GUEST_IP),
         };
-        io_manager.add_virtio_device(VirtioNet::new(tap))?;
-        self.cmdline.push("phinit.ip=172.17.0.22");
+        if let Some(ip) = ip {
+            self.cmdline.push_set_val("phinit.ip", &ip.to_string());
+        }
+        if let Some(id) = self.config.get_guest_vlan() {
+            self.cmdline.push_set_val("phinit.vlan", &id.to_string());
+        }
+        self.setup_dns();
         Ok(())
     }
 
+    // Set the MTU from --net-mtu on the host interface before the virtio-net
+    // device reads it to offer to the guest, and have ph-init set it on eth0
+    // for guest drivers which ignore the offer. Without CAP_NET_ADMIN this
+    // fails for an interface pH did not create, and the guest keeps the MTU
+    // of the interface.
+    #[cfg(feature = "network")]
+    fn set_net_mtu(&mut self, ifname: &str) {
+        let mtu = match self.config.get_net_mtu() {
+            Some(mtu) => mtu,
+            None => return,
+        };
+        match NetlinkSocket::open().and_then(|nl| nl.set_mtu(ifname, mtu)) {
+            Ok(()) => { self.cmdline.push_set_val("phinit.mtu", &mtu.to_string()); },
+            Err(e) => warn!("failed to set the MTU of {} to {}: {}", ifname, mtu, e),
+        }
+    }
+
+    // Without explicit settings the guest uses the resolv.conf of the host
+    // from /opt/ph/etc and follows changes to it
+    #[cfg(feature = "network")]
+    fn setup_dns(&mut self) {
+        let servers = self.config.get_dns_servers();
+        if !servers.is_empty() {
+            let servers = servers.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+            self.cmdline.push_set_val("phinit.dns", &servers.join(","));
+        }
+        let search = self.config.get_dns_search();
+        if !search.is_empty() {
+            self.cmdline.push_set_val("phinit.dns_search", &search.join(","));
+        }
+    }
+
+    #[cfg(feature = "network")]
+    fn add_net_device<B: NetBackend + 'static>(&mut self, io_manager: &mut IoManager, dev: VirtioNet<B>) -> Result<()> {
+        let control = dev.net_control();
+        if let Some(limit) = self.config.get_net_tx_limit() {
+            control.set_tx_limit(Some(limit));
+        }
+        if let Some(size) = self.config.get_net_capture_size() {
+            control.set_capture_size(size);
+        }
+        control.set_check_checksums(self.config.is_net_check_checksums());
+        if let Some(path) = self.config.get_net_capture() {
+            if let Err(e) = control.start_capture(path) {
+                warn!("failed to open network capture file {}: {}", path.display(), e);
+            }
+        }
+        self.net_control = Some(control);
+        io_manager.add_virtio_device(dev)?;
+        Ok(())
+    }
+
+    // Only a tap device pH created, directly or with the network helper, is
+    // added to a bridge again by the `reload` control command.
+    #[cfg(feature = "network")]
+    fn bridge_control(tap: &Tap, bridge: &str, helper: Option<&str>) -> Option<Arc<BridgeControl>> {
+        match tap.try_clone_file() {
+            Ok(file) => Some(Arc::new(BridgeControl::new(file, bridge, helper))),
+            Err(e) => {
+                warn!("failed to duplicate tap device {}: {}", tap.name(), e);
+                None
+            }
+        }
+    }
+
+    #[cfg(feature = "network")]
     fn setup_tap(&self) -> Result<Tap> {
-        let bridge_name = self.config.bridge();
         let tap = Tap::new_default()?;
         let nl = NetlinkSocket::open()?;
+        nl.join_bridge(tap.name(), self.config.bridge())?;
+        Ok(tap)
+    }
+}
 
-        if !nl.interface_exists(bridge_name) {
-            nl.create_bridge(bridge_name)?;
-            nl.set_interface_up(bridge_name)?;
+// Name of the virtio block device with the given index as assigned by the
+// guest kernel: vda to vdz, then vdaa, vdab and so on
+fn virtio_disk_name(index: usize) -> String {
+    let mut suffix = Vec::new();
+    let mut n = index + 1;
+    while n > 0 {
+        n -= 1;
+        suffix.insert(0, b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    format!("/dev/vd{}", String::from_utf8_lossy(&suffix))
+}
+
+// With --audit-fds, log each file descriptor which a program started now
+// would inherit.
+fn report_fd_audit(when: &str) {
+    if !fd_audit::is_enabled() {
+        return;
+    }
+    let fds = match fd_audit::open_fds() {
+        Ok(fds) => fds,
+        Err(e) => {
+            warn!("fd audit ({}): failed to list open file descriptors: {}", when, e);
+            return;
         }
-        nl.add_interface_to_bridge(tap.name(), bridge_name)?;
-        nl.set_interface_up(tap.name())?;
-        Ok(tap)
+    };
+    let leaked: Vec<_> = fds.iter().filter(|f| !f.cloexec && f.fd > 2).collect();
+    notify!("fd audit ({}): {} open, {} without close-on-exec", when, fds.len(), leaked.len());
+    for f in leaked {
+        warn!("fd audit: fd {} ({}) is not close-on-exec, created by {}",
+              f.fd, f.target, f.origin.unwrap_or("unknown"));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,75 @@
+#[cfg(feature = "terminal-theme")]
+use libcitadel::terminal::{TerminalPalette, AnsiTerminal, Base16Scheme};
+
+///
+/// Applies a color scheme to the terminal the console is running in for as
+/// long as the VM runs.
+///
+/// The current palette is saved when the scheme is applied and restored when
+/// the `TerminalTheme` is dropped. Without the `terminal-theme` feature color
+/// schemes are ignored and the terminal is not touched.
+///
+pub struct TerminalTheme {
+    #[cfg(feature = "terminal-theme")]
+    saved: Option<TerminalPalette>,
+}
+
+#[cfg(feature = "terminal-theme")]
+impl TerminalTheme {
+    pub fn apply(scheme_name: &str) -> Self {
+        let saved = Self::save();
+        match (Base16Scheme::by_name(scheme_name), AnsiTerminal::new()) {
+            (Some(scheme), Ok(mut term)) => if let Err(err) = term.apply_base16(scheme) {
+                warn!("Failed to set terminal color scheme: {}", err);
+            },
+            (None, _) => warn!("Unknown terminal color scheme: {}", scheme_name),
+            (_, Err(err)) => warn!("Failed to set terminal color scheme: {}", err),
+        }
+        TerminalTheme { saved }
+    }
+
+    fn save() -> Option<TerminalPalette> {
+        let mut term = match AnsiTerminal::new() {
+            Ok(term) => term,
+            Err(e) => {
+                warn!("failed to open terminal: {}", e);
+                return None;
+            }
+        };
+
+        let mut palette = TerminalPalette::default();
+        if let Err(e) = palette.load(&mut term) {
+            warn!("failed to load palette: {}", e);
+            return None;
+        }
+        if let Err(e) = term.clear_screen() {
+            warn!("failed to clear screen: {}", e);
+            return None;
+        }
+        Some(palette)
+    }
+
+    fn restore(&self) {
+        if let Some(p) = self.saved.as_ref() {
+            if let Ok(mut term) = AnsiTerminal::new() {
+                let _ = p.apply(&mut term);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "terminal-theme"))]
+impl TerminalTheme {
+    pub fn apply(scheme_name: &str) -> Self {
+        let _ = scheme_name;
+        TerminalTheme {}
+    }
+
+    fn restore(&self) {}
+}
+
+impl Drop for TerminalTheme {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
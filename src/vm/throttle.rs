@@ -0,0 +1,66 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::vm::vcpu::VcpuControl;
+
+// Length of the accounting period, and how often running vcpus are
+// interrupted so that their usage is checked
+const QUOTA_PERIOD: Duration = Duration::from_millis(100);
+
+fn thread_cpu_time() -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts);
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+///
+/// Limits the host CPU time used by a vcpu thread to `percent` of one host
+/// CPU without relying on cgroups.
+///
+/// `throttle()` is called by the vcpu thread before every KVM_RUN. When the
+/// thread has used more CPU time in the current period than its quota allows
+/// it sleeps until its usage is back within the quota. A vcpu which runs
+/// without exiting to pH is interrupted every period by `spawn_ticker()`.
+///
+pub struct VcpuThrottle {
+    percent: u32,
+    period_start: Instant,
+    cpu_start: Duration,
+}
+
+impl VcpuThrottle {
+    pub fn new(percent: u32) -> Self {
+        VcpuThrottle {
+            percent,
+            period_start: Instant::now(),
+            cpu_start: thread_cpu_time(),
+        }
+    }
+
+    pub fn throttle(&mut self) {
+        let used = thread_cpu_time().saturating_sub(self.cpu_start);
+        // Wall clock time over which `used` would be within the quota
+        let allowed = used * 100 / self.percent;
+        let elapsed = self.period_start.elapsed();
+        if allowed > elapsed {
+            thread::sleep(allowed - elapsed);
+        } else if elapsed < QUOTA_PERIOD {
+            return;
+        }
+        self.period_start = Instant::now();
+        self.cpu_start = thread_cpu_time();
+    }
+}
+
+/// Interrupt the running vcpus every period until the VM shuts down
+pub fn spawn_ticker(control: Arc<VcpuControl>) {
+    thread::spawn(move || {
+        while !control.is_shutdown() {
+            thread::sleep(QUOTA_PERIOD);
+            control.kick_all();
+        }
+    });
+}
@@ -1,22 +1,302 @@
-use std::sync::{Arc, Barrier};
-use std::sync::atomic::{AtomicBool,Ordering};
+use std::io;
+use std::sync::{Arc, Barrier, Condvar, Mutex, MutexGuard, Once};
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
 use kvm_ioctls::{VcpuExit, VcpuFd};
+use libc::{c_int, c_void, siginfo_t};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
 use crate::io::manager::IoManager;
+use crate::vm::handle::{VmEvent, VmExitReason};
+use crate::vm::throttle::VcpuThrottle;
+use crate::vm::msr::{KvmRunMap, MsrHandler, KVM_EXIT_X86_RDMSR, KVM_EXIT_X86_WRMSR};
 
+// Real-time signal (relative to SIGRTMIN) sent to vcpu threads to make
+// KVM_RUN return so that a pause or shutdown request is noticed
+const VCPU_KICK_SIGNAL_OFFSET: c_int = 0;
+
+extern "C" fn handle_kick_signal(_: c_int, _: *mut siginfo_t, _: *mut c_void) {}
+
+fn kick_signal() -> c_int {
+    SIGRTMIN() + VCPU_KICK_SIGNAL_OFFSET
+}
+
+#[derive(Default)]
+struct VcpuState {
+    paused: bool,
+    // Threads of the vcpus which have not exited yet
+    threads: Vec<libc::pthread_t>,
+    // Number of vcpus waiting in wait_while_paused()
+    parked: usize,
+    started: bool,
+    ready: bool,
+    exit_reason: Option<VmExitReason>,
+    events: Vec<Sender<VmEvent>>,
+}
+
+///
+/// State shared between the vcpu threads and the `VmHandle` which controls
+/// them.
+///
+/// Pausing or shutting down the VM sets a flag and then signals every vcpu
+/// thread so that KVM_RUN returns and the flag is checked. Lifecycle events
+/// are sent to every channel registered with `add_listener()`.
+///
+/// The first reason recorded with `request_exit()` is the exit reason of the
+/// VM. The guest requests a reset or a shutdown through the eventfds returned
+/// by `reset_event()` and `shutdown_event()`, which are checked after every
+/// port write.
+///
+/// Every vcpu has a thread, but the guest may keep some of them offline. The
+/// number it is asked to keep online is set with `set_online_cpus()`.
+///
+pub struct VcpuControl {
+    shutdown: AtomicBool,
+    state: Mutex<VcpuState>,
+    cond: Condvar,
+    ncpus: usize,
+    online_cpus: AtomicUsize,
+    // Percent of a host CPU each vcpu thread may use
+    cpu_quota: Option<u32>,
+    reset_evt: EventFd,
+    shutdown_evt: EventFd,
+}
+
+impl VcpuControl {
+    pub fn new(ncpus: usize, online_cpus: usize) -> io::Result<Self> {
+        static REGISTER_SIGNAL: Once = Once::new();
+        REGISTER_SIGNAL.call_once(|| {
+            if let Err(err) = register_signal_handler(kick_signal(), handle_kick_signal) {
+                warn!("Failed to register vcpu signal handler: {}", err);
+            }
+        });
+        Ok(VcpuControl {
+            shutdown: AtomicBool::new(false),
+            state: Mutex::new(VcpuState::default()),
+            cond: Condvar::new(),
+            ncpus,
+            online_cpus: AtomicUsize::new(online_cpus),
+            cpu_quota: None,
+            reset_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            shutdown_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+        })
+    }
+
+    pub fn reset_event(&self) -> io::Result<EventFd> {
+        self.reset_evt.try_clone()
+    }
+
+    pub fn shutdown_event(&self) -> io::Result<EventFd> {
+        self.shutdown_evt.try_clone()
+    }
+
+    fn state(&self) -> MutexGuard<VcpuState> {
+        self.state.lock().unwrap()
+    }
+
+    fn send_event(state: &mut VcpuState, event: VmEvent) {
+        state.events.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    fn kick(state: &VcpuState) {
+        for &thread in &state.threads {
+            unsafe { libc::pthread_kill(thread, kick_signal()); }
+        }
+    }
+
+    pub fn set_cpu_quota(&mut self, percent: Option<u32>) {
+        self.cpu_quota = percent;
+    }
+
+    pub fn cpu_quota(&self) -> Option<u32> {
+        self.cpu_quota
+    }
+
+    // Make every running vcpu return from KVM_RUN
+    pub fn kick_all(&self) {
+        Self::kick(&self.state());
+    }
+
+    /// Number of vcpus, including any the guest keeps offline
+    pub fn max_cpus(&self) -> usize {
+        self.ncpus
+    }
+
+    /// Number of vcpus the guest is asked to keep online
+    pub fn online_cpus(&self) -> usize {
+        self.online_cpus.load(Ordering::Relaxed)
+    }
+
+    /// Ask the guest to bring vcpus online or take them offline so that
+    /// `count` are online. The guest picks up the change the next time
+    /// ph-init reads the cpu hotplug port.
+    pub fn set_online_cpus(&self, count: usize) -> io::Result<()> {
+        if count == 0 || count > self.ncpus {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("cpu count must be between 1 and {}", self.ncpus)));
+        }
+        self.online_cpus.store(count, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn add_listener(&self, tx: Sender<VmEvent>) {
+        self.state().events.push(tx);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state().paused
+    }
+
+    /// Whether ph-init has reported that the guest finished booting
+    pub fn is_ready(&self) -> bool {
+        self.state().ready
+    }
+
+    // Called when ph-init has started the guest services and the shell
+    pub fn guest_ready(&self) {
+        let mut state = self.state();
+        if !state.ready {
+            state.ready = true;
+            Self::send_event(&mut state, VmEvent::Ready);
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.state().threads.is_empty()
+    }
+
+    /// Stop every vcpu, recording `reason` as the exit reason unless the VM
+    /// is already stopping for another reason.
+    pub fn request_exit(&self, reason: VmExitReason) {
+        let mut state = self.state();
+        if state.exit_reason.is_none() {
+            state.exit_reason = Some(reason);
+        }
+        self.shutdown.store(true, Ordering::Relaxed);
+        Self::kick(&state);
+        self.cond.notify_all();
+    }
+
+    pub fn exit_reason(&self) -> Option<VmExitReason> {
+        self.state().exit_reason.clone()
+    }
+
+    // Called after a port write to handle a reset or shutdown requested by
+    // the write
+    fn check_guest_exit(&self) {
+        if self.shutdown_evt.read().is_ok() {
+            self.request_exit(VmExitReason::GuestShutdown);
+        } else if self.reset_evt.read().is_ok() {
+            self.request_exit(VmExitReason::GuestReset);
+        }
+    }
+
+    pub fn request_pause(&self) {
+        let mut state = self.state();
+        if !state.paused {
+            state.paused = true;
+            Self::kick(&state);
+        }
+    }
+
+    pub fn request_resume(&self) {
+        let mut state = self.state();
+        if state.paused {
+            state.paused = false;
+            self.cond.notify_all();
+        }
+    }
+
+    // Called by each vcpu thread before it first enters the guest
+    fn vcpu_started(&self) {
+        let mut state = self.state();
+        state.threads.push(unsafe { libc::pthread_self() });
+        if state.threads.len() == self.ncpus && !state.started {
+            state.started = true;
+            Self::send_event(&mut state, VmEvent::Started);
+        }
+    }
+
+    // Called by each vcpu thread when it stops running
+    fn vcpu_exited(&self) {
+        let mut state = self.state();
+        let this = unsafe { libc::pthread_self() };
+        state.threads.retain(|&t| unsafe { libc::pthread_equal(t, this) } == 0);
+        if state.threads.is_empty() {
+            let reason = state.exit_reason.clone()
+                .unwrap_or_else(|| VmExitReason::HostError("vcpus exited".to_string()));
+            Self::send_event(&mut state, VmEvent::Stopped(reason));
+            self.cond.notify_all();
+        }
+    }
+
+    // Blocks the calling vcpu thread for as long as the VM is paused. The
+    // Paused event is sent once every vcpu has stopped.
+    fn wait_while_paused(&self) {
+        let mut state = self.state();
+        if !state.paused || self.is_shutdown() {
+            return;
+        }
+        state.parked += 1;
+        if state.parked == state.threads.len() {
+            Self::send_event(&mut state, VmEvent::Paused);
+        }
+        while state.paused && !self.is_shutdown() {
+            state = self.cond.wait(state).unwrap();
+        }
+        if state.parked == state.threads.len() && !self.is_shutdown() {
+            Self::send_event(&mut state, VmEvent::Resumed);
+        }
+        state.parked -= 1;
+    }
+
+    // Signals any vcpu which missed the kick for a pending shutdown because
+    // it arrived just before it entered KVM_RUN
+    pub fn kick_if_shutdown(&self) {
+        if self.is_shutdown() {
+            Self::kick(&self.state());
+        }
+    }
+
+    // Wait until every vcpu has exited or `timeout` has passed
+    pub fn wait_for_exit(&self, timeout: Duration) {
+        let state = self.state();
+        if !state.threads.is_empty() {
+            let (_state, _) = self.cond.wait_timeout(state, timeout).unwrap();
+        }
+    }
+}
+
+// Reports the exit of a vcpu thread to VcpuControl
+struct VcpuExited<'a>(&'a VcpuControl);
+
+impl Drop for VcpuExited<'_> {
+    fn drop(&mut self) {
+        self.0.vcpu_exited();
+    }
+}
 
 pub struct Vcpu {
     vcpu_fd: VcpuFd,
     io_manager: IoManager,
-    shutdown: Arc<AtomicBool>,
+    control: Arc<VcpuControl>,
+    // Set when unknown MSR accesses exit to userspace
+    msr_exits: Option<(Arc<MsrHandler>, KvmRunMap)>,
 }
 
 
 impl Vcpu {
-    pub fn new(vcpu_fd: VcpuFd, io_manager: IoManager, shutdown: Arc<AtomicBool>) -> Self {
+    pub fn new(vcpu_fd: VcpuFd, io_manager: IoManager, control: Arc<VcpuControl>, msr_exits: Option<(Arc<MsrHandler>, KvmRunMap)>) -> Self {
         Vcpu {
             vcpu_fd,
             io_manager,
-            shutdown,
+            control,
+            msr_exits,
         }
     }
 
@@ -27,6 +307,7 @@ impl Vcpu {
 
     fn handle_io_out(&self, port: u16, data: &[u8]) {
         let _ok = self.io_manager.pio_write(port, data);
+        self.control.check_guest_exit();
     }
 
     fn handle_io_in(&self, port: u16, data: &mut [u8]) {
@@ -41,33 +322,65 @@ impl Vcpu {
         let _ok = self.io_manager.mmio_write(addr,data);
     }
 
-    fn handle_shutdown(&self) {
-        self.shutdown.store(true, Ordering::Relaxed);
+    fn handle_ioapic_eoi(&self, vector: u8) {
+        self.io_manager.irqs().end_of_interrupt(vector);
+    }
+
+    fn handle_unsupported(&self, reason: u32) {
+        match self.msr_exits {
+            Some((ref handler, ref run)) if reason == KVM_EXIT_X86_RDMSR || reason == KVM_EXIT_X86_WRMSR => {
+                handler.handle_exit(run, reason);
+            }
+            _ => warn!("unhandled exit: {}", reason),
+        }
+    }
+
+    // A triple fault, or state which KVM cannot run
+    fn handle_crash(&self, exit: &str) {
+        warn!("VCPU stopped on {}", exit);
+        self.control.request_exit(VmExitReason::GuestCrash);
     }
 
     pub fn run(&self, barrier: &Arc<Barrier>) {
+        self.control.vcpu_started();
+        // Dropped when the vcpu returns or its thread panics
+        let _exited = VcpuExited(&self.control);
         barrier.wait();
+        self.run_loop();
+    }
+
+    fn run_loop(&self) {
+        let mut throttle = self.control.cpu_quota().map(VcpuThrottle::new);
         loop {
+            self.control.wait_while_paused();
+            if self.control.is_shutdown() {
+                return;
+            }
+            if let Some(throttle) = throttle.as_mut() {
+                throttle.throttle();
+            }
             match self.vcpu_fd.run() {
                 Ok(VcpuExit::IoOut(port, data)) => self.handle_io_out(port, data),
                 Ok(VcpuExit::IoIn(port, data)) => self.handle_io_in(port, data),
                 Ok(VcpuExit::MmioRead(addr, data)) => self.handle_mmio_read(addr, data),
                 Ok(VcpuExit::MmioWrite(addr, data)) => self.handle_mmio_write(addr, data),
-                Ok(VcpuExit::Shutdown) => self.handle_shutdown(),
+                Ok(VcpuExit::IoapicEoi(vector)) => self.handle_ioapic_eoi(vector),
+                Ok(VcpuExit::Shutdown) => self.handle_crash("shutdown"),
+                Ok(VcpuExit::FailEntry(..)) => self.handle_crash("failed VM entry"),
+                Ok(VcpuExit::InternalError) => self.handle_crash("KVM internal error"),
+                Ok(VcpuExit::Unsupported(reason)) => self.handle_unsupported(reason),
                 Ok(exit) => {
                     println!("unhandled exit: {:?}", exit);
                 },
                 Err(err) => {
-                    if err.errno() == libc::EAGAIN {}
+                    if err.errno() == libc::EAGAIN || err.errno() == libc::EINTR {}
                     else {
                         warn!("VCPU run() returned error: {}", err);
+                        self.control.request_exit(VmExitReason::HostError(format!("vcpu run failed: {}", err)));
                         return;
                     }
                 }
             }
-            if self.shutdown.load(Ordering::Relaxed) {
-                return;
-            }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,235 @@
+use std::result;
+use kvm_ioctls::{IoEventAddress, NoDatamatch};
+use vmm_sys_util::eventfd::EventFd;
+use crate::vm::KvmVm;
+use crate::vm::irq_routing::MsiMessage;
+
+type KvmResult<T> = result::Result<T, kvm_ioctls::Error>;
+
+///
+/// The VM operations needed by virtqueues, interrupt lines and the device
+/// shared memory manager.
+///
+/// `KvmVm` implements these with KVM ioctls. With the `mock-kvm` feature
+/// `MockVm` records them instead so that devices and queues can be exercised
+/// on machines without /dev/kvm.
+///
+pub trait VmOps: Send + Sync {
+    /// Inject interrupt `gsi` whenever `fd` is signaled
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> KvmResult<()>;
+
+    /// Signal `fd` when the guest writes to the MMIO address `addr`
+    fn register_mmio_ioevent(&self, fd: &EventFd, addr: u64) -> KvmResult<()>;
+
+    fn add_memory_region(&self, slot: u32, guest_address: u64, host_address: u64, size: usize) -> KvmResult<()>;
+
+    fn add_readonly_memory_region(&self, slot: u32, guest_address: u64, host_address: u64, size: usize) -> KvmResult<()>;
+
+    fn remove_memory_region(&self, slot: u32) -> KvmResult<()>;
+
+    /// Allocate a GSI routed to the MSI message `msg`, for a device which
+    /// raises MSI-X vectors through irqfds registered on the GSI
+    fn allocate_msi_route(&self, msg: MsiMessage) -> KvmResult<u32>;
+
+    /// Route a GSI from `allocate_msi_route()` to a new message
+    fn update_msi_route(&self, gsi: u32, msg: MsiMessage) -> KvmResult<()>;
+
+    fn release_msi_route(&self, gsi: u32) -> KvmResult<()>;
+}
+
+impl VmOps for KvmVm {
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> KvmResult<()> {
+        self.vm_fd().register_irqfd(fd, gsi)
+    }
+
+    fn register_mmio_ioevent(&self, fd: &EventFd, addr: u64) -> KvmResult<()> {
+        self.vm_fd().register_ioevent(fd, &IoEventAddress::Mmio(addr), NoDatamatch)
+    }
+
+    fn add_memory_region(&self, slot: u32, guest_address: u64, host_address: u64, size: usize) -> KvmResult<()> {
+        KvmVm::add_memory_region(self, slot, guest_address, host_address, size)
+    }
+
+    fn add_readonly_memory_region(&self, slot: u32, guest_address: u64, host_address: u64, size: usize) -> KvmResult<()> {
+        KvmVm::add_readonly_memory_region(self, slot, guest_address, host_address, size)
+    }
+
+    fn remove_memory_region(&self, slot: u32) -> KvmResult<()> {
+        KvmVm::remove_memory_region(self, slot)
+    }
+
+    fn allocate_msi_route(&self, msg: MsiMessage) -> KvmResult<u32> {
+        KvmVm::allocate_msi_route(self, msg)
+    }
+
+    fn update_msi_route(&self, gsi: u32, msg: MsiMessage) -> KvmResult<()> {
+        KvmVm::update_msi_route(self, gsi, msg)
+    }
+
+    fn release_msi_route(&self, gsi: u32) -> KvmResult<()> {
+        KvmVm::release_msi_route(self, gsi)
+    }
+}
+
+#[cfg(feature = "mock-kvm")]
+pub use mock::{MockVm, MockMemoryRegion};
+
+#[cfg(feature = "mock-kvm")]
+mod mock {
+    use std::collections::HashMap;
+    use std::sync::{Mutex, MutexGuard};
+    use vmm_sys_util::eventfd::EventFd;
+    use crate::vm::irq_routing::{GsiRouting, MsiMessage};
+    use super::{KvmResult, VmOps};
+
+    #[derive(Copy,Clone,Debug,Eq,PartialEq)]
+    pub struct MockMemoryRegion {
+        pub guest_address: u64,
+        pub host_address: u64,
+        pub size: usize,
+        pub read_only: bool,
+    }
+
+    struct MockState {
+        irqfds: Vec<(u32, EventFd)>,
+        ioevents: Vec<(u64, EventFd)>,
+        regions: HashMap<u32, MockMemoryRegion>,
+        // GSIs are allocated as KvmVm allocates them, the routes are only
+        // recorded
+        routing: GsiRouting,
+        msi_routes: HashMap<u32, MsiMessage>,
+    }
+
+    impl Default for MockState {
+        fn default() -> Self {
+            MockState {
+                irqfds: Vec::new(),
+                ioevents: Vec::new(),
+                regions: HashMap::new(),
+                routing: GsiRouting::new(),
+                msi_routes: HashMap::new(),
+            }
+        }
+    }
+
+    ///
+    /// A `VmOps` implementation which records every registration.
+    ///
+    /// Registered eventfds are kept so that a test can play the part of the
+    /// guest by signaling a queue notify address with `notify()` and check
+    /// for interrupts with `take_interrupts()`.
+    ///
+    #[derive(Default)]
+    pub struct MockVm {
+        state: Mutex<MockState>,
+    }
+
+    impl MockVm {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        fn state(&self) -> MutexGuard<MockState> {
+            self.state.lock().unwrap()
+        }
+
+        /// Signal the ioeventfd registered for `addr` as a guest write would.
+        /// Returns `false` if nothing is registered at `addr`.
+        pub fn notify(&self, addr: u64) -> bool {
+            let state = self.state();
+            match state.ioevents.iter().find(|(a, _)| *a == addr) {
+                Some((_, evt)) => evt.write(1).is_ok(),
+                None => false,
+            }
+        }
+
+        /// Number of times interrupt `gsi` was raised since the last call
+        pub fn take_interrupts(&self, gsi: u32) -> u64 {
+            self.state().irqfds.iter()
+                .filter(|(g, _)| *g == gsi)
+                .map(|(_, evt)| evt.read().unwrap_or(0))
+                .sum()
+        }
+
+        pub fn ioevent_addresses(&self) -> Vec<u64> {
+            self.state().ioevents.iter().map(|(addr, _)| *addr).collect()
+        }
+
+        pub fn memory_region(&self, slot: u32) -> Option<MockMemoryRegion> {
+            self.state().regions.get(&slot).copied()
+        }
+
+        pub fn memory_region_count(&self) -> usize {
+            self.state().regions.len()
+        }
+
+        /// The MSI message GSI `gsi` is routed to, if it has been allocated
+        pub fn msi_route(&self, gsi: u32) -> Option<MsiMessage> {
+            self.state().msi_routes.get(&gsi).copied()
+        }
+
+        fn add_region(&self, slot: u32, region: MockMemoryRegion) -> KvmResult<()> {
+            let mut state = self.state();
+            if state.regions.contains_key(&slot) {
+                return Err(kvm_ioctls::Error::new(libc::EEXIST));
+            }
+            state.regions.insert(slot, region);
+            Ok(())
+        }
+    }
+
+    impl VmOps for MockVm {
+        fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> KvmResult<()> {
+            let fd = fd.try_clone().map_err(|e| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO)))?;
+            self.state().irqfds.push((gsi, fd));
+            Ok(())
+        }
+
+        fn register_mmio_ioevent(&self, fd: &EventFd, addr: u64) -> KvmResult<()> {
+            let fd = fd.try_clone().map_err(|e| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO)))?;
+            self.state().ioevents.push((addr, fd));
+            Ok(())
+        }
+
+        fn add_memory_region(&self, slot: u32, guest_address: u64, host_address: u64, size: usize) -> KvmResult<()> {
+            self.add_region(slot, MockMemoryRegion { guest_address, host_address, size, read_only: false })
+        }
+
+        fn add_readonly_memory_region(&self, slot: u32, guest_address: u64, host_address: u64, size: usize) -> KvmResult<()> {
+            self.add_region(slot, MockMemoryRegion { guest_address, host_address, size, read_only: true })
+        }
+
+        fn remove_memory_region(&self, slot: u32) -> KvmResult<()> {
+            match self.state().regions.remove(&slot) {
+                Some(_) => Ok(()),
+                None => Err(kvm_ioctls::Error::new(libc::EINVAL)),
+            }
+        }
+
+        fn allocate_msi_route(&self, msg: MsiMessage) -> KvmResult<u32> {
+            let mut state = self.state();
+            let gsi = state.routing.allocate_msi_gsi()
+                .ok_or_else(|| kvm_ioctls::Error::new(libc::ENOSPC))?;
+            state.routing.set_msi_route(gsi, msg);
+            state.msi_routes.insert(gsi, msg);
+            Ok(gsi)
+        }
+
+        fn update_msi_route(&self, gsi: u32, msg: MsiMessage) -> KvmResult<()> {
+            let mut state = self.state();
+            if !state.msi_routes.contains_key(&gsi) {
+                return Err(kvm_ioctls::Error::new(libc::EINVAL));
+            }
+            state.routing.set_msi_route(gsi, msg);
+            state.msi_routes.insert(gsi, msg);
+            Ok(())
+        }
+
+        fn release_msi_route(&self, gsi: u32) -> KvmResult<()> {
+            let mut state = self.state();
+            state.routing.release_msi_gsi(gsi);
+            state.msi_routes.remove(&gsi);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Boots the embedded kernel and ph-init under KVM and checks the devices
+//! from inside the guest.
+//!
+//! The guest boots from the host root filesystem and runs a bash script from
+//! a temporary directory shared as its home directory. The script writes its
+//! results to the same directory where the test checks them after the VM has
+//! shut down.
+//!
+//! These tests need /dev/kvm and are only built with the `kvm-tests` feature:
+//!
+//!     $ cargo test --features kvm-tests --test kvm_boot -- --test-threads=1
+//!
+//! The network test also needs an existing tap device given in `PH_TEST_TAP`
+//! and an address reachable through it in `PH_TEST_PEER`.
+#![cfg(feature = "kvm-tests")]
+
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::env;
+use std::process;
+
+use ph::{OpenType, RootDevice, VmConfig, VmEvent, VmExitReason};
+
+struct GuestTest {
+    dir: PathBuf,
+}
+
+impl GuestTest {
+    fn new(name: &str) -> Option<GuestTest> {
+        if !Path::new("/dev/kvm").exists() {
+            eprintln!("skipping {}: /dev/kvm does not exist", name);
+            return None;
+        }
+        let dir = env::temp_dir().join(format!("ph-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Some(GuestTest { dir })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn config(&self, script: &str) -> VmConfig {
+        let path = self.path("test.sh");
+        fs::write(&path, format!("set -e\ncd {}\n{}\n", self.dir.display(), script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        VmConfig::new_default()
+            .num_cpus(1)
+            .ram_size_megs(256)
+            .enable_wayland(false)
+            .enable_audio(false)
+            .enable_network(false)
+            .root_shell(true)
+            .home_dir(&self.dir)
+            .root_device(RootDevice::HostRoot)
+            .guest_command(&path.display().to_string())
+    }
+
+    // Boot the VM and return the contents of the file `result` written by
+    // the script
+    fn run(&self, config: VmConfig) -> String {
+        let reason = config.start()
+            .expect("failed to start VM")
+            .wait();
+        assert_eq!(reason, VmExitReason::GuestShutdown);
+        fs::read_to_string(self.path("result"))
+            .expect("guest did not write a result")
+            .trim()
+            .to_string()
+    }
+}
+
+impl Drop for GuestTest {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn boot_and_shutdown() {
+    let test = match GuestTest::new("boot") { Some(t) => t, None => return };
+    let config = test.config("echo booted > result");
+    assert_eq!(test.run(config), "booted");
+}
+
+#[test]
+fn ready_event() {
+    let test = match GuestTest::new("ready") { Some(t) => t, None => return };
+    let config = test.config("sleep 1");
+    let vm = config.start().expect("failed to start VM");
+    let events = vm.events();
+    let mut ready = false;
+    for event in events.iter() {
+        match event {
+            VmEvent::Ready => ready = true,
+            VmEvent::Stopped(_) => break,
+            _ => {},
+        }
+    }
+    assert!(ready);
+    assert!(vm.is_ready());
+    assert_eq!(vm.wait(), VmExitReason::GuestShutdown);
+}
+
+#[test]
+fn p9_round_trip() {
+    let test = match GuestTest::new("p9") { Some(t) => t, None => return };
+    fs::write(test.path("input"), "written by host").unwrap();
+    let config = test.config(r#"
+cat input > result
+echo "written by guest" > from-guest
+mkdir -p subdir && echo nested > subdir/file
+"#);
+    assert_eq!(test.run(config), "written by host");
+    assert_eq!(fs::read_to_string(test.path("from-guest")).unwrap(), "written by guest\n");
+    assert_eq!(fs::read_to_string(test.path("subdir/file")).unwrap(), "nested\n");
+}
+
+#[test]
+fn block_round_trip() {
+    let test = match GuestTest::new("block") { Some(t) => t, None => return };
+    let image = test.path("disk.img");
+    let mut data = vec![0u8; 1 << 20];
+    data[..12].copy_from_slice(b"written-host");
+    fs::write(&image, &data).unwrap();
+
+    let config = test.config(r#"
+dd if=/dev/vda bs=12 count=1 status=none > result
+printf written-guest | dd of=/dev/vda bs=4096 seek=1 conv=notrunc,fsync status=none
+"#).raw_disk_image(&image, OpenType::ReadWrite);
+    assert_eq!(test.run(config), "written-host");
+
+    let mut buf = [0u8; 13];
+    let mut file = OpenOptions::new().read(true).open(&image).unwrap();
+    file.seek(SeekFrom::Start(4096)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"written-guest");
+}
+
+#[test]
+fn block_read_only() {
+    let test = match GuestTest::new("block-ro") { Some(t) => t, None => return };
+    let image = test.path("disk.img");
+    fs::write(&image, vec![0u8; 1 << 20]).unwrap();
+
+    let config = test.config(r#"
+if printf x | dd of=/dev/vda conv=notrunc,fsync status=none 2>/dev/null; then
+    echo writable > result
+else
+    echo read-only > result
+fi
+"#).raw_disk_image(&image, OpenType::ReadOnly);
+    assert_eq!(test.run(config), "read-only");
+    let mut file = OpenOptions::new().read(true).open(&image).unwrap();
+    let mut byte = [0u8; 1];
+    file.read_exact(&mut byte).unwrap();
+    assert_eq!(byte[0], 0);
+}
+
+#[test]
+#[cfg(feature = "network")]
+fn network_round_trip() {
+    let (tap, peer) = match (env::var("PH_TEST_TAP"), env::var("PH_TEST_PEER")) {
+        (Ok(tap), Ok(peer)) => (tap, peer),
+        _ => {
+            eprintln!("skipping network_round_trip: PH_TEST_TAP and PH_TEST_PEER are not set");
+            return;
+        }
+    };
+    let test = match GuestTest::new("net") { Some(t) => t, None => return };
+    let config = test.config(&format!("ping -c 1 -W 5 {} > /dev/null && echo reachable > result", peer))
+        .enable_network(true)
+        .tap_device(&tap);
+    assert_eq!(test.run(config), "reachable");
+}
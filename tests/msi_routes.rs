@@ -0,0 +1,63 @@
+//! Allocates, changes and releases MSI routes through `VmOps` as a device
+//! with MSI-X vectors would, and checks the GSIs handed out.
+//!
+//! These tests use the mock VM and are only built with the `mock-kvm` feature:
+//!
+//!     $ cargo test --features mock-kvm --test msi_routes
+#![cfg(feature = "mock-kvm")]
+
+use ph::testing::{MockVm, MsiMessage, VmOps};
+
+// GSIs below this are the IOAPIC pins
+const IOAPIC_NUM_PINS: u32 = 24;
+const MAX_GSI: u32 = 1023;
+
+fn message(vector: u32) -> MsiMessage {
+    MsiMessage::new(0xfee0_0000, vector)
+}
+
+#[test]
+fn allocate_above_ioapic_pins() {
+    let vm = MockVm::new();
+    let a = vm.allocate_msi_route(message(0x30)).unwrap();
+    let b = vm.allocate_msi_route(message(0x31)).unwrap();
+    assert!(a >= IOAPIC_NUM_PINS);
+    assert!(b >= IOAPIC_NUM_PINS);
+    assert_ne!(a, b);
+    assert_eq!(vm.msi_route(a), Some(message(0x30)));
+    assert_eq!(vm.msi_route(b), Some(message(0x31)));
+}
+
+#[test]
+fn update_route() {
+    let vm = MockVm::new();
+    let gsi = vm.allocate_msi_route(message(0x30)).unwrap();
+    vm.update_msi_route(gsi, message(0x40)).unwrap();
+    assert_eq!(vm.msi_route(gsi), Some(message(0x40)));
+}
+
+#[test]
+fn released_gsi_is_reused() {
+    let vm = MockVm::new();
+    let a = vm.allocate_msi_route(message(0x30)).unwrap();
+    let b = vm.allocate_msi_route(message(0x31)).unwrap();
+    vm.release_msi_route(a).unwrap();
+    assert_eq!(vm.msi_route(a), None);
+
+    assert_eq!(vm.allocate_msi_route(message(0x32)).unwrap(), a);
+    assert_eq!(vm.msi_route(a), Some(message(0x32)));
+    assert_eq!(vm.msi_route(b), Some(message(0x31)));
+}
+
+#[test]
+fn gsis_run_out() {
+    let vm = MockVm::new();
+    for gsi in IOAPIC_NUM_PINS..=MAX_GSI {
+        assert_eq!(vm.allocate_msi_route(message(0x30)).unwrap(), gsi);
+    }
+    let err = vm.allocate_msi_route(message(0x30)).unwrap_err();
+    assert_eq!(err.errno(), libc::ENOSPC);
+
+    vm.release_msi_route(100).unwrap();
+    assert_eq!(vm.allocate_msi_route(message(0x30)).unwrap(), 100);
+}
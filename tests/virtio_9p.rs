@@ -0,0 +1,433 @@
+//! Drives a virtio 9p device exporting a temporary directory through a split
+//! virtqueue in mock guest memory, playing the part of a hostile guest which
+//! tries to reach files outside of the export with `..`, symlinks and names
+//! containing `/`. A device serving a `SyntheticFS` is checked to present
+//! symlinks, device nodes and hard links to the guest.
+//!
+//! These tests use the mock VM and are only built with the `mock-kvm` feature:
+//!
+//!     $ cargo test --features mock-kvm --test virtio_9p
+#![cfg(feature = "mock-kvm")]
+
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use ph::SyntheticFS;
+use ph::testing::{self, MockVm, PciBar, PciDevice, VirtioDevice, VirtioDeviceState, VirtioP9};
+
+const BAR_BASE: u64 = 0xe000_0000;
+const NOTIFY_OFFSET: u64 = 0x400;
+
+const QUEUE_SIZE: u16 = 128;
+const DESC_TABLE: u64 = 0x1000;
+const AVAIL_RING: u64 = 0x2000;
+const USED_RING: u64 = 0x3000;
+
+const REQUEST: u64 = 0x10000;
+const REPLY: u64 = 0x20000;
+const MSIZE: u32 = 0x2000;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+const NOFID: u32 = !0;
+const ROOT_FID: u32 = 1;
+
+// Contents of the files the guest must not be able to read
+const SECRET: &[u8] = b"secret\n";
+
+struct P9Test {
+    memory: GuestMemoryMmap,
+    vm: Arc<MockVm>,
+    device: VirtioDeviceState,
+    base: Option<PathBuf>,
+    avail_idx: u16,
+}
+
+impl P9Test {
+    // Export `base/export` holding `dir/file`, next to `base/outside` which
+    // holds a file with the same name and secret contents
+    fn new(name: &str) -> P9Test {
+        let base = env::temp_dir().join(format!("ph-test-9p-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("export/dir")).unwrap();
+        fs::create_dir_all(base.join("outside")).unwrap();
+        fs::write(base.join("export/dir/file"), b"inside\n").unwrap();
+        fs::write(base.join("outside/file"), SECRET).unwrap();
+
+        let export = base.join("export");
+        let p9 = VirtioP9::new_filesystem("test", export.to_str().unwrap(), false, false).unwrap();
+        Self::start(p9, Some(base))
+    }
+
+    // Serve `filesystem` the way the boot filesystem is served
+    fn synthetic(filesystem: SyntheticFS) -> P9Test {
+        Self::start(VirtioP9::new(filesystem, "/dev/root", "/", false), None)
+    }
+
+    fn start<T: VirtioDevice + 'static>(p9: T, base: Option<PathBuf>) -> P9Test {
+        let memory = testing::guest_memory(1 << 20);
+        let vm = Arc::new(MockVm::new());
+        let device = testing::virtio_device(p9, vm.clone(), memory.clone(), 5);
+        let mut test = P9Test { memory, vm, device, base, avail_idx: 0 };
+        test.start_driver();
+        test.version();
+        test.attach(ROOT_FID);
+        test
+    }
+
+    // Host path of `name` in the temporary directory of the test
+    fn path<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        self.base.as_ref().expect("test has no host directory").join(name)
+    }
+
+    fn write_bar(&mut self, offset: u64, data: &[u8]) {
+        self.device.write_bar(PciBar::Bar0, offset, data);
+    }
+
+    fn read_bar_u32(&mut self, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        self.device.read_bar(PciBar::Bar0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn set_status(&mut self, status: u8) {
+        self.write_bar(20, &[status]);
+    }
+
+    // Accept every feature offered by the device and set up queue 0
+    fn start_driver(&mut self) {
+        self.device.configure_bars(vec![(PciBar::Bar0, BAR_BASE)]);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        for word in 0..2u32 {
+            self.write_bar(0, &word.to_le_bytes());
+            let features = self.read_bar_u32(4);
+            self.write_bar(8, &word.to_le_bytes());
+            self.write_bar(12, &features.to_le_bytes());
+        }
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+
+        self.write_bar(22, &0u16.to_le_bytes());
+        self.write_bar(24, &QUEUE_SIZE.to_le_bytes());
+        self.write_bar(32, &(DESC_TABLE as u32).to_le_bytes());
+        self.write_bar(40, &(AVAIL_RING as u32).to_le_bytes());
+        self.write_bar(48, &(USED_RING as u32).to_le_bytes());
+        self.write_bar(28, &1u16.to_le_bytes());
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK);
+    }
+
+    // Place a chain of `(address, length, writeable)` buffers on the avail
+    // ring, notify the device and wait for it to be returned. Returns the
+    // length written by the device.
+    fn submit(&mut self, buffers: &[(u64, u32, bool)]) -> u32 {
+        for (i, &(address, len, writeable)) in buffers.iter().enumerate() {
+            let mut flags = if writeable { VIRTQ_DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let desc = DESC_TABLE + i as u64 * 16;
+            self.memory.write_obj(address, GuestAddress(desc)).unwrap();
+            self.memory.write_obj(len, GuestAddress(desc + 8)).unwrap();
+            self.memory.write_obj(flags, GuestAddress(desc + 12)).unwrap();
+            self.memory.write_obj(i as u16 + 1, GuestAddress(desc + 14)).unwrap();
+        }
+        let slot = self.avail_idx % QUEUE_SIZE;
+        self.memory.write_obj(0u16, GuestAddress(AVAIL_RING + 4 + slot as u64 * 2)).unwrap();
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.memory.write_obj(self.avail_idx, GuestAddress(AVAIL_RING + 2)).unwrap();
+        assert!(self.vm.notify(BAR_BASE + NOTIFY_OFFSET));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.memory.read_obj::<u16>(GuestAddress(USED_RING + 2)).unwrap() != self.avail_idx {
+            assert!(Instant::now() < deadline, "request was not completed");
+            thread::sleep(Duration::from_millis(1));
+        }
+        let elem = USED_RING + 4 + slot as u64 * 8;
+        self.memory.read_obj(GuestAddress(elem + 4)).unwrap()
+    }
+
+    // Send a request with body `body` and return the type and body of the reply
+    fn request(&mut self, msg_type: u8, body: &[u8]) -> (u8, Vec<u8>) {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&(7 + body.len() as u32).to_le_bytes());
+        msg.push(msg_type);
+        msg.extend_from_slice(&1u16.to_le_bytes());
+        msg.extend_from_slice(body);
+        self.memory.write_slice(&msg, GuestAddress(REQUEST)).unwrap();
+
+        let len = self.submit(&[(REQUEST, msg.len() as u32, false), (REPLY, MSIZE, true)]) as usize;
+        assert!(len >= 7, "reply too short");
+        let mut reply = vec![0u8; len];
+        self.memory.read_slice(&mut reply, GuestAddress(REPLY)).unwrap();
+        assert_eq!(u32::from_le_bytes([reply[0], reply[1], reply[2], reply[3]]) as usize, len);
+        (reply[4], reply[7..].to_vec())
+    }
+
+    // Like request(), but an Rlerror reply is returned as the error code
+    fn call(&mut self, msg_type: u8, body: &[u8]) -> Result<Vec<u8>, i32> {
+        match self.request(msg_type, body) {
+            (RLERROR, body) => Err(i32::from_le_bytes([body[0], body[1], body[2], body[3]])),
+            (reply_type, body) => {
+                assert_eq!(reply_type, msg_type + 1);
+                Ok(body)
+            }
+        }
+    }
+
+    fn version(&mut self) {
+        let mut body = MSIZE.to_le_bytes().to_vec();
+        put_string(&mut body, "9P2000.L");
+        self.call(TVERSION, &body).expect("Tversion failed");
+    }
+
+    fn attach(&mut self, fid: u32) {
+        let mut body = Vec::new();
+        body.extend_from_slice(&fid.to_le_bytes());
+        body.extend_from_slice(&NOFID.to_le_bytes());
+        put_string(&mut body, "user");
+        put_string(&mut body, "");
+        body.extend_from_slice(&1000u32.to_le_bytes());
+        self.call(TATTACH, &body).expect("Tattach failed");
+    }
+
+    // Walk from `fid` to `newfid` and return the number of names walked
+    fn walk(&mut self, fid: u32, newfid: u32, names: &[&str]) -> Result<usize, i32> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&fid.to_le_bytes());
+        body.extend_from_slice(&newfid.to_le_bytes());
+        body.extend_from_slice(&(names.len() as u16).to_le_bytes());
+        for name in names {
+            put_string(&mut body, name);
+        }
+        let reply = self.call(TWALK, &body)?;
+        Ok(u16::from_le_bytes([reply[0], reply[1]]) as usize)
+    }
+
+    fn lopen(&mut self, fid: u32) -> Result<(), i32> {
+        let mut body = fid.to_le_bytes().to_vec();
+        body.extend_from_slice(&0u32.to_le_bytes());
+        self.call(TLOPEN, &body).map(|_| ())
+    }
+
+    fn read(&mut self, fid: u32) -> Result<Vec<u8>, i32> {
+        let mut body = fid.to_le_bytes().to_vec();
+        body.extend_from_slice(&0u64.to_le_bytes());
+        body.extend_from_slice(&(MSIZE - 64).to_le_bytes());
+        let reply = self.call(TREAD, &body)?;
+        let count = u32::from_le_bytes([reply[0], reply[1], reply[2], reply[3]]) as usize;
+        Ok(reply[4..4 + count].to_vec())
+    }
+
+    // Return the mode, link count and device number of the file at `fid`
+    fn getattr(&mut self, fid: u32) -> Result<(u32, u64, u64), i32> {
+        let mut body = fid.to_le_bytes().to_vec();
+        body.extend_from_slice(&P9_GETATTR_BASIC.to_le_bytes());
+        let reply = self.call(TGETATTR, &body)?;
+        let mode = u32::from_le_bytes(reply[21..25].try_into().unwrap());
+        let nlink = u64::from_le_bytes(reply[33..41].try_into().unwrap());
+        let rdev = u64::from_le_bytes(reply[41..49].try_into().unwrap());
+        Ok((mode, nlink, rdev))
+    }
+
+    // Return the qid path of the file at `fid`
+    fn qid_path(&mut self, fid: u32) -> u64 {
+        let mut body = fid.to_le_bytes().to_vec();
+        body.extend_from_slice(&P9_GETATTR_BASIC.to_le_bytes());
+        let reply = self.call(TGETATTR, &body).expect("Tgetattr failed");
+        u64::from_le_bytes(reply[13..21].try_into().unwrap())
+    }
+
+    fn readlink(&mut self, fid: u32) -> Result<String, i32> {
+        let reply = self.call(TREADLINK, &fid.to_le_bytes())?;
+        let len = u16::from_le_bytes([reply[0], reply[1]]) as usize;
+        Ok(String::from_utf8(reply[2..2 + len].to_vec()).unwrap())
+    }
+
+    // Open and read the file at `fid`
+    fn read_file(&mut self, fid: u32) -> Result<Vec<u8>, i32> {
+        self.lopen(fid)?;
+        self.read(fid)
+    }
+}
+
+impl Drop for P9Test {
+    fn drop(&mut self) {
+        self.device.shutdown();
+        if let Some(base) = &self.base {
+            let _ = fs::remove_dir_all(base);
+        }
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn is_symlink_error(errno: i32) -> bool {
+    errno == libc::ELOOP || errno == libc::ENOTDIR
+}
+
+#[test]
+fn walk_and_read_inside_export() {
+    let mut test = P9Test::new("inside");
+
+    assert_eq!(test.walk(ROOT_FID, 2, &["dir", "file"]), Ok(2));
+    assert_eq!(test.read_file(2), Ok(b"inside\n".to_vec()));
+}
+
+#[test]
+fn parent_of_root_is_refused() {
+    let mut test = P9Test::new("dotdot");
+
+    assert_eq!(test.walk(ROOT_FID, 2, &[".."]), Err(libc::EINVAL));
+    assert_eq!(test.walk(ROOT_FID, 2, &["..", "outside", "file"]), Err(libc::EINVAL));
+
+    // `..` back to the root is allowed, one more stops the walk there and no
+    // fid is created
+    assert_eq!(test.walk(ROOT_FID, 2, &["dir", "..", "..", "outside", "file"]), Ok(2));
+    assert_eq!(test.lopen(2), Err(libc::EBADF));
+}
+
+#[test]
+fn names_containing_slash_are_refused() {
+    let mut test = P9Test::new("slash");
+
+    assert_eq!(test.walk(ROOT_FID, 2, &["dir/file"]), Err(libc::EINVAL));
+    assert_eq!(test.walk(ROOT_FID, 2, &["/etc"]), Err(libc::EINVAL));
+    assert_eq!(test.walk(ROOT_FID, 2, &["dir/../../outside"]), Err(libc::EINVAL));
+    assert_eq!(test.walk(ROOT_FID, 2, &["dir", "../../outside/file"]), Ok(1));
+    assert_eq!(test.lopen(2), Err(libc::EBADF));
+}
+
+#[test]
+fn absolute_symlink_is_not_followed() {
+    let mut test = P9Test::new("abs");
+    let outside = test.path("outside");
+    symlink(&outside, test.path("export/abs")).unwrap();
+    symlink(outside.join("file"), test.path("export/abs-file")).unwrap();
+
+    // The guest may walk to a symlink to read it, but not through it
+    assert_eq!(test.walk(ROOT_FID, 2, &["abs", "file"]), Ok(1));
+    assert_eq!(test.lopen(2), Err(libc::EBADF));
+    assert_eq!(test.walk(ROOT_FID, 3, &["abs-file"]), Ok(1));
+    let errno = test.read_file(3).unwrap_err();
+    assert!(is_symlink_error(errno), "unexpected error {}", errno);
+}
+
+#[test]
+fn relative_symlink_to_outside_is_not_followed() {
+    let mut test = P9Test::new("rel");
+    symlink("../outside", test.path("export/rel")).unwrap();
+    symlink("../../outside/file", test.path("export/dir/rel-file")).unwrap();
+
+    assert_eq!(test.walk(ROOT_FID, 2, &["rel", "file"]), Ok(1));
+    assert_eq!(test.lopen(2), Err(libc::EBADF));
+    assert_eq!(test.walk(ROOT_FID, 3, &["dir", "rel-file"]), Ok(2));
+    let errno = test.read_file(3).unwrap_err();
+    assert!(is_symlink_error(errno), "unexpected error {}", errno);
+}
+
+#[test]
+fn directory_swapped_for_symlink_after_walk() {
+    let mut test = P9Test::new("swap");
+
+    assert_eq!(test.walk(ROOT_FID, 2, &["dir", "file"]), Ok(2));
+    assert_eq!(test.walk(ROOT_FID, 3, &["dir"]), Ok(1));
+
+    // Replace the directory the fids were walked through with a symlink to a
+    // directory outside of the export holding a file with the same name
+    let dir = test.path("export/dir");
+    fs::rename(&dir, test.path("export/dir.old")).unwrap();
+    symlink(test.path("outside"), &dir).unwrap();
+
+    let errno = test.read_file(2).unwrap_err();
+    assert!(is_symlink_error(errno), "unexpected error {}", errno);
+    let errno = test.walk(3, 4, &["file"]).unwrap_err();
+    assert!(is_symlink_error(errno), "unexpected error {}", errno);
+}
+
+fn synthetic_fs() -> SyntheticFS {
+    let mut fs = SyntheticFS::new();
+    fs.add_memory_file("/bin", "busybox", 0o755, b"busybox\n").unwrap();
+    fs.add_hardlink("/bin/busybox", "/bin", "sh").unwrap();
+    fs.add_symlink("/bin", "ls", "busybox").unwrap();
+    fs.add_char_device("/dev", "null", 0o666, 1, 3).unwrap();
+    fs.add_block_device("/dev", "vda", 0o600, 254, 0).unwrap();
+    fs
+}
+
+#[test]
+fn synthetic_symlink() {
+    let mut test = P9Test::synthetic(synthetic_fs());
+
+    assert_eq!(test.walk(ROOT_FID, 2, &["bin", "ls"]), Ok(2));
+    assert_eq!(test.readlink(2), Ok("busybox".to_string()));
+    let (mode, _, _) = test.getattr(2).unwrap();
+    assert_eq!(mode, libc::S_IFLNK | 0o777);
+    assert_eq!(test.lopen(2), Err(libc::ELOOP));
+
+    assert_eq!(test.walk(ROOT_FID, 3, &["bin", "busybox"]), Ok(2));
+    assert_eq!(test.readlink(3), Err(libc::EINVAL));
+}
+
+#[test]
+fn synthetic_device_nodes() {
+    let mut test = P9Test::synthetic(synthetic_fs());
+
+    assert_eq!(test.walk(ROOT_FID, 2, &["dev", "null"]), Ok(2));
+    assert_eq!(test.getattr(2), Ok((libc::S_IFCHR | 0o666, 1, libc::makedev(1, 3))));
+    assert_eq!(test.lopen(2), Err(libc::ENXIO));
+
+    assert_eq!(test.walk(ROOT_FID, 3, &["dev", "vda"]), Ok(2));
+    assert_eq!(test.getattr(3), Ok((libc::S_IFBLK | 0o600, 1, libc::makedev(254, 0))));
+}
+
+#[test]
+fn synthetic_hardlink() {
+    let mut test = P9Test::synthetic(synthetic_fs());
+
+    assert_eq!(test.walk(ROOT_FID, 2, &["bin", "busybox"]), Ok(2));
+    assert_eq!(test.walk(ROOT_FID, 3, &["bin", "sh"]), Ok(2));
+    assert_eq!(test.getattr(2), Ok((libc::S_IFREG | 0o755, 2, 0)));
+    assert_eq!(test.getattr(3), Ok((libc::S_IFREG | 0o755, 2, 0)));
+    assert_eq!(test.qid_path(2), test.qid_path(3));
+    assert_eq!(test.read_file(3), Ok(b"busybox\n".to_vec()));
+}
+
+#[test]
+fn synthetic_invalid_links() {
+    let mut fs = synthetic_fs();
+    let errno = |r: io::Result<()>| r.unwrap_err().raw_os_error();
+
+    assert_eq!(errno(fs.add_hardlink("/bin", "/", "bin2")), Some(libc::EPERM));
+    assert_eq!(errno(fs.add_hardlink("/missing", "/bin", "x")), Some(libc::ENOENT));
+    assert_eq!(errno(fs.add_symlink("/bin", "sh", "busybox")), Some(libc::EEXIST));
+    assert_eq!(errno(fs.add_char_device("/bin/busybox", "null", 0o666, 1, 3)), Some(libc::ENOTDIR));
+}
@@ -0,0 +1,418 @@
+//! Drives a virtio block device backed by a temporary raw image through a
+//! split virtqueue in mock guest memory, playing the part of the guest
+//! driver, and checks the status and data of each request.
+//!
+//! These tests use the mock VM and are only built with the `mock-kvm` feature:
+//!
+//!     $ cargo test --features mock-kvm --test virtio_block
+#![cfg(feature = "mock-kvm")]
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use ph::OpenType;
+use ph::testing::{self, DiskImage, DmaRanges, MockVm, PciBar, PciDevice, RawDiskImage, VirtioBlock, VirtioDeviceState};
+
+const BAR_BASE: u64 = 0xe000_0000;
+const NOTIFY_OFFSET: u64 = 0x400;
+
+const QUEUE_SIZE: u16 = 256;
+const DESC_TABLE: u64 = 0x1000;
+const AVAIL_RING: u64 = 0x2000;
+const USED_RING: u64 = 0x3000;
+
+const HEADER: u64 = 0x10000;
+const DATA: u64 = 0x11000;
+const STATUS: u64 = 0x20000;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_NEEDS_RESET: u8 = 64;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_GET_ID: u32 = 8;
+
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+const SECTOR_SIZE: usize = 512;
+const DISK_SECTORS: usize = 8;
+
+// Marks guest memory which the device should not have written
+const UNTOUCHED: u8 = 0xAA;
+
+// Every byte of sector N of the test image is N + 1
+fn sector_byte(sector: usize) -> u8 {
+    sector as u8 + 1
+}
+
+fn create_image(name: &str) -> PathBuf {
+    let image = env::temp_dir().join(format!("ph-test-blk-{}-{}.img", name, process::id()));
+    let contents: Vec<u8> = (0..DISK_SECTORS * SECTOR_SIZE)
+        .map(|i| sector_byte(i / SECTOR_SIZE))
+        .collect();
+    fs::write(&image, contents).unwrap();
+    image
+}
+
+struct BlockTest {
+    memory: GuestMemoryMmap,
+    vm: Arc<MockVm>,
+    device: VirtioDeviceState,
+    image: PathBuf,
+    avail_idx: u16,
+}
+
+impl BlockTest {
+    fn new(name: &str, open_type: OpenType) -> BlockTest {
+        Self::with_dma_ranges(name, open_type, DmaRanges::unrestricted())
+    }
+
+    fn with_dma_ranges(name: &str, open_type: OpenType, dma: DmaRanges) -> BlockTest {
+        let image = create_image(name);
+        let disk = RawDiskImage::new(&image, open_type).unwrap();
+        Self::start(image, disk, dma)
+    }
+
+    fn with_label(name: &str, label: &str) -> BlockTest {
+        let image = create_image(name);
+        let mut disk = RawDiskImage::new(&image, OpenType::ReadWrite).unwrap();
+        disk.set_label(label);
+        Self::start(image, disk, DmaRanges::unrestricted())
+    }
+
+    fn start(image: PathBuf, disk: RawDiskImage, dma: DmaRanges) -> BlockTest {
+        let memory = testing::guest_memory(1 << 20);
+        let vm = Arc::new(MockVm::new());
+        let mut device = testing::virtio_device(VirtioBlock::new(disk), vm.clone(), memory.clone(), 5);
+        device.set_dma_ranges(dma);
+        let mut test = BlockTest { memory, vm, device, image, avail_idx: 0 };
+        test.start_driver();
+        test
+    }
+
+    fn write_bar(&mut self, offset: u64, data: &[u8]) {
+        self.device.write_bar(PciBar::Bar0, offset, data);
+    }
+
+    fn read_bar_u32(&mut self, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        self.device.read_bar(PciBar::Bar0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn set_status(&mut self, status: u8) {
+        self.write_bar(20, &[status]);
+    }
+
+    fn status(&mut self) -> u8 {
+        let mut data = [0u8; 1];
+        self.device.read_bar(PciBar::Bar0, 20, &mut data);
+        data[0]
+    }
+
+    // Accept every feature offered by the device and set up queue 0
+    fn start_driver(&mut self) {
+        self.device.configure_bars(vec![(PciBar::Bar0, BAR_BASE)]);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        for word in 0..2u32 {
+            self.write_bar(0, &word.to_le_bytes());
+            let features = self.read_bar_u32(4);
+            self.write_bar(8, &word.to_le_bytes());
+            self.write_bar(12, &features.to_le_bytes());
+        }
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+
+        self.write_bar(22, &0u16.to_le_bytes());
+        self.write_bar(24, &QUEUE_SIZE.to_le_bytes());
+        self.write_bar(32, &(DESC_TABLE as u32).to_le_bytes());
+        self.write_bar(40, &(AVAIL_RING as u32).to_le_bytes());
+        self.write_bar(48, &(USED_RING as u32).to_le_bytes());
+        self.write_bar(28, &1u16.to_le_bytes());
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK);
+    }
+
+    fn write_header(&self, msg_type: u32, sector: u64) {
+        let mut header = Vec::new();
+        header.extend_from_slice(&msg_type.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&sector.to_le_bytes());
+        self.memory.write_slice(&header, GuestAddress(HEADER)).unwrap();
+    }
+
+    fn fill(&self, address: u64, len: usize, byte: u8) {
+        self.memory.write_slice(&vec![byte; len], GuestAddress(address)).unwrap();
+    }
+
+    fn read(&self, address: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        self.memory.read_slice(&mut buf, GuestAddress(address)).unwrap();
+        buf
+    }
+
+    // Place a chain of `(address, length, writeable)` buffers on the avail
+    // ring, notify the device and wait for it to be returned. Returns the
+    // length written by the device.
+    fn submit(&mut self, buffers: &[(u64, u32, bool)]) -> u32 {
+        for (i, &(address, len, writeable)) in buffers.iter().enumerate() {
+            let mut flags = if writeable { VIRTQ_DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let desc = DESC_TABLE + i as u64 * 16;
+            self.memory.write_obj(address, GuestAddress(desc)).unwrap();
+            self.memory.write_obj(len, GuestAddress(desc + 8)).unwrap();
+            self.memory.write_obj(flags, GuestAddress(desc + 12)).unwrap();
+            self.memory.write_obj(i as u16 + 1, GuestAddress(desc + 14)).unwrap();
+        }
+        let slot = self.avail_idx % QUEUE_SIZE;
+        self.memory.write_obj(0u16, GuestAddress(AVAIL_RING + 4 + slot as u64 * 2)).unwrap();
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.memory.write_obj(self.avail_idx, GuestAddress(AVAIL_RING + 2)).unwrap();
+        assert!(self.vm.notify(BAR_BASE + NOTIFY_OFFSET));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.memory.read_obj::<u16>(GuestAddress(USED_RING + 2)).unwrap() != self.avail_idx {
+            assert!(Instant::now() < deadline, "request was not completed");
+            thread::sleep(Duration::from_millis(1));
+        }
+        let elem = USED_RING + 4 + slot as u64 * 8;
+        assert_eq!(self.memory.read_obj::<u32>(GuestAddress(elem)).unwrap(), 0);
+        self.memory.read_obj(GuestAddress(elem + 4)).unwrap()
+    }
+
+    // Submit a request with a full header, `data` and a separate status
+    // descriptor and return the status and the length written by the device.
+    fn request(&mut self, msg_type: u32, sector: u64, data: &[(u64, u32, bool)]) -> (u8, u32) {
+        self.write_header(msg_type, sector);
+        self.fill(STATUS, 1, UNTOUCHED);
+        let mut buffers = vec![(HEADER, 16, false)];
+        buffers.extend_from_slice(data);
+        buffers.push((STATUS, 1, true));
+        let len = self.submit(&buffers);
+        (self.read(STATUS, 1)[0], len)
+    }
+
+    fn image_sector(&self, sector: usize) -> Vec<u8> {
+        let contents = fs::read(&self.image).unwrap();
+        contents[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE].to_vec()
+    }
+}
+
+impl Drop for BlockTest {
+    fn drop(&mut self) {
+        self.device.shutdown();
+        let _ = fs::remove_file(&self.image);
+    }
+}
+
+#[test]
+fn read_and_write_sectors() {
+    let mut test = BlockTest::new("rw", OpenType::ReadWrite);
+
+    test.fill(DATA, 2 * SECTOR_SIZE, 0x5A);
+    let (status, len) = test.request(T_OUT, 2, &[(DATA, 2 * SECTOR_SIZE as u32, false)]);
+    assert_eq!(status, S_OK);
+    assert_eq!(len, 1);
+    assert_eq!(test.image_sector(2), vec![0x5A; SECTOR_SIZE]);
+    assert_eq!(test.image_sector(4), vec![sector_byte(4); SECTOR_SIZE]);
+
+    test.fill(DATA, 2 * SECTOR_SIZE, UNTOUCHED);
+    let (status, len) = test.request(T_IN, 3, &[(DATA, 2 * SECTOR_SIZE as u32, true)]);
+    assert_eq!(status, S_OK);
+    assert_eq!(len, 2 * SECTOR_SIZE as u32 + 1);
+    assert_eq!(test.read(DATA, SECTOR_SIZE), vec![0x5A; SECTOR_SIZE]);
+    assert_eq!(test.read(DATA + SECTOR_SIZE as u64, SECTOR_SIZE), vec![sector_byte(4); SECTOR_SIZE]);
+}
+
+#[test]
+fn status_is_last_writeable_byte() {
+    let mut test = BlockTest::new("status", OpenType::ReadWrite);
+
+    // The second descriptor holds the last sector of data and the status
+    test.write_header(T_IN, 1);
+    test.fill(DATA, 2 * SECTOR_SIZE + 1, UNTOUCHED);
+    let len = test.submit(&[
+        (HEADER, 16, false),
+        (DATA, SECTOR_SIZE as u32, true),
+        (DATA + SECTOR_SIZE as u64, SECTOR_SIZE as u32 + 1, true),
+    ]);
+    assert_eq!(len, 2 * SECTOR_SIZE as u32 + 1);
+    assert_eq!(test.read(DATA, SECTOR_SIZE), vec![sector_byte(1); SECTOR_SIZE]);
+    assert_eq!(test.read(DATA + SECTOR_SIZE as u64, SECTOR_SIZE), vec![sector_byte(2); SECTOR_SIZE]);
+    assert_eq!(test.read(DATA + 2 * SECTOR_SIZE as u64, 1)[0], S_OK);
+}
+
+#[test]
+fn partial_sector_is_rejected() {
+    let mut test = BlockTest::new("partial", OpenType::ReadWrite);
+
+    test.fill(DATA, SECTOR_SIZE + 100, UNTOUCHED);
+    let (status, _) = test.request(T_IN, 0, &[(DATA, SECTOR_SIZE as u32 + 100, true)]);
+    assert_eq!(status, S_IOERR);
+    assert_eq!(test.read(DATA, SECTOR_SIZE + 100), vec![UNTOUCHED; SECTOR_SIZE + 100]);
+
+    let (status, _) = test.request(T_OUT, 0, &[(DATA, SECTOR_SIZE as u32 + 100, false)]);
+    assert_eq!(status, S_IOERR);
+    assert_eq!(test.image_sector(0), vec![sector_byte(0); SECTOR_SIZE]);
+}
+
+#[test]
+fn request_beyond_end_of_disk() {
+    let mut test = BlockTest::new("range", OpenType::ReadWrite);
+    let last = DISK_SECTORS as u64 - 1;
+
+    test.fill(DATA, 2 * SECTOR_SIZE, UNTOUCHED);
+    let (status, _) = test.request(T_IN, last, &[(DATA, 2 * SECTOR_SIZE as u32, true)]);
+    assert_eq!(status, S_IOERR);
+    assert_eq!(test.read(DATA, 2 * SECTOR_SIZE), vec![UNTOUCHED; 2 * SECTOR_SIZE]);
+
+    let (status, _) = test.request(T_OUT, last, &[(DATA, 2 * SECTOR_SIZE as u32, false)]);
+    assert_eq!(status, S_IOERR);
+    assert_eq!(test.image_sector(DISK_SECTORS - 1), vec![sector_byte(DISK_SECTORS - 1); SECTOR_SIZE]);
+
+    let (status, _) = test.request(T_IN, u64::MAX, &[(DATA, SECTOR_SIZE as u32, true)]);
+    assert_eq!(status, S_IOERR);
+
+    let (status, _) = test.request(T_IN, last, &[(DATA, SECTOR_SIZE as u32, true)]);
+    assert_eq!(status, S_OK);
+}
+
+#[test]
+fn malformed_requests() {
+    let mut test = BlockTest::new("malformed", OpenType::ReadWrite);
+
+    let (status, _) = test.request(99, 0, &[]);
+    assert_eq!(status, S_UNSUPP);
+
+    // Header shorter than 16 bytes
+    test.write_header(T_IN, 0);
+    test.fill(STATUS, 1, UNTOUCHED);
+    test.submit(&[(HEADER, 8, false), (STATUS, 1, true)]);
+    assert_eq!(test.read(STATUS, 1)[0], S_IOERR);
+
+    // No status byte, the chain is returned without anything written
+    test.write_header(T_IN, 0);
+    assert_eq!(test.submit(&[(HEADER, 16, false)]), 0);
+
+    let (status, _) = test.request(T_IN, 0, &[(DATA, SECTOR_SIZE as u32, true)]);
+    assert_eq!(status, S_OK);
+}
+
+#[test]
+fn write_to_read_only_image() {
+    let mut test = BlockTest::new("ro", OpenType::ReadOnly);
+
+    test.fill(DATA, SECTOR_SIZE, 0x5A);
+    let (status, _) = test.request(T_OUT, 0, &[(DATA, SECTOR_SIZE as u32, false)]);
+    assert_eq!(status, S_IOERR);
+    assert_eq!(test.image_sector(0), vec![sector_byte(0); SECTOR_SIZE]);
+}
+
+#[test]
+fn get_id() {
+    let mut test = BlockTest::new("id", OpenType::ReadWrite);
+
+    test.fill(DATA, 20, UNTOUCHED);
+    let (status, len) = test.request(T_GET_ID, 0, &[(DATA, 20, true)]);
+    assert_eq!(status, S_OK);
+    assert_eq!(len, 21);
+    assert!(!test.read(DATA, 20).contains(&UNTOUCHED));
+}
+
+#[test]
+fn get_id_of_labeled_disk() {
+    let mut test = BlockTest::with_label("label", "data");
+
+    let (status, _) = test.request(T_GET_ID, 0, &[(DATA, 20, true)]);
+    assert_eq!(status, S_OK);
+    let mut expected = b"data".to_vec();
+    expected.resize(20, 0);
+    assert_eq!(test.read(DATA, 20), expected);
+}
+
+#[test]
+fn long_label_is_truncated() {
+    // 21 bytes, cut to 19 so that the last character is not split
+    let mut test = BlockTest::with_label("long-label", "a\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}");
+
+    let (status, _) = test.request(T_GET_ID, 0, &[(DATA, 20, true)]);
+    assert_eq!(status, S_OK);
+    let mut expected = "a\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}".as_bytes().to_vec();
+    expected.resize(20, 0);
+    assert_eq!(test.read(DATA, 20), expected);
+}
+
+#[test]
+fn ext4_volume_label() {
+    // An ext4 superblock with the label "home" in a filesystem starting at
+    // `offset` bytes into the image
+    fn image_with_label(name: &str, offset: usize, magic: u16) -> PathBuf {
+        let image = create_image(name);
+        let mut contents = fs::read(&image).unwrap();
+        let sb = offset + 1024;
+        contents[sb + 0x38..sb + 0x3a].copy_from_slice(&magic.to_le_bytes());
+        contents[sb + 0x78..sb + 0x78 + 16].copy_from_slice(b"home\0\0\0\0\0\0\0\0\0\0\0\0");
+        fs::write(&image, contents).unwrap();
+        image
+    }
+
+    let image = image_with_label("volume-label", 0, 0xEF53);
+    let disk = RawDiskImage::new(&image, OpenType::ReadOnly).unwrap();
+    assert_eq!(disk.volume_label(), Some("home".to_string()));
+    fs::remove_file(&image).unwrap();
+
+    let image = image_with_label("volume-label-offset", 1024, 0xEF53);
+    let disk = RawDiskImage::new_with_offset(&image, OpenType::ReadOnly, 1024).unwrap();
+    assert_eq!(disk.volume_label(), Some("home".to_string()));
+    fs::remove_file(&image).unwrap();
+
+    let image = image_with_label("volume-label-magic", 0, 0x1234);
+    let disk = RawDiskImage::new(&image, OpenType::ReadOnly).unwrap();
+    assert_eq!(disk.volume_label(), None);
+    fs::remove_file(&image).unwrap();
+}
+
+#[test]
+fn strict_dma() {
+    // Only the range holding the request buffers, not the rings
+    let dma = DmaRanges::strict(&[(HEADER, STATUS + 0x1000 - HEADER)]);
+    let mut test = BlockTest::with_dma_ranges("dma", OpenType::ReadWrite, dma);
+
+    let (status, _) = test.request(T_IN, 0, &[(DATA, SECTOR_SIZE as u32, true)]);
+    assert_eq!(status, S_OK);
+    assert_eq!(test.status() & STATUS_NEEDS_RESET, 0);
+
+    // Data outside of the allowed ranges, the chain is cut off before it and
+    // returned without anything written
+    test.write_header(T_IN, 0);
+    test.fill(0x40000, SECTOR_SIZE, UNTOUCHED);
+    assert_eq!(test.submit(&[(HEADER, 16, false), (0x40000, SECTOR_SIZE as u32, true), (STATUS, 1, true)]), 0);
+    assert_eq!(test.read(0x40000, SECTOR_SIZE), vec![UNTOUCHED; SECTOR_SIZE]);
+    assert_ne!(test.status() & STATUS_NEEDS_RESET, 0);
+}
+
+#[test]
+fn strict_dma_rejects_rings() {
+    // Everything is allowed, but the used ring is still off limits
+    let dma = DmaRanges::strict(&[(0, 1 << 20)]);
+    let mut test = BlockTest::with_dma_ranges("dma-rings", OpenType::ReadWrite, dma);
+
+    test.write_header(T_IN, 0);
+    assert_eq!(test.submit(&[(HEADER, 16, false), (USED_RING, SECTOR_SIZE as u32, true), (STATUS, 1, true)]), 0);
+    assert_ne!(test.status() & STATUS_NEEDS_RESET, 0);
+}
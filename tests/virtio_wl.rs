@@ -0,0 +1,280 @@
+//! Drives a virtio wayland device connected to a test compositor socket
+//! through split virtqueues in mock guest memory and checks that data the
+//! guest sends in a chain of several descriptors arrives on the host intact.
+//!
+//! These tests use the mock VM and are only built with the `mock-kvm` and
+//! `wayland` features:
+//!
+//!     $ cargo test --features mock-kvm --test virtio_wl
+#![cfg(all(feature = "mock-kvm", feature = "wayland"))]
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use ph::testing::{self, MockVm, PciBar, PciDevice, ScmSocket, VirtioDeviceState, VirtioWayland};
+
+const BAR_BASE: u64 = 0xe000_0000;
+const NOTIFY_OFFSET: u64 = 0x400;
+
+const QUEUE_SIZE: u16 = 128;
+const OUT_QUEUE: u16 = 1;
+
+// Descriptor table, avail ring and used ring of the in queue and the out
+// queue. The in queue is never given buffers.
+const RINGS: [(u64, u64, u64); 2] = [
+    (0x1000, 0x2000, 0x3000),
+    (0x4000, 0x5000, 0x6000),
+];
+
+const REQUEST: u64 = 0x10000;
+const REPLY: u64 = 0xf0000;
+const REPLY_SIZE: u32 = 64;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+
+const CMD_VFD_SEND: u32 = 258;
+const CMD_VFD_NEW_CTX: u32 = 260;
+const CMD_VFD_NEW_PIPE: u32 = 261;
+const RESP_OK: u32 = 4096;
+const RESP_VFD_NEW: u32 = 4097;
+
+const VFD_WRITE: u32 = 1;
+
+const CTX_ID: u32 = 1;
+const PIPE_ID: u32 = 2;
+
+struct WlTest {
+    memory: GuestMemoryMmap,
+    vm: Arc<MockVm>,
+    device: VirtioDeviceState,
+    listener: UnixListener,
+    socket_path: PathBuf,
+    avail_idx: u16,
+}
+
+impl WlTest {
+    fn new(name: &str) -> WlTest {
+        let socket_path = env::temp_dir().join(format!("ph-test-wl-{}-{}", name, process::id()));
+        let _ = fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path).unwrap();
+
+        let memory = testing::guest_memory(1 << 20);
+        let vm = Arc::new(MockVm::new());
+        let shm = testing::device_shm_manager(vm.clone(), &memory);
+        let wl = VirtioWayland::new(false, None, &socket_path, shm);
+        let device = testing::virtio_device(wl, vm.clone(), memory.clone(), 5);
+        let mut test = WlTest { memory, vm, device, listener, socket_path, avail_idx: 0 };
+        test.start_driver();
+        test
+    }
+
+    fn write_bar(&mut self, offset: u64, data: &[u8]) {
+        self.device.write_bar(PciBar::Bar0, offset, data);
+    }
+
+    fn read_bar_u32(&mut self, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        self.device.read_bar(PciBar::Bar0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn set_status(&mut self, status: u8) {
+        self.write_bar(20, &[status]);
+    }
+
+    // Accept every feature offered by the device and set up both queues
+    fn start_driver(&mut self) {
+        self.device.configure_bars(vec![(PciBar::Bar0, BAR_BASE)]);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        for word in 0..2u32 {
+            self.write_bar(0, &word.to_le_bytes());
+            let features = self.read_bar_u32(4);
+            self.write_bar(8, &word.to_le_bytes());
+            self.write_bar(12, &features.to_le_bytes());
+        }
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+
+        for (queue, &(desc, avail, used)) in RINGS.iter().enumerate() {
+            self.write_bar(22, &(queue as u16).to_le_bytes());
+            self.write_bar(24, &QUEUE_SIZE.to_le_bytes());
+            self.write_bar(32, &(desc as u32).to_le_bytes());
+            self.write_bar(40, &(avail as u32).to_le_bytes());
+            self.write_bar(48, &(used as u32).to_le_bytes());
+            self.write_bar(28, &1u16.to_le_bytes());
+        }
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK);
+    }
+
+    // Place each of `parts` in its own readable descriptor followed by a
+    // descriptor for the reply, submit the chain on the out queue and wait
+    // for it to be returned. Returns the response code written by the device.
+    fn command(&mut self, parts: &[&[u8]]) -> u32 {
+        assert!(parts.len() < QUEUE_SIZE as usize);
+        let (desc_table, avail_ring, used_ring) = RINGS[OUT_QUEUE as usize];
+
+        let mut address = REQUEST;
+        let mut buffers = Vec::new();
+        for part in parts {
+            self.memory.write_slice(part, GuestAddress(address)).unwrap();
+            buffers.push((address, part.len() as u32, 0));
+            // Leave a gap so that no two descriptors are contiguous in memory
+            address += part.len() as u64 + 0x100;
+        }
+        buffers.push((REPLY, REPLY_SIZE, VIRTQ_DESC_F_WRITE));
+
+        for (i, &(address, len, flags)) in buffers.iter().enumerate() {
+            let flags = if i + 1 < buffers.len() { flags | VIRTQ_DESC_F_NEXT } else { flags };
+            let desc = desc_table + i as u64 * 16;
+            self.memory.write_obj(address, GuestAddress(desc)).unwrap();
+            self.memory.write_obj(len, GuestAddress(desc + 8)).unwrap();
+            self.memory.write_obj(flags, GuestAddress(desc + 12)).unwrap();
+            self.memory.write_obj(i as u16 + 1, GuestAddress(desc + 14)).unwrap();
+        }
+        let slot = self.avail_idx % QUEUE_SIZE;
+        self.memory.write_obj(0u16, GuestAddress(avail_ring + 4 + slot as u64 * 2)).unwrap();
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.memory.write_obj(self.avail_idx, GuestAddress(avail_ring + 2)).unwrap();
+        assert!(self.vm.notify(BAR_BASE + NOTIFY_OFFSET + 4 * OUT_QUEUE as u64));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.memory.read_obj::<u16>(GuestAddress(used_ring + 2)).unwrap() != self.avail_idx {
+            assert!(Instant::now() < deadline, "command was not completed");
+            thread::sleep(Duration::from_millis(1));
+        }
+        self.memory.read_obj(GuestAddress(REPLY)).unwrap()
+    }
+
+    // Create a context vfd and return the compositor end of its connection
+    fn new_ctx(&mut self, id: u32) -> UnixStream {
+        let msg = words(&[CMD_VFD_NEW_CTX, 0, id]);
+        assert_eq!(self.command(&[&msg[..]]), RESP_VFD_NEW);
+        let (stream, _) = self.listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }
+
+    fn new_pipe(&mut self, id: u32, flags: u32) {
+        let msg = words(&[CMD_VFD_NEW_PIPE, 0, id, flags]);
+        assert_eq!(self.command(&[&msg[..]]), RESP_VFD_NEW);
+    }
+}
+
+impl Drop for WlTest {
+    fn drop(&mut self) {
+        self.device.shutdown();
+        let _ = fs::remove_file(&self.socket_path);
+    }
+}
+
+fn words(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn send_header(id: u32, vfd_ids: &[u32]) -> Vec<u8> {
+    let mut header = words(&[CMD_VFD_SEND, 0, id, vfd_ids.len() as u32]);
+    header.extend_from_slice(&words(vfd_ids));
+    header
+}
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+// Split `data` into pieces with lengths taken in turn from `sizes`
+fn split<'a>(data: &'a [u8], sizes: &[usize]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    let mut rest = data;
+    for &size in sizes.iter().cycle() {
+        if rest.is_empty() {
+            break;
+        }
+        let (part, tail) = rest.split_at(size.min(rest.len()));
+        parts.push(part);
+        rest = tail;
+    }
+    parts
+}
+
+fn read_all<R: Read>(r: &mut R, len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn send_data_in_several_descriptors() {
+    let mut test = WlTest::new("send");
+    let mut stream = test.new_ctx(CTX_ID);
+
+    let header = send_header(CTX_ID, &[]);
+    let data = payload(12000);
+    let mut parts = vec![&header[..8], &header[8..]];
+    parts.extend(split(&data, &[1, 4095, 5000]));
+    assert_eq!(test.command(&parts), RESP_OK);
+
+    assert_eq!(read_all(&mut stream, data.len()), data);
+}
+
+#[test]
+fn send_header_split_inside_fields() {
+    let mut test = WlTest::new("split");
+    let mut stream = test.new_ctx(CTX_ID);
+
+    // Descriptor boundaries fall inside the header fields and the first
+    // descriptor holding data also holds the end of the header
+    let data = payload(300);
+    let mut msg = send_header(CTX_ID, &[]);
+    msg.extend_from_slice(&data);
+    let parts = split(&msg, &[3, 7, 13]);
+    assert_eq!(test.command(&parts), RESP_OK);
+
+    assert_eq!(read_all(&mut stream, data.len()), data);
+}
+
+#[test]
+fn send_with_fds_in_several_descriptors() {
+    let mut test = WlTest::new("fds");
+    let mut stream = test.new_ctx(CTX_ID);
+    test.new_pipe(PIPE_ID, VFD_WRITE);
+
+    let header = send_header(CTX_ID, &[PIPE_ID]);
+    let data = payload(3000);
+    let mut parts = vec![&header[..]];
+    parts.extend(split(&data, &[100, 1900, 1]));
+    assert_eq!(test.command(&parts), RESP_OK);
+
+    // The data is sent with the file descriptor in a single message
+    let mut buf = vec![0u8; data.len()];
+    let mut fds = [-1; 4];
+    let (len, nfds) = stream.recv_with_fds(&mut buf, &mut fds).unwrap();
+    assert_eq!(nfds, 1);
+    assert_eq!(&buf[..len], &data[..len]);
+    assert_eq!(read_all(&mut stream, data.len() - len), &data[len..]);
+
+    // Data the guest writes to the pipe vfd arrives at the end which was sent
+    let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+    let header = send_header(PIPE_ID, &[]);
+    let data = payload(5000);
+    let mut parts = vec![&header[..]];
+    parts.extend(split(&data, &[2048, 17]));
+    assert_eq!(test.command(&parts), RESP_OK);
+
+    assert_eq!(read_all(&mut pipe, data.len()), data);
+}
+